@@ -36,7 +36,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         128 << 20,
         128 * 1024 * 1024,
     ));
-    let engine = BlockEngine::new("/dev/loop8", meta_engine);
+    let engine = BlockEngine::new("/dev/loop8", meta_engine).unwrap();
 
     c.bench_function("block device test", |b| {
         b.iter(|| {
@@ -0,0 +1,41 @@
+//! run the benchmark with:
+//!     cargo bench --bench hash_ring
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use sealfs::common::{
+    cache::OwnerCache,
+    hash_ring::{HashAlgorithm, HashRing},
+};
+
+const SERVERS: usize = 1000;
+const PATHS: usize = 1000;
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let servers = (0..SERVERS)
+        .map(|i| (format!("10.0.{}.{}:8085", i / 256, i % 256), 100))
+        .collect();
+    let hash_ring = HashRing::new(HashAlgorithm::Rendezvous, servers);
+    let paths: Vec<String> = (0..PATHS).map(|i| format!("test/dir/file{}", i)).collect();
+
+    c.bench_function("rendezvous ring lookup", |b| {
+        b.iter(|| {
+            paths.iter().for_each(|path| {
+                hash_ring.get(path).unwrap();
+            })
+        })
+    });
+
+    let owner_cache = OwnerCache::new(PATHS);
+    c.bench_function("owner cache lookup", |b| {
+        b.iter(|| {
+            paths.iter().for_each(|path| {
+                owner_cache.get(hash_ring.epoch, path, || {
+                    hash_ring.get(path).unwrap().address.clone()
+                });
+            })
+        })
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
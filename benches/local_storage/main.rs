@@ -51,7 +51,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         128 << 20,
         128 * 1024 * 1024,
     ));
-    let engine = file_engine::FileEngine::new("/tmp/bench/root", meta_engine);
+    let engine = file_engine::FileEngine::new("/tmp/bench/root", meta_engine).unwrap();
 
     c.bench_function("default engine file 512", |b| {
         b.iter(|| {
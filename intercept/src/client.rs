@@ -3,37 +3,71 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use async_trait::async_trait;
-use sealfs::common::util::{empty_file, path_split};
+use sealfs::common::util::{empty_file, hostname, owner, path_split, temp_file_path};
 use spin::RwLock;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use dashmap::DashMap;
 use lazy_static::lazy_static;
-use libc::{dirent64, iovec, O_CREAT};
+use libc::{dirent64, iovec, statfs, O_CREAT, O_EXCL, O_RDWR, O_TRUNC, S_IFDIR, S_IFMT};
 use log::{debug, error, info};
 use sealfs::common::byte::CHUNK_SIZE;
-use sealfs::common::errors::{status_to_string, CONNECTION_ERROR};
-use sealfs::common::hash_ring::HashRing;
+use sealfs::common::cache::{AttrCache, NegativeCache, OwnerCache};
+use sealfs::common::errors::{status_to_errno, status_to_string, CONNECTION_ERROR, STALE_EPOCH};
+use sealfs::common::hash_ring::{HashRing, HashRingInfo};
 use sealfs::common::info_syncer::{ClientStatusMonitor, InfoSyncer};
+use sealfs::common::page_cache::{page_stamp, SharedPageCache};
 use sealfs::common::sender::{Sender, REQUEST_TIMEOUT};
 use sealfs::common::serialization::{
-    file_attr_as_bytes_mut, tostat, tostatx, ClusterStatus, CreateDirSendMetaData,
-    CreateFileSendMetaData, DeleteDirSendMetaData, DeleteFileSendMetaData, LinuxDirent,
+    bytes_as_dir_entries, file_attr_as_bytes_mut, tostat, tostatx, AppendFileRecvMetaData,
+    AppendFileSendMetaData, ClusterStatus, CreateDirSendMetaData, CreateFileSendMetaData,
+    DeleteDirSendMetaData, DeleteFileSendMetaData, LinuxDirent, LockRecvMetaData, LockSendMetaData,
     OpenFileSendMetaData, OperationType, ReadDirSendMetaData, ReadFileSendMetaData,
-    TruncateFileSendMetaData,
+    RenameSendMetaData, TruncateFileSendMetaData, WriteFileSendMetaData,
 };
-use sealfs::rpc::client::TcpStreamCreator;
+use sealfs::rpc::local::{LocalReadHalf, LocalStreamCreator, LocalWriteHalf};
 use sealfs::{offset_of, rpc};
+
+use crate::CONFIG;
+
+// how long a missing path is remembered, and how many of them.
+const NEGATIVE_TTL: Duration = Duration::from_secs(1);
+const NEGATIVE_CACHE_CAPACITY: usize = 65536;
+// how long the attrs prefetched by a listing are kept.
+const ATTR_TTL: Duration = Duration::from_secs(1);
+// the stamps of the files kept for the page cache, all of them are
+// forgotten past this number.
+const PAGE_STAMPS_CAPACITY: usize = 65536;
+// the owners of the paths kept, see OwnerCache.
+const OWNER_CACHE_CAPACITY: usize = 65536;
+// the statfs of a volume, as a fuse mount answers it.
+const FUSE_SUPER_MAGIC: libc::__fsword_t = 0x65735546;
+const STATFS_BLOCK_SIZE: u64 = 4096;
+// a blocking lock is asked again after these, doubled up to the max.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// the owner of a lock, the process for the fcntl locks and the open file
+// description for the flock locks.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum LockOwner {
+    Process(u32),
+    File(u64),
+}
+
+impl LockOwner {
+    fn id(self) -> u64 {
+        match self {
+            LockOwner::Process(pid) => pid as u64,
+            LockOwner::File(id) => id,
+        }
+    }
+}
+
 pub struct Client {
-    pub client: Arc<
-        rpc::client::RpcClient<
-            tokio::net::tcp::OwnedReadHalf,
-            tokio::net::tcp::OwnedWriteHalf,
-            TcpStreamCreator,
-        >,
-    >,
+    pub client: Arc<rpc::client::RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
     pub sender: Arc<Sender>,
     pub inodes: DashMap<String, u64>,
     pub inodes_reverse: DashMap<u64, String>,
@@ -44,6 +78,25 @@ pub struct Client {
     pub hash_ring: Arc<RwLock<Option<HashRing>>>,
     pub new_hash_ring: Arc<RwLock<Option<HashRing>>>,
     pub manager_address: Arc<tokio::sync::Mutex<String>>,
+    pub negative_cache: NegativeCache,
+    // the attrs of the entries of the directories listed, got in the
+    // background for the stats that follow, set by attr_prefetch.
+    pub attr_prefetch: bool,
+    pub attr_cache: AttrCache,
+    // bumped by the changes made through this client, see prefetch_attrs.
+    attr_generation: AtomicU64,
+    // the files created by root are owned by nobody, see Config.
+    pub root_squash: bool,
+    // the chunks read shared with the other clients of the host, see
+    // page_cache.rs, and path -> the stamp of its chunks.
+    pub page_cache: Option<SharedPageCache>,
+    pub page_stamps: DashMap<String, u64>,
+    pub owner_cache: OwnerCache,
+    // this process in the POSIX locks it takes, and (path, lock owner) of
+    // the locks taken -> the process which took them, released when the file
+    // is closed or the process exits.
+    lock_client: String,
+    locked: DashMap<(String, LockOwner), u32>,
 }
 
 impl Default for Client {
@@ -73,7 +126,7 @@ impl ClientStatusMonitor for Client {
     fn manager_address(&self) -> &Arc<tokio::sync::Mutex<String>> {
         &self.manager_address
     }
-    async fn get_new_hash_ring_info(&self) -> Result<Vec<(String, usize)>, i32> {
+    async fn get_new_hash_ring_info(&self) -> Result<HashRingInfo, i32> {
         self.sender
             .get_new_hash_ring_info(&self.manager_address.lock().await)
             .await
@@ -93,6 +146,9 @@ impl ClientStatusMonitor for Client {
     fn new_hash_ring(&self) -> &Arc<RwLock<Option<HashRing>>> {
         &self.new_hash_ring
     }
+    fn owner_cache(&self) -> Option<&OwnerCache> {
+        Some(&self.owner_cache)
+    }
 }
 
 impl Client {
@@ -109,6 +165,112 @@ impl Client {
             hash_ring: Arc::new(RwLock::new(None)),
             new_hash_ring: Arc::new(RwLock::new(None)),
             manager_address: Arc::new(tokio::sync::Mutex::new("".to_string())),
+            negative_cache: NegativeCache::new(NEGATIVE_TTL, NEGATIVE_CACHE_CAPACITY),
+            attr_prefetch: CONFIG.attr_prefetch && CONFIG.attr_cache_size > 0,
+            attr_cache: AttrCache::new(ATTR_TTL, CONFIG.attr_cache_size),
+            attr_generation: AtomicU64::new(0),
+            root_squash: CONFIG.root_squash,
+            page_cache: open_page_cache(),
+            page_stamps: DashMap::new(),
+            owner_cache: OwnerCache::new(OWNER_CACHE_CAPACITY),
+            lock_client: format!("{}:{}", hostname(), std::process::id()),
+            locked: DashMap::new(),
+        }
+    }
+
+    // owner returns the owner of the files created by the process.
+    fn owner(&self) -> (u32, u32) {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        owner(uid, gid, self.root_squash)
+    }
+
+    // prefetch_attrs gets in the background the attrs of the entries of dir
+    // just listed, as a listing is mostly followed by the stats of its
+    // entries. The attrs got while a change is made through this client are
+    // dropped.
+    fn prefetch_attrs(&'static self, dir: &str, names: Vec<String>) {
+        if !self.attr_prefetch || names.is_empty() {
+            return;
+        }
+        let paths: Vec<String> = names
+            .into_iter()
+            .map(|name| format!("{}/{}", dir, name))
+            .collect();
+        let generation = self.attr_generation.load(Ordering::SeqCst);
+        self.handle.spawn(async move {
+            let attrs = self.batch_get_file_attr(&paths).await;
+            for (path, attr) in paths.iter().zip(attrs) {
+                if let Ok(attr) = attr {
+                    self.attr_cache.insert(path, attr);
+                }
+            }
+            // checked after inserting, a change made since either bumped the
+            // generation or dropped the attr after it was inserted.
+            if self.attr_generation.load(Ordering::SeqCst) != generation {
+                for path in &paths {
+                    self.attr_cache.invalidate(path);
+                }
+            }
+        });
+    }
+
+    // forget_attrs drops the attrs of paths changed through this client.
+    fn forget_attrs(&self, paths: &[&str]) {
+        self.attr_generation.fetch_add(1, Ordering::SeqCst);
+        for path in paths {
+            self.attr_cache.invalidate(path);
+            self.page_stamps.remove(*path);
+        }
+    }
+
+    // note_page_stamp keeps the stamp of the chunks of pathname, from an attr
+    // got from the servers.
+    fn note_page_stamp(&self, pathname: &str, stamp: Option<u64>) {
+        let (Some(_), Some(stamp)) = (&self.page_cache, stamp) else {
+            return;
+        };
+        if self.page_stamps.len() >= PAGE_STAMPS_CAPACITY {
+            self.page_stamps.clear();
+        }
+        self.page_stamps.insert(pathname.to_owned(), stamp);
+    }
+
+    // read_pages copies the part of pathname at offset into bufs from the
+    // page cache, and returns its length, or None to read it from the
+    // servers.
+    fn read_pages(&self, pathname: &str, offset: i64, bufs: &mut [&mut [u8]]) -> Option<usize> {
+        let page_cache = self.page_cache.as_ref()?;
+        let stamp = *self.page_stamps.get(pathname)?;
+        let size = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let data = page_cache.read(pathname, stamp, offset as u64, size as u32)?;
+        let mut rest = &data[..];
+        for buf in bufs.iter_mut() {
+            let length = buf.len().min(rest.len());
+            buf[..length].copy_from_slice(&rest[..length]);
+            rest = &rest[length..];
+        }
+        Some(data.len())
+    }
+
+    // fill_pages caches the length bytes of pathname read at offset into
+    // bufs.
+    fn fill_pages(&self, pathname: &str, offset: i64, bufs: &[&mut [u8]], length: usize) {
+        let (Some(page_cache), Some(stamp)) = (
+            self.page_cache.as_ref(),
+            self.page_stamps.get(pathname).map(|stamp| *stamp),
+        ) else {
+            return;
+        };
+        let size = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        page_cache.fill(pathname, stamp, offset as u64, size as u32, &data[..length]);
+    }
+
+    // forget_pages drops the chunks written through this client for the
+    // other clients of the host.
+    fn forget_pages(&self, pathname: &str, offset: i64, length: usize) {
+        if let Some(page_cache) = &self.page_cache {
+            page_cache.invalidate(pathname, offset as u64, length as u64);
         }
     }
 
@@ -121,6 +283,7 @@ impl Client {
         self.sender
             .init_volume(&self.get_connection_address(volume_name), volume_name)
             .await
+            .map(|_| ())
     }
 
     pub async fn init(&'static self) -> Result<(), String> {
@@ -164,15 +327,13 @@ impl Client {
         .await;
 
         match result {
-            Ok(all_servers_address) => {
-                for server_address in &all_servers_address {
+            Ok(info) => {
+                for server_address in &info.servers {
                     if let Err(e) = self.add_connection(&server_address.0).await {
                         panic!("add connection failed: {}", e);
                     }
                 }
-                self.hash_ring
-                    .write()
-                    .replace(HashRing::new(all_servers_address));
+                self.hash_ring.write().replace(HashRing::from(info));
                 Ok(())
             }
             Err(e) => Err(status_to_string(e)),
@@ -181,8 +342,12 @@ impl Client {
 
     pub fn open_remote(&self, pathname: &str, flag: i32, mode: u32) -> Result<(), i32> {
         debug!("open_remote {}", pathname);
+        if flag & O_TRUNC != 0 {
+            self.forget_attrs(&[pathname]);
+        }
         if flag & O_CREAT != 0 {
             let (parent, name) = path_split(pathname).map_err(|_| libc::EINVAL)?;
+            self.forget_attrs(&[pathname, &parent]);
             let server_address = self.get_connection_address(&parent);
             let mut status = 0i32;
             let mut rsp_flags = 0u32;
@@ -191,11 +356,16 @@ impl Client {
             let mut recv_data_length = 0usize;
 
             let mut recv_meta_data = vec![0u8; 1024];
+            let (uid, gid) = self.owner();
             let send_meta_data = bincode::serialize(&CreateFileSendMetaData {
                 flags: flag,
                 umask: 0,
                 mode,
                 name,
+                uid,
+                gid,
+                size_hint: 0,
+                append_only: false,
             })
             .unwrap();
             if self
@@ -220,11 +390,33 @@ impl Client {
                 return Err(libc::EIO);
             }
             if status != 0 {
-                Err(status)
-            } else {
-                Ok(())
+                return Err(status);
+            }
+            self.negative_cache.invalidate_dir(&parent);
+            // without O_EXCL the attr of an existing file is returned, which
+            // is opened as the kernel opens it: a directory fails, and the
+            // data of a file is dropped by O_TRUNC.
+            let mut attr = Box::new(empty_file());
+            let length = recv_meta_data_length.min(std::mem::size_of_val(&*attr));
+            file_attr_as_bytes_mut(&mut attr)[..length].copy_from_slice(&recv_meta_data[..length]);
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            tostat(&attr, unsafe {
+                std::slice::from_raw_parts_mut(
+                    &mut stat as *mut libc::stat as *mut u8,
+                    std::mem::size_of::<libc::stat>(),
+                )
+            });
+            if stat.st_mode & S_IFMT == S_IFDIR {
+                return Err(libc::EISDIR);
             }
+            if flag & O_TRUNC != 0 && stat.st_size > 0 {
+                return self.truncate_remote(pathname, 0);
+            }
+            Ok(())
         } else {
+            if self.negative_cache.contains(pathname) {
+                return Err(libc::ENOENT);
+            }
             let server_address = self.get_connection_address(&pathname);
             let mut status = 0i32;
             let mut rsp_flags = 0u32;
@@ -256,57 +448,241 @@ impl Client {
             {
                 return Err(libc::EIO);
             }
+            if status == libc::ENOENT {
+                self.negative_cache.insert(pathname);
+            }
             if status != 0 {
-                Err(status)
-            } else {
-                Ok(())
+                return Err(status);
+            }
+            // the servers open the files without O_TRUNC.
+            if flag & O_TRUNC != 0 {
+                self.truncate_remote(pathname, 0)?;
+            }
+            // the chunks of the version opened are read from the page cache.
+            if self.page_cache.is_some() {
+                if let Ok(attr) = self
+                    .handle
+                    .block_on(self.sender.get_file_attr(&server_address, pathname))
+                {
+                    self.note_page_stamp(pathname, page_stamp(&attr));
+                }
             }
+            Ok(())
         }
     }
 
-    pub fn rename_remote(&self, _oldpath: &str, _newpath: &str) -> i32 {
-        debug!("rename_remote");
-        todo!()
+    // rename_remote moves oldpath to newpath, the server of the directory of
+    // oldpath moving the file and the entries.
+    pub fn rename_remote(&self, oldpath: &str, newpath: &str) -> Result<(), i32> {
+        debug!("rename_remote {} {}", oldpath, newpath);
+        let (parent, name) = path_split(oldpath).map_err(|_| libc::EINVAL)?;
+        let (new_parent, new_name) = path_split(newpath).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[oldpath, newpath]);
+        let send_meta_data = bincode::serialize(&RenameSendMetaData {
+            name,
+            new_parent: new_parent.clone(),
+            new_name,
+            flags: 0,
+        })
+        .unwrap();
+        self.handle.block_on(self.sender.rename(
+            &self.get_connection_address(&parent),
+            OperationType::Rename,
+            &parent,
+            &send_meta_data,
+        ))?;
+        self.negative_cache.invalidate_dir(&new_parent);
+        Ok(())
     }
 
-    pub fn truncate_remote(&self, pathname: &str, length: i64) -> Result<(), i32> {
-        debug!("truncate_remote {}", pathname);
-        let server_address = self.get_connection_address(pathname);
-        let send_meta_data = bincode::serialize(&TruncateFileSendMetaData { length }).unwrap();
-        let mut status = 0i32;
-        let mut rsp_flags = 0u32;
+    pub fn fsync_remote(&self, pathname: &str, datasync: bool) -> Result<(), i32> {
+        debug!("fsync_remote {}", pathname);
+        self.handle
+            .block_on(
+                self.sender
+                    .fsync(&self.get_connection_address(pathname), pathname, datasync),
+            )
+            .map_err(status_to_errno)
+    }
 
-        let mut recv_meta_data_length = 0usize;
-        let mut recv_data_length = 0usize;
+    pub fn seek_remote(&self, pathname: &str, offset: u64, hole: bool) -> Result<u64, i32> {
+        debug!("seek_remote {}", pathname);
+        self.handle
+            .block_on(self.sender.seek(
+                &self.get_connection_address(pathname),
+                pathname,
+                offset,
+                hole,
+            ))
+            .map_err(status_to_errno)
+    }
 
-        if let Err(_) = self.handle.block_on(self.client.call_remote(
-            &server_address,
-            OperationType::TruncateFile.into(),
-            0,
+    // lock_remote takes or, with F_UNLCK, releases the lock of owner on the
+    // bytes start..=end, and with wait waits for the conflicting locks to be
+    // released.
+    pub fn lock_remote(
+        &self,
+        pathname: &str,
+        owner: LockOwner,
+        (start, end): (u64, u64),
+        typ: i32,
+        wait: bool,
+    ) -> Result<(), i32> {
+        debug!("lock_remote {}", pathname);
+        let md = LockSendMetaData {
+            client: self.lock_client.clone(),
+            owner: owner.id(),
+            start,
+            end,
+            typ,
+            pid: std::process::id(),
+            flock: matches!(owner, LockOwner::File(_)),
+        };
+        let mut interval = LOCK_RETRY_INTERVAL;
+        loop {
+            let operation_type = match typ {
+                libc::F_UNLCK => OperationType::Unlock,
+                _ => OperationType::Lock,
+            };
+            match self.handle.block_on(self.sender.lock(
+                &self.get_connection_address(pathname),
+                operation_type,
+                pathname,
+                &md,
+            )) {
+                Ok(_) => {
+                    if typ != libc::F_UNLCK {
+                        self.locked
+                            .insert((pathname.to_owned(), owner), std::process::id());
+                    }
+                    return Ok(());
+                }
+                Err(libc::EAGAIN) if wait => {
+                    std::thread::sleep(interval);
+                    interval = (interval * 2).min(MAX_LOCK_RETRY_INTERVAL);
+                }
+                Err(e) => return Err(status_to_errno(e)),
+            }
+        }
+    }
+
+    // test_lock_remote returns a lock conflicting with the one of owner, or
+    // F_UNLCK.
+    pub fn test_lock_remote(
+        &self,
+        pathname: &str,
+        owner: LockOwner,
+        (start, end): (u64, u64),
+        typ: i32,
+    ) -> Result<LockRecvMetaData, i32> {
+        debug!("test_lock_remote {}", pathname);
+        let md = LockSendMetaData {
+            client: self.lock_client.clone(),
+            owner: owner.id(),
+            start,
+            end,
+            typ,
+            pid: std::process::id(),
+            flock: matches!(owner, LockOwner::File(_)),
+        };
+        match self.handle.block_on(self.sender.lock(
+            &self.get_connection_address(pathname),
+            OperationType::TestLock,
             pathname,
-            &send_meta_data,
-            &[],
-            &mut status,
-            &mut rsp_flags,
-            &mut recv_meta_data_length,
-            &mut recv_data_length,
-            &mut [],
-            &mut [],
-            REQUEST_TIMEOUT,
+            &md,
         )) {
-            return Err(libc::EIO);
+            Ok(Some(lock)) => Ok(lock),
+            Ok(None) => Err(libc::EIO),
+            Err(e) => Err(status_to_errno(e)),
         }
+    }
 
-        if status != 0 {
-            Err(status)
-        } else {
-            Ok(())
+    // release_locks releases the locks of owner on a file it closes, if
+    // this process took them, the locks of an open file taken before a fork
+    // stay with the process which took them.
+    pub fn release_locks(&self, pathname: &str, owner: LockOwner) {
+        let pid = std::process::id();
+        if self
+            .locked
+            .remove_if(&(pathname.to_owned(), owner), |_, locker| *locker == pid)
+            .is_none()
+        {
+            return;
+        }
+        if let Err(e) = self.lock_remote(pathname, owner, (0, u64::MAX), libc::F_UNLCK, false) {
+            error!("release locks of {}: {}", pathname, e);
+        }
+    }
+
+    // release_all_locks releases the locks taken by the process exiting,
+    // whose descriptors are not closed.
+    pub fn release_all_locks(&self) {
+        let pid = std::process::id();
+        let held: Vec<(String, LockOwner)> = self
+            .locked
+            .iter()
+            .filter(|entry| *entry.value() == pid)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for (pathname, owner) in held {
+            self.release_locks(&pathname, owner);
         }
     }
 
+    pub fn truncate_remote(&self, pathname: &str, length: i64) -> Result<(), i32> {
+        debug!("truncate_remote {}", pathname);
+        self.forget_attrs(&[pathname]);
+        self.handle.block_on(async {
+            let mut refreshed = false;
+            loop {
+                let (server_address, epoch) = self.get_connection_route(pathname);
+                let send_meta_data =
+                    bincode::serialize(&TruncateFileSendMetaData { length, epoch }).unwrap();
+                let mut status = 0i32;
+                let mut rsp_flags = 0u32;
+
+                let mut recv_meta_data_length = 0usize;
+                let mut recv_data_length = 0usize;
+
+                if let Err(_) = self
+                    .client
+                    .call_remote(
+                        &server_address,
+                        OperationType::TruncateFile.into(),
+                        0,
+                        pathname,
+                        &send_meta_data,
+                        &[],
+                        &mut status,
+                        &mut rsp_flags,
+                        &mut recv_meta_data_length,
+                        &mut recv_data_length,
+                        &mut [],
+                        &mut [],
+                        REQUEST_TIMEOUT,
+                    )
+                    .await
+                {
+                    return Err(libc::EIO);
+                }
+                // fenced like the writes, see pwrite.
+                if status == STALE_EPOCH && !refreshed {
+                    refreshed = true;
+                    self.refresh_hash_ring().await?;
+                    continue;
+                }
+                return match status {
+                    0 => Ok(()),
+                    _ => Err(status),
+                };
+            }
+        })
+    }
+
     pub fn mkdir_remote(&self, pathname: &str, mode: u32) -> Result<(), i32> {
         debug!("mkdir_remote {}", pathname);
         let (parent, name) = path_split(pathname).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[pathname, &parent]);
         let server_address = self.get_connection_address(&parent);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -314,7 +690,14 @@ impl Client {
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
-        let send_meta_data = bincode::serialize(&CreateDirSendMetaData { mode, name }).unwrap();
+        let (uid, gid) = self.owner();
+        let send_meta_data = bincode::serialize(&CreateDirSendMetaData {
+            mode,
+            name,
+            uid,
+            gid,
+        })
+        .unwrap();
         let mut recv_meta_data = vec![0u8; 1024];
         if let Err(_) = self.handle.block_on(self.client.call_remote(
             &server_address,
@@ -336,6 +719,7 @@ impl Client {
         if status != 0 {
             Err(status)
         } else {
+            self.negative_cache.invalidate_dir(&parent);
             Ok(())
         }
     }
@@ -343,6 +727,7 @@ impl Client {
     pub fn rmdir_remote(&self, pathname: &str) -> Result<(), i32> {
         debug!("rmdir_remote {}", pathname);
         let (parent, name) = path_split(pathname).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[pathname, &parent]);
         let server_address = self.get_connection_address(&parent);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -376,7 +761,7 @@ impl Client {
     }
 
     pub fn getdents_remote(
-        &self,
+        &'static self,
         pathname: &str,
         dirp: &mut [u8],
         dirp_offset: i64,
@@ -418,26 +803,20 @@ impl Client {
             return Err(status);
         }
 
+        let entries =
+            bytes_as_dir_entries(&recv_data[..recv_data_length]).map_err(|_| libc::EIO)?;
         let dirp_len = dirp.len();
         let mut dirp_ptr = dirp.as_ptr();
         let mut total = 0;
-        let mut recv_total = 0;
         let mut offset = dirp_offset;
-        while recv_total < recv_data_length {
+        let mut names = Vec::new();
+        for (r#type, d_off, name) in entries {
             let dirp = unsafe { (dirp_ptr as *mut LinuxDirent).as_mut().unwrap() };
-            let r#type =
-                u8::from_le_bytes(recv_data[recv_total..recv_total + 1].try_into().unwrap());
-
-            let name_len = u16::from_le_bytes(
-                recv_data[recv_total + 1..recv_total + 3]
-                    .try_into()
-                    .unwrap(),
-            );
+            let name_len = name.len() as u16;
             debug!(
-                "type: {}, {}, {}, {}, {}",
+                "type: {}, {}, {}, {}",
                 r#type,
                 name_len,
-                recv_total,
                 offset_of!(LinuxDirent, d_name),
                 dirp_ptr as usize
             );
@@ -445,12 +824,12 @@ impl Client {
                 break;
             }
             dirp.d_ino = 1;
-            dirp.d_off = offset;
+            // the cursor of the entry, where telldir and seekdir go on from.
+            dirp.d_off = d_off;
             dirp.d_reclen = offset_of!(LinuxDirent, d_name) as u16 + name_len + 2;
             unsafe {
                 std::ptr::copy(
-                    recv_data[recv_total + 3..recv_total + 3 + name_len as usize].as_ptr()
-                        as *const i8,
+                    name.as_ptr() as *const i8,
                     dirp.d_name.as_mut_ptr(),
                     name_len as usize,
                 );
@@ -459,16 +838,19 @@ impl Client {
                 *name_after.add(1) = r#type;
                 dirp_ptr = dirp_ptr.add(dirp.d_reclen as usize);
             }
-            offset += 1;
+            if self.attr_prefetch && name != b"." && name != b".." {
+                names.push(String::from_utf8_lossy(name).into_owned());
+            }
+            offset = d_off;
             total += dirp.d_reclen as usize;
-            recv_total += (name_len + 3) as usize;
         }
+        self.prefetch_attrs(pathname, names);
         debug!("getdents_remote {}", pathname);
         Ok((total as isize, offset))
     }
 
     pub fn getdents64_remote(
-        &self,
+        &'static self,
         pathname: &str,
         dirp: &mut [u8],
         dirp_offset: i64,
@@ -510,31 +892,26 @@ impl Client {
             return Err(status);
         }
 
+        let entries =
+            bytes_as_dir_entries(&recv_data[..recv_data_length]).map_err(|_| libc::EIO)?;
         let dirp_len = dirp.len();
         let mut dirp_ptr = dirp.as_ptr();
         let mut total = 0;
-        let mut recv_total = 0;
         let mut offset = dirp_offset;
-        while recv_total < recv_data_length {
+        let mut names = Vec::new();
+        for (r#type, d_off, name) in entries {
             let dirp = unsafe { (dirp_ptr as *mut dirent64).as_mut().unwrap() };
-            let r#type =
-                u8::from_le_bytes(recv_data[recv_total..recv_total + 1].try_into().unwrap());
-            let name_len = u16::from_le_bytes(
-                recv_data[recv_total + 1..recv_total + 3]
-                    .try_into()
-                    .unwrap(),
-            );
+            let name_len = name.len() as u16;
             if total + offset_of!(dirent64, d_name) + name_len as usize + 1 > dirp_len {
                 break;
             }
             dirp.d_ino = 1;
-            dirp.d_off = offset;
+            dirp.d_off = d_off;
             dirp.d_reclen = offset_of!(dirent64, d_name) as u16 + name_len + 1;
             dirp.d_type = r#type;
             unsafe {
                 std::ptr::copy(
-                    recv_data[recv_total + 3..recv_total + 3 + name_len as usize].as_ptr()
-                        as *const i8,
+                    name.as_ptr() as *const i8,
                     dirp.d_name.as_mut_ptr(),
                     name_len as usize,
                 );
@@ -542,16 +919,20 @@ impl Client {
                 *name_after = b'\0';
                 dirp_ptr = dirp_ptr.add(dirp.d_reclen as usize);
             }
-            offset += 1;
+            if self.attr_prefetch && name != b"." && name != b".." {
+                names.push(String::from_utf8_lossy(name).into_owned());
+            }
+            offset = d_off;
             total += dirp.d_reclen as usize;
-            recv_total += (name_len + 3) as usize;
         }
+        self.prefetch_attrs(pathname, names);
         Ok((total as isize, offset))
     }
 
     pub fn unlink_remote(&self, pathname: &str) -> Result<(), i32> {
         debug!("unlink_remote {}", pathname);
         let (parent, name) = path_split(pathname).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[pathname, &parent]);
         let server_address = self.get_connection_address(&parent);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -584,8 +965,128 @@ impl Client {
         }
     }
 
+    // create_temp_file creates an unnamed file in the directory dir for
+    // O_TMPFILE, and returns its path.
+    pub fn create_temp_file(&self, dir: &str, mode: u32) -> Result<String, i32> {
+        debug!("create_temp_file {}", dir);
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        self.stat_remote(dir, unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut stat as *mut libc::stat as *mut u8,
+                std::mem::size_of::<libc::stat>(),
+            )
+        })?;
+        if stat.st_mode & S_IFMT != S_IFDIR {
+            return Err(libc::ENOTDIR);
+        }
+        let pathname = temp_file_path(dir);
+        let (uid, gid) = self.owner();
+        let send_meta_data = bincode::serialize(&CreateFileSendMetaData {
+            mode,
+            umask: 0,
+            flags: O_CREAT | O_EXCL | O_RDWR,
+            name: "".to_string(),
+            uid,
+            gid,
+            size_hint: 0,
+            append_only: false,
+        })
+        .unwrap();
+        self.handle.block_on(self.sender.create_no_parent(
+            &self.get_connection_address(&pathname),
+            OperationType::CreateFileNoParent,
+            &pathname,
+            &send_meta_data,
+        ))?;
+        Ok(pathname)
+    }
+
+    // link_temp_file gives the unnamed file temp_path the name pathname.
+    pub fn link_temp_file(&self, temp_path: &str, pathname: &str) -> Result<(), i32> {
+        debug!("link_temp_file {} {}", temp_path, pathname);
+        let (parent, name) = path_split(pathname).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[pathname, &parent]);
+        self.handle.block_on(self.sender.link_temp_file(
+            &self.get_connection_address(&parent),
+            &parent,
+            &name,
+            temp_path,
+        ))?;
+        self.negative_cache.invalidate_dir(&parent);
+        Ok(())
+    }
+
+    // symlink_remote creates pathname, a symbolic link to target.
+    pub fn symlink_remote(&self, target: &str, pathname: &str) -> Result<(), i32> {
+        debug!("symlink_remote {} {}", target, pathname);
+        let (parent, name) = path_split(pathname).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[pathname, &parent]);
+        self.handle.block_on(self.sender.create_symlink(
+            &self.get_connection_address(&parent),
+            &parent,
+            &name,
+            target,
+            self.owner(),
+        ))?;
+        self.negative_cache.invalidate_dir(&parent);
+        Ok(())
+    }
+
+    // link_remote creates newpath, a hard link to the file oldpath.
+    pub fn link_remote(&self, oldpath: &str, newpath: &str) -> Result<(), i32> {
+        debug!("link_remote {} {}", oldpath, newpath);
+        let (parent, name) = path_split(newpath).map_err(|_| libc::EINVAL)?;
+        self.forget_attrs(&[oldpath, newpath, &parent]);
+        self.handle.block_on(self.sender.link(
+            &self.get_connection_address(&parent),
+            &parent,
+            &name,
+            oldpath,
+        ))?;
+        self.negative_cache.invalidate_dir(&parent);
+        Ok(())
+    }
+
+    // delete_temp_file removes an unnamed file closed before being linked.
+    pub fn delete_temp_file(&self, temp_path: &str) -> Result<(), i32> {
+        debug!("delete_temp_file {}", temp_path);
+        self.handle.block_on(self.sender.delete_no_parent(
+            &self.get_connection_address(temp_path),
+            OperationType::DeleteFileNoParent,
+            temp_path,
+            &[],
+        ))
+    }
+
+    // statfs_remote answers the statfs of the volume with the space of all
+    // the servers, not the space of the local root.
+    pub fn statfs_remote(&self, statfsbuf: &mut statfs) -> Result<(), i32> {
+        debug!("statfs_remote");
+        let space = self
+            .handle
+            .block_on(self.get_stat_fs())
+            .map_err(status_to_errno)?;
+        *statfsbuf = unsafe { std::mem::zeroed() };
+        statfsbuf.f_type = FUSE_SUPER_MAGIC;
+        statfsbuf.f_bsize = STATFS_BLOCK_SIZE as _;
+        statfsbuf.f_frsize = STATFS_BLOCK_SIZE as _;
+        statfsbuf.f_blocks = space.total / STATFS_BLOCK_SIZE;
+        statfsbuf.f_bfree = space.free / STATFS_BLOCK_SIZE;
+        statfsbuf.f_bavail = space.free / STATFS_BLOCK_SIZE;
+        statfsbuf.f_namelen = 255;
+        Ok(())
+    }
+
     pub fn stat_remote(&self, pathname: &str, statbuf: &mut [u8]) -> Result<(), i32> {
         debug!("stat_remote {}", pathname);
+        if self.negative_cache.contains(pathname) {
+            return Err(libc::ENOENT);
+        }
+        if let Some(attr) = self.attr_cache.take(pathname) {
+            self.note_page_stamp(pathname, page_stamp(&attr));
+            tostat(&attr, statbuf);
+            return Ok(());
+        }
         let server_address = self.get_connection_address(pathname);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -612,16 +1113,28 @@ impl Client {
         )) {
             return Err(libc::EIO);
         }
+        if status == libc::ENOENT {
+            self.negative_cache.insert(pathname);
+        }
         if status != 0 {
             return Err(status);
         }
 
+        self.note_page_stamp(pathname, page_stamp(&file_attr));
         tostat(&file_attr, statbuf);
         Ok(())
     }
 
     pub fn statx_remote(&self, pathname: &str, statxbuf: &mut [u8]) -> Result<(), i32> {
         debug!("statx_remote {}", pathname);
+        if self.negative_cache.contains(pathname) {
+            return Err(libc::ENOENT);
+        }
+        if let Some(attr) = self.attr_cache.take(pathname) {
+            self.note_page_stamp(pathname, page_stamp(&attr));
+            tostatx(&attr, statxbuf);
+            return Ok(());
+        }
         let server_address = self.get_connection_address(pathname);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -648,109 +1161,237 @@ impl Client {
         )) {
             return Err(libc::EIO);
         }
+        if status == libc::ENOENT {
+            self.negative_cache.insert(pathname);
+        }
         if status != 0 {
             return Err(status);
         }
 
+        self.note_page_stamp(pathname, page_stamp(&file_attr));
         tostatx(&file_attr, statxbuf);
         Ok(())
     }
 
     pub fn pread_remote(&self, pathname: &str, buf: &mut [u8], offset: i64) -> Result<isize, i32> {
-        debug!("pread_remote {}", pathname);
-        let mut idx = offset / CHUNK_SIZE;
-        let end_idx = offset + buf.len() as i64;
-        let mut chunk_left = offset;
-        let mut chunk_right = std::cmp::min((idx + 1) * CHUNK_SIZE, offset + buf.len() as i64);
-        let mut inbuf = buf;
+        let iov = iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        self.preadv_remote(pathname, &[iov], offset)
+    }
+
+    // preadv_remote reads into the iovecs directly, every chunk request
+    // scatters its response into the part of the iovecs it covers. A chunk
+    // read shorter than asked is asked again for the rest, it ends at the end
+    // of the file only when nothing more is read. A failure after a part was
+    // read returns that part.
+    pub fn preadv_remote(&self, pathname: &str, iov: &[iovec], offset: i64) -> Result<isize, i32> {
+        debug!("preadv_remote {}", pathname);
+        let total = iov.iter().map(|v| v.iov_len).sum::<usize>();
+        let end_idx = offset + total as i64;
         self.handle.block_on(async {
             let mut result = 0;
-            while chunk_left < end_idx {
-                // let file_path = format!("{}_{}", pathname, idx);
-                let server_address = self.get_connection_address(&pathname);
-                let mut status = 0i32;
-                let mut rsp_flags = 0u32;
+            while result < total {
+                let chunk_left = offset + result as i64;
+                let chunk_right =
+                    std::cmp::min((chunk_left / CHUNK_SIZE + 1) * CHUNK_SIZE, end_idx);
+                let chunk_size = (chunk_right - chunk_left) as usize;
+                let cached = self.read_pages(
+                    pathname,
+                    chunk_left,
+                    &mut iovec_slices(iov, result, result + chunk_size),
+                );
+                if let Some(length) = cached {
+                    result += length;
+                    if length < chunk_size {
+                        break;
+                    }
+                    continue;
+                }
+                let mut length = 0;
+                while length < chunk_size {
+                    let mut chunk_bufs = iovec_slices(iov, result + length, result + chunk_size);
+                    match self
+                        .read_server(pathname, chunk_left + length as i64, &mut chunk_bufs)
+                        .await
+                    {
+                        Ok(0) => break,
+                        Ok(read) => length += read,
+                        Err(_) if result + length > 0 => return Ok((result + length) as isize),
+                        Err(e) => return Err(e),
+                    }
+                }
+                self.fill_pages(
+                    pathname,
+                    chunk_left,
+                    &iovec_slices(iov, result, result + chunk_size),
+                    length,
+                );
+                result += length;
+                if length < chunk_size {
+                    break;
+                }
+            }
+            Ok(result as isize)
+        })
+    }
 
-                let mut recv_meta_data_length = 0usize;
-                let mut recv_data_length = 0usize;
-                let (chunk_buf, next_buf) = inbuf.split_at_mut((chunk_right - chunk_left) as usize);
-                inbuf = next_buf;
-                let send_meta_data = bincode::serialize(&ReadFileSendMetaData {
+    // read_server reads the data of pathname at offset into bufs, and
+    // returns its length.
+    async fn read_server(
+        &self,
+        pathname: &str,
+        offset: i64,
+        bufs: &mut [&mut [u8]],
+    ) -> Result<usize, i32> {
+        let server_address = self.get_connection_address(pathname);
+        let send_meta_data = bincode::serialize(&ReadFileSendMetaData {
+            offset,
+            size: bufs.iter().map(|buf| buf.len()).sum::<usize>() as u32,
+        })
+        .unwrap();
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        if let Err(e) = self
+            .client
+            .call_remote_vectored(
+                &server_address,
+                OperationType::ReadFile.into(),
+                0,
+                pathname,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                bufs,
+                REQUEST_TIMEOUT,
+            )
+            .await
+        {
+            error!("read {} error: {}", pathname, e);
+            return Err(libc::EIO);
+        }
+        match status {
+            0 => Ok(recv_data_length),
+            status => Err(status),
+        }
+    }
+
+    pub fn pwrite_remote(&self, pathname: &str, buf: &[u8], offset: i64) -> Result<isize, i32> {
+        let iov = iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        self.pwritev_remote(pathname, &[iov], offset)
+    }
+
+    // pwritev_remote sends the iovecs directly, without copying them into one
+    // buffer. A chunk the server writes partly is sent again for the rest. A
+    // failure after a part was written returns that part.
+    pub fn pwritev_remote(&self, pathname: &str, iov: &[iovec], offset: i64) -> Result<isize, i32> {
+        debug!("pwritev_remote {}", pathname);
+        self.forget_attrs(&[pathname]);
+        let total = iov.iter().map(|v| v.iov_len).sum::<usize>();
+        self.forget_pages(pathname, offset, total);
+        let end_idx = offset + total as i64;
+        self.handle.block_on(async {
+            let mut result = 0;
+            let mut refreshed = false;
+            while result < total {
+                let chunk_left = offset + result as i64;
+                let chunk_right =
+                    std::cmp::min((chunk_left / CHUNK_SIZE + 1) * CHUNK_SIZE, end_idx);
+                let (server_address, epoch) = self.get_connection_route(pathname);
+                let send_meta_data = bincode::serialize(&WriteFileSendMetaData {
                     offset: chunk_left,
-                    size: chunk_buf.len() as u32,
+                    epoch,
+                    version: 0,
                 })
                 .unwrap();
-                if let Err(_) = self
+                let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+                    (0, 0, 0, 0);
+                let chunk_bufs = iovec_slices(iov, result, (chunk_right - offset) as usize);
+                let chunk_bufs: Vec<&[u8]> = chunk_bufs.into_iter().map(|buf| &*buf).collect();
+
+                let mut recv_meta_data = [0u8; std::mem::size_of::<u64>()];
+                if let Err(e) = self
                     .client
-                    .call_remote(
+                    .call_remote_vectored(
                         &server_address,
-                        OperationType::ReadFile.into(),
+                        OperationType::WriteFile.into(),
                         0,
-                        &pathname,
+                        pathname,
                         &send_meta_data,
-                        &[],
+                        &chunk_bufs,
                         &mut status,
                         &mut rsp_flags,
                         &mut recv_meta_data_length,
                         &mut recv_data_length,
+                        &mut recv_meta_data,
                         &mut [],
-                        chunk_buf,
                         REQUEST_TIMEOUT,
                     )
                     .await
                 {
+                    error!("pwritev_remote error: {}", e);
+                    if result > 0 {
+                        break;
+                    }
                     return Err(libc::EIO);
                 }
-                if status != 0 {
-                    return Err(status);
+                // the owner changed since our hash ring, retry once with the
+                // manager's one.
+                if status == STALE_EPOCH && !refreshed {
+                    refreshed = true;
+                    self.refresh_hash_ring().await?;
+                    continue;
                 }
-                idx += 1;
-                result += recv_data_length as isize;
-                if recv_data_length < chunk_right as usize - chunk_left as usize {
-                    break;
+                let size = match status {
+                    0 => u64::from_le_bytes(recv_meta_data) as usize,
+                    _ => 0,
+                };
+                if size == 0 {
+                    if result > 0 {
+                        break;
+                    }
+                    return Err(if status == 0 { libc::EIO } else { status });
                 }
-                chunk_left = chunk_right;
-                chunk_right = std::cmp::min(chunk_right + CHUNK_SIZE, end_idx);
+                result += size;
             }
-            Ok(result)
+            Ok(result as isize)
         })
     }
 
-    pub fn preadv_remote(&self, _pathname: &str, _buf: &[iovec], _offset: i64) -> isize {
-        debug!("preadv_remote");
-        todo!()
-    }
-
-    pub fn pwrite_remote(&self, pathname: &str, buf: &[u8], offset: i64) -> Result<isize, i32> {
-        debug!("pwrite_remote {}", pathname);
-        let mut idx = offset / CHUNK_SIZE;
-        let end_idx = offset + buf.len() as i64;
-        let mut chunk_left = offset;
-        let mut chunk_right = std::cmp::min((idx + 1) * CHUNK_SIZE, end_idx);
-        let mut outbuf = buf;
+    // appendv_remote sends the iovecs in one request to be written at the end
+    // of the file, where its server chooses, and returns the bytes written
+    // and the offset after them.
+    pub fn appendv_remote(&self, pathname: &str, iov: &[iovec]) -> Result<(isize, i64), i32> {
+        debug!("appendv_remote {}", pathname);
+        self.forget_attrs(&[pathname]);
+        let total = iov.iter().map(|v| v.iov_len).sum::<usize>();
+        let bufs = iovec_slices(iov, 0, total);
+        let bufs: Vec<&[u8]> = bufs.into_iter().map(|buf| &*buf).collect();
         self.handle.block_on(async {
-            let mut result = 0;
-            while chunk_left < end_idx {
-                // let file_path = format!("{}_{}", pathname, idx);
-                let server_address = self.get_connection_address(&pathname);
-                // println!("write: {} {}", file_path, server_address);
-                let mut status = 0i32;
-                let mut rsp_flags = 0u32;
-                let (chunk_buf, next_buf) = outbuf.split_at((chunk_right - chunk_left) as usize);
-                outbuf = next_buf;
-                let mut recv_meta_data_length = 0usize;
-                let mut recv_data_length = 0usize;
-
-                let mut recv_meta_data = [0u8; std::mem::size_of::<isize>()];
-                if let Err(_) = self
+            let mut refreshed = false;
+            loop {
+                let (server_address, epoch) = self.get_connection_route(pathname);
+                let send_meta_data = bincode::serialize(&AppendFileSendMetaData { epoch }).unwrap();
+                let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+                    (0, 0, 0, 0);
+                let mut recv_meta_data = [0u8; 16];
+                if let Err(e) = self
                     .client
-                    .call_remote(
+                    .call_remote_vectored(
                         &server_address,
-                        OperationType::WriteFile.into(),
+                        OperationType::AppendFile.into(),
                         0,
-                        &pathname,
-                        &chunk_left.to_le_bytes(),
-                        chunk_buf,
+                        pathname,
+                        &send_meta_data,
+                        &bufs,
                         &mut status,
                         &mut rsp_flags,
                         &mut recv_meta_data_length,
@@ -761,25 +1402,74 @@ impl Client {
                     )
                     .await
                 {
+                    error!("appendv_remote error: {}", e);
                     return Err(libc::EIO);
                 }
+                // the owner changed since our hash ring, retry once with the
+                // manager's one.
+                if status == STALE_EPOCH && !refreshed {
+                    refreshed = true;
+                    self.refresh_hash_ring().await?;
+                    continue;
+                }
                 if status != 0 {
-                    return Err(status);
+                    return Err(status_to_errno(status));
                 }
-                let size = isize::from_le_bytes(recv_meta_data);
-                idx += 1;
-                chunk_left = chunk_right;
-                chunk_right = std::cmp::min(chunk_right + CHUNK_SIZE, end_idx);
-                result += size as isize;
+                let appended: AppendFileRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                        .map_err(|_| libc::EIO)?;
+                if appended.size == 0 && total > 0 {
+                    return Err(libc::EIO);
+                }
+                self.forget_pages(pathname, appended.offset as i64, appended.size as usize);
+                return Ok((
+                    appended.size as isize,
+                    (appended.offset + appended.size) as i64,
+                ));
             }
-            Ok(result)
         })
     }
+}
+
+// open_page_cache opens the page cache of the config, if any.
+fn open_page_cache() -> Option<SharedPageCache> {
+    if CONFIG.page_cache.is_empty() {
+        return None;
+    }
+    match SharedPageCache::open(&CONFIG.page_cache, CONFIG.page_cache_size << 20) {
+        Ok(page_cache) => Some(page_cache),
+        Err(e) => {
+            error!(
+                "open page cache {} failed: {}",
+                CONFIG.page_cache,
+                status_to_string(e)
+            );
+            None
+        }
+    }
+}
 
-    pub fn pwritev_remote(&self, _pathname: &str, _buf: &[iovec], _offset: i64) -> isize {
-        debug!("pwritev_remote");
-        todo!()
+// iovec_slices returns the parts of the iovecs between byte begin and byte end,
+// counted from the start of the first iovec.
+#[allow(clippy::mut_from_ref)]
+fn iovec_slices(iov: &[iovec], begin: usize, end: usize) -> Vec<&mut [u8]> {
+    let mut slices = Vec::new();
+    let mut position = 0;
+    for v in iov {
+        let (left, right) = (position, position + v.iov_len);
+        position = right;
+        if right <= begin || v.iov_len == 0 {
+            continue;
+        }
+        if left >= end {
+            break;
+        }
+        let from = begin.saturating_sub(left);
+        let to = std::cmp::min(end, right) - left;
+        let buf = unsafe { std::slice::from_raw_parts_mut(v.iov_base as *mut u8, v.iov_len) };
+        slices.push(&mut buf[from..to]);
     }
+    slices
 }
 
 lazy_static! {
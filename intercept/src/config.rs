@@ -0,0 +1,193 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The config of the library, read once when it is loaded, in order:
+ * - the yaml file at SEALFS_INTERCEPT_CONFIG, or `intercept.yaml` in the
+ *   SEALFS_CONFIG_PATH directory, if any;
+ * - the overrides of the program in the `processes` map of the file, keyed
+ *   by the name the program was run with, e.g. `git`;
+ * - the SEALFS_<KEY> variables, e.g. SEALFS_ATTR_CACHE_SIZE, a list being
+ *   comma separated. They are set per process by containers and schedulers.
+ */
+use std::{collections::BTreeMap, fmt::Debug, str::FromStr};
+
+use sealfs::common::logging::LogFormat;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub manager_address: String,
+    pub all_servers_address: Vec<String>,
+    pub heartbeat: bool,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    // the paths under mount_point are the files of volume_name.
+    pub mount_point: String,
+    pub volume_name: String,
+    // get the attrs of the entries of a directory listed, for the stats that
+    // follow, and keep up to attr_cache_size of them.
+    pub attr_prefetch: bool,
+    pub attr_cache_size: usize,
+    // the files created by root are owned by nobody.
+    pub root_squash: bool,
+    pub compress_rpc: bool,
+    pub checksum_rpc: bool,
+    // the page cache shared with the other clients of the host, e.g. in
+    // /dev/shm, off when empty, and its size in MiB when it is created.
+    pub page_cache: String,
+    pub page_cache_size: usize,
+    // program name -> the keys it overrides.
+    #[serde(skip_serializing)]
+    pub processes: BTreeMap<String, Value>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            manager_address: "127.0.0.1:8081".to_string(),
+            all_servers_address: Vec::new(),
+            heartbeat: false,
+            log_level: "warn".to_string(),
+            log_format: LogFormat::Text,
+            mount_point: "/mnt/fs".to_string(),
+            volume_name: "".to_string(),
+            attr_prefetch: true,
+            attr_cache_size: 65536,
+            root_squash: false,
+            compress_rpc: false,
+            checksum_rpc: false,
+            page_cache: "".to_string(),
+            page_cache_size: 1024,
+            processes: BTreeMap::new(),
+        }
+    }
+}
+
+fn var<T: FromStr>(key: &str) -> Option<T>
+where
+    T::Err: Debug,
+{
+    std::env::var(key).ok().map(|value| match value.parse() {
+        Ok(value) => value,
+        Err(e) => panic!("invalid {}={:?}: {:?}", key, value, e),
+    })
+}
+
+// flag is set by any value but 0 and false.
+fn flag(key: &str) -> Option<bool> {
+    std::env::var(key)
+        .ok()
+        .map(|value| value != "0" && value != "false")
+}
+
+impl Config {
+    // merge sets the keys of overrides, a yaml map.
+    fn merge(self, overrides: &Value) -> Result<Self, serde_yaml::Error> {
+        let processes = self.processes.clone();
+        let mut config = serde_yaml::to_value(&self)?;
+        if let (Value::Mapping(config), Value::Mapping(overrides)) = (&mut config, overrides) {
+            for (key, value) in overrides {
+                config.insert(key.clone(), value.clone());
+            }
+        }
+        let mut config: Self = serde_yaml::from_value(config)?;
+        config.processes = processes;
+        Ok(config)
+    }
+
+    fn merge_env(&mut self) {
+        if let Some(address) = var("SEALFS_MANAGER_ADDRESS") {
+            self.manager_address = address;
+        }
+        if let Ok(addresses) = std::env::var("SEALFS_ALL_SERVERS_ADDRESS") {
+            self.all_servers_address = addresses
+                .split(',')
+                .filter(|address| !address.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(heartbeat) = flag("SEALFS_HEARTBEAT") {
+            self.heartbeat = heartbeat;
+        }
+        if let Some(log_level) = var("SEALFS_LOG_LEVEL") {
+            self.log_level = log_level;
+        }
+        if let Some(log_format) = var("SEALFS_LOG_FORMAT") {
+            self.log_format = log_format;
+        }
+        if let Some(mount_point) = var("SEALFS_MOUNT_POINT") {
+            self.mount_point = mount_point;
+        }
+        if let Some(volume_name) = var("SEALFS_VOLUME_NAME") {
+            self.volume_name = volume_name;
+        }
+        if let Some(attr_prefetch) = flag("SEALFS_ATTR_PREFETCH") {
+            self.attr_prefetch = attr_prefetch;
+        }
+        if let Some(attr_cache_size) = var("SEALFS_ATTR_CACHE_SIZE") {
+            self.attr_cache_size = attr_cache_size;
+        }
+        if let Some(root_squash) = flag("SEALFS_ROOT_SQUASH") {
+            self.root_squash = root_squash;
+        }
+        if let Some(compress_rpc) = flag("SEALFS_COMPRESS_RPC") {
+            self.compress_rpc = compress_rpc;
+        }
+        if let Some(checksum_rpc) = flag("SEALFS_CHECKSUM_RPC") {
+            self.checksum_rpc = checksum_rpc;
+        }
+        if let Some(page_cache) = var("SEALFS_PAGE_CACHE") {
+            self.page_cache = page_cache;
+        }
+        if let Some(page_cache_size) = var("SEALFS_PAGE_CACHE_SIZE") {
+            self.page_cache_size = page_cache_size;
+        }
+    }
+}
+
+// program_name returns the name the process was run with, without its
+// directory. std::env::args is not set yet when the library is loaded.
+fn program_name() -> Option<String> {
+    let cmdline = std::fs::read("/proc/self/cmdline").ok()?;
+    let arg0 = cmdline.split(|&b| b == 0).next()?;
+    let arg0 = String::from_utf8_lossy(arg0);
+    Some(arg0.rsplit('/').next().unwrap_or(&arg0).to_string())
+}
+
+fn config_path() -> Option<String> {
+    if let Ok(path) = std::env::var("SEALFS_INTERCEPT_CONFIG") {
+        return Some(path);
+    }
+    let path = format!(
+        "{}/intercept.yaml",
+        std::env::var("SEALFS_CONFIG_PATH").ok()?
+    );
+    std::path::Path::new(&path).exists().then_some(path)
+}
+
+pub fn load_config() -> Config {
+    let mut config = match config_path() {
+        Some(path) => {
+            let yaml = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("read {} failed: {}", path, e));
+            let config: Config = serde_yaml::from_str(&yaml)
+                .unwrap_or_else(|e| panic!("parse {} failed: {}", path, e));
+            match program_name().and_then(|name| config.processes.get(&name).cloned()) {
+                Some(overrides) => config
+                    .merge(&overrides)
+                    .unwrap_or_else(|e| panic!("parse the overrides of {} failed: {}", path, e)),
+                None => config,
+            }
+        }
+        None => Config::default(),
+    };
+    config.merge_env();
+    if config.mount_point.ends_with('/') {
+        config.mount_point.pop();
+    }
+    config
+}
@@ -1,5 +1,6 @@
 use crossbeam_channel::{bounded, Receiver, Sender};
 use dashmap::DashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum FdType {
@@ -24,8 +25,13 @@ lazy_static::lazy_static! {
         (s, r)
     };
     static ref FD_TB: DashMap<i32, FdAttr> = DashMap::new();
+    // fd -> the open file description it refers to, shared by the copies of
+    // the fd in the forked processes and unique among the processes.
+    static ref DESCRIPTIONS: DashMap<i32, u64> = DashMap::new();
 }
 
+static NEXT_DESCRIPTION: AtomicU32 = AtomicU32::new(0);
+
 pub fn insert_attr(attr: FdAttr) -> Option<i32> {
     let fd = match IDLE_FD.1.recv() {
         Ok(value) => value,
@@ -33,12 +39,15 @@ pub fn insert_attr(attr: FdAttr) -> Option<i32> {
     };
 
     FD_TB.insert(fd, attr);
+    let description = NEXT_DESCRIPTION.fetch_add(1, Ordering::Relaxed);
+    DESCRIPTIONS.insert(fd, (std::process::id() as u64) << 32 | description as u64);
     return Some(fd);
 }
 
 pub fn remove_attr(fd: i32) -> bool {
     match FD_TB.remove(&fd) {
         Some(_) => {
+            DESCRIPTIONS.remove(&fd);
             IDLE_FD.0.send(fd).unwrap();
             true
         }
@@ -53,6 +62,10 @@ pub fn get_attr(fd: i32) -> Option<FdAttr> {
     }
 }
 
+pub fn get_description(fd: i32) -> Option<u64> {
+    DESCRIPTIONS.get(&fd).map(|description| *description)
+}
+
 pub fn set_attr(fd: i32, attr: FdAttr) -> bool {
     match FD_TB.get_mut(&fd) {
         Some(mut value) => {
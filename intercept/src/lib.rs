@@ -1,44 +1,45 @@
 pub mod client;
+pub mod config;
 pub mod file_desc;
 pub mod path;
 pub mod syscall_intercept;
 pub mod test_log;
+pub mod trace;
 
-use client::CLIENT;
-use env_logger::fmt;
+use client::{LockOwner, CLIENT};
+use config::{load_config, Config};
 use file_desc::{FdAttr, FdType};
 use lazy_static::lazy_static;
 use libc::{
-    c_char, iovec, stat, statx, SYS_close, SYS_creat, SYS_fstat, SYS_fsync, SYS_ftruncate,
-    SYS_getdents, SYS_getdents64, SYS_lseek, SYS_lstat, SYS_mkdir, SYS_mkdirat, SYS_open,
-    SYS_openat, SYS_pread64, SYS_preadv, SYS_pwrite64, SYS_pwritev, SYS_read, SYS_readlink,
-    SYS_readv, SYS_rename, SYS_renameat, SYS_rmdir, SYS_stat, SYS_statx, SYS_truncate, SYS_unlink,
-    SYS_write, SYS_writev, AT_FDCWD, O_CREAT, O_DIRECTORY, O_TRUNC, O_WRONLY, SEEK_CUR, SEEK_END,
-    SEEK_SET, S_IFLNK,
+    c_char, flock, iovec, stat, statx, SYS_chdir, SYS_close, SYS_creat, SYS_exit_group, SYS_fchdir,
+    SYS_fcntl, SYS_fdatasync, SYS_flock, SYS_fstat, SYS_fstatfs, SYS_fsync, SYS_ftruncate,
+    SYS_getcwd, SYS_getdents, SYS_getdents64, SYS_link, SYS_linkat, SYS_lseek, SYS_lstat,
+    SYS_mkdir, SYS_mkdirat, SYS_open, SYS_openat, SYS_pread64, SYS_preadv, SYS_pwrite64,
+    SYS_pwritev, SYS_read, SYS_readlink, SYS_readlinkat, SYS_readv, SYS_rename, SYS_renameat,
+    SYS_rmdir, SYS_stat, SYS_statfs, SYS_statx, SYS_symlink, SYS_symlinkat, SYS_truncate,
+    SYS_unlink, SYS_write, SYS_writev, AT_EMPTY_PATH, AT_FDCWD, O_APPEND, O_CREAT, O_DIRECTORY,
+    O_EXCL, O_TMPFILE, O_TRUNC, O_WRONLY, SEEK_CUR, SEEK_DATA, SEEK_END, SEEK_HOLE, SEEK_SET,
+    S_IFDIR, S_IFLNK, S_IFMT,
 };
 use log::info;
-use path::{get_absolutepath, get_remotepath, CURRENT_DIR, MOUNT_POINT};
+use path::{
+    current_dir, get_absolutepath, get_remotepath, set_current_dir, MOUNT_POINT, VOLUME_NAME,
+};
 use sealfs::common::errors::status_to_string;
 use sealfs::common::info_syncer::{init_network_connections, ClientStatusMonitor};
-use serde::{Deserialize, Serialize};
+use sealfs::common::logging::init_logger;
+use sealfs::common::util::is_temp_file;
 use std::cell::Cell;
-use std::ffi::CStr;
-use std::str::FromStr;
+use std::ffi::{CStr, CString};
 use syscall_intercept::*;
 
 const STAT_SIZE: usize = std::mem::size_of::<stat>();
 const STATX_SIZE: usize = std::mem::size_of::<statx>();
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    manager_address: String,
-    all_servers_address: Vec<String>,
-    heartbeat: bool,
-    log_level: String,
-}
-
 pub async fn init_client_async(manager_address: String, volume_name: String) {
     info!("init client");
+    CLIENT.client.set_compression(CONFIG.compress_rpc);
+    CLIENT.client.set_checksum(CONFIG.checksum_rpc);
     init_network_connections(manager_address, CLIENT.clone()).await;
 
     info!("connect_servers");
@@ -60,19 +61,15 @@ pub async fn init_client_async(manager_address: String, volume_name: String) {
 
 extern "C" fn initialize() {
     unsafe {
+        // read before the hook is set, the hook needs the mount point.
+        lazy_static::initialize(&CONFIG);
         set_hook_fn(dispatch);
-        let manager_address =
-            std::env::var("SEALFS_MANAGER_ADDRESS").unwrap_or("127.0.0.1:8081".to_string());
-        let volume_name = match std::env::var("SEALFS_VOLUME_NAME") {
-            Ok(name) => name,
-            Err(_) => panic!("SEALFS_VOLUME_NAME is not set"),
-        };
-        let log_level = std::env::var("SEALFS_LOG_LEVEL").unwrap_or("warn".to_string());
-        let mut builder = env_logger::Builder::from_default_env();
-        builder
-            .format_timestamp(Some(fmt::TimestampPrecision::Millis))
-            .filter(None, log::LevelFilter::from_str(&log_level).unwrap());
-        builder.init();
+        let manager_address = CONFIG.manager_address.clone();
+        if CONFIG.volume_name.is_empty() {
+            panic!("SEALFS_VOLUME_NAME is not set");
+        }
+        let volume_name = CONFIG.volume_name.clone();
+        init_logger("intercept", &CONFIG.log_level, CONFIG.log_format);
 
         RUNTIME.block_on(init_client_async(manager_address, volume_name));
     }
@@ -84,6 +81,7 @@ extern "C" fn initialize() {
 pub static INITIALIZE_CTOR: extern "C" fn() = self::initialize;
 
 lazy_static! {
+    pub static ref CONFIG: Config = load_config();
     static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -116,8 +114,28 @@ impl Drop for InterceptGuard {
     }
 }
 
-#[allow(non_upper_case_globals)]
+#[allow(clippy::too_many_arguments)]
 extern "C" fn dispatch(
+    syscall_number: isize,
+    arg0: isize,
+    arg1: isize,
+    arg2: isize,
+    arg3: isize,
+    arg4: isize,
+    arg5: isize,
+    result: &mut isize,
+) -> InterceptResult {
+    let intercept_result =
+        dispatch_syscall(syscall_number, arg0, arg1, arg2, arg3, arg4, arg5, result);
+    if matches!(intercept_result, InterceptResult::Hook) && trace::enabled() {
+        trace::record(syscall_number, [arg0, arg1, arg2, arg3, arg4], *result);
+    }
+    intercept_result
+}
+
+#[allow(non_upper_case_globals)]
+#[allow(clippy::too_many_arguments)]
+fn dispatch_syscall(
     syscall_number: isize,
     arg0: isize,
     arg1: isize,
@@ -133,17 +151,24 @@ extern "C" fn dispatch(
     };
     match syscall_number as i64 {
         // int close(int fd)
-        SYS_close => {
-            if file_desc::remove_attr(arg0 as i32) {
+        SYS_close => match file_desc::get_attr(arg0 as i32) {
+            Some(attr) => {
+                let description = file_desc::get_description(arg0 as i32).unwrap_or_default();
+                file_desc::remove_attr(arg0 as i32);
+                CLIENT.release_locks(&attr.pathname, LockOwner::Process(std::process::id()));
+                CLIENT.release_locks(&attr.pathname, LockOwner::File(description));
+                // an unnamed file is gone once closed.
+                if is_temp_file(&attr.pathname) {
+                    let _ = CLIENT.delete_temp_file(&attr.pathname);
+                }
                 *result = 0;
                 InterceptResult::Hook
-            } else {
-                InterceptResult::Forward
             }
-        }
+            None => InterceptResult::Forward,
+        },
         // int creat(const char *pathname, mode_t mode)
         SYS_creat => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -164,7 +189,7 @@ extern "C" fn dispatch(
                         pathname: remote_pathname,
                         r#type: FdType::File,
                         offset: 0,
-                        flags: arg1 as i32,
+                        flags: O_CREAT | O_WRONLY | O_TRUNC,
                     }) {
                         Some(value) => value,
                         None => {
@@ -181,7 +206,7 @@ extern "C" fn dispatch(
         }
         // int open(const char *pathname, int flags, mode_t mode)
         SYS_open => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -195,6 +220,10 @@ extern "C" fn dispatch(
                 Some(value) => value,
                 None => return InterceptResult::Forward,
             };
+            if (arg1 as i32) & O_TMPFILE == O_TMPFILE {
+                *result = open_temp_file(&remote_pathname, arg1 as i32, arg2 as u32);
+                return InterceptResult::Hook;
+            }
             match CLIENT.open_remote(&remote_pathname, arg1 as i32, arg2 as u32) {
                 Ok(()) => {
                     let filetype = match (arg1 as i32) & O_DIRECTORY {
@@ -223,7 +252,7 @@ extern "C" fn dispatch(
         SYS_openat => {
             let file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
             let dir_path = if arg0 as i32 == AT_FDCWD {
-                CURRENT_DIR.to_string()
+                current_dir()
             } else {
                 match file_desc::get_attr(arg0 as i32) {
                     Some(value) => MOUNT_POINT.to_string() + &value.pathname,
@@ -250,6 +279,10 @@ extern "C" fn dispatch(
                 None => return InterceptResult::Forward,
             };
 
+            if (arg2 as i32) & O_TMPFILE == O_TMPFILE {
+                *result = open_temp_file(&remote_pathname, arg2 as i32, arg3 as u32);
+                return InterceptResult::Hook;
+            }
             match CLIENT.open_remote(&remote_pathname, arg2 as i32, arg3 as u32) {
                 Ok(()) => {
                     let filetype = match (arg2 as i32) & O_DIRECTORY {
@@ -261,7 +294,7 @@ extern "C" fn dispatch(
                         pathname: remote_pathname,
                         r#type: filetype,
                         offset: 0,
-                        flags: arg1 as i32,
+                        flags: arg2 as i32,
                     }) {
                         Some(value) => value as isize,
                         None => -libc::EMFILE as isize,
@@ -276,106 +309,131 @@ extern "C" fn dispatch(
         }
         // int rename(const char *oldpath, const char *newpath)
         SYS_rename => {
-            // todo other state
-            let dir_path = &CURRENT_DIR;
             let old_file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let new_file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
-            let absolute_oldpath = match get_absolutepath(dir_path, old_file_path) {
-                Ok(value) => value,
-                Err(0) => return InterceptResult::Forward,
-                Err(value) => {
-                    *result = value as isize;
-                    return InterceptResult::Hook;
-                }
-            };
-            let absolute_newpath = match get_absolutepath(dir_path, new_file_path) {
-                Ok(value) => value,
-                Err(0) => return InterceptResult::Forward,
-                Err(value) => {
-                    *result = value as isize;
-                    return InterceptResult::Hook;
-                }
-            };
-            let remote_oldpath = match get_remotepath(&absolute_oldpath) {
-                Some(value) => value,
-                None => return InterceptResult::Forward,
-            };
-            let remote_newpath = match get_remotepath(&absolute_newpath) {
-                Some(value) => value,
-                None => return InterceptResult::Forward,
-            };
-
-            *result = CLIENT.rename_remote(&remote_oldpath, &remote_newpath) as isize;
-            InterceptResult::Hook
+            let dir_path = current_dir();
+            on_remote_paths(
+                (&dir_path, old_file_path),
+                (&dir_path, new_file_path),
+                result,
+                |oldpath, newpath| CLIENT.rename_remote(oldpath, newpath),
+            )
         }
         // int renameat(int olddirfd, const char *oldpath,
         //             int newdirfd, const char *newpath)
         SYS_renameat => {
-            // todo other state
             let old_file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
             let new_file_path = unsafe { CStr::from_ptr(arg3 as *const c_char).to_str().unwrap() };
-
-            let old_dir_path = if arg0 as i32 == AT_FDCWD {
-                CURRENT_DIR.to_string()
-            } else {
-                match file_desc::get_attr(arg0 as i32) {
-                    Some(value) => MOUNT_POINT.to_string() + &value.pathname,
-                    None => {
-                        if !old_file_path.starts_with('/') {
-                            return InterceptResult::Forward;
-                        } else {
-                            "".to_string()
-                        }
-                    }
-                }
+            let (Some(old_dir_path), Some(new_dir_path)) = (
+                at_dir_path(arg0 as i32, old_file_path),
+                at_dir_path(arg2 as i32, new_file_path),
+            ) else {
+                return InterceptResult::Forward;
             };
-            let new_dir_path = if arg2 as i32 == AT_FDCWD {
-                CURRENT_DIR.to_string()
+            on_remote_paths(
+                (&old_dir_path, old_file_path),
+                (&new_dir_path, new_file_path),
+                result,
+                |oldpath, newpath| CLIENT.rename_remote(oldpath, newpath),
+            )
+        }
+        // int linkat(int olddirfd, const char *oldpath, int newdirfd,
+        //            const char *newpath, int flags)
+        SYS_linkat => {
+            // an open file is named by linkat(fd, "", ..., AT_EMPTY_PATH) or
+            // /proc/self/fd/<fd>, e.g. an O_TMPFILE one.
+            let old_file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
+            let fd = if old_file_path.is_empty() && (arg4 as i32) & AT_EMPTY_PATH != 0 {
+                arg0 as i32
             } else {
-                match file_desc::get_attr(arg0 as i32) {
-                    Some(value) => MOUNT_POINT.to_string() + &value.pathname,
+                match old_file_path
+                    .strip_prefix("/proc/self/fd/")
+                    .and_then(|fd| fd.parse().ok())
+                {
+                    Some(fd) => fd,
                     None => {
-                        if !new_file_path.starts_with('/') {
+                        let new_file_path =
+                            unsafe { CStr::from_ptr(arg3 as *const c_char).to_str().unwrap() };
+                        let (Some(old_dir_path), Some(new_dir_path)) = (
+                            at_dir_path(arg0 as i32, old_file_path),
+                            at_dir_path(arg2 as i32, new_file_path),
+                        ) else {
                             return InterceptResult::Forward;
-                        } else {
-                            "".to_string()
-                        }
+                        };
+                        return on_remote_paths(
+                            (&old_dir_path, old_file_path),
+                            (&new_dir_path, new_file_path),
+                            result,
+                            |oldpath, newpath| CLIENT.link_remote(oldpath, newpath),
+                        );
                     }
                 }
             };
+            let attr = match file_desc::get_attr(fd) {
+                Some(value) => value,
+                None => return InterceptResult::Forward,
+            };
 
-            let absolute_oldpath = match get_absolutepath(&old_dir_path, old_file_path) {
-                Ok(value) => value,
-                Err(0) => return InterceptResult::Forward,
-                Err(value) => {
-                    *result = value as isize;
-                    return InterceptResult::Hook;
+            let new_file_path = unsafe { CStr::from_ptr(arg3 as *const c_char).to_str().unwrap() };
+            let new_dir_path = if arg2 as i32 == AT_FDCWD {
+                current_dir()
+            } else {
+                match file_desc::get_attr(arg2 as i32) {
+                    Some(value) => MOUNT_POINT.to_string() + &value.pathname[VOLUME_NAME.len()..],
+                    None => "".to_string(),
                 }
             };
-            let absolute_newpath = match get_absolutepath(&new_dir_path, new_file_path) {
-                Ok(value) => value,
-                Err(0) => return InterceptResult::Forward,
-                Err(value) => {
-                    *result = value as isize;
+            let remote_newpath = match get_absolutepath(&new_dir_path, new_file_path)
+                .ok()
+                .and_then(|path| get_remotepath(&path))
+            {
+                Some(value) => value,
+                None => {
+                    *result = -libc::EXDEV as isize;
                     return InterceptResult::Hook;
                 }
             };
 
-            let remote_oldpath = match get_remotepath(&absolute_oldpath) {
-                Some(value) => value,
-                None => return InterceptResult::Forward,
-            };
-            let remote_newpath = match get_remotepath(&absolute_newpath) {
-                Some(value) => value,
-                None => return InterceptResult::Forward,
+            *result = if !is_temp_file(&attr.pathname) {
+                match CLIENT.link_remote(&attr.pathname, &remote_newpath) {
+                    Ok(()) => 0,
+                    Err(e) => -e as isize,
+                }
+            } else if attr.flags & O_EXCL != 0 {
+                // O_TMPFILE | O_EXCL files can never be linked.
+                -libc::ENOENT as isize
+            } else {
+                match CLIENT.link_temp_file(&attr.pathname, &remote_newpath) {
+                    Ok(()) => {
+                        file_desc::set_attr(
+                            fd,
+                            FdAttr {
+                                pathname: remote_newpath,
+                                ..attr
+                            },
+                        );
+                        0
+                    }
+                    Err(e) => -e as isize,
+                }
             };
-
-            *result = CLIENT.rename_remote(&remote_oldpath, &remote_newpath) as isize;
             InterceptResult::Hook
         }
+        // int link(const char *oldpath, const char *newpath);
+        SYS_link => {
+            let old_file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
+            let new_file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
+            let dir_path = current_dir();
+            on_remote_paths(
+                (&dir_path, old_file_path),
+                (&dir_path, new_file_path),
+                result,
+                |oldpath, newpath| CLIENT.link_remote(oldpath, newpath),
+            )
+        }
         // int truncate(const char *path, off_t length)
         SYS_truncate => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -416,7 +474,7 @@ extern "C" fn dispatch(
         }
         // int mkdir(const char *pathname, mode_t mode)
         SYS_mkdir => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -470,7 +528,7 @@ extern "C" fn dispatch(
         }
         // int rmdir(const char *pathname)
         SYS_rmdir => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -565,7 +623,7 @@ extern "C" fn dispatch(
         }
         // int unlink(const char *pathname)
         SYS_unlink => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -591,7 +649,7 @@ extern "C" fn dispatch(
         //    int stat(const char *restrict pathname,
         //             struct stat *restrict statbuf);
         SYS_stat => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -618,7 +676,7 @@ extern "C" fn dispatch(
         //  int lstat(const char *restrict pathname,
         //     struct stat *restrict statbuf);
         SYS_lstat => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -708,7 +766,7 @@ extern "C" fn dispatch(
             };
 
             let buf = unsafe { std::slice::from_raw_parts_mut(arg1 as *mut u8, arg2 as usize) };
-            match CLIENT.pread_remote(&remote_pathname, buf, arg2 as i64) {
+            match CLIENT.pread_remote(&remote_pathname, buf, arg3 as i64) {
                 Ok(value) => *result = value,
                 Err(e) => {
                     *result = -e as isize;
@@ -733,8 +791,15 @@ extern "C" fn dispatch(
             };
 
             let iov = unsafe { std::slice::from_raw_parts(arg1 as *const iovec, arg2 as usize) };
-            *result = CLIENT.preadv_remote(&remote_pathname, iov, offset) as isize;
-            file_desc::set_offset(arg0 as i32, offset + *result as i64);
+            match CLIENT.preadv_remote(&remote_pathname, iov, offset) {
+                Ok(value) => {
+                    *result = value;
+                    file_desc::set_offset(arg0 as i32, offset + *result as i64);
+                }
+                Err(e) => {
+                    *result = -e as isize;
+                }
+            }
             InterceptResult::Hook
         }
         // ssize_t preadv(int fd, const struct iovec *iov, int iovcnt,
@@ -754,7 +819,10 @@ extern "C" fn dispatch(
             };
 
             let iov = unsafe { std::slice::from_raw_parts(arg1 as *const iovec, arg2 as usize) };
-            *result = CLIENT.preadv_remote(&remote_pathname, iov, arg3 as i64) as isize;
+            *result = match CLIENT.preadv_remote(&remote_pathname, iov, arg3 as i64) {
+                Ok(value) => value,
+                Err(e) => -e as isize,
+            };
 
             InterceptResult::Hook
         }
@@ -762,7 +830,7 @@ extern "C" fn dispatch(
         // ssize_t readlink(const char *restrict pathname, char *restrict buf,
         //                     size_t bufsiz);
         SYS_readlink => {
-            let dir_path = &CURRENT_DIR;
+            let dir_path = &current_dir();
             let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
             let absolute_pathname = match get_absolutepath(dir_path, file_path) {
                 Ok(value) => value,
@@ -776,48 +844,124 @@ extern "C" fn dispatch(
                 Some(value) => value,
                 None => return InterceptResult::Forward,
             };
-            let mut statbuf = [0u8; STAT_SIZE];
-            match CLIENT.stat_remote(&remote_pathname, &mut statbuf) {
-                Ok(_) => {
-                    let mode = unsafe { (*(statbuf.as_ptr() as *const stat)).st_mode };
-                    if (mode & S_IFLNK) == 0 {
-                        *result = -libc::EINVAL as isize;
-                        return InterceptResult::Hook;
+            let buf = unsafe { std::slice::from_raw_parts_mut(arg1 as *mut u8, arg2 as usize) };
+            *result = readlink_remote(&remote_pathname, buf);
+            InterceptResult::Hook
+        }
+        // ssize_t readlinkat(int dirfd, const char *restrict pathname,
+        //                    char *restrict buf, size_t bufsiz);
+        SYS_readlinkat => {
+            let file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
+            let dir_path = if arg0 as i32 == AT_FDCWD {
+                current_dir()
+            } else {
+                match file_desc::get_attr(arg0 as i32) {
+                    Some(value) => MOUNT_POINT.to_string() + &value.pathname[VOLUME_NAME.len()..],
+                    None => {
+                        if !file_path.starts_with('/') {
+                            return InterceptResult::Forward;
+                        } else {
+                            "".to_string()
+                        }
                     }
                 }
-                Err(e) => {
-                    *result = -e as isize;
+            };
+            let absolute_pathname = match get_absolutepath(&dir_path, file_path) {
+                Ok(value) => value,
+                Err(0) => return InterceptResult::Forward,
+                Err(value) => {
+                    *result = value as isize;
                     return InterceptResult::Hook;
                 }
-            }
-
-            let buf = unsafe { std::slice::from_raw_parts_mut(arg1 as *mut u8, arg2 as usize) };
-
-            match CLIENT.pread_remote(&remote_pathname, buf, 0) {
-                Ok(value) => {
-                    *result = value;
+            };
+            let remote_pathname = match get_remotepath(&absolute_pathname) {
+                Some(value) => value,
+                None => return InterceptResult::Forward,
+            };
+            let buf = unsafe { std::slice::from_raw_parts_mut(arg2 as *mut u8, arg3 as usize) };
+            *result = readlink_remote(&remote_pathname, buf);
+            InterceptResult::Hook
+        }
+        // int symlink(const char *target, const char *linkpath);
+        SYS_symlink => {
+            let target = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
+            let file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
+            let absolute_pathname = match get_absolutepath(&current_dir(), file_path) {
+                Ok(value) => value,
+                Err(0) => return InterceptResult::Forward,
+                Err(value) => {
+                    *result = value as isize;
+                    return InterceptResult::Hook;
                 }
-                Err(e) => {
-                    *result = -e as isize;
+            };
+            let remote_pathname = match get_remotepath(&absolute_pathname) {
+                Some(value) => value,
+                None => return InterceptResult::Forward,
+            };
+            *result = match CLIENT.symlink_remote(target, &remote_pathname) {
+                Ok(()) => 0,
+                Err(e) => -e as isize,
+            };
+            InterceptResult::Hook
+        }
+        // int symlinkat(const char *target, int newdirfd, const char *linkpath);
+        SYS_symlinkat => {
+            let target = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
+            let file_path = unsafe { CStr::from_ptr(arg2 as *const c_char).to_str().unwrap() };
+            let dir_path = if arg1 as i32 == AT_FDCWD {
+                current_dir()
+            } else {
+                match file_desc::get_attr(arg1 as i32) {
+                    Some(value) => MOUNT_POINT.to_string() + &value.pathname[VOLUME_NAME.len()..],
+                    None => {
+                        if !file_path.starts_with('/') {
+                            return InterceptResult::Forward;
+                        } else {
+                            "".to_string()
+                        }
+                    }
                 }
-            }
-
-            return InterceptResult::Hook;
+            };
+            let absolute_pathname = match get_absolutepath(&dir_path, file_path) {
+                Ok(value) => value,
+                Err(0) => return InterceptResult::Forward,
+                Err(value) => {
+                    *result = value as isize;
+                    return InterceptResult::Hook;
+                }
+            };
+            let remote_pathname = match get_remotepath(&absolute_pathname) {
+                Some(value) => value,
+                None => return InterceptResult::Forward,
+            };
+            *result = match CLIENT.symlink_remote(target, &remote_pathname) {
+                Ok(()) => 0,
+                Err(e) => -e as isize,
+            };
+            InterceptResult::Hook
         }
         // ssize_t write(int fd, const void *buf, size_t count);
         SYS_write => {
-            let (remote_pathname, offset) = {
+            let (remote_pathname, offset, flags) = {
                 match file_desc::get_attr(arg0 as i32) {
                     Some(attr) => {
                         if attr.r#type != FdType::File {
                             *result = -libc::EBADF as isize;
                             return InterceptResult::Hook;
                         }
-                        (attr.pathname.clone(), attr.offset)
+                        (attr.pathname.clone(), attr.offset, attr.flags)
                     }
                     _ => return InterceptResult::Forward,
                 }
             };
+            if flags & O_APPEND != 0 {
+                let iov = iovec {
+                    iov_base: arg1 as *mut libc::c_void,
+                    iov_len: arg2 as usize,
+                };
+                *result = append(arg0 as i32, &remote_pathname, &[iov]);
+                return InterceptResult::Hook;
+            }
             let buf = unsafe { std::slice::from_raw_parts(arg1 as *const u8, arg2 as usize) };
             match CLIENT.pwrite_remote(&remote_pathname, buf, offset) {
                 Ok(value) => {
@@ -857,22 +1001,33 @@ extern "C" fn dispatch(
         }
         // ssize_t writev(int fd, const struct iovec *iov, int iovcnt);
         SYS_writev => {
-            let (remote_pathname, offset) = {
+            let (remote_pathname, offset, flags) = {
                 match file_desc::get_attr(arg0 as i32) {
                     Some(attr) => {
                         if attr.r#type != FdType::File {
                             *result = -libc::EBADF as isize;
                             return InterceptResult::Hook;
                         }
-                        (attr.pathname.clone(), attr.offset)
+                        (attr.pathname.clone(), attr.offset, attr.flags)
                     }
                     _ => return InterceptResult::Forward,
                 }
             };
 
             let iov = unsafe { std::slice::from_raw_parts(arg1 as *const iovec, arg2 as usize) };
-            *result = CLIENT.pwritev_remote(&remote_pathname, iov, offset) as isize;
-            file_desc::set_offset(arg0 as i32, offset + *result as i64);
+            if flags & O_APPEND != 0 {
+                *result = append(arg0 as i32, &remote_pathname, iov);
+                return InterceptResult::Hook;
+            }
+            match CLIENT.pwritev_remote(&remote_pathname, iov, offset) {
+                Ok(value) => {
+                    *result = value;
+                    file_desc::set_offset(arg0 as i32, offset + *result as i64);
+                }
+                Err(e) => {
+                    *result = -e as isize;
+                }
+            }
             InterceptResult::Hook
         }
         // ssize_t pwritev(int fd, const struct iovec *iov, int iovcnt,
@@ -891,42 +1046,52 @@ extern "C" fn dispatch(
                 }
             };
             let iov = unsafe { std::slice::from_raw_parts(arg1 as *const iovec, arg2 as usize) };
-            *result = CLIENT.pwritev_remote(&remote_pathname, iov, arg3 as i64) as isize;
+            *result = match CLIENT.pwritev_remote(&remote_pathname, iov, arg3 as i64) {
+                Ok(value) => value,
+                Err(e) => -e as isize,
+            };
 
             InterceptResult::Hook
         }
         // off_t lseek(int fd, off_t offset, int whence);
         SYS_lseek => {
-            let (remote_pathname, offset) = {
+            let (remote_pathname, offset, r#type) = {
                 match file_desc::get_attr(arg0 as i32) {
-                    Some(attr) => {
-                        if attr.r#type != FdType::File {
-                            *result = -libc::EBADF as isize;
-                            return InterceptResult::Hook;
-                        }
-                        (attr.pathname.clone(), attr.offset)
-                    }
+                    Some(attr) => (attr.pathname.clone(), attr.offset, attr.r#type),
                     _ => return InterceptResult::Forward,
                 }
             };
 
-            match arg2 as i32 {
-                SEEK_SET => file_desc::set_offset(arg0 as i32, arg1 as i64),
-                SEEK_CUR => file_desc::set_offset(arg0 as i32, offset + arg1 as i64),
+            let new_offset = match arg2 as i32 {
+                // the offset of a directory is the cursor of the last entry
+                // read, set back only by seekdir and rewinddir.
+                SEEK_SET if r#type == FdType::Dir => Ok(arg1 as i64),
+                SEEK_CUR if r#type == FdType::Dir && arg1 == 0 => Ok(offset),
+                _ if r#type == FdType::Dir => Err(libc::EINVAL),
+                SEEK_SET => Ok(arg1 as i64),
+                SEEK_CUR => Ok(offset + arg1 as i64),
                 SEEK_END => {
                     let mut statbuf = [0u8; STAT_SIZE];
-                    match CLIENT.stat_remote(&remote_pathname, &mut statbuf) {
-                        Ok(_) => {
-                            let filesize = unsafe { (*(statbuf.as_ptr() as *const stat)).st_size };
-
-                            file_desc::set_offset(arg0 as i32, filesize + arg1 as i64);
-                        }
-                        Err(e) => {
-                            *result = -e as isize;
-                        }
-                    }
+                    CLIENT.stat_remote(&remote_pathname, &mut statbuf).map(|_| {
+                        let filesize = unsafe { (*(statbuf.as_ptr() as *const stat)).st_size };
+                        filesize + arg1 as i64
+                    })
+                }
+                whence @ (SEEK_DATA | SEEK_HOLE) => match arg1 as i64 {
+                    offset if offset < 0 => Err(libc::ENXIO),
+                    offset => CLIENT
+                        .seek_remote(&remote_pathname, offset as u64, whence == SEEK_HOLE)
+                        .map(|offset| offset as i64),
+                },
+                _ => Err(libc::EINVAL),
+            };
+            *result = match new_offset {
+                Ok(new_offset) if new_offset < 0 => -libc::EINVAL as isize,
+                Ok(new_offset) => {
+                    file_desc::set_offset(arg0 as i32, new_offset);
+                    new_offset as isize
                 }
-                _ => {}
+                Err(e) => -e as isize,
             };
 
             InterceptResult::Hook
@@ -936,7 +1101,7 @@ extern "C" fn dispatch(
         262 => {
             let file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
             let dir_path = if arg0 as i32 == AT_FDCWD {
-                CURRENT_DIR.to_string()
+                current_dir()
             } else {
                 match file_desc::get_attr(arg0 as i32) {
                     Some(value) => MOUNT_POINT.to_string() + &value.pathname,
@@ -976,7 +1141,7 @@ extern "C" fn dispatch(
         SYS_statx => {
             let file_path = unsafe { CStr::from_ptr(arg1 as *const c_char).to_str().unwrap() };
             let dir_path = if arg0 as i32 == AT_FDCWD {
-                CURRENT_DIR.to_string()
+                current_dir()
             } else {
                 match file_desc::get_attr(arg0 as i32) {
                     Some(value) => MOUNT_POINT.to_string() + &value.pathname,
@@ -1012,14 +1177,335 @@ extern "C" fn dispatch(
             }
             InterceptResult::Hook
         }
-        // int fsync(int fd);
-        SYS_fsync => {
+        // int statfs(const char *path, struct statfs *buf);
+        SYS_statfs => {
+            let dir_path = &current_dir();
+            let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
+            let absolute_pathname = match get_absolutepath(dir_path, file_path) {
+                Ok(value) => value,
+                Err(0) => return InterceptResult::Forward,
+                Err(value) => {
+                    *result = value as isize;
+                    return InterceptResult::Hook;
+                }
+            };
+            if get_remotepath(&absolute_pathname).is_none() {
+                return InterceptResult::Forward;
+            }
+            let statfsbuf = unsafe { &mut *(arg1 as *mut libc::statfs) };
+            match CLIENT.statfs_remote(statfsbuf) {
+                Ok(()) => *result = 0,
+                Err(e) => *result = -e as isize,
+            }
+            InterceptResult::Hook
+        }
+        // int fstatfs(int fd, struct statfs *buf);
+        SYS_fstatfs => {
             if file_desc::get_attr(arg0 as i32).is_none() {
                 return InterceptResult::Forward;
             }
-            *result = 0;
+            let statfsbuf = unsafe { &mut *(arg1 as *mut libc::statfs) };
+            match CLIENT.statfs_remote(statfsbuf) {
+                Ok(()) => *result = 0,
+                Err(e) => *result = -e as isize,
+            }
+            InterceptResult::Hook
+        }
+        // int fsync(int fd);
+        // int fdatasync(int fd);
+        SYS_fsync | SYS_fdatasync => {
+            let remote_pathname = match file_desc::get_attr(arg0 as i32) {
+                Some(value) => value.pathname.clone(),
+                None => return InterceptResult::Forward,
+            };
+            match CLIENT.fsync_remote(&remote_pathname, syscall_number as i64 == SYS_fdatasync) {
+                Ok(()) => *result = 0,
+                Err(e) => *result = -e as isize,
+            }
+            InterceptResult::Hook
+        }
+        // int fcntl(int fd, int cmd, ... /* struct flock *lock */);
+        SYS_fcntl => {
+            let cmd = arg1 as i32;
+            if cmd != libc::F_SETLK && cmd != libc::F_SETLKW && cmd != libc::F_GETLK {
+                return InterceptResult::Forward;
+            }
+            let attr = match file_desc::get_attr(arg0 as i32) {
+                Some(value) => value,
+                None => return InterceptResult::Forward,
+            };
+            let lock = unsafe { &mut *(arg2 as *mut flock) };
+            let range = match lock_range(&attr, lock) {
+                Ok(value) => value,
+                Err(e) => {
+                    *result = -e as isize;
+                    return InterceptResult::Hook;
+                }
+            };
+            let owner = LockOwner::Process(std::process::id());
+            let typ = lock.l_type as i32;
+            *result = if cmd == libc::F_GETLK {
+                match CLIENT.test_lock_remote(&attr.pathname, owner, range, typ) {
+                    Ok(held) => {
+                        lock.l_type = held.typ as libc::c_short;
+                        if held.typ != libc::F_UNLCK {
+                            lock.l_whence = SEEK_SET as libc::c_short;
+                            lock.l_start = held.start as i64;
+                            lock.l_len = match held.end {
+                                u64::MAX => 0,
+                                end => (end - held.start + 1) as i64,
+                            };
+                            lock.l_pid = held.pid as i32;
+                        }
+                        0
+                    }
+                    Err(e) => -e as isize,
+                }
+            } else {
+                match CLIENT.lock_remote(&attr.pathname, owner, range, typ, cmd == libc::F_SETLKW) {
+                    Ok(()) => 0,
+                    Err(e) => -e as isize,
+                }
+            };
+            InterceptResult::Hook
+        }
+        // int flock(int fd, int operation);
+        SYS_flock => {
+            let (attr, description) = match (
+                file_desc::get_attr(arg0 as i32),
+                file_desc::get_description(arg0 as i32),
+            ) {
+                (Some(attr), Some(description)) => (attr, description),
+                _ => return InterceptResult::Forward,
+            };
+            let operation = arg1 as i32;
+            let typ = match operation & !libc::LOCK_NB {
+                libc::LOCK_SH => libc::F_RDLCK,
+                libc::LOCK_EX => libc::F_WRLCK,
+                libc::LOCK_UN => libc::F_UNLCK,
+                _ => {
+                    *result = -libc::EINVAL as isize;
+                    return InterceptResult::Hook;
+                }
+            };
+            *result = match CLIENT.lock_remote(
+                &attr.pathname,
+                LockOwner::File(description),
+                (0, u64::MAX),
+                typ,
+                operation & libc::LOCK_NB == 0,
+            ) {
+                Ok(()) => 0,
+                // flock fails with EWOULDBLOCK, the same as EAGAIN.
+                Err(e) => -e as isize,
+            };
+            InterceptResult::Hook
+        }
+        // void exit_group(int status);
+        SYS_exit_group => {
+            CLIENT.release_all_locks();
+            InterceptResult::Forward
+        }
+        // int chdir(const char *path);
+        SYS_chdir => {
+            let dir_path = current_dir();
+            let file_path = unsafe { CStr::from_ptr(arg0 as *const c_char).to_str().unwrap() };
+            let absolute_pathname = match get_absolutepath(&dir_path, file_path) {
+                Ok(value) => value,
+                Err(0) => return InterceptResult::Forward,
+                Err(value) => {
+                    *result = value as isize;
+                    return InterceptResult::Hook;
+                }
+            };
+            *result = match get_remotepath(&absolute_pathname) {
+                Some(remote_pathname) => enter_remote_dir(&remote_pathname),
+                // the kernel does not know the directory under the mount
+                // point we leave, so it gets the absolute path.
+                None if get_remotepath(&dir_path).is_some() => {
+                    let path = CString::new(absolute_pathname).unwrap();
+                    enter_local_dir(SYS_chdir, path.as_ptr() as isize)
+                }
+                None => enter_local_dir(SYS_chdir, arg0),
+            };
+            InterceptResult::Hook
+        }
+        // int fchdir(int fd);
+        SYS_fchdir => {
+            *result = match file_desc::get_attr(arg0 as i32) {
+                Some(attr) => enter_remote_dir(&attr.pathname),
+                None => enter_local_dir(SYS_fchdir, arg0),
+            };
+            InterceptResult::Hook
+        }
+        // char *getcwd(char *buf, size_t size);
+        SYS_getcwd => {
+            let dir_path = current_dir();
+            if get_remotepath(&dir_path).is_none() {
+                return InterceptResult::Forward;
+            }
+            if dir_path.len() + 1 > arg1 as usize {
+                *result = -libc::ERANGE as isize;
+                return InterceptResult::Hook;
+            }
+            let buf =
+                unsafe { std::slice::from_raw_parts_mut(arg0 as *mut u8, dir_path.len() + 1) };
+            buf[..dir_path.len()].copy_from_slice(dir_path.as_bytes());
+            buf[dir_path.len()] = 0;
+            *result = buf.len() as isize;
             InterceptResult::Hook
         }
         _ => InterceptResult::Forward,
     }
 }
+
+// readlink_remote reads the target of the symbolic link remote_pathname into
+// buf, cut to its size.
+fn readlink_remote(remote_pathname: &str, buf: &mut [u8]) -> isize {
+    let mut statbuf = [0u8; STAT_SIZE];
+    if let Err(e) = CLIENT.stat_remote(remote_pathname, &mut statbuf) {
+        return -e as isize;
+    }
+    let mode = unsafe { (*(statbuf.as_ptr() as *const stat)).st_mode };
+    if mode & S_IFMT != S_IFLNK {
+        return -libc::EINVAL as isize;
+    }
+    match CLIENT.pread_remote(remote_pathname, buf, 0) {
+        Ok(value) => value,
+        Err(e) => -e as isize,
+    }
+}
+
+// at_dir_path is the directory a path of an *at call is relative to, None
+// when it is an fd not of the volume and the path is relative.
+fn at_dir_path(dirfd: i32, file_path: &str) -> Option<String> {
+    if dirfd == AT_FDCWD {
+        return Some(current_dir());
+    }
+    match file_desc::get_attr(dirfd) {
+        Some(value) => Some(MOUNT_POINT.to_string() + &value.pathname[VOLUME_NAME.len()..]),
+        None if file_path.starts_with('/') => Some("".to_string()),
+        None => None,
+    }
+}
+
+// on_remote_paths runs call, a link or a rename, on oldpath and newpath of
+// the volume, given with the directories they are relative to. A call
+// between the volume and another file system is EXDEV, as for two mounts.
+fn on_remote_paths(
+    old: (&str, &str),
+    new: (&str, &str),
+    result: &mut isize,
+    call: impl FnOnce(&str, &str) -> Result<(), i32>,
+) -> InterceptResult {
+    let mut remote_paths = Vec::with_capacity(2);
+    for (dir_path, file_path) in [old, new] {
+        match get_absolutepath(dir_path, file_path) {
+            Ok(value) => remote_paths.push(get_remotepath(&value)),
+            Err(0) => remote_paths.push(None),
+            Err(value) => {
+                *result = value as isize;
+                return InterceptResult::Hook;
+            }
+        }
+    }
+    *result = match (&remote_paths[0], &remote_paths[1]) {
+        (None, None) => return InterceptResult::Forward,
+        (Some(oldpath), Some(newpath)) => match call(oldpath, newpath) {
+            Ok(()) => 0,
+            Err(e) => -e as isize,
+        },
+        _ => -libc::EXDEV as isize,
+    };
+    InterceptResult::Hook
+}
+
+// enter_remote_dir makes the directory remote_pathname of the volume the
+// working directory, the kernel keeps the last local one.
+fn enter_remote_dir(remote_pathname: &str) -> isize {
+    let mut statbuf = [0u8; STAT_SIZE];
+    if let Err(e) = CLIENT.stat_remote(remote_pathname, &mut statbuf) {
+        return -e as isize;
+    }
+    let mode = unsafe { (*(statbuf.as_ptr() as *const stat)).st_mode };
+    if mode & S_IFMT != S_IFDIR {
+        return -libc::ENOTDIR as isize;
+    }
+    set_current_dir(MOUNT_POINT.to_string() + &remote_pathname[VOLUME_NAME.len()..]);
+    0
+}
+
+// append writes the iovecs at the end of the file of fd opened with
+// O_APPEND, and moves the offset of fd after them.
+fn append(fd: i32, remote_pathname: &str, iov: &[iovec]) -> isize {
+    match CLIENT.appendv_remote(remote_pathname, iov) {
+        Ok((written, end)) => {
+            file_desc::set_offset(fd, end);
+            written
+        }
+        Err(e) => -e as isize,
+    }
+}
+
+// lock_range returns the first and the last bytes of the file of attr
+// covered by lock, the last is u64::MAX for a lock to the end of the file.
+fn lock_range(attr: &FdAttr, lock: &flock) -> Result<(u64, u64), i32> {
+    let base = match lock.l_whence as i32 {
+        SEEK_SET => 0,
+        SEEK_CUR => attr.offset,
+        SEEK_END => {
+            let mut statbuf = [0u8; STAT_SIZE];
+            CLIENT.stat_remote(&attr.pathname, &mut statbuf)?;
+            unsafe { (*(statbuf.as_ptr() as *const stat)).st_size }
+        }
+        _ => return Err(libc::EINVAL),
+    };
+    let start = base.checked_add(lock.l_start).ok_or(libc::EOVERFLOW)?;
+    let (start, end) = match lock.l_len {
+        0 => (start, None),
+        len if len > 0 => (start, start.checked_add(len - 1)),
+        // a negative length covers the bytes before start.
+        len => (start + len, Some(start - 1)),
+    };
+    if start < 0 {
+        return Err(libc::EINVAL);
+    }
+    match end {
+        Some(end) if end < start => Err(libc::EINVAL),
+        Some(end) => Ok((start as u64, end as u64)),
+        None if lock.l_len == 0 => Ok((start as u64, u64::MAX)),
+        None => Err(libc::EOVERFLOW),
+    }
+}
+
+// enter_local_dir runs a chdir or fchdir to a local directory, and takes
+// the working directory back from the kernel.
+fn enter_local_dir(syscall_number: i64, arg: isize) -> isize {
+    let result = unsafe { syscall_no_intercept(syscall_number as isize, arg) };
+    if result == 0 {
+        if let Ok(dir) = std::env::current_dir() {
+            set_current_dir(dir.to_string_lossy().into_owned());
+        }
+    }
+    result
+}
+
+// open_temp_file opens an unnamed file in the directory dir for O_TMPFILE.
+fn open_temp_file(dir: &str, flags: i32, mode: u32) -> isize {
+    let pathname = match CLIENT.create_temp_file(dir, mode) {
+        Ok(value) => value,
+        Err(e) => return -e as isize,
+    };
+    match file_desc::insert_attr(FdAttr {
+        pathname: pathname.clone(),
+        r#type: FdType::File,
+        offset: 0,
+        flags,
+    }) {
+        Some(value) => value as isize,
+        None => {
+            let _ = CLIENT.delete_temp_file(&pathname);
+            -libc::EMFILE as isize
+        }
+    }
+}
@@ -1,20 +1,31 @@
+use std::sync::RwLock;
+
+use crate::CONFIG;
+
 lazy_static::lazy_static! {
-    pub static ref CURRENT_DIR: String = std::env::current_dir()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-    pub static ref MOUNT_POINT: String = {
-        let mut value =
-            std::env::var("SEALFS_MOUNT_POINT").unwrap_or_else(|_| "/mnt/fs".to_string());
-        if value.ends_with('/') {
-            value.pop();
-        }
-        value
-    };
-    pub static ref VOLUME_NAME: String = {
-        std::env::var("SEALFS_VOLUME_NAME").unwrap_or_else(|_| "sealfs".to_string())
-    };
+    // the working directory of the process, which the kernel does not know
+    // of while it is under the mount point, see SYS_chdir in lib.rs. A
+    // process started there by a shell gets it from PWD.
+    static ref CURRENT_DIR: RwLock<String> = RwLock::new(
+        match std::env::var("PWD") {
+            Ok(pwd) if get_remotepath(&pwd).is_some() => pwd,
+            _ => std::env::current_dir()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string(),
+        },
+    );
+    pub static ref MOUNT_POINT: String = CONFIG.mount_point.clone();
+    pub static ref VOLUME_NAME: String = CONFIG.volume_name.clone();
+}
+
+pub fn current_dir() -> String {
+    CURRENT_DIR.read().unwrap().clone()
+}
+
+pub fn set_current_dir(path: String) {
+    *CURRENT_DIR.write().unwrap() = path;
 }
 
 fn get_realpath(path: &str) -> Option<String> {
@@ -22,7 +33,7 @@ fn get_realpath(path: &str) -> Option<String> {
     let path = if path.starts_with('/') {
         path.to_string()
     } else {
-        let mut cwd = CURRENT_DIR.to_string();
+        let mut cwd = current_dir();
         cwd.push('/');
         cwd.push_str(path);
         cwd
@@ -0,0 +1,173 @@
+/*
+ * Trace of the syscalls served by sealfs, written to SEALFS_TRACE_FILE when
+ * it is set. tests/intercept.rs replays it against a local directory to find
+ * the first call whose result differs from the kernel. One call per line:
+ *     <pid> <syscall> <result> <args...>
+ * Paths are absolute, under the mount point. The data of reads and writes is
+ * in hex, the size of a stat. Calls the replay does not know only have their
+ * result.
+ */
+use std::ffi::CStr;
+
+use libc::{
+    c_char, iovec, stat, statx, SYS_close, SYS_creat, SYS_fstat, SYS_ftruncate, SYS_lseek,
+    SYS_lstat, SYS_mkdir, SYS_mkdirat, SYS_open, SYS_openat, SYS_pread64, SYS_preadv, SYS_pwrite64,
+    SYS_pwritev, SYS_read, SYS_readv, SYS_rename, SYS_renameat, SYS_rmdir, SYS_stat, SYS_statx,
+    SYS_truncate, SYS_unlink, SYS_write, SYS_writev, AT_FDCWD, O_APPEND, O_CLOEXEC, O_CREAT,
+    O_WRONLY,
+};
+
+use crate::{
+    file_desc,
+    path::{current_dir, get_absolutepath, MOUNT_POINT, VOLUME_NAME},
+    syscall_intercept::syscall_no_intercept,
+};
+
+// newfstatat, not exported by libc for every target.
+const SYS_NEWFSTATAT: i64 = 262;
+
+lazy_static::lazy_static! {
+    static ref TRACE_FD: Option<isize> = std::env::var("SEALFS_TRACE_FILE").ok().map(|path| {
+        let path = std::ffi::CString::new(path).unwrap();
+        unsafe {
+            syscall_no_intercept(
+                SYS_open as isize,
+                path.as_ptr(),
+                O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC,
+                0o644,
+            )
+        }
+    });
+}
+
+pub fn enabled() -> bool {
+    matches!(*TRACE_FD, Some(fd) if fd >= 0)
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2 + 1);
+    s.push('x');
+    for b in data {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+fn c_path(arg: isize) -> String {
+    unsafe { CStr::from_ptr(arg as *const c_char) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+// path returns the absolute path of a path argument, relative to dirfd.
+fn path(dirfd: i32, arg: isize) -> String {
+    let file_path = c_path(arg);
+    let dir_path = match file_desc::get_attr(dirfd) {
+        Some(attr) if dirfd != AT_FDCWD && !file_path.starts_with('/') => {
+            MOUNT_POINT.to_string() + &attr.pathname[VOLUME_NAME.len()..]
+        }
+        _ => current_dir(),
+    };
+    get_absolutepath(&dir_path, &file_path).unwrap_or(file_path)
+}
+
+fn gather(iov: isize, iovcnt: isize, length: isize) -> Vec<u8> {
+    let iov = unsafe { std::slice::from_raw_parts(iov as *const iovec, iovcnt as usize) };
+    let mut data = Vec::new();
+    for v in iov {
+        let buf = unsafe { std::slice::from_raw_parts(v.iov_base as *const u8, v.iov_len) };
+        data.extend_from_slice(buf);
+    }
+    data.truncate(length.max(0) as usize);
+    data
+}
+
+fn read_data(buf: isize, result: isize) -> String {
+    hex(unsafe { std::slice::from_raw_parts(buf as *const u8, result.max(0) as usize) })
+}
+
+// write_data returns the bytes written, all the bytes asked for if the
+// write failed.
+fn write_data(buf: isize, count: isize, result: isize) -> String {
+    let length = if result < 0 { count } else { result };
+    hex(unsafe { std::slice::from_raw_parts(buf as *const u8, length as usize) })
+}
+
+// record writes a call served by sealfs to the trace.
+#[allow(non_upper_case_globals)]
+pub fn record(num: isize, args: [isize; 5], result: isize) {
+    let [a0, a1, a2, a3, a4] = args;
+    let fd = match *TRACE_FD {
+        Some(fd) if fd >= 0 => fd,
+        _ => return,
+    };
+    let (name, args) = match num as i64 {
+        SYS_open => ("open", format!("{} {} {}", path(AT_FDCWD, a0), a1, a2)),
+        SYS_creat => ("creat", format!("{} {}", path(AT_FDCWD, a0), a1)),
+        SYS_openat => ("open", format!("{} {} {}", path(a0 as i32, a1), a2, a3)),
+        SYS_close => ("close", format!("{}", a0)),
+        SYS_read => ("read", format!("{} {}", a0, read_data(a1, result))),
+        SYS_pread64 => ("pread", format!("{} {} {}", a0, a3, read_data(a1, result))),
+        SYS_readv => ("read", format!("{} {}", a0, hex(&gather(a1, a2, result)))),
+        SYS_preadv => (
+            "pread",
+            format!("{} {} {}", a0, a3, hex(&gather(a1, a2, result))),
+        ),
+        SYS_write => ("write", format!("{} {}", a0, write_data(a1, a2, result))),
+        SYS_pwrite64 => (
+            "pwrite",
+            format!("{} {} {}", a0, a3, write_data(a1, a2, result)),
+        ),
+        SYS_writev => (
+            "write",
+            format!("{} {}", a0, hex(&gather(a1, a2, isize::MAX))),
+        ),
+        SYS_pwritev => (
+            "pwrite",
+            format!("{} {} {}", a0, a3, hex(&gather(a1, a2, isize::MAX))),
+        ),
+        SYS_lseek => ("lseek", format!("{} {} {}", a0, a1, a2)),
+        SYS_truncate => ("truncate", format!("{} {}", path(AT_FDCWD, a0), a1)),
+        SYS_ftruncate => ("ftruncate", format!("{} {}", a0, a1)),
+        SYS_mkdir => ("mkdir", format!("{} {}", path(AT_FDCWD, a0), a1)),
+        SYS_mkdirat => ("mkdir", format!("{} {}", path(a0 as i32, a1), a2)),
+        SYS_rmdir => ("rmdir", path(AT_FDCWD, a0)),
+        SYS_unlink => ("unlink", path(AT_FDCWD, a0)),
+        SYS_rename => (
+            "rename",
+            format!("{} {}", path(AT_FDCWD, a0), path(AT_FDCWD, a1)),
+        ),
+        SYS_renameat => (
+            "rename",
+            format!("{} {}", path(a0 as i32, a1), path(a2 as i32, a3)),
+        ),
+        SYS_stat | SYS_lstat => (
+            "stat",
+            format!("{} {}", path(AT_FDCWD, a0), stat_size(a1, result)),
+        ),
+        SYS_NEWFSTATAT => (
+            "stat",
+            format!("{} {}", path(a0 as i32, a1), stat_size(a2, result)),
+        ),
+        SYS_fstat => ("fstat", format!("{} {}", a0, stat_size(a1, result))),
+        SYS_statx => {
+            let size = match result {
+                0 => unsafe { (*(a4 as *const statx)).stx_size as i64 },
+                _ => -1,
+            };
+            ("stat", format!("{} {}", path(a0 as i32, a1), size))
+        }
+        _ => ("other", format!("{}", num)),
+    };
+    let line = format!("{} {} {} {}\n", std::process::id(), name, result, args);
+    unsafe {
+        syscall_no_intercept(SYS_write as isize, fd, line.as_ptr(), line.len());
+    }
+}
+
+fn stat_size(statbuf: isize, result: isize) -> i64 {
+    match result {
+        0 => unsafe { (*(statbuf as *const stat)).st_size },
+        _ => -1,
+    }
+}
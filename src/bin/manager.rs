@@ -3,14 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use clap::Parser;
-use env_logger::fmt;
 use log::{error, info, warn};
-use sealfs::manager::manager_service::update_server_status;
+use sealfs::common::dns::{resolve_addresses, SRV_PREFIX};
+use sealfs::common::hash_ring::HashAlgorithm;
+use sealfs::common::logging::{init_logger, LogFormat};
+use sealfs::manager::manager_service::{discover_servers, update_server_status};
 use sealfs::{manager::manager_service::ManagerService, rpc::server::RpcServer};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::io::Read;
-use std::str::FromStr;
+use std::time::Duration;
 use std::{fmt::Debug, sync::Arc};
 
 #[derive(Parser, Debug)]
@@ -25,10 +28,24 @@ struct Args {
     use_config_file: bool,
     #[arg(long)]
     log_level: Option<String>,
+    /// Log format, text or json with the fields of the requests at debug level
+    #[arg(long)]
+    log_format: Option<LogFormat>,
     #[arg(long)]
     all_servers_address: Option<Vec<String>>,
     #[arg(long)]
     virtual_nodes: Option<usize>,
+    /// Hash placing the files on the servers: conhash, wyhash or rendezvous.
+    /// It must not change once the cluster holds files
+    #[arg(long)]
+    hash_algorithm: Option<HashAlgorithm>,
+    /// File keeping the created volumes and their metadata over restarts
+    #[arg(long)]
+    volume_registry: Option<String>,
+    /// Seconds a server may be away, e.g. restarting, before it is removed
+    /// from the cluster. 0 never removes it
+    #[arg(long)]
+    server_grace_period: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,12 +54,21 @@ struct Properties {
     all_servers_address: Vec<String>,
     virtual_nodes: usize,
     log_level: String,
+    #[serde(default)]
+    log_format: LogFormat,
+    #[serde(default)]
+    hash_algorithm: HashAlgorithm,
+    #[serde(default)]
+    volume_registry: Option<String>,
+    // the group of the servers in one, e.g. their rack.
+    #[serde(default)]
+    server_groups: BTreeMap<String, String>,
+    #[serde(default)]
+    server_grace_period: Option<u64>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let mut builder = env_logger::Builder::from_default_env();
-
     // read from default configuration.
     let config_path = std::env::var("SEALFS_CONFIG_PATH").unwrap_or("~".to_string());
 
@@ -71,6 +97,9 @@ async fn main() -> anyhow::Result<()> {
                     if args.log_level.is_some() {
                         result.log_level = args.log_level.unwrap();
                     }
+                    if let Some(log_format) = args.log_format {
+                        result.log_format = log_format;
+                    }
                     result
                 }
                 _ => {
@@ -90,30 +119,61 @@ async fn main() -> anyhow::Result<()> {
                 .virtual_nodes
                 .unwrap_or(default_properties.virtual_nodes),
             log_level: args.log_level.unwrap_or(default_properties.log_level),
+            log_format: args.log_format.unwrap_or(default_properties.log_format),
+            hash_algorithm: args
+                .hash_algorithm
+                .unwrap_or(default_properties.hash_algorithm),
+            volume_registry: args.volume_registry.or(default_properties.volume_registry),
+            server_groups: default_properties.server_groups,
+            server_grace_period: args
+                .server_grace_period
+                .or(default_properties.server_grace_period),
         },
     };
 
-    builder
-        .format_timestamp(Some(fmt::TimestampPrecision::Millis))
-        .filter(
-            None,
-            log::LevelFilter::from_str(&properties.log_level).unwrap(),
-        );
-    builder.init();
+    init_logger("manager", &properties.log_level, properties.log_format);
 
     info!("Starting manager with log level: {}", properties.log_level);
 
     let address = properties.address;
 
-    let servers_address = properties
+    // the srv records are resolved now and then again for the new servers.
+    let srv_names: Vec<String> = properties
         .all_servers_address
         .iter()
-        .map(|s| (s.to_string(), properties.virtual_nodes))
+        .filter(|s| s.starts_with(SRV_PREFIX))
+        .cloned()
+        .collect();
+    let servers_address = resolve_addresses(properties.all_servers_address.clone())
+        .await
+        .into_iter()
+        .map(|s| (s, properties.virtual_nodes))
         .collect::<Vec<(String, usize)>>();
 
-    info!("All servers address: {:?}", servers_address);
-
-    let manager = Arc::new(ManagerService::new(servers_address.clone()));
+    info!(
+        "All servers address: {:?}, hash algorithm: {}",
+        servers_address, properties.hash_algorithm
+    );
+
+    let manager = Arc::new(ManagerService::new(
+        properties.hash_algorithm,
+        servers_address.clone(),
+    ));
+
+    manager.manager.init_server_groups(properties.server_groups);
+    if let Some(seconds) = properties.server_grace_period {
+        let _ = manager
+            .manager
+            .server_grace_period
+            .set(Duration::from_secs(seconds));
+    }
+
+    if let Some(path) = &properties.volume_registry {
+        if let Err(e) = manager.manager.load_volume_registry(path) {
+            error!("{}", e);
+            return Err(anyhow::anyhow!(e));
+        }
+    }
 
     let server = Arc::new(RpcServer::new(manager.clone(), &address));
 
@@ -131,6 +191,14 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    if !srv_names.is_empty() {
+        tokio::spawn(discover_servers(
+            manager.manager.clone(),
+            srv_names,
+            properties.virtual_nodes,
+        ));
+    }
+
     update_server_status(manager.manager.clone()).await;
 
     Ok(())
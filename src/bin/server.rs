@@ -3,12 +3,19 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use clap::Parser;
-use env_logger::fmt;
-use log::info;
-use sealfs::server;
+use log::{error, info};
+use sealfs::common::{
+    affinity,
+    errors::status_to_string,
+    logging::{init_logger, LogFormat},
+};
+use sealfs::server::{
+    self,
+    distributed_engine::{IdMap, TRANSFER_CONCURRENCY, TRANSFER_DESTINATION_CONCURRENCY},
+    ServerOptions,
+};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
-use std::str::FromStr;
 
 const _SERVER_FLAG: u32 = 1;
 
@@ -19,16 +26,80 @@ struct Args {
     manager_address: Option<String>,
     #[arg(required = true, long)]
     server_address: Option<String>,
-    #[arg(required = true, long)]
+    #[arg(required_unless_present = "cache_node", long)]
     database_path: Option<String>,
     #[arg(long)]
     cache_capacity: Option<usize>,
     #[arg(long)]
     write_buffer_size: Option<usize>,
-    #[arg(required = true, long)]
+    #[arg(required_unless_present_any = ["cache_node", "storage"], long)]
     storage_path: Option<String>,
+    /// Storage engine and its params, `<name>:<params>`, e.g.
+    /// `chunk:/data/chunks`, the same as `file:<storage_path>` if not set
+    #[arg(long)]
+    storage: Option<String>,
     #[arg(long)]
     log_level: Option<String>,
+    /// Log format, text or json with the fields of the requests at debug level
+    #[arg(long)]
+    log_format: Option<LogFormat>,
+    /// Number of tokio worker threads, defaults to the number of cpus
+    #[arg(long)]
+    worker_threads: Option<usize>,
+    /// Cpus to pin the runtime threads to, e.g. "0-3,8"
+    #[arg(long)]
+    worker_cores: Option<String>,
+    /// NUMA node to run on, the runtime threads are pinned to its cpus
+    /// (unless worker_cores is set) and allocate memory from it
+    #[arg(long)]
+    numa_node: Option<usize>,
+    /// Run as a cache node, which caches the data read by the clients near them
+    /// and forwards the other requests to the servers
+    #[arg(long)]
+    cache_node: bool,
+    /// Number of 1MB chunks kept by a cache node
+    #[arg(long)]
+    cache_chunks: Option<usize>,
+    /// Migrate a database of an older format version at startup instead of
+    /// refusing to start
+    #[arg(long)]
+    auto_migrate: bool,
+    /// Added to the uids of the clients in the files of the server, the same
+    /// for every server of a cluster
+    #[arg(long)]
+    uid_offset: Option<u32>,
+    /// Added to the gids of the clients in the files of the server
+    #[arg(long)]
+    gid_offset: Option<u32>,
+    /// Compress the large data sent to the other servers with LZ4, the
+    /// responses to the clients are compressed when they ask for it
+    #[arg(long)]
+    compress_rpc: bool,
+    /// Check the CRC of the requests and responses sent to the other
+    /// servers, the responses to the clients have one when they ask for it
+    #[arg(long)]
+    checksum_rpc: bool,
+    /// Metadata requests per second of a client connection, the requests
+    /// over it wait, no limit if not set
+    #[arg(long)]
+    metadata_rate: Option<u32>,
+    /// Metadata requests a client connection sends at once before it is
+    /// limited, the rate if not set
+    #[arg(long)]
+    metadata_burst: Option<u32>,
+    /// Mirror a sample of the reads to this candidate server, holding a copy
+    /// of the data of this one, and log the responses which differ
+    #[arg(long)]
+    mirror_address: Option<String>,
+    /// Percent of the reads mirrored, 10 if not set
+    #[arg(long)]
+    mirror_percent: Option<u32>,
+    /// Files transferred at once in a rebalance, 16 if not set
+    #[arg(long)]
+    transfer_concurrency: Option<usize>,
+    /// Files transferred at once to the same server in a rebalance, 4 if not set
+    #[arg(long)]
+    transfer_destination_concurrency: Option<usize>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,50 +109,146 @@ struct Properties {
     database_path: String,
     cache_capacity: usize,
     write_buffer_size: usize,
-    storage_path: String,
+    storage: String,
     log_level: String,
+    log_format: LogFormat,
+    worker_threads: Option<usize>,
+    worker_cores: Option<String>,
+    numa_node: Option<usize>,
+    cache_node: bool,
+    cache_chunks: usize,
+    auto_migrate: bool,
+    uid_offset: u32,
+    gid_offset: u32,
+    compress_rpc: bool,
+    checksum_rpc: bool,
+    metadata_rate: u32,
+    metadata_burst: u32,
+    mirror_address: Option<String>,
+    mirror_percent: u32,
+    transfer_concurrency: usize,
+    transfer_destination_concurrency: usize,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<(), Box<dyn std::error::Error>> {
+fn main() -> anyhow::Result<(), Box<dyn std::error::Error>> {
     // read from command line.
     let args: Args = Args::parse();
     // if the user provides the config file, parse it and use the arguments from the config file.
     let properties: Properties = Properties {
         manager_address: args.manager_address.unwrap_or("127.0.0.1:8081".to_owned()),
         server_address: args.server_address.unwrap(),
-        database_path: args.database_path.unwrap(),
+        database_path: args.database_path.unwrap_or_default(),
         cache_capacity: args.cache_capacity.unwrap_or(13421772),
         write_buffer_size: args.write_buffer_size.unwrap_or(0x4000000),
-        storage_path: args.storage_path.unwrap(),
+        storage: args
+            .storage
+            .unwrap_or(format!("file:{}", args.storage_path.unwrap_or_default())),
         log_level: args.log_level.unwrap_or("warn".to_owned()),
+        log_format: args.log_format.unwrap_or_default(),
+        worker_threads: args.worker_threads,
+        worker_cores: args.worker_cores,
+        numa_node: args.numa_node,
+        cache_node: args.cache_node,
+        cache_chunks: args.cache_chunks.unwrap_or(1024),
+        auto_migrate: args.auto_migrate,
+        uid_offset: args.uid_offset.unwrap_or(0),
+        gid_offset: args.gid_offset.unwrap_or(0),
+        compress_rpc: args.compress_rpc,
+        checksum_rpc: args.checksum_rpc,
+        metadata_rate: args.metadata_rate.unwrap_or(0),
+        metadata_burst: args.metadata_burst.or(args.metadata_rate).unwrap_or(0),
+        mirror_address: args.mirror_address,
+        mirror_percent: args.mirror_percent.unwrap_or(10),
+        transfer_concurrency: args.transfer_concurrency.unwrap_or(TRANSFER_CONCURRENCY),
+        transfer_destination_concurrency: args
+            .transfer_destination_concurrency
+            .unwrap_or(TRANSFER_DESTINATION_CONCURRENCY),
     };
 
-    let mut builder = env_logger::Builder::from_default_env();
-    builder
-        .format_timestamp(Some(fmt::TimestampPrecision::Millis))
-        .filter(
-            None,
-            match log::LevelFilter::from_str(&properties.log_level) {
-                Ok(level) => level,
-                Err(_) => log::LevelFilter::Warn,
-            },
-        );
-    builder.init();
+    let component = match properties.cache_node {
+        true => "cache-node",
+        false => "server",
+    };
+    init_logger(component, &properties.log_level, properties.log_format);
 
     info!("start server with properties: {:?}", properties);
 
     let manager_address = properties.manager_address;
     let server_address = properties.server_address.clone();
 
-    server::run(
-        properties.database_path,
-        properties.storage_path,
-        server_address,
-        manager_address,
-        properties.cache_capacity,
-        properties.write_buffer_size,
-    )
-    .await?;
+    let cpus = match (&properties.worker_cores, properties.numa_node) {
+        (Some(cores), _) => Some(
+            affinity::parse_cpu_list(cores)
+                .map_err(|e| format!("invalid worker cores: {}", status_to_string(e)))?,
+        ),
+        (None, Some(node)) => Some(affinity::numa_node_cpus(node).map_err(|e| {
+            format!(
+                "get cpus of numa node {} failed: {}",
+                node,
+                status_to_string(e)
+            )
+        })?),
+        (None, None) => None,
+    };
+    let numa_node = properties.numa_node;
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(worker_threads) = properties.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    if cpus.is_some() || numa_node.is_some() {
+        info!(
+            "pin runtime threads to cpus {:?}, numa node {:?}",
+            cpus, numa_node
+        );
+        runtime.on_thread_start(move || {
+            if let Some(cpus) = &cpus {
+                if let Err(e) = affinity::pin_current_thread(cpus) {
+                    error!("pin thread to cpus failed: {}", status_to_string(e));
+                }
+            }
+            if let Some(node) = numa_node {
+                if let Err(e) = affinity::prefer_numa_node(node) {
+                    error!("set numa memory policy failed: {}", status_to_string(e));
+                }
+            }
+        });
+    }
+
+    let runtime = runtime.build()?;
+    if properties.cache_node {
+        runtime.block_on(server::cache_node::run(
+            server_address,
+            manager_address,
+            properties.cache_chunks,
+        ))?;
+        return Ok(());
+    }
+    runtime.block_on(server::run(
+        &properties.storage,
+        ServerOptions {
+            database_path: properties.database_path,
+            server_address,
+            manager_address,
+            #[cfg(feature = "disk-db")]
+            cache_capacity: properties.cache_capacity,
+            #[cfg(feature = "disk-db")]
+            write_buffer_size: properties.write_buffer_size,
+            auto_migrate: properties.auto_migrate,
+            id_map: IdMap {
+                uid_offset: properties.uid_offset,
+                gid_offset: properties.gid_offset,
+            },
+            compress_rpc: properties.compress_rpc,
+            checksum_rpc: properties.checksum_rpc,
+            metadata_rate: properties.metadata_rate,
+            metadata_burst: properties.metadata_burst,
+            mirror_address: properties.mirror_address,
+            mirror_percent: properties.mirror_percent,
+            transfer_concurrency: properties.transfer_concurrency,
+            transfer_destination_concurrency: properties.transfer_destination_concurrency,
+        },
+    ))?;
     Ok(())
 }
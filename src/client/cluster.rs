@@ -0,0 +1,333 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Bring-up of a cluster from a declarative config, with
+ * `client cluster init --config cluster.yaml`:
+ *     manager: 127.0.0.1:8081
+ *     hash_algorithm: conhash
+ *     servers:
+ *       - address: 127.0.0.1:8085
+ *         weight: 100
+ *         group: rack1
+ * The servers missing from the hash ring of the manager are added and the
+ * weights and groups of the others are set, then the command waits for the
+ * cluster to be idle with the ring of the config. The servers of the ring
+ * missing from the config are reported, not removed. Running it again on a
+ * cluster matching the config only prints its health.
+ */
+use std::{
+    collections::HashSet,
+    net::{TcpStream, ToSocketAddrs},
+    process::{Command, Stdio},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use super::fuse_client::Client;
+use crate::common::{
+    errors::status_to_string,
+    hash_ring::{HashAlgorithm, HashRingInfo},
+    info_syncer::{init_network_connections, ClientStatusMonitor, InfoSyncer},
+    serialization::{ClusterStatus, MembershipChange},
+    util::split_address,
+};
+
+const MANAGER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_weight() -> usize {
+    100
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ServerConfig {
+    pub address: String,
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+    #[serde(default)]
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ClusterConfig {
+    pub manager: String,
+    // checked against the manager if set, it cannot change once the cluster
+    // holds files.
+    #[serde(default)]
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub servers: Vec<ServerConfig>,
+}
+
+impl ClusterConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let yaml = std::fs::read_to_string(path).map_err(|e| format!("read {}: {}", path, e))?;
+        let config: Self =
+            serde_yaml::from_str(&yaml).map_err(|e| format!("parse {}: {}", path, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.servers.is_empty() {
+            return Err("the config has no server".to_owned());
+        }
+        let mut addresses = HashSet::new();
+        for server in std::iter::once(&self.manager).chain(self.servers.iter().map(|s| &s.address))
+        {
+            if split_address(server).is_none() {
+                return Err(format!("invalid address {:?}", server));
+            }
+            if !addresses.insert(server) {
+                return Err(format!("address {} is given twice", server));
+            }
+        }
+        if let Some(server) = self.servers.iter().find(|s| s.weight == 0) {
+            return Err(format!(
+                "weight of server {} must be positive",
+                server.address
+            ));
+        }
+        Ok(())
+    }
+
+    // changes returns the membership changes bringing the ring of the manager
+    // to the config, and the servers of the ring missing from the config.
+    pub fn changes(&self, ring: &HashRingInfo) -> (Vec<MembershipChange>, Vec<String>) {
+        let mut new_servers = Vec::new();
+        let mut changes = Vec::new();
+        for server in &self.servers {
+            match ring
+                .servers
+                .iter()
+                .find(|(address, _)| *address == server.address)
+            {
+                None => new_servers.push((server.address.clone(), server.weight)),
+                Some((_, weight)) if *weight != server.weight => changes.push(
+                    MembershipChange::SetWeight(server.address.clone(), server.weight),
+                ),
+                _ => {}
+            }
+            let group = ring.groups.servers.get(&server.address);
+            if group.map_or("", |g| g.as_str()) != server.group {
+                changes.push(MembershipChange::SetServerGroup(
+                    server.address.clone(),
+                    server.group.clone(),
+                ));
+            }
+        }
+        if !new_servers.is_empty() {
+            changes.insert(0, MembershipChange::AddNodes(new_servers));
+        }
+        let unknown = ring
+            .servers
+            .iter()
+            .filter(|(address, _)| self.servers.iter().all(|s| s.address != *address))
+            .map(|(address, _)| address.clone())
+            .collect();
+        (changes, unknown)
+    }
+}
+
+fn reachable(address: &str) -> bool {
+    match address.to_socket_addrs() {
+        Ok(mut addrs) => {
+            addrs.any(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(1)).is_ok())
+        }
+        Err(_) => false,
+    }
+}
+
+// start_manager starts the manager binary next to the client with the servers
+// of the config, if the manager does not answer. The weights and groups are
+// set once it is up.
+fn start_manager(config: &ClusterConfig) -> Result<(), String> {
+    if reachable(&config.manager) {
+        return Ok(());
+    }
+    let manager = std::env::current_exe()
+        .map_err(|e| e.to_string())?
+        .with_file_name("manager");
+    info!("start manager {}", manager.display());
+    let mut command = Command::new(&manager);
+    command.args(["--address", &config.manager]);
+    if let Some(algorithm) = config.hash_algorithm {
+        command.args(["--hash-algorithm", &algorithm.to_string()]);
+    }
+    for server in &config.servers {
+        command.args(["--all-servers-address", &server.address]);
+    }
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("start {}: {}", manager.display(), e))?;
+    let start = Instant::now();
+    while !reachable(&config.manager) {
+        if start.elapsed() > MANAGER_START_TIMEOUT {
+            return Err(format!("manager {} did not start", config.manager));
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+async fn submit(client: &Client, change: &MembershipChange) -> Result<(), i32> {
+    match change {
+        MembershipChange::AddNodes(nodes) => client.add_new_servers(nodes.clone()).await,
+        MembershipChange::RemoveNodes(nodes) => client.delete_servers(nodes.clone()).await,
+        MembershipChange::SetWeight(server, weight) => {
+            client.set_server_weight(server, *weight).await
+        }
+        MembershipChange::SetServerGroup(server, group) => {
+            client.set_server_group(server, group).await
+        }
+    }
+}
+
+pub async fn init(
+    client: Arc<Client>,
+    config: &ClusterConfig,
+    start: bool,
+    timeout: Duration,
+) -> Result<(), String> {
+    if start {
+        start_manager(config)?;
+    } else if !reachable(&config.manager) {
+        return Err(format!("manager {} is not reachable", config.manager));
+    }
+    init_network_connections(config.manager.clone(), client.clone()).await;
+
+    let ring = client
+        .get_hash_ring_info()
+        .await
+        .map_err(|e| format!("get hash ring failed, error = {}", status_to_string(e)))?;
+    if let Some(algorithm) = config.hash_algorithm {
+        if algorithm != ring.algorithm {
+            return Err(format!(
+                "the manager places the files with {}, not {}",
+                ring.algorithm, algorithm
+            ));
+        }
+    }
+
+    // the changes are queued by the manager until the servers are up.
+    let (changes, _) = config.changes(&ring);
+    for change in &changes {
+        info!("cluster init: {}", change);
+        submit(&client, change)
+            .await
+            .map_err(|e| format!("{} failed, error = {}", change, status_to_string(e)))?;
+    }
+
+    let start = Instant::now();
+    let ring = loop {
+        let status = client.get_cluster_status().await;
+        let membership = client.get_membership_changes().await;
+        if let (Ok(ClusterStatus::Idle), Ok(membership)) = (status, membership) {
+            if membership.running.is_empty() && membership.queued.is_empty() {
+                if let Ok(ring) = client.get_hash_ring_info().await {
+                    if config.changes(&ring).0.is_empty() {
+                        break ring;
+                    }
+                }
+            }
+        }
+        if start.elapsed() > timeout {
+            return Err(format!(
+                "the cluster is not ready after {:?}, status = {:?}",
+                timeout, status
+            ));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+
+    println!(
+        "cluster ready: {} servers, hash {}, epoch {}",
+        ring.servers.len(),
+        ring.algorithm,
+        ring.epoch
+    );
+    let (_, unknown) = config.changes(&ring);
+    for (address, weight) in &ring.servers {
+        let health = match client.add_connection(address).await {
+            Ok(_) => "up",
+            Err(_) => "unreachable",
+        };
+        let group = ring.groups.servers.get(address).map_or("", |g| g.as_str());
+        let note = match unknown.contains(address) {
+            true => ", not in the config",
+            false => "",
+        };
+        println!(
+            "  {} weight {} group {:?}: {}{}",
+            address, weight, group, health, note
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClusterConfig;
+    use crate::common::{
+        hash_ring::{HashAlgorithm, HashRingInfo},
+        serialization::MembershipChange,
+    };
+
+    #[test]
+    fn cluster_config_test() {
+        let config: ClusterConfig = serde_yaml::from_str(
+            "manager: 127.0.0.1:8081
+hash_algorithm: wyhash
+servers:
+  - address: 127.0.0.1:8085
+  - address: 127.0.0.1:8086
+    weight: 200
+    group: rack1
+  - address: 127.0.0.1:8087
+",
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.hash_algorithm, Some(HashAlgorithm::Wyhash));
+        assert_eq!(config.servers[0].weight, 100);
+
+        let ring = HashRingInfo {
+            servers: vec![
+                ("127.0.0.1:8085".to_owned(), 100),
+                ("127.0.0.1:8086".to_owned(), 100),
+                ("127.0.0.1:8088".to_owned(), 100),
+            ],
+            ..Default::default()
+        };
+        let (changes, unknown) = config.changes(&ring);
+        assert_eq!(
+            changes,
+            vec![
+                MembershipChange::AddNodes(vec![("127.0.0.1:8087".to_owned(), 100)]),
+                MembershipChange::SetWeight("127.0.0.1:8086".to_owned(), 200),
+                MembershipChange::SetServerGroup("127.0.0.1:8086".to_owned(), "rack1".to_owned()),
+            ]
+        );
+        assert_eq!(unknown, vec!["127.0.0.1:8088".to_owned()]);
+
+        let invalid = |yaml: &str| {
+            serde_yaml::from_str::<ClusterConfig>(yaml)
+                .unwrap()
+                .validate()
+        };
+        assert!(invalid("manager: m:1\nservers: []").is_err());
+        assert!(invalid("manager: m:1\nservers: [{address: s}]").is_err());
+        assert!(invalid("manager: m:1\nservers: [{address: s:1}, {address: s:1}]").is_err());
+        assert!(invalid("manager: m:1\nservers: [{address: s:1, weight: 0}]").is_err());
+        // IPv6 hosts are in brackets.
+        assert!(invalid("manager: '[::1]:1'\nservers: [{address: '[fd00::2]:1'}]").is_ok());
+        assert!(invalid("manager: m:1\nservers: [{address: '::1:1'}]").is_err());
+        assert!(invalid("manager: m:1\nservers: [{address: '[m]:1'}]").is_err());
+    }
+}
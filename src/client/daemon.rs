@@ -4,37 +4,126 @@
 
 use std::{
     io::{Read, Write},
-    sync::Arc,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::fs::MetadataExt,
+    },
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use async_trait::async_trait;
 use dashmap::DashMap;
-use fuser::{BackgroundSession, MountOption};
+use fuser::{BackgroundSession, MountOption, Session};
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     common::{
-        errors::{status_to_string, CONNECTION_ERROR},
+        errors::{status_to_errno, status_to_string, CONNECTION_ERROR, SERIALIZATION_ERROR},
         sender::REQUEST_TIMEOUT,
-        serialization::MountVolumeSendMetaData,
+        serialization::{CacheTtl, MountVolumeSendMetaData},
     },
     rpc::{
         client::{RpcClient, UnixStreamCreator},
+        connection::ConnectionStats,
         server::Handler,
     },
 };
 
-use super::{fuse_client::Client, SealFS};
+use super::{
+    fuse_client::{Client, LastError},
+    takeover,
+    telemetry::LatencyHistogram,
+    SealFS,
+};
 const MOUNT: u32 = 1;
 const PROBE: u32 = 2;
 const UMOUNT: u32 = 3;
 const LIST_MOUNTPOINTS: u32 = 4;
+const STATS: u32 = 5;
+
+// MountStats counts the requests of a mount point. All the mount points of
+// the daemon share one client, so they share its server connections.
+#[derive(Default)]
+pub struct MountStats {
+    pub ops: AtomicU64,
+    // bytes requested by the reads.
+    pub read_bytes: AtomicU64,
+    pub written_bytes: AtomicU64,
+    // FUSE operation -> its requests.
+    pub op_counts: DashMap<&'static str, u64>,
+    // FUSE operation -> the latencies of its requests sent to the servers.
+    pub op_latencies: DashMap<&'static str, LatencyHistogram>,
+    // requests sent to the servers and not replied yet.
+    pub in_flight: AtomicU64,
+    // stops the session once the mount point is handed over, see takeover.rs.
+    pub handover: takeover::Handover,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MountPointStats {
+    pub mount_point: String,
+    pub volume_name: String,
+    pub ops: u64,
+    pub read_bytes: u64,
+    pub written_bytes: u64,
+    pub last_error: Option<LastError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonStats {
+    pub mounts: Vec<MountPointStats>,
+    pub connections: Vec<ConnectionStats>,
+}
+
+// MountOptions are kept in the index file, so the mount points come back
+// the same when the daemon restarts.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct MountOptions {
+    pub read_only: bool,
+    // a public volume mounted read-write by its owner.
+    pub owner: bool,
+    // uid of the user who mounted it, only root and this user may unmount it.
+    pub uid: u32,
+    // users allowed to use the mount point besides root, the user running
+    // the daemon and the user who mounted it, sorted.
+    pub allowed_uids: Vec<u32>,
+    // the files created by root are owned by nobody.
+    pub root_squash: bool,
+    pub ttl: CacheTtl,
+}
+
+pub struct MountPoint {
+    pub volume_name: String,
+    pub options: MountOptions,
+    pub session: BackgroundSession,
+    pub stats: Arc<MountStats>,
+    // a dup of the FUSE fd of the session, handed over to the next daemon.
+    pub fd: OwnedFd,
+    pub root_inode: u64,
+    // taken over from the previous daemon, fuser does not unmount it.
+    pub taken_over: bool,
+}
 
 pub struct SealfsFused {
     pub client: Arc<Client>,
-    pub mount_points: DashMap<String, (String, bool, BackgroundSession)>,
+    pub mount_points: DashMap<String, MountPoint>,
     pub index_file: String,
     pub mount_lock: tokio::sync::Mutex<()>,
+    // users and groups allowed to use the socket besides root and the user
+    // running the daemon.
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+    // connection id -> (uid, gid) of the peer.
+    pub peers: DashMap<u32, (u32, u32)>,
+    // the mount points outlive the daemon, to be taken over by the next one.
+    pub takeover: bool,
+    // the kernel caches the writes of the mount points, see SealFS::init.
+    pub writeback_cache: bool,
 }
 
 // TODO: remove this
@@ -44,12 +133,51 @@ unsafe impl Sync for SealfsFused {}
 unsafe impl Send for SealfsFused {}
 
 impl SealfsFused {
-    pub fn new(index_file: String, client: Arc<Client>) -> Self {
+    pub fn new(
+        index_file: String,
+        client: Arc<Client>,
+        allowed_uids: Vec<u32>,
+        allowed_gids: Vec<u32>,
+    ) -> Self {
         Self {
             client,
             mount_points: DashMap::new(),
             index_file,
             mount_lock: tokio::sync::Mutex::new(()),
+            allowed_uids,
+            allowed_gids,
+            peers: DashMap::new(),
+            takeover: false,
+            writeback_cache: false,
+        }
+    }
+
+    pub fn is_allowed(&self, uid: u32, gid: u32) -> bool {
+        uid == 0
+            || uid == unsafe { libc::geteuid() }
+            || self.allowed_uids.contains(&uid)
+            || self.allowed_gids.contains(&gid)
+    }
+
+    // check_mount checks that uid may mount on mountpoint: root may mount
+    // anywhere, the other users only on directories they own.
+    fn check_mount(&self, uid: u32, mountpoint: &str) -> Result<(), i32> {
+        if uid == 0 {
+            return Ok(());
+        }
+        match std::fs::metadata(mountpoint) {
+            Ok(metadata) if metadata.uid() == uid => Ok(()),
+            Ok(_) => Err(libc::EACCES),
+            Err(e) => Err(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    // check_unmount checks that uid may unmount mountpoint: root may unmount
+    // any mount point, the other users only their own.
+    fn check_unmount(&self, uid: u32, mountpoint: &str) -> Result<(), i32> {
+        match self.mount_points.get(mountpoint) {
+            Some(mount_point) if uid != 0 && mount_point.options.uid != uid => Err(libc::EPERM),
+            _ => Ok(()),
         }
     }
 
@@ -57,96 +185,216 @@ impl SealfsFused {
         &self,
         mountpoint: String,
         volume_name: String,
-        read_only: bool,
-    ) -> Result<(), String> {
+        mut options: MountOptions,
+    ) -> Result<(), i32> {
+        options.allowed_uids.sort_unstable();
+        options.allowed_uids.dedup();
         let _lock = self.mount_lock.lock().await;
-        let mount_mode = if read_only {
-            MountOption::RO
-        } else {
-            MountOption::RW
-        };
-        let mut options = vec![mount_mode, MountOption::FSName("seal".to_string())];
-        options.push(MountOption::AutoUnmount);
-        options.push(MountOption::AllowRoot);
-        options.push(MountOption::CUSTOM("nonempty".to_string()));
         let result = self.client.init_volume(&volume_name).await;
         match result {
             Ok(inode) => {
                 info!("volume {} inited, now mount", volume_name);
+                let public = self
+                    .client
+                    .volumes
+                    .get(&volume_name)
+                    .is_some_and(|volume| volume.public);
+                if public && !options.read_only && !options.owner {
+                    info!("volume {} is public, mount it read-only", volume_name);
+                    options.read_only = true;
+                }
+                let mount_mode = if options.read_only {
+                    MountOption::RO
+                } else {
+                    MountOption::RW
+                };
+                let mut mount_options = vec![mount_mode, MountOption::FSName("seal".to_string())];
+                if !self.takeover {
+                    mount_options.push(MountOption::AutoUnmount);
+                }
+                mount_options.push(MountOption::CUSTOM("nonempty".to_string()));
+                let (allowed_uids, allow_other) = allowed_uids(&options);
+                if allow_other {
+                    mount_options.push(MountOption::AllowOther);
+                } else {
+                    mount_options.push(MountOption::AllowRoot);
+                }
 
                 // check if already mounted
-                if self.mount_points.contains_key(&mountpoint) {
+                if let Some(mount_point) = self.mount_points.get(&mountpoint) {
                     warn!("mountpoint {} already mounted", mountpoint);
-                    if self.mount_points.get(&mountpoint).unwrap().0 != volume_name {
-                        return Err(format!(
+                    if mount_point.volume_name != volume_name {
+                        error!(
                             "mountpoint {} already mounted with different volume",
                             mountpoint
-                        ));
+                        );
+                        return Err(libc::EBUSY);
                     }
-                    if self.mount_points.get(&mountpoint).unwrap().1 != read_only {
-                        return Err(format!(
+                    if mount_point.options.read_only != options.read_only {
+                        error!(
                             "mountpoint {} already mounted with different mode",
                             mountpoint
-                        ));
+                        );
+                        return Err(libc::EBUSY);
+                    }
+                    if mount_point.options.allowed_uids != options.allowed_uids {
+                        error!(
+                            "mountpoint {} already mounted with different allowed uids",
+                            mountpoint
+                        );
+                        return Err(libc::EBUSY);
+                    }
+                    if mount_point.options.root_squash != options.root_squash {
+                        error!(
+                            "mountpoint {} already mounted with different root squash",
+                            mountpoint
+                        );
+                        return Err(libc::EBUSY);
+                    }
+                    if mount_point.options.ttl != options.ttl {
+                        error!(
+                            "mountpoint {} already mounted with different cache ttl",
+                            mountpoint
+                        );
+                        return Err(libc::EBUSY);
                     }
                     return Ok(());
                 }
+                // the allowed uids restrict who uses the volume, another mount
+                // point of it must not let other users in.
+                if let Some(mount_point) = self.mount_points.iter().find(|mount_point| {
+                    mount_point.volume_name == volume_name
+                        && mount_point.options.allowed_uids != options.allowed_uids
+                }) {
+                    error!(
+                        "volume {} already mounted on {} with different allowed uids",
+                        volume_name,
+                        mount_point.key()
+                    );
+                    return Err(libc::EBUSY);
+                }
 
-                match fuser::spawn_mount2(
-                    SealFS::new(self.client.clone(), inode),
+                let stats = Arc::new(MountStats::default());
+                let session = Session::new(
+                    SealFS::new(
+                        self.client.clone(),
+                        inode,
+                        stats.clone(),
+                        allowed_uids,
+                        options.root_squash,
+                        options.ttl,
+                        self.writeback_cache,
+                    ),
                     &mountpoint,
-                    &options,
-                ) {
-                    Ok(session) => {
+                    &mount_options,
+                )
+                .and_then(|session| {
+                    let fd = session.as_fd().try_clone_to_owned()?;
+                    Ok((session.spawn()?, fd))
+                });
+                match session {
+                    Ok((session, fd)) => {
                         info!("mount success");
-                        self.mount_points
-                            .insert(mountpoint, (volume_name, read_only, session));
+                        self.mount_points.insert(
+                            mountpoint,
+                            MountPoint {
+                                volume_name,
+                                options,
+                                session,
+                                stats,
+                                fd,
+                                root_inode: inode,
+                                taken_over: false,
+                            },
+                        );
                         Ok(())
                     }
-                    Err(e) => Err(format!("mount error: {}", e)),
+                    Err(e) => {
+                        error!("mount error: {}", e);
+                        Err(e.raw_os_error().unwrap_or(libc::EIO))
+                    }
+                }
+            }
+            Err(e) => {
+                error!("init volume {} error: {}", volume_name, status_to_string(e));
+                // the volume does not exist, as mount(2) does for an unknown fs.
+                if e == libc::ENOENT {
+                    Err(libc::ENODEV)
+                } else {
+                    Err(status_to_errno(e))
                 }
             }
-            Err(e) => Err(format!("mount error: {}", status_to_string(e))),
         }
     }
 
-    pub async fn unmount(&self, mountpoint: &str) -> Result<(), String> {
+    pub async fn unmount(&self, mountpoint: &str) -> Result<(), i32> {
         let _lock = self.mount_lock.lock().await;
         match self.mount_points.remove(mountpoint) {
-            Some(_) => Ok(()),
-            None => Err(format!("mountpoint {} not found", mountpoint)),
+            Some((mountpoint, mount_point)) => {
+                // fuser only unmounts the mount points it mounted.
+                if mount_point.taken_over {
+                    takeover::unmount(&mountpoint);
+                }
+                Ok(())
+            }
+            None => {
+                error!("mountpoint {} not found", mountpoint);
+                Err(libc::EINVAL)
+            }
         }
     }
 
     pub fn list_mountpoints(&self) -> Vec<(String, String)> {
         let mut result = Vec::new();
         for k in self.mount_points.iter() {
-            result.push((k.key().clone(), k.value().0.clone()));
+            result.push((k.key().clone(), k.value().volume_name.clone()));
         }
         result
     }
 
+    pub fn stats(&self) -> DaemonStats {
+        let mounts = self
+            .mount_points
+            .iter()
+            .map(|k| MountPointStats {
+                mount_point: k.key().clone(),
+                volume_name: k.value().volume_name.clone(),
+                ops: k.value().stats.ops.load(Ordering::Relaxed),
+                read_bytes: k.value().stats.read_bytes.load(Ordering::Relaxed),
+                written_bytes: k.value().stats.written_bytes.load(Ordering::Relaxed),
+                last_error: self.client.last_error(&k.value().volume_name),
+            })
+            .collect();
+        DaemonStats {
+            mounts,
+            connections: self.client.client.connection_stats(),
+        }
+    }
+
     // remove old index file and sync mount points to index file
     pub fn sync_index_file(&self) {
+        let entries: Vec<(String, String, MountOptions)> = self
+            .mount_points
+            .iter()
+            .map(|k| {
+                (
+                    k.key().clone(),
+                    k.value().volume_name.clone(),
+                    k.value().options.clone(),
+                )
+            })
+            .collect();
+        let content = format_index(&entries);
+
         // write to swap file first
         let mut file = std::fs::File::create(format!("{}.swap", &self.index_file)).unwrap();
-        for k in self.mount_points.iter() {
-            let line = format!("{}\n{}\n{}\n\n", k.key(), k.value().0, k.value().1);
-            file.write_all(line.as_bytes()).unwrap();
-        }
-        // write a $ to indicate the end of file
-        file.write_all(b"$\n").unwrap();
+        file.write_all(content.as_bytes()).unwrap();
         file.sync_all().unwrap();
         drop(file);
 
         std::fs::remove_file(&self.index_file).unwrap_or(());
         let mut file = std::fs::File::create(&self.index_file).unwrap();
-        for k in self.mount_points.iter() {
-            let line = format!("{}\n{}\n{}\n\n", k.key(), k.value().0, k.value().1);
-            file.write_all(line.as_bytes()).unwrap();
-        }
-        // write a $ to indicate the end of file
-        file.write_all(b"$\n").unwrap();
+        file.write_all(content.as_bytes()).unwrap();
         file.sync_all().unwrap();
         drop(file);
         std::fs::remove_file(format!("{}.swap", &self.index_file)).unwrap_or(());
@@ -156,14 +404,13 @@ impl SealfsFused {
         &self,
         index_file_name: &str,
         allow_nonexist: bool,
-    ) -> Result<Vec<(String, String, bool)>, String> {
-        let mut result = Vec::new();
+    ) -> Result<Vec<(String, String, MountOptions)>, String> {
         let mut file = match std::fs::File::open(index_file_name) {
             Ok(f) => f,
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::NotFound {
                     if allow_nonexist {
-                        return Ok(result);
+                        return Ok(Vec::new());
                     }
                     return Err(format!("index file {} not found", index_file_name));
                 }
@@ -178,22 +425,8 @@ impl SealfsFused {
                 return Err(format!("read index file {} error: {}", index_file_name, e));
             }
         }
-        let lines: Vec<&str> = content.split('\n').collect();
-        for i in (0..lines.len()).step_by(4) {
-            if i + 3 >= lines.len() {
-                println!("{}", lines[i]);
-                if lines[i] == "$" {
-                    break;
-                }
-                return Err(format!("index file {} format error", index_file_name));
-            }
-            result.push((
-                lines[i].to_string(),
-                lines[i + 1].to_string(),
-                lines[i + 2].parse::<bool>().unwrap(),
-            ));
-        }
-        Ok(result)
+        parse_index(&content)
+            .map_err(|e| format!("index file {} format error: {}", index_file_name, e))
     }
 
     // read index file and mount all volumes
@@ -213,11 +446,19 @@ impl SealfsFused {
             }
         };
 
-        for (mountpoint, volume_name, read_only) in volumes {
-            match self.mount(mountpoint, volume_name.clone(), read_only).await {
+        for (mountpoint, volume_name, options) in volumes {
+            match self
+                .mount(mountpoint.clone(), volume_name.clone(), options)
+                .await
+            {
                 Ok(_) => {}
                 Err(e) => {
-                    return Err(e);
+                    return Err(format!(
+                        "mount {} on {} error: {}",
+                        volume_name,
+                        mountpoint,
+                        status_to_string(e)
+                    ));
                 }
             }
         }
@@ -229,26 +470,78 @@ impl SealfsFused {
 impl Handler for SealfsFused {
     async fn dispatch(
         &self,
-        _id: u32,
+        id: u32,
         operation_type: u32,
         _flags: u32,
         path: Vec<u8>,
         _data: Vec<u8>,
         metadata: Vec<u8>,
     ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        let uid = match self.peers.get(&id) {
+            Some(peer) => peer.0,
+            None => {
+                warn!("connection {} has no peer credentials", id);
+                return Ok((libc::EACCES, 0, 0, 0, vec![], vec![]));
+            }
+        };
         match operation_type {
             MOUNT => {
-                let send_meta_data: MountVolumeSendMetaData =
-                    bincode::deserialize(&metadata).unwrap();
+                let send_meta_data: MountVolumeSendMetaData = match bincode::deserialize(&metadata)
+                {
+                    Ok(meta_data) => meta_data,
+                    Err(e) => {
+                        error!("mount, deserialize error: {}", e);
+                        return Ok((libc::EINVAL, 0, 0, 0, vec![], vec![]));
+                    }
+                };
                 info!(
-                    "mounting volume {} to {}",
-                    send_meta_data.volume_name, send_meta_data.mount_point
+                    "uid {} mounting volume {} to {}",
+                    uid, send_meta_data.volume_name, send_meta_data.mount_point
                 );
+                if let Err(status) = self.check_mount(uid, &send_meta_data.mount_point) {
+                    warn!(
+                        "uid {} may not mount on {}: {}",
+                        uid,
+                        send_meta_data.mount_point,
+                        status_to_string(status)
+                    );
+                    return Ok((status, 0, 0, 0, vec![], vec![]));
+                }
+                // pinning moves the files of the volume, only root may do it.
+                if let Some(group) = &send_meta_data.group {
+                    if uid != 0 {
+                        warn!(
+                            "uid {} may not pin volume {}",
+                            uid, send_meta_data.volume_name
+                        );
+                        return Ok((libc::EPERM, 0, 0, 0, vec![], vec![]));
+                    }
+                    if let Err(status) = self
+                        .client
+                        .pin_volume(&send_meta_data.volume_name, group)
+                        .await
+                    {
+                        error!(
+                            "pin volume {} to {} failed: {}",
+                            send_meta_data.volume_name,
+                            group,
+                            status_to_string(status)
+                        );
+                        return Ok((status, 0, 0, 0, vec![], vec![]));
+                    }
+                }
                 match self
                     .mount(
                         send_meta_data.mount_point,
                         send_meta_data.volume_name,
-                        send_meta_data.read_only,
+                        MountOptions {
+                            read_only: send_meta_data.read_only,
+                            owner: send_meta_data.owner,
+                            uid,
+                            allowed_uids: send_meta_data.allowed_uids,
+                            root_squash: send_meta_data.root_squash,
+                            ttl: send_meta_data.ttl,
+                        },
                     )
                     .await
                 {
@@ -256,24 +549,25 @@ impl Handler for SealfsFused {
                         self.sync_index_file();
                         Ok((0, 0, 0, 0, vec![], vec![]))
                     }
-                    Err(e) => {
-                        error!("mount error: {}", e);
-                        Ok((libc::EIO, 0, 0, 0, vec![], vec![]))
-                    }
+                    Err(e) => Ok((e, 0, 0, 0, vec![], vec![])),
                 }
             }
             UMOUNT => {
-                let mountpoint = std::str::from_utf8(&path).unwrap();
-                info!("unmounting volume {}", mountpoint);
+                let mountpoint = match std::str::from_utf8(&path) {
+                    Ok(mountpoint) => mountpoint,
+                    Err(_) => return Ok((libc::EINVAL, 0, 0, 0, vec![], vec![])),
+                };
+                info!("uid {} unmounting volume {}", uid, mountpoint);
+                if let Err(status) = self.check_unmount(uid, mountpoint) {
+                    warn!("uid {} may not unmount {}", uid, mountpoint);
+                    return Ok((status, 0, 0, 0, vec![], vec![]));
+                }
                 match self.unmount(mountpoint).await {
                     Ok(()) => {
                         self.sync_index_file();
                         Ok((0, 0, 0, 0, vec![], vec![]))
                     }
-                    Err(e) => {
-                        error!("unmount error: {}", e);
-                        Ok((libc::EIO, 0, 0, 0, vec![], vec![]))
-                    }
+                    Err(e) => Ok((e, 0, 0, 0, vec![], vec![])),
                 }
             }
             LIST_MOUNTPOINTS => {
@@ -281,6 +575,10 @@ impl Handler for SealfsFused {
                 let result = self.list_mountpoints();
                 Ok((0, 0, 0, 0, vec![], bincode::serialize(&result).unwrap()))
             }
+            STATS => {
+                let stats = bincode::serialize(&self.stats()).unwrap();
+                Ok((0, 0, 0, stats.len(), vec![], stats))
+            }
             PROBE => {
                 info!("probe");
                 Ok((0, 0, 0, 0, vec![], vec![]))
@@ -291,6 +589,32 @@ impl Handler for SealfsFused {
             }
         }
     }
+
+    fn accept_peer(&self, id: u32, uid: u32, gid: u32) -> bool {
+        if !self.is_allowed(uid, gid) {
+            warn!("connection {} refused, uid: {}, gid: {}", id, uid, gid);
+            return false;
+        }
+        self.peers.insert(id, (uid, gid));
+        true
+    }
+
+    fn peer_closed(&self, id: u32) {
+        self.peers.remove(&id);
+    }
+}
+
+// allowed_uids returns the users allowed to use a mount point, and if the
+// mount needs allow_other for them: fuse only lets the other users in with
+// allow_other, SealFS checks them against the allowed uids.
+pub fn allowed_uids(options: &MountOptions) -> (Vec<u32>, bool) {
+    let daemon_uid = unsafe { libc::geteuid() };
+    let mut allowed_uids = vec![0, daemon_uid, options.uid];
+    allowed_uids.extend_from_slice(&options.allowed_uids);
+    let allow_other = allowed_uids
+        .iter()
+        .any(|uid| *uid != 0 && *uid != daemon_uid);
+    (allowed_uids, allow_other)
 }
 
 pub struct LocalCli {
@@ -319,11 +643,17 @@ impl LocalCli {
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn mount(
         &self,
         volume_name: &str,
         mount_point: &str,
         read_only: bool,
+        owner: bool,
+        allowed_uids: &[u32],
+        root_squash: bool,
+        group: Option<&str>,
+        ttl: CacheTtl,
     ) -> Result<(), i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -335,6 +665,11 @@ impl LocalCli {
             volume_name: volume_name.to_string(),
             mount_point: mount_point.to_string(),
             read_only,
+            owner,
+            allowed_uids: allowed_uids.to_vec(),
+            root_squash,
+            group: group.map(|group| group.to_owned()),
+            ttl,
         })
         .unwrap();
 
@@ -450,6 +785,47 @@ impl LocalCli {
         }
     }
 
+    pub async fn stats(&self) -> Result<DaemonStats, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut stats = vec![0u8; 1 << 20];
+
+        let result = self
+            .client
+            .call_remote(
+                &self.path,
+                STATS,
+                0,
+                "",
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut stats,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&stats[..recv_data_length]).map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("get stats failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
     pub async fn probe(&self) -> Result<(), i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -489,3 +865,151 @@ impl LocalCli {
         }
     }
 }
+
+// format_index writes a mount point per record: its path, volume, mode and
+// then its options, a record ends with an empty line and the file with a $.
+pub fn format_index(entries: &[(String, String, MountOptions)]) -> String {
+    let mut content = String::new();
+    for (mountpoint, volume_name, options) in entries {
+        let allowed_uids: Vec<String> = options
+            .allowed_uids
+            .iter()
+            .map(|uid| uid.to_string())
+            .collect();
+        content.push_str(&format!(
+            "{}\n{}\n{}\nowner={}\nuid={}\nallowed_uids={}\nroot_squash={}\nentry_ttl={}\nattr_ttl={}\nno_cache={}\n\n",
+            mountpoint,
+            volume_name,
+            options.read_only,
+            options.owner,
+            options.uid,
+            allowed_uids.join(","),
+            options.root_squash,
+            options.ttl.entry.as_millis(),
+            options.ttl.attr.as_millis(),
+            options.ttl.no_cache
+        ));
+    }
+    // write a $ to indicate the end of file
+    content.push_str("$\n");
+    content
+}
+
+pub fn parse_index(content: &str) -> Result<Vec<(String, String, MountOptions)>, String> {
+    let mut result = Vec::new();
+    let mut lines = content.split('\n');
+    loop {
+        let mountpoint = match lines.next() {
+            Some("$") => return Ok(result),
+            Some(line) if !line.is_empty() => line.to_string(),
+            _ => return Err("no end of file".to_string()),
+        };
+        let volume_name = match lines.next() {
+            Some(line) if !line.is_empty() => line.to_string(),
+            _ => return Err(format!("no volume for {}", mountpoint)),
+        };
+        let read_only = match lines.next().map(|line| line.parse::<bool>()) {
+            Some(Ok(read_only)) => read_only,
+            _ => return Err(format!("no mode for {}", mountpoint)),
+        };
+        // records written before the options were kept end here, they were
+        // mounted by root and the read-write ones by the volume owners.
+        let mut options = MountOptions {
+            read_only,
+            owner: !read_only,
+            ..Default::default()
+        };
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+            let invalid = || format!("invalid option {} for {}", line, mountpoint);
+            match line.split_once('=') {
+                Some(("owner", value)) => options.owner = value.parse().map_err(|_| invalid())?,
+                Some(("uid", value)) => options.uid = value.parse().map_err(|_| invalid())?,
+                Some(("allowed_uids", value)) => {
+                    options.allowed_uids = value
+                        .split(',')
+                        .filter(|uid| !uid.is_empty())
+                        .map(|uid| uid.parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| invalid())?
+                }
+                Some(("root_squash", value)) => {
+                    options.root_squash = value.parse().map_err(|_| invalid())?
+                }
+                // in milliseconds.
+                Some(("entry_ttl", value)) => {
+                    options.ttl.entry = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                Some(("attr_ttl", value)) => {
+                    options.ttl.attr = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                Some(("no_cache", value)) => {
+                    options.ttl.no_cache = value.parse().map_err(|_| invalid())?
+                }
+                // options of a newer daemon.
+                _ => warn!("unknown option {} for {}", line, mountpoint),
+            }
+        }
+        result.push((mountpoint, volume_name, options));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{format_index, parse_index, MountOptions};
+    use crate::common::serialization::CacheTtl;
+
+    #[test]
+    fn index_test() {
+        let entries = vec![
+            (
+                "/mnt/a".to_string(),
+                "a".to_string(),
+                MountOptions {
+                    read_only: true,
+                    owner: false,
+                    uid: 1000,
+                    allowed_uids: vec![1001, 1002],
+                    root_squash: true,
+                    ttl: CacheTtl {
+                        entry: Duration::from_millis(200),
+                        ..CacheTtl::none()
+                    },
+                },
+            ),
+            (
+                "/mnt/b".to_string(),
+                "b".to_string(),
+                MountOptions::default(),
+            ),
+        ];
+        assert_eq!(parse_index(&format_index(&entries)).unwrap(), entries);
+        assert_eq!(parse_index(&format_index(&[])).unwrap(), vec![]);
+
+        // an index written before the options were kept.
+        assert_eq!(
+            parse_index("/mnt/a\na\nfalse\n\n$\n").unwrap(),
+            vec![(
+                "/mnt/a".to_string(),
+                "a".to_string(),
+                MountOptions {
+                    read_only: false,
+                    owner: true,
+                    uid: 0,
+                    allowed_uids: vec![],
+                    root_squash: false,
+                    ttl: CacheTtl::default(),
+                },
+            )]
+        );
+
+        // a swap file cut by a crash.
+        assert!(parse_index("/mnt/a\na\nfalse\nowner=false\n").is_err());
+        assert!(parse_index("").is_err());
+        assert!(parse_index("/mnt/a\na\nfalse\nuid=x\n\n$\n").is_err());
+    }
+}
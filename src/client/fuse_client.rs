@@ -2,42 +2,81 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::common::errors::CONNECTION_ERROR;
-use crate::common::hash_ring::HashRing;
+use crate::common::cache::{AttrCache, NegativeCache, OwnerCache};
+use crate::common::errors::{status_to_errno, status_to_string, CONNECTION_ERROR, STALE_EPOCH};
+use crate::common::hash_ring::{FailureDomain, HashRing};
 use crate::common::info_syncer::{ClientStatusMonitor, InfoSyncer};
+use crate::common::page_cache::{page_stamp, SharedPageCache};
 use crate::common::sender::{Sender, REQUEST_TIMEOUT};
 use crate::common::serialization::{
-    file_attr_as_bytes_mut, ClusterStatus, CreateDirSendMetaData, CreateFileSendMetaData,
-    DeleteDirSendMetaData, DeleteFileSendMetaData, OpenFileSendMetaData, OperationType,
-    ReadDirSendMetaData, ReadFileSendMetaData, Volume, WriteFileSendMetaData,
+    bytes_as_dir_entries, file_attr_as_bytes_mut, AtimePolicy, CacheTtl, ClusterStatus,
+    CreateDirSendMetaData, CreateFileSendMetaData, DedupScanRecvMetaData, DedupScanSendMetaData,
+    DeleteDirSendMetaData, DeleteFileSendMetaData, FileLayout, GetMembershipChangesRecvMetaData,
+    GrepMatch, GrepSendMetaData, LockSendMetaData, ManagerOperationType, OpenFileSendMetaData,
+    OperationType, ReadDirSendMetaData, ReadFileSendMetaData, RenameSendMetaData,
+    SetAttrSendMetaData, SetAttrTreeSendMetaData, SetVolumeSendMetaData,
+    TransferStatusRecvMetaData, Volume, VolumeDay, VolumeInfo, WriteFileSendMetaData,
 };
-use crate::common::util::{empty_dir, empty_file};
+use crate::common::util::{empty_dir, empty_file, hostname, path_split};
 use crate::rpc;
-use crate::rpc::client::TcpStreamCreator;
+use crate::rpc::local::{LocalReadHalf, LocalStreamCreator, LocalWriteHalf};
+use crate::rpc::protocol::REQUEST_FLAG_STREAM;
 use async_trait::async_trait;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use fuser::{
-    ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen,
-    ReplyWrite,
+    consts::FOPEN_DIRECT_IO, FileAttr, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyLock, ReplyLseek, ReplyOpen, ReplyWrite,
 };
 use libc::{mode_t, DT_DIR, DT_LNK, DT_REG};
-use log::{debug, error};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
 use spin::RwLock;
+use std::collections::HashSet;
 use std::ffi::{OsStr, OsString};
 use std::ops::Deref;
+use std::os::unix::fs::FileExt;
 use std::sync::atomic::AtomicI32;
-use std::sync::Arc;
-use std::time::Duration;
-const TTL: Duration = Duration::from_secs(1); // 1 second
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::journal::Journal;
+use super::short_circuit::ShortCircuit;
+use super::write_batch::{Batch, WriteBatch};
+// the attrs got with a listing answer the lookups that follow it for as long.
+const ATTR_CACHE_TTL: Duration = Duration::from_secs(1);
+// how long a missing path is remembered, and how many of them.
+const NEGATIVE_TTL: Duration = Duration::from_secs(1);
+const NEGATIVE_CACHE_CAPACITY: usize = 65536;
+// a blocking lock is asked again after these, doubled up to the max.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// the attrs got by readdir for the following lookups, see AttrCache.
+const ATTR_CACHE_CAPACITY: usize = 65536;
+// the stamps of the files kept for the page cache, all of them are
+// forgotten past this number.
+const PAGE_STAMPS_CAPACITY: usize = 65536;
+// the owners of the paths kept, see OwnerCache.
+const OWNER_CACHE_CAPACITY: usize = 65536;
+
+// interval of retrying the journal replay in the disconnected mode.
+const JOURNAL_REPLAY_INTERVAL: Duration = Duration::from_secs(5);
+// size of the pages of a tree listing.
+const TREE_PAGE_SIZE: u32 = 64 * 1024;
+// paths changed in a batch of a tree change.
+const SET_ATTR_TREE_BATCH: u32 = 4096;
+// bytes of the matches in a response of grep.
+const GREP_PAGE_SIZE: u32 = 1 << 20;
+// times an upload part is resent after a network error.
+const UPLOAD_PART_RETRIES: u32 = 3;
+const UPLOAD_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// interval of syncing the servers in maintenance, and of checking whether
+// the server of a held change is back.
+const MAINTENANCE_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+// a change held longer than this fails with EAGAIN.
+const MAINTENANCE_WAIT_TIMEOUT: Duration = Duration::from_secs(600);
 
 pub struct Client {
-    pub client: Arc<
-        rpc::client::RpcClient<
-            tokio::net::tcp::OwnedReadHalf,
-            tokio::net::tcp::OwnedWriteHalf,
-            TcpStreamCreator,
-        >,
-    >,
+    pub client: Arc<rpc::client::RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
     pub sender: Arc<Sender>,
     pub inodes: DashMap<String, u64>,
     pub inodes_reverse: DashMap<u64, String>,
@@ -48,6 +87,44 @@ pub struct Client {
     pub hash_ring: Arc<RwLock<Option<HashRing>>>,
     pub new_hash_ring: Arc<RwLock<Option<HashRing>>>,
     pub manager_address: Arc<tokio::sync::Mutex<String>>,
+    pub volumes: DashMap<String, Volume>,
+    // set when the disconnected mode is enabled.
+    pub journal: OnceLock<Journal>,
+    // set when the requests go through a cache node.
+    pub cache_node: OnceLock<String>,
+    // set when the local files of the servers are read directly.
+    pub short_circuit: OnceLock<ShortCircuit>,
+    // set when the chunks read are shared with the other clients of the host.
+    pub page_cache: OnceLock<SharedPageCache>,
+    // set when the small writes are coalesced.
+    pub write_batch: OnceLock<WriteBatch>,
+    // path -> the stamp of its chunks in the page cache.
+    pub page_stamps: DashMap<String, u64>,
+    // servers in maintenance, synced from the manager.
+    pub maintenance_servers: DashSet<String>,
+    // volume name -> the last error replied for it.
+    pub last_errors: DashMap<String, LastError>,
+    // (volume name, operation) -> the errors replied for them.
+    pub error_counts: DashMap<(String, String), u64>,
+    pub negative_cache: NegativeCache,
+    pub attr_cache: AttrCache,
+    pub owner_cache: OwnerCache,
+    // this client in the POSIX locks it takes, see server/posix_lock.rs.
+    pub lock_client: String,
+    // (inode, lock owner) of the locks taken, released when the file is
+    // closed.
+    pub locked: DashSet<(u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LastError {
+    pub operation: String,
+    pub path: String,
+    // the status of the request and the errno replied for it.
+    pub status: i32,
+    pub errno: i32,
+    // seconds since the epoch.
+    pub time: u64,
 }
 
 impl Default for Client {
@@ -92,6 +169,12 @@ impl ClientStatusMonitor for Client {
     fn new_hash_ring(&self) -> &Arc<RwLock<Option<HashRing>>> {
         &self.new_hash_ring
     }
+    fn proxy_address(&self) -> Option<&str> {
+        self.cache_node.get().map(|address| address.as_str())
+    }
+    fn owner_cache(&self) -> Option<&OwnerCache> {
+        Some(&self.owner_cache)
+    }
 }
 
 impl Client {
@@ -109,7 +192,323 @@ impl Client {
             hash_ring: Arc::new(RwLock::new(None)),
             new_hash_ring: Arc::new(RwLock::new(None)),
             manager_address: Arc::new(tokio::sync::Mutex::new("".to_string())),
+            volumes: DashMap::new(),
+            journal: OnceLock::new(),
+            cache_node: OnceLock::new(),
+            short_circuit: OnceLock::new(),
+            page_cache: OnceLock::new(),
+            write_batch: OnceLock::new(),
+            page_stamps: DashMap::new(),
+            maintenance_servers: DashSet::new(),
+            last_errors: DashMap::new(),
+            error_counts: DashMap::new(),
+            negative_cache: NegativeCache::new(NEGATIVE_TTL, NEGATIVE_CACHE_CAPACITY),
+            attr_cache: AttrCache::new(ATTR_CACHE_TTL, ATTR_CACHE_CAPACITY),
+            owner_cache: OwnerCache::new(OWNER_CACHE_CAPACITY),
+            lock_client: format!("{}:{}", hostname(), std::process::id()),
+            locked: DashSet::new(),
+        }
+    }
+
+    // use_cache_node sends all the file requests to the cache node at address.
+    pub async fn use_cache_node(&self, address: &str) -> Result<(), i32> {
+        self.add_connection(address).await?;
+        if self.cache_node.set(address.to_owned()).is_err() {
+            return Err(libc::EEXIST);
+        }
+        Ok(())
+    }
+
+    // enable_journal turns on the disconnected mode: writes to unreachable
+    // servers go to the journal at path and are replayed later.
+    pub fn enable_journal(&self, path: &str) -> Result<(), i32> {
+        let journal = Journal::open(path)?;
+        if self.journal.set(journal).is_err() {
+            return Err(libc::EEXIST);
+        }
+        Ok(())
+    }
+
+    // enable_short_circuit reads the files of the servers of this host from
+    // their local files, see short_circuit.rs.
+    pub fn enable_short_circuit(&self) -> Result<(), i32> {
+        if self.cache_node.get().is_some() {
+            return Err(libc::EINVAL);
+        }
+        if self.short_circuit.set(ShortCircuit::default()).is_err() {
+            return Err(libc::EEXIST);
+        }
+        Ok(())
+    }
+
+    // enable_page_cache shares the chunks read with the other clients of this
+    // host in the page cache at path, see page_cache.rs.
+    pub fn enable_page_cache(&self, path: &str, size: usize) -> Result<(), i32> {
+        let page_cache = SharedPageCache::open(path, size)?;
+        if self.page_cache.set(page_cache).is_err() {
+            return Err(libc::EEXIST);
+        }
+        Ok(())
+    }
+
+    // enable_write_batch coalesces the writes of less than max_size bytes
+    // following each other, sent after interval at most, see write_batch.rs.
+    pub fn enable_write_batch(&self, max_size: usize, interval: Duration) -> Result<(), i32> {
+        if max_size == 0 {
+            return Err(libc::EINVAL);
+        }
+        if self
+            .write_batch
+            .set(WriteBatch::new(max_size, interval))
+            .is_err()
+        {
+            return Err(libc::EEXIST);
+        }
+        Ok(())
+    }
+
+    // note_page_stamp keeps the stamp of the chunks of path from an attr got
+    // from the servers, before its ino is replaced.
+    fn note_page_stamp(&self, path: &str, attr: &FileAttr) {
+        if self.page_cache.get().is_none() {
+            return;
+        }
+        let Some(stamp) = page_stamp(attr) else {
+            return;
+        };
+        if self.page_stamps.len() >= PAGE_STAMPS_CAPACITY {
+            self.page_stamps.clear();
+        }
+        self.page_stamps.insert(path.to_owned(), stamp);
+    }
+
+    // forget_pages stops reading path from the page cache until its new attr
+    // is got, and drops the chunks of length bytes at offset for the other
+    // clients.
+    fn forget_pages(&self, path: &str, offset: i64, length: usize) {
+        if let Some(page_cache) = self.page_cache.get() {
+            self.page_stamps.remove(path);
+            if length > 0 {
+                page_cache.invalidate(path, offset as u64, length as u64);
+            }
+        }
+    }
+
+    pub async fn replay_journal_loop(&self) {
+        loop {
+            tokio::time::sleep(JOURNAL_REPLAY_INTERVAL).await;
+            if let Err(e) = self.replay_journal().await {
+                debug!("replay journal stopped: {}", status_to_string(e));
+            }
+        }
+    }
+
+    // replay_journal sends the journaled writes to the servers in order.
+    // It stops at the first unreachable server and goes on from there next time.
+    pub async fn replay_journal(&self) -> Result<(), i32> {
+        let journal = match self.journal.get() {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let _replaying = journal.lock_replay().await;
+        let entries = journal.entries();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        info!("replay {} journaled writes", entries.len());
+        let (mut checked, mut conflicts) = (HashSet::new(), HashSet::new());
+        for (i, entry) in entries.iter().enumerate() {
+            if self.in_maintenance(&entry.path) {
+                journal.consume(i)?;
+                return Err(libc::EAGAIN);
+            }
+            let (server_address, epoch) = self.get_connection_route(&entry.path);
+            // the file must not be changed by others since we went offline.
+            if !checked.contains(&entry.path) {
+                if let Some(base_mtime) = entry.base_mtime {
+                    match self
+                        .sender
+                        .get_file_attr(&server_address, &entry.path)
+                        .await
+                    {
+                        Ok(attr) if attr.mtime == base_mtime => {}
+                        Err(CONNECTION_ERROR) => {
+                            journal.consume(i)?;
+                            return Err(CONNECTION_ERROR);
+                        }
+                        _ => {
+                            conflicts.insert(entry.path.clone());
+                        }
+                    }
+                }
+                checked.insert(entry.path.clone());
+            }
+            if conflicts.contains(&entry.path) {
+                journal.save_conflict(entry)?;
+                continue;
+            }
+            match self
+                .sender
+                .write_file(
+                    &server_address,
+                    &entry.path,
+                    entry.offset,
+                    epoch,
+                    0,
+                    &entry.data,
+                )
+                .await
+            {
+                Ok(_) => {}
+                Err(CONNECTION_ERROR) => {
+                    journal.consume(i)?;
+                    return Err(CONNECTION_ERROR);
+                }
+                // replayed again from this entry with the new hash ring.
+                Err(STALE_EPOCH) => {
+                    journal.consume(i)?;
+                    self.refresh_hash_ring().await?;
+                    return Err(STALE_EPOCH);
+                }
+                Err(e) => {
+                    error!(
+                        "replay write of {} failed: {}",
+                        entry.path,
+                        status_to_string(e)
+                    );
+                    conflicts.insert(entry.path.clone());
+                    journal.save_conflict(entry)?;
+                }
+            }
+        }
+        journal.consume(entries.len())?;
+        for path in checked {
+            self.refresh_journal_mtime(&path).await;
+        }
+        Ok(())
+    }
+
+    // replay_journal_of replays the journal if it still has writes of path,
+    // so that a new write of path is not overwritten by the older ones. It
+    // fails if writes of path are left.
+    async fn replay_journal_of(&self, path: &str) -> Result<(), i32> {
+        let journal = match self.journal.get() {
+            Some(journal) if journal.has_entries(path) => journal,
+            _ => return Ok(()),
+        };
+        let result = self.replay_journal().await;
+        match journal.has_entries(path) {
+            true => Err(result.err().unwrap_or(libc::EAGAIN)),
+            false => Ok(()),
+        }
+    }
+
+    // refresh_journal_mtime records the mtime of path after it was changed
+    // by ourselves, for the conflict check of the writes journaled later.
+    async fn refresh_journal_mtime(&self, path: &str) {
+        if let Some(journal) = self.journal.get() {
+            let (server_address, _) = self.get_connection_route(path);
+            let attr = self.sender.get_file_attr(&server_address, path).await;
+            journal.set_mtime(path, attr.ok().map(|attr| attr.mtime));
+        }
+    }
+
+    pub async fn sync_maintenance_loop(&self) {
+        loop {
+            match self
+                .sender
+                .get_maintenance_servers(&self.manager_address.lock().await)
+                .await
+            {
+                Ok(servers) => {
+                    self.maintenance_servers
+                        .retain(|server| servers.contains(server));
+                    for server in servers {
+                        if self.maintenance_servers.insert(server.clone()) {
+                            info!("server {} enters maintenance", server);
+                        }
+                    }
+                }
+                Err(e) => debug!("sync maintenance failed: {}", status_to_string(e)),
+            }
+            tokio::time::sleep(MAINTENANCE_SYNC_INTERVAL).await;
+        }
+    }
+
+    // reply_error returns the errno replied for status of operation on path,
+    // and keeps it as the last error of the volume. Missing files are not
+    // errors of the volume, but a missing volume root means the volume is gone.
+    pub fn reply_error(&self, operation: &str, path: &str, status: i32) -> i32 {
+        let mut errno = status_to_errno(status);
+        if errno == libc::ENOENT {
+            if path.contains('/') {
+                return errno;
+            }
+            errno = libc::ESTALE;
+        }
+        debug!(
+            "{} {} error: {}, reply {}",
+            operation,
+            path,
+            status_to_string(status),
+            status_to_string(errno)
+        );
+        let volume_name = path.split('/').next().unwrap_or_default();
+        *self
+            .error_counts
+            .entry((volume_name.to_owned(), operation.to_owned()))
+            .or_default() += 1;
+        self.last_errors.insert(
+            volume_name.to_owned(),
+            LastError {
+                operation: operation.to_owned(),
+                path: path.to_owned(),
+                status,
+                errno,
+                time: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            },
+        );
+        errno
+    }
+
+    pub fn last_error(&self, volume_name: &str) -> Option<LastError> {
+        self.last_errors
+            .get(volume_name)
+            .map(|last_error| last_error.clone())
+    }
+
+    // in_maintenance checks whether the owner of path is in maintenance.
+    pub fn in_maintenance(&self, path: &str) -> bool {
+        !self.maintenance_servers.is_empty()
+            && self.maintenance_servers.contains(&self.get_address(path))
+    }
+
+    // wait_maintenance holds a change until the owners of paths are out of
+    // maintenance. Reads are not held, they are served until the server stops.
+    pub async fn wait_maintenance(&self, paths: &[&str]) -> Result<(), i32> {
+        let start = Instant::now();
+        while paths.iter().any(|path| self.in_maintenance(path)) {
+            if start.elapsed() > MAINTENANCE_WAIT_TIMEOUT {
+                return Err(libc::EAGAIN);
+            }
+            tokio::time::sleep(MAINTENANCE_SYNC_INTERVAL).await;
         }
+        Ok(())
+    }
+
+    pub async fn set_maintenance(&self, server_address: &str, enter: bool) -> Result<(), i32> {
+        self.sender
+            .set_maintenance(&self.manager_address.lock().await, server_address, enter)
+            .await
+    }
+
+    pub async fn get_membership_changes(&self) -> Result<GetMembershipChangesRecvMetaData, i32> {
+        self.sender
+            .get_membership_changes(&self.manager_address.lock().await)
+            .await
     }
 
     pub fn remove_connection(&self, server_address: &str) {
@@ -135,10 +534,18 @@ impl Client {
         let inode = self.get_new_inode();
         self.inodes_reverse.insert(inode, volume_name.to_string());
         self.inodes.insert(volume_name.to_string(), inode);
-        self.sender
+        self.load_volume(volume_name).await?;
+        Ok(inode)
+    }
+
+    // load_volume gets the settings of a volume, whose root has an inode.
+    pub async fn load_volume(&self, volume_name: &str) -> Result<(), i32> {
+        let volume = self
+            .sender
             .init_volume(&self.get_connection_address(volume_name), volume_name)
             .await?;
-        Ok(inode)
+        self.volumes.insert(volume_name.to_string(), volume);
+        Ok(())
     }
 
     pub async fn list_volumes(&self) -> Result<Vec<Volume>, i32> {
@@ -157,24 +564,478 @@ impl Client {
             .await
     }
 
+    pub async fn set_server_weight(&self, server_address: &str, weight: usize) -> Result<(), i32> {
+        self.sender
+            .set_server_weight(&self.manager_address.lock().await, server_address, weight)
+            .await
+    }
+
+    pub async fn set_server_group(&self, server_address: &str, group: &str) -> Result<(), i32> {
+        self.sender
+            .set_server_group(&self.manager_address.lock().await, server_address, group)
+            .await
+    }
+
+    pub async fn set_server_domain(
+        &self,
+        server_address: &str,
+        domain: FailureDomain,
+    ) -> Result<Vec<String>, i32> {
+        self.sender
+            .set_server_domain(&self.manager_address.lock().await, server_address, domain)
+            .await
+    }
+
+    pub async fn pin_volume(&self, name: &str, group: &str) -> Result<(), i32> {
+        self.sender
+            .pin_volume(&self.manager_address.lock().await, name, group)
+            .await
+    }
+
     pub fn get_full_path(&self, parent: &str, name: &OsStr) -> String {
         let path = format!("{}/{}", parent, name.to_str().unwrap());
         path
     }
 
-    pub async fn create_volume(&self, name: &str, size: u64) -> Result<(), i32> {
+    pub async fn create_volume(
+        &self,
+        name: &str,
+        size: u64,
+        atime_policy: AtimePolicy,
+        public: bool,
+        description: &str,
+    ) -> Result<(), i32> {
+        // the manager reserves the name first, the clients may pick different
+        // servers for it during a rebalance.
+        let manager_address = self.manager_address.lock().await.clone();
         self.sender
-            .create_volume(&self.get_connection_address(name), name, size)
+            .volume_namespace(
+                &manager_address,
+                ManagerOperationType::ReserveVolume,
+                name,
+                &[],
+            )
+            .await?;
+        let result = self
+            .sender
+            .create_volume(
+                &self.get_connection_address(name),
+                name,
+                size,
+                atime_policy,
+                public,
+            )
+            .await;
+        let (operation_type, meta_data) = match result {
+            Ok(()) => {
+                let info = VolumeInfo {
+                    name: name.to_owned(),
+                    created_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    creator: format!("{}@{}", unsafe { libc::getuid() }, hostname()),
+                    description: description.to_owned(),
+                    quota: size,
+                    layout: FileLayout::default(),
+                    group: String::new(),
+                };
+                (
+                    ManagerOperationType::CommitVolume,
+                    bincode::serialize(&info).unwrap(),
+                )
+            }
+            Err(_) => (ManagerOperationType::ReleaseVolume, Vec::new()),
+        };
+        if let Err(e) = self
+            .sender
+            .volume_namespace(&manager_address, operation_type, name, &meta_data)
             .await
+        {
+            error!("update volume {} on the manager failed: {}", name, e);
+        }
+        result
     }
 
     pub async fn delete_volume(&self, name: &str) -> Result<(), i32> {
         self.sender
             .delete_volume(&self.get_connection_address(name), name)
+            .await?;
+        self.sender
+            .volume_namespace(
+                &self.manager_address.lock().await,
+                ManagerOperationType::ReleaseVolume,
+                name,
+                &[],
+            )
+            .await
+    }
+
+    pub async fn list_volume_infos(&self) -> Result<Vec<VolumeInfo>, i32> {
+        self.sender
+            .list_volume_infos(&self.manager_address.lock().await)
+            .await
+    }
+
+    pub async fn get_volume_history(&self, name: &str, days: u32) -> Result<Vec<VolumeDay>, i32> {
+        self.sender
+            .get_volume_history(&self.manager_address.lock().await, name, days)
+            .await
+    }
+
+    pub async fn set_volume(&self, update: &SetVolumeSendMetaData) -> Result<Vec<String>, i32> {
+        self.sender
+            .set_volume(&self.manager_address.lock().await, update)
+            .await
+    }
+
+    // walk_tree sends the (path, attr) pairs of all the files and directories
+    // under path to entries. Every server lists the attrs it stores in parallel,
+    // so the pairs arrive in no particular order.
+    pub async fn walk_tree(
+        &self,
+        path: &str,
+        entries: tokio::sync::mpsc::Sender<(String, FileAttr)>,
+    ) -> Result<(), i32> {
+        let servers = match self.hash_ring.read().as_ref() {
+            Some(hash_ring) => hash_ring.get_server_lists(),
+            None => return Err(CONNECTION_ERROR),
+        };
+        let mut tasks = tokio::task::JoinSet::new();
+        for server_address in servers {
+            let (sender, path, entries) = (self.sender.clone(), path.to_owned(), entries.clone());
+            tasks.spawn(async move {
+                let mut start_after = String::new();
+                loop {
+                    let page = sender
+                        .list_tree(&server_address, &path, &start_after, TREE_PAGE_SIZE)
+                        .await?;
+                    let Some((last, _)) = page.last() else {
+                        return Ok(());
+                    };
+                    start_after = last.clone();
+                    for entry in page {
+                        if entries.send(entry).await.is_err() {
+                            // the receiver is gone, nobody needs the rest.
+                            return Ok(());
+                        }
+                    }
+                }
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    tasks.abort_all();
+                    return Err(e);
+                }
+                Err(e) => {
+                    error!("walk tree task failed: {}", e);
+                    return Err(libc::EIO);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // set_attr_tree changes the permissions and the owner of path and of all
+    // the files and directories under it, each server changing the attrs it
+    // stores in parallel, and returns the number of paths changed. progress
+    // is called with the paths changed so far after every batch.
+    pub async fn set_attr_tree(
+        &self,
+        path: &str,
+        mode: Option<u32>,
+        owner: (Option<u32>, Option<u32>),
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64, i32> {
+        let servers = match self.hash_ring.read().as_ref() {
+            Some(hash_ring) => hash_ring.get_server_lists(),
+            None => return Err(CONNECTION_ERROR),
+        };
+        let (batches, mut changed) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = tokio::task::JoinSet::new();
+        for server_address in servers {
+            let (sender, path, batches) = (self.sender.clone(), path.to_owned(), batches.clone());
+            tasks.spawn(async move {
+                let mut md = SetAttrTreeSendMetaData {
+                    mode,
+                    uid: owner.0,
+                    gid: owner.1,
+                    start_after: String::new(),
+                    count: SET_ATTR_TREE_BATCH,
+                };
+                loop {
+                    let recv_md = sender.set_attr_tree(&server_address, &path, &md).await?;
+                    let _ = batches.send(recv_md.changed as u64);
+                    if recv_md.last.is_empty() {
+                        return Ok(());
+                    }
+                    md.start_after = recv_md.last;
+                }
+            });
+        }
+        drop(batches);
+        let mut total = 0;
+        while let Some(batch) = changed.recv().await {
+            total += batch;
+            progress(total);
+        }
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(e),
+                Err(e) => {
+                    error!("set attr tree task failed: {}", e);
+                    return Err(libc::EIO);
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    // transfer_status returns the transfers of the last rebalance of every
+    // server of the hash ring and of the new one, if any.
+    pub async fn transfer_status(&self) -> Result<Vec<(String, TransferStatusRecvMetaData)>, i32> {
+        let mut servers = Vec::new();
+        for ring in [&self.hash_ring, &self.new_hash_ring] {
+            if let Some(ring) = ring.read().as_ref() {
+                servers.extend(ring.get_server_lists());
+            }
+        }
+        servers.sort();
+        servers.dedup();
+        let mut reports = Vec::new();
+        for server_address in servers {
+            self.add_connection(&server_address).await?;
+            let report = self.sender.transfer_status(&server_address).await?;
+            reports.push((server_address, report));
+        }
+        Ok(reports)
+    }
+
+    // dedup_scan counts the duplicate chunks of the files under path, each
+    // server scanning the files it stores in parallel, and adds up their
+    // counts. The duplicates are only looked for among the files of a server.
+    pub async fn dedup_scan(&self, path: &str, apply: bool) -> Result<DedupScanRecvMetaData, i32> {
+        let servers = match self.hash_ring.read().as_ref() {
+            Some(hash_ring) => hash_ring.get_server_lists(),
+            None => return Err(CONNECTION_ERROR),
+        };
+        let mut tasks = tokio::task::JoinSet::new();
+        for server_address in servers {
+            let (sender, path) = (self.sender.clone(), path.to_owned());
+            tasks.spawn(async move {
+                sender
+                    .dedup_scan(&server_address, &path, &DedupScanSendMetaData { apply })
+                    .await
+            });
+        }
+        let mut total = DedupScanRecvMetaData::default();
+        while let Some(result) = tasks.join_next().await {
+            let result = match result {
+                Ok(result) => result?,
+                Err(e) => {
+                    error!("dedup scan task failed: {}", e);
+                    return Err(libc::EIO);
+                }
+            };
+            total.files += result.files;
+            total.bytes += result.bytes;
+            total.chunks += result.chunks;
+            total.duplicate_chunks += result.duplicate_chunks;
+            total.duplicate_bytes += result.duplicate_bytes;
+            total.shared_bytes += result.shared_bytes;
+            total.unsupported |= result.unsupported;
+        }
+        Ok(total)
+    }
+
+    // shard_dir spreads the new entries of the directory over shards servers.
+    // create_log creates an append-only log at path, see FileLayout.append_only.
+    pub async fn create_log(&self, path: &str) -> Result<(), i32> {
+        let (parent, name) = path_split(path)?;
+        self.sender
+            .create_file(
+                &self.get_connection_address(&parent),
+                &parent,
+                &name,
+                0o644,
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0,
+                true,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn shard_dir(&self, path: &str, shards: u32) -> Result<(), i32> {
+        self.sender
+            .shard_dir(&self.get_connection_address(path), path, shards)
+            .await
+    }
+
+    // list_files returns the regular files under path, or path itself if it is a file.
+    pub async fn list_files(&self, path: &str) -> Result<Vec<String>, i32> {
+        let attr = self
+            .sender
+            .get_file_attr(&self.get_connection_address(path), path)
+            .await?;
+        if attr.kind != fuser::FileType::Directory {
+            return Ok(vec![path.to_owned()]);
+        }
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(TREE_PAGE_SIZE as usize);
+        let (result, files) = tokio::join!(self.walk_tree(path, sender), async {
+            let mut files = Vec::new();
+            while let Some((path, attr)) = receiver.recv().await {
+                if attr.kind == fuser::FileType::RegularFile {
+                    files.push(path);
+                }
+            }
+            files
+        });
+        result.map(|_| files)
+    }
+
+    // grep calls f with the lines of the file containing pattern, or with the
+    // offsets of pattern if lines is false, scanned on the server of the file.
+    pub async fn grep(
+        &self,
+        path: &str,
+        pattern: &[u8],
+        lines: bool,
+        mut f: impl FnMut(GrepMatch) -> bool,
+    ) -> Result<(), i32> {
+        let mut md = GrepSendMetaData {
+            pattern: pattern.to_vec(),
+            lines,
+            offset: 0,
+            size: GREP_PAGE_SIZE,
+        };
+        loop {
+            let (recv_md, matches) = self
+                .sender
+                .grep_file(&self.get_connection_address(path), path, &md)
+                .await?;
+            for m in matches {
+                // f returns false when it needs no more matches.
+                if !f(m) {
+                    return Ok(());
+                }
+            }
+            if recv_md.done {
+                return Ok(());
+            }
+            md.offset = recv_md.next_offset;
+        }
+    }
+
+    // upload_file copies the local file to path in parts of part_size bytes,
+    // parallel parts at a time. The parts are published together at the end,
+    // and a part that fails on the network is retried.
+    pub async fn upload_file(
+        &self,
+        local_path: &str,
+        path: &str,
+        part_size: u64,
+        parallel: usize,
+    ) -> Result<u64, i32> {
+        let local_file = std::fs::File::open(local_path).map_err(|e| {
+            error!("open {} failed: {}", local_path, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        let size = local_file
+            .metadata()
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?
+            .len();
+        let (parent, name) = path_split(path)?;
+        // the space of a new file is reserved on its server.
+        self.sender
+            .create_file(
+                &self.get_connection_address(&parent),
+                &parent,
+                &name,
+                0o644,
+                libc::O_CREAT | libc::O_RDWR,
+                size,
+                false,
+            )
+            .await?;
+        let address = self.get_connection_address(path);
+        let upload_id = self.sender.begin_upload(&address, path).await?;
+
+        let local_file = Arc::new(local_file);
+        let mut offsets = (0..size).step_by(part_size.max(1) as usize);
+        let mut tasks = tokio::task::JoinSet::new();
+        loop {
+            while tasks.len() < parallel.max(1) {
+                let Some(offset) = offsets.next() else {
+                    break;
+                };
+                let (sender, address, path, local_file) = (
+                    self.sender.clone(),
+                    address.clone(),
+                    path.to_owned(),
+                    local_file.clone(),
+                );
+                let length = part_size.min(size - offset) as usize;
+                tasks.spawn(async move {
+                    let mut data = vec![0u8; length];
+                    local_file
+                        .read_exact_at(&mut data, offset)
+                        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                    let mut retries = 0;
+                    loop {
+                        match sender
+                            .upload_part(&address, &path, upload_id, offset, &data)
+                            .await
+                        {
+                            Err(CONNECTION_ERROR) if retries < UPLOAD_PART_RETRIES => {
+                                retries += 1;
+                                tokio::time::sleep(UPLOAD_RETRY_INTERVAL).await;
+                            }
+                            result => return result,
+                        }
+                    }
+                });
+            }
+            let Some(result) = tasks.join_next().await else {
+                break;
+            };
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("upload part of {} failed: {}", path, status_to_string(e));
+                    tasks.abort_all();
+                    return Err(e);
+                }
+                Err(e) => {
+                    error!("upload task failed: {}", e);
+                    tasks.abort_all();
+                    return Err(libc::EIO);
+                }
+            }
+        }
+        self.sender
+            .complete_upload(&address, path, upload_id, size)
+            .await?;
+        Ok(size)
+    }
+
+    pub async fn prefetch_file(&self, path: &str) -> Result<u64, i32> {
+        self.sender
+            .prefetch_file(&self.get_connection_address(path), path)
             .await
     }
 
-    pub async fn lookup_remote(&self, parent: u64, name: OsString, reply: ReplyEntry) {
+    pub async fn lookup_remote(
+        &self,
+        parent: u64,
+        name: OsString,
+        ttl: CacheTtl,
+        reply: ReplyEntry,
+    ) {
         debug!(
             "lookup_remote, parent: {}, name: {}",
             parent,
@@ -188,6 +1049,29 @@ impl Client {
                 return;
             }
         };
+        if !ttl.no_cache && self.negative_cache.contains(&path) {
+            reply.error(libc::ENOENT);
+            return;
+        }
+        let cached = match ttl.no_cache {
+            true => None,
+            false => self.attr_cache.take(&path),
+        };
+        if let Some(mut file_attr) = cached {
+            self.note_page_stamp(&path, &file_attr);
+            if self.inodes.contains_key(&path) {
+                file_attr.ino = *self.inodes.get(&path).unwrap().value();
+            } else {
+                file_attr.ino = self.get_new_inode();
+                self.inodes.insert(path.clone(), file_attr.ino);
+                self.inodes_reverse.insert(file_attr.ino, path.clone());
+            }
+            if let Some(journal) = self.journal.get() {
+                journal.set_mtime(&path, Some(file_attr.mtime));
+            }
+            reply.entry(&ttl.entry, &file_attr, 0);
+            return;
+        }
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -219,8 +1103,11 @@ impl Client {
         match result {
             Ok(_) => {
                 debug!("lookup_remote status: {}", status);
+                if status == libc::ENOENT {
+                    self.negative_cache.insert(&path);
+                }
                 if status != 0 {
-                    reply.error(status);
+                    reply.error(self.reply_error("lookup", &path, status));
                     return;
                 }
                 debug!(
@@ -228,6 +1115,7 @@ impl Client {
                     &recv_meta_data[..recv_meta_data_length]
                 );
 
+                self.note_page_stamp(&path, &file_attr);
                 if self.inodes.contains_key(&path) {
                     file_attr.ino = *self.inodes.get(&path).unwrap().value();
                 } else {
@@ -235,15 +1123,19 @@ impl Client {
                     self.inodes.insert(path.clone(), file_attr.ino);
                     self.inodes_reverse.insert(file_attr.ino, path.clone());
                 }
+                if let Some(journal) = self.journal.get() {
+                    journal.set_mtime(&path, Some(file_attr.mtime));
+                }
 
-                reply.entry(&TTL, &file_attr, 0);
+                reply.entry(&ttl.entry, &file_attr, 0);
             }
             Err(_) => {
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("lookup", &path, CONNECTION_ERROR));
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn create_remote(
         &self,
         parent: u64,
@@ -251,6 +1143,8 @@ impl Client {
         mode: u32,
         umask: u32,
         flags: i32,
+        owner: (u32, u32),
+        ttl: CacheTtl,
         reply: ReplyCreate,
     ) {
         debug!("create_remote");
@@ -262,6 +1156,13 @@ impl Client {
                 return;
             }
         };
+        if let Err(e) = self
+            .wait_maintenance(&[&path, &self.get_full_path(&path, &name)])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -277,6 +1178,10 @@ impl Client {
             umask,
             flags,
             name: name.to_str().unwrap().to_owned(),
+            uid: owner.0,
+            gid: owner.1,
+            size_hint: 0,
+            append_only: false,
         })
         .unwrap();
 
@@ -301,7 +1206,7 @@ impl Client {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    reply.error(status);
+                    reply.error(self.reply_error("create", &path, status));
                     return;
                 }
                 debug!(
@@ -316,19 +1221,20 @@ impl Client {
 
                 file_attr.ino = self.get_new_inode();
 
+                self.negative_cache.invalidate_dir(&path);
                 let path = self.get_full_path(&path, &name);
                 self.inodes.insert(path.clone(), file_attr.ino);
                 self.inodes_reverse.insert(file_attr.ino, path);
 
-                reply.created(&TTL, &file_attr, 0, 0, 0);
+                reply.created(&ttl.entry, &file_attr, 0, 0, open_flags(ttl));
             }
             Err(_) => {
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("create", &path, CONNECTION_ERROR));
             }
         }
     }
 
-    pub async fn getattr_remote(&self, ino: u64, reply: ReplyAttr) {
+    pub async fn getattr_remote(&self, ino: u64, ttl: CacheTtl, reply: ReplyAttr) {
         debug!("getattr_remote");
         let path = match self.inodes_reverse.get(&ino) {
             Some(path) => path.clone(),
@@ -339,6 +1245,7 @@ impl Client {
             }
         };
         debug!("getattr_remote path: {:?}", path);
+        self.flush_writes(&path).await;
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -370,7 +1277,7 @@ impl Client {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    reply.error(status);
+                    reply.error(self.reply_error("getattr", &path, status));
                     return;
                 }
                 debug!(
@@ -383,6 +1290,7 @@ impl Client {
                 //     file_attr_simple.into()
                 // };
                 debug!("getattr_remote file_attr: {:?}", file_attr);
+                self.note_page_stamp(&path, &file_attr);
                 if self.inodes.contains_key(&path) {
                     file_attr.ino = *self.inodes.get(&path).unwrap().value();
                 } else {
@@ -390,13 +1298,70 @@ impl Client {
                     self.inodes.insert(path.clone(), file_attr.ino);
                     self.inodes_reverse.insert(file_attr.ino, path.clone());
                 }
-                reply.attr(&TTL, &file_attr);
+                if let Some(journal) = self.journal.get() {
+                    journal.set_mtime(&path, Some(file_attr.mtime));
+                }
+                reply.attr(&ttl.attr, &file_attr);
                 debug!("getattr_remote success");
             }
             Err(_) => {
                 debug!("getattr_remote error");
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("getattr", &path, CONNECTION_ERROR));
+            }
+        }
+    }
+
+    // setattr_remote changes the attrs of ino set in md, for chmod, chown,
+    // truncate and utimens, and replies the new attr.
+    pub async fn setattr_remote(
+        &self,
+        ino: u64,
+        mut md: SetAttrSendMetaData,
+        ttl: CacheTtl,
+        reply: ReplyAttr,
+    ) {
+        debug!("setattr_remote");
+        let path = match self.inodes_reverse.get(&ino) {
+            Some(path) => path.clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if let Err(e) = self.wait_maintenance(&[&path]).await {
+            reply.error(e);
+            return;
+        }
+        self.flush_writes(&path).await;
+        self.attr_cache.invalidate(&path);
+        // the chunks cached are of the version before the change.
+        self.forget_pages(&path, 0, 0);
+        let mut refreshed = false;
+        let result = loop {
+            let (server_address, epoch) = self.get_connection_route(&path);
+            md.epoch = epoch;
+            match self.sender.set_attr(&server_address, &path, &md).await {
+                // the owner changed since our hash ring, retry once with the
+                // manager's one.
+                Err(STALE_EPOCH) if !refreshed => {
+                    refreshed = true;
+                    if let Err(e) = self.refresh_hash_ring().await {
+                        break Err(e);
+                    }
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(mut file_attr) => {
+                self.note_page_stamp(&path, &file_attr);
+                file_attr.ino = ino;
+                if let Some(journal) = self.journal.get() {
+                    journal.set_mtime(&path, Some(file_attr.mtime));
+                }
+                reply.attr(&ttl.attr, &file_attr);
             }
+            Err(e) => reply.error(self.reply_error("setattr", &path, e)),
         }
     }
 
@@ -448,78 +1413,187 @@ impl Client {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    reply.error(status);
+                    reply.error(self.reply_error("readdir", &path, status));
                     return;
                 }
                 debug!(
                     "readdir_remote recv_data: {:?}",
                     &recv_data[..recv_data_length]
                 );
-                let mut total = 0;
-                let mut offset = offset;
-                while total < recv_data_length {
-                    let r#type = u8::from_le_bytes(recv_data[total..total + 1].try_into().unwrap());
-                    let name_len =
-                        u16::from_le_bytes(recv_data[total + 1..total + 3].try_into().unwrap());
-                    let name = String::from_utf8(
-                        recv_data[total + 3..total + 3 + name_len as usize]
-                            .try_into()
-                            .unwrap(),
-                    )
-                    .unwrap();
+                let entries = match bytes_as_dir_entries(&recv_data[..recv_data_length]) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        reply.error(self.reply_error("readdir", &path, e));
+                        return;
+                    }
+                };
+                let mut paths = Vec::new();
+                // d_off is the cursor of the entry, the kernel asks for the
+                // entries after it next.
+                for (r#type, d_off, name) in entries {
+                    let name = String::from_utf8_lossy(name).into_owned();
                     let kind = match r#type {
                         DT_REG => fuser::FileType::RegularFile,
                         DT_DIR => fuser::FileType::Directory,
                         DT_LNK => fuser::FileType::Symlink,
                         _ => fuser::FileType::RegularFile,
                     };
-                    offset += 1;
-                    let r = reply.add(1, offset, kind, name);
-                    if r {
+                    let entry_path = match name.as_str() {
+                        "." | ".." => None,
+                        _ => Some(format!("{}/{}", path, name)),
+                    };
+                    if reply.add(1, d_off, kind, name) {
                         break;
                     }
-
-                    total += 3 + name_len as usize;
+                    paths.extend(entry_path);
                 }
 
+                // without readdirplus, the lookups of the entries that follow
+                // a listing, e.g. of ls -l, are answered with attrs got in
+                // batches.
+                for (path, attr) in paths.iter().zip(self.batch_get_file_attr(&paths).await) {
+                    if let Ok(attr) = attr {
+                        self.attr_cache.insert(path, attr);
+                    }
+                }
                 reply.ok();
                 debug!("readdir_remote success");
             }
             Err(_) => {
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("readdir", &path, CONNECTION_ERROR));
             }
         }
     }
 
     pub async fn read_remote(&self, ino: u64, offset: i64, size: u32, reply: ReplyData) {
+        match self.read_data(ino, offset, size).await {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // read_data reads the file and returns its data or the errno to reply.
+    pub async fn read_data(&self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, i32> {
         debug!("read_remote");
         let path = match self.inodes_reverse.get(&ino) {
             Some(path) => path.clone(),
             None => {
-                reply.error(libc::ENOENT);
                 debug!("read_remote error");
-                return;
+                return Err(libc::ENOENT);
             }
         };
+        self.flush_writes(&path).await;
         let server_address = self.get_connection_address(&path);
 
-        let meta_data = bincode::serialize(&ReadFileSendMetaData { offset, size }).unwrap();
-
-        let mut status = 0i32;
-        let mut rsp_flags = 0u32;
+        let page_cache = self.page_cache.get().and_then(|page_cache| {
+            let stamp = *self.page_stamps.get(&path)?;
+            Some((page_cache, stamp))
+        });
+        if let Some((page_cache, stamp)) = page_cache {
+            if let Some(mut data) = page_cache.read(&path, stamp, offset as u64, size) {
+                if let Some(journal) = self.journal.get() {
+                    journal.apply(&path, offset, size, &mut data);
+                }
+                return Ok(data);
+            }
+        }
 
-        let mut recv_meta_data_length = 0usize;
-        let mut recv_data_length = 0usize;
+        if let Some(short_circuit) = self.short_circuit.get() {
+            if let Some(mut data) = short_circuit
+                .read(&self.sender, &server_address, &path, offset, size)
+                .await
+            {
+                if let Some(journal) = self.journal.get() {
+                    journal.cache_read(&path, offset, size, &data);
+                    journal.apply(&path, offset, size, &mut data);
+                }
+                return Ok(data);
+            }
+        }
 
+        // a read shorter than asked ends at the end of the file only when
+        // the server returns nothing more, the rest is asked again until then.
         let mut recv_data = vec![0u8; size as usize];
+        let mut received = 0;
+        let result = loop {
+            let length = match self
+                .read_server(
+                    &server_address,
+                    &path,
+                    offset + received as i64,
+                    &mut recv_data[received..],
+                )
+                .await
+            {
+                Ok(length) => length,
+                Err(e) => break Err(e),
+            };
+            received += length;
+            if length == 0 || received == recv_data.len() {
+                break Ok(());
+            }
+            debug!(
+                "read_remote {} short of {} bytes at {}",
+                path,
+                recv_data.len() - received,
+                offset + received as i64
+            );
+        };
+        match result {
+            Ok(()) => {
+                debug!("read_remote success, size: {}", received);
+                recv_data.truncate(received);
+                if let Some((page_cache, stamp)) = page_cache {
+                    page_cache.fill(&path, stamp, offset as u64, size, &recv_data);
+                }
+                if let Some(journal) = self.journal.get() {
+                    journal.cache_read(&path, offset, size, &recv_data);
+                    // writes not replayed yet are newer than the servers' data.
+                    journal.apply(&path, offset, size, &mut recv_data);
+                }
+                Ok(recv_data)
+            }
+            Err(CONNECTION_ERROR) => {
+                debug!("read_remote disconnected");
+                match self
+                    .journal
+                    .get()
+                    .and_then(|journal| journal.read(&path, offset, size))
+                {
+                    Some(data) => {
+                        debug!("read_remote disconnected, served from the journal");
+                        Ok(data)
+                    }
+                    None => Err(self.reply_error("read", &path, CONNECTION_ERROR)),
+                }
+            }
+            Err(e) => Err(self.reply_error("read", &path, e)),
+        }
+    }
 
-        let result = self
+    // read_server reads the data of path at offset from the server at
+    // server_address into buf, and returns its length.
+    async fn read_server(
+        &self,
+        server_address: &str,
+        path: &str,
+        offset: i64,
+        buf: &mut [u8],
+    ) -> Result<usize, i32> {
+        let meta_data = bincode::serialize(&ReadFileSendMetaData {
+            offset,
+            size: buf.len() as u32,
+        })
+        .unwrap();
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        if let Err(e) = self
             .client
             .call_remote(
-                &server_address,
+                server_address,
                 OperationType::ReadFile.into(),
-                0,
-                &path,
+                REQUEST_FLAG_STREAM,
+                path,
                 &meta_data,
                 &[],
                 &mut status,
@@ -527,30 +1601,30 @@ impl Client {
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
                 &mut [],
-                &mut recv_data,
+                buf,
                 REQUEST_TIMEOUT,
             )
-            .await;
-        match result {
-            Ok(()) => {
-                if status != 0 {
-                    reply.error(status);
-                    return;
-                }
-                debug!(
-                    "read_remote success recv_data: {:?}",
-                    &recv_data[..recv_data_length]
-                );
-                reply.data(&recv_data);
-            }
-            Err(e) => {
-                debug!("read_remote error: {:?}", e);
-                reply.error(libc::EIO);
-            }
+            .await
+        {
+            debug!("read_remote error: {:?}", e);
+            return Err(CONNECTION_ERROR);
+        }
+        match status {
+            0 => Ok(recv_data_length),
+            status => Err(status),
         }
     }
 
-    pub async fn write_remote(&self, ino: u64, offset: i64, data: Vec<u8>, reply: ReplyWrite) {
+    // write_remote writes data at offset, or at the end of the file chosen by
+    // its server with append, for the files opened with O_APPEND.
+    pub async fn write_remote(
+        &self,
+        ino: u64,
+        offset: i64,
+        data: Vec<u8>,
+        append: bool,
+        reply: ReplyWrite,
+    ) {
         debug!("write_remote");
         let path = match self.inodes_reverse.get(&ino) {
             Some(path) => path.clone(),
@@ -561,49 +1635,391 @@ impl Client {
             }
         };
         debug!("write_remote path: {:?}, data_len: {}", path, data.len());
-        let server_address = self.get_connection_address(&path);
-        let send_meta_data = bincode::serialize(&WriteFileSendMetaData { offset }).unwrap();
-        let mut status = 0i32;
-        let mut rsp_flags = 0u32;
+        self.attr_cache.invalidate(&path);
+        self.forget_pages(&path, offset, data.len());
+        // changes to a server in maintenance go to the journal until it is back,
+        // an append waits as its offset is not known.
+        if !append && self.in_maintenance(&path) {
+            if let Some(journal) = self.journal.get() {
+                match journal.append(&path, offset, &data) {
+                    Ok(()) => reply.written(data.len() as u32),
+                    Err(e) => reply.error(e),
+                }
+                return;
+            }
+        }
+        if let Err(e) = self.wait_maintenance(&[&path]).await {
+            reply.error(e);
+            return;
+        }
+        let result = match self.write_batch.get() {
+            Some(_) if append => {
+                self.flush_writes(&path).await;
+                self.append_data(&path, &data).await
+            }
+            Some(write_batch) => self.write_batched(write_batch, &path, offset, data).await,
+            None if append => self.append_data(&path, &data).await,
+            None => self.write_data(&path, offset, &data).await,
+        };
+        match result {
+            Ok(written) => reply.written(written as u32),
+            Err(e) => reply.error(self.reply_error("write", &path, e)),
+        }
+    }
 
-        let mut recv_meta_data_length = 0usize;
-        let mut recv_data_length = 0usize;
+    // write_data sends data to the server of path at offset, and returns the
+    // bytes written. A write the server completes partly is sent again for
+    // the rest. A failure after a part was written returns that part, the
+    // error is left to the next write.
+    async fn write_data(&self, path: &str, offset: i64, data: &[u8]) -> Result<usize, i32> {
+        // the servers are still unreachable for the journaled writes of
+        // path, this one follows them in the journal.
+        if let Err(e) = self.replay_journal_of(path).await {
+            debug!("replay journal of {} failed: {}", path, status_to_string(e));
+            if let Some(journal) = self.journal.get() {
+                journal.append(path, offset, data)?;
+                return Ok(data.len());
+            }
+        }
+        let mut written = 0;
+        let mut refreshed = false;
+        while written < data.len() {
+            let (server_address, epoch) = self.get_connection_route(path);
+            let send_meta_data = bincode::serialize(&WriteFileSendMetaData {
+                offset: offset + written as i64,
+                epoch,
+                version: 0,
+            })
+            .unwrap();
+            let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+                (0, 0, 0, 0);
+            let mut recv_meta_data = vec![0u8; std::mem::size_of::<u64>()];
+            let result = self
+                .client
+                .call_remote(
+                    &server_address,
+                    OperationType::WriteFile.into(),
+                    0,
+                    path,
+                    &send_meta_data,
+                    &data[written..],
+                    &mut status,
+                    &mut rsp_flags,
+                    &mut recv_meta_data_length,
+                    &mut recv_data_length,
+                    &mut recv_meta_data,
+                    &mut [],
+                    REQUEST_TIMEOUT,
+                )
+                .await;
+            if result.is_err() {
+                debug!("write_remote error");
+                return match self.journal.get() {
+                    Some(journal) => {
+                        journal.append(path, offset + written as i64, &data[written..])?;
+                        debug!("write_remote disconnected, journaled");
+                        Ok(data.len())
+                    }
+                    None if written > 0 => Ok(written),
+                    None => Err(CONNECTION_ERROR),
+                };
+            }
+            // the owner changed since our hash ring, retry once with the
+            // manager's one.
+            if status == STALE_EPOCH && !refreshed {
+                refreshed = true;
+                self.refresh_hash_ring().await?;
+                continue;
+            }
+            let size = match status {
+                0 => bincode::deserialize::<u64>(&recv_meta_data[..recv_meta_data_length]).unwrap()
+                    as usize,
+                _ => 0,
+            };
+            if size == 0 {
+                if written == 0 {
+                    return Err(if status == 0 { libc::EIO } else { status });
+                }
+                break;
+            }
+            written += size;
+        }
+        debug!("write_remote success, size: {}", written);
+        self.refresh_journal_mtime(path).await;
+        Ok(written)
+    }
+
+    // append_data sends data to the server of path to write it at the end of
+    // the file, in one request so that the appends of other clients do not
+    // overlap it, and returns the bytes written.
+    async fn append_data(&self, path: &str, data: &[u8]) -> Result<usize, i32> {
+        self.replay_journal_of(path).await?;
+        let mut refreshed = false;
+        loop {
+            let (server_address, epoch) = self.get_connection_route(path);
+            match self
+                .sender
+                .append_file(&server_address, path, epoch, data)
+                .await
+            {
+                // the owner changed since our hash ring, retry once with the
+                // manager's one.
+                Err(STALE_EPOCH) if !refreshed => {
+                    refreshed = true;
+                    self.refresh_hash_ring().await?;
+                }
+                Err(e) => return Err(e),
+                Ok(appended) if appended.size == 0 && !data.is_empty() => return Err(libc::EIO),
+                Ok(appended) => {
+                    self.forget_pages(path, appended.offset as i64, appended.size as usize);
+                    self.refresh_journal_mtime(path).await;
+                    return Ok(appended.size as usize);
+                }
+            }
+        }
+    }
 
-        let mut recv_meta_data = vec![0u8; 4];
+    // write_batched keeps a small write in the batch of path and returns its
+    // length, see write_batch.rs. The batch it does not follow is sent first.
+    async fn write_batched(
+        &self,
+        write_batch: &WriteBatch,
+        path: &str,
+        offset: i64,
+        data: Vec<u8>,
+    ) -> Result<usize, i32> {
+        let file = write_batch.file(path);
+        let mut batch = file.lock().await;
+        if let Some(e) = write_batch.take_error(path) {
+            return Err(e);
+        }
+        let length = data.len();
+        if !write_batch.append(&mut batch, offset, &data) {
+            if let Some(pending) = batch.take() {
+                self.send_batch(path, pending).await?;
+            }
+            if length >= write_batch.max_size {
+                return self.write_data(path, offset, &data).await;
+            }
+            *batch = Some(Batch::new(offset, data));
+        }
+        if batch
+            .as_ref()
+            .is_some_and(|pending| pending.data.len() >= write_batch.max_size)
+        {
+            self.send_batch(path, batch.take().unwrap()).await?;
+        }
+        Ok(length)
+    }
 
-        let result = self
-            .client
-            .call_remote(
-                &server_address,
-                OperationType::WriteFile.into(),
-                0,
-                &path,
-                &send_meta_data,
-                &data,
-                &mut status,
-                &mut rsp_flags,
-                &mut recv_meta_data_length,
-                &mut recv_data_length,
-                &mut recv_meta_data,
-                &mut [],
-                REQUEST_TIMEOUT,
-            )
-            .await;
-        match result {
-            Ok(()) => {
-                let size: u32 =
-                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
-                debug!("write_remote success, size: {}", size);
-                reply.written(size);
+    async fn send_batch(&self, path: &str, batch: Batch) -> Result<(), i32> {
+        match self.write_data(path, batch.offset, &batch.data).await? {
+            written if written == batch.data.len() => Ok(()),
+            _ => Err(libc::EIO),
+        }
+    }
+
+    // flush_writes sends the batch of path before a request reading or
+    // changing it, its error is kept for the next write, flush or fsync.
+    pub async fn flush_writes(&self, path: &str) {
+        let Some(write_batch) = self.write_batch.get() else {
+            return;
+        };
+        let Some(file) = write_batch.get(path) else {
+            return;
+        };
+        let mut batch = file.lock().await;
+        if let Some(pending) = batch.take() {
+            if let Err(e) = self.send_batch(path, pending).await {
+                write_batch.set_error(path, e);
             }
-            Err(_) => {
-                debug!("write_remote error");
-                reply.error(libc::EIO);
+        }
+    }
+
+    // flush_all_writes sends the batches of all the files.
+    pub async fn flush_all_writes(&self) {
+        let Some(write_batch) = self.write_batch.get() else {
+            return;
+        };
+        for path in write_batch.paths() {
+            self.flush_writes(&path).await;
+        }
+    }
+
+    // flush_writes_loop sends the batches which waited for the interval.
+    pub async fn flush_writes_loop(&self) {
+        let Some(write_batch) = self.write_batch.get() else {
+            return;
+        };
+        loop {
+            tokio::time::sleep(write_batch.interval).await;
+            for path in write_batch.due() {
+                self.flush_writes(&path).await;
+            }
+        }
+    }
+
+    // flush_remote sends the batch of a file closed, and replies the error
+    // of its writes sent in the background.
+    pub async fn flush_remote(&self, ino: u64, reply: ReplyEmpty) {
+        let path = match self.inodes_reverse.get(&ino) {
+            Some(path) => path.clone(),
+            None => {
+                reply.ok();
+                return;
+            }
+        };
+        self.flush_writes(&path).await;
+        match self
+            .write_batch
+            .get()
+            .and_then(|write_batch| write_batch.take_error(&path))
+        {
+            Some(e) => reply.error(self.reply_error("flush", &path, e)),
+            None => reply.ok(),
+        }
+    }
+
+    // setlk_remote takes or releases a POSIX lock of lock_owner, and with
+    // sleep waits for the conflicting locks to be released.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn setlk_remote(
+        &self,
+        ino: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let path = match self.inodes_reverse.get(&ino) {
+            Some(path) => path.clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        // the next owner of the lock reads the writes made under it.
+        self.flush_writes(&path).await;
+        let md = LockSendMetaData {
+            client: self.lock_client.clone(),
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            flock: false,
+        };
+        let mut interval = LOCK_RETRY_INTERVAL;
+        loop {
+            let operation_type = match typ {
+                libc::F_UNLCK => OperationType::Unlock,
+                _ => OperationType::Lock,
+            };
+            let server_address = self.get_connection_address(&path);
+            match self
+                .sender
+                .lock(&server_address, operation_type, &path, &md)
+                .await
+            {
+                Ok(_) => {
+                    if typ != libc::F_UNLCK {
+                        self.locked.insert((ino, lock_owner));
+                    }
+                    reply.ok();
+                    return;
+                }
+                Err(libc::EAGAIN) if sleep => {
+                    tokio::time::sleep(interval).await;
+                    interval = (interval * 2).min(MAX_LOCK_RETRY_INTERVAL);
+                }
+                Err(e) => {
+                    reply.error(self.reply_error("setlk", &path, e));
+                    return;
+                }
             }
         }
     }
 
-    pub async fn mkdir_remote(&self, parent: u64, name: OsString, _mode: u32, reply: ReplyEntry) {
+    // getlk_remote replies a lock conflicting with the one of lock_owner, or
+    // F_UNLCK.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn getlk_remote(
+        &self,
+        ino: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let path = match self.inodes_reverse.get(&ino) {
+            Some(path) => path.clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        let md = LockSendMetaData {
+            client: self.lock_client.clone(),
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+            flock: false,
+        };
+        let server_address = self.get_connection_address(&path);
+        match self
+            .sender
+            .lock(&server_address, OperationType::TestLock, &path, &md)
+            .await
+        {
+            Ok(Some(lock)) => reply.locked(lock.start, lock.end, lock.typ, lock.pid),
+            Ok(None) => reply.error(libc::EIO),
+            Err(e) => reply.error(self.reply_error("getlk", &path, e)),
+        }
+    }
+
+    // release_locks releases the locks of lock_owner on a file it closes.
+    pub async fn release_locks(&self, ino: u64, lock_owner: u64) {
+        if self.locked.remove(&(ino, lock_owner)).is_none() {
+            return;
+        }
+        let Some(path) = self.inodes_reverse.get(&ino).map(|path| path.clone()) else {
+            return;
+        };
+        let md = LockSendMetaData {
+            client: self.lock_client.clone(),
+            owner: lock_owner,
+            start: 0,
+            end: u64::MAX,
+            typ: libc::F_UNLCK,
+            pid: 0,
+            flock: false,
+        };
+        let server_address = self.get_connection_address(&path);
+        if let Err(e) = self
+            .sender
+            .lock(&server_address, OperationType::Unlock, &path, &md)
+            .await
+        {
+            error!("release locks of {}: {}", path, status_to_string(e));
+        }
+    }
+
+    pub async fn mkdir_remote(
+        &self,
+        parent: u64,
+        name: OsString,
+        mode: u32,
+        owner: (u32, u32),
+        ttl: CacheTtl,
+        reply: ReplyEntry,
+    ) {
         debug!("mkdir_remote");
         let path = match self.inodes_reverse.get(&parent) {
             Some(parent_path) => parent_path.deref().clone(),
@@ -614,6 +2030,13 @@ impl Client {
             }
         };
         debug!("mkdir_remote ,path: {:?}", &path);
+        if let Err(e) = self
+            .wait_maintenance(&[&path, &self.get_full_path(&path, &name)])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -624,10 +2047,11 @@ impl Client {
         let mut file_attr = Box::new(empty_dir());
         let recv_meta_data = file_attr_as_bytes_mut(&mut file_attr);
 
-        let mode: mode_t = 0o755;
         let send_meta_data = bincode::serialize(&CreateDirSendMetaData {
             mode,
             name: name.to_str().unwrap().to_owned(),
+            uid: owner.0,
+            gid: owner.1,
         })
         .unwrap();
 
@@ -652,7 +2076,7 @@ impl Client {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    reply.error(status);
+                    reply.error(self.reply_error("mkdir", &path, status));
                     return;
                 }
                 debug!(
@@ -667,19 +2091,267 @@ impl Client {
 
                 file_attr.ino = self.get_new_inode();
 
-                reply.entry(&TTL, &file_attr, 0);
+                self.negative_cache.invalidate_dir(&path);
+                reply.entry(&ttl.entry, &file_attr, 0);
 
                 let path = self.get_full_path(&path, &name);
                 self.inodes.insert(path.clone(), file_attr.ino);
                 self.inodes_reverse.insert(file_attr.ino, path);
             }
             Err(_) => {
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("mkdir", &path, CONNECTION_ERROR));
             }
         }
     }
 
-    pub async fn open_remote(&self, ino: u64, flags: i32, reply: ReplyOpen) {
+    pub async fn symlink_remote(
+        &self,
+        parent: u64,
+        name: OsString,
+        target: String,
+        owner: (u32, u32),
+        ttl: CacheTtl,
+        reply: ReplyEntry,
+    ) {
+        debug!("symlink_remote");
+        let path = match self.inodes_reverse.get(&parent) {
+            Some(parent_path) => parent_path.deref().clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .wait_maintenance(&[&path, &self.get_full_path(&path, &name)])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
+        let result = self
+            .sender
+            .create_symlink(
+                &self.get_connection_address(&path),
+                &path,
+                name.to_str().unwrap(),
+                &target,
+                owner,
+            )
+            .await;
+        match result {
+            Ok(mut file_attr) => {
+                file_attr.ino = self.get_new_inode();
+                self.negative_cache.invalidate_dir(&path);
+                reply.entry(&ttl.entry, &file_attr, 0);
+
+                let path = self.get_full_path(&path, &name);
+                self.inodes.insert(path.clone(), file_attr.ino);
+                self.inodes_reverse.insert(file_attr.ino, path);
+            }
+            Err(e) => reply.error(self.reply_error("symlink", &path, e)),
+        }
+    }
+
+    // link_remote adds newname in newparent as a hard link to the file ino,
+    // on the server of the file only.
+    pub async fn link_remote(
+        &self,
+        ino: u64,
+        newparent: u64,
+        newname: OsString,
+        ttl: CacheTtl,
+        reply: ReplyEntry,
+    ) {
+        debug!("link_remote");
+        let (target, path) = match (
+            self.inodes_reverse.get(&ino),
+            self.inodes_reverse.get(&newparent),
+        ) {
+            (Some(target), Some(parent_path)) => {
+                (target.deref().clone(), parent_path.deref().clone())
+            }
+            _ => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if let Err(e) = self
+            .wait_maintenance(&[&path, &target, &self.get_full_path(&path, &newname)])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
+        let result = self
+            .sender
+            .link(
+                &self.get_connection_address(&path),
+                &path,
+                newname.to_str().unwrap(),
+                &target,
+            )
+            .await;
+        match result {
+            Ok(mut file_attr) => {
+                file_attr.ino = self.get_new_inode();
+                self.negative_cache.invalidate_dir(&path);
+                reply.entry(&ttl.entry, &file_attr, 0);
+
+                let path = self.get_full_path(&path, &newname);
+                self.inodes.insert(path.clone(), file_attr.ino);
+                self.inodes_reverse.insert(file_attr.ino, path);
+            }
+            Err(e) => reply.error(self.reply_error("link", &path, e)),
+        }
+    }
+
+    // rename_remote moves name of parent to newname of newparent, the server
+    // of parent moving the file and the entries, see DistributedEngine::rename.
+    pub async fn rename_remote(
+        &self,
+        parent: u64,
+        name: OsString,
+        newparent: u64,
+        newname: OsString,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("rename_remote");
+        let (parent_path, new_parent_path) = match (
+            self.inodes_reverse.get(&parent),
+            self.inodes_reverse.get(&newparent),
+        ) {
+            (Some(parent_path), Some(new_parent_path)) => {
+                (parent_path.deref().clone(), new_parent_path.deref().clone())
+            }
+            _ => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        let path = self.get_full_path(&parent_path, &name);
+        let new_path = self.get_full_path(&new_parent_path, &newname);
+        for path in [&path, &new_path] {
+            self.flush_writes(path).await;
+            self.attr_cache.invalidate(path);
+            self.forget_pages(path, 0, 0);
+        }
+        if let Err(e) = self
+            .wait_maintenance(&[&parent_path, &new_parent_path, &path, &new_path])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
+        let send_meta_data = bincode::serialize(&RenameSendMetaData {
+            name: name.to_str().unwrap().to_owned(),
+            new_parent: new_parent_path.clone(),
+            new_name: newname.to_str().unwrap().to_owned(),
+            flags,
+        })
+        .unwrap();
+        let result = self
+            .sender
+            .rename(
+                &self.get_connection_address(&parent_path),
+                OperationType::Rename,
+                &parent_path,
+                &send_meta_data,
+            )
+            .await;
+        match result {
+            Ok(()) => {
+                self.negative_cache.invalidate_dir(&new_parent_path);
+                // the inode of the file it replaced is gone, and the file
+                // keeps its own.
+                if let Some((_, ino)) = self.inodes.remove(&new_path) {
+                    self.inodes_reverse.remove(&ino);
+                }
+                if let Some((_, ino)) = self.inodes.remove(&path) {
+                    self.inodes_reverse.insert(ino, new_path.clone());
+                    self.inodes.insert(new_path, ino);
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(self.reply_error("rename", &path, e)),
+        }
+    }
+
+    // fsync_remote replies once the data of the file or the entries of the
+    // directory ino are durable on its server.
+    pub async fn fsync_remote(&self, ino: u64, datasync: bool, reply: ReplyEmpty) {
+        debug!("fsync_remote");
+        let path = match self.inodes_reverse.get(&ino) {
+            Some(path) => path.clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if let Err(e) = self.wait_maintenance(&[&path]).await {
+            reply.error(e);
+            return;
+        }
+        self.flush_writes(&path).await;
+        if let Some(e) = self
+            .write_batch
+            .get()
+            .and_then(|write_batch| write_batch.take_error(&path))
+        {
+            reply.error(self.reply_error("fsync", &path, e));
+            return;
+        }
+        let server_address = self.get_connection_address(&path);
+        match self.sender.fsync(&server_address, &path, datasync).await {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.reply_error("fsync", &path, e)),
+        }
+    }
+
+    // seek_remote replies where the data or the hole at or after offset
+    // starts, the kernel seeks with the other whences itself.
+    pub async fn seek_remote(&self, ino: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        debug!("seek_remote");
+        let hole = match whence {
+            libc::SEEK_DATA => false,
+            libc::SEEK_HOLE => true,
+            _ => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+        if offset < 0 {
+            reply.error(libc::ENXIO);
+            return;
+        }
+        let path = match self.inodes_reverse.get(&ino) {
+            Some(path) => path.clone(),
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        self.flush_writes(&path).await;
+        let server_address = self.get_connection_address(&path);
+        match self
+            .sender
+            .seek(&server_address, &path, offset as u64, hole)
+            .await
+        {
+            Ok(offset) => reply.offset(offset as i64),
+            Err(e) => reply.error(self.reply_error("seek", &path, e)),
+        }
+    }
+
+    // readlink_remote replies the target of a symbolic link, its data.
+    pub async fn readlink_remote(&self, ino: u64, reply: ReplyData) {
+        match self.read_data(ino, 0, libc::PATH_MAX as u32).await {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    pub async fn open_remote(&self, ino: u64, flags: i32, ttl: CacheTtl, reply: ReplyOpen) {
         debug!("open_remote");
         if flags & libc::O_CREAT != 0 {
             todo!("open_remote O_CREAT") // this is not supported by the fuse crate
@@ -692,6 +2364,9 @@ impl Client {
                 return;
             }
         };
+        if flags & libc::O_TRUNC != 0 {
+            self.forget_pages(&path, 0, 0);
+        }
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -729,11 +2404,15 @@ impl Client {
             .await;
         match result {
             Ok(()) => {
-                reply.opened(self.get_new_fd(), 0);
+                if status != 0 {
+                    reply.error(self.reply_error("open", &path, status));
+                    return;
+                }
+                reply.opened(self.get_new_fd(), open_flags(ttl));
             }
             Err(e) => {
                 debug!("open_remote error: {}", e);
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("open", &path, CONNECTION_ERROR));
             }
         }
     }
@@ -748,6 +2427,17 @@ impl Client {
                 return;
             }
         };
+        self.flush_writes(&self.get_full_path(&path, &name)).await;
+        self.attr_cache
+            .invalidate(&self.get_full_path(&path, &name));
+        self.forget_pages(&self.get_full_path(&path, &name), 0, 0);
+        if let Err(e) = self
+            .wait_maintenance(&[&path, &self.get_full_path(&path, &name)])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -780,6 +2470,10 @@ impl Client {
             .await;
         match result {
             Ok(_) => {
+                if status != 0 {
+                    reply.error(self.reply_error("unlink", &path, status));
+                    return;
+                }
                 let path = self.get_full_path(&path, &name);
                 self.inodes_reverse
                     .remove(self.inodes.get(&path).as_deref().unwrap());
@@ -787,7 +2481,7 @@ impl Client {
                 reply.ok();
             }
             Err(_) => {
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("unlink", &path, CONNECTION_ERROR));
             }
         }
     }
@@ -802,6 +2496,15 @@ impl Client {
                 return;
             }
         };
+        self.attr_cache
+            .invalidate(&self.get_full_path(&path, &name));
+        if let Err(e) = self
+            .wait_maintenance(&[&path, &self.get_full_path(&path, &name)])
+            .await
+        {
+            reply.error(e);
+            return;
+        }
         let server_address = self.get_connection_address(&path);
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
@@ -834,6 +2537,10 @@ impl Client {
             .await;
         match result {
             Ok(_) => {
+                if status != 0 {
+                    reply.error(self.reply_error("rmdir", &path, status));
+                    return;
+                }
                 let path = self.get_full_path(&path, &name);
                 self.inodes_reverse
                     .remove(self.inodes.get(&path).as_deref().unwrap());
@@ -841,8 +2548,17 @@ impl Client {
                 reply.ok();
             }
             Err(_) => {
-                reply.error(libc::EIO);
+                reply.error(self.reply_error("rmdir", &path, CONNECTION_ERROR));
             }
         }
     }
 }
+
+// open_flags are the flags of the files opened: without the cache, the
+// kernel sends their reads and writes around its page cache.
+fn open_flags(ttl: CacheTtl) -> u32 {
+    match ttl.no_cache {
+        true => FOPEN_DIRECT_IO,
+        false => 0,
+    }
+}
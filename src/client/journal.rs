@@ -0,0 +1,285 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Journal of the disconnected mode.
+ * When the servers of a file are unreachable, writes are appended to a local
+ * journal file instead of failing, and reads are served from the data read
+ * before with the journaled writes applied on top. The journal is replayed
+ * when the servers are back. A file changed on the servers in the meantime
+ * is a conflict: its journaled writes are moved to <journal>.conflict
+ * instead of overwriting the remote data.
+ *
+ * Every record in the journal file is a u32 length followed by a bincode
+ * encoded JournalEntry.
+ */
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    time::SystemTime,
+};
+
+use dashmap::DashMap;
+use log::{error, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{cache::LRUCache, errors::SERIALIZATION_ERROR};
+
+// read responses kept for the disconnected mode.
+const READ_CACHE_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub path: String,
+    pub offset: i64,
+    pub data: Vec<u8>,
+    // mtime of the file last seen from the servers, None if unknown.
+    pub base_mtime: Option<SystemTime>,
+}
+
+pub struct Journal {
+    path: String,
+    file: Mutex<File>,
+    entries: Mutex<Vec<JournalEntry>>,
+    read_cache: LRUCache<Vec<u8>>,
+    mtimes: DashMap<String, SystemTime>,
+    // held while the entries are replayed, so that they are sent once and in order.
+    replaying: tokio::sync::Mutex<()>,
+}
+
+impl Journal {
+    // open loads the entries left by a previous run.
+    pub fn open(path: &str) -> Result<Self, i32> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                error!("open journal {} failed: {}", path, e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        let entries = decode_entries(&data);
+        if !entries.is_empty() {
+            warn!("journal {} has {} entries to replay", path, entries.len());
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            entries: Mutex::new(entries),
+            read_cache: LRUCache::new(READ_CACHE_CAPACITY),
+            mtimes: DashMap::new(),
+            replaying: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.lock().clone()
+    }
+
+    // has_entries tells if writes of path are still to be replayed.
+    pub fn has_entries(&self, path: &str) -> bool {
+        self.entries.lock().iter().any(|entry| entry.path == path)
+    }
+
+    pub async fn lock_replay(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.replaying.lock().await
+    }
+
+    // append records a write done while disconnected.
+    pub fn append(&self, path: &str, offset: i64, data: &[u8]) -> Result<(), i32> {
+        let entry = JournalEntry {
+            path: path.to_owned(),
+            offset,
+            data: data.to_owned(),
+            base_mtime: self.mtimes.get(path).map(|mtime| *mtime),
+        };
+        let record = encode_entry(&entry)?;
+        let mut entries = self.entries.lock();
+        let mut file = self.file.lock();
+        if let Err(e) = file.write_all(&record).and_then(|_| file.sync_data()) {
+            error!("append journal {} failed: {}", self.path, e);
+            return Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        entries.push(entry);
+        Ok(())
+    }
+
+    // consume removes the first count entries after they are replayed.
+    pub fn consume(&self, count: usize) -> Result<(), i32> {
+        let mut entries = self.entries.lock();
+        let mut file = self.file.lock();
+        let count = count.min(entries.len());
+        entries.drain(..count);
+        let mut data = Vec::new();
+        for entry in entries.iter() {
+            data.extend(encode_entry(entry)?);
+        }
+        let result = file
+            .set_len(0)
+            .and_then(|_| file.write_all(&data))
+            .and_then(|_| file.sync_data());
+        if let Err(e) = result {
+            error!("rewrite journal {} failed: {}", self.path, e);
+            return Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        Ok(())
+    }
+
+    // save_conflict keeps an entry that can not be replayed.
+    pub fn save_conflict(&self, entry: &JournalEntry) -> Result<(), i32> {
+        let path = format!("{}.conflict", self.path);
+        warn!(
+            "journaled write of {} at {} conflicts, saved to {}",
+            entry.path, entry.offset, path
+        );
+        let record = encode_entry(entry)?;
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&record));
+        result.map_err(|e| {
+            error!("save conflict to {} failed: {}", path, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })
+    }
+
+    // set_mtime records the mtime of a file seen from the servers, None
+    // when it is not known.
+    pub fn set_mtime(&self, path: &str, mtime: Option<SystemTime>) {
+        match mtime {
+            Some(mtime) => self.mtimes.insert(path.to_owned(), mtime),
+            None => self.mtimes.remove(path).map(|(_, mtime)| mtime),
+        };
+    }
+
+    pub fn cache_read(&self, path: &str, offset: i64, size: u32, data: &[u8]) {
+        self.read_cache
+            .insert(read_cache_key(path, offset, size).as_bytes(), data.to_vec());
+    }
+
+    // read returns the data of a read done while disconnected, which is
+    // only possible if the same range was read before.
+    pub fn read(&self, path: &str, offset: i64, size: u32) -> Option<Vec<u8>> {
+        let mut data = self
+            .read_cache
+            .get(read_cache_key(path, offset, size).as_bytes())?
+            .clone();
+        self.apply(path, offset, size, &mut data);
+        Some(data)
+    }
+
+    // apply copies the journaled writes of path in [offset, offset + size) to data.
+    pub fn apply(&self, path: &str, offset: i64, size: u32, data: &mut Vec<u8>) {
+        let end = offset + size as i64;
+        for entry in self.entries.lock().iter().filter(|e| e.path == path) {
+            let entry_end = entry.offset + entry.data.len() as i64;
+            let (begin, stop) = (entry.offset.max(offset), entry_end.min(end));
+            if begin >= stop {
+                continue;
+            }
+            let length = (stop - offset) as usize;
+            if data.len() < length {
+                data.resize(length, 0);
+            }
+            data[(begin - offset) as usize..length].copy_from_slice(
+                &entry.data[(begin - entry.offset) as usize..(stop - entry.offset) as usize],
+            );
+        }
+    }
+}
+
+fn read_cache_key(path: &str, offset: i64, size: u32) -> String {
+    format!("{}:{}:{}", path, offset, size)
+}
+
+fn encode_entry(entry: &JournalEntry) -> Result<Vec<u8>, i32> {
+    let body = bincode::serialize(entry).map_err(|_| SERIALIZATION_ERROR)?;
+    let mut record = (body.len() as u32).to_le_bytes().to_vec();
+    record.extend(body);
+    Ok(record)
+}
+
+// decode_entries ignores a torn record at the end, left by a crash in append.
+fn decode_entries(mut data: &[u8]) -> Vec<JournalEntry> {
+    let mut entries = Vec::new();
+    while data.len() >= 4 {
+        let length = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        if data.len() < 4 + length {
+            warn!("ignore a torn journal record");
+            break;
+        }
+        match bincode::deserialize(&data[4..4 + length]) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                error!("decode journal record failed: {}", e);
+                break;
+            }
+        }
+        data = &data[4 + length..];
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_entries, encode_entry, Journal, JournalEntry};
+
+    #[test]
+    fn journal_test() {
+        let path = "/tmp/test_client_journal";
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(format!("{}.conflict", path));
+        {
+            let journal = Journal::open(path).unwrap();
+            assert!(journal.is_empty());
+            journal.cache_read("v/a", 0, 8, b"abcdef");
+            journal.append("v/a", 2, b"XY").unwrap();
+            journal.append("v/a", 5, b"ZZZ").unwrap();
+            journal.append("v/b", 0, b"b").unwrap();
+            assert_eq!(journal.read("v/a", 0, 8), Some(b"abXYeZZZ".to_vec()));
+            assert_eq!(journal.read("v/a", 0, 4), None);
+            assert!(journal.has_entries("v/b"));
+            assert!(!journal.has_entries("v/c"));
+        }
+        {
+            // the entries survive a restart.
+            let journal = Journal::open(path).unwrap();
+            let entries = journal.entries();
+            assert_eq!(entries.len(), 3);
+            journal.consume(2).unwrap();
+            journal.save_conflict(&entries[2]).unwrap();
+        }
+        let journal = Journal::open(path).unwrap();
+        assert_eq!(journal.entries().len(), 1);
+        assert_eq!(journal.entries()[0].path, "v/b");
+        journal.consume(1).unwrap();
+        assert!(Journal::open(path).unwrap().is_empty());
+
+        let conflicts = std::fs::read(format!("{}.conflict", path)).unwrap();
+        assert_eq!(decode_entries(&conflicts)[0].data, b"b");
+
+        let mut torn = encode_entry(&JournalEntry {
+            path: "v/c".to_owned(),
+            offset: 0,
+            data: vec![1],
+            base_mtime: None,
+        })
+        .unwrap();
+        torn.truncate(torn.len() - 1);
+        assert!(decode_entries(&torn).is_empty());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(format!("{}.conflict", path)).unwrap();
+    }
+}
@@ -1,29 +1,76 @@
 // Copyright 2022 labring. All rights reserved.
 //
 // SPDX-License-Identifier: Apache-2.0
+pub mod cluster;
 pub mod daemon;
 pub mod fuse_client;
+pub mod journal;
+pub mod readahead;
+pub mod short_circuit;
+pub mod takeover;
+pub mod telemetry;
+pub mod write_batch;
 
 use clap::{Parser, Subcommand};
-use env_logger::fmt;
 use fuser::{
-    Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
-    ReplyWrite, Request,
+    consts::{
+        FOPEN_DIRECT_IO, FUSE_BIG_WRITES, FUSE_FLOCK_LOCKS, FUSE_POSIX_LOCKS, FUSE_WRITEBACK_CACHE,
+    },
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+};
+use log::{debug, error, info, warn};
+use std::{
+    ffi::OsStr,
+    future::Future,
+    path::Path,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime},
 };
-use log::{debug, error, info};
-use std::{ffi::OsStr, str::FromStr, sync::Arc};
 
 use crate::{
-    client::daemon::{LocalCli, SealfsFused},
+    client::{
+        daemon::{LocalCli, MountStats, SealfsFused},
+        readahead::ReadAhead,
+        telemetry::{export_loop, OtlpExporter},
+    },
     common::{
         errors::status_to_string,
+        hash_ring::FailureDomain,
         info_syncer::{init_network_connections, ClientStatusMonitor, InfoSyncer},
+        logging::{init_logger, LogFormat},
+        serialization::{
+            AtimePolicy, CacheTtl, Compression, SetAttrSendMetaData, SetVolumeSendMetaData,
+            VolumeDay,
+        },
+        util::{empty_dir, empty_file, owner},
     },
     rpc::server::RpcServer,
+    server::storage_engine::{block_engine::BlockEngine, meta_engine::MetaEngine, migration},
 };
 
 use self::fuse_client::Client;
 
+// the .sealfs files are made by every mount point for its users, who may not
+// reach the daemon socket: stats is read-only, the others are control files
+// tuning the mount point when a value is written to them. The names hide the
+// same names of the volume root, and the inodes are never given to files.
+const SEALFS_DIR_NAME: &str = ".sealfs";
+const SEALFS_DIR_INODE: u64 = u64::MAX - 1;
+const STATS_FILE_INODE: u64 = u64::MAX - 2;
+const READAHEAD_FILE_INODE: u64 = u64::MAX - 3;
+const CACHE_SIZE_FILE_INODE: u64 = u64::MAX - 4;
+const VIRTUAL_FILES: [(&str, u64); 3] = [
+    ("stats", STATS_FILE_INODE),
+    ("readahead", READAHEAD_FILE_INODE),
+    ("cache_size", CACHE_SIZE_FILE_INODE),
+];
+const VIRTUAL_TTL: Duration = Duration::from_secs(1);
+// the largest write asked to the kernel, in bytes. fuser asks for the
+// FUSE_MAX_PAGES it needs to send writes this large.
+const MAX_WRITE: u32 = 1 << 20;
+
 const LOCAL_PATH: &str = "/tmp/sealfs.sock";
 const LOCAL_INDEX_PATH: &str = "/tmp/sealfs.index";
 
@@ -36,6 +83,10 @@ struct Cli {
     /// Log level
     #[arg(long = "log-level", name = "log-level")]
     log_level: Option<String>,
+
+    /// Log format, text or json with the fields of the requests at debug level
+    #[arg(long = "log-format", name = "log-format")]
+    log_format: Option<LogFormat>,
 }
 
 #[derive(Subcommand)]
@@ -49,6 +100,18 @@ enum Commands {
         #[arg(required = true, name = "volume-size")]
         volume_size: Option<u64>,
 
+        /// Access time update policy of the volume: relatime, strictatime or noatime
+        #[arg(long = "atime", name = "atime")]
+        atime: Option<String>,
+
+        /// Export the volume as a public dataset, mounted read-only by default
+        #[arg(long = "public", name = "public")]
+        public: bool,
+
+        /// Description of the volume kept in the manager registry
+        #[arg(long = "description", name = "description")]
+        description: Option<String>,
+
         /// Address of the manager
         #[arg(short = 'm', long = "manager-address", name = "manager-address")]
         manager_address: Option<String>,
@@ -65,7 +128,8 @@ enum Commands {
     Daemon {
         /// Start a daemon that hosts volumes
 
-        /// Address of the manager
+        /// Address of the manager, or comma separated addresses of managers
+        /// tried in order
         #[arg(short = 'm', long = "manager-address", name = "manager-address")]
         manager_address: Option<String>,
 
@@ -80,6 +144,80 @@ enum Commands {
         /// clean socket file
         #[arg(long = "clean-socket", name = "clean-socket")]
         clean_socket: bool,
+
+        /// Journal writes to this file while the servers are unreachable, and replay them later
+        #[arg(long = "journal-file", name = "journal-file")]
+        journal_file: Option<String>,
+
+        /// Send the file requests through the cache node at this address
+        #[arg(long = "cache-node", name = "cache-node")]
+        cache_node: Option<String>,
+
+        /// Allow this user to use the daemon socket, besides root and the daemon user
+        #[arg(long = "allow-uid", name = "allow-uid")]
+        allow_uids: Vec<u32>,
+
+        /// Allow the users of this group to use the daemon socket
+        #[arg(long = "allow-gid", name = "allow-gid")]
+        allow_gids: Vec<u32>,
+
+        /// Compress the large data sent to and received from the servers with LZ4
+        #[arg(long = "compress-rpc", name = "compress-rpc")]
+        compress_rpc: bool,
+
+        /// Check the CRC of the requests and responses exchanged with the servers
+        #[arg(long = "checksum-rpc", name = "checksum-rpc")]
+        checksum_rpc: bool,
+
+        /// Read the files of the servers of this host from their local files,
+        /// for the daemons running as root or as the servers
+        #[arg(long = "short-circuit-reads", name = "short-circuit-reads")]
+        short_circuit_reads: bool,
+
+        /// Share the chunks read with the other clients of this host in the
+        /// page cache at this path, e.g. in /dev/shm
+        #[arg(long = "page-cache", name = "page-cache")]
+        page_cache: Option<String>,
+
+        /// Size of the page cache in MiB, when this client creates it
+        #[arg(
+            long = "page-cache-size",
+            name = "page-cache-size",
+            default_value_t = 1024
+        )]
+        page_cache_size: usize,
+
+        /// Let the kernel cache the writes and send them in batches, for the
+        /// volumes written by this client only
+        #[arg(long = "writeback-cache", name = "writeback-cache")]
+        writeback_cache: bool,
+
+        /// Coalesce the writes following each other into batches of up to
+        /// this many bytes, sent as a single request
+        #[arg(long = "write-batch-size", name = "write-batch-size")]
+        write_batch_size: Option<usize>,
+
+        /// Milliseconds a batch of writes waits for the next writes
+        #[arg(
+            long = "write-batch-interval",
+            name = "write-batch-interval",
+            default_value_t = 10
+        )]
+        write_batch_interval: u64,
+
+        /// Take over the mount points of the daemon running with --takeover, and
+        /// keep the mount points for the next one
+        #[arg(long = "takeover", name = "takeover")]
+        takeover: bool,
+
+        /// Push the metrics of the mount points to the OpenTelemetry collector
+        /// at this endpoint, with OTLP over http, e.g. http://127.0.0.1:4318
+        #[arg(long = "otlp-endpoint", name = "otlp-endpoint")]
+        otlp_endpoint: Option<String>,
+
+        /// Seconds between the pushes of the metrics
+        #[arg(long = "otlp-interval", name = "otlp-interval", default_value_t = 10)]
+        otlp_interval: u64,
     },
     Mount {
         /// Act as a client, and mount FUSE at given path
@@ -95,6 +233,35 @@ enum Commands {
 
         #[arg(long = "read-only", name = "read-only")]
         read_only: bool,
+
+        /// Mount a public volume read-write, as its owner
+        #[arg(long = "owner", name = "owner")]
+        owner: bool,
+
+        /// Allow this user to use the mount point, besides root and the user mounting it
+        #[arg(long = "allow-uid", name = "allow-uid")]
+        allow_uids: Vec<u32>,
+
+        /// Make the files created by root owned by nobody
+        #[arg(long = "root-squash", name = "root-squash")]
+        root_squash: bool,
+
+        /// Pin the volume to this group of servers, moving its files there
+        #[arg(long = "group", name = "group")]
+        group: Option<String>,
+
+        /// Milliseconds the kernel keeps the entries looked up
+        #[arg(long = "entry-ttl", name = "entry-ttl", default_value_t = 1000)]
+        entry_ttl: u64,
+
+        /// Milliseconds the kernel keeps the attrs of the files
+        #[arg(long = "attr-ttl", name = "attr-ttl", default_value_t = 1000)]
+        attr_ttl: u64,
+
+        /// With none, neither the kernel nor the client cache the entries,
+        /// attrs and data, for files written by several clients at once
+        #[arg(long = "cache", name = "cache", default_value = "auto", value_parser = ["auto", "none"])]
+        cache: String,
     },
     Umount {
         /// Unmount FUSE at given path
@@ -118,6 +285,63 @@ enum Commands {
         #[arg(short = 'm', long = "manager-address", name = "manager-address")]
         manager_address: Option<String>,
     },
+    SetWeight {
+        /// Change the weight of a server, only the files whose owner changes are moved
+        #[arg(required = true, name = "server-address")]
+        server_address: Option<String>,
+
+        /// New weight of the server
+        #[arg(required = true, name = "weight")]
+        weight: Option<usize>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    SetGroup {
+        /// Move a server to a group, the files of the volumes pinned to the
+        /// groups are moved
+        #[arg(required = true, name = "server-address")]
+        server_address: Option<String>,
+
+        /// Group of the server, empty to take it out of its group
+        #[arg(required = true, name = "group")]
+        group: Option<String>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    SetDomain {
+        /// Set the failure domain of a server, the replicas of a file are
+        /// placed in distinct zones and racks when possible
+        #[arg(required = true, name = "server-address")]
+        server_address: Option<String>,
+
+        /// Zone of the server
+        #[arg(long = "zone", name = "zone")]
+        zone: Option<String>,
+
+        /// Rack of the server
+        #[arg(long = "rack", name = "rack")]
+        rack: Option<String>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    Maintenance {
+        /// Put a server in maintenance before rebooting it, or take it out after
+        #[arg(required = true, name = "action", value_parser = ["enter", "exit"])]
+        action: Option<String>,
+
+        #[arg(required = true, name = "server-address")]
+        server_address: Option<String>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
     Delete {
         /// Delete a server from the cluster
         #[arg(required = true, name = "server-address")]
@@ -143,35 +367,495 @@ enum Commands {
         #[arg(long = "socket-path", name = "socket-path")]
         socket_path: Option<String>,
     },
+    Stats {
+        /// Show the requests of every mount point and of every server connection
+        /// shared by them
+        #[arg(long = "socket-path", name = "socket-path")]
+        socket_path: Option<String>,
+    },
     Status {
         /// Address of the manager
         #[arg(short = 'm', long = "manager-address", name = "manager-ddress")]
         manager_address: Option<String>,
+
+        /// Show the files every server moves in the rebalance, or moved in
+        /// the last one
+        #[arg(long = "transfers", name = "transfers")]
+        transfers: bool,
     },
     Probe {
         #[arg(long = "socket-path", name = "socket-path")]
         socket_path: Option<String>,
         // Probe the local client
     },
+    Health {
+        /// Check the health of a server, a cache node or a manager, and fail
+        /// if it is not ready
+        #[arg(required = true, name = "address")]
+        address: Option<String>,
+
+        /// The address is the one of a manager
+        #[arg(long = "manager", name = "manager")]
+        manager: bool,
+
+        /// Only check that it answers
+        #[arg(long = "live", name = "live")]
+        live: bool,
+    },
+    ListTree {
+        /// List the files and directories under a path of a volume recursively
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    SetAttrTree {
+        /// Change the permissions and the owner of a path of a volume and of everything under it, on the servers
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// New permissions, in octal
+        #[arg(long = "mode", name = "mode")]
+        mode: Option<String>,
+
+        /// New owner
+        #[arg(long = "uid", name = "uid")]
+        uid: Option<u32>,
+
+        /// New group
+        #[arg(long = "gid", name = "gid")]
+        gid: Option<u32>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    DedupScan {
+        /// Count the duplicate chunks of the files under a path of a volume, on the servers
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// Share the extents of the duplicates, on the servers whose disks support reflink
+        #[arg(long = "apply", name = "apply")]
+        apply: bool,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    CreateLog {
+        /// Create an append-only log file in a volume
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    ShardDir {
+        /// Spread the entries of an empty directory of a volume over shards
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// Number of shards
+        #[arg(long = "shards", name = "shards")]
+        shards: u32,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    #[cfg(feature = "grep-pushdown")]
+    Grep {
+        /// Print the lines of a file of a volume containing a pattern, scanned on its server
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        #[arg(required = true, name = "pattern")]
+        pattern: Option<String>,
+
+        /// Print the byte offsets of the pattern instead of the lines
+        #[arg(long = "bytes", name = "bytes")]
+        bytes: bool,
+
+        /// Stop after this number of matches
+        #[arg(long = "max-count", name = "max-count")]
+        max_count: Option<usize>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    Upload {
+        /// Upload a local file to a path of a volume in parallel parts
+        #[arg(required = true, name = "local-file")]
+        local_file: Option<String>,
+
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// Size of the parts in MB
+        #[arg(long = "part-size", name = "part-size", default_value = "64")]
+        part_size: u64,
+
+        /// Number of parts uploaded at the same time
+        #[arg(long = "parallel", name = "parallel", default_value = "4")]
+        parallel: usize,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    Prefetch {
+        /// Read the files under a path of a volume into the memory of their servers
+        #[arg(required = true, name = "path")]
+        path: Option<String>,
+
+        /// Number of files prefetched at the same time
+        #[arg(long = "parallel", name = "parallel", default_value = "16")]
+        parallel: usize,
+
+        /// Load the files into the cache node at this address instead
+        #[arg(long = "cache-node", name = "cache-node")]
+        cache_node: Option<String>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    Mkfs {
+        /// Format a block device for the block storage engine
+        #[arg(required = true, name = "device")]
+        device: Option<String>,
+    },
+    Migrate {
+        /// Database path of a stopped server, migrated to the current format version
+        #[arg(required = true, long = "database-path", name = "database-path")]
+        database_path: Option<String>,
+    },
+    Volume {
+        #[command(subcommand)]
+        command: VolumeCommands,
+    },
+    Cluster {
+        #[command(subcommand)]
+        command: ClusterCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ClusterCommands {
+    Init {
+        /// Bring the cluster to a config listing its manager and servers, and
+        /// wait for it to be ready
+        #[arg(required = true, long = "config", name = "config")]
+        config: Option<String>,
+
+        /// Start the manager next to the client if it does not answer
+        #[arg(long = "start-manager", name = "start-manager")]
+        start_manager: bool,
+
+        /// Seconds to wait for the servers
+        #[arg(long = "timeout", name = "timeout", default_value_t = 300)]
+        timeout: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum VolumeCommands {
+    Set {
+        /// Edit the metadata of a volume in the manager registry
+        #[arg(required = true, name = "name")]
+        name: Option<String>,
+
+        /// New description of the volume
+        #[arg(long = "description", name = "description")]
+        description: Option<String>,
+
+        /// New quota of the volume, in bytes
+        #[arg(long = "quota", name = "quota")]
+        quota: Option<u64>,
+
+        /// Number of stripes of the files created in the volume
+        #[arg(long = "stripe-count", name = "stripe-count")]
+        stripe_count: Option<u32>,
+
+        /// Number of replicas of the files created in the volume
+        #[arg(long = "replication", name = "replication")]
+        replication: Option<u32>,
+
+        /// Compression of the files created in the volume: none, lz4 or zstd
+        #[arg(long = "compression", name = "compression")]
+        compression: Option<Compression>,
+
+        /// Chunk size of the files created in the volume, in bytes, 0 for the default
+        #[arg(long = "chunk-size", name = "chunk-size")]
+        chunk_size: Option<u32>,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
+    History {
+        /// Print the daily usage of a volume kept by the manager
+        #[arg(required = true, name = "name")]
+        name: Option<String>,
+
+        /// Number of days, the last ones
+        #[arg(long = "days", name = "days", default_value_t = 30)]
+        days: u32,
+
+        /// Address of the manager
+        #[arg(short = 'm', long = "manager-address", name = "manager-address")]
+        manager_address: Option<String>,
+    },
 }
 
 struct SealFS {
     client: Arc<Client>,
     volume_root_inode: u64,
+    stats: Arc<MountStats>,
+    // uids allowed to use the mount point.
+    allowed_uids: Vec<u32>,
+    // the files created by root are owned by nobody.
+    root_squash: bool,
+    ttl: CacheTtl,
+    // ask the kernel to cache the writes, see init.
+    writeback_cache: bool,
+    readahead: Arc<ReadAhead>,
 }
 
 impl SealFS {
-    fn new(client: Arc<Client>, volume_root_inode: u64) -> Self {
+    fn new(
+        client: Arc<Client>,
+        volume_root_inode: u64,
+        stats: Arc<MountStats>,
+        allowed_uids: Vec<u32>,
+        root_squash: bool,
+        ttl: CacheTtl,
+        writeback_cache: bool,
+    ) -> Self {
         Self {
             client,
             volume_root_inode,
+            stats,
+            allowed_uids,
+            root_squash,
+            ttl,
+            writeback_cache,
+            readahead: Arc::new(ReadAhead::default()),
+        }
+    }
+
+    // start counts a request and checks that its user may use the mount point.
+    fn start(&self, req: &Request, operation: &'static str) -> bool {
+        self.stats.ops.fetch_add(1, Ordering::Relaxed);
+        *self.stats.op_counts.entry(operation).or_default() += 1;
+        if !self.allowed_uids.contains(&req.uid()) {
+            debug!("uid {} is not allowed", req.uid());
+            return false;
+        }
+        true
+    }
+
+    // spawn runs a request of operation on the runtime of the client, and
+    // records its latency until it is replied. The session reads no more
+    // requests once it is handed over.
+    fn spawn(&self, operation: &'static str, request: impl Future<Output = ()> + Send + 'static) {
+        let stats = self.stats.clone();
+        stats.in_flight.fetch_add(1, Ordering::AcqRel);
+        self.client.handle.spawn(async move {
+            let start = Instant::now();
+            request.await;
+            stats
+                .op_latencies
+                .entry(operation)
+                .or_default()
+                .record(start.elapsed());
+            stats.in_flight.fetch_sub(1, Ordering::AcqRel);
+        });
+        self.stats.handover.stop();
+    }
+
+    // owner returns the owner of the files created by a request.
+    fn owner(&self, req: &Request) -> (u32, u32) {
+        owner(req.uid(), req.gid(), self.root_squash)
+    }
+
+    fn virtual_attr(&self, ino: u64) -> FileAttr {
+        match ino {
+            SEALFS_DIR_INODE => FileAttr {
+                ino,
+                perm: 0o555,
+                nlink: 2,
+                ..empty_dir()
+            },
+            STATS_FILE_INODE => FileAttr {
+                ino,
+                size: self.virtual_file(ino).len() as u64,
+                perm: 0o444,
+                nlink: 1,
+                ..empty_file()
+            },
+            // the control files are changed by the users allowed to use the
+            // mount point, whoever owns them.
+            _ => FileAttr {
+                ino,
+                size: self.virtual_file(ino).len() as u64,
+                perm: 0o666,
+                nlink: 1,
+                ..empty_file()
+            },
+        }
+    }
+
+    fn virtual_file(&self, ino: u64) -> Vec<u8> {
+        match ino {
+            READAHEAD_FILE_INODE => {
+                format!("{}\n", self.readahead.size.load(Ordering::Relaxed)).into_bytes()
+            }
+            CACHE_SIZE_FILE_INODE => {
+                format!("{}\n", self.readahead.capacity.load(Ordering::Relaxed)).into_bytes()
+            }
+            _ => self.stats_file(),
+        }
+    }
+
+    // write_control_file sets the value written to a control file, a
+    // decimal number on one write.
+    fn write_control_file(&self, ino: u64, data: &[u8]) -> Result<(), i32> {
+        let value = std::str::from_utf8(data)
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .ok_or(libc::EINVAL)?;
+        info!("set {} of the mount point to {}", ino_name(ino), value);
+        match ino {
+            READAHEAD_FILE_INODE => self.readahead.size.store(value, Ordering::Relaxed),
+            CACHE_SIZE_FILE_INODE => self
+                .readahead
+                .capacity
+                .store(value as usize, Ordering::Relaxed),
+            _ => return Err(libc::EACCES),
+        }
+        Ok(())
+    }
+
+    // stats_file is the content of .sealfs/stats, made again on every read.
+    // The negative cache and the connections are shared by all the mount
+    // points of the daemon.
+    fn stats_file(&self) -> Vec<u8> {
+        let mut content = String::new();
+        if let Some(volume_name) = self.client.inodes_reverse.get(&self.volume_root_inode) {
+            content += &format!("volume: {}\n", *volume_name);
         }
+        content += &format!(
+            "ops: {}\nread_bytes: {}\nwritten_bytes: {}\n",
+            self.stats.ops.load(Ordering::Relaxed),
+            self.stats.read_bytes.load(Ordering::Relaxed),
+            self.stats.written_bytes.load(Ordering::Relaxed)
+        );
+        let mut op_counts: Vec<(&str, u64)> = self
+            .stats
+            .op_counts
+            .iter()
+            .map(|kv| (*kv.key(), *kv.value()))
+            .collect();
+        op_counts.sort();
+        for (operation, count) in op_counts {
+            content += &format!("op_{}: {}\n", operation, count);
+        }
+        let (hits, misses) = self.client.negative_cache.hit_stats();
+        content += &format!(
+            "negative_cache_hits: {}\nnegative_cache_misses: {}\nnegative_cache_hit_rate: {:.3}\n",
+            hits,
+            misses,
+            hits as f64 / (hits + misses).max(1) as f64
+        );
+        let mut connections = self.client.client.connection_stats();
+        connections.sort_by(|a, b| a.server_address.cmp(&b.server_address));
+        for connection in connections {
+            content += &format!(
+                "server {}: connected {}, requests {}, avg_latency_us {}, max_latency_us {}, compression_ratio {:.2}, corrupt_frames {}\n",
+                connection.server_address,
+                connection.connected,
+                connection.requests,
+                connection.avg_latency_us,
+                connection.max_latency_us,
+                connection.compression_ratio,
+                connection.corrupt_frames
+            );
+        }
+        content.into_bytes()
+    }
+}
+
+fn is_virtual(ino: u64) -> bool {
+    ino == SEALFS_DIR_INODE || VIRTUAL_FILES.iter().any(|(_, i)| *i == ino)
+}
+
+fn ino_name(ino: u64) -> &'static str {
+    match VIRTUAL_FILES.iter().find(|(_, i)| *i == ino) {
+        Some((name, _)) => name,
+        None => SEALFS_DIR_NAME,
     }
 }
 
 impl Filesystem for SealFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    // init asks the kernel for writes of up to MAX_WRITE bytes and, with
+    // --writeback-cache, to cache the writes in the page cache and send them
+    // in batches. fuser reads and writes /dev/fuse without splice, so the
+    // splice capabilities are not asked for. The POSIX locks and the flocks,
+    // which come as locks of the whole file, are held by the servers.
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        let _ = config.add_capabilities(FUSE_BIG_WRITES);
+        if config
+            .add_capabilities(FUSE_POSIX_LOCKS | FUSE_FLOCK_LOCKS)
+            .is_err()
+        {
+            warn!("the kernel does not support the remote locks");
+        }
+        if let Err(max_write) = config.set_max_write(MAX_WRITE) {
+            info!("max write {} not supported, use {}", MAX_WRITE, max_write);
+            let _ = config.set_max_write(max_write);
+        }
+        if self.writeback_cache && config.add_capabilities(FUSE_WRITEBACK_CACHE).is_err() {
+            warn!("the kernel does not support the writeback cache");
+        }
+        Ok(())
+    }
+
+    // statfs replies as fuser does by default. The takeover sends one to a
+    // mount point to stop its session.
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+        self.stats.handover.stop();
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if !self.start(req, "lookup") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!("lookup, parent = {}, name = {:?}", parent, name);
+        let ino = match (parent, name.to_str()) {
+            (1, Some(SEALFS_DIR_NAME)) => Some(SEALFS_DIR_INODE),
+            (SEALFS_DIR_INODE, name) => {
+                match VIRTUAL_FILES.iter().find(|(n, _)| Some(*n) == name) {
+                    Some((_, ino)) => Some(*ino),
+                    None => {
+                        reply.error(libc::ENOENT);
+                        return;
+                    }
+                }
+            }
+            _ => None,
+        };
+        if let Some(ino) = ino {
+            reply.entry(&VIRTUAL_TTL, &self.virtual_attr(ino), 0);
+            return;
+        }
         let client = self.client.clone();
         let name = name.to_owned();
         let parent = if parent == 1 {
@@ -179,14 +863,15 @@ impl Filesystem for SealFS {
         } else {
             parent
         };
-        self.client
-            .handle
-            .spawn(async move { client.lookup_remote(parent, name, reply).await });
+        let ttl = self.ttl;
+        self.spawn("lookup", async move {
+            client.lookup_remote(parent, name, ttl, reply).await
+        });
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -194,6 +879,10 @@ impl Filesystem for SealFS {
         flags: i32,
         reply: ReplyCreate,
     ) {
+        if !self.start(req, "create") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!(
             "create, parent = {}, name = {:?}, mode = {}, umask = {}, flags = {}",
             parent, name, mode, umask, flags
@@ -205,42 +894,79 @@ impl Filesystem for SealFS {
         };
         let client = self.client.clone();
         let name = name.to_owned();
-        self.client.handle.spawn(async move {
+        let (owner, ttl) = (self.owner(req), self.ttl);
+        self.spawn("create", async move {
             client
-                .create_remote(parent, name, mode, umask, flags, reply)
+                .create_remote(parent, name, mode, umask, flags, owner, ttl, reply)
                 .await
         });
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    fn getattr(&mut self, req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        if !self.start(req, "getattr") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!("getattr, ino = {}", ino);
+        if is_virtual(ino) {
+            reply.attr(&VIRTUAL_TTL, &self.virtual_attr(ino));
+            return;
+        }
         let client = self.client.clone();
         let ino = if ino == 1 {
             self.volume_root_inode
         } else {
             ino
         };
-        self.client
-            .handle
-            .spawn(async move { client.getattr_remote(ino, reply).await });
+        let ttl = self.ttl;
+        self.spawn("getattr", async move {
+            client.getattr_remote(ino, ttl, reply).await
+        });
     }
 
-    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, reply: ReplyDirectory) {
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if !self.start(req, "readdir") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!("readdir, ino = {}, offset = {}", ino, offset);
+        if ino == SEALFS_DIR_INODE {
+            let mut entries = vec![
+                (SEALFS_DIR_INODE, FileType::Directory, "."),
+                (1, FileType::Directory, ".."),
+            ];
+            for (name, ino) in VIRTUAL_FILES {
+                entries.push((ino, FileType::RegularFile, name));
+            }
+            for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+                if reply.add(*ino, (i + 1) as i64, *kind, name) {
+                    break;
+                }
+            }
+            reply.ok();
+            return;
+        }
         let client = self.client.clone();
         let ino = if ino == 1 {
             self.volume_root_inode
         } else {
             ino
         };
-        self.client
-            .handle
-            .spawn(async move { client.readdir_remote(ino, offset, reply).await });
+        self.spawn("readdir", async move {
+            client.readdir_remote(ino, offset, reply).await
+        });
     }
 
     fn read(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
@@ -249,36 +975,87 @@ impl Filesystem for SealFS {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
+        if !self.start(req, "read") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!("read, ino = {}, offset = {}, size = {}", ino, offset, size);
+        if is_virtual(ino) {
+            let content = self.virtual_file(ino);
+            let begin = (offset as usize).min(content.len());
+            let end = (begin + size as usize).min(content.len());
+            reply.data(&content[begin..end]);
+            return;
+        }
+        self.stats
+            .read_bytes
+            .fetch_add(size as u64, Ordering::Relaxed);
+        // nothing is read ahead without the cache.
+        let readahead = !self.ttl.no_cache;
+        if let Some(data) = self.readahead.get(ino, offset, size).filter(|_| readahead) {
+            reply.data(&data);
+            return;
+        }
         let client = self.client.clone();
         let ino = if ino == 1 {
             self.volume_root_inode
         } else {
             ino
         };
-        self.client
-            .handle
-            .spawn(async move { client.read_remote(ino, offset, size, reply).await });
+        match self.readahead.read_size(size).filter(|_| readahead) {
+            Some(read_size) => {
+                let readahead = self.readahead.clone();
+                self.spawn("read", async move {
+                    match client.read_data(ino, offset, read_size).await {
+                        Ok(data) => {
+                            reply.data(&data[..data.len().min(size as usize)]);
+                            readahead.insert(ino, offset, read_size, data);
+                        }
+                        Err(e) => reply.error(e),
+                    }
+                });
+            }
+            None => {
+                self.spawn("read", async move {
+                    client.read_remote(ino, offset, size, reply).await
+                });
+            }
+        }
     }
 
     fn write(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
         data: &[u8],
         _write_flags: u32,
-        _flags: i32,
+        flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
+        if !self.start(req, "write") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if is_virtual(ino) {
+            match self.write_control_file(ino, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        self.readahead.invalidate(ino);
         debug!(
             "write, ino = {}, offset = {}, data_len = {}",
             ino,
             offset,
             data.len()
         );
+        self.stats
+            .written_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
         let client = self.client.clone();
         let data = data.to_owned();
         let ino = if ino == 1 {
@@ -286,22 +1063,30 @@ impl Filesystem for SealFS {
         } else {
             ino
         };
-        self.client.handle.spawn(async move {
+        // the server chooses the offset of the writes to a file opened with
+        // O_APPEND, but for the writes back from the page cache, made at the
+        // offsets of the kernel.
+        let append = flags & libc::O_APPEND != 0 && !self.writeback_cache;
+        self.spawn("write", async move {
             client
-                .write_remote(ino, offset, data.to_owned(), reply)
+                .write_remote(ino, offset, data.to_owned(), append, reply)
                 .await
         });
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
+        if !self.start(req, "mkdir") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!(
             "mkdir, parent = {}, name = {:?}, mode = {}",
             parent, name, mode
@@ -313,26 +1098,45 @@ impl Filesystem for SealFS {
         } else {
             parent
         };
-        self.client.handle.spawn(async move {
+        let (mode, owner, ttl) = (mode & !umask, self.owner(req), self.ttl);
+        self.spawn("mkdir", async move {
             client
-                .mkdir_remote(parent, name.to_owned(), mode, reply)
+                .mkdir_remote(parent, name.to_owned(), mode, owner, ttl, reply)
                 .await
         });
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if !self.start(req, "open") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if is_virtual(ino) {
+            if ino == STATS_FILE_INODE && flags & libc::O_ACCMODE != libc::O_RDONLY {
+                reply.error(libc::EACCES);
+                return;
+            }
+            // the size changes between the reads, read it until the end.
+            reply.opened(0, FOPEN_DIRECT_IO);
+            return;
+        }
         let client = self.client.clone();
         let ino = if ino == 1 {
             self.volume_root_inode
         } else {
             ino
         };
-        self.client
-            .handle
-            .spawn(async move { client.open_remote(ino, flags, reply).await });
+        let ttl = self.ttl;
+        self.spawn("open", async move {
+            client.open_remote(ino, flags, ttl, reply).await
+        });
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        if !self.start(req, "unlink") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!("unlink");
         let client = self.client.clone();
         let name = name.to_owned();
@@ -341,12 +1145,16 @@ impl Filesystem for SealFS {
         } else {
             parent
         };
-        self.client
-            .handle
-            .spawn(async move { client.unlink_remote(parent, name.to_owned(), reply).await });
+        self.spawn("unlink", async move {
+            client.unlink_remote(parent, name.to_owned(), reply).await
+        });
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        if !self.start(req, "rmdir") {
+            reply.error(libc::EACCES);
+            return;
+        }
         debug!("rmdir");
         let client = self.client.clone();
         let name = name.to_owned();
@@ -355,37 +1163,400 @@ impl Filesystem for SealFS {
         } else {
             parent
         };
-        self.client
-            .handle
-            .spawn(async move { client.rmdir_remote(parent, name.to_owned(), reply).await });
+        self.spawn("rmdir", async move {
+            client.rmdir_remote(parent, name.to_owned(), reply).await
+        });
     }
-}
 
-pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
-
-    let log_level = match cli.log_level {
-        Some(level) => level,
-        None => "warn".to_owned(),
-    };
-    let mut builder = env_logger::Builder::from_default_env();
-    builder
-        .format_timestamp(Some(fmt::TimestampPrecision::Millis))
-        .filter(None, log::LevelFilter::from_str(&log_level).unwrap());
-    builder.init();
-
-    info!("spawn client");
-
-    let client = Arc::new(Client::new());
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        if !self.start(req, "symlink") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!("symlink, parent = {}, name = {:?}", parent, link_name);
+        let Some(target) = target.to_str().map(str::to_owned) else {
+            reply.error(libc::EINVAL);
+            return;
+        };
+        let client = self.client.clone();
+        let name = link_name.to_owned();
+        let parent = if parent == 1 {
+            self.volume_root_inode
+        } else {
+            parent
+        };
+        let (owner, ttl) = (self.owner(req), self.ttl);
+        self.spawn("symlink", async move {
+            client
+                .symlink_remote(parent, name, target, owner, ttl, reply)
+                .await
+        });
+    }
 
-    match cli.command {
-        Commands::CreateVolume {
-            mount_point,
-            volume_size,
-            manager_address,
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        if !self.start(req, "link") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!(
+            "link, ino = {}, newparent = {}, newname = {:?}",
+            ino, newparent, newname
+        );
+        let client = self.client.clone();
+        let name = newname.to_owned();
+        let newparent = if newparent == 1 {
+            self.volume_root_inode
+        } else {
+            newparent
+        };
+        let ttl = self.ttl;
+        self.spawn("link", async move {
+            client.link_remote(ino, newparent, name, ttl, reply).await
+        });
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if !self.start(req, "rename") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!(
+            "rename, parent = {}, name = {:?}, newparent = {}, newname = {:?}",
+            parent, name, newparent, newname
+        );
+        let client = self.client.clone();
+        let (name, newname) = (name.to_owned(), newname.to_owned());
+        let root = |ino| match ino {
+            1 => self.volume_root_inode,
+            ino => ino,
+        };
+        let (parent, newparent) = (root(parent), root(newparent));
+        self.spawn("rename", async move {
+            client
+                .rename_remote(parent, name, newparent, newname, flags, reply)
+                .await
+        });
+    }
+
+    fn fsync(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if !self.start(req, "fsync") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!("fsync, ino = {}, datasync = {}", ino, datasync);
+        match ino {
+            READAHEAD_FILE_INODE | CACHE_SIZE_FILE_INODE => reply.ok(),
+            _ => {
+                let client = self.client.clone();
+                self.spawn("fsync", async move {
+                    client.fsync_remote(ino, datasync, reply).await
+                });
+            }
+        }
+    }
+
+    fn fsyncdir(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if !self.start(req, "fsyncdir") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!("fsyncdir, ino = {}, datasync = {}", ino, datasync);
+        let client = self.client.clone();
+        let ino = match ino {
+            1 => self.volume_root_inode,
+            ino => ino,
+        };
+        self.spawn("fsyncdir", async move {
+            client.fsync_remote(ino, datasync, reply).await
+        });
+    }
+
+    fn flush(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if !self.start(req, "flush") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!("flush, ino = {}", ino);
+        // closing a file releases the POSIX locks of its owner on it.
+        if is_virtual(ino)
+            || (self.client.write_batch.get().is_none()
+                && !self.client.locked.contains(&(ino, lock_owner)))
+        {
+            reply.ok();
+            return;
+        }
+        let client = self.client.clone();
+        self.spawn("flush", async move {
+            client.release_locks(ino, lock_owner).await;
+            client.flush_remote(ino, reply).await
+        });
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: i32,
+        lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        // the flocks are released with the last descriptor of the file.
+        let Some(lock_owner) = lock_owner
+            .filter(|owner| !is_virtual(ino) && self.client.locked.contains(&(ino, *owner)))
+        else {
+            reply.ok();
+            return;
+        };
+        let client = self.client.clone();
+        self.spawn("release", async move {
+            client.release_locks(ino, lock_owner).await;
+            reply.ok()
+        });
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: fuser::ReplyLock,
+    ) {
+        if !self.start(req, "getlk") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if is_virtual(ino) {
+            reply.error(libc::ENOLCK);
+            return;
+        }
+        debug!("getlk, ino = {}, start = {}, end = {}", ino, start, end);
+        let client = self.client.clone();
+        self.spawn("getlk", async move {
+            client
+                .getlk_remote(ino, lock_owner, start, end, typ, pid, reply)
+                .await
+        });
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if !self.start(req, "setlk") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        if is_virtual(ino) {
+            reply.error(libc::ENOLCK);
+            return;
+        }
+        debug!(
+            "setlk, ino = {}, start = {}, end = {}, type = {}",
+            ino, start, end, typ
+        );
+        let client = self.client.clone();
+        self.spawn("setlk", async move {
+            client
+                .setlk_remote(ino, lock_owner, start, end, typ, pid, sleep, reply)
+                .await
+        });
+    }
+
+    fn lseek(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        if !self.start(req, "lseek") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!(
+            "lseek, ino = {}, offset = {}, whence = {}",
+            ino, offset, whence
+        );
+        let client = self.client.clone();
+        self.spawn("lseek", async move {
+            client.seek_remote(ino, offset, whence, reply).await
+        });
+    }
+
+    fn readlink(&mut self, req: &Request<'_>, ino: u64, reply: ReplyData) {
+        if !self.start(req, "readlink") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        debug!("readlink, ino = {}", ino);
+        let client = self.client.clone();
+        self.spawn("readlink", async move {
+            client.readlink_remote(ino, reply).await
+        });
+    }
+
+    // only the truncation of a control file before writing it is supported,
+    // `echo 0 > .sealfs/readahead` opens it with O_TRUNC.
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        if !self.start(req, "setattr") {
+            reply.error(libc::EACCES);
+            return;
+        }
+        match ino {
+            READAHEAD_FILE_INODE | CACHE_SIZE_FILE_INODE => {
+                reply.attr(&VIRTUAL_TTL, &self.virtual_attr(ino))
+            }
+            _ => {
+                debug!(
+                    "setattr, ino = {}, mode = {:?}, uid = {:?}, gid = {:?}, size = {:?}",
+                    ino, mode, uid, gid, size
+                );
+                let time = |time: Option<TimeOrNow>| match time? {
+                    TimeOrNow::SpecificTime(time) => Some(time),
+                    TimeOrNow::Now => Some(SystemTime::now()),
+                };
+                let md = SetAttrSendMetaData {
+                    mode,
+                    uid,
+                    gid,
+                    size,
+                    atime: time(atime),
+                    mtime: time(mtime),
+                    epoch: 0,
+                };
+                let client = self.client.clone();
+                let ino = if ino == 1 {
+                    self.volume_root_inode
+                } else {
+                    ino
+                };
+                let ttl = self.ttl;
+                self.spawn("setattr", async move {
+                    client.setattr_remote(ino, md, ttl, reply).await
+                });
+            }
+        }
+    }
+}
+
+pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    let log_level = match cli.log_level {
+        Some(level) => level,
+        None => "warn".to_owned(),
+    };
+    let component = match cli.command {
+        Commands::Daemon { .. } => "client-daemon",
+        _ => "client",
+    };
+    init_logger(component, &log_level, cli.log_format.unwrap_or_default());
+
+    info!("spawn client");
+
+    let client = Arc::new(Client::new());
+
+    match cli.command {
+        Commands::CreateVolume {
+            mount_point,
+            volume_size,
+            atime,
+            public,
+            description,
+            manager_address,
         } => {
             let mountpoint = mount_point.unwrap();
 
+            let atime_policy = match atime {
+                Some(atime) => match AtimePolicy::from_str(&atime) {
+                    Ok(policy) => policy,
+                    Err(e) => {
+                        error!("create_volume failed, {}", e);
+                        return Ok(());
+                    }
+                },
+                None => AtimePolicy::default(),
+            };
+
             let manager_address = match manager_address {
                 Some(address) => address,
                 None => "127.0.0.1:8081".to_owned(),
@@ -405,7 +1576,13 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
 
             info!("create_volume");
             if let Err(status) = client
-                .create_volume(&mountpoint, volume_size.unwrap())
+                .create_volume(
+                    &mountpoint,
+                    volume_size.unwrap(),
+                    atime_policy,
+                    public,
+                    &description.unwrap_or_default(),
+                )
                 .await
             {
                 error!(
@@ -456,17 +1633,42 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
             manager_address,
             socket_path,
             clean_socket,
+            journal_file,
+            cache_node,
+            allow_uids,
+            allow_gids,
+            compress_rpc,
+            checksum_rpc,
+            short_circuit_reads,
+            page_cache,
+            page_cache_size,
+            writeback_cache,
+            write_batch_size,
+            write_batch_interval,
+            takeover,
+            otlp_endpoint,
+            otlp_interval,
         } => {
             let index_file = match index_file {
                 Some(file) => file,
                 None => LOCAL_INDEX_PATH.to_owned(),
             };
 
+            let exporter = match otlp_endpoint.as_deref().map(OtlpExporter::new).transpose() {
+                Ok(exporter) => exporter,
+                Err(e) => {
+                    error!("export the metrics failed, {}", e);
+                    return Ok(());
+                }
+            };
+
             let manager_address = match manager_address {
                 Some(address) => address,
                 None => "127.0.0.1:8081".to_owned(),
             };
             info!("init client");
+            client.client.set_compression(compress_rpc);
+            client.client.set_checksum(checksum_rpc);
             init_network_connections(manager_address, client.clone()).await;
 
             info!("connect_servers");
@@ -478,18 +1680,94 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
                 return Ok(());
             }
 
-            let sealfsd = SealfsFused::new(index_file, client);
-            match sealfsd.init().await {
-                Ok(_) => info!("sealfsd init success"),
-                Err(e) => panic!("sealfsd init failed, error = {}", e),
+            if let Some(cache_node) = cache_node {
+                if let Err(status) = client.use_cache_node(&cache_node).await {
+                    error!(
+                        "connect to cache node {} failed, status = {:?}",
+                        cache_node,
+                        status_to_string(status)
+                    );
+                    return Ok(());
+                }
+            }
+
+            if short_circuit_reads {
+                if let Err(status) = client.enable_short_circuit() {
+                    error!(
+                        "enable short-circuit reads failed, status = {:?}",
+                        status_to_string(status)
+                    );
+                    return Ok(());
+                }
+            }
+
+            if let Some(page_cache) = page_cache {
+                if let Err(status) = client.enable_page_cache(&page_cache, page_cache_size << 20) {
+                    error!(
+                        "enable page cache {} failed, status = {:?}",
+                        page_cache,
+                        status_to_string(status)
+                    );
+                    return Ok(());
+                }
+            }
+
+            if let Some(write_batch_size) = write_batch_size {
+                let interval = Duration::from_millis(write_batch_interval);
+                if let Err(status) = client.enable_write_batch(write_batch_size, interval) {
+                    error!(
+                        "enable write batch failed, status = {:?}",
+                        status_to_string(status)
+                    );
+                    return Ok(());
+                }
+                let client = client.clone();
+                tokio::spawn(async move { client.flush_writes_loop().await });
+            }
+
+            {
+                let client = client.clone();
+                tokio::spawn(async move { client.sync_maintenance_loop().await });
+            }
+
+            if let Some(journal_file) = journal_file {
+                if let Err(status) = client.enable_journal(&journal_file) {
+                    error!(
+                        "enable journal failed, status = {:?}",
+                        status_to_string(status)
+                    );
+                    return Ok(());
+                }
+                let client = client.clone();
+                tokio::spawn(async move { client.replay_journal_loop().await });
             }
 
             let socket_path = match socket_path {
                 Some(path) => path,
                 None => LOCAL_PATH.to_owned(),
             };
+            let takeover_path = takeover::takeover_path(&socket_path);
+
+            let mut sealfsd = SealfsFused::new(index_file, client, allow_uids, allow_gids);
+            sealfsd.takeover = takeover;
+            sealfsd.writeback_cache = writeback_cache;
+            let taken_over = takeover
+                && match takeover::take_over(&sealfsd, &takeover_path).await {
+                    Ok(taken_over) => taken_over,
+                    Err(e) => panic!("take over failed, error = {}", e),
+                };
+            if taken_over {
+                info!("sealfsd took over the mount points");
+                sealfsd.sync_index_file();
+            } else {
+                match sealfsd.init().await {
+                    Ok(_) => info!("sealfsd init success"),
+                    Err(e) => panic!("sealfsd init failed, error = {}", e),
+                }
+            }
 
-            if clean_socket {
+            // the socket of the previous daemon is replaced.
+            if clean_socket || taken_over {
                 if let Err(e) = std::fs::remove_file(&socket_path) {
                     if e.kind() != std::io::ErrorKind::NotFound {
                         panic!("remove socket file failed, error = {}", e);
@@ -497,7 +1775,21 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
-            let server = RpcServer::new(Arc::new(sealfsd), &socket_path);
+            let sealfsd = Arc::new(sealfsd);
+            if let Some(exporter) = exporter {
+                let sealfsd = sealfsd.clone();
+                let interval = Duration::from_secs(otlp_interval.max(1));
+                tokio::spawn(async move { export_loop(sealfsd, exporter, interval).await });
+            }
+            if takeover {
+                let sealfsd = sealfsd.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = takeover::serve(sealfsd, takeover_path).await {
+                        error!("serve takeover failed, error = {}", e);
+                    }
+                });
+            }
+            let server = RpcServer::new(sealfsd, &socket_path);
             let result = server.run_unix_stream().await;
             match result {
                 Ok(_) => info!("server run success"),
@@ -512,7 +1804,22 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
             volume_name,
             socket_path,
             read_only,
+            owner,
+            allow_uids,
+            root_squash,
+            group,
+            entry_ttl,
+            attr_ttl,
+            cache,
         } => {
+            let ttl = match cache.as_str() {
+                "none" => CacheTtl::none(),
+                _ => CacheTtl {
+                    entry: Duration::from_millis(entry_ttl),
+                    attr: Duration::from_millis(attr_ttl),
+                    no_cache: false,
+                },
+            };
             let socket_path = match socket_path {
                 Some(path) => path,
                 None => LOCAL_PATH.to_owned(),
@@ -524,7 +1831,16 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
             }
 
             let result = local_client
-                .mount(&volume_name.unwrap(), &mount_point.unwrap(), read_only)
+                .mount(
+                    &volume_name.unwrap(),
+                    &mount_point.unwrap(),
+                    read_only,
+                    owner,
+                    &allow_uids,
+                    root_squash,
+                    group.as_deref(),
+                    ttl,
+                )
                 .await;
             match result {
                 Ok(_) => info!("mount success"),
@@ -581,6 +1897,137 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
             };
             Ok(())
         }
+        Commands::SetWeight {
+            server_address,
+            weight,
+            manager_address,
+        } => {
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            let server_address = server_address.unwrap();
+            match client
+                .set_server_weight(&server_address, weight.unwrap())
+                .await
+            {
+                Ok(_) => {
+                    info!("set weight of {} success, rebalancing", server_address);
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "set weight of {} failed, error = {}",
+                        server_address,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
+        Commands::SetGroup {
+            server_address,
+            group,
+            manager_address,
+        } => {
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            let (server_address, group) = (server_address.unwrap(), group.unwrap());
+            match client.set_server_group(&server_address, &group).await {
+                Ok(_) => {
+                    info!("set group of {} success, rebalancing", server_address);
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "set group of {} failed, error = {}",
+                        server_address,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
+        Commands::SetDomain {
+            server_address,
+            zone,
+            rack,
+            manager_address,
+        } => {
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            let server_address = server_address.unwrap();
+            let domain = FailureDomain {
+                zone: zone.unwrap_or_default(),
+                rack: rack.unwrap_or_default(),
+            };
+            match client.set_server_domain(&server_address, domain).await {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        warn!("{}", warning);
+                    }
+                    info!("set domain of {} success", server_address);
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "set domain of {} failed, error = {}",
+                        server_address,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
+        Commands::Maintenance {
+            action,
+            server_address,
+            manager_address,
+        } => {
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            let (action, server_address) = (action.unwrap(), server_address.unwrap());
+            match client
+                .set_maintenance(&server_address, action == "enter")
+                .await
+            {
+                Ok(_) => {
+                    info!("maintenance {} {} success", action, server_address);
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "maintenance {} {} failed, error = {}",
+                        action,
+                        server_address,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
         Commands::Delete {
             server_address,
             manager_address,
@@ -628,8 +2075,16 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
             match result {
                 Ok(volumes) => {
                     info!("list volumes success");
+                    // volumes created before the registry have no info.
+                    let infos = client.list_volume_infos().await.unwrap_or_else(|e| {
+                        error!("list volume infos failed, error = {}", status_to_string(e));
+                        Vec::new()
+                    });
                     for volume in volumes {
                         println!("{}", volume);
+                        if let Some(info) = infos.iter().find(|info| info.name == volume.name) {
+                            println!("  {}", info);
+                        }
                     }
                 }
                 Err(e) => {
@@ -663,7 +2118,75 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
             };
             Ok(())
         }
-        Commands::Status { manager_address } => {
+        Commands::Stats { socket_path } => {
+            let socket_path = match socket_path {
+                Some(path) => path,
+                None => LOCAL_PATH.to_owned(),
+            };
+            let local_client = LocalCli::new(socket_path.clone());
+
+            if let Err(e) = local_client.add_connection(&socket_path).await {
+                panic!("add connection failed, error = {}", status_to_string(e))
+            }
+
+            match local_client.stats().await {
+                Ok(stats) => {
+                    println!("mount point, volume, ops, read bytes, written bytes");
+                    for mount in &stats.mounts {
+                        println!(
+                            "{}, {}, {}, {}, {}",
+                            mount.mount_point,
+                            mount.volume_name,
+                            mount.ops,
+                            mount.read_bytes,
+                            mount.written_bytes
+                        );
+                    }
+                    println!("server, connected, requests, sent bytes, reconnects, avg latency us, max latency us, compression ratio, corrupt frames");
+                    for connection in &stats.connections {
+                        println!(
+                            "{}, {}, {}, {}, {}, {}, {}, {:.2}, {}",
+                            connection.server_address,
+                            connection.connected,
+                            connection.requests,
+                            connection.sent_bytes,
+                            connection.reconnects,
+                            connection.avg_latency_us,
+                            connection.max_latency_us,
+                            connection.compression_ratio,
+                            connection.corrupt_frames
+                        );
+                    }
+                    for mount in &stats.mounts {
+                        if let Some(last_error) = &mount.last_error {
+                            println!(
+                                "last error of {}: {} {} at {}, {} replied as {}",
+                                mount.mount_point,
+                                last_error.operation,
+                                last_error.path,
+                                last_error.time,
+                                status_to_string(last_error.status),
+                                status_to_string(last_error.errno)
+                            );
+                        }
+                    }
+                    println!(
+                        "{} mount points share {} connections",
+                        stats.mounts.len(),
+                        stats.connections.len()
+                    );
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("get stats failed, error = {}", status_to_string(e)),
+                ))),
+            }
+        }
+        Commands::Status {
+            manager_address,
+            transfers,
+        } => {
             let manager_address = match manager_address {
                 Some(address) => address,
                 None => "127.0.0.1:8081".to_owned(),
@@ -681,7 +2204,64 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
                     info!("get cluster status failed, error = {}", status_to_string(e))
                 }
             };
-            Ok(())
+            match client.get_membership_changes().await {
+                Ok(changes) => {
+                    for change in changes.running {
+                        println!("running: {}", change);
+                    }
+                    for change in changes.queued {
+                        println!("queued: {}", change);
+                    }
+                }
+                Err(e) => {
+                    info!(
+                        "get membership changes failed, error = {}",
+                        status_to_string(e)
+                    )
+                }
+            };
+            if !transfers {
+                return Ok(());
+            }
+            match client.transfer_status().await {
+                Ok(reports) => {
+                    for (server, report) in reports {
+                        println!(
+                            "{}: {} pending, {} in flight, {} done, {} failed, {} bytes moved",
+                            server,
+                            report.pending,
+                            report.in_flight,
+                            report.done,
+                            report.failed,
+                            report.bytes
+                        );
+                        for (path, transfer) in report.files {
+                            match transfer.failures {
+                                0 => println!(
+                                    "  {} {}: {} bytes",
+                                    transfer.state, path, transfer.bytes
+                                ),
+                                failures => println!(
+                                    "  {} {}: {} bytes, {} failures, last {}",
+                                    transfer.state,
+                                    path,
+                                    transfer.bytes,
+                                    failures,
+                                    status_to_string(transfer.error)
+                                ),
+                            }
+                        }
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "get transfer status failed, error = {}",
+                        status_to_string(e)
+                    ),
+                ))),
+            }
         }
         Commands::Probe { socket_path } => {
             let socket_path = match socket_path {
@@ -707,5 +2287,589 @@ pub async fn run_command() -> Result<(), Box<dyn std::error::Error>> {
 
             Ok(())
         }
+        Commands::Health {
+            address,
+            manager,
+            live,
+        } => {
+            let address = address.unwrap();
+            let result = match client.add_connection(&address).await {
+                Ok(_) => client.sender.health(&address, manager).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(report) if live || report.healthy() => {
+                    println!("{}", report);
+                    Ok(())
+                }
+                Ok(report) => {
+                    println!("{}", report);
+                    Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("{} is not ready", address),
+                    )))
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "health of {} failed, error = {}",
+                        address,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
+        Commands::ListTree {
+            path,
+            manager_address,
+        } => {
+            let path = path.unwrap().trim_matches('/').to_owned();
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            let (sender, mut receiver) = tokio::sync::mpsc::channel::<(String, FileAttr)>(1024);
+            let printer = tokio::spawn(async move {
+                while let Some((path, attr)) = receiver.recv().await {
+                    println!("{:?}\t{}\t{}", attr.kind, attr.size, path);
+                }
+            });
+            let result = client.walk_tree(&path, sender).await;
+            let _ = printer.await;
+            if let Err(e) = result {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("list tree {} failed, error = {}", path, status_to_string(e)),
+                )));
+            }
+            Ok(())
+        }
+        Commands::SetAttrTree {
+            path,
+            mode,
+            uid,
+            gid,
+            manager_address,
+        } => {
+            let path = path.unwrap().trim_matches('/').to_owned();
+            let mode = match mode.map(|mode| u32::from_str_radix(&mode, 8)).transpose() {
+                Ok(mode) => mode,
+                Err(e) => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid mode: {}", e),
+                    )))
+                }
+            };
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            let result = client
+                .set_attr_tree(&path, mode, (uid, gid), |changed| {
+                    eprint!("\rchanged {} paths", changed)
+                })
+                .await;
+            eprintln!();
+            match result {
+                Ok(changed) => {
+                    println!("changed {} paths", changed);
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "set attr tree {} failed, error = {}",
+                        path,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
+        Commands::DedupScan {
+            path,
+            apply,
+            manager_address,
+        } => {
+            let path = path.unwrap().trim_matches('/').to_owned();
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            match client.dedup_scan(&path, apply).await {
+                Ok(result) => {
+                    println!(
+                        "{} files, {} bytes in {} chunks",
+                        result.files, result.bytes, result.chunks
+                    );
+                    println!(
+                        "{} duplicate chunks, {} bytes ({:.1}%) could be saved",
+                        result.duplicate_chunks,
+                        result.duplicate_bytes,
+                        result.duplicate_bytes as f64 * 100.0 / result.bytes.max(1) as f64
+                    );
+                    if apply {
+                        println!("{} bytes shared", result.shared_bytes);
+                        if result.unsupported {
+                            println!("some servers cannot share extents, their disks or storage engines do not support reflink");
+                        }
+                    }
+                    Ok(())
+                }
+                Err(e) => Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "dedup scan {} failed, error = {}",
+                        path,
+                        status_to_string(e)
+                    ),
+                ))),
+            }
+        }
+        Commands::CreateLog {
+            path,
+            manager_address,
+        } => {
+            let path = path.unwrap().trim_matches('/').to_owned();
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            if let Err(e) = client.create_log(&path).await {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!(
+                        "create log {} failed, error = {}",
+                        path,
+                        status_to_string(e)
+                    ),
+                )));
+            }
+            Ok(())
+        }
+        Commands::ShardDir {
+            path,
+            shards,
+            manager_address,
+        } => {
+            let path = path.unwrap().trim_matches('/').to_owned();
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            if let Err(e) = client.shard_dir(&path, shards).await {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("shard dir {} failed, error = {}", path, status_to_string(e)),
+                )));
+            }
+            Ok(())
+        }
+        #[cfg(feature = "grep-pushdown")]
+        Commands::Grep {
+            path,
+            pattern,
+            bytes,
+            max_count,
+            manager_address,
+        } => {
+            let (path, pattern) = (path.unwrap(), pattern.unwrap());
+            let path = path.trim_matches('/');
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            use std::io::Write;
+            let mut stdout = std::io::stdout().lock();
+            let mut count = 0;
+            let result = client
+                .grep(path, pattern.as_bytes(), !bytes, |m| {
+                    let _ = match bytes {
+                        true => writeln!(stdout, "{}", m.offset),
+                        // the last line of the file may have no newline.
+                        false if !m.data.ends_with(b"\n") => stdout
+                            .write_all(&m.data)
+                            .and_then(|_| stdout.write_all(b"\n")),
+                        false => stdout.write_all(&m.data),
+                    };
+                    count += 1;
+                    max_count.is_none_or(|max_count| count < max_count)
+                })
+                .await;
+            if let Err(e) = result {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("grep {} failed, error = {}", path, status_to_string(e)),
+                )));
+            }
+            Ok(())
+        }
+        Commands::Upload {
+            local_file,
+            path,
+            part_size,
+            parallel,
+            manager_address,
+        } => {
+            let (local_file, path) = (local_file.unwrap(), path.unwrap());
+            let path = path.trim_matches('/');
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+
+            match client
+                .upload_file(&local_file, path, part_size << 20, parallel)
+                .await
+            {
+                Ok(size) => println!("uploaded {} bytes to {}", size, path),
+                Err(e) => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                            "upload {} to {} failed, error = {}",
+                            local_file,
+                            path,
+                            status_to_string(e)
+                        ),
+                    )))
+                }
+            }
+            Ok(())
+        }
+        Commands::Prefetch {
+            path,
+            parallel,
+            cache_node,
+            manager_address,
+        } => {
+            let path = path.unwrap().trim_matches('/').to_owned();
+            let manager_address = match manager_address {
+                Some(address) => address,
+                None => "127.0.0.1:8081".to_owned(),
+            };
+            info!("init client");
+            init_network_connections(manager_address, client.clone()).await;
+
+            info!("connect_servers");
+            if let Err(status) = client.connect_servers().await {
+                error!(
+                    "connect_servers failed, status = {:?}",
+                    status_to_string(status)
+                );
+                return Ok(());
+            }
+            if let Some(address) = cache_node {
+                if let Err(status) = client.use_cache_node(&address).await {
+                    error!(
+                        "use cache node {} failed, status = {:?}",
+                        address,
+                        status_to_string(status)
+                    );
+                    return Ok(());
+                }
+            }
+
+            let files = match client.list_files(&path).await {
+                Ok(files) => files,
+                Err(e) => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("list {} failed, error = {}", path, status_to_string(e)),
+                    )))
+                }
+            };
+            let total = files.len();
+            let (mut done, mut failed, mut bytes) = (0, 0, 0);
+            let mut tasks = tokio::task::JoinSet::new();
+            let mut files = files.into_iter();
+            loop {
+                while tasks.len() < parallel.max(1) {
+                    let Some(file) = files.next() else {
+                        break;
+                    };
+                    let client = client.clone();
+                    tasks.spawn(async move {
+                        let result = client.prefetch_file(&file).await;
+                        (file, result)
+                    });
+                }
+                let Some(result) = tasks.join_next().await else {
+                    break;
+                };
+                match result {
+                    Ok((_, Ok(size))) => bytes += size,
+                    Ok((file, Err(e))) => {
+                        error!("prefetch {} failed, error = {}", file, status_to_string(e));
+                        failed += 1;
+                    }
+                    Err(e) => {
+                        error!("prefetch task failed: {}", e);
+                        failed += 1;
+                    }
+                }
+                done += 1;
+                eprint!("\rprefetched {}/{} files, {} bytes", done, total, bytes);
+            }
+            eprintln!();
+            println!(
+                "prefetched {} files, {} bytes, {} failed",
+                done - failed,
+                bytes,
+                failed
+            );
+            Ok(())
+        }
+        Commands::Mkfs { device } => {
+            let device = device.unwrap();
+            match BlockEngine::mkfs(&device) {
+                Ok(superblock) => info!(
+                    "mkfs {} success, chunks: {}, reserved chunks: {}",
+                    device,
+                    superblock.chunk_num,
+                    superblock.reserved_chunks()
+                ),
+                Err(e) => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("mkfs {} failed, error = {}", device, status_to_string(e)),
+                    )))
+                }
+            };
+            Ok(())
+        }
+        Commands::Migrate { database_path } => {
+            let database_path = database_path.unwrap();
+            let meta_engine = MetaEngine::new(
+                &database_path,
+                #[cfg(feature = "disk-db")]
+                13421772,
+                #[cfg(feature = "disk-db")]
+                0x4000000,
+            );
+            match migration::migrate(&meta_engine) {
+                Ok(applied) => {
+                    for description in &applied {
+                        info!("migrated: {}", description);
+                    }
+                    info!(
+                        "migrate {} success, {} migrations applied, format version: {}",
+                        database_path,
+                        applied.len(),
+                        migration::FORMAT_VERSION
+                    );
+                }
+                Err(e) => {
+                    return Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("migrate {} failed, error = {}", database_path, e),
+                    )))
+                }
+            };
+            Ok(())
+        }
+        Commands::Cluster { command } => match command {
+            ClusterCommands::Init {
+                config,
+                start_manager,
+                timeout,
+            } => {
+                let config = cluster::ClusterConfig::load(&config.unwrap())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                info!("init cluster");
+                cluster::init(
+                    client.clone(),
+                    &config,
+                    start_manager,
+                    Duration::from_secs(timeout),
+                )
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                Ok(())
+            }
+        },
+        Commands::Volume { command } => match command {
+            VolumeCommands::Set {
+                name,
+                description,
+                quota,
+                stripe_count,
+                replication,
+                compression,
+                chunk_size,
+                manager_address,
+            } => {
+                let name = name.unwrap();
+                let manager_address = match manager_address {
+                    Some(address) => address,
+                    None => "127.0.0.1:8081".to_owned(),
+                };
+                info!("init client");
+                init_network_connections(manager_address, client.clone()).await;
+
+                let update = SetVolumeSendMetaData {
+                    name: name.clone(),
+                    description,
+                    quota,
+                    stripe_count,
+                    replication,
+                    compression,
+                    chunk_size,
+                };
+                match client.set_volume(&update).await {
+                    Ok(warnings) => {
+                        for warning in warnings {
+                            warn!("{}", warning);
+                        }
+                        Ok(())
+                    }
+                    Err(status) => Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                            "set volume {} failed, error = {}",
+                            name,
+                            status_to_string(status)
+                        ),
+                    ))),
+                }
+            }
+            VolumeCommands::History {
+                name,
+                days,
+                manager_address,
+            } => {
+                let name = name.unwrap();
+                let manager_address = match manager_address {
+                    Some(address) => address,
+                    None => "127.0.0.1:8081".to_owned(),
+                };
+                info!("init client");
+                init_network_connections(manager_address, client.clone()).await;
+
+                match client.get_volume_history(&name, days).await {
+                    Ok(history) => {
+                        println!(
+                            "date, ops, read bytes, written bytes, files created, files deleted"
+                        );
+                        for day in history.iter().rev() {
+                            println!(
+                                "{}, {}, {}, {}, {}, {}",
+                                day.date(),
+                                day.ops,
+                                day.read_bytes,
+                                day.write_bytes,
+                                day.files_created,
+                                day.files_deleted
+                            );
+                        }
+                        if !history.is_empty() {
+                            let n = history.len() as u64;
+                            let sum = |f: fn(&VolumeDay) -> u64| history.iter().map(f).sum::<u64>();
+                            println!(
+                                "average of {} days: {} ops, {} read bytes, {} written bytes, {:+} files",
+                                n,
+                                sum(|d| d.ops) / n,
+                                sum(|d| d.read_bytes) / n,
+                                sum(|d| d.write_bytes) / n,
+                                (sum(|d| d.files_created) as i64 - sum(|d| d.files_deleted) as i64)
+                                    / n as i64
+                            );
+                        }
+                        Ok(())
+                    }
+                    Err(status) => Err(Box::new(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                            "get history of volume {} failed, error = {}",
+                            name,
+                            status_to_string(status)
+                        ),
+                    ))),
+                }
+            }
+        },
     }
 }
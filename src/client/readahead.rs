@@ -0,0 +1,150 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Read ahead of a mount point.
+ * A read missing the buffers asks the server for `size` bytes instead, and
+ * keeps them for the next reads of the file. The buffers of a file are
+ * dropped when it is written through the mount point and expire like the
+ * attrs, so the changes of other clients are seen as late as with the attrs.
+ * Both settings are changed at runtime through the .sealfs control files.
+ */
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+pub const READAHEAD_TTL: Duration = Duration::from_secs(1);
+pub const DEFAULT_READAHEAD_CAPACITY: usize = 64;
+
+struct Buffer {
+    offset: i64,
+    data: Vec<u8>,
+    // the read stopped at the end of the file.
+    eof: bool,
+    time: Instant,
+}
+
+#[derive(Default)]
+struct Buffers {
+    files: HashMap<u64, Buffer>,
+    // inodes from the oldest buffer.
+    order: VecDeque<u64>,
+}
+
+pub struct ReadAhead {
+    // bytes read by a read missing the buffers, 0 disables read ahead.
+    pub size: AtomicU64,
+    // files whose buffers are kept.
+    pub capacity: AtomicUsize,
+    buffers: Mutex<Buffers>,
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new(0, DEFAULT_READAHEAD_CAPACITY)
+    }
+}
+
+impl ReadAhead {
+    pub fn new(size: u64, capacity: usize) -> Self {
+        Self {
+            size: AtomicU64::new(size),
+            capacity: AtomicUsize::new(capacity),
+            buffers: Mutex::new(Buffers::default()),
+        }
+    }
+
+    // read_size returns the bytes to read for a read of size missing the
+    // buffers, or None if it is not read ahead.
+    pub fn read_size(&self, size: u32) -> Option<u32> {
+        let readahead = self.size.load(Ordering::Relaxed).min(u32::MAX as u64) as u32;
+        match readahead > size && self.capacity.load(Ordering::Relaxed) > 0 {
+            true => Some(readahead),
+            false => None,
+        }
+    }
+
+    pub fn get(&self, ino: u64, offset: i64, size: u32) -> Option<Vec<u8>> {
+        let buffers = self.buffers.lock();
+        let buffer = buffers.files.get(&ino)?;
+        if buffer.time.elapsed() >= READAHEAD_TTL || offset < buffer.offset {
+            return None;
+        }
+        let begin = (offset - buffer.offset) as usize;
+        let end = begin + size as usize;
+        if end <= buffer.data.len() {
+            Some(buffer.data[begin..end].to_vec())
+        } else if buffer.eof {
+            Some(buffer.data[begin.min(buffer.data.len())..].to_vec())
+        } else {
+            None
+        }
+    }
+
+    // insert keeps data read at offset for a read of size bytes.
+    pub fn insert(&self, ino: u64, offset: i64, size: u32, data: Vec<u8>) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let mut buffers = self.buffers.lock();
+        let eof = data.len() < size as usize;
+        let buffer = Buffer {
+            offset,
+            data,
+            eof,
+            time: Instant::now(),
+        };
+        if buffers.files.insert(ino, buffer).is_none() {
+            buffers.order.push_back(ino);
+        }
+        while buffers.files.len() > capacity {
+            match buffers.order.pop_front() {
+                Some(oldest) => {
+                    buffers.files.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn invalidate(&self, ino: u64) {
+        let mut buffers = self.buffers.lock();
+        if buffers.files.remove(&ino).is_some() {
+            buffers.order.retain(|i| *i != ino);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadAhead;
+
+    #[test]
+    fn readahead_test() {
+        let readahead = ReadAhead::new(0, 2);
+        assert_eq!(readahead.read_size(4096), None);
+        readahead
+            .size
+            .store(1 << 20, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(readahead.read_size(4096), Some(1 << 20));
+
+        readahead.insert(1, 100, 8, b"abcdefgh".to_vec());
+        assert_eq!(readahead.get(1, 102, 4), Some(b"cdef".to_vec()));
+        assert_eq!(readahead.get(1, 106, 4), None);
+        assert_eq!(readahead.get(1, 99, 4), None);
+        // a short read ends at the end of the file.
+        readahead.insert(2, 0, 8, b"abc".to_vec());
+        assert_eq!(readahead.get(2, 1, 4), Some(b"bc".to_vec()));
+        assert_eq!(readahead.get(2, 5, 4), Some(Vec::new()));
+
+        // the oldest file is dropped when full.
+        readahead.insert(3, 0, 1, b"a".to_vec());
+        assert_eq!(readahead.get(1, 100, 1), None);
+        assert_eq!(readahead.get(3, 0, 1), Some(b"a".to_vec()));
+        readahead.invalidate(3);
+        assert_eq!(readahead.get(3, 0, 1), None);
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Short-circuit reads, HDFS style: a client on the host of the server of a
+ * file reads the local file of the server directly instead of asking the
+ * server for the data. The client asks the server for the path of the local
+ * file with an OpenLocal request on the local socket, see rpc/local.rs, and
+ * the server only answers the peers running as root or as the server. The
+ * file is kept open and read with pread until it is unlinked, e.g. by a
+ * delete or a migration, then it is asked again. A server refusing the
+ * short-circuit reads, e.g. a server of another host, is not asked again.
+ * The reads bypass the server: they update no atime and are not counted in
+ * the usage of the volume.
+ */
+use std::{
+    fs::File,
+    os::unix::fs::{FileExt, MetadataExt},
+    sync::Arc,
+};
+
+use dashmap::{DashMap, DashSet};
+use log::{debug, info};
+
+use crate::common::{errors::status_to_string, sender::Sender};
+
+// the local files kept open, all of them are closed past this number.
+pub const SHORT_CIRCUIT_FILES: usize = 1024;
+
+#[derive(Default)]
+pub struct ShortCircuit {
+    // path -> the address of its server and its local file.
+    files: DashMap<String, (String, Arc<File>)>,
+    // the servers refusing the short-circuit reads.
+    refused: DashSet<String>,
+}
+
+impl ShortCircuit {
+    // read returns the data of path read from its local file on the server at
+    // address, or None to read it from the server.
+    pub async fn read(
+        &self,
+        sender: &Sender,
+        address: &str,
+        path: &str,
+        offset: i64,
+        size: u32,
+    ) -> Option<Vec<u8>> {
+        if self.refused.contains(address) {
+            return None;
+        }
+        let cached = self.files.get(path).and_then(|entry| {
+            let linked = entry.1.metadata().is_ok_and(|m| m.nlink() > 0);
+            (entry.0 == address && linked).then(|| entry.1.clone())
+        });
+        let file = match cached {
+            Some(file) => file,
+            None => self.open(sender, address, path).await?,
+        };
+        // a short read ends at the end of the file only when nothing more is read.
+        let (mut data, mut length) = (vec![0u8; size as usize], 0);
+        while length < data.len() {
+            match file.read_at(&mut data[length..], offset as u64 + length as u64) {
+                Ok(0) => break,
+                Ok(read) => length += read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("short-circuit read of {} failed: {}", path, e);
+                    self.files.remove(path);
+                    return None;
+                }
+            }
+        }
+        data.truncate(length);
+        Some(data)
+    }
+
+    async fn open(&self, sender: &Sender, address: &str, path: &str) -> Option<Arc<File>> {
+        let local_path = match sender.open_local(address, path).await {
+            Ok(local_path) => local_path,
+            Err(e @ (libc::EPERM | libc::EACCES | libc::ENOTSUP)) => {
+                info!(
+                    "short-circuit reads refused by {}: {}",
+                    address,
+                    status_to_string(e)
+                );
+                self.refused.insert(address.to_owned());
+                return None;
+            }
+            Err(e) => {
+                debug!("open local {} failed: {}", path, status_to_string(e));
+                self.files.remove(path);
+                return None;
+            }
+        };
+        let file = match File::open(&local_path) {
+            Ok(file) => Arc::new(file),
+            // deleted since it was opened.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.files.remove(path);
+                return None;
+            }
+            Err(e) => {
+                info!(
+                    "open {} failed: {}, no short-circuit reads from {}",
+                    local_path, e, address
+                );
+                self.refused.insert(address.to_owned());
+                return None;
+            }
+        };
+        if self.files.len() >= SHORT_CIRCUIT_FILES {
+            self.files.clear();
+        }
+        self.files
+            .insert(path.to_owned(), (address.to_owned(), file.clone()));
+        Some(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use async_trait::async_trait;
+
+    use super::ShortCircuit;
+    use crate::{
+        common::{sender::Sender, serialization::OperationType},
+        rpc::{
+            client::RpcClient,
+            local::{serve_local, LOCAL_CONNECTION},
+            server::Handler,
+        },
+    };
+
+    const LOCAL_FILE: &str = "/tmp/test_short_circuit";
+
+    // LocalFileHandler answers the OpenLocal requests of the local peers.
+    struct LocalFileHandler;
+
+    #[async_trait]
+    impl Handler for LocalFileHandler {
+        async fn dispatch(
+            &self,
+            id: u32,
+            operation_type: u32,
+            _flags: u32,
+            _path: Vec<u8>,
+            _data: Vec<u8>,
+            _metadata: Vec<u8>,
+        ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+            assert_eq!(operation_type, OperationType::OpenLocal as u32);
+            match id & LOCAL_CONNECTION {
+                0 => Ok((libc::EPERM, 0, 0, 0, vec![], vec![])),
+                _ => Ok((0, 0, 0, LOCAL_FILE.len(), vec![], LOCAL_FILE.into())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn short_circuit_test() {
+        let address = "127.0.0.1:18395";
+        std::fs::write(LOCAL_FILE, b"hello").unwrap();
+        tokio::spawn(serve_local(Arc::new(LocalFileHandler), address.to_owned()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let sender = Sender::new(Arc::new(RpcClient::new()));
+        sender.client.add_connection(address).await.unwrap();
+
+        let short_circuit = ShortCircuit::default();
+        let read = |offset, size| short_circuit.read(&sender, address, "v/a", offset, size);
+        assert_eq!(read(1, 3).await, Some(b"ell".to_vec()));
+        assert_eq!(read(3, 10).await, Some(b"lo".to_vec()));
+        // a file replaced is opened again.
+        std::fs::remove_file(LOCAL_FILE).unwrap();
+        std::fs::write(LOCAL_FILE, b"world").unwrap();
+        assert_eq!(read(0, 5).await, Some(b"world".to_vec()));
+        std::fs::remove_file(LOCAL_FILE).unwrap();
+        // the file is gone, and the server is not refused for it.
+        assert_eq!(read(0, 5).await, None);
+        assert!(short_circuit.refused.is_empty());
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Takeover of the mount points of a running daemon by a new one, e.g. to
+ * upgrade it, without unmounting them.
+ * A daemon started with --takeover mounts without auto_unmount and listens on
+ * `<socket_path>.takeover`. The next daemon started with --takeover connects
+ * to it and receives the state of the sessions, the mount points and the
+ * inode table, with the FUSE fds passed as SCM_RIGHTS. It serves the fds and
+ * acks, and the old daemon exits without unmounting.
+ * The inodes and file handles held by the kernel stay valid, as the handles
+ * are not kept by the daemon and the inode table is handed over.
+ * Before the fds are sent, the old sessions stop reading: each one parks at
+ * its next request, a statfs sent to its mount point if it is idle. The
+ * requests they read are answered and the batched writes sent, the kernel
+ * queues the new ones for the next daemon meanwhile.
+ */
+use std::{
+    fs::Permissions,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{fs::PermissionsExt, net::UnixStream},
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::Thread,
+    time::{Duration, Instant},
+};
+
+use fuser::{Session, SessionACL};
+use log::{error, info, warn};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    daemon::{allowed_uids, MountOptions, MountPoint, MountStats, SealfsFused},
+    SealFS,
+};
+use crate::common::errors::status_to_string;
+
+// how long the old sessions may take to stop reading, and to answer the
+// requests they read.
+const TAKEOVER_STOP: Duration = Duration::from_secs(5);
+const TAKEOVER_DRAIN: Duration = Duration::from_secs(30);
+const TAKEOVER_POLL: Duration = Duration::from_millis(10);
+// SCM_MAX_FD, the fds a message may pass.
+const MAX_MOUNTS: usize = 253;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SessionMount {
+    pub mount_point: String,
+    pub volume_name: String,
+    pub options: MountOptions,
+    pub root_inode: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct SessionState {
+    pub mounts: Vec<SessionMount>,
+    pub inodes: Vec<(u64, String)>,
+    pub inode_counter: u64,
+    pub fd_counter: u64,
+}
+
+// Handover parks the thread of a session at its next request while its
+// mount point is handed over.
+#[derive(Default)]
+pub struct Handover {
+    handed_over: AtomicBool,
+    parked: Mutex<Option<Thread>>,
+}
+
+impl Handover {
+    // stop parks the session thread calling it while the mount point is
+    // handed over, after it dispatched its request.
+    pub fn stop(&self) {
+        if !self.handed_over.load(Ordering::Acquire) {
+            return;
+        }
+        *self.parked.lock() = Some(std::thread::current());
+        while self.handed_over.load(Ordering::Acquire) {
+            std::thread::park();
+        }
+    }
+
+    fn is_parked(&self) -> bool {
+        self.parked.lock().is_some()
+    }
+
+    fn begin(&self) {
+        self.handed_over.store(true, Ordering::Release);
+    }
+
+    // cancel resumes the session, the takeover failed.
+    fn cancel(&self) {
+        self.handed_over.store(false, Ordering::Release);
+        if let Some(thread) = self.parked.lock().take() {
+            thread.unpark();
+        }
+    }
+}
+
+pub fn takeover_path(socket_path: &str) -> String {
+    format!("{}.takeover", socket_path)
+}
+
+// unmount unmounts a mount point taken over, which has no fuser mount.
+pub fn unmount(mount_point: &str) {
+    for fusermount in ["fusermount3", "fusermount"] {
+        match std::process::Command::new(fusermount)
+            .args(["-u", "-z", mount_point])
+            .status()
+        {
+            Ok(status) if status.success() => return,
+            Ok(status) => warn!("{} -u {}: {}", fusermount, mount_point, status),
+            Err(e) => warn!("{} -u {}: {}", fusermount, mount_point, e),
+        }
+    }
+    error!("unmount {} failed", mount_point);
+}
+
+// send_state sends the length of the state with the fds, then the state.
+fn send_state(stream: &UnixStream, state: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let header = (state.len() as u64).to_le_bytes();
+    let sent = sendmsg::<()>(
+        stream.as_raw_fd(),
+        &[IoSlice::new(&header)],
+        &[ControlMessage::ScmRights(fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    let mut stream = stream;
+    stream.write_all(&header[sent..])?;
+    stream.write_all(state)
+}
+
+fn recv_state(stream: &UnixStream) -> io::Result<(Vec<u8>, Vec<OwnedFd>)> {
+    let mut header = [0u8; 8];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_MOUNTS]);
+    let mut fds = Vec::new();
+    let received = {
+        let mut iov = [IoSliceMut::new(&mut header)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(raw_fds) = cmsg {
+                fds.extend(
+                    raw_fds
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        msg.bytes
+    };
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut stream = stream;
+    stream.read_exact(&mut header[received..])?;
+    let mut state = vec![0u8; u64::from_le_bytes(header) as usize];
+    stream.read_exact(&mut state)?;
+    Ok((state, fds))
+}
+
+// session_state returns the state of the sessions of daemon, and the fds of
+// its mount points in the same order.
+fn session_state(daemon: &SealfsFused) -> (SessionState, Vec<RawFd>) {
+    let client = &daemon.client;
+    let mut state = SessionState {
+        inodes: client
+            .inodes_reverse
+            .iter()
+            .map(|kv| (*kv.key(), kv.value().clone()))
+            .collect(),
+        inode_counter: client.inode_counter.load(Ordering::Acquire),
+        fd_counter: client.fd_counter.load(Ordering::Acquire),
+        ..Default::default()
+    };
+    let mut fds = Vec::new();
+    for mount_point in daemon.mount_points.iter() {
+        state.mounts.push(SessionMount {
+            mount_point: mount_point.key().clone(),
+            volume_name: mount_point.volume_name.clone(),
+            options: mount_point.options.clone(),
+            root_inode: mount_point.root_inode,
+        });
+        fds.push(mount_point.fd.as_raw_fd());
+    }
+    (state, fds)
+}
+
+// stop_sessions stops the sessions of daemon reading requests, and returns
+// whether they all did in time.
+async fn stop_sessions(daemon: &SealfsFused) -> bool {
+    for mount_point in daemon.mount_points.iter() {
+        mount_point.stats.handover.begin();
+        // answered by the session once it resumes, or by the next daemon.
+        let path = mount_point.key().clone();
+        std::thread::spawn(move || nix::sys::statvfs::statvfs(path.as_str()));
+    }
+    let deadline = Instant::now() + TAKEOVER_STOP;
+    while !daemon
+        .mount_points
+        .iter()
+        .all(|mount_point| mount_point.stats.handover.is_parked())
+    {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(TAKEOVER_POLL).await;
+    }
+    true
+}
+
+// drain waits for the requests read by the stopped sessions to be replied,
+// and sends the writes batched for them.
+async fn drain(daemon: &SealfsFused) {
+    let deadline = Instant::now() + TAKEOVER_DRAIN;
+    while daemon
+        .mount_points
+        .iter()
+        .any(|mount_point| mount_point.stats.in_flight.load(Ordering::Acquire) > 0)
+    {
+        if Instant::now() >= deadline {
+            warn!(
+                "takeover with requests not replied after {:?}",
+                TAKEOVER_DRAIN
+            );
+            break;
+        }
+        tokio::time::sleep(TAKEOVER_POLL).await;
+    }
+    daemon.client.flush_all_writes().await;
+}
+
+fn resume_sessions(daemon: &SealfsFused) {
+    for mount_point in daemon.mount_points.iter() {
+        mount_point.stats.handover.cancel();
+    }
+}
+
+// serve hands the mount points of daemon over to the next daemon connecting
+// to path, and exits.
+pub async fn serve(daemon: Arc<SealfsFused>, path: String) -> io::Result<()> {
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+    let daemon_uid = unsafe { libc::geteuid() };
+    loop {
+        let (stream, _) = listener.accept().await?;
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == 0 || cred.uid() == daemon_uid => {}
+            _ => {
+                warn!("takeover refused to a peer of another user");
+                continue;
+            }
+        }
+        // no mount point changes until the daemon exits.
+        let _lock = daemon.mount_lock.lock().await;
+        let (state, fds) = session_state(&daemon);
+        if fds.len() > MAX_MOUNTS {
+            error!("takeover of more than {} mount points", MAX_MOUNTS);
+            continue;
+        }
+        info!("hand {} mount points over", fds.len());
+        if !stop_sessions(&daemon).await {
+            warn!(
+                "takeover with sessions still reading after {:?}",
+                TAKEOVER_STOP
+            );
+        }
+        drain(&daemon).await;
+        let state = bincode::serialize(&state).unwrap();
+        let result = async {
+            let stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+            tokio::task::spawn_blocking(move || {
+                send_state(&stream, &state, &fds)?;
+                let mut ack = [0u8];
+                (&stream).read_exact(&mut ack)
+            })
+            .await?
+        }
+        .await;
+        match result {
+            Ok(_) => {
+                info!("mount points taken over, exiting");
+                // exiting without dropping the sessions, which would unmount.
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!("takeover failed: {}", e);
+                resume_sessions(&daemon);
+            }
+        }
+    }
+}
+
+// take_over serves the mount points of the daemon listening on path, if
+// any, and returns whether it did.
+pub async fn take_over(daemon: &SealfsFused, path: &str) -> Result<bool, String> {
+    let stream = match tokio::net::UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(false)
+        }
+        Err(e) => return Err(format!("connect to {}: {}", path, e)),
+    };
+    let stream = stream.into_std().map_err(|e| e.to_string())?;
+    stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+    let (stream, state, fds) = tokio::task::spawn_blocking(move || {
+        recv_state(&stream).map(|(state, fds)| (stream, state, fds))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| format!("receive the sessions: {}", e))?;
+    let state: SessionState = bincode::deserialize(&state).map_err(|e| e.to_string())?;
+    if fds.len() != state.mounts.len() {
+        return Err(format!(
+            "received {} fds for {} mount points",
+            fds.len(),
+            state.mounts.len()
+        ));
+    }
+
+    let client = &daemon.client;
+    for (inode, path) in state.inodes {
+        client.inodes.insert(path.clone(), inode);
+        client.inodes_reverse.insert(inode, path);
+    }
+    client
+        .inode_counter
+        .fetch_max(state.inode_counter, Ordering::AcqRel);
+    client
+        .fd_counter
+        .fetch_max(state.fd_counter, Ordering::AcqRel);
+
+    let _lock = daemon.mount_lock.lock().await;
+    for (mount, fd) in state.mounts.into_iter().zip(fds) {
+        client
+            .load_volume(&mount.volume_name)
+            .await
+            .map_err(|e| format!("load volume {}: {}", mount.volume_name, status_to_string(e)))?;
+        let stats = Arc::new(MountStats::default());
+        let (uids, allow_other) = allowed_uids(&mount.options);
+        let acl = match allow_other {
+            true => SessionACL::All,
+            false => SessionACL::RootAndOwner,
+        };
+        let session_fd = fd.try_clone().map_err(|e| e.to_string())?;
+        let session = Session::from_fd(
+            SealFS::new(
+                client.clone(),
+                mount.root_inode,
+                stats.clone(),
+                uids,
+                mount.options.root_squash,
+                mount.options.ttl,
+                daemon.writeback_cache,
+            ),
+            session_fd,
+            acl,
+        )
+        .spawn()
+        .map_err(|e| format!("serve {}: {}", mount.mount_point, e))?;
+        info!("took over {} on {}", mount.volume_name, mount.mount_point);
+        daemon.mount_points.insert(
+            mount.mount_point,
+            MountPoint {
+                volume_name: mount.volume_name,
+                options: mount.options,
+                session,
+                stats,
+                fd,
+                root_inode: mount.root_inode,
+                taken_over: true,
+            },
+        );
+    }
+    (&stream).write_all(&[1]).map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::{
+        fd::AsRawFd,
+        unix::{fs::MetadataExt, net::UnixStream},
+    };
+
+    use super::{recv_state, send_state, Handover, SessionMount, SessionState};
+    use crate::client::daemon::MountOptions;
+
+    #[test]
+    fn handover_test() {
+        let handover = std::sync::Arc::new(Handover::default());
+        // a session not handed over goes on.
+        handover.stop();
+        assert!(!handover.is_parked());
+
+        handover.begin();
+        let session = {
+            let handover = handover.clone();
+            std::thread::spawn(move || handover.stop())
+        };
+        while !handover.is_parked() {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(!session.is_finished());
+        handover.cancel();
+        session.join().unwrap();
+    }
+
+    #[test]
+    fn takeover_state_test() {
+        let state = SessionState {
+            mounts: vec![SessionMount {
+                mount_point: "/mnt/v".to_owned(),
+                volume_name: "v".to_owned(),
+                options: MountOptions {
+                    read_only: true,
+                    allowed_uids: vec![1000],
+                    ..Default::default()
+                },
+                root_inode: 2,
+            }],
+            inodes: vec![(2, "v".to_owned()), (3, "v/a".to_owned())],
+            inode_counter: 4,
+            fd_counter: 7,
+        };
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        let bytes = bincode::serialize(&state).unwrap();
+        send_state(&a, &bytes, &[file.as_raw_fd()]).unwrap();
+        let (received, fds) = recv_state(&b).unwrap();
+        assert_eq!(
+            bincode::deserialize::<SessionState>(&received).unwrap(),
+            state
+        );
+        // the fd received is the same file.
+        assert_eq!(fds.len(), 1);
+        let path = format!("/proc/self/fd/{}", fds[0].as_raw_fd());
+        assert_eq!(
+            std::fs::metadata(path).unwrap().rdev(),
+            file.metadata().unwrap().rdev()
+        );
+    }
+}
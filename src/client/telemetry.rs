@@ -0,0 +1,378 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Metrics of a client daemon pushed to an OpenTelemetry collector, with OTLP
+ * over HTTP in its JSON encoding, every interval. For every mount point: the
+ * requests, the latencies and the bytes of its FUSE operations, and the
+ * errors replied for its volume; for the daemon: the hits of its caches and
+ * the requests to the servers. The sums are cumulative since the daemon
+ * started. Only plain http endpoints are supported, e.g. a collector running
+ * next to the daemon.
+ */
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::daemon::SealfsFused;
+use crate::common::util::hostname;
+
+// the upper bounds of the buckets of the latencies, in microseconds.
+pub const LATENCY_BOUNDS_US: [u64; 12] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// LatencyHistogram counts the latencies of an operation in the buckets of
+// LATENCY_BOUNDS_US, the last bucket holding the ones above all the bounds.
+#[derive(Default)]
+pub struct LatencyHistogram {
+    count: AtomicU64,
+    sum_us: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BOUNDS_US.len() + 1],
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let bucket = LATENCY_BOUNDS_US
+            .iter()
+            .position(|bound| us <= *bound)
+            .unwrap_or(LATENCY_BOUNDS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // data_point returns the histogram as an OTLP data point.
+    fn data_point(&self, attributes: Value, start: u64, now: u64) -> Value {
+        json!({
+            "attributes": attributes,
+            "startTimeUnixNano": start.to_string(),
+            "timeUnixNano": now.to_string(),
+            "count": self.count.load(Ordering::Relaxed).to_string(),
+            "sum": self.sum_us.load(Ordering::Relaxed) as f64,
+            "bucketCounts": self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed).to_string())
+                .collect::<Vec<_>>(),
+            "explicitBounds": LATENCY_BOUNDS_US,
+        })
+    }
+}
+
+fn key_values(pairs: &[(&str, &str)]) -> Value {
+    pairs
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+fn sum_point(attributes: Value, value: u64, start: u64, now: u64) -> Value {
+    json!({
+        "attributes": attributes,
+        "startTimeUnixNano": start.to_string(),
+        "timeUnixNano": now.to_string(),
+        "asInt": value.to_string(),
+    })
+}
+
+fn sum_metric(name: &str, unit: &str, description: &str, data_points: Vec<Value>) -> Value {
+    json!({
+        "name": name,
+        "unit": unit,
+        "description": description,
+        // cumulative.
+        "sum": {"aggregationTemporality": 2, "isMonotonic": true, "dataPoints": data_points},
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+// metrics_request returns the OTLP export request of the metrics of the
+// daemon, cumulative since start.
+pub fn metrics_request(fused: &SealfsFused, start: SystemTime) -> Value {
+    let (start, now) = (unix_nanos(start), unix_nanos(SystemTime::now()));
+    let (mut operations, mut latencies, mut read_bytes, mut written_bytes, mut errors) =
+        (vec![], vec![], vec![], vec![], vec![]);
+    for mount_point in fused.mount_points.iter() {
+        let (path, volume) = (mount_point.key(), &mount_point.volume_name);
+        let stats = &mount_point.stats;
+        for op_count in stats.op_counts.iter() {
+            let attributes = key_values(&[
+                ("mount_point", path),
+                ("volume", volume),
+                ("operation", op_count.key()),
+            ]);
+            operations.push(sum_point(attributes, *op_count.value(), start, now));
+        }
+        for latency in stats.op_latencies.iter() {
+            let attributes = key_values(&[
+                ("mount_point", path),
+                ("volume", volume),
+                ("operation", latency.key()),
+            ]);
+            latencies.push(latency.data_point(attributes, start, now));
+        }
+        let attributes = key_values(&[("mount_point", path), ("volume", volume)]);
+        read_bytes.push(sum_point(
+            attributes.clone(),
+            stats.read_bytes.load(Ordering::Relaxed),
+            start,
+            now,
+        ));
+        written_bytes.push(sum_point(
+            attributes,
+            stats.written_bytes.load(Ordering::Relaxed),
+            start,
+            now,
+        ));
+        for error_count in fused.client.error_counts.iter() {
+            let (error_volume, operation) = error_count.key();
+            if error_volume == volume {
+                let attributes = key_values(&[
+                    ("mount_point", path),
+                    ("volume", volume),
+                    ("operation", operation),
+                ]);
+                errors.push(sum_point(attributes, *error_count.value(), start, now));
+            }
+        }
+    }
+    let mut cache_hits = vec![];
+    for (cache, (hits, misses)) in [
+        ("negative", fused.client.negative_cache.hit_stats()),
+        ("owner", fused.client.owner_cache.hit_stats()),
+    ] {
+        for (result, value) in [("hit", hits), ("miss", misses)] {
+            let attributes = key_values(&[("cache", cache), ("result", result)]);
+            cache_hits.push(sum_point(attributes, value, start, now));
+        }
+    }
+    let server_requests = fused
+        .client
+        .client
+        .connection_stats()
+        .into_iter()
+        .map(|connection| {
+            let attributes = key_values(&[("server", &connection.server_address)]);
+            sum_point(attributes, connection.requests, start, now)
+        })
+        .collect();
+
+    let metrics = vec![
+        sum_metric(
+            "sealfs.client.operations",
+            "{operation}",
+            "FUSE operations of the mount point",
+            operations,
+        ),
+        json!({
+            "name": "sealfs.client.operation.duration",
+            "unit": "us",
+            "description": "latency of the FUSE operations of the mount point",
+            "histogram": {"aggregationTemporality": 2, "dataPoints": latencies},
+        }),
+        sum_metric(
+            "sealfs.client.read",
+            "By",
+            "bytes requested by the reads of the mount point",
+            read_bytes,
+        ),
+        sum_metric(
+            "sealfs.client.written",
+            "By",
+            "bytes written to the mount point",
+            written_bytes,
+        ),
+        sum_metric(
+            "sealfs.client.errors",
+            "{error}",
+            "errors replied for the volume of the mount point",
+            errors,
+        ),
+        sum_metric(
+            "sealfs.client.cache.lookups",
+            "{lookup}",
+            "lookups of the caches of the daemon",
+            cache_hits,
+        ),
+        sum_metric(
+            "sealfs.client.server.requests",
+            "{request}",
+            "requests sent to the servers",
+            server_requests,
+        ),
+    ];
+    let resource = key_values(&[
+        ("service.name", "sealfs-client"),
+        ("host.name", &hostname()),
+    ]);
+    json!({
+        "resourceMetrics": [{
+            "resource": {"attributes": resource},
+            "scopeMetrics": [{
+                "scope": {"name": "sealfs", "version": env!("CARGO_PKG_VERSION")},
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+// OtlpExporter posts the export requests to a collector.
+pub struct OtlpExporter {
+    // host:port of the collector.
+    address: String,
+    path: String,
+}
+
+impl OtlpExporter {
+    // new takes the endpoint of the collector, http://host:port with an
+    // optional path, /v1/metrics by default.
+    pub fn new(endpoint: &str) -> Result<Self, String> {
+        let rest = endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("endpoint {} is not http://", endpoint))?;
+        let (address, path) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => (&rest[..i], &rest[i..]),
+            Some(i) => (&rest[..i], "/v1/metrics"),
+            None => (rest, "/v1/metrics"),
+        };
+        if address.is_empty() {
+            return Err(format!("endpoint {} has no host", endpoint));
+        }
+        Ok(Self {
+            address: address.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+
+    pub async fn export(&self, request: &Value) -> Result<(), String> {
+        let body = request.to_string();
+        let message = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.address,
+            body.len(),
+            body
+        );
+        let post = async {
+            let mut stream = TcpStream::connect(&self.address)
+                .await
+                .map_err(|e| format!("connect to {} failed: {}", self.address, e))?;
+            stream
+                .write_all(message.as_bytes())
+                .await
+                .map_err(|e| format!("send to {} failed: {}", self.address, e))?;
+            let mut response = Vec::new();
+            stream
+                .read_to_end(&mut response)
+                .await
+                .map_err(|e| format!("receive from {} failed: {}", self.address, e))?;
+            Ok::<_, String>(response)
+        };
+        let response = tokio::time::timeout(EXPORT_TIMEOUT, post)
+            .await
+            .map_err(|_| format!("export to {} timed out", self.address))??;
+        let status_line = String::from_utf8_lossy(&response)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        match status_line.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(format!(
+                "export to {} refused: {}",
+                self.address, status_line
+            )),
+        }
+    }
+}
+
+// export_loop pushes the metrics of the daemon to the collector every
+// interval, the failed exports are only logged.
+pub async fn export_loop(fused: Arc<SealfsFused>, exporter: OtlpExporter, interval: Duration) {
+    info!(
+        "export the metrics to {}{} every {:?}",
+        exporter.address, exporter.path, interval
+    );
+    let start = SystemTime::now();
+    loop {
+        tokio::time::sleep(interval).await;
+        let request = metrics_request(&fused, start);
+        match exporter.export(&request).await {
+            Ok(()) => debug!("exported the metrics to {}", exporter.address),
+            Err(e) => error!("export the metrics failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{LatencyHistogram, OtlpExporter};
+
+    #[tokio::test]
+    async fn telemetry_test() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(50));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(2));
+        let point = histogram.data_point(json!([]), 1, 2);
+        assert_eq!(point["count"], "3");
+        assert_eq!(point["bucketCounts"][0], "1");
+        assert_eq!(point["bucketCounts"][5], "1");
+        assert_eq!(point["bucketCounts"][12], "1");
+
+        assert!(OtlpExporter::new("https://collector:4318").is_err());
+        let exporter = OtlpExporter::new("http://collector:4318").unwrap();
+        assert_eq!(exporter.path, "/v1/metrics");
+
+        let listener = TcpListener::bind("127.0.0.1:18397").await.unwrap();
+        let collector = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in ["200 OK", "400 Bad Request"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0u8; 4096];
+                let length = stream.read(&mut request).await.unwrap();
+                request.truncate(length);
+                requests.push(String::from_utf8(request).unwrap());
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        let exporter = OtlpExporter::new("http://127.0.0.1:18397/").unwrap();
+        let request = json!({"resourceMetrics": []});
+        assert!(exporter.export(&request).await.is_ok());
+        assert!(exporter.export(&request).await.is_err());
+        let requests = collector.await.unwrap();
+        assert!(requests[0].starts_with("POST /v1/metrics HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{\"resourceMetrics\":[]}"));
+    }
+}
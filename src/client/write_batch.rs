@@ -0,0 +1,157 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Coalescing of the small writes of the mount points, off by default.
+ * A write smaller than the max size is replied at once and kept in the
+ * batch of its file, the writes right after it are appended, and the batch
+ * is sent as a single WriteFile once full, when a write does not follow it,
+ * after the interval, or before a request reading or changing the file.
+ * The error of a batch sent in the background is replied to the next write,
+ * flush or fsync of the file, as after a close with the writeback cache.
+ */
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::sync::Mutex;
+
+pub const DEFAULT_WRITE_BATCH_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct Batch {
+    pub offset: i64,
+    pub data: Vec<u8>,
+    time: Instant,
+}
+
+impl Batch {
+    pub fn new(offset: i64, data: Vec<u8>) -> Self {
+        Self {
+            offset,
+            data,
+            time: Instant::now(),
+        }
+    }
+}
+
+pub struct WriteBatch {
+    // bytes of a batch at most, the larger writes are sent at once.
+    pub max_size: usize,
+    // time a batch waits for the next writes.
+    pub interval: Duration,
+    // path -> its batch, locked while it is sent.
+    files: DashMap<String, Arc<Mutex<Option<Batch>>>>,
+    // path -> the error of its last batch sent in the background.
+    errors: DashMap<String, i32>,
+}
+
+impl WriteBatch {
+    pub fn new(max_size: usize, interval: Duration) -> Self {
+        Self {
+            max_size,
+            interval,
+            files: DashMap::new(),
+            errors: DashMap::new(),
+        }
+    }
+
+    pub fn file(&self, path: &str) -> Arc<Mutex<Option<Batch>>> {
+        self.files.entry(path.to_owned()).or_default().clone()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.files.iter().map(|file| file.key().clone()).collect()
+    }
+
+    // get returns the batch of path, if it has one.
+    pub fn get(&self, path: &str) -> Option<Arc<Mutex<Option<Batch>>>> {
+        self.files.get(path).map(|file| file.clone())
+    }
+
+    // append adds data at offset to batch if it follows it and fits, and
+    // returns whether it did.
+    pub fn append(&self, batch: &mut Option<Batch>, offset: i64, data: &[u8]) -> bool {
+        match batch {
+            Some(batch)
+                if batch.offset + batch.data.len() as i64 == offset
+                    && batch.data.len() + data.len() <= self.max_size =>
+            {
+                batch.data.extend_from_slice(data);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_error(&self, path: &str, status: i32) {
+        self.errors.insert(path.to_owned(), status);
+    }
+
+    pub fn take_error(&self, path: &str) -> Option<i32> {
+        self.errors.remove(path).map(|(_, status)| status)
+    }
+
+    // due returns the paths whose batch waited for the interval, and forgets
+    // the files left without a batch.
+    pub fn due(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+        self.files.retain(|path, file| {
+            // in use by a request.
+            if Arc::strong_count(file) > 1 {
+                return true;
+            }
+            match file.try_lock() {
+                Ok(batch) => match batch.as_ref() {
+                    Some(batch) => {
+                        if batch.time.elapsed() >= self.interval {
+                            paths.push(path.clone());
+                        }
+                        true
+                    }
+                    None => false,
+                },
+                Err(_) => true,
+            }
+        });
+        paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Batch, WriteBatch};
+
+    #[tokio::test]
+    async fn write_batch_test() {
+        let write_batch = WriteBatch::new(8, Duration::ZERO);
+        let file = write_batch.file("v/a");
+        {
+            let mut batch = file.lock().await;
+            assert!(!write_batch.append(&mut batch, 0, b"ab"));
+            *batch = Some(Batch::new(0, b"ab".to_vec()));
+            assert!(write_batch.append(&mut batch, 2, b"cd"));
+            // not right after the batch, or too large.
+            assert!(!write_batch.append(&mut batch, 5, b"e"));
+            assert!(!write_batch.append(&mut batch, 4, b"efghi"));
+            assert_eq!(batch.as_ref().unwrap().data, b"abcd");
+        }
+        // the files in use are kept.
+        assert!(write_batch.due().is_empty());
+        drop(file);
+        assert_eq!(write_batch.due(), ["v/a"]);
+
+        // the files without a batch are forgotten.
+        write_batch.get("v/a").unwrap().lock().await.take();
+        assert!(write_batch.due().is_empty());
+        assert!(write_batch.get("v/a").is_none());
+
+        write_batch.set_error("v/a", libc::ENOSPC);
+        assert_eq!(write_batch.take_error("v/a"), Some(libc::ENOSPC));
+        assert_eq!(write_batch.take_error("v/a"), None);
+    }
+}
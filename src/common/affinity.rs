@@ -0,0 +1,108 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use log::error;
+
+// MPOL_PREFERRED from linux/mempolicy.h
+const MPOL_PREFERRED: i32 = 1;
+
+// parse_cpu_list parses a cpu list like "0-3,8,10-11", the format used by
+// taskset and /sys/devices/system/node/node*/cpulist. The cpus must be below
+// CPU_SETSIZE to be pinned to.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, i32> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (begin, end) = match range.split_once('-') {
+            Some((begin, end)) => (begin.trim().parse::<usize>(), end.trim().parse()),
+            None => (range.trim().parse::<usize>(), range.trim().parse()),
+        };
+        match (begin, end) {
+            (Ok(_), Ok(end)) if end >= libc::CPU_SETSIZE as usize => {
+                error!(
+                    "cpu {} out of range, cpus go up to {}",
+                    end,
+                    libc::CPU_SETSIZE - 1
+                );
+                return Err(libc::EINVAL);
+            }
+            (Ok(begin), Ok(end)) if begin <= end => cpus.extend(begin..=end),
+            _ => {
+                error!("invalid cpu list: {}", list);
+                return Err(libc::EINVAL);
+            }
+        }
+    }
+    if cpus.is_empty() {
+        error!("empty cpu list");
+        return Err(libc::EINVAL);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+// numa_node_cpus returns the cpus of a NUMA node.
+pub fn numa_node_cpus(node: usize) -> Result<Vec<usize>, i32> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    match std::fs::read_to_string(&path) {
+        Ok(list) => parse_cpu_list(&list),
+        Err(e) => {
+            error!("read {} error: {}", path, e);
+            Err(libc::ENOENT)
+        }
+    }
+}
+
+// pin_current_thread restricts the current thread to the given cpus.
+pub fn pin_current_thread(cpus: &[usize]) -> Result<(), i32> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(libc::EINVAL);
+        }
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    let result =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if result < 0 {
+        return Err(nix::errno::errno());
+    }
+    Ok(())
+}
+
+// prefer_numa_node makes the memory allocated by the current thread come
+// from the given NUMA node when possible.
+pub fn prefer_numa_node(node: usize) -> Result<(), i32> {
+    let mut mask = vec![0u64; node / 64 + 1];
+    mask[node / 64] |= 1 << (node % 64);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            MPOL_PREFERRED,
+            mask.as_ptr(),
+            mask.len() * 64 + 1,
+        )
+    };
+    if result < 0 {
+        return Err(nix::errno::errno());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cpu_list;
+
+    #[test]
+    fn parse_cpu_list_test() {
+        assert_eq!(parse_cpu_list("0-3,8\n"), Ok(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list("5,1-2,2"), Ok(vec![1, 2, 5]));
+        assert_eq!(parse_cpu_list("3-1"), Err(libc::EINVAL));
+        assert_eq!(parse_cpu_list("a"), Err(libc::EINVAL));
+        assert_eq!(parse_cpu_list(""), Err(libc::EINVAL));
+        assert_eq!(parse_cpu_list("1020-1023").unwrap().len(), 4);
+        assert_eq!(parse_cpu_list("1024"), Err(libc::EINVAL));
+        assert_eq!(parse_cpu_list("0-4096"), Err(libc::EINVAL));
+    }
+}
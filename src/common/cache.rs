@@ -3,8 +3,11 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use dashmap::DashMap;
+use fuser::FileAttr;
 use parking_lot::Mutex;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{marker::PhantomData, mem, ptr::NonNull};
 
 #[derive(Copy, Clone)]
@@ -338,6 +341,190 @@ where
     }
 }
 
+// NegativeCache remembers for a short ttl the paths found missing, so the
+// lookups of the same missing paths, as build tools do searching include
+// paths, do not go to the servers. A create in a directory drops the
+// missing names of that directory.
+pub struct NegativeCache {
+    ttl: Duration,
+    capacity: usize,
+    len: AtomicUsize,
+    // directory -> its missing names and when they were found missing.
+    dirs: DashMap<String, HashMap<String, Instant>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NegativeCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            len: AtomicUsize::new(0),
+            dirs: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn insert(&self, path: &str) {
+        let (dir, name) = match path.rsplit_once('/') {
+            Some(split) => split,
+            None => return,
+        };
+        // expired entries are only removed when looked up, start over when full.
+        if self.len.load(Ordering::Relaxed) >= self.capacity {
+            self.dirs.clear();
+            self.len.store(0, Ordering::Relaxed);
+        }
+        if self
+            .dirs
+            .entry(dir.to_owned())
+            .or_default()
+            .insert(name.to_owned(), Instant::now())
+            .is_none()
+        {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        let found = self.lookup(path);
+        match found {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        found
+    }
+
+    // hit_stats returns the (hits, misses) of contains.
+    pub fn hit_stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn lookup(&self, path: &str) -> bool {
+        let (dir, name) = match path.rsplit_once('/') {
+            Some(split) => split,
+            None => return false,
+        };
+        let mut names = match self.dirs.get_mut(dir) {
+            Some(names) => names,
+            None => return false,
+        };
+        match names.get(name) {
+            Some(time) if time.elapsed() < self.ttl => true,
+            Some(_) => {
+                names.remove(name);
+                self.len.fetch_sub(1, Ordering::Relaxed);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // invalidate_dir drops the missing names of dir, called when a file or a
+    // directory is created in it.
+    pub fn invalidate_dir(&self, dir: &str) {
+        if let Some((_, names)) = self.dirs.remove(dir) {
+            self.len.fetch_sub(names.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+// AttrCache keeps for a short ttl the attrs got ahead of their lookups, as
+// a readdir does for the lookups that follow it, e.g. of `ls -l`. An attr is
+// used by one lookup only, and dropped when the file is changed through
+// this client.
+pub struct AttrCache {
+    ttl: Duration,
+    capacity: usize,
+    attrs: DashMap<String, (FileAttr, Instant)>,
+}
+
+impl AttrCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            attrs: DashMap::new(),
+        }
+    }
+
+    pub fn insert(&self, path: &str, attr: FileAttr) {
+        // expired attrs are only removed when taken, start over when full.
+        if self.attrs.len() >= self.capacity {
+            self.attrs.clear();
+        }
+        self.attrs.insert(path.to_owned(), (attr, Instant::now()));
+    }
+
+    pub fn take(&self, path: &str) -> Option<FileAttr> {
+        match self.attrs.remove(path) {
+            Some((_, (attr, time))) if time.elapsed() < self.ttl => Some(attr),
+            _ => None,
+        }
+    }
+
+    pub fn invalidate(&self, path: &str) {
+        self.attrs.remove(path);
+    }
+}
+
+// OwnerCache keeps the owners of the paths placed on a hash ring, so the
+// clients do not place a path again for every request, which takes a pass
+// over all the servers with rendezvous hashing. An owner is only used with
+// the ring of the epoch it was placed on, the manager bumps the epoch for
+// every ring changing the owners.
+pub struct OwnerCache {
+    capacity: usize,
+    // path -> the epoch of the ring and the address of the owner.
+    owners: DashMap<String, (u64, String)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl OwnerCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            owners: DashMap::new(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // get returns the owner of path on the ring of epoch, placing it with
+    // place if it is not cached.
+    pub fn get(&self, epoch: u64, path: &str, place: impl FnOnce() -> String) -> String {
+        if let Some(owner) = self.owners.get(path) {
+            if owner.0 == epoch {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return owner.1.clone();
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let address = place();
+        // the owners of the older epochs are replaced, start over when full.
+        if self.owners.len() >= self.capacity {
+            self.owners.clear();
+        }
+        self.owners
+            .insert(path.to_owned(), (epoch, address.clone()));
+        address
+    }
+
+    // hit_stats returns the (hits, misses) of get.
+    pub fn hit_stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
 #[cfg(test)]
 mod test {
     mod test_linkedlist {
@@ -424,4 +611,87 @@ mod test {
             }
         }
     }
+
+    mod test_attr_cache {
+        use std::time::Duration;
+
+        use super::super::AttrCache;
+        use crate::common::util::empty_file;
+
+        #[test]
+        fn test() {
+            let cache = AttrCache::new(Duration::from_secs(60), 2);
+            cache.insert("v/a", empty_file());
+            cache.insert("v/b", empty_file());
+            // used once.
+            assert!(cache.take("v/a").is_some());
+            assert!(cache.take("v/a").is_none());
+            cache.invalidate("v/b");
+            assert!(cache.take("v/b").is_none());
+
+            // full, so it starts over.
+            cache.insert("v/a", empty_file());
+            cache.insert("v/b", empty_file());
+            cache.insert("v/c", empty_file());
+            assert!(cache.take("v/a").is_none());
+            assert!(cache.take("v/c").is_some());
+
+            let cache = AttrCache::new(Duration::ZERO, 2);
+            cache.insert("v/a", empty_file());
+            assert!(cache.take("v/a").is_none());
+        }
+    }
+
+    mod test_negative_cache {
+        use std::time::Duration;
+
+        use super::super::NegativeCache;
+
+        #[test]
+        fn test() {
+            let cache = NegativeCache::new(Duration::from_secs(60), 3);
+            cache.insert("v/include/a.h");
+            cache.insert("v/include/b.h");
+            cache.insert("v/src/a.h");
+            assert!(cache.contains("v/include/a.h"));
+            assert!(!cache.contains("v/include/c.h"));
+            assert!(!cache.contains("v/lib/a.h"));
+
+            cache.invalidate_dir("v/include");
+            assert!(!cache.contains("v/include/a.h"));
+            assert!(cache.contains("v/src/a.h"));
+
+            // full, so it starts over.
+            cache.insert("v/include/a.h");
+            cache.insert("v/include/b.h");
+            cache.insert("v/include/c.h");
+            assert!(!cache.contains("v/src/a.h"));
+            assert!(cache.contains("v/include/c.h"));
+
+            let cache = NegativeCache::new(Duration::ZERO, 3);
+            cache.insert("v/include/a.h");
+            assert!(!cache.contains("v/include/a.h"));
+            assert_eq!(cache.hit_stats(), (0, 1));
+        }
+    }
+
+    mod test_owner_cache {
+        use super::super::OwnerCache;
+
+        #[test]
+        fn test() {
+            let cache = OwnerCache::new(2);
+            assert_eq!(cache.get(1, "v/a", || "s1".to_owned()), "s1");
+            assert_eq!(cache.get(1, "v/a", || unreachable!()), "s1");
+            // placed again on the ring of a new epoch.
+            assert_eq!(cache.get(2, "v/a", || "s2".to_owned()), "s2");
+            assert_eq!(cache.get(2, "v/a", || unreachable!()), "s2");
+            assert_eq!(cache.hit_stats(), (2, 2));
+
+            // full, so it starts over.
+            cache.get(2, "v/b", || "s1".to_owned());
+            cache.get(2, "v/c", || "s1".to_owned());
+            assert_eq!(cache.get(2, "v/a", || "s3".to_owned()), "s3");
+        }
+    }
 }
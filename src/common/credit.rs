@@ -0,0 +1,142 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Credits limit the metadata requests a server sends to another server at
+ * once, so a create storm in one hot directory cannot overload the server
+ * owning it or the servers of its files.
+ * Every destination has DESTINATION_CREDITS, given in FIFO order. The
+ * requests of one directory take at most DIRECTORY_CREDITS of them and wait
+ * on their directory before, so the requests of the other directories are
+ * not queued behind a hot one.
+ */
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DESTINATION_CREDITS: usize = 128;
+pub const DIRECTORY_CREDITS: usize = 16;
+
+pub struct Credits {
+    destination_credits: usize,
+    directory_credits: usize,
+    destinations: DashMap<String, Arc<Semaphore>>,
+    // removed when their last credit is returned.
+    directories: DashMap<(String, String), Arc<Semaphore>>,
+    // requests which had to wait for a credit.
+    waits: AtomicU64,
+}
+
+impl Default for Credits {
+    fn default() -> Self {
+        Self::new(DESTINATION_CREDITS, DIRECTORY_CREDITS)
+    }
+}
+
+impl Credits {
+    pub fn new(destination_credits: usize, directory_credits: usize) -> Self {
+        Self {
+            destination_credits,
+            directory_credits,
+            destinations: DashMap::new(),
+            directories: DashMap::new(),
+            waits: AtomicU64::new(0),
+        }
+    }
+
+    // acquire waits for a credit of the directory and of the destination, the
+    // credit is returned when it is dropped.
+    pub async fn acquire(&self, destination: &str, directory: &str) -> Credit<'_> {
+        // declared first to be dropped last if the wait is cancelled.
+        let mut credit = Credit {
+            credits: self,
+            key: (destination.to_owned(), directory.to_owned()),
+            permits: None,
+        };
+        let directory_semaphore = self
+            .directories
+            .entry(credit.key.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.directory_credits)))
+            .clone();
+        let destination_semaphore = self
+            .destinations
+            .entry(destination.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.destination_credits)))
+            .clone();
+        let directory_permit = match directory_semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.waits.fetch_add(1, Ordering::Relaxed);
+                directory_semaphore.acquire_owned().await.unwrap()
+            }
+        };
+        let destination_permit = match destination_semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                self.waits.fetch_add(1, Ordering::Relaxed);
+                destination_semaphore.acquire_owned().await.unwrap()
+            }
+        };
+        credit.permits = Some((directory_permit, destination_permit));
+        credit
+    }
+
+    pub fn waits(&self) -> u64 {
+        self.waits.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Credit<'a> {
+    credits: &'a Credits,
+    key: (String, String),
+    permits: Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)>,
+}
+
+impl Drop for Credit<'_> {
+    fn drop(&mut self) {
+        self.permits.take();
+        // the map holds the last reference when no request uses the directory.
+        self.credits
+            .directories
+            .remove_if(&self.key, |_, semaphore| Arc::strong_count(semaphore) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::Credits;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn credit_test() {
+        let credits = Credits::new(2, 1);
+        let wait = Duration::from_millis(50);
+
+        let hot = credits.acquire("server1", "/hot").await;
+        // the hot directory has no credit left, the others still have.
+        assert!(timeout(wait, credits.acquire("server1", "/hot"))
+            .await
+            .is_err());
+        let cold = credits.acquire("server1", "/cold").await;
+        let other = credits.acquire("server2", "/hot").await;
+        // server1 has no credit left.
+        assert!(timeout(wait, credits.acquire("server1", "/other"))
+            .await
+            .is_err());
+        assert_eq!(credits.waits(), 2);
+
+        drop(hot);
+        let _hot = credits.acquire("server1", "/hot").await;
+        drop(cold);
+        drop(other);
+        assert_eq!(credits.directories.len(), 1);
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Discovery of the managers and the servers through DNS. An address is
+ * either `host:port`, the host being resolved by the system each time a
+ * connection is made, or `srv:<name>`, e.g.
+ * `srv:_sealfs._tcp.sealfs-server.default.svc.cluster.local` for the pods of
+ * a Kubernetes headless service, which stands for the `target:port` of the
+ * SRV records of name, by priority. The SRV records are asked to the
+ * nameservers of /etc/resolv.conf over UDP, a truncated answer gives the
+ * records it holds. They are resolved again when the clients fail over to
+ * another manager, and every DISCOVERY_INTERVAL by the manager for the
+ * servers to add to the cluster.
+ */
+use std::{net::SocketAddr, time::Duration};
+
+use log::{error, warn};
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::util::join_address;
+
+pub const SRV_PREFIX: &str = "srv:";
+pub const DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+impl SrvRecord {
+    pub fn address(&self) -> String {
+        join_address(&self.target, self.port)
+    }
+}
+
+fn nameservers() -> Vec<SocketAddr> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    let mut servers: Vec<SocketAddr> = resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+    if servers.is_empty() {
+        servers.push(([127, 0, 0, 1], 53).into());
+    }
+    servers
+}
+
+fn build_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16, String> {
+    packet
+        .get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| "short dns answer".to_owned())
+}
+
+// read_name returns the name at offset, following the compression pointers,
+// and the offset after it.
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(String, usize), String> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..128 {
+        let length = *packet.get(offset).ok_or("short dns answer")? as usize;
+        match length {
+            0 => {
+                let name = labels.join(".");
+                return Ok((name, end.unwrap_or(offset + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                end.get_or_insert(offset + 2);
+                offset = (read_u16(packet, offset)? & 0x3fff) as usize;
+            }
+            l => {
+                let label = packet
+                    .get(offset + 1..offset + 1 + l)
+                    .ok_or("short dns answer")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + l;
+            }
+        }
+    }
+    Err("dns name loop".to_owned())
+}
+
+fn parse_response(id: u16, packet: &[u8]) -> Result<Vec<SrvRecord>, String> {
+    if read_u16(packet, 0)? != id {
+        return Err("dns answer to another query".to_owned());
+    }
+    match packet[3] & 0x0f {
+        0 => {}
+        3 => return Err("no such name".to_owned()),
+        rcode => return Err(format!("dns error {}", rcode)),
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        offset = read_name(packet, offset)?.1;
+        let r#type = read_u16(packet, offset)?;
+        let length = read_u16(packet, offset + 8)? as usize;
+        let data = offset + 10;
+        if r#type == TYPE_SRV {
+            records.push(SrvRecord {
+                priority: read_u16(packet, data)?,
+                weight: read_u16(packet, data + 2)?,
+                port: read_u16(packet, data + 4)?,
+                target: read_name(packet, data + 6)?.0,
+            });
+        }
+        offset = data + length;
+    }
+    // the lowest priority first, then the heaviest.
+    records.sort_by(|a, b| (a.priority, b.weight).cmp(&(b.priority, a.weight)));
+    Ok(records)
+}
+
+pub async fn lookup_srv(name: &str) -> Result<Vec<SrvRecord>, String> {
+    let id = rand::random::<u16>();
+    let query = build_query(id, name);
+    let mut last_error = "no nameserver".to_owned();
+    for nameserver in nameservers() {
+        let bind: SocketAddr = match nameserver {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let result = async {
+            let socket = UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+            socket
+                .send_to(&query, nameserver)
+                .await
+                .map_err(|e| e.to_string())?;
+            let mut packet = vec![0u8; 65535];
+            let length = timeout(DNS_TIMEOUT, socket.recv(&mut packet))
+                .await
+                .map_err(|_| "timeout".to_owned())?
+                .map_err(|e| e.to_string())?;
+            parse_response(id, &packet[..length])
+        }
+        .await;
+        match result {
+            Ok(records) => return Ok(records),
+            Err(e) => {
+                warn!("srv lookup of {} at {} failed: {}", name, nameserver, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+// resolve_addresses replaces the `srv:<name>` addresses by the addresses of
+// their records, and keeps the others.
+pub async fn resolve_addresses(addresses: Vec<String>) -> Vec<String> {
+    let mut resolved = Vec::new();
+    for address in addresses {
+        match address.strip_prefix(SRV_PREFIX) {
+            Some(name) => match lookup_srv(name).await {
+                Ok(records) => resolved.extend(records.iter().map(SrvRecord::address)),
+                Err(e) => error!("resolve {} failed: {}", address, e),
+            },
+            None => resolved.push(address),
+        }
+    }
+    resolved.dedup();
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_query, parse_response, SrvRecord};
+
+    #[test]
+    fn srv_response_test() {
+        let name = "_sealfs._tcp.fs.local";
+        let mut packet = build_query(7, name);
+        // a response with two answers.
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[7] = 2;
+        for (priority, weight, port, target) in [(20, 0, 8086, "s2"), (10, 5, 8085, "s1")] {
+            // the name is a pointer to the question.
+            packet.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+            let mut data = Vec::new();
+            for value in [priority, weight, port] {
+                data.extend_from_slice(&u16::to_be_bytes(value));
+            }
+            data.push(target.len() as u8);
+            data.extend_from_slice(target.as_bytes());
+            // the target ends with the domain of the question.
+            data.extend_from_slice(&[0xc0, 25]);
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&data);
+        }
+        let records = parse_response(7, &packet).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                SrvRecord {
+                    priority: 10,
+                    weight: 5,
+                    port: 8085,
+                    target: "s1.fs.local".to_owned(),
+                },
+                SrvRecord {
+                    priority: 20,
+                    weight: 0,
+                    port: 8086,
+                    target: "s2.fs.local".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(records[0].address(), "s1.fs.local:8085");
+        assert!(parse_response(8, &packet).is_err());
+        packet[3] = 0x83;
+        assert_eq!(parse_response(7, &packet), Err("no such name".to_owned()));
+    }
+}
@@ -10,6 +10,25 @@ pub const CONNECTION_ERROR: i32 = 10001;
 pub const INVALID_CLUSTER_STATUS: i32 = 10002;
 pub const DATABASE_ERROR: i32 = 10003;
 pub const SERIALIZATION_ERROR: i32 = 10004;
+// a write was routed with a hash ring older than the one of the server.
+pub const STALE_EPOCH: i32 = 10005;
+// the file changed since the version a conditional write expected.
+pub const VERSION_MISMATCH: i32 = 10006;
+// another server with the same address is a member, with another identity.
+pub const SERVER_ID_MISMATCH: i32 = 10007;
+
+// status_to_errno maps a status to the errno replied to the users, the
+// statuses of sealfs are not errnos.
+pub fn status_to_errno(status: i32) -> i32 {
+    match status {
+        // a server is down or the cluster is rebalancing, retrying may succeed.
+        CONNECTION_ERROR | INVALID_CLUSTER_STATUS | STALE_EPOCH => libc::EAGAIN,
+        DATABASE_ERROR | SERIALIZATION_ERROR => libc::EIO,
+        VERSION_MISMATCH => libc::ESTALE,
+        status if status > 0 && status < 4096 => status,
+        _ => libc::EIO,
+    }
+}
 
 pub fn status_to_string(status: i32) -> String {
     match status {
@@ -17,6 +36,9 @@ pub fn status_to_string(status: i32) -> String {
         INVALID_CLUSTER_STATUS => "INVALID_CLUSTER_STATUS".to_string(),
         DATABASE_ERROR => "DATABASE_ERROR".to_string(),
         SERIALIZATION_ERROR => "SERIALIZATION_ERROR".to_string(),
+        STALE_EPOCH => "STALE_EPOCH".to_string(),
+        VERSION_MISMATCH => "VERSION_MISMATCH".to_string(),
+        SERVER_ID_MISMATCH => "SERVER_ID_MISMATCH".to_string(),
         _ => unsafe { CStr::from_ptr(strerror(status)) }
             .to_str()
             .unwrap()
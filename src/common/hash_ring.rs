@@ -2,9 +2,16 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    str::FromStr,
+};
 
 use conhash::{ConsistentHash, Node};
+use serde::{Deserialize, Serialize};
+
+use super::{serialization::ClusterStatus, util::SHARD_SEPARATOR};
 
 #[derive(Clone)]
 pub struct ServerNode {
@@ -17,58 +24,450 @@ impl Node for ServerNode {
     }
 }
 
+// HashAlgorithm places the paths on the servers. It is chosen when the
+// cluster is initialized and served by the manager with the hash ring, so
+// all the nodes agree on it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    // md5 consistent hash ring, with weight virtual nodes per server.
+    #[default]
+    Conhash,
+    // wyhash consistent hash ring, faster and less skewed for short paths.
+    Wyhash,
+    // rendezvous (highest random weight) hashing, no virtual nodes needed.
+    Rendezvous,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "conhash" => Ok(HashAlgorithm::Conhash),
+            "wyhash" => Ok(HashAlgorithm::Wyhash),
+            "rendezvous" => Ok(HashAlgorithm::Rendezvous),
+            _ => Err(format!(
+                "unknown hash algorithm {}, expected conhash, wyhash or rendezvous",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Conhash => write!(f, "conhash"),
+            HashAlgorithm::Wyhash => write!(f, "wyhash"),
+            HashAlgorithm::Rendezvous => write!(f, "rendezvous"),
+        }
+    }
+}
+
+// ServerGroups restricts volumes to named groups of servers, e.g. the
+// servers of a rack: the paths of a volume pinned to a group are placed on
+// its servers only.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct ServerGroups {
+    // the group of the servers in one.
+    pub servers: BTreeMap<String, String>,
+    // the group of the pinned volumes.
+    pub volumes: BTreeMap<String, String>,
+    // the failure domain of the servers with one.
+    pub domains: BTreeMap<String, FailureDomain>,
+}
+
+// FailureDomain is where a server may fail together with others. A server
+// without a rack is alone in its own, and a server without a zone is in the
+// zone of its rack.
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct FailureDomain {
+    pub zone: String,
+    pub rack: String,
+}
+
+impl ServerGroups {
+    // members returns the servers of group among servers.
+    pub fn members(&self, group: &str, servers: &HashMap<String, usize>) -> HashMap<String, usize> {
+        servers
+            .iter()
+            .filter(|(server, _)| self.servers.get(*server).is_some_and(|g| g == group))
+            .map(|(server, weight)| (server.clone(), *weight))
+            .collect()
+    }
+
+    // zone_and_rack returns the keys of the zone and of the rack of server.
+    fn zone_and_rack(&self, server: &str) -> (String, String) {
+        let domain = self.domains.get(server).cloned().unwrap_or_default();
+        let rack = match domain.rack.as_str() {
+            "" => format!("server/{}", server),
+            rack => format!("zone/{}/rack/{}", domain.zone, rack),
+        };
+        let zone = match domain.zone.as_str() {
+            "" => rack.clone(),
+            zone => format!("zone/{}", zone),
+        };
+        (zone, rack)
+    }
+}
+
+// HashRingInfo is the hash ring served by the manager.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HashRingInfo {
+    pub algorithm: HashAlgorithm,
+    // the servers with their weights.
+    pub servers: Vec<(String, usize)>,
+    pub groups: ServerGroups,
+    pub epoch: u64,
+}
+
+// HashRingSnapshot is the cluster status of the manager with the hash rings
+// it routes with, taken together, so the epoch of a ring always matches the
+// status it is used in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HashRingSnapshot {
+    pub status: ClusterStatus,
+    pub hash_ring: HashRingInfo,
+    // the hash ring of the rebalance running, if any.
+    pub new_hash_ring: Option<HashRingInfo>,
+}
+
+impl HashRingSnapshot {
+    // routing returns the hash ring the clients route with in status.
+    pub fn routing(&self) -> &HashRingInfo {
+        match (self.status, &self.new_hash_ring) {
+            (ClusterStatus::PreFinish, Some(new_hash_ring)) => new_hash_ring,
+            _ => &self.hash_ring,
+        }
+    }
+}
+
+enum Placement {
+    Conhash(ConsistentHash<ServerNode>),
+    Wyhash(BTreeMap<u64, ServerNode>),
+    // servers sorted by address with their weights.
+    Rendezvous(Vec<(ServerNode, usize)>),
+}
+
+impl Placement {
+    fn new(algorithm: HashAlgorithm, servers: &HashMap<String, usize>) -> Self {
+        match algorithm {
+            HashAlgorithm::Conhash => {
+                let mut ring = ConsistentHash::<ServerNode>::new();
+                for (server, weight) in servers.iter() {
+                    ring.add(
+                        &ServerNode {
+                            address: server.clone(),
+                        },
+                        *weight,
+                    );
+                }
+                Placement::Conhash(ring)
+            }
+            HashAlgorithm::Wyhash => {
+                let mut ring = BTreeMap::new();
+                for (server, weight) in servers.iter() {
+                    for i in 0..*weight {
+                        ring.insert(
+                            wyhash::wyhash(format!("{}-{}", server, i).as_bytes(), 0),
+                            ServerNode {
+                                address: server.clone(),
+                            },
+                        );
+                    }
+                }
+                Placement::Wyhash(ring)
+            }
+            HashAlgorithm::Rendezvous => {
+                let mut nodes: Vec<(ServerNode, usize)> = servers
+                    .iter()
+                    .map(|(server, weight)| {
+                        (
+                            ServerNode {
+                                address: server.clone(),
+                            },
+                            *weight,
+                        )
+                    })
+                    .collect();
+                nodes.sort_by(|a, b| a.0.address.cmp(&b.0.address));
+                Placement::Rendezvous(nodes)
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&ServerNode> {
+        match self {
+            Placement::Conhash(ring) => ring.get_str(key),
+            Placement::Wyhash(ring) => {
+                let hash = wyhash::wyhash(key.as_bytes(), 0);
+                ring.range(hash..)
+                    .next()
+                    .or_else(|| ring.iter().next())
+                    .map(|(_, node)| node)
+            }
+            Placement::Rendezvous(nodes) => {
+                let mut best: Option<(&ServerNode, f64)> = None;
+                for (node, weight) in nodes {
+                    let score = rendezvous_score(key, &node.address, *weight);
+                    match best {
+                        Some((_, best_score)) if best_score >= score => {}
+                        _ => best = Some((node, score)),
+                    }
+                }
+                best.map(|(node, _)| node)
+            }
+        }
+    }
+}
+
+// rendezvous_score is the weighted score of key on server: weight / -ln(u)
+// with u uniform in (0, 1), so a server gets a share of the keys
+// proportional to its weight.
+fn rendezvous_score(key: &str, server: &str, weight: usize) -> f64 {
+    let hash = wyhash::wyhash(key.as_bytes(), wyhash::wyhash(server.as_bytes(), 0));
+    let u = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    weight as f64 / -u.ln()
+}
+
 pub struct HashRing {
-    pub ring: ConsistentHash<ServerNode>,
+    pub algorithm: HashAlgorithm,
+    placement: Placement,
     pub servers: HashMap<String, usize>,
+    pub groups: ServerGroups,
+    // the placements of the groups with servers in the ring.
+    group_placements: HashMap<String, Placement>,
+    // incremented by the manager for every ring changing the owners of
+    // paths, so the servers can fence the writes routed with an older one.
+    pub epoch: u64,
 }
 
 impl Clone for HashRing {
     fn clone(&self) -> Self {
-        let servers = self.servers.clone();
-        let mut ring = ConsistentHash::<ServerNode>::new();
-        for (server, weight) in servers.iter() {
-            ring.add(
-                &ServerNode {
-                    address: server.clone(),
-                },
-                *weight,
-            );
-        }
-        HashRing { ring, servers }
+        let mut ring = HashRing::with_groups(
+            self.algorithm,
+            self.servers.clone().into_iter().collect(),
+            self.groups.clone(),
+        );
+        ring.epoch = self.epoch;
+        ring
+    }
+}
+
+impl From<HashRingInfo> for HashRing {
+    fn from(info: HashRingInfo) -> Self {
+        let mut ring = HashRing::with_groups(info.algorithm, info.servers, info.groups);
+        ring.epoch = info.epoch;
+        ring
     }
 }
 
 impl HashRing {
-    pub fn new(servers: Vec<(String, usize)>) -> Self {
-        let mut ring = ConsistentHash::<ServerNode>::new();
-        let mut servers_map = HashMap::new();
-        for (server, weight) in servers {
-            ring.add(
-                &ServerNode {
-                    address: server.clone(),
-                },
-                weight,
-            );
-            servers_map.insert(server, weight);
+    pub fn new(algorithm: HashAlgorithm, servers: Vec<(String, usize)>) -> Self {
+        HashRing::with_groups(algorithm, servers, ServerGroups::default())
+    }
+
+    pub fn with_groups(
+        algorithm: HashAlgorithm,
+        servers: Vec<(String, usize)>,
+        groups: ServerGroups,
+    ) -> Self {
+        let servers: HashMap<String, usize> = servers.into_iter().collect();
+        let mut ring = HashRing {
+            algorithm,
+            placement: Placement::new(algorithm, &servers),
+            servers,
+            groups,
+            group_placements: HashMap::new(),
+            epoch: 0,
+        };
+        ring.place_groups();
+        ring
+    }
+
+    pub fn info(&self) -> HashRingInfo {
+        HashRingInfo {
+            algorithm: self.algorithm,
+            servers: self
+                .servers
+                .iter()
+                .map(|(server, weight)| (server.clone(), *weight))
+                .collect(),
+            groups: self.groups.clone(),
+            epoch: self.epoch,
         }
-        HashRing {
-            ring,
-            servers: servers_map,
+    }
+
+    fn place_groups(&mut self) {
+        self.group_placements.clear();
+        let groups: BTreeSet<&String> = self.groups.volumes.values().collect();
+        for group in groups {
+            let members = self.groups.members(group, &self.servers);
+            if !members.is_empty() {
+                self.group_placements
+                    .insert(group.clone(), Placement::new(self.algorithm, &members));
+            }
         }
     }
 
+    // get places key among the servers of the group of its volume if it is
+    // pinned to a group with servers, among all the servers otherwise.
     pub fn get(&self, key: &str) -> Option<&ServerNode> {
-        self.ring.get_str(key)
+        let volume = key
+            .trim_start_matches('/')
+            .split(['/', SHARD_SEPARATOR])
+            .next()
+            .unwrap_or_default();
+        match self
+            .groups
+            .volumes
+            .get(volume)
+            .and_then(|group| self.group_placements.get(group))
+        {
+            Some(placement) => placement.get(key),
+            None => self.placement.get(key),
+        }
+    }
+
+    // eligible_servers returns the servers key may be placed on.
+    fn eligible_servers(&self, key: &str) -> HashMap<String, usize> {
+        let volume = key
+            .trim_start_matches('/')
+            .split(['/', SHARD_SEPARATOR])
+            .next()
+            .unwrap_or_default();
+        match self.groups.volumes.get(volume) {
+            Some(group) if self.group_placements.contains_key(group) => {
+                self.groups.members(group, &self.servers)
+            }
+            _ => self.servers.clone(),
+        }
+    }
+
+    // replicas returns the servers of the count replicas of key, its owner
+    // first. The others are taken in their rendezvous order for key, in
+    // other zones than the chosen ones first, then in other racks, then on
+    // any other server, so fewer are returned only if there are fewer
+    // servers.
+    pub fn replicas(&self, key: &str, count: usize) -> Vec<String> {
+        let owner = match self.get(key) {
+            Some(owner) => owner.address.clone(),
+            None => return Vec::new(),
+        };
+        let mut candidates: Vec<(String, f64)> = self
+            .eligible_servers(key)
+            .into_iter()
+            .filter(|(server, _)| *server != owner)
+            .map(|(server, weight)| {
+                let score = rendezvous_score(key, &server, weight);
+                (server, score)
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let mut replicas = vec![owner];
+        for pass in 0..3 {
+            for (server, _) in &candidates {
+                if replicas.len() >= count {
+                    return replicas;
+                }
+                if replicas.contains(server) {
+                    continue;
+                }
+                let (zone, rack) = self.groups.zone_and_rack(server);
+                let taken = replicas.iter().any(|replica| {
+                    let (replica_zone, replica_rack) = self.groups.zone_and_rack(replica);
+                    match pass {
+                        0 => replica_zone == zone,
+                        1 => replica_rack == rack,
+                        _ => false,
+                    }
+                });
+                if !taken {
+                    replicas.push(server.clone());
+                }
+            }
+        }
+        replicas.truncate(count);
+        replicas
+    }
+
+    // replication_warning tells why the count replicas of the files of
+    // volume cannot all be in distinct failure domains, if they cannot.
+    pub fn replication_warning(&self, volume: &str, count: usize) -> Option<String> {
+        let servers = self.eligible_servers(volume);
+        let (zones, racks): (BTreeSet<String>, BTreeSet<String>) = servers
+            .keys()
+            .map(|server| self.groups.zone_and_rack(server))
+            .unzip();
+        if servers.len() < count {
+            Some(format!(
+                "volume {} has {} replicas but only {} servers",
+                volume,
+                count,
+                servers.len()
+            ))
+        } else if racks.len() < count {
+            Some(format!(
+                "volume {} has {} replicas but its servers are in {} racks, replicas share a rack",
+                volume,
+                count,
+                racks.len()
+            ))
+        } else if zones.len() > 1 && zones.len() < count {
+            Some(format!(
+                "volume {} has {} replicas but its servers are in {} zones, replicas share a zone",
+                volume,
+                count,
+                zones.len()
+            ))
+        } else {
+            None
+        }
+    }
+
+    // set_domain sets the failure domain of server.
+    pub fn set_domain(&mut self, server: &str, domain: FailureDomain) {
+        match domain == FailureDomain::default() {
+            true => self.groups.domains.remove(server),
+            false => self.groups.domains.insert(server.to_owned(), domain),
+        };
     }
 
     pub fn add(&mut self, server: ServerNode, weight: usize) {
-        self.ring.add(&server, weight);
         self.servers.insert(server.address, weight);
+        self.placement = Placement::new(self.algorithm, &self.servers);
+        self.place_groups();
     }
 
     pub fn remove(&mut self, server: &ServerNode) {
-        self.ring.remove(server);
         self.servers.remove(&server.address);
+        self.placement = Placement::new(self.algorithm, &self.servers);
+        self.place_groups();
+    }
+
+    // set_group puts server in group, or in no group if it is empty.
+    pub fn set_group(&mut self, server: &str, group: &str) {
+        match group {
+            "" => self.groups.servers.remove(server),
+            _ => self
+                .groups
+                .servers
+                .insert(server.to_owned(), group.to_owned()),
+        };
+        self.place_groups();
+    }
+
+    // pin_volume pins volume to group, or unpins it if it is empty.
+    pub fn pin_volume(&mut self, volume: &str, group: &str) {
+        match group {
+            "" => self.groups.volumes.remove(volume),
+            _ => self
+                .groups
+                .volumes
+                .insert(volume.to_owned(), group.to_owned()),
+        };
+        self.place_groups();
     }
 
     pub fn contains(&self, server: &str) -> bool {
@@ -79,3 +478,155 @@ impl HashRing {
         self.servers.keys().cloned().collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{FailureDomain, HashAlgorithm, HashRing, ServerGroups, ServerNode};
+
+    #[test]
+    fn hash_algorithm_test() {
+        for algorithm in [
+            HashAlgorithm::Conhash,
+            HashAlgorithm::Wyhash,
+            HashAlgorithm::Rendezvous,
+        ] {
+            assert_eq!(
+                algorithm.to_string().parse::<HashAlgorithm>(),
+                Ok(algorithm)
+            );
+            let servers: Vec<(String, usize)> = (0..4)
+                .map(|i| (format!("127.0.0.1:{}", 8085 + i), 100))
+                .collect();
+            let ring = HashRing::new(algorithm, servers);
+            let mut counts = HashMap::new();
+            for i in 0..4000 {
+                let address = ring.get(&format!("v/f{}", i)).unwrap().address.clone();
+                *counts.entry(address).or_insert(0) += 1;
+            }
+            // every server gets a share of the paths.
+            assert_eq!(counts.len(), 4);
+            assert!(counts.values().all(|count| *count > 500));
+
+            // only the paths of a removed server move.
+            let mut new_ring = ring.clone();
+            new_ring.remove(&ServerNode {
+                address: "127.0.0.1:8085".to_owned(),
+            });
+            for i in 0..4000 {
+                let path = format!("v/f{}", i);
+                let address = &ring.get(&path).unwrap().address;
+                if address != "127.0.0.1:8085" {
+                    assert_eq!(&new_ring.get(&path).unwrap().address, address);
+                }
+            }
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn server_groups_test() {
+        let servers: Vec<(String, usize)> = (0..4)
+            .map(|i| (format!("127.0.0.1:{}", 8085 + i), 100))
+            .collect();
+        let mut ring = HashRing::new(HashAlgorithm::Conhash, servers.clone());
+        ring.set_group("127.0.0.1:8085", "rack1");
+        ring.set_group("127.0.0.1:8086", "rack1");
+        ring.set_group("127.0.0.1:8087", "rack2");
+        ring.pin_volume("v", "rack1");
+        // a group without servers places the volume on all of them.
+        ring.pin_volume("w", "rack3");
+        let mut counts = HashMap::new();
+        for i in 0..1000 {
+            for path in [
+                format!("v/d/f{}", i),
+                format!("/v/f{}", i),
+                format!("v\0{}", i),
+            ] {
+                let address = ring.get(&path).unwrap().address.clone();
+                *counts.entry(address).or_insert(0) += 1;
+            }
+        }
+        assert_eq!(counts.len(), 2);
+        assert!(counts.contains_key("127.0.0.1:8085") && counts.contains_key("127.0.0.1:8086"));
+        let pinned = (0..1000)
+            .filter(|i| ring.get(&format!("w/f{}", i)).unwrap().address == "127.0.0.1:8088")
+            .count();
+        assert!(pinned > 0);
+
+        // the groups and the epoch are kept by the rings of rebalances.
+        ring.epoch = 3;
+        let mut new_ring = ring.clone();
+        assert_eq!(new_ring.epoch, 3);
+        assert_eq!(HashRing::from(ring.info()).epoch, 3);
+        new_ring.remove(&ServerNode {
+            address: "127.0.0.1:8085".to_owned(),
+        });
+        for i in 0..100 {
+            assert_eq!(
+                new_ring.get(&format!("v/f{}", i)).unwrap().address,
+                "127.0.0.1:8086"
+            );
+        }
+        let groups = ServerGroups {
+            servers: ring.groups.servers.clone(),
+            ..Default::default()
+        };
+        let unpinned = HashRing::with_groups(HashAlgorithm::Conhash, servers, groups);
+        ring.pin_volume("v", "");
+        ring.pin_volume("w", "");
+        assert_eq!(ring.groups, unpinned.groups);
+    }
+
+    #[test]
+    fn replicas_test() {
+        let servers: Vec<(String, usize)> = (0..6)
+            .map(|i| (format!("127.0.0.1:{}", 8085 + i), 100))
+            .collect();
+        let mut ring = HashRing::new(HashAlgorithm::Rendezvous, servers);
+        // without domains the replicas are on distinct servers.
+        let replicas = ring.replicas("v/f", 3);
+        assert_eq!(replicas.len(), 3);
+        assert_eq!(replicas[0], ring.get("v/f").unwrap().address);
+        assert!(replicas[1] != replicas[0] && replicas[2] != replicas[1]);
+        assert_eq!(ring.replicas("v/f", 10).len(), 6);
+        assert!(ring.replication_warning("v", 6).is_none());
+        assert!(ring.replication_warning("v", 7).is_some());
+
+        // two zones of racks of two servers.
+        for i in 0..6 {
+            ring.set_domain(
+                &format!("127.0.0.1:{}", 8085 + i),
+                FailureDomain {
+                    zone: format!("z{}", i / 4),
+                    rack: format!("r{}", i / 2),
+                },
+            );
+        }
+        let domain = |server: &String| ring.groups.domains[server].clone();
+        for i in 0..100 {
+            let replicas = ring.replicas(&format!("v/f{}", i), 3);
+            assert_eq!(replicas.len(), 3);
+            // two zones, then another rack.
+            assert_ne!(domain(&replicas[0]).zone, domain(&replicas[1]).zone);
+            let racks: std::collections::HashSet<String> =
+                replicas.iter().map(|r| domain(r).rack).collect();
+            assert_eq!(racks.len(), 3);
+        }
+        assert!(ring.replication_warning("v", 2).is_none());
+        assert!(ring.replication_warning("v", 3).unwrap().contains("zone"));
+        assert!(ring.replication_warning("v", 4).unwrap().contains("rack"));
+
+        // the replicas of a pinned volume stay in its group.
+        ring.set_group("127.0.0.1:8085", "g");
+        ring.set_group("127.0.0.1:8086", "g");
+        ring.pin_volume("v", "g");
+        let mut replicas = ring.replicas("v/f", 3);
+        replicas.sort();
+        assert_eq!(replicas, vec!["127.0.0.1:8085", "127.0.0.1:8086"]);
+        assert!(ring.replication_warning("v", 2).unwrap().contains("rack"));
+        ring.set_domain("127.0.0.1:8085", FailureDomain::default());
+        assert!(ring.replication_warning("v", 2).is_none());
+    }
+}
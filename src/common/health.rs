@@ -0,0 +1,118 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Health of the servers, the cache nodes and the manager, answered to the
+ * Health request without touching the files of the volumes. A component is
+ * live if it answers, and ready if all its checks pass: the database is
+ * writable, the disk has MIN_FREE_RATIO of free space, the hash ring is
+ * synced with the manager. `client health` runs the request for the probes
+ * of Kubernetes and the load balancers.
+ */
+use std::{fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+// the disk of a server is not ready with less free space.
+pub const MIN_FREE_RATIO: f64 = 0.05;
+// a server is not synced, and not up for the manager, without a heartbeat in
+// this time.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HealthCheck {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct HealthReport {
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    pub fn check(&mut self, name: &str, result: Result<String, String>) {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        self.checks.push(HealthCheck {
+            name: name.to_owned(),
+            ok,
+            detail,
+        });
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.checks.iter().all(|check| check.ok)
+    }
+}
+
+impl Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for check in &self.checks {
+            writeln!(
+                f,
+                "{}: {}, {}",
+                check.name,
+                match check.ok {
+                    true => "ok",
+                    false => "failed",
+                },
+                check.detail
+            )?;
+        }
+        write!(
+            f,
+            "{}",
+            match self.healthy() {
+                true => "ready",
+                false => "not ready",
+            }
+        )
+    }
+}
+
+// check_disk checks the free space of a disk of total bytes.
+pub fn check_disk(free: u64, total: u64) -> Result<String, String> {
+    let detail = format!("{} of {} bytes free", free, total);
+    match total > 0 && (free as f64) < total as f64 * MIN_FREE_RATIO {
+        true => Err(detail),
+        false => Ok(detail),
+    }
+}
+
+// disk_space returns the free and total bytes of the file system of path.
+pub fn disk_space(path: &str) -> Result<(u64, u64), i32> {
+    let stat = nix::sys::statvfs::statvfs(path).map_err(|e| e as i32)?;
+    let fragment = stat.fragment_size() as u64;
+    Ok((
+        stat.blocks_available() as u64 * fragment,
+        stat.blocks() as u64 * fragment,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_disk, disk_space, HealthReport};
+
+    #[test]
+    fn health_report_test() {
+        assert!(check_disk(10, 100).is_ok());
+        assert_eq!(check_disk(4, 100), Err("4 of 100 bytes free".to_owned()));
+        let (free, total) = disk_space("/tmp").unwrap();
+        assert!(free <= total);
+
+        let mut report = HealthReport::default();
+        report.check("db", Ok("writable".to_owned()));
+        assert!(report.healthy());
+        report.check("disk", check_disk(0, 100));
+        assert!(!report.healthy());
+        assert_eq!(
+            report.to_string(),
+            "db: ok, writable\ndisk: failed, 0 of 100 bytes free\nnot ready"
+        );
+    }
+}
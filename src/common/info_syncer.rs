@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     sync::{
         atomic::{AtomicI32, Ordering},
         Arc,
@@ -7,13 +8,38 @@ use std::{
 };
 
 use async_trait::async_trait;
-use log::{debug, error, info};
+use fuser::FileAttr;
+use log::{debug, error, info, warn};
 use spin::RwLock;
 use tokio::time::sleep;
 
-use crate::common::errors::{self, status_to_string, CONNECTION_ERROR};
+use crate::common::errors::{self, status_to_string, CONNECTION_ERROR, INVALID_CLUSTER_STATUS};
 
-use super::{hash_ring::HashRing, sender::Sender, serialization::ClusterStatus};
+use super::{
+    cache::OwnerCache,
+    dns::{resolve_addresses, SRV_PREFIX},
+    hash_ring::{HashRing, HashRingInfo, HashRingSnapshot},
+    sender::Sender,
+    serialization::{ClusterStatus, StatFsRecvMetaData},
+};
+
+// the paths of a BatchGetAttr request at most.
+pub const BATCH_GET_ATTR_PATHS: usize = 1024;
+
+// the syncs of the cluster status failed in a row after which a client given
+// several managers moves to the next one.
+const MANAGER_FAILOVER_ERRORS: u32 = 3;
+
+// manager_addresses splits the managers given to a client as a comma
+// separated list, tried in order, see dns.rs for the `srv:` ones.
+pub fn manager_addresses(manager_address: &str) -> Vec<String> {
+    manager_address
+        .split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
 
 #[async_trait]
 pub trait InfoSyncer {
@@ -21,12 +47,18 @@ pub trait InfoSyncer {
     fn cluster_status(&self) -> &AtomicI32;
 }
 
-async fn sync_cluster_infos<I: InfoSyncer>(client: Arc<I>) {
+async fn sync_cluster_infos<I: ClientStatusMonitor + Sync + Send>(
+    client: Arc<I>,
+    manager_address: String,
+) {
+    let managers = manager_addresses(&manager_address);
+    let mut errors = 0;
     loop {
         {
             let result = client.get_cluster_status().await;
             match result {
                 Ok(status) => {
+                    errors = 0;
                     let status = status.into();
                     if client.cluster_status().load(Ordering::Relaxed) != status {
                         client.cluster_status().store(status, Ordering::Relaxed);
@@ -34,6 +66,19 @@ async fn sync_cluster_infos<I: InfoSyncer>(client: Arc<I>) {
                 }
                 Err(e) => {
                     info!("sync server infos failed, error = {}", e);
+                    errors += 1;
+                    // the srv records are asked again, a single one may
+                    // stand for several managers.
+                    if errors >= MANAGER_FAILOVER_ERRORS
+                        && (managers.len() > 1
+                            || managers.iter().any(|m| m.starts_with(SRV_PREFIX)))
+                        && client
+                            .failover_manager(&resolve_addresses(managers.clone()).await)
+                            .await
+                            .is_ok()
+                    {
+                        errors = 0;
+                    }
                 }
             }
         }
@@ -49,14 +94,19 @@ pub trait ClientStatusMonitor: InfoSyncer {
     fn manager_address(&self) -> &Arc<tokio::sync::Mutex<String>>;
 
     fn get_address(&self, path: &str) -> String {
-        self.hash_ring()
-            .read()
-            .as_ref()
-            .unwrap()
-            .get(path)
-            .unwrap()
-            .address
-            .clone()
+        let hash_ring = self.hash_ring().read();
+        let hash_ring = hash_ring.as_ref().unwrap();
+        let place = || hash_ring.get(path).unwrap().address.clone();
+        match self.owner_cache() {
+            Some(owner_cache) => owner_cache.get(hash_ring.epoch, path, place),
+            None => place(),
+        }
+    }
+
+    // owner_cache keeps the owners of the paths on the hash ring, if the
+    // client has one.
+    fn owner_cache(&self) -> Option<&OwnerCache> {
+        None
     }
 
     fn get_new_address(&self, path: &str) -> String {
@@ -66,18 +116,53 @@ pub trait ClientStatusMonitor: InfoSyncer {
         }
     }
 
-    async fn get_hash_ring_info(&self) -> Result<Vec<(String, usize)>, i32> {
+    async fn get_hash_ring_info(&self) -> Result<HashRingInfo, i32> {
         self.sender()
             .get_hash_ring_info(&self.manager_address().lock().await)
             .await
     }
-    async fn get_new_hash_ring_info(&self) -> Result<Vec<(String, usize)>, i32> {
+    async fn get_new_hash_ring_info(&self) -> Result<HashRingInfo, i32> {
         self.sender()
             .get_new_hash_ring_info(&self.manager_address().lock().await)
             .await
     }
+    async fn get_hash_ring_snapshot(&self) -> Result<HashRingSnapshot, i32> {
+        self.sender()
+            .get_hash_ring_snapshot(&self.manager_address().lock().await)
+            .await
+    }
+
+    // get_stat_fs sums the space of the disks of all the servers, for
+    // statfs. The servers not answering are left out.
+    async fn get_stat_fs(&self) -> Result<StatFsRecvMetaData, i32> {
+        let servers: Vec<String> = match self.hash_ring().read().as_ref() {
+            Some(hash_ring) => hash_ring.servers.keys().cloned().collect(),
+            None => return Err(INVALID_CLUSTER_STATUS),
+        };
+        let mut stat_fs = None;
+        for server in servers {
+            match self.sender().get_stat_fs(&server).await {
+                Ok(space) => {
+                    let sum = stat_fs.get_or_insert_with(StatFsRecvMetaData::default);
+                    sum.total += space.total;
+                    sum.free += space.free;
+                }
+                Err(e) => debug!("stat fs of {} failed: {}", server, status_to_string(e)),
+            }
+        }
+        stat_fs.ok_or(CONNECTION_ERROR)
+    }
+
+    // proxy_address is the address that receives all the requests instead of
+    // the owners, e.g. a cache node.
+    fn proxy_address(&self) -> Option<&str> {
+        None
+    }
 
     fn get_connection_address(&self, path: &str) -> String {
+        if let Some(address) = self.proxy_address() {
+            return address.to_owned();
+        }
         let cluster_status = self.cluster_status().load(Ordering::Acquire);
 
         // check the ClusterStatus is not Idle
@@ -100,17 +185,155 @@ pub trait ClientStatusMonitor: InfoSyncer {
         }
     }
 
+    // batch_get_file_attr gets the attrs of paths with one request for up to
+    // BATCH_GET_ATTR_PATHS of them on a server. The paths a server does not
+    // answer in a batch, e.g. during a rebalance, are got one by one.
+    async fn batch_get_file_attr(&self, paths: &[String]) -> Vec<Result<FileAttr, i32>> {
+        let mut servers: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            servers
+                .entry(self.get_connection_address(path))
+                .or_default()
+                .push(i);
+        }
+        let mut attrs = vec![Err(INVALID_CLUSTER_STATUS); paths.len()];
+        for (address, indexes) in servers {
+            for indexes in indexes.chunks(BATCH_GET_ATTR_PATHS) {
+                let batch: Vec<String> = indexes.iter().map(|&i| paths[i].clone()).collect();
+                // the servers without batches fail the whole request.
+                if let Ok(batch_attrs) = self.sender().batch_get_file_attr(&address, &batch).await {
+                    for (&i, attr) in indexes.iter().zip(batch_attrs) {
+                        attrs[i] = attr;
+                    }
+                }
+            }
+        }
+        for (path, attr) in paths.iter().zip(attrs.iter_mut()) {
+            if let Err(INVALID_CLUSTER_STATUS) = attr {
+                *attr = self
+                    .sender()
+                    .get_file_attr(&self.get_connection_address(path), path)
+                    .await;
+            }
+        }
+        attrs
+    }
+
+    // get_connection_route returns the address get_connection_address
+    // routes path to, with the epoch of the hash ring it was placed on,
+    // which tags the writes. Both come from the same hash ring, so a
+    // rebalance moving on in between cannot tag a write with the epoch of
+    // another ring than the one that routed it.
+    fn get_connection_route(&self, path: &str) -> (String, u64) {
+        let status = self.cluster_status().load(Ordering::Acquire).try_into();
+        let hash_ring = self.hash_ring().read();
+        let new_hash_ring = self.new_hash_ring().read();
+        let hash_ring = match (status, new_hash_ring.as_ref()) {
+            (Ok(ClusterStatus::PreFinish), Some(new_hash_ring)) => new_hash_ring,
+            _ => hash_ring.as_ref().unwrap(),
+        };
+        let address = match self.proxy_address() {
+            Some(address) => address.to_owned(),
+            None => {
+                let place = || hash_ring.get(path).unwrap().address.clone();
+                match self.owner_cache() {
+                    Some(owner_cache) => owner_cache.get(hash_ring.epoch, path, place),
+                    None => place(),
+                }
+            }
+        };
+        (address, hash_ring.epoch)
+    }
+
+    // refresh_hash_ring takes the hash rings of the manager if they are
+    // newer, after a server fenced a write routed with this one. They come
+    // from one snapshot, so the new hash ring of a rebalance taken matches
+    // the hash ring it replaces; the client only takes a new hash ring
+    // while it has one, the steps of a rebalance are left to the watch.
+    async fn refresh_hash_ring(&self) -> Result<(), i32> {
+        let snapshot = self.get_hash_ring_snapshot().await?;
+        let epoch = |hash_ring: &Arc<RwLock<Option<HashRing>>>| {
+            hash_ring.read().as_ref().map(|hash_ring| hash_ring.epoch)
+        };
+        let current = epoch(self.hash_ring()).unwrap_or(0);
+        if snapshot.hash_ring.epoch > current {
+            info!(
+                "refresh hash ring from epoch {} to {}",
+                current, snapshot.hash_ring.epoch
+            );
+            self.connect_ring_servers(&snapshot.hash_ring).await?;
+            self.hash_ring()
+                .write()
+                .replace(HashRing::from(snapshot.hash_ring));
+        }
+        if let (Some(new), Some(info)) = (epoch(self.new_hash_ring()), snapshot.new_hash_ring) {
+            if info.epoch > new {
+                info!("refresh new hash ring from epoch {} to {}", new, info.epoch);
+                self.connect_ring_servers(&info).await?;
+                self.new_hash_ring().write().replace(HashRing::from(info));
+            }
+        }
+        Ok(())
+    }
+
+    // connect_ring_servers connects to the servers of info that are not in
+    // the hash ring.
+    async fn connect_ring_servers(&self, info: &HashRingInfo) -> Result<(), i32> {
+        for (server_address, _) in &info.servers {
+            let connected = self
+                .hash_ring()
+                .read()
+                .as_ref()
+                .is_some_and(|hash_ring| hash_ring.contains(server_address));
+            if !connected {
+                self.add_connection(server_address).await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn add_connection(&self, server_address: &str) -> Result<(), i32>;
 
+    // connect_to_manager connects to the first of the comma separated
+    // managers that answers.
     async fn connect_to_manager(&self, manager_address: &str) -> Result<(), i32> {
-        self.manager_address()
-            .lock()
-            .await
-            .push_str(manager_address);
-        self.add_connection(manager_address).await.map_err(|e| {
-            error!("add connection failed: {:?}", e);
-            CONNECTION_ERROR
-        })
+        for manager in resolve_addresses(manager_addresses(manager_address)).await {
+            match self.add_connection(&manager).await {
+                Ok(()) => {
+                    *self.manager_address().lock().await = manager;
+                    return Ok(());
+                }
+                Err(e) => error!(
+                    "add connection to manager {} failed: {}",
+                    manager,
+                    status_to_string(e)
+                ),
+            }
+        }
+        Err(CONNECTION_ERROR)
+    }
+
+    // failover_manager moves to the next of managers answering after the
+    // current one, in their order. The requests to the manager in flight
+    // fail, the next ones go to the new manager.
+    async fn failover_manager(&self, managers: &[String]) -> Result<(), i32> {
+        let current = self.manager_address().lock().await.clone();
+        let start = managers
+            .iter()
+            .position(|manager| *manager == current)
+            .map_or(0, |i| i + 1);
+        for i in 0..managers.len() {
+            let manager = &managers[(start + i) % managers.len()];
+            if *manager == current || self.add_connection(manager).await.is_err() {
+                continue;
+            }
+            if self.sender().get_cluster_status(manager).await.is_ok() {
+                warn!("manager {} does not answer, moving to {}", current, manager);
+                *self.manager_address().lock().await = manager.clone();
+                return Ok(());
+            }
+        }
+        Err(CONNECTION_ERROR)
     }
 
     async fn add_new_servers(&self, new_servers_info: Vec<(String, usize)>) -> Result<(), i32> {
@@ -151,13 +374,11 @@ pub trait ClientStatusMonitor: InfoSyncer {
         .await;
 
         match result {
-            Ok(all_servers_address) => {
-                for server_address in &all_servers_address {
+            Ok(info) => {
+                for server_address in &info.servers {
                     self.add_connection(&server_address.0).await?;
                 }
-                self.hash_ring()
-                    .write()
-                    .replace(HashRing::new(all_servers_address.clone()));
+                self.hash_ring().write().replace(HashRing::from(info));
                 Ok(())
             }
             Err(e) => Err(e),
@@ -181,15 +402,21 @@ async fn client_watch_status<I: ClientStatusMonitor + std::marker::Sync + std::m
                 // so we have to check the status in a long code block, and we could not use a loop to check the status.
                 // in the future, we will make persistent flags for status, and we separate the code block for each status.
                 info!("Transfer: start to sync new hash ring");
-                let all_servers_address = match client.get_new_hash_ring_info().await {
-                    Ok(value) => value,
+                let info = match client.get_hash_ring_snapshot().await {
+                    Ok(HashRingSnapshot {
+                        new_hash_ring: Some(info),
+                        ..
+                    }) => info,
+                    Ok(_) => {
+                        panic!("Get Hash Ring Info Failed. Error = no new hash ring");
+                    }
                     Err(e) => {
                         panic!("Get Hash Ring Info Failed. Error = {}", e);
                     }
                 };
                 info!("Transfer: get new hash ring info");
 
-                for value in all_servers_address.iter() {
+                for value in info.servers.iter() {
                     if client
                         .hash_ring()
                         .read()
@@ -204,10 +431,7 @@ async fn client_watch_status<I: ClientStatusMonitor + std::marker::Sync + std::m
                         panic!("Add Connection Failed. Error = {}", e);
                     }
                 }
-                client
-                    .new_hash_ring()
-                    .write()
-                    .replace(HashRing::new(all_servers_address));
+                client.new_hash_ring().write().replace(HashRing::from(info));
                 info!("Transfer: sync new hash ring finished");
 
                 // wait for all servers to be PreTransfer
@@ -327,6 +551,20 @@ pub async fn init_network_connections<
     if let Err(e) = client.connect_to_manager(&manager_address).await {
         panic!("connect to manager failed, err = {}", status_to_string(e));
     }
-    tokio::spawn(sync_cluster_infos(client.clone()));
+    tokio::spawn(sync_cluster_infos(client.clone(), manager_address));
     tokio::spawn(client_watch_status(client));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::manager_addresses;
+
+    #[test]
+    fn manager_addresses_test() {
+        assert_eq!(manager_addresses("127.0.0.1:8081"), vec!["127.0.0.1:8081"]);
+        assert_eq!(
+            manager_addresses("m1:8081, m2:8081,"),
+            vec!["m1:8081", "m2:8081"]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The logger of the components, chosen with `--log-format`: the text of
+ * env_logger, or one json object per line for Loki or ELK, with the fields
+ * ts, level, component, target and msg. The records of the requests served,
+ * logged at debug level by log_op, also have the fields op, path, latency_us
+ * and status, the errno of the request or 0.
+ */
+use std::{cell::RefCell, fmt::Display, io::Write, str::FromStr, sync::OnceLock, time::Duration};
+
+use env_logger::fmt::TimestampPrecision;
+use log::{Level, LevelFilter};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::errors::status_to_string;
+
+pub const OP_TARGET: &str = "sealfs::op";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expected text or json", s)),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+struct OpFields {
+    op: String,
+    path: String,
+    latency: Duration,
+    status: i32,
+}
+
+static COMPONENT: OnceLock<&'static str> = OnceLock::new();
+
+thread_local! {
+    // the fields of the request logged by log_op, read by the formatter in
+    // the same thread.
+    static OP_FIELDS: RefCell<Option<OpFields>> = const { RefCell::new(None) };
+}
+
+// init_logger sets the logger of component, the level being one of
+// error, warn, info, debug or trace, warn if it is not.
+pub fn init_logger(component: &'static str, level: &str, format: LogFormat) {
+    let _ = COMPONENT.set(component);
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter(
+        None,
+        LevelFilter::from_str(level).unwrap_or(LevelFilter::Warn),
+    );
+    match format {
+        LogFormat::Text => {
+            builder.format_timestamp(Some(TimestampPrecision::Millis));
+        }
+        LogFormat::Json => {
+            builder.format(|buf, record| {
+                let mut line = json!({
+                    "ts": buf.timestamp_millis().to_string(),
+                    "level": record.level().to_string(),
+                    "component": COMPONENT.get().copied().unwrap_or(""),
+                    "target": record.target(),
+                    "msg": record.args().to_string(),
+                });
+                if let Some(fields) = OP_FIELDS.with(|f| f.borrow_mut().take()) {
+                    add_op_fields(&mut line, fields);
+                }
+                writeln!(buf, "{}", line)
+            });
+        }
+    }
+    builder.init();
+}
+
+fn add_op_fields(line: &mut Value, fields: OpFields) {
+    let line = line.as_object_mut().unwrap();
+    line.insert("op".to_owned(), fields.op.into());
+    line.insert("path".to_owned(), fields.path.into());
+    line.insert(
+        "latency_us".to_owned(),
+        (fields.latency.as_micros() as u64).into(),
+    );
+    line.insert("status".to_owned(), fields.status.into());
+}
+
+// log_op logs a request served, with its fields in the json format.
+pub fn log_op(op: String, path: &str, latency: Duration, status: i32) {
+    if !log::log_enabled!(target: OP_TARGET, Level::Debug) {
+        return;
+    }
+    let message = format!(
+        "{} {} {}us {}",
+        op,
+        path,
+        latency.as_micros(),
+        match status {
+            0 => "ok".to_owned(),
+            status => status_to_string(status),
+        }
+    );
+    OP_FIELDS.with(|f| {
+        *f.borrow_mut() = Some(OpFields {
+            op,
+            path: path.to_owned(),
+            latency,
+            status,
+        })
+    });
+    log::debug!(target: OP_TARGET, "{}", message);
+    OP_FIELDS.with(|f| f.borrow_mut().take());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{add_op_fields, LogFormat, OpFields};
+
+    #[test]
+    fn log_format_test() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!(LogFormat::Text.to_string(), "text");
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        let mut line = serde_json::json!({ "msg": "read \"a\"" });
+        add_op_fields(
+            &mut line,
+            OpFields {
+                op: "ReadFile".to_owned(),
+                path: "v/a\nb".to_owned(),
+                latency: Duration::from_micros(85),
+                status: libc::ENOENT,
+            },
+        );
+        assert_eq!(
+            line.to_string(),
+            r#"{"latency_us":85,"msg":"read \"a\"","op":"ReadFile","path":"v/a\nb","status":2}"#
+        );
+    }
+}
@@ -2,11 +2,17 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod affinity;
 pub mod byte;
 pub mod cache;
+pub mod credit;
+pub mod dns;
 pub mod errors;
 pub mod hash_ring;
+pub mod health;
 pub mod info_syncer;
+pub mod logging;
+pub mod page_cache;
 pub mod sender;
 pub mod serialization;
 pub mod util;
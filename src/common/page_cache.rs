@@ -0,0 +1,366 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * A page cache shared by the clients of a host, the FUSE clients and the
+ * intercepted processes, so the data read by one of them is not fetched
+ * again by the others. It is a file mapped by all of them, e.g. in
+ * /dev/shm, of slots of CHUNK_SIZE chunks of the files, keyed by the path,
+ * the index of the chunk and the stamp of the file, see page_stamp. A chunk
+ * goes in one of the WAYS slots of a set picked by its key, replacing the
+ * least recently used of them. The slots are written under a seqlock, a
+ * reader copying a slot being written misses it.
+ * A client reads the chunks of the stamp of the file it last saw, so the
+ * chunks of the old versions of a file are not read once it saw the new
+ * one, and its writes drop the chunks they change for the other clients of
+ * the host. The file is created with mode 0600, only the processes of its
+ * owner share it. Its size is set by the first client opening it.
+ */
+use std::{
+    fs::{File, OpenOptions},
+    num::NonZeroUsize,
+    os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use fuser::{FileAttr, FileType};
+use log::error;
+use nix::{
+    fcntl::{flock, FlockArg},
+    sys::mman::{mmap, munmap, MapFlags, ProtFlags},
+};
+
+use super::{byte, serialization::file_version};
+
+const CHUNK_SIZE: usize = byte::CHUNK_SIZE as usize;
+const MAGIC: u64 = u64::from_le_bytes(*b"SEALPAGE");
+const HEADER_SIZE: usize = 64;
+// seq, key, chunk, stamp, length, used, then the data.
+const SLOT_HEADER_SIZE: usize = 64;
+const SLOT_SIZE: usize = SLOT_HEADER_SIZE + CHUNK_SIZE;
+const WAYS: usize = 8;
+
+const SEQ: usize = 0;
+const KEY: usize = 8;
+const CHUNK: usize = 16;
+const STAMP: usize = 24;
+const LENGTH: usize = 32;
+const USED: usize = 40;
+
+// page_stamp returns the stamp of the chunks of a file of attr, its version
+// mixed with its mtime, since a file created again starts at the version of
+// the file it replaces, or None but for the regular files. It must be taken
+// before the ino of attr is replaced.
+pub fn page_stamp(attr: &FileAttr) -> Option<u64> {
+    if attr.kind != FileType::RegularFile {
+        return None;
+    }
+    let mtime = attr
+        .mtime
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    Some(file_version(attr) ^ mtime.rotate_left(20))
+}
+
+pub struct SharedPageCache {
+    base: *mut u8,
+    size: usize,
+    sets: usize,
+}
+
+unsafe impl Send for SharedPageCache {}
+unsafe impl Sync for SharedPageCache {}
+
+impl SharedPageCache {
+    // open maps the page cache at path, creating it with about size bytes of
+    // chunks if it does not exist.
+    pub fn open(path: &str, size: usize) -> Result<Self, i32> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        // the first client sizes the file, the others wait for it.
+        flock(file.as_raw_fd(), FlockArg::LockExclusive).map_err(|e| e as i32)?;
+        let result = Self::map(&file, size);
+        let _ = flock(file.as_raw_fd(), FlockArg::Unlock);
+        result
+    }
+
+    fn map(file: &File, size: usize) -> Result<Self, i32> {
+        let length = file
+            .metadata()
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?
+            .len() as usize;
+        let (length, created) = match length {
+            0 => {
+                let sets = (size / SLOT_SIZE / WAYS).max(1);
+                let length = HEADER_SIZE + sets * WAYS * SLOT_SIZE;
+                file.set_len(length as u64)
+                    .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                (length, true)
+            }
+            length if length > HEADER_SIZE => (length, false),
+            _ => return Err(libc::EINVAL),
+        };
+        let base = unsafe {
+            mmap(
+                None,
+                NonZeroUsize::new(length).unwrap(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+            .map_err(|e| e as i32)? as *mut u8
+        };
+        let mut cache = Self {
+            base,
+            size: length,
+            sets: 0,
+        };
+        if created {
+            cache.word(8).store(
+                ((length - HEADER_SIZE) / SLOT_SIZE) as u64,
+                Ordering::Relaxed,
+            );
+            cache.word(0).store(MAGIC, Ordering::Release);
+        }
+        let slots = cache.word(8).load(Ordering::Relaxed) as usize;
+        if cache.word(0).load(Ordering::Acquire) != MAGIC
+            || slots == 0
+            || slots % WAYS != 0
+            || HEADER_SIZE + slots * SLOT_SIZE != length
+        {
+            error!("page cache {:?} is not a sealfs page cache", file);
+            return Err(libc::EINVAL);
+        }
+        cache.sets = slots / WAYS;
+        Ok(cache)
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+
+    fn slot(&self, set: usize, way: usize) -> usize {
+        HEADER_SIZE + (set * WAYS + way) * SLOT_SIZE
+    }
+
+    fn tick(&self) -> u64 {
+        // 0 is a slot never used.
+        self.word(16).fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn key(path: &str) -> u64 {
+        // 0 is an empty slot.
+        wyhash::wyhash(path.as_bytes(), 0).max(1)
+    }
+
+    fn set(&self, key: u64, chunk: u64) -> usize {
+        ((key ^ chunk.wrapping_mul(0x9e37_79b9_7f4a_7c15)) % self.sets as u64) as usize
+    }
+
+    fn get_chunk(&self, key: u64, chunk: u64, stamp: u64, buf: &mut [u8]) -> Option<usize> {
+        let set = self.set(key, chunk);
+        for way in 0..WAYS {
+            let slot = self.slot(set, way);
+            let seq = self.word(slot + SEQ).load(Ordering::Acquire);
+            if seq % 2 == 1
+                || self.word(slot + KEY).load(Ordering::Relaxed) != key
+                || self.word(slot + CHUNK).load(Ordering::Relaxed) != chunk
+                || self.word(slot + STAMP).load(Ordering::Relaxed) != stamp
+            {
+                continue;
+            }
+            let length = (self.word(slot + LENGTH).load(Ordering::Relaxed) as usize)
+                .min(CHUNK_SIZE)
+                .min(buf.len());
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    self.base.add(slot + SLOT_HEADER_SIZE),
+                    buf.as_mut_ptr(),
+                    length,
+                );
+            }
+            fence(Ordering::Acquire);
+            if self.word(slot + SEQ).load(Ordering::Relaxed) != seq {
+                return None;
+            }
+            self.word(slot + USED).store(self.tick(), Ordering::Relaxed);
+            return Some(length);
+        }
+        None
+    }
+
+    // lock takes the slot for a writer, if no other writer has it.
+    fn lock(&self, slot: usize) -> Option<u64> {
+        let seq = self.word(slot + SEQ).load(Ordering::Relaxed);
+        if seq % 2 == 1 {
+            return None;
+        }
+        self.word(slot + SEQ)
+            .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        fence(Ordering::Release);
+        Some(seq)
+    }
+
+    fn unlock(&self, slot: usize, seq: u64) {
+        self.word(slot + SEQ).store(seq + 2, Ordering::Release);
+    }
+
+    fn put_chunk(&self, key: u64, chunk: u64, stamp: u64, data: &[u8]) {
+        let set = self.set(key, chunk);
+        // the slot of an older stamp of the chunk, or the least recently used.
+        let slot = (0..WAYS)
+            .map(|way| self.slot(set, way))
+            .min_by_key(|&slot| {
+                match self.word(slot + KEY).load(Ordering::Relaxed) == key
+                    && self.word(slot + CHUNK).load(Ordering::Relaxed) == chunk
+                {
+                    true => 0,
+                    false => self.word(slot + USED).load(Ordering::Relaxed) + 1,
+                }
+            })
+            .unwrap();
+        let Some(seq) = self.lock(slot) else {
+            return;
+        };
+        self.word(slot + KEY).store(key, Ordering::Relaxed);
+        self.word(slot + CHUNK).store(chunk, Ordering::Relaxed);
+        self.word(slot + STAMP).store(stamp, Ordering::Relaxed);
+        self.word(slot + LENGTH)
+            .store(data.len() as u64, Ordering::Relaxed);
+        self.word(slot + USED).store(self.tick(), Ordering::Relaxed);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.base.add(slot + SLOT_HEADER_SIZE),
+                data.len(),
+            );
+        }
+        self.unlock(slot, seq);
+    }
+
+    // read returns size bytes of path at offset if all their chunks of stamp
+    // are cached, less at the end of the file.
+    pub fn read(&self, path: &str, stamp: u64, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let key = Self::key(path);
+        let end = offset + size as u64;
+        let mut data = Vec::with_capacity(size as usize);
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut chunk = offset / CHUNK_SIZE as u64;
+        while chunk * (CHUNK_SIZE as u64) < end {
+            let length = self.get_chunk(key, chunk, stamp, &mut buf)?;
+            let start = chunk * CHUNK_SIZE as u64;
+            let from = (offset.max(start) - start) as usize;
+            let to = ((end - start) as usize).min(length);
+            if from < to {
+                data.extend_from_slice(&buf[from..to]);
+            }
+            // the end of the file.
+            if length < CHUNK_SIZE {
+                break;
+            }
+            chunk += 1;
+        }
+        Some(data)
+    }
+
+    // fill caches the chunks of path of stamp wholly in data, read from
+    // offset for size bytes. data shorter than size ends at the end of the
+    // file, so its last chunk is cached too.
+    pub fn fill(&self, path: &str, stamp: u64, offset: u64, size: u32, data: &[u8]) {
+        let key = Self::key(path);
+        let end = offset + data.len() as u64;
+        let eof = data.len() < size as usize;
+        let mut chunk = offset.div_ceil(CHUNK_SIZE as u64);
+        while chunk * (CHUNK_SIZE as u64) < end || (eof && chunk * (CHUNK_SIZE as u64) == end) {
+            let start = chunk * CHUNK_SIZE as u64;
+            let to = (start + CHUNK_SIZE as u64).min(end);
+            if to - start < CHUNK_SIZE as u64 && !eof {
+                break;
+            }
+            let from = (start - offset) as usize;
+            self.put_chunk(key, chunk, stamp, &data[from..(to - offset) as usize]);
+            chunk += 1;
+        }
+    }
+
+    // invalidate drops the chunks of path written by a client, of all stamps.
+    pub fn invalidate(&self, path: &str, offset: u64, length: u64) {
+        let key = Self::key(path);
+        let first = offset / CHUNK_SIZE as u64;
+        let last = (offset + length.max(1) - 1) / CHUNK_SIZE as u64;
+        for chunk in first..=last {
+            let set = self.set(key, chunk);
+            for way in 0..WAYS {
+                let slot = self.slot(set, way);
+                if self.word(slot + KEY).load(Ordering::Relaxed) != key
+                    || self.word(slot + CHUNK).load(Ordering::Relaxed) != chunk
+                {
+                    continue;
+                }
+                if let Some(seq) = self.lock(slot) {
+                    self.word(slot + KEY).store(0, Ordering::Relaxed);
+                    self.unlock(slot, seq);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SharedPageCache {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.base as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SharedPageCache, CHUNK_SIZE};
+
+    #[test]
+    fn page_cache_test() {
+        let path = "/tmp/test_page_cache";
+        let _ = std::fs::remove_file(path);
+        let cache = SharedPageCache::open(path, 1 << 20).unwrap();
+        // another process maps the same chunks, whatever size it asks.
+        let other = SharedPageCache::open(path, 1 << 30).unwrap();
+        assert_eq!(other.sets, cache.sets);
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        assert_eq!(cache.read("v/a", 1, 0, 10), None);
+        // a read of the whole file caches its 3 chunks.
+        cache.fill("v/a", 1, 0, (CHUNK_SIZE * 4) as u32, &data);
+        assert_eq!(
+            other.read("v/a", 1, 10, CHUNK_SIZE as u32).unwrap(),
+            &data[10..CHUNK_SIZE + 10]
+        );
+        assert_eq!(
+            other.read("v/a", 1, (CHUNK_SIZE * 2) as u64, 1000).unwrap(),
+            &data[CHUNK_SIZE * 2..]
+        );
+        assert_eq!(other.read("v/a", 2, 0, 10), None);
+        assert_eq!(other.read("v/b", 1, 0, 10), None);
+        // a new stamp replaces the chunk.
+        cache.fill("v/a", 2, 0, 100, &data[..10]);
+        assert_eq!(cache.read("v/a", 1, 0, 10), None);
+        // partial chunks are not cached but at the end of the file.
+        cache.fill("v/a", 3, 10, 100, &data[10..110]);
+        assert_eq!(cache.read("v/a", 3, 10, 100), None);
+        // a write drops the chunks it changes.
+        other.invalidate("v/a", CHUNK_SIZE as u64 + 5, 1);
+        assert_eq!(cache.read("v/a", 1, CHUNK_SIZE as u64, 10), None);
+        assert!(cache.read("v/a", 1, (CHUNK_SIZE * 2) as u64, 10).is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -6,43 +6,70 @@
 
 use std::{sync::Arc, time::Duration};
 
+use fuser::FileAttr;
 use log::error;
 
 use crate::{
-    common::errors::CONNECTION_ERROR,
-    rpc::client::{RpcClient, TcpStreamCreator},
+    common::{
+        errors::{CONNECTION_ERROR, SERIALIZATION_ERROR},
+        health::HealthReport,
+    },
+    rpc::{
+        client::RpcClient,
+        local::{LocalReadHalf, LocalStreamCreator, LocalWriteHalf},
+    },
 };
 
 use super::serialization::{
-    AddNodesSendMetaData, ClusterStatus, CreateVolumeSendMetaData, DeleteNodesSendMetaData,
-    GetClusterStatusRecvMetaData, GetHashRingInfoRecvMetaData, ManagerOperationType, OperationType,
-    Volume,
+    bytes_as_batch_attrs, bytes_as_dir_entries, bytes_as_tree_entries, file_attr_as_bytes_mut,
+    AddLinkRecvMetaData, AddLinkSendMetaData, AddNodesSendMetaData, AppendFileRecvMetaData,
+    AppendFileSendMetaData, AtimePolicy, BatchGetAttrSendMetaData, ClusterStatus,
+    CompleteUploadSendMetaData, CreateFileSendMetaData, CreateSymlinkSendMetaData,
+    CreateVolumeSendMetaData, DedupScanRecvMetaData, DedupScanSendMetaData,
+    DeleteNodesSendMetaData, FsyncSendMetaData, GetClusterStatusRecvMetaData,
+    GetHandoffsRecvMetaData, GetHashRingInfoRecvMetaData, GetHashRingSnapshotRecvMetaData,
+    GetMaintenanceRecvMetaData, GetMembershipChangesRecvMetaData, GetVolumeHistoryRecvMetaData,
+    GetVolumeHistorySendMetaData, GrepMatch, GrepRecvMetaData, GrepSendMetaData, LinkSendMetaData,
+    LinkTempFileSendMetaData, ListTreeSendMetaData, LockRecvMetaData, LockSendMetaData,
+    ManagerOperationType, OperationType, PinVolumeSendMetaData, ReadDirSendMetaData,
+    ReadFileSendMetaData, RecordHandoffSendMetaData, SeekSendMetaData, SetAttrSendMetaData,
+    SetAttrTreeRecvMetaData, SetAttrTreeSendMetaData, SetMaintenanceSendMetaData,
+    SetServerDomainSendMetaData, SetServerGroupSendMetaData, SetVolumeSendMetaData,
+    SetWeightSendMetaData, ShardDirSendMetaData, StatFsRecvMetaData, TransferStatusRecvMetaData,
+    UploadPartSendMetaData, Volume, VolumeDay, VolumeInfo, WriteFileSendMetaData, BATCH_ATTR_SIZE,
+};
+use super::{
+    credit::Credits,
+    hash_ring::{FailureDomain, HashRingInfo, HashRingSnapshot},
+    util::{empty_file, path_split},
 };
 
 pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
 pub const CONTROLL_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// a dedup scan reads all the files of a server under its path.
+pub const DEDUP_SCAN_TIMEOUT: Duration = Duration::from_secs(24 * 3600);
+// a rename across servers copies the data of the file.
+pub const RENAME_TIMEOUT: Duration = Duration::from_secs(3600);
+// a fsync writes back the dirty pages of the file.
+pub const FSYNC_TIMEOUT: Duration = Duration::from_secs(600);
 
 pub struct Sender {
-    pub client: Arc<
-        RpcClient<
-            tokio::net::tcp::OwnedReadHalf,
-            tokio::net::tcp::OwnedWriteHalf,
-            TcpStreamCreator,
-        >,
-    >,
+    pub client: Arc<RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
+    // limit the metadata requests sent to every server, see credit.rs.
+    pub credits: Credits,
+}
+
+// parent_dir returns the directory whose credits the request on a file takes.
+fn parent_dir(path: &str) -> String {
+    path_split(path).map(|(dir, _)| dir).unwrap_or_default()
 }
 
 impl Sender {
-    pub fn new(
-        client: Arc<
-            RpcClient<
-                tokio::net::tcp::OwnedReadHalf,
-                tokio::net::tcp::OwnedWriteHalf,
-                TcpStreamCreator,
-            >,
-        >,
-    ) -> Self {
-        Sender { client }
+    pub fn new(client: Arc<RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>) -> Self {
+        Sender {
+            client,
+            credits: Credits::default(),
+        }
     }
 
     pub async fn add_new_servers(
@@ -141,29 +168,38 @@ impl Sender {
         }
     }
 
-    pub async fn get_cluster_status(&self, manager_address: &str) -> Result<ClusterStatus, i32> {
+    pub async fn set_server_weight(
+        &self,
+        manager_address: &str,
+        server_address: &str,
+        weight: usize,
+    ) -> Result<(), i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
+        let send_meta_data = bincode::serialize(&SetWeightSendMetaData {
+            server_address: server_address.to_owned(),
+            weight,
+        })
+        .unwrap();
+
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
-        let mut recv_meta_data = vec![0u8; 4];
-
         let result = self
             .client
             .call_remote(
                 manager_address,
-                ManagerOperationType::GetClusterStatus.into(),
+                ManagerOperationType::SetWeight.into(),
                 0,
                 "",
-                &[],
+                &send_meta_data,
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
-                &mut recv_meta_data,
+                &mut [],
                 &mut [],
                 REQUEST_TIMEOUT,
             )
@@ -173,44 +209,48 @@ impl Sender {
                 if status != 0 {
                     Err(status)
                 } else {
-                    let cluster_status_meta_data: GetClusterStatusRecvMetaData =
-                        bincode::deserialize(&recv_meta_data).unwrap();
-                    Ok(cluster_status_meta_data.status)
+                    Ok(())
                 }
             }
             Err(e) => {
-                error!("get cluster status failed: {}", e);
+                error!("set server weight failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn get_hash_ring_info(
+    pub async fn set_server_group(
         &self,
         manager_address: &str,
-    ) -> Result<Vec<(String, usize)>, i32> {
+        server_address: &str,
+        group: &str,
+    ) -> Result<(), i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
+        let send_meta_data = bincode::serialize(&SetServerGroupSendMetaData {
+            server_address: server_address.to_owned(),
+            group: group.to_owned(),
+        })
+        .unwrap();
+
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
-        let mut recv_meta_data = vec![0u8; 65535];
-
         let result = self
             .client
             .call_remote(
                 manager_address,
-                ManagerOperationType::GetHashRing.into(),
+                ManagerOperationType::SetServerGroup.into(),
                 0,
                 "",
-                &[],
+                &send_meta_data,
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
-                &mut recv_meta_data,
+                &mut [],
                 &mut [],
                 REQUEST_TIMEOUT,
             )
@@ -218,26 +258,35 @@ impl Sender {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    return Err(status);
+                    Err(status)
+                } else {
+                    Ok(())
                 }
-                let hash_ring_meta_data: GetHashRingInfoRecvMetaData =
-                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
-                Ok(hash_ring_meta_data.hash_ring_info)
             }
             Err(e) => {
-                error!("get hash ring info failed: {}", e);
+                error!("set server group failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn get_new_hash_ring_info(
+    // set_server_domain returns the warnings of the manager about the
+    // replicas of the volumes.
+    pub async fn set_server_domain(
         &self,
         manager_address: &str,
-    ) -> Result<Vec<(String, usize)>, i32> {
+        server_address: &str,
+        domain: FailureDomain,
+    ) -> Result<Vec<String>, i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
+        let send_meta_data = bincode::serialize(&SetServerDomainSendMetaData {
+            server_address: server_address.to_owned(),
+            domain,
+        })
+        .unwrap();
+
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
@@ -247,10 +296,10 @@ impl Sender {
             .client
             .call_remote(
                 manager_address,
-                ManagerOperationType::GetNewHashRing.into(),
+                ManagerOperationType::SetServerDomain.into(),
                 0,
                 "",
-                &[],
+                &send_meta_data,
                 &[],
                 &mut status,
                 &mut rsp_flags,
@@ -266,65 +315,81 @@ impl Sender {
                 if status != 0 {
                     return Err(status);
                 }
-                let hash_ring_meta_data: GetHashRingInfoRecvMetaData =
-                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
-                Ok(hash_ring_meta_data.hash_ring_info)
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
             }
             Err(e) => {
-                error!("get new hash ring info failed: {}", e);
+                error!("set server domain failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn list_volumes(&self, address: &str) -> Result<Vec<Volume>, i32> {
+    pub async fn pin_volume(
+        &self,
+        manager_address: &str,
+        name: &str,
+        group: &str,
+    ) -> Result<(), i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
+        let send_meta_data = bincode::serialize(&PinVolumeSendMetaData {
+            name: name.to_owned(),
+            group: group.to_owned(),
+        })
+        .unwrap();
+
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
-        let mut recv_meta_data = vec![0u8; 65535];
-
         let result = self
             .client
             .call_remote(
-                address,
-                OperationType::ListVolumes.into(),
+                manager_address,
+                ManagerOperationType::PinVolume.into(),
                 0,
                 "",
-                &[],
+                &send_meta_data,
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
-                &mut recv_meta_data,
                 &mut [],
-                CONTROLL_REQUEST_TIMEOUT,
+                &mut [],
+                REQUEST_TIMEOUT,
             )
             .await;
         match result {
             Ok(_) => {
                 if status != 0 {
-                    return Err(status);
+                    Err(status)
+                } else {
+                    Ok(())
                 }
-                let volumes: Vec<Volume> =
-                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
-                Ok(volumes)
             }
             Err(e) => {
-                error!("list volumes failed: {}", e);
+                error!("pin volume failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn create_volume(&self, address: &str, name: &str, size: u64) -> Result<(), i32> {
+    pub async fn set_maintenance(
+        &self,
+        manager_address: &str,
+        server_address: &str,
+        enter: bool,
+    ) -> Result<(), i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
-        let send_meta_data = bincode::serialize(&CreateVolumeSendMetaData { size }).unwrap();
+        let send_meta_data = bincode::serialize(&SetMaintenanceSendMetaData {
+            server_address: server_address.to_owned(),
+            enter,
+        })
+        .unwrap();
 
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
@@ -332,10 +397,10 @@ impl Sender {
         let result = self
             .client
             .call_remote(
-                address,
-                OperationType::CreateVolume.into(),
+                manager_address,
+                ManagerOperationType::SetMaintenance.into(),
                 0,
-                name,
+                "",
                 &send_meta_data,
                 &[],
                 &mut status,
@@ -350,38 +415,41 @@ impl Sender {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    return Err(status);
+                    Err(status)
+                } else {
+                    Ok(())
                 }
-                Ok(())
             }
             Err(e) => {
-                error!("create volume failed: {:?}", e);
+                error!("set maintenance failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn delete_volume(&self, address: &str, name: &str) -> Result<(), i32> {
+    pub async fn get_maintenance_servers(&self, manager_address: &str) -> Result<Vec<String>, i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
+        let mut recv_meta_data = vec![0u8; 65535];
+
         let result = self
             .client
             .call_remote(
-                address,
-                OperationType::DeleteVolume.into(),
+                manager_address,
+                ManagerOperationType::GetMaintenance.into(),
                 0,
-                name,
+                "",
                 &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
-                &mut [],
+                &mut recv_meta_data,
                 &mut [],
                 REQUEST_TIMEOUT,
             )
@@ -391,38 +459,45 @@ impl Sender {
                 if status != 0 {
                     return Err(status);
                 }
-                Ok(())
+                let meta_data: GetMaintenanceRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
+                Ok(meta_data.servers)
             }
             Err(e) => {
-                error!("delete volume failed: {:?}", e);
+                error!("get maintenance servers failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn clean_volume(&self, address: &str, name: &str) -> Result<(), i32> {
+    pub async fn get_membership_changes(
+        &self,
+        manager_address: &str,
+    ) -> Result<GetMembershipChangesRecvMetaData, i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
+        let mut recv_meta_data = vec![0u8; 65535];
+
         let result = self
             .client
             .call_remote(
-                address,
-                OperationType::CleanVolume.into(),
+                manager_address,
+                ManagerOperationType::GetMembershipChanges.into(),
                 0,
-                name,
+                "",
                 &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
+                &mut recv_meta_data,
                 &mut [],
-                &mut [],
-                CONTROLL_REQUEST_TIMEOUT,
+                REQUEST_TIMEOUT,
             )
             .await;
         match result {
@@ -430,36 +505,38 @@ impl Sender {
                 if status != 0 {
                     return Err(status);
                 }
-                Ok(())
+                Ok(bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap())
             }
             Err(e) => {
-                error!("clean volume failed: {:?}", e);
+                error!("get membership changes failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn init_volume(&self, address: &str, name: &str) -> Result<(), i32> {
+    pub async fn get_cluster_status(&self, manager_address: &str) -> Result<ClusterStatus, i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
+        let mut recv_meta_data = vec![0u8; 4];
+
         let result = self
             .client
             .call_remote(
-                address,
-                OperationType::InitVolume.into(),
+                manager_address,
+                ManagerOperationType::GetClusterStatus.into(),
                 0,
-                name,
+                "",
                 &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
-                &mut [],
+                &mut recv_meta_data,
                 &mut [],
                 REQUEST_TIMEOUT,
             )
@@ -467,40 +544,37 @@ impl Sender {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    return Err(status);
+                    Err(status)
+                } else {
+                    let cluster_status_meta_data: GetClusterStatusRecvMetaData =
+                        bincode::deserialize(&recv_meta_data).unwrap();
+                    Ok(cluster_status_meta_data.status)
                 }
-                Ok(())
             }
             Err(e) => {
-                error!("init volume failed: {:?}", e);
+                error!("get cluster status failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn create_no_parent(
-        &self,
-        address: &str,
-        operation_type: OperationType,
-        parent: &str,
-        send_meta_data: &[u8],
-    ) -> Result<Vec<u8>, i32> {
+    pub async fn get_hash_ring_info(&self, manager_address: &str) -> Result<HashRingInfo, i32> {
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
 
         let mut recv_meta_data_length = 0usize;
         let mut recv_data_length = 0usize;
 
-        let mut recv_meta_data = vec![0u8; 1024];
+        let mut recv_meta_data = vec![0u8; 65535];
 
         let result = self
             .client
             .call_remote(
-                address,
-                operation_type.into(),
+                manager_address,
+                ManagerOperationType::GetHashRing.into(),
                 0,
-                parent,
-                send_meta_data,
+                "",
+                &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
@@ -514,41 +588,41 @@ impl Sender {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    Err(status)
-                } else {
-                    Ok(recv_meta_data[..recv_meta_data_length].to_vec())
+                    return Err(status);
                 }
+                let hash_ring_meta_data: GetHashRingInfoRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
+                Ok(HashRingInfo {
+                    algorithm: hash_ring_meta_data.hash_algorithm,
+                    servers: hash_ring_meta_data.hash_ring_info,
+                    groups: hash_ring_meta_data.groups,
+                    epoch: hash_ring_meta_data.epoch,
+                })
             }
             Err(e) => {
-                error!("create file failed with error: {}", e);
+                error!("get hash ring info failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn delete_no_parent(
+    // get_hash_ring_snapshot gets the cluster status with the hash rings of
+    // the manager in one request.
+    pub async fn get_hash_ring_snapshot(
         &self,
-        address: &str,
-        operation_type: OperationType,
-        parent: &str,
-        send_meta_data: &[u8],
-    ) -> Result<(), i32> {
-        let mut status = 0i32;
-        let mut rsp_flags = 0u32;
-
-        let mut recv_meta_data_length = 0usize;
-        let mut recv_data_length = 0usize;
-
-        let mut recv_meta_data = vec![0u8; 1024];
-
+        manager_address: &str,
+    ) -> Result<HashRingSnapshot, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let mut recv_meta_data = vec![0u8; 65535];
         let result = self
             .client
             .call_remote(
-                address,
-                operation_type.into(),
+                manager_address,
+                ManagerOperationType::GetHashRingSnapshot.into(),
                 0,
-                parent,
-                send_meta_data,
+                "",
+                &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
@@ -562,40 +636,43 @@ impl Sender {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    Err(status)
-                } else {
-                    Ok(())
+                    return Err(status);
                 }
+                let meta_data: GetHashRingSnapshotRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                        .map_err(|_| SERIALIZATION_ERROR)?;
+                Ok(meta_data.into())
             }
             Err(e) => {
-                error!("create file failed with error: {}", e);
+                error!("get hash ring snapshot failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn directory_add_entry(
-        &self,
-        address: &str,
-        path: &str,
-        send_meta_data: &[u8],
-    ) -> Result<(), i32> {
-        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
-            (0, 0, 0, 0);
+    pub async fn get_new_hash_ring_info(&self, manager_address: &str) -> Result<HashRingInfo, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 65535];
+
         let result = self
             .client
             .call_remote(
-                address,
-                OperationType::DirectoryAddEntry as u32,
+                manager_address,
+                ManagerOperationType::GetNewHashRing.into(),
                 0,
-                path,
-                send_meta_data,
+                "",
+                &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
-                &mut [],
+                &mut recv_meta_data,
                 &mut [],
                 REQUEST_TIMEOUT,
             )
@@ -603,44 +680,1912 @@ impl Sender {
         match result {
             Ok(_) => {
                 if status != 0 {
-                    Err(status)
-                } else {
-                    Ok(())
+                    return Err(status);
                 }
+                let hash_ring_meta_data: GetHashRingInfoRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
+                Ok(HashRingInfo {
+                    algorithm: hash_ring_meta_data.hash_algorithm,
+                    servers: hash_ring_meta_data.hash_ring_info,
+                    groups: hash_ring_meta_data.groups,
+                    epoch: hash_ring_meta_data.epoch,
+                })
             }
-            e => {
-                error!("Create file: DirectoryAddEntry failed: {} ,{:?}", path, e);
+            Err(e) => {
+                error!("get new hash ring info failed: {}", e);
                 Err(CONNECTION_ERROR)
             }
         }
     }
 
-    pub async fn directory_delete_entry(
-        &self,
-        address: &str,
-        path: &str,
-        send_meta_data: &[u8],
-    ) -> Result<(), i32> {
-        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
-            (0, 0, 0, 0);
+    pub async fn list_volumes(&self, address: &str) -> Result<Vec<Volume>, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 65535];
+
         let result = self
             .client
             .call_remote(
                 address,
-                OperationType::DirectoryDeleteEntry as u32,
+                OperationType::ListVolumes.into(),
                 0,
-                path,
-                send_meta_data,
+                "",
+                &[],
                 &[],
                 &mut status,
                 &mut rsp_flags,
                 &mut recv_meta_data_length,
                 &mut recv_data_length,
+                &mut recv_meta_data,
                 &mut [],
+                CONTROLL_REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                let volumes: Vec<Volume> =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length]).unwrap();
+                Ok(volumes)
+            }
+            Err(e) => {
+                error!("list volumes failed: {}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // volume_namespace sends a ReserveVolume, CommitVolume or ReleaseVolume
+    // of the volume name to the manager, a commit carries the VolumeInfo.
+    pub async fn volume_namespace(
+        &self,
+        manager_address: &str,
+        operation_type: ManagerOperationType,
+        name: &str,
+        meta_data: &[u8],
+    ) -> Result<(), i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let result = self
+            .client
+            .call_remote(
+                manager_address,
+                operation_type.into(),
+                0,
+                name,
+                meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("volume namespace operation failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // list_volume_infos gets the volumes in the manager registry, the list
+    // is sent in the data since it may be longer than a metadata.
+    pub async fn list_volume_infos(&self, manager_address: &str) -> Result<Vec<VolumeInfo>, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_data = vec![0u8; 1 << 20];
+
+        let result = self
+            .client
+            .call_remote(
+                manager_address,
+                ManagerOperationType::ListVolumeInfos.into(),
+                0,
+                "",
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_data[..recv_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("list volume infos failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // record_handoff tells the manager that server handed path off to its
+    // new owner, it returns once the manager keeps it.
+    pub async fn record_handoff(
+        &self,
+        manager_address: &str,
+        server: &str,
+        path: &str,
+    ) -> Result<(), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&RecordHandoffSendMetaData {
+            path: path.to_owned(),
+        })
+        .unwrap();
+        let result = self
+            .client
+            .call_remote(
+                manager_address,
+                ManagerOperationType::RecordHandoff.into(),
+                0,
+                server,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) if status != 0 => Err(status),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("record handoff failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // get_handoffs returns the paths server handed off in the running
+    // rebalance.
+    pub async fn get_handoffs(
+        &self,
+        manager_address: &str,
+        server: &str,
+    ) -> Result<Vec<String>, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        // the handoffs of a server are at most the files it stores.
+        let mut recv_meta_data = vec![0u8; 64 << 20];
+        let result = self
+            .client
+            .call_remote(
+                manager_address,
+                ManagerOperationType::GetHandoffs.into(),
+                0,
+                server,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize::<GetHandoffsRecvMetaData>(
+                    &recv_meta_data[..recv_meta_data_length],
+                )
+                .map(|meta_data| meta_data.paths)
+                .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("get handoffs failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // health returns the health of the server, the cache node or, if
+    // manager is set, the manager at address.
+    pub async fn health(&self, address: &str, manager: bool) -> Result<HealthReport, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 65535];
+
+        let operation_type = match manager {
+            true => ManagerOperationType::Health.into(),
+            false => OperationType::Health.into(),
+        };
+        let result = self
+            .client
+            .call_remote(
+                address,
+                operation_type,
+                0,
+                "",
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("health of {} failed: {:?}", address, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // transfer_status returns the transfers of the last rebalance of the
+    // server at address.
+    pub async fn transfer_status(&self, address: &str) -> Result<TransferStatusRecvMetaData, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 65535];
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::TransferStatus.into(),
+                0,
+                "",
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("transfer status of {} failed: {:?}", address, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // get_stat_fs returns the space of the disk of the server at address.
+    pub async fn get_stat_fs(&self, address: &str) -> Result<StatFsRecvMetaData, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 64];
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::GetStatFs.into(),
+                0,
+                "",
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("stat fs of {} failed: {:?}", address, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // open_local returns the local file of path on the server at address, for
+    // a short-circuit read, see client/short_circuit.rs.
+    pub async fn open_local(&self, address: &str, path: &str) -> Result<String, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_data = vec![0u8; 4096];
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::OpenLocal.into(),
+                0,
+                path,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                String::from_utf8(recv_data[..recv_data_length].to_vec())
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("open local {} failed: {:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // get_volume_history returns the last days of the usage of a volume,
+    // the last one first.
+    pub async fn get_volume_history(
+        &self,
+        manager_address: &str,
+        name: &str,
+        days: u32,
+    ) -> Result<Vec<VolumeDay>, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 65535];
+
+        let send_meta_data = bincode::serialize(&GetVolumeHistorySendMetaData {
+            name: name.to_owned(),
+            days,
+        })
+        .unwrap();
+
+        let result = self
+            .client
+            .call_remote(
+                manager_address,
+                ManagerOperationType::GetVolumeHistory.into(),
+                0,
+                "",
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                let meta_data: GetVolumeHistoryRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                        .map_err(|_| SERIALIZATION_ERROR)?;
+                Ok(meta_data.days)
+            }
+            Err(e) => {
+                error!("get volume history failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // set_volume returns the warnings of the manager about the replicas of
+    // the volume.
+    pub async fn set_volume(
+        &self,
+        manager_address: &str,
+        update: &SetVolumeSendMetaData,
+    ) -> Result<Vec<String>, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 65535];
+
+        let send_meta_data = bincode::serialize(update).unwrap();
+
+        let result = self
+            .client
+            .call_remote(
+                manager_address,
+                ManagerOperationType::SetVolume.into(),
+                0,
+                "",
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("set volume failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn create_volume(
+        &self,
+        address: &str,
+        name: &str,
+        size: u64,
+        atime_policy: AtimePolicy,
+        public: bool,
+    ) -> Result<(), i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let send_meta_data = bincode::serialize(&CreateVolumeSendMetaData {
+            size,
+            atime_policy,
+            public,
+        })
+        .unwrap();
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::CreateVolume.into(),
+                0,
+                name,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("create volume failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn delete_volume(&self, address: &str, name: &str) -> Result<(), i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::DeleteVolume.into(),
+                0,
+                name,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("delete volume failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn clean_volume(&self, address: &str, name: &str) -> Result<(), i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::CleanVolume.into(),
+                0,
+                name,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                CONTROLL_REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                error!("clean volume failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn init_volume(&self, address: &str, name: &str) -> Result<Volume, i32> {
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 1024];
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::InitVolume.into(),
+                0,
+                name,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                match bincode::deserialize(&recv_meta_data[..recv_meta_data_length]) {
+                    Ok(volume) => Ok(volume),
+                    Err(e) => {
+                        error!("init volume failed: {:?}", e);
+                        Err(SERIALIZATION_ERROR)
+                    }
+                }
+            }
+            Err(e) => {
+                error!("init volume failed: {:?}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn create_no_parent(
+        &self,
+        address: &str,
+        operation_type: OperationType,
+        parent: &str,
+        send_meta_data: &[u8],
+    ) -> Result<Vec<u8>, i32> {
+        let _credit = self.credits.acquire(address, &parent_dir(parent)).await;
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 1024];
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                operation_type.into(),
+                0,
+                parent,
+                send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(recv_meta_data[..recv_meta_data_length].to_vec())
+                }
+            }
+            Err(e) => {
+                error!("create file failed with error: {}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn delete_no_parent(
+        &self,
+        address: &str,
+        operation_type: OperationType,
+        parent: &str,
+        send_meta_data: &[u8],
+    ) -> Result<(), i32> {
+        let _credit = self.credits.acquire(address, &parent_dir(parent)).await;
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let mut recv_meta_data = vec![0u8; 1024];
+
+        let result = self
+            .client
+            .call_remote(
+                address,
+                operation_type.into(),
+                0,
+                parent,
+                send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                error!("create file failed with error: {}", e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn directory_add_entry(
+        &self,
+        address: &str,
+        path: &str,
+        send_meta_data: &[u8],
+    ) -> Result<(), i32> {
+        let _credit = self.credits.acquire(address, path).await;
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::DirectoryAddEntry as u32,
+                0,
+                path,
+                send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(())
+                }
+            }
+            e => {
+                error!("Create file: DirectoryAddEntry failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn directory_delete_entry(
+        &self,
+        address: &str,
+        path: &str,
+        send_meta_data: &[u8],
+    ) -> Result<(), i32> {
+        let _credit = self.credits.acquire(address, path).await;
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::DirectoryDeleteEntry as u32,
+                0,
+                path,
+                send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(())
+                }
+            }
+            e => {
+                error!("Create file: DirectoryAddEntry failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn get_file_attr(&self, address: &str, path: &str) -> Result<FileAttr, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let mut file_attr = Box::new(empty_file());
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::GetFileAttr.into(),
+                0,
+                path,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                file_attr_as_bytes_mut(&mut file_attr),
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(*file_attr)
+                }
+            }
+            Err(e) => {
+                error!("get file attr failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // batch_get_file_attr gets the attrs of paths from the server at address
+    // in one request, with the status of every path. The request carries the
+    // first path for a cache node to forward it.
+    pub async fn batch_get_file_attr(
+        &self,
+        address: &str,
+        paths: &[String],
+    ) -> Result<Vec<Result<FileAttr, i32>>, i32> {
+        if paths.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&BatchGetAttrSendMetaData {
+            count: paths.len() as u32,
+        })
+        .unwrap();
+        let send_data = bincode::serialize(paths).unwrap();
+        let mut recv_data = vec![0u8; paths.len() * BATCH_ATTR_SIZE];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::BatchGetAttr.into(),
+                0,
+                &paths[0],
+                &send_meta_data,
+                &send_data,
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                let attrs = bytes_as_batch_attrs(&recv_data[..recv_data_length])?;
+                match attrs.len() == paths.len() {
+                    true => Ok(attrs),
+                    false => Err(SERIALIZATION_ERROR),
+                }
+            }
+            Err(e) => {
+                error!("batch get file attr failed: {} ,{:?}", paths[0], e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // grep_file returns a page of the matches of md.pattern in the file, of at
+    // most md.size bytes, and where the next page starts.
+    pub async fn grep_file(
+        &self,
+        address: &str,
+        path: &str,
+        md: &GrepSendMetaData,
+    ) -> Result<(GrepRecvMetaData, Vec<GrepMatch>), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(md).unwrap();
+        let mut recv_meta_data = vec![0u8; 1024];
+        let mut recv_data = vec![0u8; md.size as usize];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::Grep.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                let recv_md: GrepRecvMetaData =
+                    bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                        .map_err(|_| SERIALIZATION_ERROR)?;
+                let matches: Vec<GrepMatch> = bincode::deserialize(&recv_data[..recv_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)?;
+                Ok((recv_md, matches))
+            }
+            Err(e) => {
+                error!("grep file failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn write_file(
+        &self,
+        address: &str,
+        path: &str,
+        offset: i64,
+        epoch: u64,
+        version: u64,
+        data: &[u8],
+    ) -> Result<u64, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&WriteFileSendMetaData {
+            offset,
+            epoch,
+            version,
+        })
+        .unwrap();
+        let mut recv_meta_data = vec![0u8; std::mem::size_of::<u64>()];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::WriteFile.into(),
+                0,
+                path,
+                &send_meta_data,
+                data,
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("write file failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // append_file writes data at the end of path, and returns where and how
+    // much of it was written.
+    pub async fn append_file(
+        &self,
+        address: &str,
+        path: &str,
+        epoch: u64,
+        data: &[u8],
+    ) -> Result<AppendFileRecvMetaData, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&AppendFileSendMetaData { epoch }).unwrap();
+        let mut recv_meta_data = vec![0u8; 16];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::AppendFile.into(),
+                0,
+                path,
+                &send_meta_data,
+                data,
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("append file failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn read_file(
+        &self,
+        address: &str,
+        path: &str,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<u8>, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&ReadFileSendMetaData { offset, size }).unwrap();
+        let mut recv_data = vec![0u8; size as usize];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::ReadFile.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                recv_data.truncate(recv_data_length);
+                Ok(recv_data)
+            }
+            Err(e) => {
+                error!("read file failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // read_dir returns the (type, d_off, name) of the entries of a directory
+    // after the cursor offset, as many as fit in size bytes.
+    pub async fn read_dir(
+        &self,
+        address: &str,
+        path: &str,
+        offset: i64,
+        size: u32,
+    ) -> Result<Vec<(u8, i64, String)>, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&ReadDirSendMetaData { offset, size }).unwrap();
+        let mut recv_data = vec![0u8; size as usize];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::ReadDir.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bytes_as_dir_entries(&recv_data[..recv_data_length])?
+                    .into_iter()
+                    .map(|(r#type, d_off, name)| {
+                        String::from_utf8(name.to_vec())
+                            .map(|name| (r#type, d_off, name))
+                            .map_err(|_| SERIALIZATION_ERROR)
+                    })
+                    .collect()
+            }
+            Err(e) => {
+                error!("read dir failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // prefetch_file makes the owner of path read it ahead, and returns its size.
+    pub async fn prefetch_file(&self, address: &str, path: &str) -> Result<u64, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let mut recv_meta_data = vec![0u8; 8];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::Prefetch.into(),
+                0,
+                path,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("prefetch file failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // list_tree returns a page of the (path, attr) pairs under path stored on
+    // the server at address, after start_after.
+    pub async fn list_tree(
+        &self,
+        address: &str,
+        path: &str,
+        start_after: &str,
+        size: u32,
+    ) -> Result<Vec<(String, FileAttr)>, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&ListTreeSendMetaData {
+            start_after: start_after.to_owned(),
+            size,
+        })
+        .unwrap();
+        let mut recv_data = vec![0u8; size as usize];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::ListTree.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bytes_as_tree_entries(&recv_data[..recv_data_length])
+            }
+            Err(e) => {
+                error!("list tree failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // set_attr_tree changes a batch of path and the paths under it stored on
+    // the server at address, see SetAttrTreeSendMetaData.
+    pub async fn set_attr_tree(
+        &self,
+        address: &str,
+        path: &str,
+        md: &SetAttrTreeSendMetaData,
+    ) -> Result<SetAttrTreeRecvMetaData, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(md).unwrap();
+        let mut recv_meta_data = vec![0u8; 8192];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::SetAttrTree.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| libc::EIO)
+            }
+            Err(e) => {
+                error!("set attr tree failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // dedup_scan counts the duplicate chunks of the files under path stored
+    // on the server at address, see server/dedup.rs.
+    pub async fn dedup_scan(
+        &self,
+        address: &str,
+        path: &str,
+        md: &DedupScanSendMetaData,
+    ) -> Result<DedupScanRecvMetaData, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(md).unwrap();
+        let mut recv_meta_data = vec![0u8; 1024];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::DedupScan.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                DEDUP_SCAN_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| libc::EIO)
+            }
+            Err(e) => {
+                error!("dedup scan failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // create_file creates the file name in the directory parent, whose owner
+    // is at address, as an append-only log with append_only.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_file(
+        &self,
+        address: &str,
+        parent: &str,
+        name: &str,
+        mode: u32,
+        flags: i32,
+        size_hint: u64,
+        append_only: bool,
+    ) -> Result<FileAttr, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&CreateFileSendMetaData {
+            mode,
+            umask: 0,
+            flags,
+            name: name.to_owned(),
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            size_hint,
+            append_only,
+        })
+        .unwrap();
+        let mut file_attr = Box::new(empty_file());
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::CreateFile.into(),
+                0,
+                parent,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                file_attr_as_bytes_mut(&mut file_attr),
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(*file_attr)
+                }
+            }
+            Err(e) => {
+                error!("create file failed: {}/{} ,{:?}", parent, name, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // link_temp_file publishes the unnamed file temp_path as name in the
+    // directory parent, whose owner is at address.
+    pub async fn link_temp_file(
+        &self,
+        address: &str,
+        parent: &str,
+        name: &str,
+        temp_path: &str,
+    ) -> Result<FileAttr, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&LinkTempFileSendMetaData {
+            name: name.to_owned(),
+            temp_path: temp_path.to_owned(),
+        })
+        .unwrap();
+        let mut file_attr = Box::new(empty_file());
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::LinkTempFile.into(),
+                0,
+                parent,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                file_attr_as_bytes_mut(&mut file_attr),
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(*file_attr)
+                }
+            }
+            Err(e) => {
+                error!("link temp file failed: {}/{} ,{:?}", parent, name, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // create_symlink adds the symbolic link name to target in the directory
+    // parent, whose owner is at address.
+    pub async fn create_symlink(
+        &self,
+        address: &str,
+        parent: &str,
+        name: &str,
+        target: &str,
+        owner: (u32, u32),
+    ) -> Result<FileAttr, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&CreateSymlinkSendMetaData {
+            name: name.to_owned(),
+            target: target.to_owned(),
+            uid: owner.0,
+            gid: owner.1,
+        })
+        .unwrap();
+        let mut file_attr = Box::new(empty_file());
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::CreateSymlink.into(),
+                0,
+                parent,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                file_attr_as_bytes_mut(&mut file_attr),
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(*file_attr)
+                }
+            }
+            Err(e) => {
+                error!("create symlink failed: {}/{} ,{:?}", parent, name, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // set_attr changes the attrs of path set in md, on the server at address,
+    // and returns the new attr.
+    pub async fn set_attr(
+        &self,
+        address: &str,
+        path: &str,
+        md: &SetAttrSendMetaData,
+    ) -> Result<FileAttr, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(md).unwrap();
+        let mut file_attr = Box::new(empty_file());
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::SetAttr.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                file_attr_as_bytes_mut(&mut file_attr),
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(*file_attr)
+                }
+            }
+            Err(e) => {
+                error!("set attr failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // link adds name in the directory parent, whose owner is at address, as a
+    // hard link to the file target.
+    pub async fn link(
+        &self,
+        address: &str,
+        parent: &str,
+        name: &str,
+        target: &str,
+    ) -> Result<FileAttr, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&LinkSendMetaData {
+            name: name.to_owned(),
+            target: target.to_owned(),
+            moved: false,
+        })
+        .unwrap();
+        let mut file_attr = Box::new(empty_file());
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::Link.into(),
+                0,
+                parent,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                file_attr_as_bytes_mut(&mut file_attr),
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(*file_attr)
+                }
+            }
+            Err(e) => {
+                error!("link failed: {}/{} ,{:?}", parent, name, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // add_link adds delta to the links of the file path on the server at
+    // address, which answers the attr of the file and the path of its links.
+    pub async fn add_link(
+        &self,
+        address: &str,
+        path: &str,
+        delta: i32,
+        linked: bool,
+    ) -> Result<AddLinkRecvMetaData, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&AddLinkSendMetaData { delta, linked }).unwrap();
+        let mut recv_meta_data = vec![0u8; 8192];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::AddLink.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("add link failed: {}, {:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // rename sends a Rename, a RenameAt or a RenameNoParent on path to the
+    // server at address.
+    pub async fn rename(
+        &self,
+        address: &str,
+        operation_type: OperationType,
+        path: &str,
+        send_meta_data: &[u8],
+    ) -> Result<(), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let result = self
+            .client
+            .call_remote(
+                address,
+                operation_type.into(),
+                0,
+                path,
+                send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                RENAME_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                error!("rename failed: {}, {:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // fsync makes the data of path on the server at address durable.
+    pub async fn fsync(&self, address: &str, path: &str, datasync: bool) -> Result<(), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&FsyncSendMetaData { datasync }).unwrap();
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::Fsync.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                FSYNC_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                error!("fsync failed: {}, {:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // seek returns where the data, or the hole with hole, starts at or after
+    // offset in path on the server at address.
+    pub async fn seek(
+        &self,
+        address: &str,
+        path: &str,
+        offset: u64,
+        hole: bool,
+    ) -> Result<u64, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&SeekSendMetaData { offset, hole }).unwrap();
+        let mut recv_meta_data = vec![0u8; 8];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::Seek.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("seek failed: {}, {:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // lock sends a Lock, Unlock or TestLock of path to the server at address,
+    // and returns the lock answered by TestLock.
+    pub async fn lock(
+        &self,
+        address: &str,
+        operation_type: OperationType,
+        path: &str,
+        md: &LockSendMetaData,
+    ) -> Result<Option<LockRecvMetaData>, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(md).unwrap();
+        let mut recv_meta_data = vec![0u8; 64];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                operation_type.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
                 &mut [],
                 REQUEST_TIMEOUT,
             )
             .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                if recv_meta_data_length == 0 {
+                    return Ok(None);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map(Some)
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("lock failed: {}, {:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // shard_dir splits the entries of the empty directory path into shards,
+    // the directory being on the server at address.
+    pub async fn shard_dir(&self, address: &str, path: &str, shards: u32) -> Result<(), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data = bincode::serialize(&ShardDirSendMetaData { shards }).unwrap();
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::ShardDir.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                CONTROLL_REQUEST_TIMEOUT,
+            )
+            .await;
         match result {
             Ok(_) => {
                 if status != 0 {
@@ -649,8 +2594,129 @@ impl Sender {
                     Ok(())
                 }
             }
-            e => {
-                error!("Create file: DirectoryAddEntry failed: {} ,{:?}", path, e);
+            Err(e) => {
+                error!("shard dir failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    // begin_upload starts a multipart upload of path and returns its id.
+    pub async fn begin_upload(&self, address: &str, path: &str) -> Result<u64, i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let mut recv_meta_data = vec![0u8; 8];
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::BeginUpload.into(),
+                0,
+                path,
+                &[],
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => {
+                if status != 0 {
+                    return Err(status);
+                }
+                bincode::deserialize(&recv_meta_data[..recv_meta_data_length])
+                    .map_err(|_| SERIALIZATION_ERROR)
+            }
+            Err(e) => {
+                error!("begin upload failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn upload_part(
+        &self,
+        address: &str,
+        path: &str,
+        upload_id: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data =
+            bincode::serialize(&UploadPartSendMetaData { upload_id, offset }).unwrap();
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::UploadPart.into(),
+                0,
+                path,
+                &send_meta_data,
+                data,
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => match status {
+                0 => Ok(()),
+                _ => Err(status),
+            },
+            Err(e) => {
+                error!("upload part failed: {} ,{:?}", path, e);
+                Err(CONNECTION_ERROR)
+            }
+        }
+    }
+
+    pub async fn complete_upload(
+        &self,
+        address: &str,
+        path: &str,
+        upload_id: u64,
+        size: u64,
+    ) -> Result<(), i32> {
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let send_meta_data =
+            bincode::serialize(&CompleteUploadSendMetaData { upload_id, size }).unwrap();
+        let result = self
+            .client
+            .call_remote(
+                address,
+                OperationType::CompleteUpload.into(),
+                0,
+                path,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        match result {
+            Ok(_) => match status {
+                0 => Ok(()),
+                _ => Err(status),
+            },
+            Err(e) => {
+                error!("complete upload failed: {} ,{:?}", path, e);
                 Err(CONNECTION_ERROR)
             }
         }
@@ -8,10 +8,17 @@ use libc::{
 };
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Formatter};
+
+use super::{
+    errors::SERIALIZATION_ERROR,
+    hash_ring::{FailureDomain, HashAlgorithm, HashRingInfo, HashRingSnapshot, ServerGroups},
+    util::empty_file,
+};
 use std::{
     collections::BTreeMap,
     fmt::Display,
-    time::{SystemTime, UNIX_EPOCH},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[macro_export]
@@ -23,6 +30,7 @@ macro_rules! offset_of {
     };
 }
 
+#[derive(Debug)]
 pub enum OperationType {
     Unkown = 0,
     Lookup = 1,
@@ -49,6 +57,36 @@ pub enum OperationType {
     ListVolumes = 22,
     DeleteVolume = 23,
     CleanVolume = 24,
+    Prefetch = 25,
+    ListTree = 26,
+    BeginUpload = 27,
+    UploadPart = 28,
+    CompleteUpload = 29,
+    LinkTempFile = 30,
+    ShardDir = 31,
+    BatchGetAttr = 32,
+    Grep = 33,
+    Health = 34,
+    OpenLocal = 35,
+    SetAttrTree = 36,
+    GetStatFs = 37,
+    CreateSymlink = 38,
+    CreateSymlinkNoParent = 39,
+    DedupScan = 40,
+    Link = 41,
+    LinkNoParent = 42,
+    SetAttr = 43,
+    TransferStatus = 44,
+    Rename = 45,
+    RenameAt = 46,
+    RenameNoParent = 47,
+    Fsync = 48,
+    Seek = 49,
+    Lock = 50,
+    Unlock = 51,
+    TestLock = 52,
+    AppendFile = 53,
+    AddLink = 54,
 }
 
 impl TryFrom<u32> for OperationType {
@@ -81,6 +119,36 @@ impl TryFrom<u32> for OperationType {
             22 => Ok(OperationType::ListVolumes),
             23 => Ok(OperationType::DeleteVolume),
             24 => Ok(OperationType::CleanVolume),
+            25 => Ok(OperationType::Prefetch),
+            26 => Ok(OperationType::ListTree),
+            27 => Ok(OperationType::BeginUpload),
+            28 => Ok(OperationType::UploadPart),
+            29 => Ok(OperationType::CompleteUpload),
+            30 => Ok(OperationType::LinkTempFile),
+            31 => Ok(OperationType::ShardDir),
+            32 => Ok(OperationType::BatchGetAttr),
+            33 => Ok(OperationType::Grep),
+            34 => Ok(OperationType::Health),
+            35 => Ok(OperationType::OpenLocal),
+            36 => Ok(OperationType::SetAttrTree),
+            37 => Ok(OperationType::GetStatFs),
+            38 => Ok(OperationType::CreateSymlink),
+            39 => Ok(OperationType::CreateSymlinkNoParent),
+            40 => Ok(OperationType::DedupScan),
+            41 => Ok(OperationType::Link),
+            42 => Ok(OperationType::LinkNoParent),
+            43 => Ok(OperationType::SetAttr),
+            44 => Ok(OperationType::TransferStatus),
+            45 => Ok(OperationType::Rename),
+            46 => Ok(OperationType::RenameAt),
+            47 => Ok(OperationType::RenameNoParent),
+            48 => Ok(OperationType::Fsync),
+            49 => Ok(OperationType::Seek),
+            50 => Ok(OperationType::Lock),
+            51 => Ok(OperationType::Unlock),
+            52 => Ok(OperationType::TestLock),
+            53 => Ok(OperationType::AppendFile),
+            54 => Ok(OperationType::AddLink),
             _ => panic!("Unkown value: {}", value),
         }
     }
@@ -114,10 +182,41 @@ impl From<OperationType> for u32 {
             OperationType::ListVolumes => 22,
             OperationType::DeleteVolume => 23,
             OperationType::CleanVolume => 24,
+            OperationType::Prefetch => 25,
+            OperationType::ListTree => 26,
+            OperationType::BeginUpload => 27,
+            OperationType::UploadPart => 28,
+            OperationType::CompleteUpload => 29,
+            OperationType::LinkTempFile => 30,
+            OperationType::ShardDir => 31,
+            OperationType::BatchGetAttr => 32,
+            OperationType::Grep => 33,
+            OperationType::Health => 34,
+            OperationType::OpenLocal => 35,
+            OperationType::SetAttrTree => 36,
+            OperationType::GetStatFs => 37,
+            OperationType::CreateSymlink => 38,
+            OperationType::CreateSymlinkNoParent => 39,
+            OperationType::DedupScan => 40,
+            OperationType::Link => 41,
+            OperationType::LinkNoParent => 42,
+            OperationType::SetAttr => 43,
+            OperationType::TransferStatus => 44,
+            OperationType::Rename => 45,
+            OperationType::RenameAt => 46,
+            OperationType::RenameNoParent => 47,
+            OperationType::Fsync => 48,
+            OperationType::Seek => 49,
+            OperationType::Lock => 50,
+            OperationType::Unlock => 51,
+            OperationType::TestLock => 52,
+            OperationType::AppendFile => 53,
+            OperationType::AddLink => 54,
         }
     }
 }
 
+#[derive(Debug)]
 pub enum ManagerOperationType {
     GetClusterStatus = 103,
     GetHashRing = 104,
@@ -126,6 +225,24 @@ pub enum ManagerOperationType {
     RemoveNodes = 107,
     UpdateServerStatus = 108,
     FinishServer = 109,
+    SetWeight = 110,
+    SetMaintenance = 111,
+    GetMaintenance = 112,
+    ReserveVolume = 113,
+    CommitVolume = 114,
+    ReleaseVolume = 115,
+    ListVolumeInfos = 116,
+    SetVolume = 117,
+    SetServerGroup = 118,
+    PinVolume = 119,
+    SetServerDomain = 120,
+    GetMembershipChanges = 121,
+    Heartbeat = 122,
+    GetVolumeHistory = 123,
+    Health = 124,
+    GetHashRingSnapshot = 125,
+    RecordHandoff = 126,
+    GetHandoffs = 127,
 }
 
 impl TryFrom<u32> for ManagerOperationType {
@@ -140,6 +257,24 @@ impl TryFrom<u32> for ManagerOperationType {
             107 => Ok(ManagerOperationType::RemoveNodes),
             108 => Ok(ManagerOperationType::UpdateServerStatus),
             109 => Ok(ManagerOperationType::FinishServer),
+            110 => Ok(ManagerOperationType::SetWeight),
+            111 => Ok(ManagerOperationType::SetMaintenance),
+            112 => Ok(ManagerOperationType::GetMaintenance),
+            113 => Ok(ManagerOperationType::ReserveVolume),
+            114 => Ok(ManagerOperationType::CommitVolume),
+            115 => Ok(ManagerOperationType::ReleaseVolume),
+            116 => Ok(ManagerOperationType::ListVolumeInfos),
+            117 => Ok(ManagerOperationType::SetVolume),
+            118 => Ok(ManagerOperationType::SetServerGroup),
+            119 => Ok(ManagerOperationType::PinVolume),
+            120 => Ok(ManagerOperationType::SetServerDomain),
+            121 => Ok(ManagerOperationType::GetMembershipChanges),
+            122 => Ok(ManagerOperationType::Heartbeat),
+            123 => Ok(ManagerOperationType::GetVolumeHistory),
+            124 => Ok(ManagerOperationType::Health),
+            125 => Ok(ManagerOperationType::GetHashRingSnapshot),
+            126 => Ok(ManagerOperationType::RecordHandoff),
+            127 => Ok(ManagerOperationType::GetHandoffs),
             _ => panic!("Unkown value: {}", value),
         }
     }
@@ -155,6 +290,24 @@ impl From<ManagerOperationType> for u32 {
             ManagerOperationType::RemoveNodes => 107,
             ManagerOperationType::UpdateServerStatus => 108,
             ManagerOperationType::FinishServer => 109,
+            ManagerOperationType::SetWeight => 110,
+            ManagerOperationType::SetMaintenance => 111,
+            ManagerOperationType::GetMaintenance => 112,
+            ManagerOperationType::ReserveVolume => 113,
+            ManagerOperationType::CommitVolume => 114,
+            ManagerOperationType::ReleaseVolume => 115,
+            ManagerOperationType::ListVolumeInfos => 116,
+            ManagerOperationType::SetVolume => 117,
+            ManagerOperationType::SetServerGroup => 118,
+            ManagerOperationType::PinVolume => 119,
+            ManagerOperationType::SetServerDomain => 120,
+            ManagerOperationType::GetMembershipChanges => 121,
+            ManagerOperationType::Heartbeat => 122,
+            ManagerOperationType::GetVolumeHistory => 123,
+            ManagerOperationType::Health => 124,
+            ManagerOperationType::GetHashRingSnapshot => 125,
+            ManagerOperationType::RecordHandoff => 126,
+            ManagerOperationType::GetHandoffs => 127,
         }
     }
 }
@@ -169,6 +322,24 @@ impl ManagerOperationType {
             ManagerOperationType::RemoveNodes => 107u32.to_le_bytes(),
             ManagerOperationType::UpdateServerStatus => 108u32.to_le_bytes(),
             ManagerOperationType::FinishServer => 109u32.to_le_bytes(),
+            ManagerOperationType::SetWeight => 110u32.to_le_bytes(),
+            ManagerOperationType::SetMaintenance => 111u32.to_le_bytes(),
+            ManagerOperationType::GetMaintenance => 112u32.to_le_bytes(),
+            ManagerOperationType::ReserveVolume => 113u32.to_le_bytes(),
+            ManagerOperationType::CommitVolume => 114u32.to_le_bytes(),
+            ManagerOperationType::ReleaseVolume => 115u32.to_le_bytes(),
+            ManagerOperationType::ListVolumeInfos => 116u32.to_le_bytes(),
+            ManagerOperationType::SetVolume => 117u32.to_le_bytes(),
+            ManagerOperationType::SetServerGroup => 118u32.to_le_bytes(),
+            ManagerOperationType::PinVolume => 119u32.to_le_bytes(),
+            ManagerOperationType::SetServerDomain => 120u32.to_le_bytes(),
+            ManagerOperationType::GetMembershipChanges => 121u32.to_le_bytes(),
+            ManagerOperationType::Heartbeat => 122u32.to_le_bytes(),
+            ManagerOperationType::GetVolumeHistory => 123u32.to_le_bytes(),
+            ManagerOperationType::Health => 124u32.to_le_bytes(),
+            ManagerOperationType::GetHashRingSnapshot => 125u32.to_le_bytes(),
+            ManagerOperationType::RecordHandoff => 126u32.to_le_bytes(),
+            ManagerOperationType::GetHandoffs => 127u32.to_le_bytes(),
         }
     }
 }
@@ -471,6 +642,22 @@ impl From<FileTypeSimple> for u8 {
     }
 }
 
+// set in FileAttr.flags of an append-only file, see FileLayout.append_only.
+pub const FILE_FLAG_APPEND_ONLY: u32 = 1;
+
+// set in FileAttr.flags of a file known by its hard links only, see
+// DistributedEngine::add_link.
+pub const FILE_FLAG_LINKED: u32 = 2;
+
+// file_version returns the version of a file, kept in the ino of its attr
+// since the servers do not use it otherwise. It starts at 1 and is
+// incremented by every change of the file but its atime, so clients can
+// check their caches against it. Clients replace the ino of the attrs with
+// their inode numbers, they must read the version before.
+pub fn file_version(attr: &FileAttr) -> u64 {
+    attr.ino
+}
+
 pub fn file_attr_as_bytes(attr: &FileAttr) -> &[u8] {
     unsafe {
         let ptr = attr as *const FileAttr as *const u8;
@@ -499,6 +686,102 @@ pub fn bytes_as_file_attr_mut(bytes: &mut [u8]) -> &mut FileAttr {
     }
 }
 
+// the size of a record of a BatchGetAttr response: the status of the path,
+// 4 bytes, and its attr, zeroed if the status is not 0.
+pub const BATCH_ATTR_SIZE: usize = 4 + std::mem::size_of::<FileAttr>();
+
+pub fn push_batch_attr(data: &mut Vec<u8>, attr: Result<&[u8], i32>) {
+    match attr {
+        Ok(attr) => {
+            data.extend_from_slice(&0i32.to_le_bytes());
+            data.extend_from_slice(attr);
+        }
+        Err(e) => {
+            data.extend_from_slice(&e.to_le_bytes());
+            data.resize(data.len() + std::mem::size_of::<FileAttr>(), 0);
+        }
+    }
+}
+
+// bytes_as_batch_attrs decodes the records of a BatchGetAttr response.
+pub fn bytes_as_batch_attrs(bytes: &[u8]) -> Result<Vec<Result<FileAttr, i32>>, i32> {
+    if bytes.len() % BATCH_ATTR_SIZE != 0 {
+        return Err(SERIALIZATION_ERROR);
+    }
+    Ok(bytes
+        .chunks(BATCH_ATTR_SIZE)
+        .map(
+            |record| match i32::from_le_bytes(record[..4].try_into().unwrap()) {
+                0 => {
+                    let mut attr = empty_file();
+                    file_attr_as_bytes_mut(&mut attr).copy_from_slice(&record[4..]);
+                    Ok(attr)
+                }
+                e => Err(e),
+            },
+        )
+        .collect())
+}
+
+// a ReadDir response is a list of (type: u8, d_off: i64, name_len: u16,
+// name) records, d_off the cursor to read the entries after the record from.
+pub const DIR_ENTRY_HEADER_SIZE: usize = 11;
+
+pub fn put_dir_entry(bytes: &mut Vec<u8>, r#type: u8, d_off: i64, name: &[u8]) {
+    bytes.push(r#type);
+    bytes.extend_from_slice(&d_off.to_le_bytes());
+    bytes.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(name);
+}
+
+// the (type, d_off, name) of a directory entry.
+pub type DirEntry<'a> = (u8, i64, &'a [u8]);
+
+// bytes_as_dir_entries decodes the records of a ReadDir response.
+pub fn bytes_as_dir_entries(mut bytes: &[u8]) -> Result<Vec<DirEntry<'_>>, i32> {
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < DIR_ENTRY_HEADER_SIZE {
+            return Err(SERIALIZATION_ERROR);
+        }
+        let d_off = i64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let name_len = u16::from_le_bytes([bytes[9], bytes[10]]) as usize;
+        if bytes.len() < DIR_ENTRY_HEADER_SIZE + name_len {
+            return Err(SERIALIZATION_ERROR);
+        }
+        entries.push((
+            bytes[0],
+            d_off,
+            &bytes[DIR_ENTRY_HEADER_SIZE..DIR_ENTRY_HEADER_SIZE + name_len],
+        ));
+        bytes = &bytes[DIR_ENTRY_HEADER_SIZE + name_len..];
+    }
+    Ok(entries)
+}
+
+// bytes_as_tree_entries decodes the (path, attr) records of a ListTree response.
+pub fn bytes_as_tree_entries(mut bytes: &[u8]) -> Result<Vec<(String, FileAttr)>, i32> {
+    let attr_size = std::mem::size_of::<FileAttr>();
+    let mut entries = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 2 {
+            return Err(SERIALIZATION_ERROR);
+        }
+        let path_len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        if bytes.len() < 2 + path_len + attr_size {
+            return Err(SERIALIZATION_ERROR);
+        }
+        let path =
+            String::from_utf8(bytes[2..2 + path_len].to_vec()).map_err(|_| SERIALIZATION_ERROR)?;
+        let mut attr = empty_file();
+        file_attr_as_bytes_mut(&mut attr)
+            .copy_from_slice(&bytes[2 + path_len..2 + path_len + attr_size]);
+        entries.push((path, attr));
+        bytes = &bytes[2 + path_len + attr_size..];
+    }
+    Ok(entries)
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct FileAttrSimple {
     pub size: u64,
@@ -780,6 +1063,27 @@ pub struct LinuxDirent {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct WriteFileSendMetaData {
     pub offset: i64,
+    // the epoch of the hash ring the write was routed with, 0 for the writes
+    // of the servers, which are not fenced.
+    pub epoch: u64,
+    // the write is refused with VERSION_MISMATCH unless the file is at this
+    // version, 0 to write whatever it is.
+    pub version: u64,
+}
+
+// the data of AppendFile is written at the end of the file, chosen by its
+// server.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct AppendFileSendMetaData {
+    // as in WriteFileSendMetaData.
+    pub epoch: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct AppendFileRecvMetaData {
+    // where the data was written, and how many bytes of it.
+    pub offset: u64,
+    pub size: u64,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -791,6 +1095,24 @@ pub struct DirectoryEntrySendMetaData {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct TruncateFileSendMetaData {
     pub length: i64,
+    // the epoch of the hash ring the truncate was routed with, see
+    // WriteFileSendMetaData.
+    pub epoch: u64,
+}
+
+// SetAttr changes the attrs of a file which are set, for chmod, chown,
+// truncate and utimens.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct SetAttrSendMetaData {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+    // the epoch of the hash ring the request was routed with, checked when
+    // the size changes, see WriteFileSendMetaData.
+    pub epoch: u64,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -799,6 +1121,263 @@ pub struct ReadDirSendMetaData {
     pub size: u32,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ListTreeSendMetaData {
+    // the last path of the previous page, empty for the first page.
+    pub start_after: String,
+    pub size: u32,
+}
+
+// SetAttrTree changes the permissions and the owner of a path and of the
+// files and directories under it stored on a server, batch by batch.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SetAttrTreeSendMetaData {
+    // the new permissions and owner, None to keep them.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // the last path of the previous batch, empty for the first batch.
+    pub start_after: String,
+    // the paths of a batch at most.
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SetAttrTreeRecvMetaData {
+    pub changed: u32,
+    // the last path of the batch, empty when the tree is done.
+    pub last: String,
+}
+
+// DedupScan hashes the chunks of the files under a path stored on a server
+// and counts the duplicates, see server/dedup.rs.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DedupScanSendMetaData {
+    // share the extents of the duplicate chunks, on the file systems with reflink.
+    pub apply: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone, Copy)]
+pub struct DedupScanRecvMetaData {
+    pub files: u64,
+    pub bytes: u64,
+    pub chunks: u64,
+    // the chunks with the content of an earlier chunk, and their bytes.
+    pub duplicate_chunks: u64,
+    pub duplicate_bytes: u64,
+    // the bytes of the duplicates whose extents are shared now.
+    pub shared_bytes: u64,
+    // the file system of the data, or the storage engine, cannot share extents.
+    pub unsupported: bool,
+}
+
+// the state of a file moved to its new owner by a rebalance.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum TransferState {
+    #[default]
+    Pending,
+    InFlight,
+    Done,
+    Failed,
+}
+
+impl Display for TransferState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pending => write!(f, "pending"),
+            Self::InFlight => write!(f, "in flight"),
+            Self::Done => write!(f, "done"),
+            Self::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FileTransfer {
+    pub state: TransferState,
+    // the bytes of the data sent by the last attempt.
+    pub bytes: u64,
+    pub failures: u32,
+    // the status of the last failure.
+    pub error: i32,
+}
+
+// TransferStatus returns the transfers of the last rebalance of a server,
+// the files pending or done are only counted.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone)]
+pub struct TransferStatusRecvMetaData {
+    pub pending: u64,
+    pub in_flight: u64,
+    pub done: u64,
+    pub failed: u64,
+    pub bytes: u64,
+    pub files: Vec<(String, FileTransfer)>,
+}
+
+// the bytes of the disk of the data of a server, 0 when its engine does not
+// know them.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default, Clone, Copy)]
+pub struct StatFsRecvMetaData {
+    pub total: u64,
+    pub free: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct UploadPartSendMetaData {
+    pub upload_id: u64,
+    pub offset: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct CompleteUploadSendMetaData {
+    pub upload_id: u64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct LinkTempFileSendMetaData {
+    pub name: String,
+    pub temp_path: String,
+}
+
+// the symbolic link name to target, name is empty for CreateSymlinkNoParent.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct CreateSymlinkSendMetaData {
+    pub name: String,
+    pub target: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// Rename moves the entry name of a directory to new_name in new_parent, sent
+// to the server of the directory. flags takes RENAME_NOREPLACE.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RenameSendMetaData {
+    pub name: String,
+    pub new_parent: String,
+    pub new_name: String,
+    pub flags: u32,
+}
+
+// RenameAt adds the entry name for old_path moved there, sent by the server
+// of the old entry to the server of the new directory.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RenameAtSendMetaData {
+    pub name: String,
+    pub old_path: String,
+    pub flags: u32,
+}
+
+// RenameNoParent moves a file to new_path on the same server.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RenameNoParentSendMetaData {
+    pub new_path: String,
+}
+
+// datasync leaves out the metadata not needed to read the data back, like
+// fdatasync.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct FsyncSendMetaData {
+    pub datasync: bool,
+}
+
+// Seek finds the data at or after offset, or the hole with hole, like
+// SEEK_DATA and SEEK_HOLE.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SeekSendMetaData {
+    pub offset: u64,
+    pub hole: bool,
+}
+
+// a POSIX lock of type typ on the bytes start..=end of a file, held by the
+// lock owner owner of client, see server/posix_lock.rs. The type of Unlock
+// is F_UNLCK. A flock lock, with flock, covers the whole file and does not
+// conflict with the fcntl locks.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct LockSendMetaData {
+    pub client: String,
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+    pub flock: bool,
+}
+
+// the lock conflicting with the one of TestLock, of type F_UNLCK if none.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct LockRecvMetaData {
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+}
+
+// the hard link name to the file target, name is empty for LinkNoParent.
+// A moved link is handed over by a transfer, target is then the file itself
+// and its links are not counted again.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct LinkSendMetaData {
+    pub name: String,
+    pub target: String,
+    pub moved: bool,
+}
+
+// AddLink adds delta to the links of a file on its server. A linked file was
+// already moved to the path of its links, see DistributedEngine::add_link.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct AddLinkSendMetaData {
+    pub delta: i32,
+    pub linked: bool,
+}
+
+// the attr of the file after AddLink, and the path its links point to.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct AddLinkRecvMetaData {
+    pub file_attr: Vec<u8>,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ShardDirSendMetaData {
+    pub shards: u32,
+}
+
+// Grep looks for a pattern in a file on its server, and returns the matching
+// lines, or the offsets of the pattern, instead of the file.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct GrepSendMetaData {
+    pub pattern: Vec<u8>,
+    // match lines instead of bytes.
+    pub lines: bool,
+    // where to start scanning.
+    pub offset: u64,
+    // the bytes of matches in the response at most.
+    pub size: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct GrepRecvMetaData {
+    // where to go on scanning, the file was scanned to its end if done.
+    pub next_offset: u64,
+    pub done: bool,
+}
+
+// a GrepMatch is a matching line, or an offset of the pattern whose data
+// is empty. The matches are sent as a bincode Vec<GrepMatch> in the data.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct GrepMatch {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// the paths of a BatchGetAttr are sent as a bincode Vec<String> in the
+// data, as they may not fit in the metadata.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct BatchGetAttrSendMetaData {
+    pub count: u32,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct OpenFileSendMetaData {
     pub flags: i32,
@@ -811,6 +1390,13 @@ pub struct CreateFileSendMetaData {
     pub umask: u32,
     pub flags: i32,
     pub name: String,
+    // owner of the new file.
+    pub uid: u32,
+    pub gid: u32,
+    // expected size of the file, 0 if unknown; its space is reserved on create.
+    pub size_hint: u64,
+    // the file is an append-only log.
+    pub append_only: bool,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -822,6 +1408,9 @@ pub struct DeleteFileSendMetaData {
 pub struct CreateDirSendMetaData {
     pub mode: u32,
     pub name: String,
+    // owner of the new directory.
+    pub uid: u32,
+    pub gid: u32,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -841,7 +1430,59 @@ pub struct GetClusterStatusRecvMetaData {
 
 #[derive(Serialize, Deserialize, PartialEq)]
 pub struct GetHashRingInfoRecvMetaData {
+    pub hash_algorithm: HashAlgorithm,
     pub hash_ring_info: Vec<(String, usize)>,
+    pub groups: ServerGroups,
+    pub epoch: u64,
+}
+
+impl From<HashRingInfo> for GetHashRingInfoRecvMetaData {
+    fn from(info: HashRingInfo) -> Self {
+        Self {
+            hash_algorithm: info.algorithm,
+            hash_ring_info: info.servers,
+            groups: info.groups,
+            epoch: info.epoch,
+        }
+    }
+}
+
+impl From<GetHashRingInfoRecvMetaData> for HashRingInfo {
+    fn from(meta_data: GetHashRingInfoRecvMetaData) -> Self {
+        Self {
+            algorithm: meta_data.hash_algorithm,
+            servers: meta_data.hash_ring_info,
+            groups: meta_data.groups,
+            epoch: meta_data.epoch,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct GetHashRingSnapshotRecvMetaData {
+    pub status: ClusterStatus,
+    pub hash_ring: GetHashRingInfoRecvMetaData,
+    pub new_hash_ring: Option<GetHashRingInfoRecvMetaData>,
+}
+
+impl From<HashRingSnapshot> for GetHashRingSnapshotRecvMetaData {
+    fn from(snapshot: HashRingSnapshot) -> Self {
+        Self {
+            status: snapshot.status,
+            hash_ring: snapshot.hash_ring.into(),
+            new_hash_ring: snapshot.new_hash_ring.map(Into::into),
+        }
+    }
+}
+
+impl From<GetHashRingSnapshotRecvMetaData> for HashRingSnapshot {
+    fn from(meta_data: GetHashRingSnapshotRecvMetaData) -> Self {
+        Self {
+            status: meta_data.status,
+            hash_ring: meta_data.hash_ring.into(),
+            new_hash_ring: meta_data.new_hash_ring.map(Into::into),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq)]
@@ -854,9 +1495,188 @@ pub struct DeleteNodesSendMetaData {
     pub deleted_servers_info: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct SetWeightSendMetaData {
+    pub server_address: String,
+    pub weight: usize,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct SetMaintenanceSendMetaData {
+    pub server_address: String,
+    pub enter: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct SetServerGroupSendMetaData {
+    pub server_address: String,
+    // empty to take the server out of its group.
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct SetServerDomainSendMetaData {
+    pub server_address: String,
+    pub domain: FailureDomain,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct PinVolumeSendMetaData {
+    pub name: String,
+    // empty to unpin the volume.
+    pub group: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct GetMaintenanceRecvMetaData {
+    pub servers: Vec<String>,
+}
+
+// the identity a server keeps in its database directory, sent with its
+// heartbeats, and the usage of the volumes since the last one.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct HeartbeatSendMetaData {
+    pub id: String,
+    pub usage: Vec<VolumeUsage>,
+}
+
+// VolumeUsage counts the requests served for a volume by a server.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct VolumeUsage {
+    pub volume: String,
+    pub ops: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub files_created: u64,
+    pub files_deleted: u64,
+}
+
+// VolumeDay is the usage of a volume over a day, kept by the manager.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Default)]
+pub struct VolumeDay {
+    // days since the epoch, in UTC.
+    pub day: u64,
+    pub ops: u64,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+    pub files_created: u64,
+    pub files_deleted: u64,
+}
+
+impl VolumeDay {
+    pub fn add(&mut self, usage: &VolumeUsage) {
+        self.ops += usage.ops;
+        self.read_bytes += usage.read_bytes;
+        self.write_bytes += usage.write_bytes;
+        self.files_created += usage.files_created;
+        self.files_deleted += usage.files_deleted;
+    }
+
+    // date returns the day as yyyy-mm-dd.
+    pub fn date(&self) -> String {
+        // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+        let z = self.day as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let d = doy - (153 * mp + 2) / 5 + 1;
+        let m = if mp < 10 { mp + 3 } else { mp - 9 };
+        let y = yoe + era * 400 + (m <= 2) as i64;
+        format!("{:04}-{:02}-{:02}", y, m, d)
+    }
+}
+
+// the file path was handed off by the server sending it, the path of the
+// request.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RecordHandoffSendMetaData {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct GetHandoffsRecvMetaData {
+    pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct GetVolumeHistorySendMetaData {
+    pub name: String,
+    pub days: u32,
+}
+
+// the days of a volume, the last one first.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct GetVolumeHistoryRecvMetaData {
+    pub days: Vec<VolumeDay>,
+}
+
+// MembershipChange is a change of the servers of the hash ring. The manager
+// queues the changes asked for while another one is running.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum MembershipChange {
+    AddNodes(Vec<(String, usize)>),
+    RemoveNodes(Vec<String>),
+    SetWeight(String, usize),
+    // an empty group takes the server out of its group.
+    SetServerGroup(String, String),
+}
+
+impl MembershipChange {
+    pub fn servers(&self) -> Vec<&str> {
+        match self {
+            MembershipChange::AddNodes(nodes) => nodes.iter().map(|(n, _)| n.as_str()).collect(),
+            MembershipChange::RemoveNodes(nodes) => nodes.iter().map(|n| n.as_str()).collect(),
+            MembershipChange::SetWeight(server, _)
+            | MembershipChange::SetServerGroup(server, _) => {
+                vec![server]
+            }
+        }
+    }
+}
+
+impl Display for MembershipChange {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            MembershipChange::AddNodes(nodes) => write!(f, "add {:?}", nodes),
+            MembershipChange::RemoveNodes(nodes) => write!(f, "remove {:?}", nodes),
+            MembershipChange::SetWeight(server, weight) => {
+                write!(f, "set weight of {} to {}", server, weight)
+            }
+            MembershipChange::SetServerGroup(server, group) => {
+                write!(f, "set group of {} to {:?}", server, group)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Default)]
+pub struct GetMembershipChangesRecvMetaData {
+    // the changes of the new hash ring, while the cluster is not idle.
+    pub running: Vec<MembershipChange>,
+    pub queued: Vec<MembershipChange>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Default)]
+pub struct SetVolumeSendMetaData {
+    pub name: String,
+    // the fields left as None are not changed.
+    pub description: Option<String>,
+    pub quota: Option<u64>,
+    pub stripe_count: Option<u32>,
+    pub replication: Option<u32>,
+    pub compression: Option<Compression>,
+    pub chunk_size: Option<u32>,
+}
+
+// CheckFile ends the transfer of a file to its new owner, which syncs the
+// file and compares its checksum before it takes the attr, the bytes of a
+// FileAttr, see DistributedEngine::transfer_files.
 #[derive(Serialize, Deserialize, PartialEq)]
 pub struct CheckFileSendMetaData {
-    pub file_attr: FileAttrSimple,
+    pub file_attr: Vec<u8>,
+    pub checksum: u64,
 }
 
 #[derive(Serialize, Deserialize, PartialEq)]
@@ -867,6 +1687,8 @@ pub struct CheckDirSendMetaData {
 #[derive(Serialize, Deserialize, PartialEq)]
 pub struct CreateVolumeSendMetaData {
     pub size: u64,
+    pub atime_policy: AtimePolicy,
+    pub public: bool,
 }
 
 #[derive(Serialize, Deserialize, PartialEq)]
@@ -874,6 +1696,45 @@ pub struct MountVolumeSendMetaData {
     pub volume_name: String,
     pub mount_point: String,
     pub read_only: bool,
+    // mount a public volume read-write, for the owner loading the data.
+    pub owner: bool,
+    // users allowed to use the mount point besides root and the user mounting it.
+    pub allowed_uids: Vec<u32>,
+    // the files created by root are owned by nobody.
+    pub root_squash: bool,
+    // pin the volume to this group of servers before mounting it.
+    pub group: Option<String>,
+    pub ttl: CacheTtl,
+}
+
+// CacheTtl is how long the kernel keeps the entries and the attrs of a mount
+// point. With no_cache nothing is cached, neither by the kernel nor by the
+// client, for the files written by several clients at once.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CacheTtl {
+    pub entry: Duration,
+    pub attr: Duration,
+    pub no_cache: bool,
+}
+
+impl Default for CacheTtl {
+    fn default() -> Self {
+        Self {
+            entry: Duration::from_secs(1),
+            attr: Duration::from_secs(1),
+            no_cache: false,
+        }
+    }
+}
+
+impl CacheTtl {
+    pub fn none() -> Self {
+        Self {
+            entry: Duration::ZERO,
+            attr: Duration::ZERO,
+            no_cache: true,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
@@ -881,14 +1742,209 @@ pub struct Volume {
     pub name: String,
     pub size: u64,
     pub used_size: u64,
+    pub atime_policy: AtimePolicy,
+    // a public volume is advertised to everyone and mounted read-only unless
+    // mounted as its owner; the servers do not enforce it.
+    pub public: bool,
 }
 
 impl Display for Volume {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Volume {{ name: {}, size: {}, used_size: {} }}",
-            self.name, self.size, self.used_size
+            "Volume {{ name: {}, size: {}, used_size: {}, atime_policy: {}, public: {} }}",
+            self.name, self.size, self.used_size, self.atime_policy, self.public
         )
     }
 }
+
+// VolumeInfo is the entry of a volume in the volume registry of the manager.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Default)]
+pub struct VolumeInfo {
+    pub name: String,
+    // seconds since the epoch.
+    pub created_at: u64,
+    // uid@host of the client that created it.
+    pub creator: String,
+    pub description: String,
+    // bytes, the size it was created with unless changed.
+    pub quota: u64,
+    // the layout of the files created in the volume.
+    #[serde(default)]
+    pub layout: FileLayout,
+    // the group of servers the volume is pinned to, empty if none.
+    #[serde(default)]
+    pub group: String,
+}
+
+impl Display for VolumeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "VolumeInfo {{ name: {}, created_at: {}, creator: {}, description: {}, quota: {}, layout: {}, group: {} }}",
+            self.name, self.created_at, self.creator, self.description, self.quota, self.layout, self.group
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unkown compression: {}", s)),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// FileLayout is fixed when a file is created, from the defaults of its
+// volume. It is kept in the attr of the file: the chunk size is its blksize
+// and the rest is packed in its flags.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default)]
+pub struct FileLayout {
+    pub stripe_count: u32,
+    pub replication: u32,
+    pub compression: Compression,
+    // bytes, 0 for the default of the storage engine.
+    pub chunk_size: u32,
+    // the server appends every write at the end of the file whatever its
+    // offset. Asked for when the file is created, never by its volume.
+    pub append_only: bool,
+}
+
+impl Default for FileLayout {
+    fn default() -> Self {
+        Self {
+            stripe_count: 1,
+            replication: 1,
+            compression: Compression::None,
+            chunk_size: 0,
+            append_only: false,
+        }
+    }
+}
+
+const LAYOUT_STRIPE_COUNT_SHIFT: u32 = 8;
+const LAYOUT_REPLICATION_SHIFT: u32 = 16;
+const LAYOUT_COMPRESSION_SHIFT: u32 = 24;
+
+impl FileLayout {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=255).contains(&self.stripe_count) {
+            return Err(format!(
+                "stripe count {} is not in [1, 255]",
+                self.stripe_count
+            ));
+        }
+        if !(1..=255).contains(&self.replication) {
+            return Err(format!(
+                "replication factor {} is not in [1, 255]",
+                self.replication
+            ));
+        }
+        if self.chunk_size != 0 && (self.chunk_size < 4096 || !self.chunk_size.is_power_of_two()) {
+            return Err(format!(
+                "chunk size {} is not a power of two of at least 4096",
+                self.chunk_size
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, attr: &mut FileAttr) {
+        attr.blksize = self.chunk_size;
+        let append_only = match self.append_only {
+            true => FILE_FLAG_APPEND_ONLY,
+            false => 0,
+        };
+        attr.flags = (attr.flags & ((1 << LAYOUT_STRIPE_COUNT_SHIFT) - 1) & !FILE_FLAG_APPEND_ONLY)
+            | append_only
+            | self.stripe_count << LAYOUT_STRIPE_COUNT_SHIFT
+            | self.replication << LAYOUT_REPLICATION_SHIFT
+            | (self.compression as u32) << LAYOUT_COMPRESSION_SHIFT;
+    }
+
+    // of returns the layout of a file, files created before the layouts
+    // have the default one.
+    pub fn of(attr: &FileAttr) -> Self {
+        let compression = match (attr.flags >> LAYOUT_COMPRESSION_SHIFT) & 0xff {
+            1 => Compression::Lz4,
+            2 => Compression::Zstd,
+            _ => Compression::None,
+        };
+        Self {
+            stripe_count: ((attr.flags >> LAYOUT_STRIPE_COUNT_SHIFT) & 0xff).max(1),
+            replication: ((attr.flags >> LAYOUT_REPLICATION_SHIFT) & 0xff).max(1),
+            compression,
+            chunk_size: attr.blksize,
+            append_only: attr.flags & FILE_FLAG_APPEND_ONLY != 0,
+        }
+    }
+}
+
+impl Display for FileLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{{ stripe_count: {}, replication: {}, compression: {}, chunk_size: {} }}",
+            self.stripe_count, self.replication, self.compression, self.chunk_size
+        )
+    }
+}
+
+// AtimePolicy decides when a read updates the access time of a file.
+// Relatime works like the linux mount option of the same name: atime is only
+// updated if it is older than mtime/ctime or older than one day.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum AtimePolicy {
+    #[default]
+    Relatime,
+    Strictatime,
+    Noatime,
+}
+
+impl FromStr for AtimePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relatime" => Ok(AtimePolicy::Relatime),
+            "strictatime" => Ok(AtimePolicy::Strictatime),
+            "noatime" => Ok(AtimePolicy::Noatime),
+            _ => Err(format!("Unkown atime policy: {}", s)),
+        }
+    }
+}
+
+impl Display for AtimePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AtimePolicy::Relatime => write!(f, "relatime"),
+            AtimePolicy::Strictatime => write!(f, "strictatime"),
+            AtimePolicy::Noatime => write!(f, "noatime"),
+        }
+    }
+}
@@ -2,11 +2,46 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::time::SystemTime;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use fuser::{FileAttr, FileType};
 use log::error;
 
+// hostname returns the host name of this node, or an empty string.
+pub fn hostname() -> String {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return String::new();
+    }
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+// split_address splits `host:port`, an IPv6 host being in brackets, e.g.
+// `[::1]:8085`. An IPv6 host without brackets is refused, its port could not
+// be told from the host.
+pub fn split_address(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']').filter(|h| h.contains(':'))?,
+        None if host.is_empty() || host.contains(':') || host.contains(']') => return None,
+        None => host,
+    };
+    Some((host, port))
+}
+
+// join_address is the inverse of split_address.
+pub fn join_address(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
 pub fn get_full_path(parent: &str, name: &str) -> String {
     if parent == "/" {
         return format!("/{}", name);
@@ -42,6 +77,104 @@ pub fn path_split(path: &str) -> Result<(String, String), i32> {
     }
 }
 
+// the ids root is mapped to by root squash, nobody and nogroup.
+pub const SQUASH_UID: u32 = 65534;
+pub const SQUASH_GID: u32 = 65534;
+
+// owner returns the owner of the files created by uid and gid, root being
+// mapped to nobody with root squash.
+pub fn owner(uid: u32, gid: u32, root_squash: bool) -> (u32, u32) {
+    match root_squash && uid == 0 {
+        true => (SQUASH_UID, SQUASH_GID),
+        false => (uid, gid),
+    }
+}
+
+// the unnamed files of O_TMPFILE are named with this prefix in their
+// directory, but have no entry in it until they are linked.
+pub const TEMP_FILE_PREFIX: &str = ".sealfs-tmpfile-";
+
+// temp_file_path returns a new path for an unnamed file in dir.
+pub fn temp_file_path(dir: &str) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    get_full_path(
+        dir,
+        &format!(
+            "{}{:x}-{:x}-{:x}",
+            TEMP_FILE_PREFIX,
+            std::process::id(),
+            now.as_nanos(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ),
+    )
+}
+
+pub fn is_temp_file(path: &str) -> bool {
+    match path_split(path) {
+        Ok((_, name)) => name.starts_with(TEMP_FILE_PREFIX),
+        Err(_) => false,
+    }
+}
+
+// a sharded directory keeps its entries in shards, the first in the
+// directory itself and shard i in the hidden directory <dir>\0<i>, placed on
+// the ring like a directory. No path of a client contains a NUL.
+pub const SHARD_SEPARATOR: char = '\0';
+pub const MAX_DIR_SHARDS: u32 = 255;
+
+pub fn shard_path(dir: &str, shard: u32) -> String {
+    match shard {
+        0 => dir.to_owned(),
+        _ => format!("{}{}{}", dir, SHARD_SEPARATOR, shard),
+    }
+}
+
+pub fn is_shard(path: &str) -> bool {
+    path.contains(SHARD_SEPARATOR)
+}
+
+// shard_dir returns the directory of a shard, or the path itself.
+pub fn shard_dir(path: &str) -> &str {
+    path.split(SHARD_SEPARATOR).next().unwrap_or(path)
+}
+
+// shard_of returns the shard keeping the entry name of a directory.
+pub fn shard_of(name: &str, shards: u32) -> u32 {
+    crc32fast::hash(name.as_bytes()) % shards.max(1)
+}
+
+// shard_index returns the shard of a directory a path is, 0 for the
+// directory itself.
+pub fn shard_index(path: &str) -> u32 {
+    path.split_once(SHARD_SEPARATOR)
+        .and_then(|(_, shard)| shard.parse().ok())
+        .unwrap_or(0)
+}
+
+// the cursor of a directory entry, its d_off, is its shard above
+// DIR_COOKIE_HASH_BITS and a hash of its name below, so it is kept while
+// other entries come and go. 0 is the start of the directory.
+pub const DIR_COOKIE_HASH_BITS: u32 = 55;
+
+pub fn dir_cookie(shard: u32, name: &[u8]) -> i64 {
+    // fnv-1a, the same in every build unlike DefaultHasher.
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in name {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let hash = (hash & ((1 << DIR_COOKIE_HASH_BITS) - 1)).max(1);
+    ((shard as i64) << DIR_COOKIE_HASH_BITS) | hash as i64
+}
+
+// cookie_shard returns the shard of the entry a cursor is at.
+pub fn cookie_shard(cookie: i64) -> u32 {
+    (cookie.max(0) >> DIR_COOKIE_HASH_BITS) as u32
+}
+
 pub fn empty_file() -> FileAttr {
     FileAttr {
         ino: 0,
@@ -2,25 +2,79 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use ahash::{HashMap, HashMapExt};
 use anyhow::Error;
 use dashmap::DashMap;
-use log::{debug, info};
+use log::{debug, error, info, warn};
+
+use crate::common::errors::SERVER_ID_MISMATCH;
+use crate::common::hash_ring::{
+    FailureDomain, HashAlgorithm, HashRing, HashRingInfo, HashRingSnapshot, ServerNode,
+};
+use crate::common::health::{HealthReport, HEARTBEAT_TIMEOUT};
+use crate::common::serialization::{
+    ClusterStatus, MembershipChange, ServerStatus, ServerType, SetVolumeSendMetaData, VolumeDay,
+    VolumeInfo, VolumeUsage,
+};
+
+use super::handoff::HandoffLog;
+use super::history::{day_of, VolumeHistory};
+
+// a volume reserved but not created in this time, by a client that crashed,
+// can be reserved again.
+const VOLUME_RESERVATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// a server of the ring not heard from in this time is removed from the
+// cluster, unless it is in maintenance.
+pub const DEFAULT_SERVER_GRACE_PERIOD: Duration = Duration::from_secs(300);
+
+pub enum VolumeState {
+    // reserved by a client creating the volume on its server.
+    Reserved(Instant),
+    Created(VolumeInfo),
+}
 
-use crate::common::hash_ring::{HashRing, ServerNode};
-use crate::common::serialization::{ClusterStatus, ServerStatus, ServerType};
 pub struct Manager {
     pub hashring: Arc<RwLock<Option<HashRing>>>,
     pub new_hashring: Arc<RwLock<Option<HashRing>>>,
     pub servers: Arc<Mutex<HashMap<String, Server>>>,
     pub cluster_status: Arc<Mutex<ClusterStatus>>,
     pub closed: AtomicBool,
+    // servers being drained for a reboot, the clients hold the changes to them.
+    pub maintenance: Mutex<HashSet<String>>,
+    // the volume names, reserved before a server creates the volume, so two
+    // clients cannot create the same volume on different servers.
+    pub volumes: Mutex<HashMap<String, VolumeState>>,
+    // the file the created volumes are kept in, if any.
+    pub volume_registry: OnceLock<String>,
+    // the daily usage of the volumes, kept next to the registry.
+    pub history: Mutex<VolumeHistory>,
+    // the files the servers handed off in the running rebalance.
+    pub handoffs: Mutex<HandoffLog>,
+    // the membership changes of the new hash ring, and the ones waiting for
+    // it to be finished.
+    pub running_changes: Mutex<Vec<MembershipChange>>,
+    pub queued_changes: Mutex<VecDeque<MembershipChange>>,
+    // the identities of the servers and when they were last heard from.
+    pub members: Mutex<HashMap<String, Member>>,
+    // the servers removed for being away longer than the grace period, 0
+    // never removes them.
+    pub lost_servers: Mutex<HashSet<String>>,
+    pub server_grace_period: OnceLock<Duration>,
+    started: Instant,
     _clients: DashMap<String, String>,
 }
 
+pub struct Member {
+    pub id: String,
+    pub last_seen: Instant,
+}
+
 pub struct Server {
     pub status: ServerStatus,
     r#_type: ServerType,
@@ -28,14 +82,28 @@ pub struct Server {
 }
 
 impl Manager {
-    pub fn new(servers: Vec<(String, usize)>) -> Self {
-        let hashring = Arc::new(RwLock::new(Some(HashRing::new(servers.clone()))));
+    pub fn new(hash_algorithm: HashAlgorithm, servers: Vec<(String, usize)>) -> Self {
+        let mut hashring = HashRing::new(hash_algorithm, servers.clone());
+        // 0 is left for the requests without an epoch.
+        hashring.epoch = 1;
+        let hashring = Arc::new(RwLock::new(Some(hashring)));
         let manager = Manager {
             hashring,
             new_hashring: Arc::new(RwLock::new(None)),
             servers: Arc::new(Mutex::new(HashMap::new())),
             cluster_status: Arc::new(Mutex::new(ClusterStatus::Initializing)),
             closed: AtomicBool::new(false),
+            maintenance: Mutex::new(HashSet::new()),
+            volumes: Mutex::new(HashMap::new()),
+            volume_registry: OnceLock::new(),
+            history: Mutex::new(VolumeHistory::default()),
+            handoffs: Mutex::new(HandoffLog::default()),
+            running_changes: Mutex::new(Vec::new()),
+            queued_changes: Mutex::new(VecDeque::new()),
+            members: Mutex::new(HashMap::new()),
+            lost_servers: Mutex::new(HashSet::new()),
+            server_grace_period: OnceLock::new(),
+            started: Instant::now(),
             _clients: DashMap::new(),
         };
 
@@ -59,77 +127,649 @@ impl Manager {
         status
     }
 
-    pub fn get_hash_ring_info(&self) -> Vec<(String, usize)> {
-        self.hashring
-            .read()
-            .unwrap()
-            .as_ref()
-            .unwrap()
-            .servers
-            .iter()
-            .map(|(k, v)| (k.clone(), *v))
-            .collect()
+    pub fn get_hash_ring_info(&self) -> HashRingInfo {
+        self.hashring.read().unwrap().as_ref().unwrap().info()
     }
 
-    pub fn get_new_hash_ring_info(&self) -> Result<Vec<(String, usize)>, Error> {
+    pub fn get_new_hash_ring_info(&self) -> Result<HashRingInfo, Error> {
         if let Some(new_hashring) = self.new_hashring.read().unwrap().as_ref() {
-            Ok(new_hashring
-                .servers
-                .iter()
-                .map(|(k, v)| (k.clone(), *v))
-                .collect())
+            Ok(new_hashring.info())
         } else {
             Err(anyhow::anyhow!("new hashring is none"))
         }
     }
 
+    // get_hash_ring_snapshot returns the cluster status with the hash rings,
+    // taken under the lock of the status, which every change of the hash
+    // rings of a rebalance holds.
+    pub fn get_hash_ring_snapshot(&self) -> HashRingSnapshot {
+        let status = self.cluster_status.lock().unwrap();
+        HashRingSnapshot {
+            status: *status,
+            hash_ring: self.get_hash_ring_info(),
+            new_hash_ring: self.get_new_hash_ring_info().ok(),
+        }
+    }
+
+    // next_hashring returns a copy of the hash ring with the next epoch, to
+    // be changed into the new hash ring of a rebalance.
+    fn next_hashring(&self) -> HashRing {
+        let mut hashring = self.hashring.read().unwrap().clone().unwrap();
+        hashring.epoch += 1;
+        hashring
+    }
+
     pub fn add_nodes(&self, nodes: Vec<(String, usize)>) -> Option<Error> {
         info!("add_nodes: {:?}", nodes);
+        self.submit_change(MembershipChange::AddNodes(nodes))
+    }
+
+    pub fn delete_nodes(&self, nodes: Vec<String>) -> Option<Error> {
+        info!("delete_nodes: {:?}", nodes);
+        self.submit_change(MembershipChange::RemoveNodes(nodes))
+    }
+
+    // set_weight changes the weight of a server through the same steps as
+    // adding a server, so only the paths whose owner changes are transferred.
+    pub fn set_weight(&self, server: String, weight: usize) -> Option<Error> {
+        info!("set_weight: {} {}", server, weight);
+        self.submit_change(MembershipChange::SetWeight(server, weight))
+    }
+
+    // set_maintenance puts a server in or out of maintenance. It does not
+    // change the hash ring, the server keeps its files.
+    pub fn set_maintenance(&self, server: String, enter: bool) -> Option<Error> {
+        info!("set_maintenance: {} {}", server, enter);
+        if !self
+            .hashring
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .contains(&server)
+        {
+            return Some(anyhow::anyhow!("server {} is not in the cluster", server));
+        }
+        let mut maintenance = self.maintenance.lock().unwrap();
+        let changed = match enter {
+            true => maintenance.insert(server.clone()),
+            false => maintenance.remove(&server),
+        };
+        if !changed {
+            return Some(anyhow::anyhow!(
+                "server {} is already {} maintenance",
+                server,
+                if enter { "in" } else { "out of" }
+            ));
+        }
+        None
+    }
+
+    // init_server_groups puts the servers in their groups of the
+    // configuration, before the cluster is started.
+    pub fn init_server_groups(&self, groups: BTreeMap<String, String>) {
+        info!("init server groups: {:?}", groups);
+        let mut hashring = self.hashring.write().unwrap();
+        let hashring = hashring.as_mut().unwrap();
+        for (server, group) in groups {
+            hashring.set_group(&server, &group);
+        }
+    }
+
+    // set_server_group moves a server to another group, or out of its group
+    // if group is empty, through the same steps as adding a server so the
+    // files of the pinned volumes follow.
+    pub fn set_server_group(&self, server: String, group: String) -> Option<Error> {
+        info!("set_server_group: {} {}", server, group);
+        self.submit_change(MembershipChange::SetServerGroup(server, group))
+    }
+
+    // submit_change starts a membership change if the cluster is idle, or
+    // queues it after the running and the queued ones. It is checked against
+    // the hash ring they will leave.
+    fn submit_change(&self, change: MembershipChange) -> Option<Error> {
         let mut cluster_status = self.cluster_status.lock().unwrap();
-        if *cluster_status != ClusterStatus::Idle {
-            return Some(anyhow::anyhow!("cluster is not idle"));
+        let mut queued_changes = self.queued_changes.lock().unwrap();
+        let mut hashring = self.pending_hashring(&queued_changes);
+        if let Err(e) = apply_change(&mut hashring, &change) {
+            return Some(e);
         }
-        let mut new_hashring = self.hashring.read().unwrap().clone().unwrap();
-        let mut servers = self.servers.lock().unwrap();
-        for (node, weight) in nodes {
-            new_hashring.add(
-                ServerNode {
-                    address: node.clone(),
-                },
-                weight,
-            );
-            servers.insert(
-                node,
-                Server {
-                    status: ServerStatus::Initializing,
-                    r#_type: ServerType::Running,
-                    _replicas: weight,
-                },
-            );
+        info!("queue membership change: {}", change);
+        queued_changes.push_back(change);
+        if *cluster_status == ClusterStatus::Idle {
+            self.start_changes(&mut cluster_status, &mut queued_changes);
+        }
+        None
+    }
+
+    // pending_hashring returns the hash ring the running and the queued
+    // changes leave.
+    fn pending_hashring(&self, queued_changes: &VecDeque<MembershipChange>) -> HashRing {
+        let mut hashring = match self.new_hashring.read().unwrap().clone() {
+            Some(new_hashring) => new_hashring,
+            None => self.hashring.read().unwrap().clone().unwrap(),
+        };
+        for queued in queued_changes.iter() {
+            let _ = apply_change(&mut hashring, queued);
         }
+        hashring
+    }
 
+    // add_discovered_servers adds the servers found in DNS which the pending
+    // hash ring misses, but the ones removed for being away until they send
+    // a heartbeat. The servers gone from DNS are left to the grace period.
+    pub fn add_discovered_servers(&self, servers: Vec<String>, weight: usize) -> Option<Error> {
+        let hashring = self.pending_hashring(&self.queued_changes.lock().unwrap());
+        let new_servers: Vec<(String, usize)> = {
+            let lost_servers = self.lost_servers.lock().unwrap();
+            servers
+                .into_iter()
+                .filter(|server| !hashring.contains(server) && !lost_servers.contains(server))
+                .map(|server| (server, weight))
+                .collect()
+        };
+        if new_servers.is_empty() {
+            return None;
+        }
+        info!("discovered servers: {:?}", new_servers);
+        self.add_nodes(new_servers)
+    }
+
+    // start_queued_changes starts the queued changes once the cluster is idle.
+    pub fn start_queued_changes(&self) {
+        let mut cluster_status = self.cluster_status.lock().unwrap();
+        let mut queued_changes = self.queued_changes.lock().unwrap();
+        if *cluster_status == ClusterStatus::Idle && !queued_changes.is_empty() {
+            self.start_changes(&mut cluster_status, &mut queued_changes);
+        }
+    }
+
+    // start_changes merges the first queued changes into one new hash ring,
+    // as long as they change distinct servers. A server added or removed
+    // cannot be changed again before its transfer is finished.
+    fn start_changes(
+        &self,
+        cluster_status: &mut ClusterStatus,
+        queued_changes: &mut VecDeque<MembershipChange>,
+    ) {
+        let mut new_hashring = self.next_hashring();
+        let mut servers = self.servers.lock().unwrap();
+        let mut changed = HashSet::new();
+        let mut running = Vec::new();
+        while let Some(change) = queued_changes.front() {
+            if change.servers().iter().any(|s| changed.contains(*s)) {
+                break;
+            }
+            let change = queued_changes.pop_front().unwrap();
+            if let Err(e) = apply_change(&mut new_hashring, &change) {
+                error!("drop membership change {}: {}", change, e);
+                continue;
+            }
+            changed.extend(change.servers().into_iter().map(str::to_owned));
+            match &change {
+                MembershipChange::AddNodes(nodes) => {
+                    for (node, weight) in nodes {
+                        servers.insert(
+                            node.clone(),
+                            Server {
+                                status: ServerStatus::Initializing,
+                                r#_type: ServerType::Running,
+                                _replicas: *weight,
+                            },
+                        );
+                    }
+                }
+                MembershipChange::SetWeight(server, weight) => {
+                    if let Some(server) = servers.get_mut(server) {
+                        server._replicas = *weight;
+                    }
+                }
+                // a lost server cannot take part in its removal.
+                MembershipChange::RemoveNodes(nodes) => {
+                    let mut lost_servers = self.lost_servers.lock().unwrap();
+                    for node in nodes {
+                        if lost_servers.remove(node) {
+                            servers.remove(node);
+                            self.members.lock().unwrap().remove(node);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            running.push(change);
+        }
+        if running.is_empty() {
+            return;
+        }
+        info!(
+            "start membership changes: {:?}, {} queued",
+            running.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            queued_changes.len()
+        );
         self.new_hashring.write().unwrap().replace(new_hashring);
+        *self.running_changes.lock().unwrap() = running;
         *cluster_status = ClusterStatus::NodesStarting;
+    }
+
+    // heartbeat records that a server is up. A server is known by the id
+    // it first sent, another server at its address is refused while it is a
+    // member. A lost server back before its removal started is kept.
+    pub fn heartbeat(&self, server: &str, id: &str, now: Instant) -> Result<(), i32> {
+        let is_member = self.servers.lock().unwrap().contains_key(server);
+        match self.members.lock().unwrap().entry(server.to_owned()) {
+            std::collections::hash_map::Entry::Occupied(mut entry) => {
+                if entry.get().id != id {
+                    if is_member {
+                        return Err(SERVER_ID_MISMATCH);
+                    }
+                    info!("server {} restarts with id {}", server, id);
+                    entry.get_mut().id = id.to_owned();
+                }
+                entry.get_mut().last_seen = now;
+            }
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                info!("server {} joins with id {}", server, id);
+                entry.insert(Member {
+                    id: id.to_owned(),
+                    last_seen: now,
+                });
+            }
+        }
+        if self.lost_servers.lock().unwrap().remove(server) {
+            info!("server {} rejoins, its removal is canceled", server);
+            let removal = MembershipChange::RemoveNodes(vec![server.to_owned()]);
+            self.queued_changes
+                .lock()
+                .unwrap()
+                .retain(|change| *change != removal);
+        }
+        Ok(())
+    }
+
+    // expire_members queues the removal of the servers of the ring away for
+    // longer than the grace period. The servers not heard from since the
+    // manager started are given the grace period from then.
+    pub fn expire_members(&self, now: Instant) {
+        let grace_period = *self
+            .server_grace_period
+            .get()
+            .unwrap_or(&DEFAULT_SERVER_GRACE_PERIOD);
+        if grace_period.is_zero() {
+            return;
+        }
+        // the servers being removed are left out of the new hash ring.
+        let servers = match self.new_hashring.read().unwrap().as_ref() {
+            Some(new_hashring) => new_hashring.get_server_lists(),
+            None => self
+                .hashring
+                .read()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .get_server_lists(),
+        };
+        let expired: Vec<String> = {
+            let members = self.members.lock().unwrap();
+            let maintenance = self.maintenance.lock().unwrap();
+            let lost_servers = self.lost_servers.lock().unwrap();
+            servers
+                .into_iter()
+                .filter(|server| !maintenance.contains(server) && !lost_servers.contains(server))
+                .filter(|server| {
+                    let last_seen = members.get(server).map_or(self.started, |m| m.last_seen);
+                    now.saturating_duration_since(last_seen) > grace_period
+                })
+                .collect()
+        };
+        for server in expired {
+            warn!(
+                "server {} is away for more than {:?}, removing it",
+                server, grace_period
+            );
+            self.lost_servers.lock().unwrap().insert(server.clone());
+            if let Some(e) = self.submit_change(MembershipChange::RemoveNodes(vec![server.clone()]))
+            {
+                error!("remove server {} failed: {}", server, e);
+                self.lost_servers.lock().unwrap().remove(&server);
+            }
+        }
+    }
 
+    // health checks that the registry can be written, that the cluster is
+    // not in error and that some servers of the ring send heartbeats.
+    pub fn health(&self, now: Instant) -> HealthReport {
+        let mut report = HealthReport::default();
+        report.check(
+            "registry",
+            match self.volume_registry.get() {
+                Some(path) => {
+                    let dir = match std::path::Path::new(path).parent() {
+                        Some(dir) if !dir.as_os_str().is_empty() => dir,
+                        _ => std::path::Path::new("."),
+                    };
+                    nix::unistd::access(dir, nix::unistd::AccessFlags::W_OK)
+                        .map(|_| format!("{} writable", path))
+                        .map_err(|e| format!("{}: {}", path, e))
+                }
+                None => Ok("not kept".to_owned()),
+            },
+        );
+        let status = self.get_cluster_status();
+        report.check(
+            "cluster",
+            match status {
+                ClusterStatus::StatusError => Err(status.to_string()),
+                _ => Ok(status.to_string()),
+            },
+        );
+        let servers = self
+            .hashring
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .get_server_lists();
+        let up = {
+            let members = self.members.lock().unwrap();
+            servers
+                .iter()
+                .filter(|server| {
+                    members.get(*server).is_some_and(|m| {
+                        now.saturating_duration_since(m.last_seen) < HEARTBEAT_TIMEOUT
+                    })
+                })
+                .count()
+        };
+        let detail = format!("{} of {} servers send heartbeats", up, servers.len());
+        report.check(
+            "servers",
+            match up == 0 && !servers.is_empty() {
+                true => Err(detail),
+                false => Ok(detail),
+            },
+        );
+        report
+    }
+
+    pub fn get_membership_changes(&self) -> (Vec<MembershipChange>, Vec<MembershipChange>) {
+        (
+            self.running_changes.lock().unwrap().clone(),
+            self.queued_changes
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+        )
+    }
+
+    // set_server_domain sets the failure domain of a server. The domains only
+    // guide the placement of the replicas, so they change the hash ring
+    // without moving files, and the nodes get them with the next hash ring.
+    pub fn set_server_domain(&self, server: String, domain: FailureDomain) -> Option<Error> {
+        info!("set_server_domain: {} {:?}", server, domain);
+        let mut hashring = self.hashring.write().unwrap();
+        let hashring = hashring.as_mut().unwrap();
+        if !hashring.contains(&server) {
+            return Some(anyhow::anyhow!("server {} is not in the cluster", server));
+        }
+        hashring.set_domain(&server, domain.clone());
+        if let Some(new_hashring) = self.new_hashring.write().unwrap().as_mut() {
+            new_hashring.set_domain(&server, domain);
+        }
         None
     }
 
-    pub fn delete_nodes(&self, nodes: Vec<String>) -> Option<Error> {
+    // replication_warnings returns why the replicas of the files of the
+    // volumes, or of the volume name only, cannot all be in distinct
+    // failure domains.
+    pub fn replication_warnings(&self, name: Option<&str>) -> Vec<String> {
+        let infos = self.list_volume_infos();
+        let hashring = self.hashring.read().unwrap();
+        let hashring = hashring.as_ref().unwrap();
+        infos
+            .iter()
+            .filter(|info| name.map_or(true, |name| info.name == name))
+            .filter_map(|info| {
+                hashring.replication_warning(&info.name, info.layout.replication as usize)
+            })
+            .collect()
+    }
+
+    // pin_volume restricts a volume to the servers of group, or to none if
+    // group is empty. The files of the volume are moved to them through the
+    // same steps as adding a server. It does nothing if the volume is
+    // already pinned to group, so every mount can ask for it.
+    pub fn pin_volume(&self, name: &str, group: &str) -> Option<Error> {
         let mut cluster_status = self.cluster_status.lock().unwrap();
+        let mut volumes = self.volumes.lock().unwrap();
+        let info = match volumes.get_mut(name) {
+            Some(VolumeState::Created(info)) => info,
+            _ => return Some(anyhow::anyhow!("volume {} not found", name)),
+        };
+        if info.group == group {
+            return None;
+        }
+        info!("pin_volume: {} {:?}", name, group);
         if *cluster_status != ClusterStatus::Idle {
             return Some(anyhow::anyhow!("cluster is not idle"));
         }
-        let mut new_hashring = self.hashring.read().unwrap().clone().unwrap();
-        new_hashring.remove(&ServerNode {
-            address: nodes[0].clone(),
-        });
+        let mut new_hashring = self.next_hashring();
+        if !group.is_empty()
+            && new_hashring
+                .groups
+                .members(group, &new_hashring.servers)
+                .is_empty()
+        {
+            return Some(anyhow::anyhow!("group {} has no server", group));
+        }
+        new_hashring.pin_volume(name, group);
+        info.group = group.to_owned();
+        if let Some(e) = self.save_volume_registry(&volumes) {
+            return Some(e);
+        }
 
         self.new_hashring.write().unwrap().replace(new_hashring);
-
         *cluster_status = ClusterStatus::NodesStarting;
         None
     }
 
+    pub fn get_maintenance_servers(&self) -> Vec<String> {
+        self.maintenance.lock().unwrap().iter().cloned().collect()
+    }
+
+    // reserve_volume reserves name for a client about to create the volume,
+    // it fails if the volume exists or another client is creating it.
+    pub fn reserve_volume(&self, name: &str) -> Option<Error> {
+        let mut volumes = self.volumes.lock().unwrap();
+        match volumes.get(name) {
+            Some(VolumeState::Created(_)) => {
+                return Some(anyhow::anyhow!("volume {} exists", name));
+            }
+            Some(VolumeState::Reserved(time)) if time.elapsed() < VOLUME_RESERVATION_TIMEOUT => {
+                return Some(anyhow::anyhow!("volume {} is being created", name));
+            }
+            _ => {}
+        }
+        info!("reserve volume {}", name);
+        volumes.insert(name.to_owned(), VolumeState::Reserved(Instant::now()));
+        None
+    }
+
+    // commit_volume records that the volume is created on its server. A
+    // volume deleted while pinned stays pinned in the hash ring, so the one
+    // created with its name is pinned to the same group.
+    pub fn commit_volume(&self, mut info: VolumeInfo) -> Option<Error> {
+        info!("commit volume {}", info.name);
+        info.group = self
+            .hashring
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .groups
+            .volumes
+            .get(&info.name)
+            .cloned()
+            .unwrap_or_default();
+        let mut volumes = self.volumes.lock().unwrap();
+        volumes.insert(info.name.clone(), VolumeState::Created(info));
+        self.save_volume_registry(&volumes)
+    }
+
+    // release_volume frees name, after the volume failed to be created or
+    // was deleted.
+    pub fn release_volume(&self, name: &str) -> Option<Error> {
+        info!("release volume {}", name);
+        let mut volumes = self.volumes.lock().unwrap();
+        volumes.remove(name);
+        self.history.lock().unwrap().remove(name);
+        self.save_volume_registry(&volumes)
+    }
+
+    // record_usage adds the usage sent by a server to the day of now, for
+    // the volumes created.
+    pub fn record_usage(&self, usage: &[VolumeUsage], now: SystemTime) {
+        if usage.is_empty() {
+            return;
+        }
+        let volumes = self.volumes.lock().unwrap();
+        let mut history = self.history.lock().unwrap();
+        for usage in usage {
+            if let Some(VolumeState::Created(_)) = volumes.get(&usage.volume) {
+                history.add(usage, day_of(now));
+            }
+        }
+    }
+
+    // volume_history returns the last days of the usage of volume, the last
+    // one first, or None if there is no such volume.
+    pub fn volume_history(&self, name: &str, days: usize) -> Option<Vec<VolumeDay>> {
+        match self.volumes.lock().unwrap().get(name) {
+            Some(VolumeState::Created(_)) => Some(self.history.lock().unwrap().get(name, days)),
+            _ => None,
+        }
+    }
+
+    pub fn save_history(&self, now: Instant) {
+        if let Err(e) = self.history.lock().unwrap().save(now) {
+            error!("{}", e);
+        }
+    }
+
+    // record_handoff keeps that server handed path off to its new owner, it
+    // returns once the handoff is durable.
+    pub fn record_handoff(&self, server: &str, path: &str) -> Option<Error> {
+        debug!("record handoff of {} by {}", path, server);
+        self.handoffs
+            .lock()
+            .unwrap()
+            .record(server, path)
+            .err()
+            .map(|e| anyhow::anyhow!(e))
+    }
+
+    // handoffs returns the paths server handed off in the running rebalance.
+    pub fn handoffs(&self, server: &str) -> Vec<String> {
+        self.handoffs.lock().unwrap().get(server)
+    }
+
+    pub fn list_volume_infos(&self) -> Vec<VolumeInfo> {
+        let mut infos: Vec<VolumeInfo> = self
+            .volumes
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|state| match state {
+                VolumeState::Created(info) => Some(info.clone()),
+                VolumeState::Reserved(_) => None,
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+
+    // set_volume changes the fields of update which are set, the servers
+    // apply a new layout to the files created after they see it.
+    pub fn set_volume(&self, update: SetVolumeSendMetaData) -> Option<Error> {
+        let mut volumes = self.volumes.lock().unwrap();
+        let info = match volumes.get_mut(&update.name) {
+            Some(VolumeState::Created(info)) => info,
+            _ => return Some(anyhow::anyhow!("volume {} not found", update.name)),
+        };
+        let mut layout = info.layout;
+        layout.stripe_count = update.stripe_count.unwrap_or(layout.stripe_count);
+        layout.replication = update.replication.unwrap_or(layout.replication);
+        layout.compression = update.compression.unwrap_or(layout.compression);
+        layout.chunk_size = update.chunk_size.unwrap_or(layout.chunk_size);
+        if let Err(e) = layout.validate() {
+            return Some(anyhow::anyhow!(e));
+        }
+        info.layout = layout;
+        if let Some(description) = update.description {
+            info.description = description;
+        }
+        if let Some(quota) = update.quota {
+            info.quota = quota;
+        }
+        info!("set volume {}: {:?}", update.name, info);
+        self.save_volume_registry(&volumes)
+    }
+
+    // load_volume_registry reads the volumes kept in path, and keeps the
+    // volumes there from now on.
+    pub fn load_volume_registry(&self, path: &str) -> Result<(), String> {
+        let infos: Vec<VolumeInfo> = match std::fs::read_to_string(path) {
+            Ok(content) => serde_yaml::from_str(&content)
+                .map_err(|e| format!("parse volume registry {} failed: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("read volume registry {} failed: {}", path, e)),
+        };
+        info!("load {} volumes from {}", infos.len(), path);
+        let mut volumes = self.volumes.lock().unwrap();
+        let mut hashring = self.hashring.write().unwrap();
+        for info in infos {
+            if !info.group.is_empty() {
+                hashring
+                    .as_mut()
+                    .unwrap()
+                    .pin_volume(&info.name, &info.group);
+            }
+            volumes.insert(info.name.clone(), VolumeState::Created(info));
+        }
+        *self.history.lock().unwrap() = VolumeHistory::load(&format!("{}.history", path))?;
+        *self.handoffs.lock().unwrap() = HandoffLog::load(&format!("{}.handoffs", path))?;
+        self.volume_registry
+            .set(path.to_owned())
+            .map_err(|_| "volume registry already loaded".to_owned())
+    }
+
+    // save_volume_registry writes the created volumes to a temporary file
+    // renamed over the registry, so a crash leaves the old or the new one.
+    fn save_volume_registry(&self, volumes: &HashMap<String, VolumeState>) -> Option<Error> {
+        let path = self.volume_registry.get()?;
+        let mut infos: Vec<&VolumeInfo> = volumes
+            .values()
+            .filter_map(|state| match state {
+                VolumeState::Created(info) => Some(info),
+                VolumeState::Reserved(_) => None,
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        let temp_path = format!("{}.tmp", path);
+        let result = serde_yaml::to_string(&infos)
+            .map_err(Error::from)
+            .and_then(|content| std::fs::write(&temp_path, content).map_err(Error::from))
+            .and_then(|_| std::fs::rename(&temp_path, path).map_err(Error::from));
+        match result {
+            Ok(()) => None,
+            Err(e) => {
+                error!("save volume registry {} failed: {}", path, e);
+                Some(e)
+            }
+        }
+    }
+
     pub fn set_server_status(&self, server_id: String, status: ServerStatus) -> Option<Error> {
         // debug : logs all server_name in self.servers
         debug!(
@@ -143,6 +783,10 @@ impl Manager {
         );
 
         info!("set server status: {} {:?}", server_id, status);
+        // e.g. a lost server back after it was removed.
+        if !self.servers.lock().unwrap().contains_key(&server_id) {
+            return Some(anyhow::anyhow!("unknown server: {}", server_id));
+        }
 
         match status {
             ServerStatus::Initializing => {
@@ -227,6 +871,10 @@ impl Manager {
                             return Some(anyhow::anyhow!("cannot finish for server: {}, server is not Finishing: status: {:?}", server_id, servers.get(&server_id).unwrap().status));
                         }
                         servers.get_mut(&server_id).unwrap().status = ServerStatus::Finished;
+                        // its copies of the files handed off are deleted.
+                        if let Err(e) = self.handoffs.lock().unwrap().finish(&server_id) {
+                            error!("{}", e);
+                        }
                         None
                     }
                     ClusterStatus::Initializing => {
@@ -278,3 +926,484 @@ impl Manager {
         }
     }
 }
+
+// apply_change changes the servers of hashring, if the change is valid.
+fn apply_change(hashring: &mut HashRing, change: &MembershipChange) -> Result<(), Error> {
+    match change {
+        MembershipChange::AddNodes(nodes) => {
+            for (node, weight) in nodes {
+                if hashring.contains(node) {
+                    return Err(anyhow::anyhow!("server {} is already in the cluster", node));
+                }
+                hashring.add(
+                    ServerNode {
+                        address: node.clone(),
+                    },
+                    *weight,
+                );
+            }
+        }
+        MembershipChange::RemoveNodes(nodes) => {
+            for node in nodes {
+                if !hashring.contains(node) {
+                    return Err(anyhow::anyhow!("server {} is not in the cluster", node));
+                }
+                hashring.remove(&ServerNode {
+                    address: node.clone(),
+                });
+            }
+            if hashring.servers.is_empty() {
+                return Err(anyhow::anyhow!("the last server cannot be removed"));
+            }
+        }
+        MembershipChange::SetWeight(server, weight) => {
+            if *weight == 0 {
+                return Err(anyhow::anyhow!("weight must be positive"));
+            }
+            match hashring.servers.get(server) {
+                None => return Err(anyhow::anyhow!("server {} is not in the cluster", server)),
+                Some(old_weight) if old_weight == weight => {
+                    return Err(anyhow::anyhow!(
+                        "weight of server {} is already {}",
+                        server,
+                        weight
+                    ))
+                }
+                _ => {}
+            }
+            hashring.add(
+                ServerNode {
+                    address: server.clone(),
+                },
+                *weight,
+            );
+        }
+        MembershipChange::SetServerGroup(server, group) => {
+            if !hashring.contains(server) {
+                return Err(anyhow::anyhow!("server {} is not in the cluster", server));
+            }
+            let old_group = hashring
+                .groups
+                .servers
+                .get(server)
+                .cloned()
+                .unwrap_or_default();
+            if old_group == *group {
+                return Err(anyhow::anyhow!(
+                    "server {} is already in group {:?}",
+                    server,
+                    group
+                ));
+            }
+            hashring.set_group(server, group);
+            // the volumes of an emptied group would be spread over all servers.
+            if !old_group.is_empty()
+                && hashring.groups.volumes.values().any(|g| *g == old_group)
+                && hashring
+                    .groups
+                    .members(&old_group, &hashring.servers)
+                    .is_empty()
+            {
+                return Err(anyhow::anyhow!(
+                    "group {} has pinned volumes and no other server",
+                    old_group
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Manager, VolumeState, VOLUME_RESERVATION_TIMEOUT};
+    use crate::common::{
+        errors::SERVER_ID_MISMATCH,
+        hash_ring::{FailureDomain, HashAlgorithm},
+        health::HEARTBEAT_TIMEOUT,
+        serialization::{
+            ClusterStatus, FileLayout, MembershipChange, SetVolumeSendMetaData, VolumeInfo,
+        },
+    };
+
+    #[test]
+    fn set_weight_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![("127.0.0.1:8085".to_owned(), 100)],
+        );
+        *manager.cluster_status.lock().unwrap() = ClusterStatus::Idle;
+        assert!(manager
+            .set_weight("127.0.0.1:8086".to_owned(), 200)
+            .is_some());
+        assert!(manager
+            .set_weight("127.0.0.1:8085".to_owned(), 100)
+            .is_some());
+        assert!(manager.set_weight("127.0.0.1:8085".to_owned(), 0).is_some());
+        assert!(manager
+            .set_weight("127.0.0.1:8085".to_owned(), 200)
+            .is_none());
+        assert_eq!(manager.get_cluster_status(), ClusterStatus::NodesStarting);
+        assert_eq!(
+            manager.get_new_hash_ring_info().unwrap().servers,
+            vec![("127.0.0.1:8085".to_owned(), 200)]
+        );
+        assert_eq!(
+            manager.get_hash_ring_info().servers,
+            vec![("127.0.0.1:8085".to_owned(), 100)]
+        );
+        // the new owners are fenced from the old ones.
+        assert_eq!(manager.get_hash_ring_info().epoch, 1);
+        assert_eq!(manager.get_new_hash_ring_info().unwrap().epoch, 2);
+        // the clients route with the new ring from PreFinish on.
+        let snapshot = manager.get_hash_ring_snapshot();
+        assert_eq!(snapshot.status, ClusterStatus::NodesStarting);
+        assert_eq!(snapshot.routing().epoch, 1);
+        *manager.cluster_status.lock().unwrap() = ClusterStatus::PreFinish;
+        assert_eq!(manager.get_hash_ring_snapshot().routing().epoch, 2);
+    }
+
+    #[test]
+    fn membership_queue_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![("127.0.0.1:8085".to_owned(), 100)],
+        );
+        // changes wait for the cluster to be idle.
+        assert!(manager
+            .add_nodes(vec![("127.0.0.1:8086".to_owned(), 100)])
+            .is_none());
+        assert!(manager
+            .set_weight("127.0.0.1:8085".to_owned(), 200)
+            .is_none());
+        // a change to the same server waits for the previous one.
+        assert!(manager
+            .set_weight("127.0.0.1:8086".to_owned(), 300)
+            .is_none());
+        // changes are checked against the ring the queued ones leave.
+        assert!(manager
+            .add_nodes(vec![("127.0.0.1:8086".to_owned(), 100)])
+            .is_some());
+        assert!(manager
+            .delete_nodes(vec!["127.0.0.1:8087".to_owned()])
+            .is_some());
+        assert_eq!(manager.get_membership_changes().1.len(), 3);
+        assert_eq!(manager.get_cluster_status(), ClusterStatus::Initializing);
+
+        *manager.cluster_status.lock().unwrap() = ClusterStatus::Idle;
+        manager.start_queued_changes();
+        assert_eq!(manager.get_cluster_status(), ClusterStatus::NodesStarting);
+        let (running, queued) = manager.get_membership_changes();
+        assert_eq!(running.len(), 2);
+        assert_eq!(
+            queued,
+            vec![MembershipChange::SetWeight(
+                "127.0.0.1:8086".to_owned(),
+                300
+            )]
+        );
+        let mut servers = manager.get_new_hash_ring_info().unwrap().servers;
+        servers.sort();
+        assert_eq!(
+            servers,
+            vec![
+                ("127.0.0.1:8085".to_owned(), 200),
+                ("127.0.0.1:8086".to_owned(), 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn server_identity_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![
+                ("127.0.0.1:8085".to_owned(), 100),
+                ("127.0.0.1:8086".to_owned(), 100),
+            ],
+        );
+        *manager.cluster_status.lock().unwrap() = ClusterStatus::Idle;
+        let _ = manager.server_grace_period.set(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(manager.heartbeat("127.0.0.1:8085", "a", now).is_ok());
+        assert_eq!(
+            manager.heartbeat("127.0.0.1:8085", "b", now),
+            Err(SERVER_ID_MISMATCH)
+        );
+        let report = manager.health(now);
+        assert!(report.healthy());
+        assert_eq!(report.checks[2].detail, "1 of 2 servers send heartbeats");
+        assert!(!manager.health(now + HEARTBEAT_TIMEOUT).healthy());
+
+        // a server restarted within the grace period is kept.
+        let later = now + Duration::from_secs(50);
+        assert!(manager.heartbeat("127.0.0.1:8085", "a", later).is_ok());
+        manager.expire_members(later);
+        assert!(manager.lost_servers.lock().unwrap().is_empty());
+
+        // a server in maintenance is never removed.
+        let later = now + Duration::from_secs(100);
+        assert!(manager
+            .set_maintenance("127.0.0.1:8086".to_owned(), true)
+            .is_none());
+        manager.expire_members(later);
+        assert!(manager.lost_servers.lock().unwrap().is_empty());
+        assert!(manager
+            .set_maintenance("127.0.0.1:8086".to_owned(), false)
+            .is_none());
+
+        let later = now + Duration::from_secs(200);
+        manager.heartbeat("127.0.0.1:8085", "a", later).unwrap();
+        manager.expire_members(later);
+        assert_eq!(manager.get_cluster_status(), ClusterStatus::NodesStarting);
+        assert_eq!(
+            manager.get_membership_changes().0,
+            vec![MembershipChange::RemoveNodes(vec![
+                "127.0.0.1:8086".to_owned()
+            ])]
+        );
+        // the lost server is not waited for.
+        assert!(!manager
+            .servers
+            .lock()
+            .unwrap()
+            .contains_key("127.0.0.1:8086"));
+    }
+
+    #[test]
+    fn discovered_servers_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![("127.0.0.1:8085".to_owned(), 100)],
+        );
+        let servers = vec!["127.0.0.1:8085".to_owned(), "127.0.0.1:8086".to_owned()];
+        assert!(manager
+            .add_discovered_servers(servers.clone(), 100)
+            .is_none());
+        // the servers queued are not added again.
+        assert!(manager.add_discovered_servers(servers, 100).is_none());
+        assert_eq!(
+            manager.get_membership_changes().1,
+            vec![MembershipChange::AddNodes(vec![(
+                "127.0.0.1:8086".to_owned(),
+                100
+            )])]
+        );
+    }
+
+    #[test]
+    fn maintenance_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![("127.0.0.1:8085".to_owned(), 100)],
+        );
+        assert!(manager
+            .set_maintenance("127.0.0.1:8086".to_owned(), true)
+            .is_some());
+        assert!(manager
+            .set_maintenance("127.0.0.1:8085".to_owned(), false)
+            .is_some());
+        assert!(manager
+            .set_maintenance("127.0.0.1:8085".to_owned(), true)
+            .is_none());
+        assert!(manager
+            .set_maintenance("127.0.0.1:8085".to_owned(), true)
+            .is_some());
+        assert_eq!(
+            manager.get_maintenance_servers(),
+            vec!["127.0.0.1:8085".to_owned()]
+        );
+        assert!(manager
+            .set_maintenance("127.0.0.1:8085".to_owned(), false)
+            .is_none());
+        assert!(manager.get_maintenance_servers().is_empty());
+    }
+
+    #[test]
+    fn server_groups_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![
+                ("127.0.0.1:8085".to_owned(), 100),
+                ("127.0.0.1:8086".to_owned(), 100),
+            ],
+        );
+        manager.init_server_groups([("127.0.0.1:8085".to_owned(), "rack1".to_owned())].into());
+        *manager.cluster_status.lock().unwrap() = ClusterStatus::Idle;
+        assert!(manager.reserve_volume("v").is_none());
+        assert!(manager
+            .commit_volume(VolumeInfo {
+                name: "v".to_owned(),
+                ..Default::default()
+            })
+            .is_none());
+        assert!(manager.pin_volume("w", "rack1").is_some());
+        assert!(manager.pin_volume("v", "rack2").is_some());
+        assert!(manager.pin_volume("v", "").is_none());
+        assert!(manager.pin_volume("v", "rack1").is_none());
+        assert_eq!(manager.get_cluster_status(), ClusterStatus::NodesStarting);
+        let groups = manager.get_new_hash_ring_info().unwrap().groups;
+        assert_eq!(groups.volumes.get("v").unwrap(), "rack1");
+        assert!(manager.get_hash_ring_info().groups.volumes.is_empty());
+        assert_eq!(manager.list_volume_infos()[0].group, "rack1");
+
+        // the last server of a group with pinned volumes cannot leave it.
+        *manager.hashring.write().unwrap() = manager.new_hashring.write().unwrap().take();
+        *manager.cluster_status.lock().unwrap() = ClusterStatus::Idle;
+        assert!(manager
+            .set_server_group("127.0.0.1:8085".to_owned(), "".to_owned())
+            .is_some());
+        assert!(manager
+            .set_server_group("127.0.0.1:8086".to_owned(), "rack2".to_owned())
+            .is_none());
+        assert_eq!(manager.get_cluster_status(), ClusterStatus::NodesStarting);
+        // a volume created again with the name of a pinned one is pinned.
+        assert!(manager.release_volume("v").is_none());
+        assert!(manager
+            .commit_volume(VolumeInfo {
+                name: "v".to_owned(),
+                ..Default::default()
+            })
+            .is_none());
+        assert_eq!(manager.list_volume_infos()[0].group, "rack1");
+    }
+
+    #[test]
+    fn replication_warnings_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![
+                ("127.0.0.1:8085".to_owned(), 100),
+                ("127.0.0.1:8086".to_owned(), 100),
+            ],
+        );
+        for name in ["v", "w"] {
+            assert!(manager.reserve_volume(name).is_none());
+            assert!(manager
+                .commit_volume(VolumeInfo {
+                    name: name.to_owned(),
+                    ..Default::default()
+                })
+                .is_none());
+        }
+        assert!(manager
+            .set_volume(SetVolumeSendMetaData {
+                name: "v".to_owned(),
+                replication: Some(2),
+                ..Default::default()
+            })
+            .is_none());
+        assert!(manager.replication_warnings(None).is_empty());
+
+        let rack = FailureDomain {
+            zone: "".to_owned(),
+            rack: "r1".to_owned(),
+        };
+        assert!(manager
+            .set_server_domain("127.0.0.1:8087".to_owned(), rack.clone())
+            .is_some());
+        for server in ["127.0.0.1:8085", "127.0.0.1:8086"] {
+            assert!(manager
+                .set_server_domain(server.to_owned(), rack.clone())
+                .is_none());
+        }
+        // only the volume with replicas cannot be placed.
+        assert_eq!(manager.replication_warnings(None).len(), 1);
+        assert!(manager.replication_warnings(Some("w")).is_empty());
+        assert_eq!(
+            manager.get_hash_ring_info().groups.domains["127.0.0.1:8085"],
+            rack
+        );
+    }
+
+    #[test]
+    fn reserve_volume_test() {
+        let manager = Manager::new(
+            HashAlgorithm::Conhash,
+            vec![("127.0.0.1:8085".to_owned(), 100)],
+        );
+        assert!(manager.reserve_volume("v").is_none());
+        // another client creating the same volume.
+        assert!(manager.reserve_volume("v").is_some());
+        assert!(manager
+            .commit_volume(VolumeInfo {
+                name: "v".to_owned(),
+                ..Default::default()
+            })
+            .is_none());
+        assert!(manager.reserve_volume("v").is_some());
+        assert!(manager.release_volume("v").is_none());
+        assert!(manager.reserve_volume("v").is_none());
+
+        // the reservation of a crashed client expires.
+        manager.volumes.lock().unwrap().insert(
+            "w".to_owned(),
+            VolumeState::Reserved(Instant::now() - VOLUME_RESERVATION_TIMEOUT),
+        );
+        assert!(manager.reserve_volume("w").is_none());
+    }
+
+    #[test]
+    fn volume_registry_test() {
+        let path = "/tmp/test_volume_registry.yaml";
+        let _ = std::fs::remove_file(path);
+        let info = VolumeInfo {
+            name: "v".to_owned(),
+            created_at: 1,
+            creator: "0@node".to_owned(),
+            description: "".to_owned(),
+            quota: 100,
+            layout: FileLayout::default(),
+            group: "".to_owned(),
+        };
+        {
+            let manager = Manager::new(HashAlgorithm::Conhash, vec![]);
+            manager.load_volume_registry(path).unwrap();
+            let set_quota = SetVolumeSendMetaData {
+                name: "v".to_owned(),
+                quota: Some(200),
+                ..Default::default()
+            };
+            assert!(manager.set_volume(set_quota).is_some());
+            assert!(manager.reserve_volume("v").is_none());
+            assert!(manager.commit_volume(info.clone()).is_none());
+            assert!(manager.reserve_volume("w").is_none());
+            assert!(manager
+                .set_volume(SetVolumeSendMetaData {
+                    name: "v".to_owned(),
+                    description: Some("datasets".to_owned()),
+                    stripe_count: Some(4),
+                    ..Default::default()
+                })
+                .is_none());
+            // an invalid layout changes nothing.
+            assert!(manager
+                .set_volume(SetVolumeSendMetaData {
+                    name: "v".to_owned(),
+                    description: Some("other".to_owned()),
+                    chunk_size: Some(1000),
+                    ..Default::default()
+                })
+                .is_some());
+        }
+        // the created volumes are kept over a restart, not the reserved ones.
+        let manager = Manager::new(HashAlgorithm::Conhash, vec![]);
+        manager.load_volume_registry(path).unwrap();
+        assert_eq!(
+            manager.list_volume_infos(),
+            vec![VolumeInfo {
+                description: "datasets".to_owned(),
+                layout: FileLayout {
+                    stripe_count: 4,
+                    ..Default::default()
+                },
+                ..info
+            }]
+        );
+        assert!(manager.reserve_volume("v").is_some());
+        assert!(manager.reserve_volume("w").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
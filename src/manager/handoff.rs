@@ -0,0 +1,153 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The handoffs of the files moved by a rebalance. A server transferring a
+ * file records its handoff once the new owner synced the file and matched
+ * its checksum, and deletes its copy only after that. A server restarted in
+ * between deletes the copies of the handoffs recorded for it, the files it
+ * did not record yet are still its own. The handoffs of a server are
+ * dropped once it finishes the rebalance.
+ * They are appended to `<registry>.handoffs`, a length and a bincode
+ * (server, path) per handoff, and synced before they are acknowledged; a
+ * record cut by a crash was never acknowledged and is left out on load.
+ */
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    io::Write,
+};
+
+#[derive(Default)]
+pub struct HandoffLog {
+    // server -> the paths it handed off.
+    handoffs: BTreeMap<String, BTreeSet<String>>,
+    file: Option<File>,
+    path: Option<String>,
+}
+
+impl HandoffLog {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut handoffs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("read handoffs {} failed: {}", path, e)),
+        };
+        let mut rest = content.as_slice();
+        while rest.len() >= 4 {
+            let length = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            let Some(record) = rest.get(4..4 + length) else {
+                break;
+            };
+            let (server, file_path): (String, String) = bincode::deserialize(record)
+                .map_err(|e| format!("parse handoffs {} failed: {}", path, e))?;
+            handoffs.entry(server).or_default().insert(file_path);
+            rest = &rest[4 + length..];
+        }
+        let mut log = Self {
+            handoffs,
+            file: None,
+            path: Some(path.to_owned()),
+        };
+        // rewritten without the cut record, if any.
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    // record adds the handoff of path by server, durable when it returns.
+    pub fn record(&mut self, server: &str, path: &str) -> Result<(), String> {
+        if let Some(file) = self.file.as_mut() {
+            let record = bincode::serialize(&(server, path)).unwrap();
+            let mut data = (record.len() as u32).to_le_bytes().to_vec();
+            data.extend_from_slice(&record);
+            file.write_all(&data)
+                .and_then(|_| file.sync_data())
+                .map_err(|e| format!("record handoff {} failed: {}", path, e))?;
+        }
+        self.handoffs
+            .entry(server.to_owned())
+            .or_default()
+            .insert(path.to_owned());
+        Ok(())
+    }
+
+    // get returns the paths handed off by server.
+    pub fn get(&self, server: &str) -> Vec<String> {
+        self.handoffs
+            .get(server)
+            .map(|paths| paths.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    // finish drops the handoffs of server, which deleted all its copies.
+    pub fn finish(&mut self, server: &str) -> Result<(), String> {
+        if self.handoffs.remove(server).is_none() {
+            return Ok(());
+        }
+        self.rewrite()
+    }
+
+    // rewrite writes the handoffs to a temporary file renamed over the log,
+    // and appends to it from then on.
+    fn rewrite(&mut self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let temp_path = format!("{}.tmp", path);
+        let mut data = Vec::new();
+        for (server, paths) in &self.handoffs {
+            for file_path in paths {
+                let record = bincode::serialize(&(server, file_path)).unwrap();
+                data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+                data.extend_from_slice(&record);
+            }
+        }
+        self.file = Some(
+            File::create(&temp_path)
+                .and_then(|mut file| file.write_all(&data).and_then(|_| file.sync_data()))
+                .and_then(|_| std::fs::rename(&temp_path, path))
+                .and_then(|_| OpenOptions::new().append(true).open(path))
+                .map_err(|e| format!("save handoffs {} failed: {}", path, e))?,
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::HandoffLog;
+
+    #[test]
+    fn handoff_log_test() {
+        let path = "/tmp/test_handoffs";
+        let _ = std::fs::remove_file(path);
+        let mut log = HandoffLog::load(path).unwrap();
+        log.record("a:1", "v/f").unwrap();
+        log.record("a:1", "v/g").unwrap();
+        log.record("b:1", "v/h").unwrap();
+        assert_eq!(log.get("a:1"), ["v/f", "v/g"]);
+        assert!(log.get("c:1").is_empty());
+
+        // a record cut by a crash is left out.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(&[100, 0, 0, 0, 1])
+            .unwrap();
+        let mut log = HandoffLog::load(path).unwrap();
+        assert_eq!(log.get("a:1"), ["v/f", "v/g"]);
+        assert_eq!(log.get("b:1"), ["v/h"]);
+
+        log.finish("a:1").unwrap();
+        log.record("b:1", "v/i").unwrap();
+        let log = HandoffLog::load(path).unwrap();
+        assert!(log.get("a:1").is_empty());
+        assert_eq!(log.get("b:1"), ["v/h", "v/i"]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The daily usage of the volumes, for capacity planning: the usage sent by
+ * the servers with their heartbeats is added to the day of the manager, in
+ * UTC. It is kept next to the volume registry in `<registry>.history`,
+ * written at most every HISTORY_SAVE_INTERVAL, so a crash of the manager
+ * loses the usage since the last save. The days older than HISTORY_DAYS are
+ * dropped.
+ */
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::common::serialization::{VolumeDay, VolumeUsage};
+
+pub const HISTORY_DAYS: usize = 400;
+pub const HISTORY_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400
+}
+
+#[derive(Default)]
+pub struct VolumeHistory {
+    // volume -> its days, oldest first.
+    volumes: BTreeMap<String, Vec<VolumeDay>>,
+    path: Option<String>,
+    dirty: bool,
+    last_save: Option<Instant>,
+}
+
+impl VolumeHistory {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let volumes = match std::fs::read_to_string(path) {
+            Ok(content) => serde_yaml::from_str(&content)
+                .map_err(|e| format!("parse volume history {} failed: {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("read volume history {} failed: {}", path, e)),
+        };
+        Ok(Self {
+            volumes,
+            path: Some(path.to_owned()),
+            ..Default::default()
+        })
+    }
+
+    pub fn add(&mut self, usage: &VolumeUsage, day: u64) {
+        let days = self.volumes.entry(usage.volume.clone()).or_default();
+        match days.last_mut() {
+            Some(last) if last.day >= day => last.add(usage),
+            _ => {
+                let mut today = VolumeDay {
+                    day,
+                    ..Default::default()
+                };
+                today.add(usage);
+                days.push(today);
+                if days.len() > HISTORY_DAYS {
+                    days.drain(..days.len() - HISTORY_DAYS);
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    // get returns the days of volume, the last ones first.
+    pub fn get(&self, volume: &str, days: usize) -> Vec<VolumeDay> {
+        self.volumes
+            .get(volume)
+            .map(|v| v.iter().rev().take(days).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove(&mut self, volume: &str) {
+        if self.volumes.remove(volume).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    // save writes the history to a temporary file renamed over it, if it
+    // changed and was not written in the last HISTORY_SAVE_INTERVAL.
+    pub fn save(&mut self, now: Instant) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) if self.dirty => path,
+            _ => return Ok(()),
+        };
+        if matches!(self.last_save, Some(last) if now < last + HISTORY_SAVE_INTERVAL) {
+            return Ok(());
+        }
+        self.last_save = Some(now);
+        let temp_path = format!("{}.tmp", path);
+        serde_yaml::to_string(&self.volumes)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&temp_path, content).map_err(|e| e.to_string()))
+            .and_then(|_| std::fs::rename(&temp_path, path).map_err(|e| e.to_string()))
+            .map_err(|e| format!("save volume history {} failed: {}", path, e))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{day_of, VolumeHistory, HISTORY_DAYS, HISTORY_SAVE_INTERVAL};
+    use crate::common::serialization::{VolumeDay, VolumeUsage};
+
+    #[test]
+    fn volume_history_test() {
+        let path = "/tmp/test_volume_history.yaml";
+        let _ = std::fs::remove_file(path);
+        let usage = VolumeUsage {
+            volume: "v".to_owned(),
+            ops: 3,
+            write_bytes: 100,
+            files_created: 1,
+            ..Default::default()
+        };
+        let mut history = VolumeHistory::load(path).unwrap();
+        history.add(&usage, 19000);
+        history.add(&usage, 19000);
+        history.add(&usage, 19002);
+        let days = history.get("v", 7);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].day, 19002);
+        assert_eq!(days[1].ops, 6);
+        assert_eq!(days[1].write_bytes, 200);
+        assert_eq!(days[1].date(), "2022-01-08");
+        assert!(history.get("w", 7).is_empty());
+
+        let now = Instant::now();
+        history.save(now).unwrap();
+        assert_eq!(VolumeHistory::load(path).unwrap().get("v", 7), days);
+        // saved at most once per interval.
+        history.remove("v");
+        history.save(now + Duration::from_secs(1)).unwrap();
+        assert_eq!(VolumeHistory::load(path).unwrap().get("v", 7), days);
+        history.save(now + HISTORY_SAVE_INTERVAL).unwrap();
+        assert!(VolumeHistory::load(path).unwrap().get("v", 7).is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        for day in 0..HISTORY_DAYS as u64 + 10 {
+            history.add(&usage, day);
+        }
+        let days = history.get("v", usize::MAX);
+        assert_eq!(days.len(), HISTORY_DAYS);
+        assert_eq!(days.last().unwrap().day, 10);
+
+        assert_eq!(day_of(SystemTime::UNIX_EPOCH), 0);
+        let day = VolumeDay {
+            day: 11016,
+            ..Default::default()
+        };
+        assert_eq!(day.date(), "2000-02-29");
+    }
+}
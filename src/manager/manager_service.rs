@@ -2,12 +2,26 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use crate::{
-    common::serialization::{
-        AddNodesSendMetaData, ClusterStatus, DeleteNodesSendMetaData, GetClusterStatusRecvMetaData,
-        GetHashRingInfoRecvMetaData, ManagerOperationType, ServerStatus,
+    common::{
+        dns::{resolve_addresses, DISCOVERY_INTERVAL},
+        errors::status_to_string,
+        hash_ring::HashAlgorithm,
+        serialization::{
+            AddNodesSendMetaData, ClusterStatus, DeleteNodesSendMetaData,
+            GetClusterStatusRecvMetaData, GetHandoffsRecvMetaData, GetHashRingInfoRecvMetaData,
+            GetHashRingSnapshotRecvMetaData, GetMaintenanceRecvMetaData,
+            GetMembershipChangesRecvMetaData, GetVolumeHistoryRecvMetaData,
+            GetVolumeHistorySendMetaData, HeartbeatSendMetaData, ManagerOperationType,
+            PinVolumeSendMetaData, RecordHandoffSendMetaData, ServerStatus,
+            SetMaintenanceSendMetaData, SetServerDomainSendMetaData, SetServerGroupSendMetaData,
+            SetVolumeSendMetaData, SetWeightSendMetaData, VolumeInfo,
+        },
     },
     rpc::server::Handler,
 };
@@ -15,7 +29,7 @@ use crate::{
 use super::core::Manager;
 
 use async_trait::async_trait;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 
 pub struct ManagerService {
@@ -39,16 +53,33 @@ pub struct MetadataResponse {
     pub instances: Vec<String>,
 }
 
+// discover_servers adds the servers of the srv records of names to the
+// cluster as they appear, see common/dns.rs.
+pub async fn discover_servers(manager: Arc<Manager>, names: Vec<String>, weight: usize) {
+    loop {
+        tokio::time::sleep(DISCOVERY_INTERVAL).await;
+        if manager.closed.load(std::sync::atomic::Ordering::Relaxed) {
+            break;
+        }
+        let servers = resolve_addresses(names.clone()).await;
+        if let Some(e) = manager.add_discovered_servers(servers, weight) {
+            error!("add discovered servers failed: {}", e);
+        }
+    }
+}
+
 pub async fn update_server_status(manager: Arc<Manager>) {
     loop {
         tokio::time::sleep(Duration::from_secs(1)).await;
         if manager.closed.load(std::sync::atomic::Ordering::Relaxed) {
             break;
         }
+        manager.expire_members(Instant::now());
+        manager.save_history(Instant::now());
         let status = *manager.cluster_status.lock().unwrap();
         debug!("current cluster status is {:?}", status);
         match status {
-            ClusterStatus::Idle => {}
+            ClusterStatus::Idle => manager.start_queued_changes(),
             ClusterStatus::NodesStarting => {
                 // if all servers is ready, change the cluster status to SyncNewHashRing
                 let flag = manager
@@ -115,12 +146,13 @@ pub async fn update_server_status(manager: Arc<Manager>) {
                     .all(|kv| kv.1.status == ServerStatus::Finishing);
                 if flag {
                     tokio::time::sleep(Duration::from_secs(1)).await;
+                    let mut cluster_status = manager.cluster_status.lock().unwrap();
                     let _ = manager
                         .hashring
                         .write()
                         .unwrap()
                         .replace(manager.new_hashring.read().unwrap().clone().unwrap());
-                    *manager.cluster_status.lock().unwrap() = ClusterStatus::Finishing;
+                    *cluster_status = ClusterStatus::Finishing;
                     info!("all servers is ready, change the cluster status to Finishing");
                 }
             }
@@ -134,6 +166,7 @@ pub async fn update_server_status(manager: Arc<Manager>) {
                     .all(|kv| kv.1.status == ServerStatus::Finished);
                 if flag {
                     tokio::time::sleep(Duration::from_secs(1)).await;
+                    let mut cluster_status = manager.cluster_status.lock().unwrap();
                     let mut new_hashring = manager.new_hashring.write().unwrap();
                     manager
                         .servers
@@ -142,7 +175,8 @@ pub async fn update_server_status(manager: Arc<Manager>) {
                         .retain(|k, _| new_hashring.as_ref().unwrap().contains(k));
                     // move new_hashring to hashring
                     let _ = new_hashring.take().unwrap();
-                    *manager.cluster_status.lock().unwrap() = ClusterStatus::Idle;
+                    manager.running_changes.lock().unwrap().clear();
+                    *cluster_status = ClusterStatus::Idle;
                     info!("all servers is ready, change the cluster status to Idle");
                 }
             }
@@ -166,14 +200,21 @@ pub async fn update_server_status(manager: Arc<Manager>) {
 }
 
 impl ManagerService {
-    pub fn new(servers: Vec<(String, usize)>) -> Self {
-        let manager = Arc::new(Manager::new(servers));
+    pub fn new(hash_algorithm: HashAlgorithm, servers: Vec<(String, usize)>) -> Self {
+        let manager = Arc::new(Manager::new(hash_algorithm, servers));
         ManagerService { manager }
     }
 }
 
 #[async_trait]
 impl Handler for ManagerService {
+    fn operation_name(&self, operation_type: u32) -> String {
+        match ManagerOperationType::try_from(operation_type) {
+            Ok(r#type) => format!("{:?}", r#type),
+            Err(_) => operation_type.to_string(),
+        }
+    }
+
     async fn dispatch(
         &self,
         id: u32,
@@ -202,12 +243,36 @@ impl Handler for ManagerService {
                 ))
             }
             ManagerOperationType::GetHashRing => {
-                let hash_ring_info = self.manager.get_hash_ring_info();
+                let info = self.manager.get_hash_ring_info();
 
-                info!("connection {} get hash ring: {:?}", id, hash_ring_info);
+                info!("connection {} get hash ring: {:?}", id, info.servers);
 
+                let response_meta_data = bincode::serialize(&GetHashRingInfoRecvMetaData {
+                    hash_algorithm: info.algorithm,
+                    hash_ring_info: info.servers,
+                    groups: info.groups,
+                    epoch: info.epoch,
+                })
+                .unwrap();
+                Ok((
+                    0,
+                    0,
+                    response_meta_data.len(),
+                    0,
+                    response_meta_data,
+                    Vec::new(),
+                ))
+            }
+            ManagerOperationType::GetHashRingSnapshot => {
+                let snapshot = self.manager.get_hash_ring_snapshot();
+                debug!(
+                    "connection {} get hash ring snapshot: {:?}, epoch {}",
+                    id,
+                    snapshot.status,
+                    snapshot.routing().epoch
+                );
                 let response_meta_data =
-                    bincode::serialize(&GetHashRingInfoRecvMetaData { hash_ring_info }).unwrap();
+                    bincode::serialize(&GetHashRingSnapshotRecvMetaData::from(snapshot)).unwrap();
                 Ok((
                     0,
                     0,
@@ -218,11 +283,15 @@ impl Handler for ManagerService {
                 ))
             }
             ManagerOperationType::GetNewHashRing => match self.manager.get_new_hash_ring_info() {
-                Ok(hash_ring_info) => {
-                    info!("connection {} get new hash ring: {:?}", id, hash_ring_info);
-                    let response_meta_data =
-                        bincode::serialize(&GetHashRingInfoRecvMetaData { hash_ring_info })
-                            .unwrap();
+                Ok(info) => {
+                    info!("connection {} get new hash ring: {:?}", id, info.servers);
+                    let response_meta_data = bincode::serialize(&GetHashRingInfoRecvMetaData {
+                        hash_algorithm: info.algorithm,
+                        hash_ring_info: info.servers,
+                        groups: info.groups,
+                        epoch: info.epoch,
+                    })
+                    .unwrap();
                     Ok((
                         0,
                         0,
@@ -264,6 +333,288 @@ impl Handler for ManagerService {
                     }
                 }
             }
+            ManagerOperationType::SetWeight => {
+                let meta_data = bincode::deserialize::<SetWeightSendMetaData>(&metadata).unwrap();
+                info!(
+                    "connection {} set weight of {} to {}",
+                    id, meta_data.server_address, meta_data.weight
+                );
+                match self
+                    .manager
+                    .set_weight(meta_data.server_address, meta_data.weight)
+                {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("set weight error: {}", e);
+                        Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::SetServerGroup => {
+                let meta_data =
+                    bincode::deserialize::<SetServerGroupSendMetaData>(&metadata).unwrap();
+                info!(
+                    "connection {} set group of {} to {:?}",
+                    id, meta_data.server_address, meta_data.group
+                );
+                match self
+                    .manager
+                    .set_server_group(meta_data.server_address, meta_data.group)
+                {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("set server group error: {}", e);
+                        Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::SetServerDomain => {
+                let meta_data =
+                    bincode::deserialize::<SetServerDomainSendMetaData>(&metadata).unwrap();
+                info!(
+                    "connection {} set domain of {} to {:?}",
+                    id, meta_data.server_address, meta_data.domain
+                );
+                match self
+                    .manager
+                    .set_server_domain(meta_data.server_address, meta_data.domain)
+                {
+                    None => {
+                        let warnings = self.manager.replication_warnings(None);
+                        for warning in &warnings {
+                            warn!("{}", warning);
+                        }
+                        let response_meta_data = bincode::serialize(&warnings).unwrap();
+                        Ok((
+                            0,
+                            0,
+                            response_meta_data.len(),
+                            0,
+                            response_meta_data,
+                            Vec::new(),
+                        ))
+                    }
+                    Some(e) => {
+                        error!("set server domain error: {}", e);
+                        Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::PinVolume => {
+                let meta_data = bincode::deserialize::<PinVolumeSendMetaData>(&metadata).unwrap();
+                info!(
+                    "connection {} pin volume {} to {:?}",
+                    id, meta_data.name, meta_data.group
+                );
+                match self.manager.pin_volume(&meta_data.name, &meta_data.group) {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("pin volume error: {}", e);
+                        Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::SetMaintenance => {
+                let meta_data =
+                    bincode::deserialize::<SetMaintenanceSendMetaData>(&metadata).unwrap();
+                info!(
+                    "connection {} set maintenance of {}: {}",
+                    id, meta_data.server_address, meta_data.enter
+                );
+                match self
+                    .manager
+                    .set_maintenance(meta_data.server_address, meta_data.enter)
+                {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("set maintenance error: {}", e);
+                        Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::GetMaintenance => {
+                let servers = self.manager.get_maintenance_servers();
+                debug!("connection {} get maintenance servers: {:?}", id, servers);
+                let response_meta_data =
+                    bincode::serialize(&GetMaintenanceRecvMetaData { servers }).unwrap();
+                Ok((
+                    0,
+                    0,
+                    response_meta_data.len(),
+                    0,
+                    response_meta_data,
+                    Vec::new(),
+                ))
+            }
+            ManagerOperationType::RecordHandoff => {
+                let server_address = std::str::from_utf8(&path).unwrap();
+                let meta_data: RecordHandoffSendMetaData = bincode::deserialize(&metadata).unwrap();
+                match self.manager.record_handoff(server_address, &meta_data.path) {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("record handoff error: {}", e);
+                        Ok((libc::EIO, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::GetHandoffs => {
+                let server_address = std::str::from_utf8(&path).unwrap();
+                let paths = self.manager.handoffs(server_address);
+                info!(
+                    "connection {} get {} handoffs of {}",
+                    id,
+                    paths.len(),
+                    server_address
+                );
+                let response_meta_data =
+                    bincode::serialize(&GetHandoffsRecvMetaData { paths }).unwrap();
+                Ok((
+                    0,
+                    0,
+                    response_meta_data.len(),
+                    0,
+                    response_meta_data,
+                    Vec::new(),
+                ))
+            }
+            ManagerOperationType::Health => {
+                let report = self.manager.health(Instant::now());
+                debug!("connection {} health: {:?}", id, report);
+                let response_meta_data = bincode::serialize(&report).unwrap();
+                Ok((
+                    0,
+                    0,
+                    response_meta_data.len(),
+                    0,
+                    response_meta_data,
+                    Vec::new(),
+                ))
+            }
+            ManagerOperationType::GetVolumeHistory => {
+                let meta_data: GetVolumeHistorySendMetaData =
+                    bincode::deserialize(&metadata).unwrap();
+                debug!("connection {} get history of volume {}", id, meta_data.name);
+                match self
+                    .manager
+                    .volume_history(&meta_data.name, meta_data.days as usize)
+                {
+                    Some(days) => {
+                        let response_meta_data =
+                            bincode::serialize(&GetVolumeHistoryRecvMetaData { days }).unwrap();
+                        Ok((
+                            0,
+                            0,
+                            response_meta_data.len(),
+                            0,
+                            response_meta_data,
+                            Vec::new(),
+                        ))
+                    }
+                    None => Ok((libc::ENOENT, 0, 0, 0, Vec::new(), Vec::new())),
+                }
+            }
+            ManagerOperationType::GetMembershipChanges => {
+                let (running, queued) = self.manager.get_membership_changes();
+                debug!(
+                    "connection {} get membership changes: {:?} {:?}",
+                    id, running, queued
+                );
+                let response_meta_data =
+                    bincode::serialize(&GetMembershipChangesRecvMetaData { running, queued })
+                        .unwrap();
+                Ok((
+                    0,
+                    0,
+                    response_meta_data.len(),
+                    0,
+                    response_meta_data,
+                    Vec::new(),
+                ))
+            }
+            ManagerOperationType::ReserveVolume => {
+                let name = String::from_utf8(path).unwrap();
+                info!("connection {} reserve volume {}", id, name);
+                match self.manager.reserve_volume(&name) {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("reserve volume error: {}", e);
+                        Ok((libc::EEXIST, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::CommitVolume => {
+                let info = bincode::deserialize::<VolumeInfo>(&metadata).unwrap();
+                info!("connection {} commit volume {}", id, info.name);
+                match self.manager.commit_volume(info) {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("commit volume error: {}", e);
+                        Ok((libc::EIO, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::ReleaseVolume => {
+                let name = String::from_utf8(path).unwrap();
+                info!("connection {} release volume {}", id, name);
+                match self.manager.release_volume(&name) {
+                    None => Ok((0, 0, 0, 0, Vec::new(), Vec::new())),
+                    Some(e) => {
+                        error!("release volume error: {}", e);
+                        Ok((libc::EIO, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::ListVolumeInfos => {
+                let infos = self.manager.list_volume_infos();
+                debug!("connection {} list {} volume infos", id, infos.len());
+                let response_data = bincode::serialize(&infos).unwrap();
+                Ok((0, 0, 0, response_data.len(), Vec::new(), response_data))
+            }
+            ManagerOperationType::SetVolume => {
+                let meta_data = bincode::deserialize::<SetVolumeSendMetaData>(&metadata).unwrap();
+                info!("connection {} set volume {}", id, meta_data.name);
+                let name = meta_data.name.clone();
+                match self.manager.set_volume(meta_data) {
+                    None => {
+                        let warnings = self.manager.replication_warnings(Some(&name));
+                        for warning in &warnings {
+                            warn!("{}", warning);
+                        }
+                        let response_meta_data = bincode::serialize(&warnings).unwrap();
+                        Ok((
+                            0,
+                            0,
+                            response_meta_data.len(),
+                            0,
+                            response_meta_data,
+                            Vec::new(),
+                        ))
+                    }
+                    Some(e) => {
+                        error!("set volume error: {}", e);
+                        Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            ManagerOperationType::Heartbeat => {
+                let server = String::from_utf8(path).unwrap();
+                let meta_data = bincode::deserialize::<HeartbeatSendMetaData>(&metadata).unwrap();
+                debug!("connection {} heartbeat of {} {}", id, server, meta_data.id);
+                match self
+                    .manager
+                    .heartbeat(&server, &meta_data.id, Instant::now())
+                {
+                    Ok(_) => {
+                        self.manager
+                            .record_usage(&meta_data.usage, SystemTime::now());
+                        Ok((0, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                    Err(e) => {
+                        error!("heartbeat of {} refused: {}", server, status_to_string(e));
+                        Ok((e, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
             ManagerOperationType::UpdateServerStatus => {
                 info!("connection {} update server status", id);
                 match self.manager.set_server_status(
@@ -3,4 +3,6 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod core;
+pub mod handoff;
+pub mod history;
 pub mod manager_service;
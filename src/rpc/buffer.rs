@@ -0,0 +1,184 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * BufferPool keeps the buffers of finished requests and responses for reuse,
+ * so that requests do not allocate fresh Vecs at high IOPS.
+ * Buffers are grouped by size class, class i holds buffers of at least
+ * MIN_CLASS_SIZE << i bytes.
+ */
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+const MIN_CLASS_SIZE: usize = 4096;
+const CLASS_NUM: usize = 11; // 4KB ~ 4MB
+const MAX_CACHED_BUFFERS: usize = 256;
+
+lazy_static! {
+    pub static ref BUFFER_POOL: BufferPool = BufferPool::new();
+}
+
+pub struct BufferPool {
+    classes: Vec<Mutex<Vec<Vec<u8>>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    recycled: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub recycled: u64,
+    pub dropped: u64,
+    pub cached_buffers: u64,
+    pub cached_bytes: u64,
+}
+
+impl std::fmt::Display for BufferPoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "hits: {}, misses: {}, recycled: {}, dropped: {}, cached buffers: {}, cached bytes: {}",
+            self.hits,
+            self.misses,
+            self.recycled,
+            self.dropped,
+            self.cached_buffers,
+            self.cached_bytes
+        )
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        Self {
+            classes: (0..CLASS_NUM).map(|_| Mutex::new(Vec::new())).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            recycled: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    // get returns a zeroed buffer of the given length.
+    pub fn get(&self, length: usize) -> Vec<u8> {
+        if length == 0 {
+            return Vec::new();
+        }
+        let class = match class_for_get(length) {
+            Some(class) => class,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return vec![0; length];
+            }
+        };
+        match self.classes[class].lock().pop() {
+            Some(mut buffer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buffer.clear();
+                buffer.resize(length, 0);
+                buffer
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let mut buffer = Vec::with_capacity(MIN_CLASS_SIZE << class);
+                buffer.resize(length, 0);
+                buffer
+            }
+        }
+    }
+
+    // put gives a buffer back to the pool.
+    pub fn put(&self, buffer: Vec<u8>) {
+        let class = match class_for_put(buffer.capacity()) {
+            Some(class) => class,
+            None => return,
+        };
+        let mut buffers = self.classes[class].lock();
+        if buffers.len() >= MAX_CACHED_BUFFERS {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        buffers.push(buffer);
+        self.recycled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        let mut stats = BufferPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            recycled: self.recycled.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for class in self.classes.iter() {
+            let buffers = class.lock();
+            stats.cached_buffers += buffers.len() as u64;
+            stats.cached_bytes += buffers.iter().map(|b| b.capacity() as u64).sum::<u64>();
+        }
+        stats
+    }
+}
+
+// the smallest class whose buffers can hold length bytes.
+fn class_for_get(length: usize) -> Option<usize> {
+    let class = length
+        .div_ceil(MIN_CLASS_SIZE)
+        .next_power_of_two()
+        .trailing_zeros() as usize;
+    (class < CLASS_NUM).then_some(class)
+}
+
+// the largest class that a buffer of this capacity can serve, none for the
+// buffers larger than the largest class, which are not kept.
+fn class_for_put(capacity: usize) -> Option<usize> {
+    if !(MIN_CLASS_SIZE..=MIN_CLASS_SIZE << (CLASS_NUM - 1)).contains(&capacity) {
+        return None;
+    }
+    Some((capacity / MIN_CLASS_SIZE).ilog2() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{class_for_get, class_for_put, BufferPool, MIN_CLASS_SIZE};
+
+    #[test]
+    fn size_class_test() {
+        assert_eq!(class_for_get(1), Some(0));
+        assert_eq!(class_for_get(MIN_CLASS_SIZE), Some(0));
+        assert_eq!(class_for_get(MIN_CLASS_SIZE + 1), Some(1));
+        assert_eq!(class_for_get(MIN_CLASS_SIZE * 1024), Some(10));
+        assert_eq!(class_for_get(MIN_CLASS_SIZE * 1024 + 1), None);
+        assert_eq!(class_for_put(MIN_CLASS_SIZE - 1), None);
+        assert_eq!(class_for_put(MIN_CLASS_SIZE * 3), Some(1));
+        assert_eq!(class_for_put(MIN_CLASS_SIZE * 1024), Some(10));
+        assert_eq!(class_for_put(MIN_CLASS_SIZE * 1024 + 1), None);
+        assert_eq!(class_for_put(MIN_CLASS_SIZE * 4096), None);
+    }
+
+    #[test]
+    fn reuse_test() {
+        let pool = BufferPool::new();
+        let mut buffer = pool.get(5000);
+        assert_eq!(buffer.len(), 5000);
+        buffer[0] = 1;
+        pool.put(buffer);
+        let buffer = pool.get(6000);
+        assert_eq!(buffer.len(), 6000);
+        assert_eq!(buffer[0], 0);
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.recycled), (1, 1, 1));
+        assert_eq!(stats.cached_buffers, 0);
+    }
+}
@@ -5,7 +5,7 @@
 use std::{
     ptr::drop_in_place,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicUsize, Ordering},
         Arc,
     },
     time::Duration,
@@ -19,8 +19,15 @@ use tokio::{
 
 use super::protocol::REQUEST_POOL_SIZE;
 
+// callback status
+const CALLBACK_FREE: u32 = 0;
+const CALLBACK_WAITING: u32 = 1;
+// a frame of a streamed response is being written to the buffers.
+const CALLBACK_RECEIVING: u32 = 2;
+
 pub struct OperationCallback {
-    pub data: *const u8,
+    // the response data is scattered into these buffers in order.
+    pub data: Vec<(*const u8, usize)>,
     pub meta_data: *const u8,
     pub data_length: usize,
     pub meta_data_length: usize,
@@ -42,7 +49,7 @@ impl Default for OperationCallback {
 impl OperationCallback {
     pub fn new(receiver: Receiver<()>) -> Self {
         Self {
-            data: std::ptr::null(),
+            data: Vec::new(),
             meta_data: std::ptr::null(),
             data_length: 0,
             meta_data_length: 0,
@@ -66,6 +73,8 @@ pub struct CallbackPool {
     // batch id for each callback, used to check if the callback is for the current request.
     // Each request has a unique batch id and will increase the batch id by 1 for each callback.
     batch: Vec<AtomicU32>,
+    // data length received by the frames of a streamed response.
+    received: Vec<AtomicUsize>,
 }
 
 impl Default for CallbackPool {
@@ -81,6 +90,7 @@ impl CallbackPool {
         let ids = kanal::unbounded_async::<u32>();
         let mut callback_status = Vec::with_capacity(REQUEST_POOL_SIZE);
         let mut batch = Vec::with_capacity(REQUEST_POOL_SIZE);
+        let mut received = Vec::with_capacity(REQUEST_POOL_SIZE);
         for i in 0..REQUEST_POOL_SIZE as u32 {
             let (sender, receiver) = tokio::sync::mpsc::channel(1);
             callbacks.push(Box::into_raw(Box::new(OperationCallback::new(receiver)))
@@ -89,6 +99,7 @@ impl CallbackPool {
             ids.0.clone_sync().send(i).unwrap();
             callback_status.push(AtomicU32::new(0));
             batch.push(AtomicU32::new(0));
+            received.push(AtomicUsize::new(0));
         }
         Self {
             callbacks,
@@ -96,6 +107,7 @@ impl CallbackPool {
             ids,
             callback_status,
             batch,
+            received,
         }
     }
 
@@ -113,19 +125,34 @@ impl CallbackPool {
         &self,
         rsp_meta_data: &mut [u8],
         rsp_data: &mut [u8],
+    ) -> Result<(u32, u32), String> {
+        self.register_callback_vectored(rsp_meta_data, &mut [rsp_data])
+            .await
+    }
+
+    // register_callback_vectored registers a callback whose response data is
+    // written directly into several buffers, without an intermediate copy.
+    pub async fn register_callback_vectored(
+        &self,
+        rsp_meta_data: &mut [u8],
+        rsp_data: &mut [&mut [u8]],
     ) -> Result<(u32, u32), String> {
         match self.ids.1.clone().recv().await {
             Ok(id) => {
                 let callback =
                     unsafe { &mut *(self.callbacks[id as usize] as *mut OperationCallback) };
-                callback.data = rsp_data.as_ptr();
+                callback.data.clear();
+                callback
+                    .data
+                    .extend(rsp_data.iter().map(|data| (data.as_ptr(), data.len())));
                 callback.meta_data = rsp_meta_data.as_ptr();
 
                 // codes above can be reordered, so we don't use AcqRel. Maybe directly use fetch and store is better.
                 let batch = self.batch[id as usize].fetch_add(1, Ordering::Release);
+                self.received[id as usize].store(0, Ordering::Release);
 
-                assert!(self.callback_status[id as usize].load(Ordering::Acquire) == 0); // only for debug
-                self.callback_status[id as usize].store(1, Ordering::Release);
+                assert!(self.callback_status[id as usize].load(Ordering::Acquire) == CALLBACK_FREE); // only for debug
+                self.callback_status[id as usize].store(CALLBACK_WAITING, Ordering::Release);
                 Ok((batch + 1, id))
             }
             Err(e) => Err(format!("register callback failed: {}", e)),
@@ -139,8 +166,8 @@ impl CallbackPool {
         }
         if self.callback_status[id as usize]
             .compare_exchange(
-                1,
-                0,
+                CALLBACK_WAITING,
+                CALLBACK_FREE,
                 std::sync::atomic::Ordering::AcqRel,
                 std::sync::atomic::Ordering::Acquire,
             )
@@ -152,10 +179,59 @@ impl CallbackPool {
         }
     }
 
+    // lock_frame_if_not_timeout is lock_if_not_timeout for a frame of a streamed response
+    // that is not the last one. The callback must be released by unlock_frame.
+    pub fn lock_frame_if_not_timeout(&self, batch: u32, id: u32) -> Result<(), String> {
+        let now_batch = self.batch[id as usize].load(Ordering::Acquire);
+        if batch != now_batch {
+            return Err("wrong batch, already timed out".into());
+        }
+        match self.callback_status[id as usize].compare_exchange(
+            CALLBACK_WAITING,
+            CALLBACK_RECEIVING,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("lock failed, already timed out".into()),
+        }
+    }
+
+    // unlock_frame records the data length of a received frame and waits for the next frame.
+    pub fn unlock_frame(&self, id: u32, data_length: usize) {
+        self.received[id as usize].fetch_add(data_length, Ordering::AcqRel);
+        self.callback_status[id as usize].store(CALLBACK_WAITING, Ordering::Release);
+    }
+
+    pub fn received_length(&self, id: u32) -> usize {
+        self.received[id as usize].load(Ordering::Acquire)
+    }
+
+    // get_data_refs returns the registered buffers after the data already received
+    // by streamed frames, cut to data_length bytes in total.
+    // If the buffers are smaller than data_length, the rest is not covered.
     #[allow(clippy::mut_from_ref)]
-    pub fn get_data_ref(&self, id: u32, data_length: usize) -> &mut [u8] {
+    pub fn get_data_refs(&self, id: u32, data_length: usize) -> Vec<&mut [u8]> {
         let callback = self.callbacks[id as usize];
-        unsafe { std::slice::from_raw_parts_mut((*callback).data as *mut u8, data_length) }
+        let mut skip = self.received_length(id);
+        let mut left = data_length;
+        let mut refs = Vec::new();
+        for (data, length) in unsafe { (*callback).data.iter() } {
+            if left == 0 {
+                break;
+            }
+            if skip >= *length {
+                skip -= *length;
+                continue;
+            }
+            let length = std::cmp::min(*length - skip, left);
+            refs.push(unsafe {
+                std::slice::from_raw_parts_mut((*data as *mut u8).add(skip), length)
+            });
+            skip = 0;
+            left -= length;
+        }
+        refs
     }
 
     #[allow(clippy::mut_from_ref)]
@@ -196,29 +272,38 @@ impl CallbackPool {
     ) -> Result<(i32, u32, usize, usize), String> {
         let receiver =
             unsafe { (*(self.callbacks[id as usize] as *mut OperationCallback)).get_receiver() };
-        let result = timeout(req_timeout, receiver.recv()).await;
-        let is_ok = match result {
-            Ok(r) => match r {
-                Some(_) => true,
-                None => panic!("wait_for_callback error"),
-            },
-            Err(_) => {
-                match self.callback_status[id as usize].compare_exchange(
-                    1,
-                    0,
-                    Ordering::AcqRel,
-                    Ordering::Acquire,
-                ) {
-                    Ok(_) => false,
-                    Err(_) => {
-                        // This means that the response has been received, and signal has been sent.
-                        match timeout(req_timeout, receiver.recv()).await {
-                            Ok(r) => match r {
-                                Some(_) => true,
-                                None => panic!("wait_for_callback error"),
-                            },
-                            Err(_) => {
-                                panic!("unexpected wait_for_callback timeout")
+        let mut progress = self.received_length(id);
+        let is_ok = loop {
+            match timeout(req_timeout, receiver.recv()).await {
+                Ok(r) => match r {
+                    Some(_) => break true,
+                    None => panic!("wait_for_callback error"),
+                },
+                Err(_) => {
+                    // frames of a streamed response are still arriving, keep waiting.
+                    let received = self.received_length(id);
+                    if received != progress {
+                        progress = received;
+                        continue;
+                    }
+                    match self.callback_status[id as usize].compare_exchange(
+                        CALLBACK_WAITING,
+                        CALLBACK_FREE,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => break false,
+                        Err(CALLBACK_RECEIVING) => continue,
+                        Err(_) => {
+                            // This means that the response has been received, and signal has been sent.
+                            match timeout(req_timeout, receiver.recv()).await {
+                                Ok(r) => match r {
+                                    Some(_) => break true,
+                                    None => panic!("wait_for_callback error"),
+                                },
+                                Err(_) => {
+                                    panic!("unexpected wait_for_callback timeout")
+                                }
                             }
                         }
                     }
@@ -301,11 +386,35 @@ mod tests {
             .register_callback(&mut recv_meta_data, &mut recv_data)
             .await;
         match result {
-            Ok((batch, id)) => assert_eq!(pool.get_data_ref(id, recv_data.len()), &mut recv_data),
+            Ok((batch, id)) => {
+                assert_eq!(
+                    pool.get_data_refs(id, recv_data.len()),
+                    vec![&mut recv_data]
+                )
+            }
             Err(_) => assert!(false),
         }
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_get_data_refs_vectored() {
+        let pool = CallbackPool::new();
+        let mut recv_meta_data = vec![];
+        let mut first = vec![0u8; 4];
+        let mut second = vec![0u8; 8];
+        let (_, id) = pool
+            .register_callback_vectored(&mut recv_meta_data, &mut [&mut first, &mut second])
+            .await
+            .unwrap();
+        let refs = pool.get_data_refs(id, 6);
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].len(), 4);
+        assert_eq!(refs[1].len(), 2);
+        let refs = pool.get_data_refs(id, 3);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].len(), 3);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_wait_for_callback() {
         let mut pool = CallbackPool::new();
@@ -409,4 +518,30 @@ mod tests {
             Err(_) => assert!(false),
         }
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_streamed_frames() {
+        let pool = CallbackPool::new();
+        let mut recv_meta_data = vec![];
+        let mut recv_data = vec![0u8; 8];
+        let (batch, id) = pool
+            .register_callback(&mut recv_meta_data, &mut recv_data)
+            .await
+            .unwrap();
+        for frame in [[1u8; 4], [2u8; 4]] {
+            pool.lock_frame_if_not_timeout(batch, id).unwrap();
+            pool.get_data_refs(id, 4)[0].copy_from_slice(&frame);
+            pool.unlock_frame(id, 4);
+        }
+        assert_eq!(pool.received_length(id), 8);
+        pool.lock_if_not_timeout(batch, id).unwrap();
+        assert!(pool.get_data_refs(id, 0).is_empty());
+        pool.response(id, 0, 0, 0, 8).await.unwrap();
+        let (_, _, _, data_length) = pool
+            .wait_for_callback(id, time::Duration::from_secs(3))
+            .await
+            .unwrap();
+        assert_eq!(data_length, 8);
+        assert_eq!(recv_data, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+    }
 }
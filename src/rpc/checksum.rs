@@ -0,0 +1,83 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * CRC32 of the requests and responses, to detect the frames corrupted by a
+ * NIC or a driver on long-lived connections, which TCP checksums can miss.
+ * It is negotiated like the compression: a client with checksums enabled
+ * sets REQUEST_FLAG_ACCEPT_CHECKSUM on its requests, and the server answers
+ * them with RESPONSE_FLAG_CHECKSUM and the CRC, after which the client sends
+ * the CRC of its requests too, until the connection is reset.
+ * The CRC covers the header and the body of a frame, and follows the body
+ * without being counted in total_length. A corrupt frame fails its request
+ * with EIO, and CORRUPT_FRAMES_RESET corrupt frames in a row reset the
+ * connection.
+ */
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+pub const CHECKSUM_SIZE: usize = 4;
+pub const CORRUPT_FRAMES_RESET: u32 = 3;
+// error of a frame whose CRC does not match.
+pub const CORRUPT_FRAME: &str = "corrupt frame";
+
+lazy_static! {
+    // corrupt requests received by the servers of this process.
+    pub static ref SERVER_CORRUPT_FRAMES: AtomicU64 = AtomicU64::new(0);
+}
+
+pub fn checksum(bufs: &[&[u8]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for buf in bufs {
+        hasher.update(buf);
+    }
+    hasher.finalize()
+}
+
+// CorruptFrames counts the corrupt frames received by a connection.
+#[derive(Default)]
+pub struct CorruptFrames {
+    total: AtomicU64,
+    in_a_row: AtomicU32,
+}
+
+impl CorruptFrames {
+    // record counts a frame received, and returns true if the connection
+    // should be reset.
+    pub fn record(&self, corrupt: bool) -> bool {
+        if !corrupt {
+            self.in_a_row.store(0, Ordering::Relaxed);
+            return false;
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        self.in_a_row.fetch_add(1, Ordering::Relaxed) + 1 >= CORRUPT_FRAMES_RESET
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, CorruptFrames, CORRUPT_FRAMES_RESET};
+
+    #[test]
+    fn checksum_test() {
+        assert_eq!(checksum(&[b"1234", b"56789"]), checksum(&[b"123456789"]));
+        assert_eq!(checksum(&[b"123456789"]), 0xcbf43926);
+
+        let frames = CorruptFrames::default();
+        for _ in 1..CORRUPT_FRAMES_RESET {
+            assert!(!frames.record(true));
+        }
+        assert!(!frames.record(false));
+        for _ in 1..CORRUPT_FRAMES_RESET {
+            assert!(!frames.record(true));
+        }
+        assert!(frames.record(true));
+        assert_eq!(frames.total(), 2 * CORRUPT_FRAMES_RESET as u64 - 1);
+    }
+}
@@ -3,14 +3,27 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::{
+    buffer::BUFFER_POOL,
     callback::CallbackPool,
-    connection::ClientConnection,
-    protocol::{CONNECTION_RETRY_TIMES, SEND_RETRY_TIMES},
+    checksum::CORRUPT_FRAME,
+    compression::COMPRESSION_THRESHOLD,
+    connection::{ClientConnection, ConnectionStats},
+    protocol::{
+        ResponseHeader, CONNECTION_RETRY_TIMES, RESPONSE_FLAG_ACCEPT_COMPRESSED,
+        RESPONSE_FLAG_CHECKSUM, RESPONSE_FLAG_COMPRESSED, RESPONSE_FLAG_MORE, SEND_RETRY_TIMES,
+    },
 };
 use async_trait::async_trait;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use log::{error, info, warn};
-use std::{marker::PhantomData, sync::Arc, time::Duration};
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[async_trait]
@@ -78,7 +91,13 @@ pub struct RpcClient<
     S: StreamCreator<R, W>,
 > {
     connections: DashMap<String, Arc<ClientConnection<W, R>>>,
+    // servers connected to on the first request to them.
+    lazy_connections: DashSet<String>,
     pool: Arc<CallbackPool>,
+    // compress the data of the requests and responses, see compression.rs.
+    compression: AtomicBool,
+    // check the CRC of the requests and responses, see checksum.rs.
+    checksum: AtomicBool,
     stream_creator: PhantomData<S>,
 }
 
@@ -105,7 +124,10 @@ impl<
         let pool = Arc::new(pool);
         Self {
             connections: DashMap::new(),
+            lazy_connections: DashSet::new(),
             pool,
+            compression: AtomicBool::new(false),
+            checksum: AtomicBool::new(false),
             stream_creator: PhantomData,
         }
     }
@@ -114,15 +136,57 @@ impl<
         self.pool.free();
     }
 
+    // set_compression turns the compression of the data on or off for all the
+    // connections.
+    pub fn set_compression(&self, enabled: bool) {
+        self.compression.store(enabled, Ordering::Relaxed);
+        for connection in self.connections.iter() {
+            connection.set_compression(enabled);
+        }
+    }
+
+    // set_checksum turns the checksums of the requests and responses on or
+    // off for all the connections.
+    pub fn set_checksum(&self, enabled: bool) {
+        self.checksum.store(enabled, Ordering::Relaxed);
+        for connection in self.connections.iter() {
+            connection.set_checksum(enabled);
+        }
+    }
+
     pub async fn add_connection(&self, server_address: &str) -> Result<(), String> {
-        for _ in 0..CONNECTION_RETRY_TIMES {
+        self.dial(server_address, CONNECTION_RETRY_TIMES).await
+    }
+
+    // add_lazy_connection registers a server to connect to on the first
+    // request to it.
+    pub fn add_lazy_connection(&self, server_address: &str) {
+        if !self.connections.contains_key(server_address) {
+            self.lazy_connections.insert(server_address.to_string());
+        }
+    }
+
+    // dial_lazy_connection tries once to connect to a server registered with
+    // add_lazy_connection, and does nothing for the other servers.
+    pub async fn dial_lazy_connection(&self, server_address: &str) -> Result<(), String> {
+        match self.lazy_connections.contains(server_address) {
+            true => self.dial(server_address, 1).await,
+            false => Ok(()),
+        }
+    }
+
+    async fn dial(&self, server_address: &str, retry_times: i32) -> Result<(), String> {
+        for i in 0..retry_times {
             match S::create_stream(server_address).await {
                 Ok((read_stream, write_stream)) => {
+                    self.lazy_connections.remove(server_address);
                     if self.connections.contains_key(server_address) {
                         warn!("connection already exists: {}", server_address);
                         return Ok(());
                     }
                     let connection = Arc::new(ClientConnection::new(server_address, write_stream));
+                    connection.set_compression(self.compression.load(Ordering::Relaxed));
+                    connection.set_checksum(self.checksum.load(Ordering::Relaxed));
                     tokio::spawn(parse_response(
                         read_stream,
                         connection.clone(),
@@ -133,13 +197,14 @@ impl<
                     info!("add connection to {} success", server_address);
                     return Ok(());
                 }
-                Err(e) => {
+                Err(e) if i + 1 < retry_times => {
                     warn!(
                         "connect to {} failed: {}, wait for a while",
                         server_address, e
                     );
                     tokio::time::sleep(Duration::from_secs(1)).await;
                 }
+                Err(e) => warn!("connect to {} failed: {}", server_address, e),
             }
         }
         Err(format!(
@@ -182,9 +247,17 @@ impl<
     }
 
     pub fn remove_connection(&self, server_address: &str) {
+        self.lazy_connections.remove(server_address);
         self.connections.remove(server_address);
     }
 
+    pub fn connection_stats(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .iter()
+            .map(|connection| connection.stats())
+            .collect()
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub async fn call_remote(
         &self,
@@ -202,6 +275,45 @@ impl<
         recv_data: &mut [u8],
         timeout: Duration,
     ) -> Result<(), String> {
+        self.call_remote_vectored(
+            server_address,
+            operation_type,
+            req_flags,
+            path,
+            send_meta_data,
+            &[send_data],
+            status,
+            rsp_flags,
+            recv_meta_data_length,
+            recv_data_length,
+            recv_meta_data,
+            &mut [recv_data],
+            timeout,
+        )
+        .await
+    }
+
+    // call_remote_vectored is call_remote with scatter-gather data buffers:
+    // send_data is gathered into one request and the response data is
+    // scattered into recv_data directly from the socket.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn call_remote_vectored(
+        &self,
+        server_address: &str,
+        operation_type: u32,
+        req_flags: u32,
+        path: &str,
+        send_meta_data: &[u8],
+        send_data: &[&[u8]],
+        status: &mut i32,
+        rsp_flags: &mut u32,
+        recv_meta_data_length: &mut usize,
+        recv_data_length: &mut usize,
+        recv_meta_data: &mut [u8],
+        recv_data: &mut [&mut [u8]],
+        timeout: Duration,
+    ) -> Result<(), String> {
+        self.dial_lazy_connection(server_address).await?;
         for _ in 0..SEND_RETRY_TIMES {
             let connection = match self.connections.get(server_address) {
                 Some(connection) => connection,
@@ -212,11 +324,12 @@ impl<
             };
             let (batch, id) = self
                 .pool
-                .register_callback(recv_meta_data, recv_data)
+                .register_callback_vectored(recv_meta_data, recv_data)
                 .await?; // TODO: unregister callback when error
 
+            let start = Instant::now();
             if let Err(e) = connection
-                .send_request(
+                .send_request_vectored(
                     batch,
                     id,
                     operation_type,
@@ -243,6 +356,7 @@ impl<
             }
             match self.pool.wait_for_callback(id, timeout).await {
                 Ok((s, f, meta_data_length, data_length)) => {
+                    connection.record_latency(start.elapsed());
                     *status = s;
                     *rsp_flags = f;
                     *recv_meta_data_length = meta_data_length;
@@ -289,11 +403,57 @@ pub async fn parse_response<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin>(
         let batch = header.batch;
         let id = header.id;
         let total_length = header.total_length;
+        let more = header.flags & RESPONSE_FLAG_MORE != 0;
+        if header.flags & RESPONSE_FLAG_ACCEPT_COMPRESSED != 0 {
+            connection.accept_compression();
+        }
+
+        // the body of a response with a checksum is read and checked first.
+        let body = match header.flags & RESPONSE_FLAG_CHECKSUM {
+            0 => None,
+            _ => {
+                connection.accept_checksum();
+                match connection
+                    .receive_checked_response(&mut read_stream, &header)
+                    .await
+                {
+                    Ok(body) => Some(body),
+                    Err(e) if e == CORRUPT_FRAME => {
+                        error!(
+                            "corrupt response from {}, batch: {}, id: {}",
+                            connection.server_address, batch, id
+                        );
+                        if connection.record_corrupt_frame(true) {
+                            error!(
+                                "too many corrupt responses, reset connection to {}",
+                                connection.server_address
+                            );
+                            connection.disconnect();
+                            break;
+                        }
+                        if pool.lock_if_not_timeout(batch, id).is_ok() {
+                            if let Err(e) = pool.response(id, libc::EIO, 0, 0, 0).await {
+                                error!("Error writing response back: {}", e);
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Error receiving response: {}", e);
+                        break;
+                    }
+                }
+            }
+        };
+        if body.is_some() {
+            connection.record_corrupt_frame(false);
+        }
 
         let result = {
-            match pool.lock_if_not_timeout(batch, id) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
+            match more {
+                true => pool.lock_frame_if_not_timeout(batch, id),
+                false => pool.lock_if_not_timeout(batch, id),
             }
         };
         match result {
@@ -303,6 +463,10 @@ pub async fn parse_response<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin>(
                     "parse_response lock timeout: {}, batch: {}, id: {}",
                     e, batch, id
                 );
+                if let Some(body) = body {
+                    BUFFER_POOL.put(body);
+                    continue;
+                }
                 let result = connection
                     .clean_response(&mut read_stream, total_length)
                     .await;
@@ -317,24 +481,35 @@ pub async fn parse_response<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin>(
             }
         }
 
-        if let Err(e) = connection
-            .receive_response(
-                &mut read_stream,
-                pool.get_meta_data_ref(id, header.meta_data_length as usize),
-                pool.get_data_ref(id, header.data_length as usize),
-            )
-            .await
-        {
-            error!("Error receiving response: {}", e);
-            break;
+        let result = match &body {
+            Some(body) => receive_body(&connection, &pool, &mut body.as_slice(), &header).await,
+            None => receive_body(&connection, &pool, &mut read_stream, &header).await,
         };
+        if let Some(body) = body {
+            BUFFER_POOL.put(body);
+        }
+        let (status, received) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Error receiving response: {}", e);
+                break;
+            }
+        };
+        if more && status == header.status {
+            pool.unlock_frame(id, received);
+            continue;
+        }
         if let Err(e) = pool
             .response(
                 id,
-                header.status,
-                header.flags,
+                status,
+                header.flags
+                    & !(RESPONSE_FLAG_MORE
+                        | RESPONSE_FLAG_COMPRESSED
+                        | RESPONSE_FLAG_ACCEPT_COMPRESSED
+                        | RESPONSE_FLAG_CHECKSUM),
                 header.meta_data_length as usize,
-                header.data_length as usize,
+                pool.received_length(id) + received,
             )
             .await
         {
@@ -343,3 +518,62 @@ pub async fn parse_response<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin>(
         };
     }
 }
+
+// receive_body reads the body of a response into the buffers of its callback,
+// and returns its status and the data length received.
+async fn receive_body<
+    W: AsyncWriteExt + Unpin,
+    R: AsyncReadExt + Unpin,
+    S: AsyncReadExt + Unpin,
+>(
+    connection: &ClientConnection<W, R>,
+    pool: &CallbackPool,
+    read_stream: &mut S,
+    header: &ResponseHeader,
+) -> Result<(i32, usize), String> {
+    let (batch, id) = (header.batch, header.id);
+    let meta_data = pool.get_meta_data_ref(id, header.meta_data_length as usize);
+    if header.flags & RESPONSE_FLAG_COMPRESSED != 0 {
+        // the stream is still in sync if the data cannot be decompressed,
+        // only this request fails.
+        return match connection
+            .receive_compressed_response(read_stream, meta_data, header.data_length)
+            .await?
+        {
+            Ok(raw) => {
+                let mut received = 0;
+                for buf in pool.get_data_refs(id, raw.len()) {
+                    buf.copy_from_slice(&raw[received..received + buf.len()]);
+                    received += buf.len();
+                }
+                Ok((header.status, received))
+            }
+            Err(e) => {
+                error!(
+                    "decompress response failed: {}, batch: {}, id: {}",
+                    e, batch, id
+                );
+                Ok((libc::EIO, 0))
+            }
+        };
+    }
+    if connection.compression() && header.data_length as usize >= COMPRESSION_THRESHOLD {
+        let length = header.data_length as usize;
+        connection.record_received(length, length);
+    }
+    let mut data = pool.get_data_refs(id, header.data_length as usize);
+    let received: usize = data.iter().map(|d| d.len()).sum();
+    connection
+        .receive_response(read_stream, meta_data, &mut data)
+        .await?;
+    if received < header.data_length as usize {
+        error!(
+            "response data is larger than the buffers, batch: {}, id: {}",
+            batch, id
+        );
+        connection
+            .clean_response(read_stream, header.data_length - received as u32)
+            .await?;
+    }
+    Ok((header.status, received))
+}
@@ -0,0 +1,146 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * LZ4 compression of the data of requests and responses, for deployments
+ * where the network is slower than the cpus.
+ * A client with compression enabled sets REQUEST_FLAG_ACCEPT_COMPRESSED on
+ * its requests, and the server compresses the data of the responses to them.
+ * The server sets RESPONSE_FLAG_ACCEPT_COMPRESSED on these responses, after
+ * which the client compresses the data of its requests too, until the
+ * connection is reset. Servers that do not know the flags never set it, so
+ * they are sent plain requests.
+ * Only the data of at least COMPRESSION_THRESHOLD bytes is compressed, and it
+ * is sent plain if it does not get smaller. Compressed data is the length of
+ * the plain data, 4 bytes, followed by the LZ4 block.
+ */
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+use super::protocol::MAX_DATA_LENGTH;
+
+pub const COMPRESSION_THRESHOLD: usize = 4096;
+
+lazy_static! {
+    // compression of the requests received and the responses sent by the
+    // servers of this process.
+    pub static ref SERVER_COMPRESSION_STATS: CompressionStats = CompressionStats::default();
+}
+
+// compress returns the compressed data, or None if it is too small or does
+// not get smaller.
+pub fn compress(data: &[&[u8]]) -> Option<Vec<u8>> {
+    let length: usize = data.iter().map(|d| d.len()).sum();
+    if length < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    let gathered;
+    let input = match data {
+        [data] => *data,
+        _ => {
+            gathered = data.concat();
+            &gathered
+        }
+    };
+    let mut output = vec![0u8; 4 + lz4_flex::block::get_maximum_output_size(length)];
+    output[..4].copy_from_slice(&(length as u32).to_le_bytes());
+    let compressed = lz4_flex::block::compress_into(input, &mut output[4..]).ok()?;
+    if 4 + compressed >= length {
+        return None;
+    }
+    output.truncate(4 + compressed);
+    Some(output)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 4 {
+        return Err("compressed data is too short".to_string());
+    }
+    let length = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    if length > MAX_DATA_LENGTH {
+        return Err(format!("decompressed length is too long: {}", length));
+    }
+    let mut output = vec![0u8; length];
+    match lz4_flex::block::decompress_into(&data[4..], &mut output) {
+        Ok(n) if n == length => Ok(output),
+        Ok(n) => Err(format!("decompressed {} bytes instead of {}", n, length)),
+        Err(e) => Err(format!("decompress error: {}", e)),
+    }
+}
+
+// CompressionStats counts the data considered for compression, before and
+// after it, plain data counting the same on both sides.
+#[derive(Default)]
+pub struct CompressionStats {
+    raw_bytes: AtomicU64,
+    wire_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    // compress is compress counting the data in the stats.
+    pub fn compress(&self, data: &[&[u8]]) -> Option<Vec<u8>> {
+        let length: usize = data.iter().map(|d| d.len()).sum();
+        let compressed = compress(data);
+        if length >= COMPRESSION_THRESHOLD {
+            self.record(length, compressed.as_ref().map_or(length, |c| c.len()));
+        }
+        compressed
+    }
+
+    pub fn record(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.raw_bytes
+            .fetch_add(raw_bytes as u64, Ordering::Relaxed);
+        self.wire_bytes
+            .fetch_add(wire_bytes as u64, Ordering::Relaxed);
+    }
+
+    // ratio returns the raw bytes by the bytes sent for them, 1 if none.
+    pub fn ratio(&self) -> f64 {
+        let wire_bytes = self.wire_bytes.load(Ordering::Relaxed);
+        match wire_bytes {
+            0 => 1.0,
+            _ => self.raw_bytes.load(Ordering::Relaxed) as f64 / wire_bytes as f64,
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "raw bytes: {}, wire bytes: {}, ratio: {:.2}",
+            self.raw_bytes.load(Ordering::Relaxed),
+            self.wire_bytes.load(Ordering::Relaxed),
+            self.ratio()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, CompressionStats, COMPRESSION_THRESHOLD};
+
+    #[test]
+    fn compression_test() {
+        let data = vec![b'a'; COMPRESSION_THRESHOLD * 4];
+        let compressed = compress(&[&data[..100], &data[100..]]).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        // too small, or not getting smaller.
+        assert_eq!(compress(&[&data[..COMPRESSION_THRESHOLD - 1]]), None);
+        let random: Vec<u8> = (0..COMPRESSION_THRESHOLD).map(|_| rand::random()).collect();
+        assert_eq!(compress(&[&random]), None);
+
+        assert!(decompress(&compressed[..compressed.len() - 1]).is_err());
+        assert!(decompress(&[1, 2]).is_err());
+
+        let stats = CompressionStats::default();
+        assert_eq!(stats.ratio(), 1.0);
+        stats.record(400, 100);
+        stats.record(100, 100);
+        assert_eq!(stats.ratio(), 2.5);
+    }
+}
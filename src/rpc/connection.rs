@@ -2,13 +2,26 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::{io::IoSlice, marker::PhantomData, sync::atomic::AtomicU32};
+use std::{
+    io::IoSlice,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
 
+use super::buffer::BUFFER_POOL;
+use super::checksum::{
+    checksum, CorruptFrames, CHECKSUM_SIZE, CORRUPT_FRAME, SERVER_CORRUPT_FRAMES,
+};
+use super::compression::{decompress, CompressionStats, SERVER_COMPRESSION_STATS};
 use super::protocol::{
     RequestHeader, ResponseHeader, MAX_DATA_LENGTH, MAX_FILENAME_LENGTH, MAX_METADATA_LENGTH,
-    REQUEST_HEADER_SIZE, RESPONSE_HEADER_SIZE,
+    REQUEST_FLAG_ACCEPT_CHECKSUM, REQUEST_FLAG_ACCEPT_COMPRESSED, REQUEST_FLAG_CHECKSUM,
+    REQUEST_FLAG_COMPRESSED, REQUEST_HEADER_SIZE, RESPONSE_FLAG_ACCEPT_COMPRESSED,
+    RESPONSE_FLAG_CHECKSUM, RESPONSE_FLAG_COMPRESSED, RESPONSE_HEADER_SIZE,
 };
 use log::{error, info};
+use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::Mutex,
@@ -17,11 +30,45 @@ use tokio::{
 const CONNECTED: u32 = 0;
 const DISCONNECTED: u32 = 1;
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionStats {
+    pub server_address: String,
+    pub connected: bool,
+    pub requests: u64,
+    pub sent_bytes: u64,
+    pub reconnects: u64,
+    // latency of the requests answered, from sending to the response.
+    pub avg_latency_us: u64,
+    pub max_latency_us: u64,
+    // bytes of the data compressed or considered for it, by the bytes sent
+    // or received for them.
+    pub compression_ratio: f64,
+    // responses whose checksum did not match.
+    pub corrupt_frames: u64,
+}
+
 pub struct ClientConnection<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> {
     pub server_address: String,
     write_stream: Mutex<Option<W>>,
     status: AtomicU32,
     reconneting_lock: Mutex<()>,
+    requests: AtomicU64,
+    sent_bytes: AtomicU64,
+    reconnects: AtomicU64,
+    responses: AtomicU64,
+    total_latency_us: AtomicU64,
+    max_latency_us: AtomicU64,
+
+    // compress the data of the requests and responses, see compression.rs.
+    compression: AtomicBool,
+    // the server accepts compressed requests.
+    peer_compression: AtomicBool,
+    compression_stats: CompressionStats,
+    // check the CRC of the requests and responses, see checksum.rs.
+    checksum: AtomicBool,
+    // the server accepts requests with a CRC.
+    peer_checksum: AtomicBool,
+    corrupt_frames: CorruptFrames,
 
     phantom_data: PhantomData<R>,
 
@@ -40,6 +87,18 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ClientConnection<W, R> {
             write_stream: Mutex::new(Some(write_stream)),
             status: AtomicU32::new(CONNECTED),
             reconneting_lock: Mutex::new(()),
+            requests: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            responses: AtomicU64::new(0),
+            total_latency_us: AtomicU64::new(0),
+            max_latency_us: AtomicU64::new(0),
+            compression: AtomicBool::new(false),
+            peer_compression: AtomicBool::new(false),
+            compression_stats: CompressionStats::default(),
+            checksum: AtomicBool::new(false),
+            peer_checksum: AtomicBool::new(false),
+            corrupt_frames: CorruptFrames::default(),
             phantom_data: PhantomData,
             _send_lock: Mutex::new(()),
         }
@@ -67,8 +126,72 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ClientConnection<W, R> {
 
     pub async fn reset_connection(&self, write_stream: W) {
         self.write_stream.lock().await.replace(write_stream);
+        // the server may have been replaced by one without compression.
+        self.peer_compression.store(false, Ordering::Relaxed);
+        self.peer_checksum.store(false, Ordering::Relaxed);
         self.status
             .store(CONNECTED, std::sync::atomic::Ordering::SeqCst);
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_compression(&self, enabled: bool) {
+        self.compression.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn compression(&self) -> bool {
+        self.compression.load(Ordering::Relaxed)
+    }
+
+    // accept_compression is called when the server tells it accepts
+    // compressed requests.
+    pub fn accept_compression(&self) {
+        self.peer_compression.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_checksum(&self, enabled: bool) {
+        self.checksum.store(enabled, Ordering::Relaxed);
+    }
+
+    // accept_checksum is called when the server sends a response with a CRC.
+    pub fn accept_checksum(&self) {
+        self.peer_checksum.store(true, Ordering::Relaxed);
+    }
+
+    // record_corrupt_frame counts a response with a CRC, and returns true if
+    // the connection should be reset.
+    pub fn record_corrupt_frame(&self, corrupt: bool) -> bool {
+        self.corrupt_frames.record(corrupt)
+    }
+
+    pub fn record_received(&self, raw_bytes: usize, wire_bytes: usize) {
+        self.compression_stats.record(raw_bytes, wire_bytes);
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        self.total_latency_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+        self.max_latency_us.fetch_max(latency_us, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let responses = self.responses.load(Ordering::Relaxed);
+        ConnectionStats {
+            server_address: self.server_address.clone(),
+            connected: self.is_connected(),
+            requests: self.requests.load(Ordering::Relaxed),
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            avg_latency_us: self
+                .total_latency_us
+                .load(Ordering::Relaxed)
+                .checked_div(responses)
+                .unwrap_or(0),
+            max_latency_us: self.max_latency_us.load(Ordering::Relaxed),
+            compression_ratio: self.compression_stats.ratio(),
+            corrupt_frames: self.corrupt_frames.total(),
+        }
     }
 
     // request
@@ -84,65 +207,94 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ClientConnection<W, R> {
         filename: &str,
         meta_data: &[u8],
         data: &[u8],
+    ) -> Result<(), String> {
+        self.send_request_vectored(
+            batch,
+            id,
+            operation_type,
+            flags,
+            filename,
+            meta_data,
+            &[data],
+        )
+        .await
+    }
+
+    // send_request_vectored sends the data gathered from several buffers as one request.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn send_request_vectored(
+        &self,
+        batch: u32,
+        id: u32,
+        operation_type: u32,
+        flags: u32,
+        filename: &str,
+        meta_data: &[u8],
+        data: &[&[u8]],
     ) -> Result<(), String> {
         if !self.is_connected() {
             return Err("connection is not connected".to_string());
         }
+        let mut flags = flags;
+        let mut compressed = None;
+        if self.compression() {
+            flags |= REQUEST_FLAG_ACCEPT_COMPRESSED;
+            if self.peer_compression.load(Ordering::Relaxed) {
+                compressed = self.compression_stats.compress(data);
+            }
+        }
+        if self.checksum.load(Ordering::Relaxed) {
+            flags |= REQUEST_FLAG_ACCEPT_CHECKSUM;
+            if self.peer_checksum.load(Ordering::Relaxed) {
+                flags |= REQUEST_FLAG_CHECKSUM;
+            }
+        }
+        let compressed_data;
+        let data = match &compressed {
+            Some(compressed) => {
+                flags |= REQUEST_FLAG_COMPRESSED;
+                compressed_data = [compressed.as_slice()];
+                &compressed_data[..]
+            }
+            None => data,
+        };
         let filename_length = filename.len();
         let meta_data_length = meta_data.len();
-        let data_length = data.len();
+        let data_length: usize = data.iter().map(|d| d.len()).sum();
         let total_length = filename_length + meta_data_length + data_length;
-        let mut request = Vec::with_capacity(total_length + REQUEST_HEADER_SIZE);
-        request.extend_from_slice(&batch.to_le_bytes());
-        request.extend_from_slice(&id.to_le_bytes());
-        request.extend_from_slice(&operation_type.to_le_bytes());
-        request.extend_from_slice(&flags.to_le_bytes());
-        request.extend_from_slice(&(total_length as u32).to_le_bytes());
-        request.extend_from_slice(&(filename_length as u32).to_le_bytes());
-        request.extend_from_slice(&(meta_data_length as u32).to_le_bytes());
-        request.extend_from_slice(&(data_length as u32).to_le_bytes());
-        request.extend_from_slice(filename.as_bytes());
-        let mut stream = self.write_stream.lock().await;
-        let mut offset = 0;
-        loop {
-            if offset >= request.len() + meta_data_length + data_length {
-                break;
-            }
-            if offset < request.len() {
-                let bufs: &[_] = &[
-                    IoSlice::new(&request[offset..]),
-                    IoSlice::new(meta_data),
-                    IoSlice::new(data),
-                ];
-                offset += stream
-                    .as_mut()
-                    .unwrap()
-                    .write_vectored(bufs)
-                    .await
-                    .map_err(|e| e.to_string())?;
-            } else if offset < request.len() + meta_data_length {
-                let bufs: &[_] = &[
-                    IoSlice::new(&meta_data[offset - request.len()..]),
-                    IoSlice::new(data),
-                ];
-                offset += stream
-                    .as_mut()
-                    .unwrap()
-                    .write_vectored(bufs)
-                    .await
-                    .map_err(|e| e.to_string())?;
-            } else {
-                let bufs: &[_] = &[IoSlice::new(
-                    &data[offset - request.len() - meta_data_length..],
-                )];
-                offset += stream
-                    .as_mut()
-                    .unwrap()
-                    .write_vectored(bufs)
-                    .await
-                    .map_err(|e| e.to_string())?;
-            }
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        for (i, value) in [
+            batch,
+            id,
+            operation_type,
+            flags,
+            total_length as u32,
+            filename_length as u32,
+            meta_data_length as u32,
+            data_length as u32,
+        ]
+        .iter()
+        .enumerate()
+        {
+            header[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let mut bufs = Vec::with_capacity(data.len() + 4);
+        bufs.push(header.as_slice());
+        bufs.push(filename.as_bytes());
+        bufs.push(meta_data);
+        bufs.extend_from_slice(data);
+        let crc;
+        if flags & REQUEST_FLAG_CHECKSUM != 0 {
+            crc = checksum(&bufs).to_le_bytes();
+            bufs.push(&crc);
         }
+        let mut stream = self.write_stream.lock().await;
+        write_all_vectored(stream.as_mut().unwrap(), &bufs).await?;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(
+            bufs.iter().map(|buf| buf.len() as u64).sum(),
+            Ordering::Relaxed,
+        );
         Ok(())
     }
 
@@ -170,34 +322,77 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ClientConnection<W, R> {
         })
     }
 
-    pub async fn receive_response(
+    // receive_checked_response reads the body of a response with a CRC, and
+    // returns it if the CRC matches.
+    pub async fn receive_checked_response(
         &self,
         read_stream: &mut R,
+        header: &ResponseHeader,
+    ) -> Result<Vec<u8>, String> {
+        let length = header.total_length as usize;
+        let mut body = BUFFER_POOL.get(length + CHECKSUM_SIZE);
+        self.receive(read_stream, &mut body).await?;
+        let crc = u32::from_le_bytes(body[length..].try_into().unwrap());
+        body.truncate(length);
+        if checksum(&[&header.encode(), &body]) != crc {
+            BUFFER_POOL.put(body);
+            return Err(CORRUPT_FRAME.to_string());
+        }
+        Ok(body)
+    }
+
+    // receive_response reads the meta data and scatters the data into the given buffers.
+    pub async fn receive_response<S: AsyncReadExt + Unpin>(
+        &self,
+        read_stream: &mut S,
         meta_data: &mut [u8],
-        data: &mut [u8],
+        data: &mut [&mut [u8]],
     ) -> Result<(), String> {
-        let meta_data_length = meta_data.len();
-        let data_length = data.len();
-        self.receive(read_stream, &mut meta_data[0..meta_data_length])
-            .await?;
-        self.receive(read_stream, &mut data[0..data_length]).await?;
+        self.receive(read_stream, meta_data).await?;
+        for buf in data.iter_mut() {
+            self.receive(read_stream, buf).await?;
+        }
         Ok(())
     }
 
-    pub async fn receive(&self, read_stream: &mut R, data: &mut [u8]) -> Result<(), String> {
+    // receive_compressed_response reads the meta data and returns the data of
+    // a compressed response, decompressed.
+    pub async fn receive_compressed_response<S: AsyncReadExt + Unpin>(
+        &self,
+        read_stream: &mut S,
+        meta_data: &mut [u8],
+        data_length: u32,
+    ) -> Result<Result<Vec<u8>, String>, String> {
+        self.receive(read_stream, meta_data).await?;
+        let mut data = BUFFER_POOL.get(data_length as usize);
+        self.receive(read_stream, &mut data).await?;
+        let raw = decompress(&data);
+        BUFFER_POOL.put(data);
+        if let Ok(raw) = &raw {
+            self.record_received(raw.len(), data_length as usize);
+        }
+        Ok(raw)
+    }
+
+    pub async fn receive<S: AsyncReadExt + Unpin>(
+        &self,
+        read_stream: &mut S,
+        data: &mut [u8],
+    ) -> Result<(), String> {
         match read_stream.read_exact(data).await {
             Ok(_) => Ok(()),
             Err(e) => Err(e.to_string()),
         }
     }
 
-    pub async fn clean_response(
+    pub async fn clean_response<S: AsyncReadExt + Unpin>(
         &self,
-        read_stream: &mut R,
+        read_stream: &mut S,
         total_length: u32,
     ) -> Result<(), String> {
-        let mut buffer = vec![0u8; total_length as usize];
+        let mut buffer = BUFFER_POOL.get(total_length as usize);
         self.receive(read_stream, &mut buffer).await?;
+        BUFFER_POOL.put(buffer);
         Ok(())
     }
 }
@@ -206,6 +401,7 @@ pub struct ServerConnection<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> {
     pub id: u32,
     name_id: String,
     write_stream: Mutex<W>,
+    corrupt_frames: CorruptFrames,
 
     phantom_data: PhantomData<R>,
 }
@@ -216,6 +412,7 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ServerConnection<W, R> {
             id,
             name_id,
             write_stream: Mutex::new(write_stream),
+            corrupt_frames: CorruptFrames::default(),
 
             phantom_data: PhantomData,
         }
@@ -235,6 +432,9 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ServerConnection<W, R> {
     // response
     // | batch | id | status | flags | total_length | meta_data_lenght | data_length | meta_data | data |
     // | 4Byte | 4Byte | 4Byte | 4Byte | 4Byte | 4Byte | 4Byte | 0~ | 0~ |
+    // The response is compressed and checksummed as the flags of its request
+    // accept it.
+    #[allow(clippy::too_many_arguments)]
     pub async fn send_response(
         &self,
         batch: u32,
@@ -243,54 +443,43 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ServerConnection<W, R> {
         flags: u32,
         meta_data: &[u8],
         data: &[u8],
+        request_flags: u32,
     ) -> Result<(), String> {
+        let mut flags = flags;
+        let mut compressed = None;
+        if request_flags & REQUEST_FLAG_ACCEPT_COMPRESSED != 0 {
+            flags |= RESPONSE_FLAG_ACCEPT_COMPRESSED;
+            compressed = SERVER_COMPRESSION_STATS.compress(&[data]);
+        }
+        let data = match &compressed {
+            Some(compressed) => {
+                flags |= RESPONSE_FLAG_COMPRESSED;
+                compressed.as_slice()
+            }
+            None => data,
+        };
+        if request_flags & REQUEST_FLAG_ACCEPT_CHECKSUM != 0 {
+            flags |= RESPONSE_FLAG_CHECKSUM;
+        }
         let data_length = data.len();
         let meta_data_length = meta_data.len();
         let total_length = data_length + meta_data_length;
-        let mut response = Vec::with_capacity(RESPONSE_HEADER_SIZE + total_length);
-        response.extend_from_slice(&batch.to_le_bytes());
-        response.extend_from_slice(&id.to_le_bytes());
-        response.extend_from_slice(&status.to_le_bytes());
-        response.extend_from_slice(&flags.to_le_bytes());
-        response.extend_from_slice(&(total_length as u32).to_le_bytes());
-        response.extend_from_slice(&(meta_data_length as u32).to_le_bytes());
-        response.extend_from_slice(&(data_length as u32).to_le_bytes());
+        let mut header = [0u8; RESPONSE_HEADER_SIZE];
+        header[0..4].copy_from_slice(&batch.to_le_bytes());
+        header[4..8].copy_from_slice(&id.to_le_bytes());
+        header[8..12].copy_from_slice(&status.to_le_bytes());
+        header[12..16].copy_from_slice(&flags.to_le_bytes());
+        header[16..20].copy_from_slice(&(total_length as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&(meta_data_length as u32).to_le_bytes());
+        header[24..28].copy_from_slice(&(data_length as u32).to_le_bytes());
         let mut stream = self.write_stream.lock().await;
-        let mut offset = 0;
-        loop {
-            if offset >= response.len() + meta_data_length + data_length {
-                break;
-            }
-            if offset < response.len() {
-                let bufs: &[_] = &[
-                    IoSlice::new(&response[offset..]),
-                    IoSlice::new(meta_data),
-                    IoSlice::new(data),
-                ];
-                offset += stream
-                    .write_vectored(bufs)
-                    .await
-                    .map_err(|e| e.to_string())?;
-            } else if offset < response.len() + meta_data_length {
-                let bufs: &[_] = &[
-                    IoSlice::new(&meta_data[offset - response.len()..]),
-                    IoSlice::new(data),
-                ];
-                offset += stream
-                    .write_vectored(bufs)
-                    .await
-                    .map_err(|e| e.to_string())?;
-            } else {
-                let bufs: &[_] = &[IoSlice::new(
-                    &data[offset - response.len() - meta_data_length..],
-                )];
-                offset += stream
-                    .write_vectored(bufs)
-                    .await
-                    .map_err(|e| e.to_string())?;
+        match flags & RESPONSE_FLAG_CHECKSUM {
+            0 => write_all_vectored(&mut *stream, &[&header, meta_data, data]).await,
+            _ => {
+                let crc = checksum(&[&header, meta_data, data]).to_le_bytes();
+                write_all_vectored(&mut *stream, &[&header, meta_data, data, &crc]).await
             }
         }
-        Ok(())
     }
 
     pub async fn receive_request_header(
@@ -336,9 +525,17 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ServerConnection<W, R> {
             error!("meta data length is too long: {}", header.meta_data_length);
             return Err("meta data length is too long".into());
         }
-        let mut path = vec![0u8; header.file_path_length as usize];
-        let mut data = vec![0u8; header.data_length as usize];
-        let mut meta_data = vec![0u8; header.meta_data_length as usize];
+        if header.total_length as u64
+            != header.file_path_length as u64
+                + header.meta_data_length as u64
+                + header.data_length as u64
+        {
+            error!("total length mismatch: {:?}", header);
+            return Err("total length mismatch".into());
+        }
+        let mut path = BUFFER_POOL.get(header.file_path_length as usize);
+        let mut data = BUFFER_POOL.get(header.data_length as usize);
+        let mut meta_data = BUFFER_POOL.get(header.meta_data_length as usize);
 
         self.receive(read_stream, &mut path[0..header.file_path_length as usize])
             .await?;
@@ -349,6 +546,29 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ServerConnection<W, R> {
         .await?;
         self.receive(read_stream, &mut data[0..header.data_length as usize])
             .await?;
+        if header.flags & REQUEST_FLAG_CHECKSUM != 0 {
+            let mut crc = [0u8; CHECKSUM_SIZE];
+            self.receive(read_stream, &mut crc).await?;
+            let corrupt =
+                checksum(&[&header.encode(), &path, &meta_data, &data]) != u32::from_le_bytes(crc);
+            let reset = self.corrupt_frames.record(corrupt);
+            if corrupt {
+                SERVER_CORRUPT_FRAMES.fetch_add(1, Ordering::Relaxed);
+                BUFFER_POOL.put(path);
+                BUFFER_POOL.put(data);
+                BUFFER_POOL.put(meta_data);
+                return match reset {
+                    true => Err("too many corrupt frames".into()),
+                    false => Err(CORRUPT_FRAME.into()),
+                };
+            }
+        }
+        if header.flags & REQUEST_FLAG_COMPRESSED != 0 {
+            let raw = decompress(&data);
+            BUFFER_POOL.put(data);
+            data = raw?;
+            SERVER_COMPRESSION_STATS.record(data.len(), header.data_length as usize);
+        }
 
         Ok((path, data, meta_data))
     }
@@ -360,3 +580,36 @@ impl<W: AsyncWriteExt + Unpin, R: AsyncReadExt + Unpin> ServerConnection<W, R> {
         }
     }
 }
+
+// write_all_vectored writes all the buffers with as few write calls as possible.
+async fn write_all_vectored<W: AsyncWriteExt + Unpin>(
+    stream: &mut W,
+    bufs: &[&[u8]],
+) -> Result<(), String> {
+    let mut index = 0;
+    let mut offset = 0;
+    while index < bufs.len() {
+        if offset >= bufs[index].len() {
+            index += 1;
+            offset = 0;
+            continue;
+        }
+        let mut slices = Vec::with_capacity(bufs.len() - index);
+        slices.push(IoSlice::new(&bufs[index][offset..]));
+        slices.extend(bufs[index + 1..].iter().map(|buf| IoSlice::new(buf)));
+        let mut written = stream
+            .write_vectored(&slices)
+            .await
+            .map_err(|e| e.to_string())?;
+        if written == 0 {
+            return Err("write zero bytes".to_string());
+        }
+        while index < bufs.len() && written >= bufs[index].len() - offset {
+            written -= bufs[index].len() - offset;
+            index += 1;
+            offset = 0;
+        }
+        offset += written;
+    }
+    Ok(())
+}
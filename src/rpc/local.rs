@@ -0,0 +1,341 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The data path to a server on the same host, over a unix socket instead of
+ * loopback TCP. Every server also listens on `<dir>/sealfs-<port>.sock`, dir
+ * being SEALFS_LOCAL_SOCKET_DIR or /tmp. A client connecting to a server
+ * whose address resolves to the loopback or to an address of this host uses
+ * the socket of its port if it exists, and TCP otherwise, e.g. when the
+ * socket is left by a server that stopped. An empty SEALFS_LOCAL_SOCKET_DIR
+ * turns the local sockets off, for the servers and the clients.
+ */
+use std::{
+    io::IoSlice,
+    net::{IpAddr, SocketAddrV4, SocketAddrV6},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{tcp, unix, TcpStream, UnixStream},
+    sync::mpsc::Sender,
+};
+
+use super::{
+    client::StreamCreator,
+    server::{Handler, RpcServer},
+};
+use crate::common::util::split_address;
+
+// local_socket_path returns the path of the local socket of the server
+// listening on port, if the local sockets are on.
+pub fn local_socket_path(port: u16) -> Option<String> {
+    let dir = std::env::var("SEALFS_LOCAL_SOCKET_DIR").unwrap_or_else(|_| "/tmp".to_owned());
+    match dir.is_empty() {
+        true => None,
+        false => Some(format!(
+            "{}/sealfs-{}.sock",
+            dir.trim_end_matches('/'),
+            port
+        )),
+    }
+}
+
+fn host_addresses() -> Vec<IpAddr> {
+    let addresses = match nix::ifaddrs::getifaddrs() {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            error!("get the addresses of the host failed: {}", e);
+            return Vec::new();
+        }
+    };
+    addresses
+        .filter_map(|interface| interface.address)
+        .filter_map(
+            |address| match (address.as_sockaddr_in(), address.as_sockaddr_in6()) {
+                (Some(v4), _) => Some(IpAddr::V4(*SocketAddrV4::from(*v4).ip())),
+                (_, Some(v6)) => Some(IpAddr::V6(*SocketAddrV6::from(*v6).ip())),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+// local_socket returns the local socket of the server at server_address, if
+// it runs on this host.
+pub async fn local_socket(server_address: &str) -> Option<String> {
+    let (_, port) = split_address(server_address)?;
+    let path = local_socket_path(port)?;
+    if !Path::new(&path).exists() {
+        return None;
+    }
+    let ips: Vec<IpAddr> = tokio::net::lookup_host(server_address)
+        .await
+        .ok()?
+        .map(|address| address.ip())
+        .collect();
+    if ips.iter().any(IpAddr::is_loopback) {
+        return Some(path);
+    }
+    let host_addresses = host_addresses();
+    ips.iter()
+        .any(|ip| host_addresses.contains(ip))
+        .then_some(path)
+}
+
+// the ids of the connections to the local socket have this bit set, not to
+// be taken for the ids of the tcp connections of the same server.
+pub const LOCAL_CONNECTION: u32 = 1 << 31;
+
+struct LocalHandler<H> {
+    handler: Arc<H>,
+}
+
+#[async_trait]
+impl<H: Handler + Sync + Send + 'static> Handler for LocalHandler<H> {
+    async fn dispatch(
+        &self,
+        id: u32,
+        operation_type: u32,
+        flags: u32,
+        path: Vec<u8>,
+        data: Vec<u8>,
+        metadata: Vec<u8>,
+    ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        self.handler
+            .dispatch(
+                id | LOCAL_CONNECTION,
+                operation_type,
+                flags,
+                path,
+                data,
+                metadata,
+            )
+            .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn dispatch_stream(
+        &self,
+        id: u32,
+        operation_type: u32,
+        flags: u32,
+        path: Vec<u8>,
+        data: Vec<u8>,
+        metadata: Vec<u8>,
+        frames: Sender<Vec<u8>>,
+    ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        self.handler
+            .dispatch_stream(
+                id | LOCAL_CONNECTION,
+                operation_type,
+                flags,
+                path,
+                data,
+                metadata,
+                frames,
+            )
+            .await
+    }
+
+    fn operation_name(&self, operation_type: u32) -> String {
+        self.handler.operation_name(operation_type)
+    }
+
+    fn accept_peer(&self, id: u32, uid: u32, gid: u32) -> bool {
+        self.handler.accept_peer(id | LOCAL_CONNECTION, uid, gid)
+    }
+
+    fn peer_closed(&self, id: u32) {
+        self.handler.peer_closed(id | LOCAL_CONNECTION)
+    }
+}
+
+// serve_local serves the requests of the clients of this host on the local
+// socket of a server, with LOCAL_CONNECTION in their ids, replacing the socket of a previous server.
+pub async fn serve_local<H: Handler + Sync + Send + 'static>(
+    handler: Arc<H>,
+    server_address: String,
+) {
+    let Some(path) = split_address(&server_address).and_then(|(_, port)| local_socket_path(port))
+    else {
+        return;
+    };
+    if let Err(e) = std::fs::remove_file(&path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            error!("remove local socket {} failed: {}", path, e);
+            return;
+        }
+    }
+    info!("serve the local clients on {}", path);
+    let handler = Arc::new(LocalHandler { handler });
+    if let Err(e) = RpcServer::new(handler, &path).run_unix_stream().await {
+        error!("serve local socket {} failed: {}", path, e);
+    }
+}
+
+pub enum LocalReadHalf {
+    Tcp(tcp::OwnedReadHalf),
+    Unix(unix::OwnedReadHalf),
+}
+
+pub enum LocalWriteHalf {
+    Tcp(tcp::OwnedWriteHalf),
+    Unix(unix::OwnedWriteHalf),
+}
+
+impl AsyncRead for LocalReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LocalWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// LocalStreamCreator connects to the local socket of a server on this host,
+// and over TCP to the others.
+pub struct LocalStreamCreator;
+
+#[async_trait]
+impl StreamCreator<LocalReadHalf, LocalWriteHalf> for LocalStreamCreator {
+    async fn create_stream(
+        server_address: &str,
+    ) -> Result<(LocalReadHalf, LocalWriteHalf), String> {
+        if let Some(path) = local_socket(server_address).await {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => {
+                    debug!("connect to {} over {}", server_address, path);
+                    let (read_stream, write_stream) = stream.into_split();
+                    return Ok((
+                        LocalReadHalf::Unix(read_stream),
+                        LocalWriteHalf::Unix(write_stream),
+                    ));
+                }
+                Err(e) => debug!("connect to {} failed: {}, use tcp", path, e),
+            }
+        }
+        let stream = match TcpStream::connect(server_address).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                return Err(format!("connect to {} error: {}", server_address, e));
+            }
+        };
+        let (read_stream, write_stream) = stream.into_split();
+        Ok((
+            LocalReadHalf::Tcp(read_stream),
+            LocalWriteHalf::Tcp(write_stream),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use async_trait::async_trait;
+
+    use super::{local_socket, serve_local, LocalStreamCreator, LocalWriteHalf};
+    use crate::rpc::{client::StreamCreator, server::Handler};
+
+    struct NullHandler;
+
+    #[async_trait]
+    impl Handler for NullHandler {
+        async fn dispatch(
+            &self,
+            _id: u32,
+            _operation_type: u32,
+            _flags: u32,
+            _path: Vec<u8>,
+            _data: Vec<u8>,
+            _metadata: Vec<u8>,
+        ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+            Ok((0, 0, 0, 0, vec![], vec![]))
+        }
+    }
+
+    #[tokio::test]
+    async fn local_socket_test() {
+        let _ = std::fs::remove_file("/tmp/sealfs-18391.sock");
+        assert_eq!(local_socket("127.0.0.1:18391").await, None);
+        // only the local socket listens, not the tcp port.
+        tokio::spawn(serve_local(
+            Arc::new(NullHandler),
+            "127.0.0.1:18391".to_owned(),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            local_socket("127.0.0.1:18391").await,
+            Some("/tmp/sealfs-18391.sock".to_owned())
+        );
+        assert_eq!(
+            local_socket("[::1]:18391").await,
+            Some("/tmp/sealfs-18391.sock".to_owned())
+        );
+        assert_eq!(local_socket("192.0.2.1:18391").await, None);
+        let (_, write_stream) = LocalStreamCreator::create_stream("localhost:18391")
+            .await
+            .unwrap();
+        assert!(matches!(write_stream, LocalWriteHalf::Unix(_)));
+        assert!(LocalStreamCreator::create_stream("127.0.0.1:18392")
+            .await
+            .is_err());
+    }
+}
@@ -2,9 +2,13 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod buffer;
 pub mod callback;
+pub mod checksum;
 pub mod client;
+pub mod compression;
 pub mod connection;
+pub mod local;
 pub mod protocol;
 pub mod rdma;
 pub mod server;
@@ -20,6 +20,28 @@ pub const REQUEST_DATA_LENGTH_SIZE: usize = 4;
 
 pub const REQUEST_POOL_SIZE: usize = 65536;
 
+// A request with REQUEST_FLAG_STREAM accepts a response split into several frames.
+// Every frame but the last one carries RESPONSE_FLAG_MORE, and the client writes
+// the data of each frame right after the data of the previous one.
+// These flags are used by the rpc layer only and never passed to handlers.
+pub const REQUEST_FLAG_STREAM: u32 = 1 << 31;
+pub const RESPONSE_FLAG_MORE: u32 = 1 << 31;
+pub const STREAM_FRAME_SIZE: usize = 1024 * 1024;
+// frames a handler can send before waiting for the connection to write them.
+pub const STREAM_FRAME_QUEUE: usize = 4;
+
+// The data of a request or a response with the COMPRESSED flag is compressed,
+// and the ACCEPT_COMPRESSED flags negotiate it, see compression.rs.
+// Like the flags above, they are never passed to handlers.
+pub const REQUEST_FLAG_COMPRESSED: u32 = 1 << 30;
+pub const REQUEST_FLAG_ACCEPT_COMPRESSED: u32 = 1 << 29;
+pub const RESPONSE_FLAG_COMPRESSED: u32 = 1 << 30;
+pub const RESPONSE_FLAG_ACCEPT_COMPRESSED: u32 = 1 << 29;
+// A frame with the CHECKSUM flag is followed by its CRC, see checksum.rs.
+pub const REQUEST_FLAG_CHECKSUM: u32 = 1 << 28;
+pub const REQUEST_FLAG_ACCEPT_CHECKSUM: u32 = 1 << 27;
+pub const RESPONSE_FLAG_CHECKSUM: u32 = 1 << 28;
+
 /* receive operation response and wake up the operation thread using condition variable
     response
     | batch | id | status | flags | total_length | meta_data_lenght | data_length | meta_data | data |
@@ -42,6 +64,26 @@ pub struct RequestHeader {
 }
 
 impl RequestHeader {
+    pub fn encode(&self) -> [u8; REQUEST_HEADER_SIZE] {
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        for (i, value) in [
+            self.batch,
+            self.id,
+            self.r#type,
+            self.flags,
+            self.total_length,
+            self.file_path_length,
+            self.meta_data_length,
+            self.data_length,
+        ]
+        .iter()
+        .enumerate()
+        {
+            header[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        header
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         batch: u32,
@@ -77,6 +119,25 @@ pub struct ResponseHeader {
 }
 
 impl ResponseHeader {
+    pub fn encode(&self) -> [u8; RESPONSE_HEADER_SIZE] {
+        let mut header = [0u8; RESPONSE_HEADER_SIZE];
+        for (i, value) in [
+            self.batch,
+            self.id,
+            self.status as u32,
+            self.flags,
+            self.total_length,
+            self.meta_data_length,
+            self.data_length,
+        ]
+        .iter()
+        .enumerate()
+        {
+            header[i * 4..i * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        header
+    }
+
     pub fn new(
         batch: u32,
         id: u32,
@@ -151,7 +151,7 @@ pub async fn parse_response(conn: Arc<Conn>, pool: Arc<CallbackPool>) {
         parse_response_body(
             response,
             pool.get_meta_data_ref(id, header.meta_data_length as usize),
-            pool.get_data_ref(id, header.data_length as usize),
+            &mut pool.get_data_refs(id, header.data_length as usize),
         );
         conn.release(response).await;
         if let Err(e) = pool
@@ -195,9 +195,8 @@ pub fn parse_response_header(response: &[u8]) -> ResponseHeader {
     }
 }
 
-pub fn parse_response_body(response: &[u8], meta_data: &mut [u8], data: &mut [u8]) {
+pub fn parse_response_body(response: &[u8], meta_data: &mut [u8], data: &mut [&mut [u8]]) {
     let meta_data_length = meta_data.len();
-    let data_length = data.len();
     debug!(
         "waiting for response_meta_data, length: {}",
         meta_data_length
@@ -206,10 +205,11 @@ pub fn parse_response_body(response: &[u8], meta_data: &mut [u8], data: &mut [u8
     meta_data
         .copy_from_slice(&response[RESPONSE_HEADER_SIZE..RESPONSE_HEADER_SIZE + meta_data_length]);
     debug!("received reponse_meta_data, meta_data: {:?}", meta_data);
-    // copy response to data
-    data.copy_from_slice(
-        &response[RESPONSE_HEADER_SIZE + meta_data_length
-            ..RESPONSE_HEADER_SIZE + meta_data_length + data_length],
-    );
+    // scatter response to data buffers
+    let mut offset = RESPONSE_HEADER_SIZE + meta_data_length;
+    for buf in data.iter_mut() {
+        buf.copy_from_slice(&response[offset..offset + buf.len()]);
+        offset += buf.len();
+    }
     debug!("received reponse_data, data: {:?}", data);
 }
@@ -2,16 +2,47 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::Arc;
+use std::{net::SocketAddr, os::fd::AsRawFd, sync::Arc, time::Instant};
 
 use async_trait::async_trait;
 use log::{error, info, warn};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, UnixListener},
+    net::{TcpListener, TcpSocket, UnixListener},
+    sync::mpsc::{channel, Sender},
 };
 
-use super::{connection::ServerConnection, protocol::RequestHeader};
+use super::{
+    buffer::BUFFER_POOL,
+    checksum::CORRUPT_FRAME,
+    connection::ServerConnection,
+    protocol::{
+        RequestHeader, REQUEST_FLAG_ACCEPT_CHECKSUM, REQUEST_FLAG_ACCEPT_COMPRESSED,
+        REQUEST_FLAG_CHECKSUM, REQUEST_FLAG_COMPRESSED, REQUEST_FLAG_STREAM, RESPONSE_FLAG_MORE,
+        STREAM_FRAME_QUEUE,
+    },
+};
+use crate::common::logging::log_op;
+
+// bind_tcp listens on address, `host:port` or `[ipv6]:port`. The IPv6
+// wildcard `[::]:port` is dual-stack: it accepts the IPv4 clients too,
+// whatever net.ipv6.bindv6only is.
+async fn bind_tcp(address: &str) -> std::io::Result<TcpListener> {
+    match address.parse::<SocketAddr>() {
+        Ok(SocketAddr::V6(v6)) if v6.ip().is_unspecified() => {
+            let socket = TcpSocket::new_v6()?;
+            nix::sys::socket::setsockopt(
+                socket.as_raw_fd(),
+                nix::sys::socket::sockopt::Ipv6V6Only,
+                &false,
+            )?;
+            socket.set_reuseaddr(true)?;
+            socket.bind(SocketAddr::V6(v6))?;
+            socket.listen(1024)
+        }
+        _ => TcpListener::bind(address).await,
+    }
+}
 
 #[async_trait]
 pub trait Handler {
@@ -24,6 +55,39 @@ pub trait Handler {
         data: Vec<u8>,
         metadata: Vec<u8>,
     ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)>;
+
+    // dispatch_stream handles a request that accepts a streamed response.
+    // The data sent to frames is written to the client as soon as possible,
+    // and the data of the returned response follows it.
+    #[allow(clippy::too_many_arguments)]
+    async fn dispatch_stream(
+        &self,
+        id: u32,
+        operation_type: u32,
+        flags: u32,
+        path: Vec<u8>,
+        data: Vec<u8>,
+        metadata: Vec<u8>,
+        _frames: Sender<Vec<u8>>,
+    ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        self.dispatch(id, operation_type, flags, path, data, metadata)
+            .await
+    }
+
+    // operation_name names operation_type in the logs of the requests.
+    fn operation_name(&self, operation_type: u32) -> String {
+        operation_type.to_string()
+    }
+
+    // accept_peer is called with the credentials of a peer connecting to a
+    // unix socket before any of its requests, the connection is closed if it
+    // returns false.
+    fn accept_peer(&self, _id: u32, _uid: u32, _gid: u32) -> bool {
+        true
+    }
+
+    // peer_closed is called once the connection id is closed.
+    fn peer_closed(&self, _id: u32) {}
 }
 
 pub async fn handle<
@@ -38,18 +102,70 @@ pub async fn handle<
     data: Vec<u8>,
     metadata: Vec<u8>,
 ) {
-    let response = handler
-        .dispatch(
-            connection.id,
-            header.r#type,
-            header.flags,
-            path.clone(),
-            data,
-            metadata,
-        )
-        .await;
+    let flags = header.flags
+        & !(REQUEST_FLAG_STREAM
+            | REQUEST_FLAG_COMPRESSED
+            | REQUEST_FLAG_ACCEPT_COMPRESSED
+            | REQUEST_FLAG_CHECKSUM
+            | REQUEST_FLAG_ACCEPT_CHECKSUM);
+    let start = Instant::now();
+    let response = match header.flags & REQUEST_FLAG_STREAM {
+        0 => {
+            handler
+                .dispatch(
+                    connection.id,
+                    header.r#type,
+                    flags,
+                    path.clone(),
+                    data,
+                    metadata,
+                )
+                .await
+        }
+        _ => {
+            let (sender, mut receiver) = channel::<Vec<u8>>(STREAM_FRAME_QUEUE);
+            let dispatch = handler.dispatch_stream(
+                connection.id,
+                header.r#type,
+                flags,
+                path.clone(),
+                data,
+                metadata,
+                sender,
+            );
+            let send_frames = async {
+                while let Some(frame) = receiver.recv().await {
+                    connection
+                        .send_response(
+                            header.batch,
+                            header.id,
+                            0,
+                            RESPONSE_FLAG_MORE,
+                            &[],
+                            &frame,
+                            header.flags,
+                        )
+                        .await?;
+                }
+                Ok::<(), String>(())
+            };
+            let (response, sent) = tokio::join!(dispatch, send_frames);
+            if let Err(e) = sent {
+                error!("handle connection: {} , send frame error: {}, batch: {}, id: {}, operation_type: {}, path: {:?}", connection.id, e, header.batch, header.id, header.r#type, std::str::from_utf8(&path));
+                let _ = connection.close().await;
+                return;
+            }
+            response
+        }
+    };
     match response {
         Ok(response) => {
+            log_op(
+                handler.operation_name(header.r#type),
+                &String::from_utf8_lossy(&path),
+                start.elapsed(),
+                response.0,
+            );
             if let Err(e) = connection
                 .send_response(
                     header.batch,
@@ -58,12 +174,15 @@ pub async fn handle<
                     response.1,
                     &response.4[0..response.2],
                     &response.5[0..response.3],
+                    header.flags,
                 )
                 .await
             {
                 error!("handle connection: {} , send response error: {}, batch: {}, id: {}, operation_type: {}, flags: {}, path: {:?}", connection.id, e, header.batch, header.id, header.r#type, header.flags, std::str::from_utf8(&path));
                 let _ = connection.close().await;
             }
+            BUFFER_POOL.put(response.4);
+            BUFFER_POOL.put(response.5);
         }
         Err(e) => {
             error!(
@@ -72,6 +191,7 @@ pub async fn handle<
             );
         }
     }
+    BUFFER_POOL.put(path);
 }
 
 pub async fn receive<
@@ -93,14 +213,40 @@ pub async fn receive<
                         warn!("{:?} receive, connection closed", id);
                         break;
                     }
-                    panic!("{:?} parse_request, header error: {}", id, e);
+                    error!("{:?} parse_request, header error: {}", id, e);
+                    break;
                 }
             };
+            // a malformed request closes its connection, not the whole server.
             let data_result = connection.receive_request(&mut read_stream, &header).await;
             let (path, data, metadata) = match data_result {
                 Ok(data) => data,
+                // the frame was read whole, only its request fails.
+                Err(e) if e == CORRUPT_FRAME => {
+                    error!(
+                        "{:?} parse_request, corrupt frame, batch: {}, id: {}",
+                        id, header.batch, header.id
+                    );
+                    let result = connection
+                        .send_response(
+                            header.batch,
+                            header.id,
+                            libc::EIO,
+                            0,
+                            &[],
+                            &[],
+                            header.flags,
+                        )
+                        .await;
+                    if let Err(e) = result {
+                        error!("{:?} send response error: {}", id, e);
+                        break;
+                    }
+                    continue;
+                }
                 Err(e) => {
-                    panic!("{:?} parse_request, data error: {}", id, e);
+                    error!("{:?} parse_request, data error: {}", id, e);
+                    break;
                 }
             };
             let handler = handler.clone();
@@ -108,6 +254,8 @@ pub async fn receive<
             tokio::spawn(handle(handler, connection, header, path, data, metadata));
         }
     }
+    let _ = connection.close().await;
+    handler.peer_closed(connection.id);
 }
 
 pub struct RpcServer<H: Handler + std::marker::Sync + std::marker::Send + 'static> {
@@ -126,7 +274,7 @@ impl<H: Handler + std::marker::Sync + std::marker::Send> RpcServer<H> {
 
     pub async fn run(&self) -> anyhow::Result<()> {
         info!("Listening on {:?}", self.bind_address);
-        let listener = TcpListener::bind(&self.bind_address).await?;
+        let listener = bind_tcp(&self.bind_address).await?;
         let mut id = 1u32;
         loop {
             match listener.accept().await {
@@ -164,6 +312,18 @@ impl<H: Handler + std::marker::Sync + std::marker::Send> RpcServer<H> {
         loop {
             match listener.accept().await {
                 Ok((stream, _)) => {
+                    let accepted = match stream.peer_cred() {
+                        Ok(cred) => self.handler.accept_peer(id, cred.uid(), cred.gid()),
+                        Err(e) => {
+                            error!("Connection {id} get peer credentials failed: {}", e);
+                            false
+                        }
+                    };
+                    if !accepted {
+                        warn!("Connection {id} refused");
+                        id += 1;
+                        continue;
+                    }
                     let (read_stream, write_stream) = stream.into_split();
                     info!("Connection {id} accepted");
                     let handler = Arc::clone(&self.handler);
@@ -0,0 +1,350 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Cache node: a read-only caching proxy placed near the clients.
+ * Clients started with --cache-node send all their requests to it. Reads are
+ * served from a local LRU of file chunks, every other request is forwarded
+ * to the owner of the path. Before serving a cached chunk its version, the
+ * mtime and size of the file, is validated against the attr on the owner, so
+ * a write through any path makes the cached chunks of the file stale.
+ * A prefetch request loads all the chunks of a file into the cache.
+ */
+use std::{
+    sync::{
+        atomic::{AtomicI32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use fuser::FileAttr;
+use log::{debug, error, info};
+use spin::RwLock;
+use tokio::time::sleep;
+
+use crate::{
+    common::{
+        cache::LRUCache,
+        errors::{status_to_string, CONNECTION_ERROR},
+        hash_ring::HashRing,
+        health::HealthReport,
+        info_syncer::{init_network_connections, ClientStatusMonitor, InfoSyncer},
+        sender::{Sender, REQUEST_TIMEOUT},
+        serialization::{ClusterStatus, OperationType, ReadFileSendMetaData},
+    },
+    rpc::{
+        client::RpcClient,
+        local::{LocalReadHalf, LocalStreamCreator, LocalWriteHalf},
+        server::{Handler, RpcServer},
+    },
+    server::distributed_engine::forward_buffers,
+};
+
+pub const CACHE_CHUNK_SIZE: u64 = 1 << 20;
+
+// the version of the cached data of a file.
+type Version = (SystemTime, u64);
+
+fn attr_version(attr: &FileAttr) -> Version {
+    (attr.mtime, attr.size)
+}
+
+#[derive(Debug)]
+struct CachedChunk {
+    version: Version,
+    data: Vec<u8>,
+}
+
+pub struct CacheNode {
+    pub client: Arc<RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
+    pub sender: Arc<Sender>,
+    pub cluster_status: AtomicI32,
+    pub hash_ring: Arc<RwLock<Option<HashRing>>>,
+    pub new_hash_ring: Arc<RwLock<Option<HashRing>>>,
+    pub manager_address: Arc<tokio::sync::Mutex<String>>,
+    chunks: LRUCache<CachedChunk>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[async_trait]
+impl InfoSyncer for CacheNode {
+    async fn get_cluster_status(&self) -> Result<ClusterStatus, i32> {
+        self.sender
+            .get_cluster_status(&self.manager_address.lock().await)
+            .await
+    }
+
+    fn cluster_status(&self) -> &AtomicI32 {
+        &self.cluster_status
+    }
+}
+
+#[async_trait]
+impl ClientStatusMonitor for CacheNode {
+    fn sender(&self) -> &Sender {
+        &self.sender
+    }
+    fn manager_address(&self) -> &Arc<tokio::sync::Mutex<String>> {
+        &self.manager_address
+    }
+    fn hash_ring(&self) -> &Arc<RwLock<Option<HashRing>>> {
+        &self.hash_ring
+    }
+    async fn add_connection(&self, server_address: &str) -> Result<(), i32> {
+        self.client
+            .add_connection(server_address)
+            .await
+            .map_err(|e| {
+                error!("add connection failed: {:?}", e);
+                CONNECTION_ERROR
+            })
+    }
+    fn new_hash_ring(&self) -> &Arc<RwLock<Option<HashRing>>> {
+        &self.new_hash_ring
+    }
+}
+
+impl CacheNode {
+    pub fn new(cache_chunks: usize) -> Self {
+        let client = Arc::new(RpcClient::default());
+        Self {
+            client: client.clone(),
+            sender: Arc::new(Sender::new(client)),
+            cluster_status: AtomicI32::new(ClusterStatus::Initializing.into()),
+            hash_ring: Arc::new(RwLock::new(None)),
+            new_hash_ring: Arc::new(RwLock::new(None)),
+            manager_address: Arc::new(tokio::sync::Mutex::new("".to_string())),
+            chunks: LRUCache::new(cache_chunks),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // read_file serves a read from the cached chunks, fetching the missing
+    // or stale ones from the owner.
+    pub async fn read_file(&self, path: &str, md: ReadFileSendMetaData) -> Result<Vec<u8>, i32> {
+        let owner = self.get_connection_address(path);
+        let attr = self.sender.get_file_attr(&owner, path).await?;
+        let version = attr_version(&attr);
+        let (begin, end) = (
+            md.offset as u64,
+            (md.offset as u64 + md.size as u64).min(attr.size),
+        );
+        let mut data = Vec::with_capacity(end.saturating_sub(begin) as usize);
+        if begin >= end {
+            return Ok(data);
+        }
+        for index in begin / CACHE_CHUNK_SIZE..=(end - 1) / CACHE_CHUNK_SIZE {
+            let chunk_begin = index * CACHE_CHUNK_SIZE;
+            let chunk = self.read_chunk(&owner, path, index, version).await?;
+            let from = begin.max(chunk_begin) - chunk_begin;
+            let to =
+                (end.min(chunk_begin + CACHE_CHUNK_SIZE) - chunk_begin).min(chunk.len() as u64);
+            if from >= to {
+                break;
+            }
+            data.extend_from_slice(&chunk[from as usize..to as usize]);
+        }
+        Ok(data)
+    }
+
+    // prefetch_file loads all the chunks of a file into the cache, the owner
+    // is not asked to prefetch since the reads will be served from here.
+    pub async fn prefetch_file(&self, path: &str) -> Result<u64, i32> {
+        let owner = self.get_connection_address(path);
+        let attr = self.sender.get_file_attr(&owner, path).await?;
+        for index in 0..attr.size.div_ceil(CACHE_CHUNK_SIZE) {
+            self.read_chunk(&owner, path, index, attr_version(&attr))
+                .await?;
+        }
+        Ok(attr.size)
+    }
+
+    async fn read_chunk(
+        &self,
+        owner: &str,
+        path: &str,
+        index: u64,
+        version: Version,
+    ) -> Result<Vec<u8>, i32> {
+        let key = format!("{}\0{}", path, index);
+        if let Some(chunk) = self.chunks.get(key.as_bytes()) {
+            if chunk.version == version {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(chunk.data.clone());
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data = self
+            .sender
+            .read_file(
+                owner,
+                path,
+                (index * CACHE_CHUNK_SIZE) as i64,
+                CACHE_CHUNK_SIZE as u32,
+            )
+            .await?;
+        self.chunks.insert(
+            key.as_bytes(),
+            CachedChunk {
+                version,
+                data: data.clone(),
+            },
+        );
+        Ok(data)
+    }
+
+    async fn forward(
+        &self,
+        operation_type: u32,
+        flags: u32,
+        path: &str,
+        data: Vec<u8>,
+        metadata: Vec<u8>,
+    ) -> Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>), i32> {
+        let r#type = OperationType::try_from(operation_type).map_err(|_| libc::EINVAL)?;
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let (mut recv_meta_data, mut recv_data) = forward_buffers(r#type, &metadata)?;
+        if let Err(e) = self
+            .client
+            .call_remote(
+                &self.get_connection_address(path),
+                operation_type,
+                flags,
+                path,
+                &metadata,
+                &data,
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut recv_data,
+                REQUEST_TIMEOUT,
+            )
+            .await
+        {
+            error!("forward request failed: {:?}", e);
+            return Err(CONNECTION_ERROR);
+        }
+        Ok((
+            status,
+            rsp_flags,
+            recv_meta_data_length,
+            recv_data_length,
+            recv_meta_data,
+            recv_data,
+        ))
+    }
+
+    // health checks that the cache node has the hash ring of the manager.
+    pub fn health(&self) -> HealthReport {
+        let mut report = HealthReport::default();
+        report.check(
+            "ring",
+            match self.hash_ring.read().as_ref() {
+                Some(ring) => Ok(format!("epoch {}", ring.epoch)),
+                None => Err("no hash ring".to_owned()),
+            },
+        );
+        report
+    }
+}
+
+pub struct CacheNodeHandler {
+    node: Arc<CacheNode>,
+}
+
+#[async_trait]
+impl Handler for CacheNodeHandler {
+    fn operation_name(&self, operation_type: u32) -> String {
+        match OperationType::try_from(operation_type) {
+            Ok(r#type) => format!("{:?}", r#type),
+            Err(_) => operation_type.to_string(),
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        _id: u32,
+        operation_type: u32,
+        flags: u32,
+        path: Vec<u8>,
+        data: Vec<u8>,
+        metadata: Vec<u8>,
+    ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        let file_path = unsafe { std::str::from_utf8_unchecked(&path) };
+        if operation_type == OperationType::ReadFile as u32 {
+            let md: ReadFileSendMetaData = bincode::deserialize(&metadata).unwrap();
+            return match self.node.read_file(file_path, md).await {
+                Ok(data) => Ok((0, 0, 0, data.len(), Vec::new(), data)),
+                Err(e) => {
+                    debug!(
+                        "cache node read {} failed: {}",
+                        file_path,
+                        status_to_string(e)
+                    );
+                    Ok((e, 0, 0, 0, Vec::new(), Vec::new()))
+                }
+            };
+        }
+        if operation_type == OperationType::Prefetch as u32 {
+            return match self.node.prefetch_file(file_path).await {
+                Ok(size) => Ok((0, 0, 8, 0, size.to_le_bytes().to_vec(), Vec::new())),
+                Err(e) => Ok((e, 0, 0, 0, Vec::new(), Vec::new())),
+            };
+        }
+        if operation_type == OperationType::Health as u32 {
+            let meta_data = bincode::serialize(&self.node.health()).unwrap();
+            return Ok((0, 0, meta_data.len(), 0, meta_data, Vec::new()));
+        }
+        // the local files of the servers are not read through a cache node.
+        if operation_type == OperationType::OpenLocal as u32 {
+            return Ok((libc::ENOTSUP, 0, 0, 0, Vec::new(), Vec::new()));
+        }
+        match self
+            .node
+            .forward(operation_type, flags, file_path, data, metadata)
+            .await
+        {
+            Ok(response) => Ok(response),
+            Err(e) => Ok((e, 0, 0, 0, Vec::new(), Vec::new())),
+        }
+    }
+}
+
+async fn report_cache_stats(node: Arc<CacheNode>) {
+    loop {
+        sleep(Duration::from_secs(60)).await;
+        info!(
+            "cache node: hits: {}, misses: {}",
+            node.hits.load(Ordering::Relaxed),
+            node.misses.load(Ordering::Relaxed)
+        );
+    }
+}
+
+pub async fn run(
+    server_address: String,
+    manager_address: String,
+    cache_chunks: usize,
+) -> anyhow::Result<()> {
+    let node = Arc::new(CacheNode::new(cache_chunks));
+    init_network_connections(manager_address, node.clone()).await;
+    if let Err(e) = node.connect_servers().await {
+        panic!("cache node connect servers failed: {}", status_to_string(e));
+    }
+    info!(
+        "cache node {} serves {} chunks of {} bytes",
+        server_address, cache_chunks, CACHE_CHUNK_SIZE
+    );
+    tokio::spawn(report_cache_stats(node.clone()));
+
+    let server = RpcServer::new(Arc::new(CacheNodeHandler { node }), &server_address);
+    server.run().await
+}
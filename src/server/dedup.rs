@@ -0,0 +1,261 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Offline deduplication scan of the files of a server. The files under a
+ * path are read in chunks of DEDUP_CHUNK_SIZE and a chunk whose hash and
+ * length were seen before is counted as a duplicate, which tells the space
+ * a content-addressed engine would save. Only the files of the same server
+ * are compared, the servers scan in parallel.
+ * With apply, the duplicate chunks of the engines keeping a local file per
+ * path are handed to FIDEDUPERANGE, which shares their extents with the
+ * first chunk on the file systems with reflink, e.g. XFS and Btrfs. The
+ * kernel compares the bytes first, so a hash collision or a chunk written
+ * meanwhile is left alone.
+ */
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd,
+};
+
+use fuser::FileType;
+use log::{debug, info};
+
+use super::{
+    distributed_engine::DistributedEngine,
+    storage_engine::{meta_store::Table, StorageEngine},
+};
+use crate::common::{
+    errors::status_to_string,
+    serialization::{bytes_as_file_attr, DedupScanRecvMetaData, DedupScanSendMetaData},
+};
+
+pub const DEDUP_CHUNK_SIZE: u64 = 1 << 20;
+
+// _IOWR(0x94, 54, struct file_dedupe_range)
+const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    // a single destination.
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+// dedupe_range shares the extents of length bytes of dest at dest_offset
+// with those of src at src_offset if they hold the same bytes, and returns
+// the bytes shared.
+pub fn dedupe_range(
+    src: &File,
+    src_offset: u64,
+    dest: &File,
+    dest_offset: u64,
+    length: u64,
+) -> Result<u64, i32> {
+    let mut range = FileDedupeRange {
+        src_offset,
+        src_length: length,
+        dest_count: 1,
+        reserved1: 0,
+        reserved2: 0,
+        dest_fd: dest.as_raw_fd() as i64,
+        dest_offset,
+        bytes_deduped: 0,
+        status: 0,
+        reserved: 0,
+    };
+    let result = unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut range) };
+    if result < 0 {
+        return Err(nix::errno::errno());
+    }
+    match range.status {
+        FILE_DEDUPE_RANGE_SAME => Ok(range.bytes_deduped),
+        status if status < 0 => Err(-status),
+        // the bytes differ.
+        _ => Ok(0),
+    }
+}
+
+impl<S: StorageEngine> DistributedEngine<S> {
+    // dedup_scan counts the duplicate chunks of the regular files under
+    // path stored on this server, and shares their extents with apply.
+    pub fn dedup_scan(
+        &self,
+        path: &str,
+        md: &DedupScanSendMetaData,
+    ) -> Result<DedupScanRecvMetaData, i32> {
+        let prefix = format!("{}/", path);
+        let mut files = Vec::new();
+        for item in self
+            .meta_engine
+            .store
+            .iter(Table::FileAttr, prefix.as_bytes())
+        {
+            let (key, value) = item?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let attr = bytes_as_file_attr(&value);
+            if attr.kind == FileType::RegularFile && !key.contains(&0) {
+                files.push((String::from_utf8_lossy(&key).into_owned(), attr.size));
+            }
+        }
+
+        let mut result = DedupScanRecvMetaData::default();
+        // (hash, length) of a chunk -> the file and the offset it was first seen at.
+        let mut chunks: HashMap<(u64, usize), (usize, u64)> = HashMap::new();
+        for (index, (file_path, size)) in files.iter().enumerate() {
+            result.files += 1;
+            let mut offset = 0;
+            while offset < *size {
+                let data = self.storage_engine.read_file(
+                    file_path,
+                    DEDUP_CHUNK_SIZE as u32,
+                    offset as i64,
+                )?;
+                if data.is_empty() {
+                    break;
+                }
+                result.chunks += 1;
+                result.bytes += data.len() as u64;
+                let key = (wyhash::wyhash(&data, 0), data.len());
+                match chunks.get(&key) {
+                    Some(&(first, first_offset)) => {
+                        result.duplicate_chunks += 1;
+                        result.duplicate_bytes += data.len() as u64;
+                        if md.apply && !result.unsupported {
+                            match self.share_chunk(
+                                &files[first].0,
+                                first_offset,
+                                file_path,
+                                offset,
+                                data.len() as u64,
+                            ) {
+                                Ok(shared) => result.shared_bytes += shared,
+                                Err(e) => {
+                                    info!(
+                                        "dedup {} stops sharing extents: {}",
+                                        path,
+                                        status_to_string(e)
+                                    );
+                                    result.unsupported = true;
+                                }
+                            }
+                        }
+                    }
+                    None => {
+                        chunks.insert(key, (index, offset));
+                    }
+                }
+                offset += data.len() as u64;
+            }
+        }
+        debug!("dedup scan {}: {:?}", path, result);
+        Ok(result)
+    }
+
+    fn share_chunk(
+        &self,
+        src_path: &str,
+        src_offset: u64,
+        dest_path: &str,
+        dest_offset: u64,
+        length: u64,
+    ) -> Result<u64, i32> {
+        // the destination of FIDEDUPERANGE is opened for writing.
+        let local_file = |path: &str, write: bool| match self.storage_engine.local_path(path) {
+            Some(local_path) => OpenOptions::new()
+                .read(true)
+                .write(write)
+                .open(local_path?)
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO)),
+            None => Err(libc::EOPNOTSUPP),
+        };
+        let (src, dest) = (local_file(src_path, false)?, local_file(dest_path, true)?);
+        match dedupe_range(&src, src_offset, &dest, dest_offset, length) {
+            Ok(shared) => Ok(shared),
+            // a chunk of the same file overlapping the first one, or a short
+            // chunk not at the end of its file, cannot be shared.
+            Err(libc::EINVAL) if src_path == dest_path || length < DEDUP_CHUNK_SIZE => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use libc::{O_CREAT, O_RDWR};
+
+    use super::DEDUP_CHUNK_SIZE;
+    use crate::{
+        common::serialization::DedupScanSendMetaData,
+        server::{
+            distributed_engine::DistributedEngine,
+            storage_engine::{
+                file_engine::FileEngine, meta_engine::MetaEngine, meta_store::MemStore,
+                StorageEngine,
+            },
+        },
+    };
+
+    #[test]
+    fn dedup_scan_test() {
+        let root = "/tmp/test_dedup_scan";
+        let _ = std::fs::remove_dir_all(root);
+        let meta_engine = Arc::new(MetaEngine::with_store(Box::new(MemStore::new())));
+        let storage_engine = Arc::new(FileEngine::new(root, meta_engine.clone()).unwrap());
+        let chunk = |byte: u8| vec![byte; DEDUP_CHUNK_SIZE as usize];
+        for (path, data) in [
+            ("v/a", [chunk(1), chunk(2)].concat()),
+            ("v/b", [chunk(1), chunk(3), b"end".to_vec()].concat()),
+            ("w/c", chunk(2)),
+        ] {
+            storage_engine
+                .create_file(path, O_CREAT | O_RDWR, 0, 0o644)
+                .unwrap();
+            storage_engine.write_file(path, &data, 0).unwrap();
+        }
+        let engine = DistributedEngine::new("".to_owned(), storage_engine, meta_engine);
+
+        // the files of another path are not compared.
+        let result = engine
+            .dedup_scan("v", &DedupScanSendMetaData { apply: false })
+            .unwrap();
+        assert_eq!(
+            (result.files, result.chunks, result.bytes),
+            (2, 5, 4 * DEDUP_CHUNK_SIZE + 3)
+        );
+        assert_eq!(
+            (result.duplicate_chunks, result.duplicate_bytes),
+            (1, DEDUP_CHUNK_SIZE)
+        );
+        assert_eq!(result.shared_bytes, 0);
+
+        // the extents are shared where the file system of the test supports it.
+        let result = engine
+            .dedup_scan("v", &DedupScanSendMetaData { apply: true })
+            .unwrap();
+        match result.unsupported {
+            true => assert_eq!(result.shared_bytes, 0),
+            false => assert_eq!(result.shared_bytes, DEDUP_CHUNK_SIZE),
+        }
+        assert_eq!(
+            engine.storage_engine.read_file("v/b", 4, 0).unwrap(),
+            [1; 4]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,42 +1,115 @@
+use super::mirror::Mirror;
+use super::posix_lock::LockTable;
+use super::rate_limit::{RateLimiter, FORWARDED_REQUEST};
 use super::storage_engine::meta_engine::MetaEngine;
+use super::storage_engine::meta_store::Table;
 use super::storage_engine::StorageEngine;
 use super::transfer_manager::TransferManager;
+use super::volume_stats::VolumeStats;
 use crate::common::byte::CHUNK_SIZE;
-use crate::common::errors::CONNECTION_ERROR;
-use crate::common::hash_ring::HashRing;
+use crate::common::errors::{status_to_string, CONNECTION_ERROR, INVALID_CLUSTER_STATUS};
+use crate::common::hash_ring::{HashRing, HashRingInfo};
+use crate::common::health::{check_disk, HealthReport, HEARTBEAT_TIMEOUT};
 use crate::common::sender::{Sender, REQUEST_TIMEOUT};
 use crate::common::serialization::{
-    file_attr_as_bytes, ClusterStatus, CreateDirSendMetaData, CreateFileSendMetaData,
-    FileTypeSimple, ManagerOperationType, ReadFileSendMetaData, ServerStatus,
-    WriteFileSendMetaData,
+    bytes_as_dir_entries, bytes_as_file_attr, bytes_as_file_attr_mut, file_attr_as_bytes,
+    push_batch_attr, AddLinkRecvMetaData, AtimePolicy, BatchGetAttrSendMetaData,
+    CheckFileSendMetaData, ClusterStatus, CreateDirSendMetaData, CreateFileSendMetaData,
+    CreateSymlinkSendMetaData, FileLayout, FileTypeSimple, FsyncSendMetaData, GrepSendMetaData,
+    HeartbeatSendMetaData, LinkSendMetaData, ListTreeSendMetaData, LockRecvMetaData,
+    LockSendMetaData, ManagerOperationType, ReadFileSendMetaData, RenameAtSendMetaData,
+    RenameNoParentSendMetaData, RenameSendMetaData, SeekSendMetaData, ServerStatus,
+    SetAttrSendMetaData, SetAttrTreeRecvMetaData, SetAttrTreeSendMetaData, StatFsRecvMetaData,
+    TransferState, TransferStatusRecvMetaData, WriteFileSendMetaData, BATCH_ATTR_SIZE,
+    FILE_FLAG_LINKED,
+};
+use crate::common::serialization::{
+    put_dir_entry, DirectoryEntrySendMetaData, LinkTempFileSendMetaData, OperationType,
 };
-use crate::common::serialization::{DirectoryEntrySendMetaData, OperationType};
 
-use crate::common::util::{empty_file, get_full_path};
-use crate::rpc::client::{RpcClient, TcpStreamCreator};
+use crate::common::util::{
+    cookie_shard, empty_file, get_full_path, is_shard, is_temp_file, shard_dir, shard_of,
+    shard_path, MAX_DIR_SHARDS,
+};
+use crate::rpc::{
+    client::RpcClient,
+    local::{LocalReadHalf, LocalStreamCreator, LocalWriteHalf},
+};
 use dashmap::mapref::one::Ref;
 use dashmap::DashMap;
 use fuser::{FileAttr, FileType};
-use libc::{O_CREAT, O_DIRECTORY, O_EXCL};
-use log::{debug, error, info};
+use libc::{O_CREAT, O_DIRECTORY, O_EXCL, O_RDWR, RENAME_NOREPLACE};
+use log::{debug, error, info, warn};
 use nix::fcntl::OFlag;
-use rocksdb::IteratorMode;
 use spin::RwLock;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 use std::{sync::Arc, vec};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+
+// the key written and deleted to check that the database is writable.
+const HEALTH_KEY: &str = "$health";
+
+// how long the layouts of the volumes are cached from the manager registry.
+const VOLUME_LAYOUT_TTL: Duration = Duration::from_secs(10);
+// how long the atime policy of a volume of another server is kept.
+const VOLUME_ATIME_TTL: Duration = Duration::from_secs(10);
+// the transfer of a file failing in a rebalance is retried this many times.
+const TRANSFER_RETRIES: u32 = 3;
+const TRANSFER_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// files transferred at once in a rebalance, and to the same server.
+pub const TRANSFER_CONCURRENCY: usize = 16;
+pub const TRANSFER_DESTINATION_CONCURRENCY: usize = 4;
+// names tried for a file replaced by a rename, or moved to the path of its
+// links, to be kept on its server.
+const ASIDE_PATH_TRIES: u32 = 64;
+// the bytes of entries read at once from a directory moved by a rename.
+const MOVE_DIR_PAGE_SIZE: u32 = 4096;
+
+// IdMap offsets the uids and gids of the clients in the files of a server,
+// so that the clusters of different tenants sharing the storage nodes keep
+// their files under distinct ids. Every server of a cluster should have the
+// same offsets.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IdMap {
+    pub uid_offset: u32,
+    pub gid_offset: u32,
+}
+
+impl IdMap {
+    pub fn to_server(&self, (uid, gid): (u32, u32)) -> (u32, u32) {
+        (
+            uid.saturating_add(self.uid_offset),
+            gid.saturating_add(self.gid_offset),
+        )
+    }
+
+    pub fn to_client(&self, (uid, gid): (u32, u32)) -> (u32, u32) {
+        (
+            uid.saturating_sub(self.uid_offset),
+            gid.saturating_sub(self.gid_offset),
+        )
+    }
+
+    // map_attr maps the owner of a raw attr back to the ids of the clients.
+    pub fn map_attr(&self, mut attr: Vec<u8>) -> Vec<u8> {
+        if attr.len() == std::mem::size_of::<FileAttr>() {
+            let attr = bytes_as_file_attr_mut(&mut attr);
+            (attr.uid, attr.gid) = self.to_client((attr.uid, attr.gid));
+        }
+        attr
+    }
+}
 
 pub struct DistributedEngine<Storage: StorageEngine> {
     pub address: String,
     pub storage_engine: Arc<Storage>,
     pub meta_engine: Arc<MetaEngine>,
-    pub client: Arc<
-        RpcClient<
-            tokio::net::tcp::OwnedReadHalf,
-            tokio::net::tcp::OwnedWriteHalf,
-            TcpStreamCreator,
-        >,
-    >,
+    pub client: Arc<RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
     pub sender: Sender,
 
     pub cluster_status: AtomicI32,
@@ -47,8 +120,35 @@ pub struct DistributedEngine<Storage: StorageEngine> {
     pub manager_address: Arc<Mutex<String>>,
 
     pub file_locks: DashMap<String, DashMap<String, u32>>,
+    // the POSIX locks of the clients on the files of this server.
+    pub lock_table: LockTable,
     pub transfer_manager: TransferManager,
 
+    // volume -> its file layout and when it was fetched.
+    pub volume_layouts: DashMap<String, (FileLayout, Instant)>,
+    // volume of another server -> its atime policy and when it was fetched.
+    pub volume_atimes: DashMap<String, (AtimePolicy, Instant)>,
+
+    pub id_map: IdMap,
+
+    // the identity sent with the heartbeats, see server/identity.rs.
+    pub server_id: String,
+
+    // the usage of the volumes sent with the next heartbeat.
+    pub volume_stats: VolumeStats,
+    // when the manager last answered a heartbeat.
+    pub last_heartbeat: RwLock<Option<Instant>>,
+    // connection id -> uid of the peers of the local socket.
+    pub local_peers: DashMap<u32, u32>,
+    pub metadata_limits: RateLimiter,
+    // the candidate server a sample of the reads is mirrored to.
+    pub mirror: Option<Arc<Mirror>>,
+    // files transferred at once in a rebalance, and to the same server.
+    pub transfer_concurrency: usize,
+    pub transfer_destination_concurrency: usize,
+    // taken to move a file to the path of its links, see add_link.
+    pub link_lock: Mutex<()>,
+
     pub closed: AtomicBool,
 }
 
@@ -77,11 +177,145 @@ where
             new_hash_ring: Arc::new(RwLock::new(None)),
             manager_address: Arc::new(Mutex::new("".to_string())),
             file_locks,
+            lock_table: LockTable::default(),
             transfer_manager: TransferManager::new(),
+            volume_layouts: DashMap::new(),
+            volume_atimes: DashMap::new(),
+            id_map: IdMap::default(),
+            server_id: String::new(),
+            volume_stats: VolumeStats::default(),
+            last_heartbeat: RwLock::new(None),
+            local_peers: DashMap::new(),
+            metadata_limits: RateLimiter::default(),
+            mirror: None,
+            transfer_concurrency: TRANSFER_CONCURRENCY,
+            transfer_destination_concurrency: TRANSFER_DESTINATION_CONCURRENCY,
+            link_lock: Mutex::new(()),
             closed: AtomicBool::new(false),
         }
     }
 
+    // volume_layout returns the layout of the files created at path, from
+    // the defaults of its volume. While the manager cannot be reached the
+    // last layout is kept, and asked again only after VOLUME_LAYOUT_TTL.
+    pub async fn volume_layout(&self, path: &str) -> FileLayout {
+        let volume = path.split('/').next().unwrap_or_default();
+        if let Some(entry) = self.volume_layouts.get(volume) {
+            if entry.1.elapsed() < VOLUME_LAYOUT_TTL {
+                return entry.0;
+            }
+        }
+        let manager_address = self.manager_address.lock().await.clone();
+        match self.sender.list_volume_infos(&manager_address).await {
+            Ok(infos) => {
+                let now = Instant::now();
+                // volumes missing from the registry have the default layout.
+                self.volume_layouts
+                    .insert(volume.to_owned(), (FileLayout::default(), now));
+                for info in infos {
+                    self.volume_layouts.insert(info.name, (info.layout, now));
+                }
+            }
+            Err(e) => {
+                error!(
+                    "get volume layouts failed: {}, volume: {}",
+                    status_to_string(e),
+                    volume
+                );
+                let layout = self
+                    .volume_layouts
+                    .get(volume)
+                    .map(|entry| entry.0)
+                    .unwrap_or_default();
+                self.volume_layouts
+                    .insert(volume.to_owned(), (layout, Instant::now()));
+            }
+        }
+        self.volume_layouts
+            .get(volume)
+            .map(|entry| entry.0)
+            .unwrap_or_default()
+    }
+
+    // atime_policy returns the atime policy of the volume of path, from the
+    // volume on this server or asked to its server. The last policy asked is
+    // kept, and asked again only after VOLUME_ATIME_TTL.
+    pub async fn atime_policy(&self, path: &str) -> AtimePolicy {
+        let volume = path.split('/').next().unwrap_or_default();
+        if let Some(volume) = self.meta_engine.volumes.get(volume) {
+            return volume.atime_policy;
+        }
+        if let Some(entry) = self.volume_atimes.get(volume) {
+            if entry.1.elapsed() < VOLUME_ATIME_TTL {
+                return entry.0;
+            }
+        }
+        let policy = match self
+            .sender
+            .init_volume(&self.get_address(volume), volume)
+            .await
+        {
+            Ok(info) => info.atime_policy,
+            Err(e) => {
+                error!(
+                    "get volume atime policy failed: {}, volume: {}",
+                    status_to_string(e),
+                    volume
+                );
+                self.volume_atimes
+                    .get(volume)
+                    .map(|entry| entry.0)
+                    .unwrap_or_default()
+            }
+        };
+        self.volume_atimes
+            .insert(volume.to_owned(), (policy, Instant::now()));
+        policy
+    }
+
+    // mirror_read mirrors a read of a client to the candidate server in the
+    // background, when it is sampled, see mirror.rs.
+    pub fn mirror_read(
+        &self,
+        r#type: OperationType,
+        flags: u32,
+        path: &str,
+        metadata: &[u8],
+        status: i32,
+        response: &[u8],
+    ) {
+        if self.mirror_sampled(&r#type, flags) {
+            self.mirror_response(r#type, path, metadata, status, response.to_vec());
+        }
+    }
+
+    pub fn mirror_sampled(&self, r#type: &OperationType, flags: u32) -> bool {
+        self.mirror
+            .as_ref()
+            .is_some_and(|mirror| mirror.sampled(r#type, flags))
+    }
+
+    // mirror_response mirrors a sampled read with its whole response, e.g.
+    // the frames of a streamed read put together.
+    pub fn mirror_response(
+        &self,
+        r#type: OperationType,
+        path: &str,
+        metadata: &[u8],
+        status: i32,
+        response: Vec<u8>,
+    ) {
+        let Some(mirror) = &self.mirror else {
+            return;
+        };
+        let (mirror, path, metadata) = (mirror.clone(), path.to_owned(), metadata.to_vec());
+        tokio::spawn(async move {
+            mirror
+                .compare(r#type, &path, &metadata, status, &response)
+                .await;
+        });
+    }
+
     pub async fn add_connection(&self, address: String) -> Result<(), i32> {
         self.client.add_connection(&address).await.map_err(|e| {
             error!("add connection failed: {:?}", e);
@@ -118,27 +352,38 @@ where
     pub fn make_up_file_map(&self) -> Vec<String> {
         let mut file_map = Vec::new();
         self.meta_engine
-            .file_attr_db
-            .db
-            .iterator(IteratorMode::Start)
+            .store
+            .iter(Table::FileAttr, b"")
             .for_each(|result| {
                 let (k, _) = result.unwrap();
-                let k = String::from_utf8(k.to_vec()).unwrap();
+                let k = String::from_utf8(k).unwrap();
                 if self.get_new_address(&k) != self.address {
                     file_map.push(k);
                 }
             });
+        // and the hard links, see transfer_link.
+        for link in self.meta_engine.links.iter() {
+            if self.get_new_address(link.key()) != self.address {
+                file_map.push(link.key().clone());
+            }
+        }
         self.transfer_manager.make_up_files(&file_map);
         file_map
     }
 
     pub async fn create_file_remote(&self, path: &str) -> Result<(), i32> {
         let address = self.get_new_address(path);
+        let attr = self.meta_engine.get_file_attr(path)?;
+        let (uid, gid) = self.id_map.to_client((attr.uid, attr.gid));
         let send_meta_data = bincode::serialize(&CreateFileSendMetaData {
-            mode: 0o777,
+            mode: attr.perm as u32,
             umask: 0,
             flags: OFlag::O_CREAT.bits() | OFlag::O_RDWR.bits(),
             name: "".to_string(),
+            uid,
+            gid,
+            size_hint: attr.size,
+            append_only: FileLayout::of(&attr).append_only,
         })
         .unwrap();
 
@@ -153,7 +398,9 @@ where
         Ok(())
     }
 
-    pub async fn write_file_remote(&self, path: &str) -> Result<(), i32> {
+    // write_file_remote copies the data of path to its new owner, and
+    // returns its checksum.
+    pub async fn write_file_remote(&self, path: &str) -> Result<u64, i32> {
         let address = self.get_new_address(path);
 
         let file_attr = self.meta_engine.get_file_attr(path).unwrap();
@@ -163,22 +410,30 @@ where
         let mut chunk_left = 0;
         let mut chunk_right = std::cmp::min((idx + 1) * CHUNK_SIZE, end_idx);
         let mut _result = 0;
+        let mut checksum = 0;
         while chunk_left < end_idx {
             // let file_path = format!("{}_{}", pathname, idx);
             // println!("write: {} {}", file_path, address);
 
-            let send_meta_data =
-                bincode::serialize(&WriteFileSendMetaData { offset: chunk_left }).unwrap();
+            let send_meta_data = bincode::serialize(&WriteFileSendMetaData {
+                offset: chunk_left,
+                epoch: 0,
+                version: 0,
+            })
+            .unwrap();
             let mut status = 0i32;
             let mut rsp_flags = 0u32;
             let chunk_buf = self
                 .storage_engine
                 .read_file(path, CHUNK_SIZE as u32, chunk_left)
                 .unwrap();
+            checksum = wyhash::wyhash(&chunk_buf, checksum);
+            self.transfer_manager
+                .add_bytes(path, chunk_buf.len() as u64);
             let mut recv_meta_data_length = 0usize;
             let mut recv_data_length = 0usize;
 
-            let mut recv_meta_data = [0u8; std::mem::size_of::<isize>()];
+            let mut recv_meta_data = [0u8; std::mem::size_of::<u64>()];
             if let Err(e) = self
                 .client
                 .call_remote(
@@ -204,20 +459,44 @@ where
             if status != 0 {
                 return Err(status);
             }
-            let size = isize::from_le_bytes(recv_meta_data);
+            let size = u64::from_le_bytes(recv_meta_data);
             idx += 1;
             chunk_left = chunk_right;
             chunk_right = std::cmp::min(chunk_right + CHUNK_SIZE, end_idx);
             _result += size;
         }
-        Ok(())
+        Ok(checksum)
+    }
+
+    // file_checksum hashes the first size bytes of path by chunks of
+    // CHUNK_SIZE, as write_file_remote sends them.
+    pub fn file_checksum(&self, path: &str, size: u64) -> Result<u64, i32> {
+        let (mut offset, mut checksum) = (0, 0);
+        while offset < size as i64 {
+            let data = self
+                .storage_engine
+                .read_file(path, CHUNK_SIZE as u32, offset)?;
+            if data.is_empty() {
+                break;
+            }
+            checksum = wyhash::wyhash(&data, checksum);
+            offset += data.len() as i64;
+        }
+        Ok(checksum)
     }
 
-    pub async fn check_file_remote(&self, path: &str) -> Result<(), i32> {
+    // check_file_remote hands path off to its new owner: once the owner has
+    // synced the file and matched checksum, the manager records the handoff
+    // and only then the file is deleted here, see recover_handoffs.
+    pub async fn check_file_remote(&self, path: &str, checksum: u64) -> Result<(), i32> {
         let server_address = self.get_new_address(path);
         // println!("check: {} {}", file_path, server_address);
 
-        let send_meta_data = self.meta_engine.get_file_attr_raw(path).unwrap();
+        let send_meta_data = bincode::serialize(&CheckFileSendMetaData {
+            file_attr: self.meta_engine.get_file_attr_raw(path).unwrap(),
+            checksum,
+        })
+        .unwrap();
         let mut status = 0i32;
         let mut rsp_flags = 0u32;
         let mut recv_meta_data_length = 0usize;
@@ -249,15 +528,51 @@ where
             return Err(status);
         }
 
+        self.sender
+            .record_handoff(&self.manager_address.lock().await, &self.address, path)
+            .await?;
         self.delete_file_no_parent(path)
     }
 
+    // recover_handoffs deletes the files this server handed off before it
+    // stopped, but did not delete yet.
+    pub async fn recover_handoffs(&self) -> Result<(), i32> {
+        let manager_address = self.manager_address.lock().await.clone();
+        let paths = self
+            .sender
+            .get_handoffs(&manager_address, &self.address)
+            .await?;
+        for path in paths {
+            let result = match self.meta_engine.is_dir(&path) {
+                Ok(true) => self.delete_dir_no_parent_force(&path),
+                Ok(false) => self.delete_file_no_parent(&path),
+                // a hard link, see transfer_link.
+                Err(libc::ENOENT) if self.meta_engine.link_target(&path).is_some() => {
+                    self.delete_file_no_parent(&path)
+                }
+                Err(libc::ENOENT) => continue,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => info!("recover handoff: {} deleted", path),
+                Err(e) => {
+                    error!("recover handoff: {} failed: {}", path, status_to_string(e));
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub async fn create_dir_remote(&self, path: &str) -> Result<(), i32> {
         let address = self.get_new_address(path);
-
+        let attr = self.meta_engine.get_file_attr(path)?;
+        let (uid, gid) = self.id_map.to_client((attr.uid, attr.gid));
         let send_meta_data = bincode::serialize(&CreateDirSendMetaData {
-            mode: 0o777,
+            mode: attr.perm as u32,
             name: "".to_string(),
+            uid,
+            gid,
         })
         .unwrap();
 
@@ -273,21 +588,25 @@ where
     }
 
     pub async fn add_subdirs_remote(&self, path: &str) -> Result<(), i32> {
-        if !path.contains('/') {
+        if !path.contains('/') && !is_shard(path) {
             // root directory of a volume
             return Ok(());
         }
         let address = self.get_new_address(path);
 
-        for item in self.meta_engine.dir_db.db.iterator(IteratorMode::From(
-            format!("{}$", path).as_bytes(),
-            rocksdb::Direction::Forward,
-        )) {
-            // let file_path = format!("{}_{}", pathname, idx);
-            // println!("write: {} {}", file_path, server_address);
-
-            let (key, value) = item.unwrap();
-            let file_name = String::from_utf8(value.to_vec()).unwrap();
+        let prefix = format!("{}$", path);
+        // read before sending, the iterator of the store is not Send.
+        let mut entries = Vec::new();
+        for item in self.meta_engine.store.iter(Table::Dir, prefix.as_bytes()) {
+            let (key, value) = item?;
+            // the entries of the next directories follow.
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            entries.push((key, value));
+        }
+        for (key, value) in entries {
+            let file_name = String::from_utf8(value).unwrap();
             let file_type = *key.last().unwrap();
 
             let send_meta_data = bincode::serialize(&DirectoryEntrySendMetaData {
@@ -339,43 +658,168 @@ where
             return Err(status);
         }
 
+        self.sender
+            .record_handoff(&self.manager_address.lock().await, &self.address, path)
+            .await?;
         self.delete_dir_no_parent_force(path)
     }
 
-    pub async fn transfer_files(&self, file_map: Vec<String>) -> Result<(), i32> {
-        // transfer all files ,and set the flag as true
+    // transfer_files moves the files of file_map to their new owners. Every
+    // new owner has transfer_destination_concurrency workers taking its
+    // files in order, and at most transfer_concurrency files are transferred
+    // at once. The first failure stops all the transfers.
+    pub async fn transfer_files(self: &Arc<Self>, file_map: Vec<String>) -> Result<(), i32>
+    where
+        Storage: Send + Sync + 'static,
+    {
         info!("transfer_files: {:?}", file_map);
+        let mut destinations: HashMap<String, VecDeque<String>> = HashMap::new();
         for k in file_map {
-            let _lock = self.transfer_manager.get_wlock(&k).await;
-            if self.transfer_manager.status(&k).unwrap() {
-                continue;
+            destinations
+                .entry(self.get_new_address(&k))
+                .or_default()
+                .push_back(k);
+        }
+        let transfers = Arc::new(Semaphore::new(self.transfer_concurrency.max(1)));
+        let mut tasks = JoinSet::new();
+        for (destination, files) in destinations {
+            let workers = files
+                .len()
+                .min(self.transfer_destination_concurrency.max(1));
+            debug!(
+                "transfer_files: {} files to {} with {} workers",
+                files.len(),
+                destination,
+                workers
+            );
+            let files = Arc::new(Mutex::new(files));
+            for _ in 0..workers {
+                let (engine, files, transfers) = (self.clone(), files.clone(), transfers.clone());
+                tasks.spawn(async move {
+                    loop {
+                        let Some(k) = files.lock().await.pop_front() else {
+                            return Ok(());
+                        };
+                        let _permit = transfers.acquire().await.unwrap();
+                        engine.transfer_path(&k).await?;
+                    }
+                });
             }
-            match self.meta_engine.is_dir(&k) {
-                Ok(true) => {
-                    self.create_dir_remote(&k).await?;
-                    self.add_subdirs_remote(&k).await?;
-                    self.check_dir_remote(&k).await?;
-                }
-                Ok(false) => {
-                    self.create_file_remote(&k).await?;
-                    self.write_file_remote(&k).await?;
-                    self.check_file_remote(&k).await?;
-                }
-                Err(libc::ENOENT) => {
-                    // file has been deleted before transfering
-                    continue;
-                }
+        }
+        while let Some(result) = tasks.join_next().await {
+            let e = match result {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e,
                 Err(e) => {
-                    error!("transfer_files: {}", e);
-                    return Err(e);
+                    error!("transfer_files: worker failed: {}", e);
+                    libc::EIO
+                }
+            };
+            tasks.abort_all();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // transfer_path moves the file or the directory k to its new owner.
+    async fn transfer_path(&self, k: &str) -> Result<(), i32> {
+        let _lock = self.transfer_manager.get_wlock(k).await;
+        if self.transfer_manager.status(k).unwrap() {
+            return Ok(());
+        }
+        let mut failures = 0;
+        loop {
+            // only the files and the links are retried, the entries of a
+            // directory added again would be counted twice.
+            let (result, retried) = match self.meta_engine.link_target(k) {
+                Some(target) => {
+                    self.transfer_manager.set_state(k, TransferState::InFlight);
+                    (self.transfer_link(k, &target).await, true)
                 }
+                None => match self.meta_engine.is_dir(k) {
+                    Ok(true) => {
+                        self.transfer_manager.set_state(k, TransferState::InFlight);
+                        (self.transfer_dir(k).await, false)
+                    }
+                    Ok(false) => {
+                        self.transfer_manager.set_state(k, TransferState::InFlight);
+                        (self.transfer_file(k, failures > 0).await, true)
+                    }
+                    Err(libc::ENOENT) => {
+                        // file has been deleted before transfering
+                        return Ok(());
+                    }
+                    Err(e) => (Err(e), false),
+                },
+            };
+            let Err(e) = result else {
+                break;
+            };
+            failures += 1;
+            self.transfer_manager.fail(k, e);
+            if !retried || failures > TRANSFER_RETRIES {
+                error!("transfer_files: {}: {}", k, status_to_string(e));
+                return Err(e);
             }
-            info!("transfer_files: {} done", k);
-            self.transfer_manager.set_status(&k, true);
+            warn!(
+                "transfer_files: {}: {}, retry {} of {}",
+                k,
+                status_to_string(e),
+                failures,
+                TRANSFER_RETRIES
+            );
+            tokio::time::sleep(TRANSFER_RETRY_INTERVAL).await;
         }
+        info!("transfer_files: {} done", k);
+        self.transfer_manager.set_state(k, TransferState::Done);
         Ok(())
     }
 
+    async fn transfer_dir(&self, path: &str) -> Result<(), i32> {
+        self.create_dir_remote(path).await?;
+        self.add_subdirs_remote(path).await?;
+        self.check_dir_remote(path).await
+    }
+
+    // transfer_file moves a regular file to its new owner, a retry finds
+    // there the file created by the failed attempt.
+    async fn transfer_file(&self, path: &str, retry: bool) -> Result<(), i32> {
+        match self.create_file_remote(path).await {
+            Err(libc::EEXIST) if retry => {}
+            result => result?,
+        }
+        let checksum = self.write_file_remote(path).await?;
+        self.check_file_remote(path, checksum).await
+    }
+
+    // transfer_link moves the hard link path to its new owner. It still links
+    // to target, whose server keeps counting it.
+    async fn transfer_link(&self, path: &str, target: &str) -> Result<(), i32> {
+        let send_meta_data = bincode::serialize(&LinkSendMetaData {
+            name: "".to_string(),
+            target: target.to_owned(),
+            moved: true,
+        })
+        .unwrap();
+        self.sender
+            .create_no_parent(
+                &self.get_new_address(path),
+                OperationType::LinkNoParent,
+                path,
+                &send_meta_data,
+            )
+            .await?;
+        self.sender
+            .record_handoff(&self.manager_address.lock().await, &self.address, path)
+            .await?;
+        self.delete_file_no_parent(path)
+    }
+
+    // transfer_status reports the transfers of the last rebalance.
+    pub fn transfer_status(&self) -> TransferStatusRecvMetaData {
+        self.transfer_manager.report()
+    }
+
     pub fn remove_connection(&self, address: String) {
         self.client.remove_connection(&address);
     }
@@ -391,6 +835,18 @@ where
             .clone()
     }
 
+    // is_stale_write tells if a write routed with the hash ring of epoch
+    // reached this server while its newer hash ring places the path on
+    // another server, the old owner must not take writes anymore. The epoch
+    // and the owner are read from the same hash ring.
+    pub fn is_stale_write(&self, path: &str, epoch: u64) -> bool {
+        let hash_ring = self.hash_ring.read();
+        let hash_ring = hash_ring.as_ref().unwrap();
+        epoch != 0
+            && epoch < hash_ring.epoch
+            && hash_ring.get(path).unwrap().address != self.address
+    }
+
     pub fn get_new_address(&self, path: &str) -> String {
         match self.new_hash_ring.read().as_ref() {
             Some(ring) => ring.get(path).unwrap().address.clone(),
@@ -575,19 +1031,151 @@ where
         }
     }
 
+    pub async fn heartbeat(&self) -> Result<(), i32> {
+        let meta_data = HeartbeatSendMetaData {
+            id: self.server_id.clone(),
+            usage: self.volume_stats.take(),
+        };
+        let send_meta_data = bincode::serialize(&meta_data).unwrap();
+
+        let mut status = 0i32;
+        let mut rsp_flags = 0u32;
+
+        let mut recv_meta_data_length = 0usize;
+        let mut recv_data_length = 0usize;
+
+        let result = self
+            .client
+            .call_remote(
+                &self.manager_address.lock().await,
+                ManagerOperationType::Heartbeat.into(),
+                0,
+                &self.address,
+                &send_meta_data,
+                &[],
+                &mut status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut [],
+                &mut [],
+                REQUEST_TIMEOUT,
+            )
+            .await;
+        let result = match result {
+            Ok(_) => {
+                if status != 0 {
+                    Err(status)
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => {
+                error!("heartbeat failed, error: {}", e);
+                Err(CONNECTION_ERROR)
+            }
+        };
+        match result {
+            Ok(()) => *self.last_heartbeat.write() = Some(Instant::now()),
+            // counted again with the next heartbeat.
+            Err(_) => self.volume_stats.restore(meta_data.usage),
+        }
+        result
+    }
+
+    // health checks that the database is writable, that the disk of the
+    // data has free space and that the hash ring is synced.
+    pub fn health(&self) -> HealthReport {
+        let mut report = HealthReport::default();
+        let store = &self.meta_engine.store;
+        report.check(
+            "db",
+            store
+                .put(Table::File, HEALTH_KEY.as_bytes(), b"")
+                .and_then(|_| store.delete(Table::File, HEALTH_KEY.as_bytes()))
+                .map(|_| "writable".to_owned())
+                .map_err(status_to_string),
+        );
+        if let Some(space) = self.storage_engine.disk_space() {
+            report.check(
+                "disk",
+                space
+                    .map_err(status_to_string)
+                    .and_then(|(free, total)| check_disk(free, total)),
+            );
+        }
+        // the fragmentation is reported, a device is ready as long as it has space.
+        if let Some(stats) = self.storage_engine.allocator_stats() {
+            report.check(
+                "allocator",
+                Ok(format!(
+                    "{} of {} chunks free in {} extents, largest {}, fragmentation {:.2}",
+                    stats.free_chunks,
+                    stats.total_chunks,
+                    stats.free_extents,
+                    stats.largest_free_extent,
+                    stats.fragmentation()
+                )),
+            );
+        }
+        let epoch = self.hash_ring.read().as_ref().map(|ring| ring.epoch);
+        let last_heartbeat = *self.last_heartbeat.read();
+        report.check(
+            "ring",
+            match (epoch, last_heartbeat) {
+                (None, _) => Err("no hash ring".to_owned()),
+                (Some(epoch), Some(last)) if last.elapsed() < HEARTBEAT_TIMEOUT => {
+                    Ok(format!("epoch {}", epoch))
+                }
+                (Some(epoch), _) => Err(format!(
+                    "epoch {}, no heartbeat answered by the manager in {:?}",
+                    epoch, HEARTBEAT_TIMEOUT
+                )),
+            },
+        );
+        report
+    }
+
+    // stat_fs returns the space of the disk of the data, summed over the
+    // servers by the clients for statfs.
+    pub fn stat_fs(&self) -> Result<StatFsRecvMetaData, i32> {
+        match self.storage_engine.disk_space() {
+            Some(space) => space.map(|(free, total)| StatFsRecvMetaData { total, free }),
+            None => Ok(StatFsRecvMetaData::default()),
+        }
+    }
+
+    // open_local returns the local file of path for a short-circuit read of
+    // the peer of connection id, see client/short_circuit.rs. Only the peers
+    // of the local socket running as root or as the server read the files of
+    // the server directly.
+    pub fn open_local(&self, id: u32, path: &str) -> Result<String, i32> {
+        match self.local_peers.get(&id) {
+            Some(uid) if *uid == 0 || *uid == nix::unistd::geteuid().as_raw() => {}
+            Some(_) => return Err(libc::EACCES),
+            None => return Err(libc::EPERM),
+        }
+        if self.get_forward_address(path).0.is_some() {
+            return Err(libc::EREMOTE);
+        }
+        self.storage_engine
+            .local_path(path)
+            .unwrap_or(Err(libc::ENOTSUP))
+    }
+
     pub async fn get_cluster_status(&self) -> Result<ClusterStatus, i32> {
         self.sender
             .get_cluster_status(&self.manager_address.lock().await)
             .await
     }
 
-    pub async fn get_hash_ring_info(&self) -> Result<Vec<(String, usize)>, i32> {
+    pub async fn get_hash_ring_info(&self) -> Result<HashRingInfo, i32> {
         self.sender
             .get_hash_ring_info(&self.manager_address.lock().await)
             .await
     }
 
-    pub async fn get_new_hash_ring_info(&self) -> Result<Vec<(String, usize)>, i32> {
+    pub async fn get_new_hash_ring_info(&self) -> Result<HashRingInfo, i32> {
         self.sender
             .get_new_hash_ring_info(&self.manager_address.lock().await)
             .await
@@ -603,57 +1191,16 @@ where
         data: Vec<u8>,
         metadata: Vec<u8>,
     ) -> Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>), i32> {
-        let (
-            mut status,
-            mut rsp_flags,
-            mut recv_meta_data_length,
-            mut recv_data_length,
-            mut recv_meta_data,
-            mut recv_data,
-        ) = match operation_type.try_into().unwrap() {
-            OperationType::Unkown => todo!(),
-            OperationType::Lookup => todo!(),
-            OperationType::CreateFile => (0, 0, 0, 0, vec![0; 1024], vec![]),
-            OperationType::CreateDir => (0, 0, 0, 0, vec![0; 1024], vec![]),
-            OperationType::GetFileAttr => (0, 0, 0, 0, vec![0; 1024], vec![]),
-            OperationType::ReadDir => (0, 0, 0, 0, vec![], vec![0; 2048]),
-            OperationType::OpenFile => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::ReadFile => {
-                let unwraped_meta_data =
-                    bincode::deserialize::<ReadFileSendMetaData>(&metadata).unwrap();
-                (
-                    0,
-                    0,
-                    0,
-                    0,
-                    vec![],
-                    vec![0; unwraped_meta_data.size as usize],
-                )
-            }
-            OperationType::WriteFile => (0, 0, 0, 0, vec![0; 4], vec![]),
-            OperationType::DeleteFile => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::DeleteDir => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::DirectoryAddEntry => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::DirectoryDeleteEntry => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::TruncateFile => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::CheckDir => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::CheckFile => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::CreateDirNoParent => (0, 0, 0, 0, vec![0; 1024], vec![]),
-            OperationType::CreateFileNoParent => (0, 0, 0, 0, vec![0; 1024], vec![]),
-            OperationType::DeleteDirNoParent => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::DeleteFileNoParent => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::CreateVolume => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::InitVolume => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::ListVolumes => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::DeleteVolume => (0, 0, 0, 0, vec![], vec![]),
-            OperationType::CleanVolume => (0, 0, 0, 0, vec![], vec![]),
-        };
+        let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let (mut recv_meta_data, mut recv_data) =
+            forward_buffers(operation_type.try_into().unwrap(), &metadata)?;
         let result = self
             .client
             .call_remote(
                 &address,
                 operation_type,
-                flags,
+                flags | FORWARDED_REQUEST,
                 path,
                 &metadata,
                 &data,
@@ -688,10 +1235,129 @@ where
         ))
     }
 
-    pub fn create_dir_no_parent(&self, path: &str, mode: u32) -> Result<Vec<u8>, i32> {
+    // entry_dir returns the directory keeping the entry name of parent, its
+    // shard if parent is sharded, see util::shard_path.
+    fn entry_dir(&self, parent: &str, name: &str) -> String {
+        match self.meta_engine.dir_shards(parent) {
+            shards if shards > 1 && !is_shard(parent) => shard_path(parent, shard_of(name, shards)),
+            _ => parent.to_owned(),
+        }
+    }
+
+    // remote_shard returns the server of a shard, if it is not this one.
+    fn remote_shard(&self, dir: &str) -> Option<String> {
+        if !is_shard(dir) {
+            return None;
+        }
+        let (address, _lock) = self.get_server_address(dir);
+        (address != self.address).then_some(address)
+    }
+
+    // forward_to_shard sends an entry request on a sharded directory to the
+    // server of its shard, and returns the meta data of the response.
+    async fn forward_to_shard(
+        &self,
+        address: String,
+        operation_type: OperationType,
+        shard: &str,
+        send_meta_data: Vec<u8>,
+    ) -> Result<Vec<u8>, i32> {
+        debug!("forward to shard {:?} on {}", shard, address);
+        let (_, _, meta_data_length, _, mut meta_data, _) = self
+            .forward_request(
+                address,
+                operation_type.into(),
+                0,
+                shard,
+                Vec::new(),
+                send_meta_data,
+            )
+            .await?;
+        meta_data.truncate(meta_data_length);
+        Ok(meta_data)
+    }
+
+    // shard_attr returns the attr of a shard, whose size is its number of
+    // entries.
+    async fn shard_attr(&self, shard: &str) -> Result<FileAttr, i32> {
+        let (address, _lock) = self.get_server_address(shard);
+        match address == self.address {
+            true => self.meta_engine.get_file_attr(shard),
+            false => self.sender.get_file_attr(&address, shard).await,
+        }
+    }
+
+    // shard_dir splits the entries of the empty directory path into shards,
+    // placed on the servers of their paths. The shards are created before
+    // the directory records them, so no entry is sent to a missing shard.
+    pub async fn shard_dir(&self, path: &str, shards: u32) -> Result<(), i32> {
+        if !(1..=MAX_DIR_SHARDS).contains(&shards) || is_shard(path) {
+            return Err(libc::EINVAL);
+        }
+        let attr = self.meta_engine.get_file_attr(path)?;
+        if attr.kind != FileType::Directory {
+            return Err(libc::ENOTDIR);
+        }
+        match self.meta_engine.dir_shards(path) {
+            current if current == shards => return Ok(()),
+            1 => {}
+            // the entries are not moved between shards.
+            _ => return Err(libc::EEXIST),
+        }
+        if attr.size > 0 {
+            return Err(libc::ENOTEMPTY);
+        }
+        let owner = self.id_map.to_client((attr.uid, attr.gid));
+        let send_meta_data = bincode::serialize(&CreateDirSendMetaData {
+            mode: attr.perm as u32,
+            name: "".to_string(),
+            uid: owner.0,
+            gid: owner.1,
+        })
+        .unwrap();
+        for shard in 1..shards {
+            let shard = shard_path(path, shard);
+            let (address, _lock) = self.get_server_address(&shard);
+            let result = if address == self.address {
+                self.create_dir_no_parent(&shard, attr.perm as u32, owner)
+            } else {
+                self.sender
+                    .create_no_parent(
+                        &address,
+                        OperationType::CreateDirNoParent,
+                        &shard,
+                        &send_meta_data,
+                    )
+                    .await
+            };
+            match result {
+                // left by a previous attempt.
+                Ok(_) | Err(libc::EEXIST) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.meta_engine.set_dir_shards(path, shards)?;
+        info!("directory {} sharded into {} shards", path, shards);
+        Ok(())
+    }
+
+    // create_dir_no_parent creates a directory owned by owner, in the ids of
+    // the clients.
+    pub fn create_dir_no_parent(
+        &self,
+        path: &str,
+        mode: u32,
+        owner: (u32, u32),
+    ) -> Result<Vec<u8>, i32> {
         match self.file_locks.insert(path.to_owned(), DashMap::new()) {
             Some(_) => Err(libc::EEXIST), // file will be checked in directory_add_entry, no need to recover here
-            None => self.meta_engine.create_directory(path, mode),
+            None => {
+                self.meta_engine.create_directory(path, mode)?;
+                let (uid, gid) = self.id_map.to_server(owner);
+                self.meta_engine
+                    .set_owner(path, uid, gid, mode)
+                    .map(|attr| self.id_map.map_attr(attr))
+            }
         }
     }
 
@@ -702,6 +1368,15 @@ where
         name: &str,
         mode: u32,
     ) -> Result<Vec<u8>, i32> {
+        let meta_data: CreateDirSendMetaData =
+            bincode::deserialize(&send_meta_data).map_err(|_| libc::EINVAL)?;
+        let owner = (meta_data.uid, meta_data.gid);
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::CreateDir, parent, send_meta_data)
+                .await;
+        }
         if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
             debug!(
                 "create dir failed, file exists, parent: {}, name: {}",
@@ -716,14 +1391,14 @@ where
 
         let result = match result {
             Ok(_) => {
-                let path = get_full_path(parent, name);
+                let path = get_full_path(shard_dir(parent), name);
                 let (address, _lock) = self.get_server_address(&path);
                 if self.address == address {
                     debug!(
                         "local create dir, parent_dir: {}, file_name: {}",
                         parent, name
                     );
-                    self.create_dir_no_parent(&path, mode)
+                    self.create_dir_no_parent(&path, mode, owner)
                 } else {
                     self.sender
                         .create_no_parent(
@@ -746,7 +1421,38 @@ where
         }
     }
 
-    pub fn delete_dir_no_parent(&self, path: &str) -> Result<(), i32> {
+    // delete_dir_no_parent deletes an empty directory, with its shards.
+    pub async fn delete_dir_no_parent(&self, path: &str) -> Result<(), i32> {
+        let shards = self.meta_engine.dir_shards(path);
+        if shards > 1 {
+            // no shard is deleted unless they are all empty.
+            for shard in 0..shards {
+                match self.shard_attr(&shard_path(path, shard)).await {
+                    Ok(attr) if attr.size > 0 => return Err(libc::ENOTEMPTY),
+                    Ok(_) | Err(libc::ENOENT) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            for shard in 1..shards {
+                let shard = shard_path(path, shard);
+                let (address, _lock) = self.get_server_address(&shard);
+                let result = if address == self.address {
+                    self.delete_local_dir(&shard)
+                } else {
+                    self.sender
+                        .delete_no_parent(&address, OperationType::DeleteDirNoParent, &shard, &[])
+                        .await
+                };
+                match result {
+                    Ok(()) | Err(libc::ENOENT) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        self.delete_local_dir(path)
+    }
+
+    fn delete_local_dir(&self, path: &str) -> Result<(), i32> {
         match self.file_locks.get(path) {
             Some(value) => {
                 self.meta_engine.delete_directory(path)?;
@@ -776,19 +1482,26 @@ where
         parent: &str,
         name: &str,
     ) -> Result<(), i32> {
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::DeleteDir, parent, send_meta_data)
+                .await
+                .map(|_| ());
+        }
         if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
             debug!("delete dir failed, file exists, path: {}/{}", parent, name);
             return Err(libc::ENOENT);
         }
 
-        let path = get_full_path(parent, name);
+        let path = get_full_path(shard_dir(parent), name);
         let (address, _lock) = self.get_server_address(&path);
         let result = if self.address == address {
             debug!(
                 "local create dir, parent_dir: {}, file_name: {}",
                 parent, name
             );
-            match self.delete_dir_no_parent(&path) {
+            match self.delete_dir_no_parent(&path).await {
                 Ok(_) => Ok(()),
                 Err(e) => Err(e),
             }
@@ -819,32 +1532,92 @@ where
         }
     }
 
-    pub fn read_dir(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
-        let _file_lock = self.lock_file(path)?;
-        self.meta_engine.read_directory(path, size, offset)
+    // read_dir lists the shards of a sharded directory one after the other,
+    // the cursors of a shard above those of the shards before. A page holds
+    // entries of one shard only, so none is skipped when it is full.
+    pub async fn read_dir(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
+        let shards = self.meta_engine.dir_shards(path);
+        if shards <= 1 {
+            let _file_lock = self.lock_file(path)?;
+            return self.meta_engine.read_directory(path, size, offset);
+        }
+        for shard in cookie_shard(offset)..shards {
+            let shard = shard_path(path, shard);
+            let (address, _lock) = self.get_server_address(&shard);
+            let data = if address == self.address {
+                let _file_lock = self.lock_file(&shard)?;
+                self.meta_engine.read_directory(&shard, size, offset)?
+            } else {
+                let mut data = Vec::with_capacity(size as usize);
+                for (file_type, d_off, name) in
+                    self.sender.read_dir(&address, &shard, offset, size).await?
+                {
+                    put_dir_entry(&mut data, file_type, d_off, name.as_bytes());
+                }
+                data
+            };
+            if !data.is_empty() {
+                return Ok(data);
+            }
+        }
+        Ok(Vec::new())
     }
 
+    // create_file_no_parent creates a file owned by owner, in the ids of the
+    // clients.
+    #[allow(clippy::too_many_arguments)]
     pub fn create_file_no_parent(
         &self,
         path: &str,
         oflag: i32,
         umask: u32,
         mode: u32,
+        layout: &FileLayout,
+        owner: (u32, u32),
     ) -> Result<Vec<u8>, i32> {
         match self.file_locks.insert(path.to_owned(), DashMap::new()) {
             Some(_) => Err(libc::EEXIST),
             None => {
                 debug!("local create file, path: {}", path);
-                self.storage_engine.create_file(path, oflag, umask, mode)
+                self.storage_engine.create_file(path, oflag, umask, mode)?;
+                if *layout != FileLayout::default() {
+                    self.meta_engine.set_layout(path, layout)?;
+                }
+                self.volume_stats.add_file(path, true);
+                let (uid, gid) = self.id_map.to_server(owner);
+                self.meta_engine
+                    .set_owner(path, uid, gid, mode & !umask)
+                    .map(|attr| self.id_map.map_attr(attr))
             }
         }
     }
 
-    pub async fn call_get_attr_remote_or_local(&self, path: &str) -> Result<Vec<u8>, i32> {
-        let (address, _lock) = self.get_server_address(path);
-        if self.address == address {
-            debug!("local get attr, path: {}", path);
-            self.meta_engine.get_file_attr_raw(path)
+    // preallocate_file reserves the space of the expected size of a new
+    // file, a failure only costs the reservation.
+    pub fn preallocate_file(&self, path: &str, size_hint: u64) {
+        if size_hint == 0 {
+            return;
+        }
+        if let Err(e) = self.storage_engine.preallocate(path, size_hint) {
+            warn!(
+                "preallocate {} bytes for {} failed: {}",
+                size_hint,
+                path,
+                status_to_string(e)
+            );
+        }
+    }
+
+    pub async fn call_get_attr_remote_or_local(&self, path: &str) -> Result<Vec<u8>, i32> {
+        // the file of a hard link of this server may be on another one.
+        let path = self.meta_engine.resolve(path);
+        let path: &str = &path;
+        let (address, _lock) = self.get_server_address(path);
+        if self.address == address {
+            debug!("local get attr, path: {}", path);
+            self.meta_engine
+                .get_file_attr_raw(path)
+                .map(|attr| self.id_map.map_attr(attr))
         } else {
             let (mut status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
                 (0, 0, 0, 0);
@@ -892,86 +1665,1012 @@ where
         umask: u32,
         mode: u32,
     ) -> Result<Vec<u8>, i32> {
-        let path = get_full_path(parent, name);
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::CreateFile, parent, send_meta_data)
+                .await;
+        }
+        let path = get_full_path(shard_dir(parent), name);
+
+        debug!(
+            "create file, parent_dir: {}, file_name: {}, oflag: {}, umask: {}, mode: {}",
+            parent, name, oflag, umask, mode
+        );
+        let meta_data: CreateFileSendMetaData =
+            bincode::deserialize(&send_meta_data).map_err(|_| libc::EINVAL)?;
+        let owner = (meta_data.uid, meta_data.gid);
+
+        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
+            if (oflag & O_EXCL) != 0 {
+                debug!("create file failed, file exists, path: {}", path);
+                return Err(libc::EEXIST); // this indicate that the file is being created or deleted
+            } else {
+                match self.call_get_attr_remote_or_local(&path).await {
+                    Ok(attr) => return Ok(attr),
+                    Err(libc::ENOENT) => {
+                        return Ok(file_attr_as_bytes(&empty_file()).to_vec());
+                        // this indicate that the file is creating or deleting
+                    }
+                    Err(e) => {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        let result =
+            self.meta_engine
+                .directory_add_entry(parent, name, FileTypeSimple::RegularFile.into());
+
+        let result = match result {
+            Ok(_) => {
+                let (address, _lock) = self.get_server_address(&path);
+                let result = if self.address == address {
+                    debug!(
+                        "local create file, parent_file: {}, file_name: {}",
+                        parent, name
+                    );
+                    let layout = FileLayout {
+                        append_only: meta_data.append_only,
+                        ..self.volume_layout(&path).await
+                    };
+                    self.create_file_no_parent(&path, oflag, umask, mode, &layout, owner)
+                        .map(|attr| {
+                            self.preallocate_file(&path, meta_data.size_hint);
+                            attr
+                        })
+                } else {
+                    self.sender
+                        .create_no_parent(
+                            &address,
+                            OperationType::CreateFileNoParent,
+                            &path,
+                            &send_meta_data,
+                        )
+                        .await
+                };
+                match result {
+                    Ok(attr) => Ok(attr),
+                    Err(libc::EEXIST) => {
+                        if (oflag & O_EXCL) != 0 {
+                            Err(libc::EEXIST) // this may indicate that the file is being created or deleted
+                        } else {
+                            self.call_get_attr_remote_or_local(&path).await
+                        }
+                    }
+                    Err(e) => {
+                        error!("Create file: DirectoryAddEntry failed: {} ,{:?}", path, e);
+                        Err(e)
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        self.file_locks.get(parent).unwrap().remove(name);
+
+        match result {
+            Ok(attr) => Ok(attr),
+            Err(e) => Err(e),
+        }
+    }
+
+    // link_temp_file publishes the unnamed file temp_path as name in the
+    // directory parent. Its data is copied to the server of the new path
+    // before the entry is added, so the file is never seen partially written.
+    pub async fn link_temp_file(
+        &self,
+        parent: &str,
+        name: &str,
+        temp_path: &str,
+    ) -> Result<Vec<u8>, i32> {
+        if !is_temp_file(temp_path) {
+            return Err(libc::EINVAL);
+        }
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            let send_meta_data = bincode::serialize(&LinkTempFileSendMetaData {
+                name: name.to_owned(),
+                temp_path: temp_path.to_owned(),
+            })
+            .unwrap();
+            return self
+                .forward_to_shard(address, OperationType::LinkTempFile, parent, send_meta_data)
+                .await;
+        }
+        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
+            return Err(libc::EEXIST);
+        }
+
+        let path = get_full_path(shard_dir(parent), name);
+        debug!("link temp file, temp_path: {}, path: {}", temp_path, path);
+        let result = match self.copy_file(temp_path, &path).await {
+            Ok(()) => match self.meta_engine.directory_add_entry(
+                parent,
+                name,
+                FileTypeSimple::RegularFile.into(),
+            ) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    let _ = self.delete_file_remote_or_local(&path).await;
+                    Err(e)
+                }
+            },
+            Err(e) => Err(e),
+        };
+        self.file_locks.get(parent).unwrap().remove(name);
+        result?;
+
+        if let Err(e) = self.delete_file_remote_or_local(temp_path).await {
+            error!(
+                "link temp file, delete {} failed: {:?}",
+                temp_path,
+                status_to_string(e)
+            );
+        }
+        self.call_get_attr_remote_or_local(&path).await
+    }
+
+    // create_symlink adds the symbolic link name to a target in the directory
+    // parent, a file of the kind Symlink whose data is the target.
+    pub async fn create_symlink(
+        &self,
+        send_meta_data: Vec<u8>,
+        parent: &str,
+        name: &str,
+    ) -> Result<Vec<u8>, i32> {
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(
+                    address,
+                    OperationType::CreateSymlink,
+                    parent,
+                    send_meta_data,
+                )
+                .await;
+        }
+        let meta_data: CreateSymlinkSendMetaData =
+            bincode::deserialize(&send_meta_data).map_err(|_| libc::EINVAL)?;
+        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
+            return Err(libc::EEXIST);
+        }
+
+        let path = get_full_path(shard_dir(parent), name);
+        debug!(
+            "create symlink, path: {}, target: {}",
+            path, meta_data.target
+        );
+        let symlink = FileTypeSimple::Symlink.into();
+        let result = match self.meta_engine.directory_add_entry(parent, name, symlink) {
+            Ok(()) => {
+                let (address, _lock) = self.get_server_address(&path);
+                let result = if address == self.address {
+                    self.create_symlink_no_parent(
+                        &path,
+                        &meta_data.target,
+                        (meta_data.uid, meta_data.gid),
+                    )
+                } else {
+                    self.sender
+                        .create_no_parent(
+                            &address,
+                            OperationType::CreateSymlinkNoParent,
+                            &path,
+                            &send_meta_data,
+                        )
+                        .await
+                };
+                // a file or a directory of the same name is there.
+                if result.is_err() {
+                    let _ = self
+                        .meta_engine
+                        .directory_delete_entry(parent, name, symlink);
+                }
+                result
+            }
+            Err(e) => Err(e),
+        };
+        self.file_locks.get(parent).unwrap().remove(name);
+        result
+    }
+
+    pub fn create_symlink_no_parent(
+        &self,
+        path: &str,
+        target: &str,
+        owner: (u32, u32),
+    ) -> Result<Vec<u8>, i32> {
+        let oflag = O_CREAT | O_EXCL | O_RDWR;
+        self.create_file_no_parent(path, oflag, 0, 0o777, &FileLayout::default(), owner)?;
+        if let Err(e) = self.write_file(path, target.as_bytes(), 0, 0) {
+            let _ = self.delete_file_no_parent(path);
+            return Err(e);
+        }
+        self.meta_engine
+            .set_kind(path, FileType::Symlink)
+            .map(|attr| self.id_map.map_attr(attr))
+    }
+
+    // link adds name in the directory parent as a hard link to the file
+    // target. The link is kept by the server of its path, and counted by the
+    // server of the file, see add_link.
+    pub async fn link(
+        &self,
+        send_meta_data: Vec<u8>,
+        parent: &str,
+        name: &str,
+    ) -> Result<Vec<u8>, i32> {
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::Link, parent, send_meta_data)
+                .await;
+        }
+        let meta_data: LinkSendMetaData =
+            bincode::deserialize(&send_meta_data).map_err(|_| libc::EINVAL)?;
+        let path = get_full_path(shard_dir(parent), name);
+        let (address, _lock) = self.get_server_address(&path);
+        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
+            return Err(libc::EEXIST);
+        }
+
+        debug!("link, path: {}, target: {}", path, meta_data.target);
+        let file = FileTypeSimple::RegularFile.into();
+        // the entry of an existing name is not removed with the failed link.
+        let result = match self.meta_engine.find_entry(parent, name) {
+            Ok(Some(_)) => Err(libc::EEXIST),
+            Ok(None) => self.meta_engine.directory_add_entry(parent, name, file),
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(()) => {
+                let result = if address == self.address {
+                    self.link_no_parent(&path, &meta_data).await
+                } else {
+                    self.sender
+                        .create_no_parent(
+                            &address,
+                            OperationType::LinkNoParent,
+                            &path,
+                            &send_meta_data,
+                        )
+                        .await
+                };
+                if result.is_err() {
+                    let _ = self.meta_engine.directory_delete_entry(parent, name, file);
+                }
+                result
+            }
+            Err(e) => Err(e),
+        };
+        self.file_locks.get(parent).unwrap().remove(name);
+        result
+    }
+
+    // link_no_parent makes path a hard link of this server to the file
+    // md.target, counted by the server of the file first. A moved link is
+    // counted already.
+    pub async fn link_no_parent(&self, path: &str, md: &LinkSendMetaData) -> Result<Vec<u8>, i32> {
+        if md.moved {
+            self.meta_engine.put_link(path, &md.target)?;
+            return Ok(Vec::new());
+        }
+        if self.meta_engine.is_exist(path)? {
+            return Err(libc::EEXIST);
+        }
+        let linked = self.add_link_remote_or_local(&md.target, 1).await?;
+        if let Err(e) = self.meta_engine.put_link(path, &linked.path) {
+            if let Err(e) = self.add_link_remote_or_local(&linked.path, -1).await {
+                error!(
+                    "link, uncount {} failed: {}",
+                    linked.path,
+                    status_to_string(e)
+                );
+            }
+            return Err(e);
+        }
+        Ok(linked.file_attr)
+    }
+
+    // add_link adds delta to the links of the regular file path of this
+    // server, and returns its attr and the path its links point to. The
+    // first link moves the file to a path of its own and leaves a link to it
+    // at its path: every name of the file is then a hard link counted in its
+    // nlink, and the file is deleted with the last one. A linked file was
+    // moved already.
+    pub async fn add_link(
+        &self,
+        path: &str,
+        delta: i32,
+        linked: bool,
+    ) -> Result<AddLinkRecvMetaData, i32> {
+        let attr = self.meta_engine.get_file_attr(path)?;
+        if linked || delta < 0 || attr.flags & FILE_FLAG_LINKED != 0 {
+            return self.count_link(path, delta);
+        }
+        let _link_lock = self.link_lock.lock().await;
+        // moved by another link in the meantime.
+        if let Some(target) = self.meta_engine.link_target(path) {
+            return self.count_link_remote_or_local(&target, delta).await;
+        }
+        let attr = self.meta_engine.get_file_attr(path)?;
+        if attr.kind != FileType::RegularFile {
+            return Err(libc::EPERM);
+        }
+        let target = self.aside_path(path, "link");
+        self.move_file(path, &target, &attr).await?;
+        let result = match self.meta_engine.put_link(path, &target) {
+            Ok(()) => self.count_link_remote_or_local(&target, delta).await,
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = self.meta_engine.remove_link(path);
+            if let Err(e) = self.move_file(&target, path, &attr).await {
+                error!(
+                    "link, restore {} from {} failed: {}",
+                    path,
+                    target,
+                    status_to_string(e)
+                );
+            }
+        }
+        result
+    }
+
+    fn count_link(&self, path: &str, delta: i32) -> Result<AddLinkRecvMetaData, i32> {
+        let attr = {
+            let _file_lock = self.lock_file(path)?;
+            self.meta_engine.add_link(path, delta)?
+        };
+        if bytes_as_file_attr(&attr).nlink == 0 {
+            self.delete_file_no_parent(path)?;
+        }
+        Ok(AddLinkRecvMetaData {
+            file_attr: self.id_map.map_attr(attr),
+            path: path.to_owned(),
+        })
+    }
+
+    async fn count_link_remote_or_local(
+        &self,
+        path: &str,
+        delta: i32,
+    ) -> Result<AddLinkRecvMetaData, i32> {
+        let (address, _lock) = self.get_server_address(path);
+        if address == self.address {
+            self.count_link(path, delta)
+        } else {
+            self.sender.add_link(&address, path, delta, true).await
+        }
+    }
+
+    // add_link_remote_or_local adds delta to the links of the file path, or
+    // of the file of path if it is a hard link of this server.
+    async fn add_link_remote_or_local(
+        &self,
+        path: &str,
+        delta: i32,
+    ) -> Result<AddLinkRecvMetaData, i32> {
+        let path = self.meta_engine.resolve(path).into_owned();
+        let (address, _lock) = self.get_server_address(&path);
+        if address == self.address {
+            self.add_link(&path, delta, false).await
+        } else {
+            self.sender.add_link(&address, &path, delta, false).await
+        }
+    }
+
+    // unlink_no_parent removes path of this server: a hard link is removed
+    // and uncounted by the server of its file, a file is deleted.
+    pub async fn unlink_no_parent(&self, path: &str) -> Result<(), i32> {
+        let Some(target) = self.meta_engine.remove_link(path)? else {
+            return self.delete_file_no_parent(path);
+        };
+        if let Err(e) = self.add_link_remote_or_local(&target, -1).await {
+            let _ = self.meta_engine.put_link(path, &target);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    async fn unlink_remote_or_local(&self, path: &str) -> Result<(), i32> {
+        let (address, _) = self.get_server_address(path);
+        if address == self.address {
+            self.unlink_no_parent(path).await
+        } else {
+            self.sender
+                .delete_no_parent(&address, OperationType::DeleteFileNoParent, path, &[])
+                .await
+        }
+    }
+
+    // move_link moves the hard link path to new_path: a new link to its file
+    // is added before the old one is removed.
+    async fn move_link(&self, path: &str, new_path: &str) -> Result<(), i32> {
+        let md = LinkSendMetaData {
+            name: "".to_string(),
+            target: path.to_owned(),
+            moved: false,
+        };
+        let (address, _lock) = self.get_server_address(new_path);
+        if address == self.address {
+            self.link_no_parent(new_path, &md).await?;
+        } else {
+            self.sender
+                .create_no_parent(
+                    &address,
+                    OperationType::LinkNoParent,
+                    new_path,
+                    &bincode::serialize(&md).unwrap(),
+                )
+                .await?;
+        }
+        if let Err(e) = self.unlink_remote_or_local(path).await {
+            let _ = self.unlink_remote_or_local(new_path).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // rename moves the entry name of the directory parent to md.new_name in
+    // md.new_parent. The server of the new directory moves the file with
+    // RenameAt, then the old entry is removed; the file is at its old or at
+    // its new path all along, not lost by a failure in between. The files are
+    // hashed by their paths, so a file moving to another server is copied
+    // there and deleted: its open handles and POSIX locks do not follow it.
+    pub async fn rename(&self, send_meta_data: Vec<u8>, parent: &str, name: &str) -> Result<(), i32>
+    where
+        Storage: Send + Sync,
+    {
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::Rename, parent, send_meta_data)
+                .await
+                .map(|_| ());
+        }
+        let md: RenameSendMetaData =
+            bincode::deserialize(&send_meta_data).map_err(|_| libc::EINVAL)?;
+        if md.flags & !RENAME_NOREPLACE != 0 {
+            return Err(libc::EINVAL);
+        }
+        let path = get_full_path(shard_dir(parent), name);
+        let new_path = get_full_path(&md.new_parent, &md.new_name);
+        if new_path == path {
+            return Ok(());
+        }
+        // a directory cannot be moved under itself.
+        if new_path.starts_with(&format!("{}/", path)) {
+            return Err(libc::EINVAL);
+        }
+        if path.split('/').next() != new_path.split('/').next() {
+            return Err(libc::EXDEV);
+        }
+        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
+            return Err(libc::EBUSY);
+        }
+
+        debug!("rename, path: {}, new_path: {}", path, new_path);
+        let result = match self.meta_engine.find_entry(parent, name) {
+            Ok(Some(file_type)) => {
+                let send_meta_data = bincode::serialize(&RenameAtSendMetaData {
+                    name: md.new_name.clone(),
+                    old_path: path.clone(),
+                    flags: md.flags,
+                })
+                .unwrap();
+                let (address, _lock) = self.get_server_address(&md.new_parent);
+                let result = if address == self.address {
+                    self.rename_at(send_meta_data, &md.new_parent, &md.new_name)
+                        .await
+                } else {
+                    self.sender
+                        .rename(
+                            &address,
+                            OperationType::RenameAt,
+                            &md.new_parent,
+                            &send_meta_data,
+                        )
+                        .await
+                };
+                result.and_then(|_| {
+                    self.meta_engine
+                        .directory_delete_entry(parent, name, file_type)
+                })
+            }
+            Ok(None) => Err(libc::ENOENT),
+            Err(e) => Err(e),
+        };
+        self.file_locks.get(parent).unwrap().remove(name);
+        result
+    }
+
+    // rename_at adds the entry name of the directory parent for the file
+    // old_path of md, moved to its new path first. An entry of the same name
+    // is replaced, its file deleted once the new one is in place.
+    pub async fn rename_at(
+        &self,
+        send_meta_data: Vec<u8>,
+        parent: &str,
+        name: &str,
+    ) -> Result<(), i32>
+    where
+        Storage: Send + Sync,
+    {
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::RenameAt, parent, send_meta_data)
+                .await
+                .map(|_| ());
+        }
+        let md: RenameAtSendMetaData =
+            bincode::deserialize(&send_meta_data).map_err(|_| libc::EINVAL)?;
+        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
+            return Err(libc::EBUSY);
+        }
+        let path = get_full_path(shard_dir(parent), name);
+        let result = self.move_entry(parent, name, &md, &path).await;
+        self.file_locks.get(parent).unwrap().remove(name);
+        result
+    }
+
+    async fn move_entry(
+        &self,
+        parent: &str,
+        name: &str,
+        md: &RenameAtSendMetaData,
+        path: &str,
+    ) -> Result<(), i32>
+    where
+        Storage: Send + Sync,
+    {
+        let attr = self.call_get_attr_remote_or_local(&md.old_path).await?;
+        let attr = *bytes_as_file_attr(&attr);
+        let is_dir = attr.kind == FileType::Directory;
+        let linked = attr.flags & FILE_FLAG_LINKED != 0;
+        let directory: u8 = FileTypeSimple::Directory.into();
+        let target = self.meta_engine.find_entry(parent, name)?;
+        match target {
+            Some(_) if md.flags & RENAME_NOREPLACE != 0 => return Err(libc::EEXIST),
+            Some(file_type) if is_dir && file_type != directory => return Err(libc::ENOTDIR),
+            Some(file_type) if !is_dir && file_type == directory => return Err(libc::EISDIR),
+            _ => {}
+        }
+
+        let owner = (attr.uid, attr.gid);
+        if is_dir {
+            // the replaced directory is deleted first, which fails unless it
+            // is empty. An empty directory is then deleted and created at its
+            // new path, the entries of another one are moved, see move_dir.
+            if target.is_some() {
+                self.delete_dir_remote_or_local(path).await?;
+            }
+            match self.delete_dir_remote_or_local(&md.old_path).await {
+                Ok(()) => {
+                    if let Err(e) = self
+                        .create_dir_remote_or_local(path, attr.perm as u32, owner)
+                        .await
+                    {
+                        let _ = self
+                            .create_dir_remote_or_local(&md.old_path, attr.perm as u32, owner)
+                            .await;
+                        return Err(e);
+                    }
+                }
+                Err(libc::ENOTEMPTY) => self.move_dir(&md.old_path, path, &attr).await?,
+                Err(e) => return Err(e),
+            }
+        } else {
+            // the replaced file is moved aside first, and only deleted once
+            // the file is moved over it, or moved back if that fails. A hard
+            // link is removed first, its file lives on in its other links.
+            let replaced = match target {
+                Some(_) => {
+                    let replaced = self.call_get_attr_remote_or_local(path).await?;
+                    let replaced = *bytes_as_file_attr(&replaced);
+                    if replaced.flags & FILE_FLAG_LINKED != 0 {
+                        self.unlink_remote_or_local(path).await?;
+                        None
+                    } else {
+                        let aside = self.aside_path(path, "replaced");
+                        self.move_file(path, &aside, &replaced).await?;
+                        Some((aside, replaced))
+                    }
+                }
+                None => None,
+            };
+            // a hard link moves as a new link to its file.
+            let moved = match linked {
+                true => self.move_link(&md.old_path, path).await,
+                false => self.move_file(&md.old_path, path, &attr).await,
+            };
+            if let Err(e) = moved {
+                if let Some((aside, replaced)) = replaced {
+                    if let Err(e) = self.move_file(&aside, path, &replaced).await {
+                        error!(
+                            "rename, restore {} from {} failed: {}",
+                            path,
+                            aside,
+                            status_to_string(e)
+                        );
+                    }
+                }
+                return Err(e);
+            }
+            if let Some((aside, _)) = replaced {
+                if let Err(e) = self.delete_file_remote_or_local(&aside).await {
+                    warn!(
+                        "rename, delete the replaced {} failed: {}",
+                        aside,
+                        status_to_string(e)
+                    );
+                }
+            }
+        }
+        // the entry replaced is kept for a file of its type, lookups find the
+        // new file at once; one of another type is removed after the new one
+        // is added.
+        let file_type = FileTypeSimple::from(attr.kind).into();
+        if target != Some(file_type) {
+            self.meta_engine
+                .directory_add_entry(parent, name, file_type)?;
+            if let Some(target) = target {
+                self.meta_engine
+                    .directory_delete_entry(parent, name, target)?;
+            }
+        }
+        if !linked {
+            let times = SetAttrSendMetaData {
+                atime: Some(attr.atime),
+                mtime: Some(attr.mtime),
+                ..Default::default()
+            };
+            let (address, _lock) = self.get_server_address(path);
+            let result = match address == self.address {
+                true => self.set_attr(path, &times).map(|_| ()),
+                false => self
+                    .sender
+                    .set_attr(&address, path, &times)
+                    .await
+                    .map(|_| ()),
+            };
+            if let Err(e) = result {
+                warn!(
+                    "rename, keep the times of {} failed: {}",
+                    path,
+                    status_to_string(e)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // move_dir moves the directory path, which has entries, to new_path: the
+    // new directory is created, the entries are renamed into it, and the old
+    // one is deleted once empty. The servers of the entries move them, the
+    // subdirectories in turn. A failure leaves the entries moved so far in
+    // the new directory and the others in the old one.
+    async fn move_dir(&self, path: &str, new_path: &str, attr: &FileAttr) -> Result<(), i32>
+    where
+        Storage: Send + Sync,
+    {
+        self.create_dir_remote_or_local(new_path, attr.perm as u32, (attr.uid, attr.gid))
+            .await?;
+        let (address, _lock) = self.get_server_address(path);
+        loop {
+            // the first page again, the entries renamed have left it.
+            let entries: Vec<String> = if address == self.address {
+                let data = self.read_dir(path, MOVE_DIR_PAGE_SIZE, 0).await?;
+                bytes_as_dir_entries(&data)?
+                    .into_iter()
+                    .map(|(_, _, name)| String::from_utf8_lossy(name).into_owned())
+                    .collect()
+            } else {
+                self.sender
+                    .read_dir(&address, path, 0, MOVE_DIR_PAGE_SIZE)
+                    .await?
+                    .into_iter()
+                    .map(|(_, _, name)| name)
+                    .collect()
+            };
+            if entries.is_empty() {
+                break;
+            }
+            for name in entries {
+                let send_meta_data = bincode::serialize(&RenameSendMetaData {
+                    name: name.clone(),
+                    new_parent: new_path.to_owned(),
+                    new_name: name.clone(),
+                    flags: RENAME_NOREPLACE,
+                })
+                .unwrap();
+                if address == self.address {
+                    self.rename_boxed(send_meta_data, path, &name).await?;
+                } else {
+                    self.sender
+                        .rename(&address, OperationType::Rename, path, &send_meta_data)
+                        .await?;
+                }
+            }
+        }
+        self.delete_dir_remote_or_local(path).await
+    }
+
+    // rename_boxed is rename for move_dir, which rename calls in turn.
+    fn rename_boxed<'a>(
+        &'a self,
+        send_meta_data: Vec<u8>,
+        parent: &'a str,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = Result<(), i32>> + Send + 'a>>
+    where
+        Storage: Send + Sync,
+    {
+        Box::pin(self.rename(send_meta_data, parent, name))
+    }
+
+    // aside_path returns a new path named after tag to keep the file of path,
+    // on the server of path if one of the names tried hashes to it so that
+    // the file is renamed rather than copied. It is not added to any
+    // directory.
+    fn aside_path(&self, path: &str, tag: &str) -> String {
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let address = self.get_server_address(path).0;
+        let names = (0..ASIDE_PATH_TRIES).map(|i| format!("{}.{}-{}-{}", path, tag, stamp, i));
+        let mut first = None;
+        for name in names {
+            if self.get_server_address(&name).0 == address {
+                return name;
+            }
+            first.get_or_insert(name);
+        }
+        first.unwrap()
+    }
+
+    // move_file moves the file path to new_path, renamed by its server if
+    // it is the server of both and its engine can, copied otherwise.
+    async fn move_file(&self, path: &str, new_path: &str, attr: &FileAttr) -> Result<(), i32> {
+        let (address, _lock) = self.get_server_address(path);
+        if address == self.get_server_address(new_path).0 {
+            let result = if address == self.address {
+                self.rename_no_parent(path, new_path)
+            } else {
+                let send_meta_data = bincode::serialize(&RenameNoParentSendMetaData {
+                    new_path: new_path.to_owned(),
+                })
+                .unwrap();
+                self.sender
+                    .rename(
+                        &address,
+                        OperationType::RenameNoParent,
+                        path,
+                        &send_meta_data,
+                    )
+                    .await
+            };
+            if result != Err(libc::EOPNOTSUPP) {
+                return result;
+            }
+        }
+        if attr.kind == FileType::Symlink {
+            let target = match address == self.address {
+                true => self.read_file(path, libc::PATH_MAX as u32, 0, AtimePolicy::Noatime),
+                false => {
+                    self.sender
+                        .read_file(&address, path, 0, libc::PATH_MAX as u32)
+                        .await
+                }
+            }?;
+            let target = String::from_utf8(target).map_err(|_| libc::EINVAL)?;
+            self.create_symlink_remote_or_local(new_path, &target, (attr.uid, attr.gid))
+                .await?;
+        } else {
+            self.copy_file(path, new_path).await?;
+        }
+        if let Err(e) = self.delete_file_remote_or_local(path).await {
+            let _ = self.delete_file_remote_or_local(new_path).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // rename_no_parent moves the file path of this server to new_path, also
+    // of this server.
+    pub fn rename_no_parent(&self, path: &str, new_path: &str) -> Result<(), i32> {
+        if self.file_locks.contains_key(new_path) {
+            return Err(libc::EEXIST);
+        }
+        match self.file_locks.get_mut(path) {
+            Some(value) => {
+                self.storage_engine.rename_file(path, new_path)?;
+                drop(value);
+                self.file_locks.remove(path);
+                self.file_locks.insert(new_path.to_owned(), DashMap::new());
+                Ok(())
+            }
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    async fn create_dir_remote_or_local(
+        &self,
+        path: &str,
+        mode: u32,
+        owner: (u32, u32),
+    ) -> Result<(), i32> {
+        let (address, _lock) = self.get_server_address(path);
+        if address == self.address {
+            return self.create_dir_no_parent(path, mode, owner).map(|_| ());
+        }
+        let send_meta_data = bincode::serialize(&CreateDirSendMetaData {
+            mode,
+            name: "".to_string(),
+            uid: owner.0,
+            gid: owner.1,
+        })
+        .unwrap();
+        self.sender
+            .create_no_parent(
+                &address,
+                OperationType::CreateDirNoParent,
+                path,
+                &send_meta_data,
+            )
+            .await
+            .map(|_| ())
+    }
+
+    async fn delete_dir_remote_or_local(&self, path: &str) -> Result<(), i32> {
+        let (address, _lock) = self.get_server_address(path);
+        if address == self.address {
+            self.delete_dir_no_parent(path).await
+        } else {
+            self.sender
+                .delete_no_parent(&address, OperationType::DeleteDirNoParent, path, &[])
+                .await
+        }
+    }
+
+    async fn create_symlink_remote_or_local(
+        &self,
+        path: &str,
+        target: &str,
+        owner: (u32, u32),
+    ) -> Result<(), i32> {
+        let (address, _lock) = self.get_server_address(path);
+        if address == self.address {
+            return self
+                .create_symlink_no_parent(path, target, owner)
+                .map(|_| ());
+        }
+        let send_meta_data = bincode::serialize(&CreateSymlinkSendMetaData {
+            name: "".to_string(),
+            target: target.to_owned(),
+            uid: owner.0,
+            gid: owner.1,
+        })
+        .unwrap();
+        self.sender
+            .create_no_parent(
+                &address,
+                OperationType::CreateSymlinkNoParent,
+                path,
+                &send_meta_data,
+            )
+            .await
+            .map(|_| ())
+    }
 
-        debug!(
-            "create file, parent_dir: {}, file_name: {}, oflag: {}, umask: {}, mode: {}",
-            parent, name, oflag, umask, mode
-        );
+    // copy_file creates path without a directory entry, with the mode
+    // and data of from.
+    async fn copy_file(&self, from: &str, path: &str) -> Result<(), i32> {
+        let (from_address, _) = self.get_server_address(from);
+        let (address, _) = self.get_server_address(path);
+        let (from_attr, owner) = if from_address == self.address {
+            let attr = self.meta_engine.get_file_attr(from)?;
+            (attr, self.id_map.to_client((attr.uid, attr.gid)))
+        } else {
+            let attr = self.sender.get_file_attr(&from_address, from).await?;
+            (attr, (attr.uid, attr.gid))
+        };
 
-        if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
-            if (oflag & O_EXCL) != 0 {
-                debug!("create file failed, file exists, path: {}", path);
-                return Err(libc::EEXIST); // this indicate that the file is being created or deleted
-            } else {
-                match self.call_get_attr_remote_or_local(&path).await {
-                    Ok(attr) => return Ok(attr),
-                    Err(libc::ENOENT) => {
-                        return Ok(file_attr_as_bytes(&empty_file()).to_vec());
-                        // this indicate that the file is creating or deleting
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
-                }
-            }
+        let (oflag, mode) = (O_CREAT | O_EXCL | O_RDWR, from_attr.perm as u32);
+        let append_only = FileLayout::of(&from_attr).append_only;
+        if address == self.address {
+            let layout = FileLayout {
+                append_only,
+                ..self.volume_layout(path).await
+            };
+            self.create_file_no_parent(path, oflag, 0, mode, &layout, owner)?;
+            self.preallocate_file(path, from_attr.size);
+        } else {
+            let send_meta_data = bincode::serialize(&CreateFileSendMetaData {
+                mode,
+                umask: 0,
+                flags: oflag,
+                name: "".to_string(),
+                uid: owner.0,
+                gid: owner.1,
+                size_hint: from_attr.size,
+                append_only,
+            })
+            .unwrap();
+            self.sender
+                .create_no_parent(
+                    &address,
+                    OperationType::CreateFileNoParent,
+                    path,
+                    &send_meta_data,
+                )
+                .await?;
         }
 
-        let result =
-            self.meta_engine
-                .directory_add_entry(parent, name, FileTypeSimple::RegularFile.into());
-
-        let result = match result {
-            Ok(_) => {
-                let (address, _lock) = self.get_server_address(&path);
-                let result = if self.address == address {
-                    debug!(
-                        "local create file, parent_file: {}, file_name: {}",
-                        parent, name
-                    );
-                    self.create_file_no_parent(&path, oflag, umask, mode)
-                } else {
-                    self.sender
-                        .create_no_parent(
-                            &address,
-                            OperationType::CreateFileNoParent,
-                            &path,
-                            &send_meta_data,
-                        )
-                        .await
-                };
-                match result {
-                    Ok(attr) => Ok(attr),
-                    Err(libc::EEXIST) => {
-                        if (oflag & O_EXCL) != 0 {
-                            Err(libc::EEXIST) // this may indicate that the file is being created or deleted
-                        } else {
-                            self.call_get_attr_remote_or_local(&path).await
-                        }
-                    }
-                    Err(e) => {
-                        error!("Create file: DirectoryAddEntry failed: {} ,{:?}", path, e);
-                        Err(e)
-                    }
-                }
+        let mut offset = 0;
+        let result = loop {
+            if offset >= from_attr.size as i64 {
+                break Ok(());
             }
-            Err(e) => Err(e),
+            let data = if from_address == self.address {
+                self.read_file(from, CHUNK_SIZE as u32, offset, AtimePolicy::Noatime)
+            } else {
+                self.sender
+                    .read_file(&from_address, from, offset, CHUNK_SIZE as u32)
+                    .await
+            };
+            let data = match data {
+                Ok(data) if data.is_empty() => break Ok(()),
+                Ok(data) => data,
+                Err(e) => break Err(e),
+            };
+            let written = if address == self.address {
+                self.write_file(path, &data, offset, 0).map(|_| ())
+            } else {
+                self.sender
+                    .write_file(&address, path, offset, 0, 0, &data)
+                    .await
+                    .map(|_| ())
+            };
+            if let Err(e) = written {
+                break Err(e);
+            }
+            offset += data.len() as i64;
         };
+        if result.is_err() {
+            let _ = self.delete_file_remote_or_local(path).await;
+        }
+        result
+    }
 
-        self.file_locks.get(parent).unwrap().remove(name);
-
-        match result {
-            Ok(attr) => Ok(attr),
-            Err(e) => Err(e),
+    async fn delete_file_remote_or_local(&self, path: &str) -> Result<(), i32> {
+        let (address, _) = self.get_server_address(path);
+        if address == self.address {
+            self.delete_file_no_parent(path)
+        } else {
+            self.sender
+                .delete_no_parent(&address, OperationType::DeleteFileNoParent, path, &[])
+                .await
         }
     }
 
+    // delete_file_no_parent deletes the file path of this server. A hard link
+    // is only removed, e.g. moved by a transfer, see unlink_no_parent to
+    // uncount it.
     pub fn delete_file_no_parent(&self, path: &str) -> Result<(), i32> {
+        if self.meta_engine.remove_link(path)?.is_some() {
+            return Ok(());
+        }
         match self.file_locks.get_mut(path) {
             Some(value) => {
                 self.storage_engine.delete_file(path)?;
                 drop(value);
                 self.file_locks.remove(path);
+                self.volume_stats.add_file(path, false);
                 Ok(())
             }
             None => Err(libc::ENOENT),
@@ -984,22 +2683,26 @@ where
         parent: &str,
         name: &str,
     ) -> Result<(), i32> {
+        let parent = &self.entry_dir(parent, name);
+        if let Some(address) = self.remote_shard(parent) {
+            return self
+                .forward_to_shard(address, OperationType::DeleteFile, parent, send_meta_data)
+                .await
+                .map(|_| ());
+        }
         if self.lock_file(parent)?.insert(name.to_owned(), 0).is_some() {
             debug!("delete file failed, file exists, path: {}/{}", parent, name);
             return Err(libc::ENOENT); // this may indicate that the file is being created or deleted
         }
 
-        let path = get_full_path(parent, name);
+        let path = get_full_path(shard_dir(parent), name);
         let (address, _lock) = self.get_server_address(&path);
         let result = if self.address == address {
             debug!(
                 "local create file, parent_file: {}, file_name: {}",
                 parent, name
             );
-            match self.delete_file_no_parent(&path) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
-            }
+            self.unlink_no_parent(&path).await
         } else {
             self.sender
                 .delete_no_parent(
@@ -1012,11 +2715,9 @@ where
         };
 
         if result.is_ok() {
-            self.meta_engine.directory_delete_entry(
-                parent,
-                name,
-                FileTypeSimple::RegularFile.into(),
-            )?;
+            let file_type = self.meta_engine.entry_type(parent, name)?;
+            self.meta_engine
+                .directory_delete_entry(parent, name, file_type)?;
         }
         self.file_locks.get(parent).unwrap().remove(name);
 
@@ -1032,19 +2733,171 @@ where
         self.storage_engine.truncate_file(path, length)
     }
 
-    pub fn read_file(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
+    // set_attr changes the attrs of path set in md, the size first, and
+    // returns the new attr.
+    pub fn set_attr(&self, path: &str, md: &SetAttrSendMetaData) -> Result<Vec<u8>, i32> {
+        let _file_lock = self.lock_file(path)?;
+        if let Some(size) = md.size {
+            if self.meta_engine.is_dir(path)? {
+                return Err(libc::EISDIR);
+            }
+            self.storage_engine.truncate_file(path, size as i64)?;
+        }
+        let (uid, gid) = self
+            .id_map
+            .to_server((md.uid.unwrap_or(0), md.gid.unwrap_or(0)));
+        let attr = self.meta_engine.set_attr(path, |attr| {
+            if let Some(mode) = md.mode {
+                attr.perm = (mode & 0o7777) as u16;
+            }
+            if md.uid.is_some() {
+                attr.uid = uid;
+            }
+            if md.gid.is_some() {
+                attr.gid = gid;
+            }
+            if let Some(atime) = md.atime {
+                attr.atime = atime;
+            }
+            if let Some(mtime) = md.mtime {
+                attr.mtime = mtime;
+            }
+        })?;
+        Ok(self.id_map.map_attr(attr))
+    }
+
+    pub fn read_file(
+        &self,
+        path: &str,
+        size: u32,
+        offset: i64,
+        atime_policy: AtimePolicy,
+    ) -> Result<Vec<u8>, i32> {
         let _file_lock = self.lock_file(path)?;
-        self.storage_engine.read_file(path, size, offset)
+        let data = self.storage_engine.read_file(path, size, offset)?;
+        if let Err(e) = self.meta_engine.update_atime(path, atime_policy) {
+            error!(
+                "read file, update atime failed: {:?}, path: {}",
+                status_to_string(e),
+                path
+            );
+        }
+        Ok(data)
     }
 
-    pub fn write_file(&self, path: &str, data: &[u8], offset: i64) -> Result<usize, i32> {
+    // write_file writes data at offset, if the file is at version unless it is 0.
+    pub fn write_file(
+        &self,
+        path: &str,
+        data: &[u8],
+        offset: i64,
+        version: u64,
+    ) -> Result<usize, i32> {
         let _file_lock = self.lock_file(path)?;
+        self.meta_engine.check_version(path, version)?;
         self.storage_engine.write_file(path, data, offset)
     }
 
-    pub fn get_file_attr(&self, path: &str) -> Result<Vec<u8>, i32> {
+    // append_file writes data at the end of path, chosen by the storage
+    // engine one append at a time so that concurrent appends do not overlap.
+    // It returns the offset written at and the size written.
+    pub fn append_file(&self, path: &str, data: &[u8]) -> Result<(u64, usize), i32> {
+        let _file_lock = self.lock_file(path)?;
+        self.storage_engine.append_file(path, data)
+    }
+
+    pub fn list_tree(&self, path: &str, start_after: &str, size: u32) -> Result<Vec<u8>, i32> {
+        self.meta_engine.list_tree(path, start_after, size)
+    }
+
+    // set_attr_tree changes the permissions and the owner of a batch of path
+    // and the paths under it stored on this server, for chmod -R and chown -R.
+    pub fn set_attr_tree(
+        &self,
+        path: &str,
+        md: &SetAttrTreeSendMetaData,
+    ) -> Result<SetAttrTreeRecvMetaData, i32> {
+        let (uid, gid) = self
+            .id_map
+            .to_server((md.uid.unwrap_or(0), md.gid.unwrap_or(0)));
+        let now = std::time::SystemTime::now();
+        let (changed, last) =
+            self.meta_engine
+                .set_attr_tree(path, &md.start_after, md.count, |attr| {
+                    if let Some(mode) = md.mode {
+                        attr.perm = (mode & 0o7777) as u16;
+                    }
+                    if md.uid.is_some() {
+                        attr.uid = uid;
+                    }
+                    if md.gid.is_some() {
+                        attr.gid = gid;
+                    }
+                    attr.ctime = now;
+                })?;
+        Ok(SetAttrTreeRecvMetaData { changed, last })
+    }
+
+    pub fn prefetch_file(&self, path: &str) -> Result<u64, i32> {
+        let _file_lock = self.lock_file(path)?;
+        self.storage_engine.prefetch_file(path)
+    }
+
+    pub fn begin_upload(&self, path: &str) -> Result<u64, i32> {
+        let _file_lock = self.lock_file(path)?;
+        self.storage_engine.begin_upload(path)
+    }
+
+    pub fn upload_part(
+        &self,
+        path: &str,
+        upload_id: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), i32> {
+        let _file_lock = self.lock_file(path)?;
+        self.storage_engine
+            .upload_part(path, upload_id, offset, data)
+    }
+
+    pub fn complete_upload(&self, path: &str, upload_id: u64, size: u64) -> Result<(), i32> {
         let _file_lock = self.lock_file(path)?;
-        self.meta_engine.get_file_attr_raw(path)
+        self.storage_engine.complete_upload(path, upload_id, size)
+    }
+
+    pub async fn get_file_attr(&self, path: &str) -> Result<Vec<u8>, i32> {
+        let mut attr = {
+            let _file_lock = self.lock_file(path)?;
+            self.meta_engine.get_file_attr(path)?
+        };
+        // the size and the links of a sharded directory count its entries in
+        // all the shards.
+        for shard in 1..self.meta_engine.dir_shards(path) {
+            let shard_attr = self.shard_attr(&shard_path(path, shard)).await?;
+            attr.size += shard_attr.size;
+            attr.nlink += shard_attr.nlink.saturating_sub(2);
+        }
+        Ok(self.id_map.map_attr(file_attr_as_bytes(&attr).to_vec()))
+    }
+
+    // batch_get_file_attr returns the records of a BatchGetAttr response. The
+    // paths that this server does not serve, e.g. transferred by a rebalance
+    // or sent through a cache node, are answered with INVALID_CLUSTER_STATUS
+    // for the client to get them one by one.
+    pub async fn batch_get_file_attr(&self, paths: &[String]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(paths.len() * BATCH_ATTR_SIZE);
+        for path in paths {
+            // the file of a hard link of this server may be on another one.
+            let path = self.meta_engine.resolve(path);
+            let attr = match self.get_forward_address(&path) {
+                (None, _) if self.get_address(&path) == self.address => {
+                    self.get_file_attr(&path).await
+                }
+                _ => Err(INVALID_CLUSTER_STATUS),
+            };
+            push_batch_attr(&mut data, attr.as_deref().map_err(|e| *e));
+        }
+        data
     }
 
     pub fn open_file(&self, path: &str, flag: i32, mode: u32) -> Result<(), i32> {
@@ -1081,7 +2934,69 @@ where
         }
     }
 
-    pub fn check_file(&self, path: &str, file_attr: &FileAttr) -> Result<(), i32> {
+    // fsync makes the data of path and its attr durable, the entries of a
+    // directory are in the metadata only.
+    pub fn fsync(&self, path: &str, md: &FsyncSendMetaData) -> Result<(), i32> {
+        if !self.meta_engine.is_dir(path)? {
+            self.storage_engine.sync_file(path, md.datasync)?;
+        }
+        self.meta_engine.sync()
+    }
+
+    // seek returns where the data or the hole at or after the offset of md
+    // starts in path.
+    pub fn seek(&self, path: &str, md: &SeekSendMetaData) -> Result<u64, i32> {
+        let file_attr = self.meta_engine.get_file_attr(path)?;
+        if file_attr.kind == FileType::Directory {
+            return Err(libc::EISDIR);
+        }
+        self.storage_engine
+            .seek_data(path, md.offset, file_attr.size, md.hole)
+    }
+
+    // lock takes a POSIX lock on a file for the client of connection, or
+    // fails with EAGAIN while another owner holds a conflicting one.
+    pub fn lock(&self, path: &str, md: &LockSendMetaData, connection: u32) -> Result<(), i32> {
+        self.meta_engine.get_file_attr(path)?;
+        self.lock_table.lock(path, md.into(), connection)
+    }
+
+    pub fn unlock(&self, path: &str, md: &LockSendMetaData) -> Result<(), i32> {
+        self.lock_table.unlock(path, &md.into())
+    }
+
+    // test_lock returns a lock conflicting with the one of md.
+    pub fn test_lock(&self, path: &str, md: &LockSendMetaData) -> Result<LockRecvMetaData, i32> {
+        self.meta_engine.get_file_attr(path)?;
+        Ok(match self.lock_table.test(path, &md.into()) {
+            Some(lock) => LockRecvMetaData {
+                start: lock.start,
+                end: lock.end,
+                typ: lock.typ,
+                pid: lock.pid,
+            },
+            None => LockRecvMetaData {
+                start: md.start,
+                end: md.end,
+                typ: libc::F_UNLCK,
+                pid: 0,
+            },
+        })
+    }
+
+    // check_file takes the attr of a file transferred to this server, once
+    // its data is durable and matches the checksum of the old owner.
+    pub fn check_file(&self, path: &str, md: &CheckFileSendMetaData) -> Result<(), i32> {
+        let file_attr = bytes_as_file_attr(&md.file_attr);
+        self.storage_engine.sync_file(path, false)?;
+        let checksum = self.file_checksum(path, file_attr.size)?;
+        if checksum != md.checksum {
+            error!(
+                "check file {}: checksum {:x} differs from {:x}",
+                path, checksum, md.checksum
+            );
+            return Err(libc::EIO);
+        }
         self.meta_engine.complete_transfer_file(path, file_attr)
     }
 
@@ -1109,10 +3024,16 @@ where
         }
     }
 
-    pub fn create_volume(&self, name: &str, _size: u64) -> Result<(), i32> {
+    pub fn create_volume(
+        &self,
+        name: &str,
+        _size: u64,
+        atime_policy: AtimePolicy,
+        public: bool,
+    ) -> Result<(), i32> {
         match self.file_locks.insert(name.to_owned(), DashMap::new()) {
             Some(_) => Err(libc::EEXIST),
-            None => self.meta_engine.create_volume(name),
+            None => self.meta_engine.create_volume(name, atime_policy, public),
         }
     }
 
@@ -1125,7 +3046,10 @@ where
             .map(|x| (x.key().to_owned(), x.value().file_attr.kind))
             .collect();
         for kv in files {
-            if kv.0.starts_with(&(name.to_owned() + "/")) {
+            // with the shards of the root directory.
+            if kv.0.starts_with(&(name.to_owned() + "/"))
+                || (is_shard(&kv.0) && shard_dir(&kv.0) == name)
+            {
                 if kv.1 == FileType::RegularFile {
                     self.delete_file_no_parent(&kv.0)?;
                 } else {
@@ -1133,6 +3057,16 @@ where
                 }
             }
         }
+        let links: Vec<String> = self
+            .meta_engine
+            .links
+            .iter()
+            .map(|link| link.key().clone())
+            .filter(|link| link.starts_with(&(name.to_owned() + "/")))
+            .collect();
+        for link in links {
+            self.meta_engine.remove_link(&link)?;
+        }
         Ok(())
     }
 
@@ -1196,3 +3130,458 @@ where
         }
     }
 }
+
+// forward_buffers returns the buffers to receive the response metadata and
+// data of a forwarded request, EINVAL for the requests that are not forwarded
+// and for bad metadata.
+pub fn forward_buffers(
+    operation_type: OperationType,
+    metadata: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), i32> {
+    let buffers = match operation_type {
+        OperationType::Unkown | OperationType::Lookup => return Err(libc::EINVAL),
+        OperationType::CreateFile => (vec![0; 1024], vec![]),
+        OperationType::CreateDir => (vec![0; 1024], vec![]),
+        OperationType::GetFileAttr => (vec![0; 1024], vec![]),
+        OperationType::ReadDir => (vec![], vec![0; 2048]),
+        OperationType::OpenFile => (vec![], vec![]),
+        OperationType::ReadFile => {
+            let unwraped_meta_data =
+                bincode::deserialize::<ReadFileSendMetaData>(metadata).map_err(|_| libc::EINVAL)?;
+            (vec![], vec![0; unwraped_meta_data.size as usize])
+        }
+        OperationType::WriteFile => (vec![0; 8], vec![]),
+        OperationType::AppendFile => (vec![0; 16], vec![]),
+        OperationType::DeleteFile => (vec![], vec![]),
+        OperationType::DeleteDir => (vec![], vec![]),
+        OperationType::DirectoryAddEntry => (vec![], vec![]),
+        OperationType::DirectoryDeleteEntry => (vec![], vec![]),
+        OperationType::TruncateFile => (vec![], vec![]),
+        OperationType::CheckDir => (vec![], vec![]),
+        OperationType::CheckFile => (vec![], vec![]),
+        OperationType::CreateDirNoParent => (vec![0; 1024], vec![]),
+        OperationType::CreateFileNoParent => (vec![0; 1024], vec![]),
+        OperationType::DeleteDirNoParent => (vec![], vec![]),
+        OperationType::DeleteFileNoParent => (vec![], vec![]),
+        OperationType::CreateVolume => (vec![], vec![]),
+        OperationType::InitVolume => (vec![0; 1024], vec![]),
+        OperationType::ListVolumes => (vec![], vec![]),
+        OperationType::DeleteVolume => (vec![], vec![]),
+        OperationType::CleanVolume => (vec![], vec![]),
+        OperationType::Prefetch => (vec![0; 8], vec![]),
+        OperationType::BeginUpload => (vec![0; 8], vec![]),
+        OperationType::UploadPart => (vec![], vec![]),
+        OperationType::CompleteUpload => (vec![], vec![]),
+        OperationType::LinkTempFile => (vec![0; 1024], vec![]),
+        OperationType::CreateSymlink => (vec![0; 1024], vec![]),
+        OperationType::CreateSymlinkNoParent => (vec![0; 1024], vec![]),
+        OperationType::Link => (vec![0; 1024], vec![]),
+        OperationType::LinkNoParent => (vec![0; 1024], vec![]),
+        OperationType::AddLink => (vec![0; 8192], vec![]),
+        OperationType::SetAttr => (vec![0; 1024], vec![]),
+        OperationType::TransferStatus => (vec![0; 65535], vec![]),
+        OperationType::Rename => (vec![], vec![]),
+        OperationType::RenameAt => (vec![], vec![]),
+        OperationType::RenameNoParent => (vec![], vec![]),
+        OperationType::Fsync => (vec![], vec![]),
+        OperationType::Seek => (vec![0; 8], vec![]),
+        OperationType::Lock => (vec![], vec![]),
+        OperationType::Unlock => (vec![], vec![]),
+        OperationType::TestLock => (vec![0; 64], vec![]),
+        OperationType::ShardDir => (vec![], vec![]),
+        OperationType::Health => (vec![0; 65535], vec![]),
+        OperationType::OpenLocal => (vec![], vec![0; 4096]),
+        OperationType::SetAttrTree => (vec![0; 8192], vec![]),
+        OperationType::DedupScan => (vec![0; 1024], vec![]),
+        OperationType::GetStatFs => (vec![0; 64], vec![]),
+        OperationType::Grep => {
+            let unwraped_meta_data =
+                bincode::deserialize::<GrepSendMetaData>(metadata).map_err(|_| libc::EINVAL)?;
+            (vec![0; 1024], vec![0; unwraped_meta_data.size as usize])
+        }
+        OperationType::BatchGetAttr => {
+            let unwraped_meta_data = bincode::deserialize::<BatchGetAttrSendMetaData>(metadata)
+                .map_err(|_| libc::EINVAL)?;
+            (
+                vec![],
+                vec![0; unwraped_meta_data.count as usize * BATCH_ATTR_SIZE],
+            )
+        }
+        OperationType::ListTree => {
+            let unwraped_meta_data =
+                bincode::deserialize::<ListTreeSendMetaData>(metadata).map_err(|_| libc::EINVAL)?;
+            (vec![], vec![0; unwraped_meta_data.size as usize])
+        }
+    };
+    Ok(buffers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use libc::{O_CREAT, O_RDWR, RENAME_NOREPLACE};
+
+    use super::{forward_buffers, DistributedEngine};
+    use crate::{
+        common::{
+            hash_ring::{HashAlgorithm, HashRing},
+            serialization::{
+                ClusterStatus, FileLayout, FileTypeSimple, LinkSendMetaData, OperationType,
+                ReadFileSendMetaData, RenameSendMetaData, FILE_FLAG_LINKED,
+            },
+        },
+        rpc::server::{Handler, RpcServer},
+        server::{
+            storage_engine::{
+                file_engine::FileEngine, meta_engine::MetaEngine, meta_store::MemStore,
+                StorageEngine,
+            },
+            FileRequestHandler,
+        },
+    };
+
+    // engine returns an idle engine at address owning all the files.
+    fn engine(root: &str, address: &str) -> DistributedEngine<FileEngine> {
+        let _ = std::fs::remove_dir_all(root);
+        let meta_engine = Arc::new(MetaEngine::with_store(Box::new(MemStore::new())));
+        let storage_engine = Arc::new(FileEngine::new(root, meta_engine.clone()).unwrap());
+        let engine = DistributedEngine::new(address.to_owned(), storage_engine, meta_engine);
+        engine.hash_ring.write().replace(HashRing::new(
+            HashAlgorithm::Wyhash,
+            vec![(address.to_owned(), 100)],
+        ));
+        engine
+            .cluster_status
+            .store(ClusterStatus::Idle.into(), Ordering::Relaxed);
+        engine
+    }
+
+    #[tokio::test]
+    async fn rename_test() {
+        let root = "/tmp/test_rename";
+        let engine = engine(root, "127.0.0.1:18399");
+
+        let (file, dir) = (
+            FileTypeSimple::RegularFile as u8,
+            FileTypeSimple::Directory as u8,
+        );
+        engine.create_dir_no_parent("v", 0o755, (0, 0)).unwrap();
+        for (name, data) in [("a", b"new".as_slice()), ("b", b"old data")] {
+            let path = format!("v/{}", name);
+            engine
+                .create_file_no_parent(
+                    &path,
+                    O_CREAT | O_RDWR,
+                    0,
+                    0o644,
+                    &FileLayout::default(),
+                    (0, 0),
+                )
+                .unwrap();
+            engine
+                .meta_engine
+                .directory_add_entry("v", name, file)
+                .unwrap();
+            engine.storage_engine.write_file(&path, data, 0).unwrap();
+        }
+        for name in ["d", "e"] {
+            engine
+                .create_dir_no_parent(&format!("v/{}", name), 0o755, (0, 0))
+                .unwrap();
+            engine
+                .meta_engine
+                .directory_add_entry("v", name, dir)
+                .unwrap();
+        }
+        let rename = |name: &str, new_parent: &str, new_name: &str, flags: u32| {
+            let send_meta_data = bincode::serialize(&RenameSendMetaData {
+                name: name.to_owned(),
+                new_parent: new_parent.to_owned(),
+                new_name: new_name.to_owned(),
+                flags,
+            })
+            .unwrap();
+            let name = name.to_owned();
+            let engine = &engine;
+            async move { engine.rename(send_meta_data, "v", &name).await }
+        };
+
+        assert_eq!(
+            rename("a", "v", "b", RENAME_NOREPLACE).await,
+            Err(libc::EEXIST)
+        );
+        assert_eq!(rename("a", "v", "e", 0).await, Err(libc::EISDIR));
+        assert_eq!(rename("d", "v", "b", 0).await, Err(libc::ENOTDIR));
+        assert_eq!(rename("d", "v/d", "x", 0).await, Err(libc::EINVAL));
+        assert_eq!(rename("x", "v", "y", 0).await, Err(libc::ENOENT));
+
+        // b is replaced by a.
+        rename("a", "v", "b", 0).await.unwrap();
+        assert_eq!(engine.meta_engine.find_entry("v", "a"), Ok(None));
+        assert_eq!(engine.meta_engine.find_entry("v", "b"), Ok(Some(file)));
+        assert_eq!(engine.meta_engine.get_file_attr("v/b").unwrap().size, 3);
+        assert_eq!(
+            engine.storage_engine.read_file("v/b", 16, 0).unwrap(),
+            b"new"
+        );
+
+        // an empty directory replaces another one, and a file moves into it.
+        rename("d", "v", "e", 0).await.unwrap();
+        assert_eq!(engine.meta_engine.find_entry("v", "e"), Ok(Some(dir)));
+        rename("b", "v/e", "f", 0).await.unwrap();
+        assert_eq!(engine.meta_engine.find_entry("v/e", "f"), Ok(Some(file)));
+        assert_eq!(
+            engine.storage_engine.read_file("v/e/f", 16, 0).unwrap(),
+            b"new"
+        );
+        // which is not empty anymore, and moves with its entries.
+        rename("e", "v", "g", 0).await.unwrap();
+        assert_eq!(engine.meta_engine.find_entry("v", "e"), Ok(None));
+        assert_eq!(engine.meta_engine.find_entry("v", "g"), Ok(Some(dir)));
+        assert_eq!(engine.meta_engine.find_entry("v/g", "f"), Ok(Some(file)));
+        assert_eq!(engine.meta_engine.is_exist("v/e"), Ok(false));
+        assert_eq!(
+            engine.storage_engine.read_file("v/g/f", 16, 0).unwrap(),
+            b"new"
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hard_link_test() {
+        let root = "/tmp/test_hard_link";
+        let engine = engine(root, "127.0.0.1:18397");
+        let file = FileTypeSimple::RegularFile as u8;
+        engine.create_dir_no_parent("v", 0o755, (0, 0)).unwrap();
+        engine
+            .create_file_no_parent(
+                "v/f",
+                O_CREAT | O_RDWR,
+                0,
+                0o644,
+                &FileLayout::default(),
+                (0, 0),
+            )
+            .unwrap();
+        engine
+            .meta_engine
+            .directory_add_entry("v", "f", file)
+            .unwrap();
+        engine.storage_engine.write_file("v/f", b"hi", 0).unwrap();
+        let link = |target: &str, name: &str| {
+            let send_meta_data = bincode::serialize(&LinkSendMetaData {
+                name: name.to_owned(),
+                target: target.to_owned(),
+                moved: false,
+            })
+            .unwrap();
+            let name = name.to_owned();
+            let engine = &engine;
+            async move { engine.link(send_meta_data, "v", &name).await }
+        };
+
+        // the first link moves the file away, f and g link to it.
+        link("v/f", "g").await.unwrap();
+        link("v/g", "h").await.unwrap();
+        let object = engine.meta_engine.link_target("v/f").unwrap();
+        assert_eq!(engine.meta_engine.link_target("v/h").unwrap(), object);
+        let attr = engine.meta_engine.get_file_attr(&object).unwrap();
+        assert_eq!(
+            (attr.nlink, attr.flags & FILE_FLAG_LINKED),
+            (3, FILE_FLAG_LINKED)
+        );
+        assert_eq!(link("v/f", "g").await.err(), Some(libc::EEXIST));
+
+        // the file outlives its first path, and a link moves by a rename.
+        engine.delete_file(vec![], "v", "f").await.unwrap();
+        let send_meta_data = bincode::serialize(&RenameSendMetaData {
+            name: "g".to_owned(),
+            new_parent: "v".to_owned(),
+            new_name: "k".to_owned(),
+            flags: 0,
+        })
+        .unwrap();
+        engine.rename(send_meta_data, "v", "g").await.unwrap();
+        assert_eq!(engine.meta_engine.find_entry("v", "k"), Ok(Some(file)));
+        assert_eq!(engine.meta_engine.link_target("v/k").unwrap(), object);
+        assert_eq!(engine.meta_engine.get_file_attr(&object).unwrap().nlink, 2);
+        assert_eq!(
+            engine.storage_engine.read_file(&object, 16, 0).unwrap(),
+            b"hi"
+        );
+
+        // and is deleted with its last link.
+        engine.delete_file(vec![], "v", "k").await.unwrap();
+        engine.delete_file(vec![], "v", "h").await.unwrap();
+        assert!(engine.meta_engine.links.is_empty());
+        assert_eq!(engine.meta_engine.is_exist(&object), Ok(false));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn append_file_test() {
+        let root = "/tmp/test_append_file";
+        let engine = engine(root, "127.0.0.1:18398");
+        engine
+            .create_file_no_parent(
+                "f",
+                O_CREAT | O_RDWR,
+                0,
+                0o644,
+                &FileLayout::default(),
+                (0, 0),
+            )
+            .unwrap();
+        engine.storage_engine.write_file("f", b"ab", 0).unwrap();
+
+        // the concurrent appends get disjoint ranges after the data.
+        let mut appended: Vec<(u64, u8)> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (b'c'..b'k')
+                .map(|byte| {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        let (offset, size) = engine.append_file("f", &[byte; 100]).unwrap();
+                        assert_eq!(size, 100);
+                        (offset, byte)
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        appended.sort();
+        let data = engine.storage_engine.read_file("f", 4096, 0).unwrap();
+        assert_eq!(data.len(), 802);
+        assert_eq!(&data[..2], b"ab");
+        for (i, (offset, byte)) in appended.into_iter().enumerate() {
+            assert_eq!(offset, 2 + i as u64 * 100);
+            assert!(data[offset as usize..][..100].iter().all(|b| *b == byte));
+        }
+        assert_eq!(engine.append_file("g", b"x"), Err(libc::ENOENT));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn forward_buffers_test() {
+        let metadata = bincode::serialize(&ReadFileSendMetaData {
+            offset: 0,
+            size: 100,
+        })
+        .unwrap();
+        let (recv_meta_data, recv_data) =
+            forward_buffers(OperationType::ReadFile, &metadata).unwrap();
+        assert_eq!((recv_meta_data.len(), recv_data.len()), (0, 100));
+        assert_eq!(
+            forward_buffers(OperationType::ReadFile, &[1]),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            forward_buffers(OperationType::Lookup, &[]),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(
+            forward_buffers(OperationType::Unkown, &[]),
+            Err(libc::EINVAL)
+        );
+    }
+
+    // ManagerHandler accepts the handoffs of the transfers.
+    struct ManagerHandler;
+
+    #[async_trait]
+    impl Handler for ManagerHandler {
+        async fn dispatch(
+            &self,
+            _id: u32,
+            _operation_type: u32,
+            _flags: u32,
+            _path: Vec<u8>,
+            _data: Vec<u8>,
+            _metadata: Vec<u8>,
+        ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+            Ok((0, 0, 0, 0, vec![], vec![]))
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_files_test() {
+        let (manager, source, destination) =
+            ("127.0.0.1:18393", "127.0.0.1:18394", "127.0.0.1:18395");
+        let (source_root, destination_root) = (
+            "/tmp/test_transfer_source",
+            "/tmp/test_transfer_destination",
+        );
+        let destination_engine = Arc::new(engine(destination_root, destination));
+        let handler = Arc::new(FileRequestHandler::new(destination_engine.clone()));
+        tokio::spawn(async move { RpcServer::new(handler, destination).run().await });
+        tokio::spawn(async move {
+            RpcServer::new(Arc::new(ManagerHandler), manager)
+                .run()
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut engine = engine(source_root, source);
+        engine.transfer_concurrency = 3;
+        engine.transfer_destination_concurrency = 2;
+        *engine.manager_address.lock().await = manager.to_owned();
+        engine.new_hash_ring.write().replace(HashRing::new(
+            HashAlgorithm::Wyhash,
+            vec![(destination.to_owned(), 100)],
+        ));
+        engine.client.add_connection(manager).await.unwrap();
+        engine.client.add_connection(destination).await.unwrap();
+        // the entries of the root of a volume are not transferred.
+        engine.create_dir_no_parent("v", 0o755, (0, 0)).unwrap();
+        engine.create_dir_no_parent("v/d", 0o755, (0, 0)).unwrap();
+        for i in 0..8 {
+            let (name, path) = (format!("f{}", i), format!("v/d/f{}", i));
+            engine
+                .create_file_no_parent(
+                    &path,
+                    O_CREAT | O_RDWR,
+                    0,
+                    0o644,
+                    &FileLayout::default(),
+                    (0, 0),
+                )
+                .unwrap();
+            engine
+                .meta_engine
+                .directory_add_entry("v/d", &name, FileTypeSimple::RegularFile as u8)
+                .unwrap();
+            engine
+                .storage_engine
+                .write_file(&path, name.as_bytes(), 0)
+                .unwrap();
+        }
+        let engine = Arc::new(engine);
+        let file_map = engine.make_up_file_map();
+        assert_eq!(file_map.len(), 10);
+        engine.transfer_files(file_map).await.unwrap();
+
+        let report = engine.transfer_status();
+        assert_eq!((report.done, report.pending, report.failed), (10, 0, 0));
+        for i in 0..8 {
+            let path = format!("v/d/f{}", i);
+            assert_eq!(
+                destination_engine
+                    .storage_engine
+                    .read_file(&path, 16, 0)
+                    .unwrap(),
+                format!("f{}", i).as_bytes()
+            );
+            assert_eq!(engine.meta_engine.is_exist(&path), Ok(false));
+        }
+        assert_eq!(
+            destination_engine.meta_engine.find_entry("v/d", "f7"),
+            Ok(Some(FileTypeSimple::RegularFile as u8))
+        );
+        std::fs::remove_dir_all(source_root).unwrap();
+        std::fs::remove_dir_all(destination_root).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Grep of files on their servers, only built with the experimental
+ * grep-pushdown feature. A log search reads the matching lines, or the
+ * offsets of a byte pattern, instead of the whole file.
+ * A request scans at most GREP_SCAN_LIMIT bytes, or until its response is
+ * full, and returns where to go on from. The file is read in chunks of
+ * GREP_CHUNK_SIZE, a longer line is cut at the end of its chunk.
+ *
+ * The tests run with:
+ *     cargo test --features disk-db,grep-pushdown grep
+ */
+use crate::common::serialization::{GrepMatch, GrepRecvMetaData, GrepSendMetaData};
+
+use super::{distributed_engine::DistributedEngine, storage_engine::StorageEngine};
+
+pub const GREP_CHUNK_SIZE: usize = 1 << 20;
+pub const GREP_SCAN_LIMIT: u64 = 64 << 20;
+
+// the bytes of a match in the response besides its data, and of the
+// length of the matches.
+const MATCH_OVERHEAD: usize = 16;
+const MATCHES_OVERHEAD: usize = 8;
+
+// scan looks for the pattern in data, read at offset of the file, and
+// returns where to go on from, and if the response is full. A line, or an
+// occurrence of the pattern, is only matched once whole in data, unless it
+// is the end of the file.
+pub fn scan(
+    data: &[u8],
+    offset: u64,
+    eof: bool,
+    md: &GrepSendMetaData,
+    matches: &mut Vec<GrepMatch>,
+    budget: &mut usize,
+) -> (u64, bool) {
+    let pattern = &md.pattern[..];
+    let mut start = 0;
+    while start < data.len() {
+        let (end, found) = if md.lines {
+            let end = match data[start..].iter().position(|&b| b == b'\n') {
+                Some(i) => start + i + 1,
+                // a line longer than a chunk is cut.
+                None if eof || start == 0 => data.len(),
+                None => break,
+            };
+            let line = &data[start..end];
+            (end, line.windows(pattern.len()).any(|w| w == pattern))
+        } else {
+            if !eof && data.len() - start < pattern.len() {
+                break;
+            }
+            (start + 1, data[start..].starts_with(pattern))
+        };
+        if found {
+            let data = match md.lines {
+                true => data[start..end].to_vec(),
+                false => Vec::new(),
+            };
+            // a response has one match at least, so the scan goes on.
+            if MATCH_OVERHEAD + data.len() > *budget && !matches.is_empty() {
+                return (offset + start as u64, true);
+            }
+            *budget = budget.saturating_sub(MATCH_OVERHEAD + data.len());
+            matches.push(GrepMatch {
+                offset: offset + start as u64,
+                data,
+            });
+        }
+        start = end;
+    }
+    (offset + start as u64, false)
+}
+
+impl<S: StorageEngine> DistributedEngine<S> {
+    pub fn grep_file(
+        &self,
+        path: &str,
+        md: &GrepSendMetaData,
+    ) -> Result<(GrepRecvMetaData, Vec<GrepMatch>), i32> {
+        if md.pattern.is_empty() {
+            return Err(libc::EINVAL);
+        }
+        let _file_lock = self.lock_file(path)?;
+        let mut matches = Vec::new();
+        let mut budget = (md.size as usize).saturating_sub(MATCHES_OVERHEAD);
+        let mut offset = md.offset;
+        loop {
+            let data =
+                self.storage_engine
+                    .read_file(path, GREP_CHUNK_SIZE as u32, offset as i64)?;
+            let eof = data.len() < GREP_CHUNK_SIZE;
+            let (next_offset, full) = scan(&data, offset, eof, md, &mut matches, &mut budget);
+            let done = eof && next_offset == offset + data.len() as u64;
+            offset = next_offset;
+            if done || full || offset - md.offset >= GREP_SCAN_LIMIT {
+                return Ok((
+                    GrepRecvMetaData {
+                        next_offset: offset,
+                        done,
+                    },
+                    matches,
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::scan;
+    use crate::common::serialization::GrepSendMetaData;
+
+    #[test]
+    fn grep_test() {
+        let md = GrepSendMetaData {
+            pattern: b"error".to_vec(),
+            lines: true,
+            offset: 0,
+            size: 1024,
+        };
+        let data = b"ok\nerror 1\nok\nerror 2";
+        let (mut matches, mut budget) = (Vec::new(), 1024);
+        // the last line may go on in the next chunk.
+        assert_eq!(
+            scan(data, 100, false, &md, &mut matches, &mut budget),
+            (114, false)
+        );
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            (matches[0].offset, &matches[0].data[..]),
+            (103, &b"error 1\n"[..])
+        );
+        assert_eq!(
+            scan(&data[14..], 114, true, &md, &mut matches, &mut budget),
+            (121, false)
+        );
+        assert_eq!(matches[1].data, b"error 2");
+
+        // a full response stops before the next match.
+        let (mut matches, mut budget) = (Vec::new(), 30);
+        assert_eq!(
+            scan(data, 0, true, &md, &mut matches, &mut budget),
+            (14, true)
+        );
+        assert_eq!(matches.len(), 1);
+
+        let md = GrepSendMetaData {
+            lines: false,
+            pattern: b"aa".to_vec(),
+            ..md
+        };
+        let (mut matches, mut budget) = (Vec::new(), 1024);
+        assert_eq!(
+            scan(b"aaxaa", 0, false, &md, &mut matches, &mut budget),
+            (4, false)
+        );
+        assert_eq!(
+            scan(b"aa", 4, true, &md, &mut matches, &mut budget),
+            (6, false)
+        );
+        let offsets: Vec<u64> = matches.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![0, 3, 4]);
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The identity of a server, a random uuid kept next to its databases in
+ * `<database_path>_server_id`. It is sent with the heartbeats, so the manager
+ * tells a server restarted with its data from another one started at the same
+ * address: the former rejoins without a rebalance, the latter is refused
+ * until the old one is removed.
+ */
+use std::{fs, io};
+
+// HEARTBEAT_INTERVAL must stay well below the grace period of the manager.
+pub const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+fn new_server_id() -> String {
+    // a version 4 uuid.
+    let mut id = rand::random::<u128>();
+    id = (id & !(0xf << 76)) | (0x4 << 76);
+    id = (id & !(0x3 << 62)) | (0x2 << 62);
+    let hex = format!("{:032x}", id);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// load_server_id returns the identity of the server with these databases,
+// made on its first start.
+pub fn load_server_id(database_path: &str) -> io::Result<String> {
+    let path = format!("{}_server_id", database_path);
+    match fs::read_to_string(&path) {
+        Ok(id) if !id.trim().is_empty() => Ok(id.trim().to_owned()),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is empty", path),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let id = new_server_id();
+            // written whole or not at all.
+            let tmp_path = format!("{}.tmp", path);
+            fs::write(&tmp_path, format!("{}\n", id))?;
+            fs::rename(&tmp_path, &path)?;
+            Ok(id)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load_server_id;
+
+    #[test]
+    fn server_id_test() {
+        let db_path = "/tmp/test_server_id_db";
+        let _ = std::fs::remove_file(format!("{}_server_id", db_path));
+        let id = load_server_id(db_path).unwrap();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert_eq!(load_server_id(db_path).unwrap(), id);
+        std::fs::remove_file(format!("{}_server_id", db_path)).unwrap();
+        assert_ne!(load_server_id(db_path).unwrap(), id);
+        std::fs::remove_file(format!("{}_server_id", db_path)).unwrap();
+    }
+}
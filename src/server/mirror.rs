@@ -0,0 +1,290 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Mirroring of the reads of a server to a candidate server, off by default,
+ * to validate a new version or storage engine against live traffic. A
+ * sample of the reads and attr lookups of the clients is sent again to the
+ * candidate in the background, after the client got its response, and the
+ * responses are compared: the status and the data of a read, the status,
+ * kind, size, permissions, owner and links of an attr, not its times. A
+ * mismatch is logged with the path, the responses go on as if there were no
+ * mirror. The candidate holds a copy of the files of this server, e.g. a
+ * server started on a copy of its disks with another engine; a file written
+ * between the two reads is a false mismatch.
+ */
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+
+use super::rate_limit::FORWARDED_REQUEST;
+use crate::{
+    common::{
+        errors::status_to_string,
+        serialization::{bytes_as_file_attr, OperationType, ReadFileSendMetaData},
+    },
+    rpc::{
+        client::RpcClient,
+        local::{LocalReadHalf, LocalStreamCreator, LocalWriteHalf},
+    },
+};
+
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+// the mismatches are counted in the log every this many mirrored reads.
+const MIRROR_REPORT_INTERVAL: u64 = 10000;
+
+pub struct Mirror {
+    address: String,
+    // percent of the reads mirrored.
+    percent: u32,
+    client: Arc<RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
+    requests: AtomicU64,
+    mismatches: AtomicU64,
+    // the reads the candidate did not answer.
+    errors: AtomicU64,
+}
+
+impl Mirror {
+    pub fn new(
+        address: String,
+        percent: u32,
+        client: Arc<RpcClient<LocalReadHalf, LocalWriteHalf, LocalStreamCreator>>,
+    ) -> Self {
+        Self {
+            address,
+            percent: percent.min(100),
+            client,
+            requests: AtomicU64::new(0),
+            mismatches: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    // sampled tells if a read of a client is mirrored, the requests of the
+    // other servers and of the mirrors are not.
+    pub fn sampled(&self, r#type: &OperationType, flags: u32) -> bool {
+        matches!(r#type, OperationType::ReadFile | OperationType::GetFileAttr)
+            && flags & FORWARDED_REQUEST == 0
+            && rand::random::<u32>() % 100 < self.percent
+    }
+
+    // compare sends the read again to the candidate and compares its
+    // response with ours, it returns whether they match.
+    pub async fn compare(
+        &self,
+        r#type: OperationType,
+        path: &str,
+        metadata: &[u8],
+        status: i32,
+        response: &[u8],
+    ) -> bool {
+        let (read, name) = (
+            matches!(r#type, OperationType::ReadFile),
+            format!("{:?}", r#type),
+        );
+        let (mut recv_meta_data, mut recv_data) = match read {
+            true => {
+                let md: ReadFileSendMetaData = match bincode::deserialize(metadata) {
+                    Ok(md) => md,
+                    Err(_) => return true,
+                };
+                (vec![], vec![0u8; md.size as usize])
+            }
+            false => (vec![0u8; response.len().max(1024)], vec![]),
+        };
+        let (mut mirror_status, mut rsp_flags, mut recv_meta_data_length, mut recv_data_length) =
+            (0, 0, 0, 0);
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Err(e) = self
+            .client
+            .call_remote(
+                &self.address,
+                r#type.into(),
+                FORWARDED_REQUEST,
+                path,
+                metadata,
+                &[],
+                &mut mirror_status,
+                &mut rsp_flags,
+                &mut recv_meta_data_length,
+                &mut recv_data_length,
+                &mut recv_meta_data,
+                &mut recv_data,
+                MIRROR_TIMEOUT,
+            )
+            .await
+        {
+            debug!("mirror {} to {} failed: {}", path, self.address, e);
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        let mirror_response = match read {
+            true => &recv_data[..recv_data_length],
+            false => &recv_meta_data[..recv_meta_data_length],
+        };
+        let matched = same_response(read, status, response, mirror_status, mirror_response);
+        if !matched {
+            let mismatches = self.mismatches.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "mirror mismatch {} {}: {} with {} bytes, {} answered {} with {} bytes, {} of {}",
+                name,
+                path,
+                status_to_string(status),
+                response.len(),
+                self.address,
+                status_to_string(mirror_status),
+                mirror_response.len(),
+                mismatches,
+                requests
+            );
+        }
+        if requests % MIRROR_REPORT_INTERVAL == 0 {
+            info!(
+                "mirror {}: {} reads, {} mismatches, {} errors",
+                self.address,
+                requests,
+                self.mismatches.load(Ordering::Relaxed),
+                self.errors.load(Ordering::Relaxed)
+            );
+        }
+        matched
+    }
+
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches.load(Ordering::Relaxed)
+    }
+}
+
+// same_response compares the responses of a read or of an attr lookup, the
+// times of the attrs and their inodes differ from a server to another.
+fn same_response(
+    read: bool,
+    status: i32,
+    response: &[u8],
+    mirror_status: i32,
+    mirror_response: &[u8],
+) -> bool {
+    if status != mirror_status {
+        return false;
+    }
+    match read {
+        false if status == 0 => {
+            if response.len() != mirror_response.len()
+                || response.len() < std::mem::size_of::<fuser::FileAttr>()
+            {
+                return response == mirror_response;
+            }
+            let (attr, mirror_attr) = (
+                bytes_as_file_attr(response),
+                bytes_as_file_attr(mirror_response),
+            );
+            attr.kind == mirror_attr.kind
+                && attr.size == mirror_attr.size
+                && attr.perm == mirror_attr.perm
+                && attr.uid == mirror_attr.uid
+                && attr.gid == mirror_attr.gid
+                && attr.nlink == mirror_attr.nlink
+        }
+        _ => response == mirror_response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use async_trait::async_trait;
+
+    use super::Mirror;
+    use crate::{
+        common::{
+            serialization::{file_attr_as_bytes, OperationType, ReadFileSendMetaData},
+            util::empty_file,
+        },
+        rpc::{
+            client::RpcClient,
+            server::{Handler, RpcServer},
+        },
+    };
+
+    // CandidateHandler answers the reads with hello and the attrs with a
+    // file of 5 bytes.
+    struct CandidateHandler;
+
+    #[async_trait]
+    impl Handler for CandidateHandler {
+        async fn dispatch(
+            &self,
+            _id: u32,
+            operation_type: u32,
+            _flags: u32,
+            _path: Vec<u8>,
+            _data: Vec<u8>,
+            _metadata: Vec<u8>,
+        ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+            match OperationType::try_from(operation_type) {
+                Ok(OperationType::ReadFile) => Ok((0, 0, 0, 5, vec![], b"hello".to_vec())),
+                _ => {
+                    let mut attr = empty_file();
+                    attr.size = 5;
+                    attr.ino = 7;
+                    let attr = file_attr_as_bytes(&attr).to_vec();
+                    Ok((0, 0, attr.len(), 0, attr, vec![]))
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mirror_test() {
+        let address = "127.0.0.1:18398";
+        tokio::spawn(async move {
+            RpcServer::new(Arc::new(CandidateHandler), address)
+                .run()
+                .await
+                .unwrap();
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = Arc::new(RpcClient::new());
+        client.add_connection(address).await.unwrap();
+        let mirror = Mirror::new(address.to_owned(), 100, client);
+
+        let metadata = bincode::serialize(&ReadFileSendMetaData {
+            offset: 0,
+            size: 16,
+        })
+        .unwrap();
+        let compare = |response: &'static [u8]| {
+            mirror.compare(OperationType::ReadFile, "v/a", &metadata, 0, response)
+        };
+        assert!(compare(b"hello").await);
+        assert!(!compare(b"world").await);
+
+        // the times and the inodes of the attrs are not compared.
+        let mut attr = empty_file();
+        attr.size = 5;
+        attr.ino = 9;
+        attr.mtime = std::time::UNIX_EPOCH;
+        let response = file_attr_as_bytes(&attr).to_vec();
+        assert!(
+            mirror
+                .compare(OperationType::GetFileAttr, "v/a", &[], 0, &response)
+                .await
+        );
+        attr.size = 6;
+        let response = file_attr_as_bytes(&attr).to_vec();
+        assert!(
+            !mirror
+                .compare(OperationType::GetFileAttr, "v/a", &[], 0, &response)
+                .await
+        );
+        assert_eq!(mirror.mismatches(), 2);
+    }
+}
@@ -2,36 +2,64 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod cache_node;
+pub mod dedup;
 pub mod distributed_engine;
+#[cfg(feature = "grep-pushdown")]
+pub mod grep;
+pub mod identity;
+pub mod mirror;
+pub mod posix_lock;
+pub mod rate_limit;
 pub mod storage_engine;
 mod transfer_manager;
+pub mod volume_stats;
 use std::{
+    borrow::Cow,
     sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 
 use async_trait::async_trait;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use storage_engine::StorageEngine;
-use tokio::time::sleep;
+use tokio::{sync::Semaphore, task::JoinSet, time::sleep};
 
+#[cfg(feature = "grep-pushdown")]
+use crate::common::serialization::GrepSendMetaData;
 use crate::{
     common::{
-        errors::status_to_string,
+        dns::resolve_addresses,
+        errors::{status_to_string, SERVER_ID_MISMATCH, STALE_EPOCH},
         hash_ring::HashRing,
         serialization::{
-            bytes_as_file_attr, ClusterStatus, CreateDirSendMetaData, CreateFileSendMetaData,
-            CreateVolumeSendMetaData, DeleteDirSendMetaData, DeleteFileSendMetaData,
-            DirectoryEntrySendMetaData, OpenFileSendMetaData, OperationType, ReadDirSendMetaData,
-            ServerStatus, TruncateFileSendMetaData,
+            bytes_as_file_attr, AddLinkSendMetaData, AppendFileRecvMetaData,
+            AppendFileSendMetaData, CheckFileSendMetaData, ClusterStatus,
+            CompleteUploadSendMetaData, CreateDirSendMetaData, CreateFileSendMetaData,
+            CreateSymlinkSendMetaData, CreateVolumeSendMetaData, DedupScanSendMetaData,
+            DeleteDirSendMetaData, DeleteFileSendMetaData, DirectoryEntrySendMetaData, FileLayout,
+            FsyncSendMetaData, LinkSendMetaData, LinkTempFileSendMetaData, ListTreeSendMetaData,
+            LockSendMetaData, OpenFileSendMetaData, OperationType, ReadDirSendMetaData,
+            RenameAtSendMetaData, RenameNoParentSendMetaData, RenameSendMetaData, SeekSendMetaData,
+            ServerStatus, SetAttrSendMetaData, SetAttrTreeSendMetaData, ShardDirSendMetaData,
+            TruncateFileSendMetaData, UploadPartSendMetaData,
         },
         serialization::{ReadFileSendMetaData, WriteFileSendMetaData},
     },
-    rpc::server::{Handler, RpcServer},
+    rpc::{
+        buffer::BUFFER_POOL,
+        checksum::SERVER_CORRUPT_FRAMES,
+        compression::SERVER_COMPRESSION_STATS,
+        local::serve_local,
+        protocol::STREAM_FRAME_SIZE,
+        server::{Handler, RpcServer},
+    },
     server::storage_engine::meta_engine::MetaEngine,
 };
-use distributed_engine::DistributedEngine;
-use storage_engine::file_engine::FileEngine;
+use distributed_engine::{DistributedEngine, IdMap};
+use mirror::Mirror;
+use rate_limit::{is_metadata, RateLimiter, FORWARDED_REQUEST};
+use storage_engine::registry::{parse_storage, STORAGE_ENGINES};
 
 #[derive(Debug, PartialEq, Eq, thiserror::Error)]
 pub enum ServerError {
@@ -39,7 +67,9 @@ pub enum ServerError {
     ParseHeaderError,
 }
 
-pub async fn sync_cluster_status(engine: Arc<DistributedEngine<FileEngine>>) {
+pub async fn sync_cluster_status<S: StorageEngine + Send + Sync + 'static>(
+    engine: Arc<DistributedEngine<S>>,
+) {
     loop {
         {
             let result = engine.get_cluster_status().await;
@@ -60,7 +90,23 @@ pub async fn sync_cluster_status(engine: Arc<DistributedEngine<FileEngine>>) {
     }
 }
 
-pub async fn watch_status(engine: Arc<DistributedEngine<FileEngine>>) {
+pub async fn send_heartbeats<S: StorageEngine + Send + Sync + 'static>(
+    engine: Arc<DistributedEngine<S>>,
+) {
+    loop {
+        sleep(identity::HEARTBEAT_INTERVAL).await;
+        if engine.closed.load(Ordering::Relaxed) {
+            break;
+        }
+        if let Err(e) = engine.heartbeat().await {
+            error!("heartbeat failed, error = {}", status_to_string(e));
+        }
+    }
+}
+
+pub async fn watch_status<S: StorageEngine + Send + Sync + 'static>(
+    engine: Arc<DistributedEngine<S>>,
+) {
     loop {
         if engine.closed.load(Ordering::Relaxed) {
             error!("watch status: server closed");
@@ -74,14 +120,14 @@ pub async fn watch_status(engine: Arc<DistributedEngine<FileEngine>>) {
         {
             ClusterStatus::SyncNewHashRing => {
                 info!("watch status: start to sync new hash ring");
-                let all_servers_address = match engine.get_new_hash_ring_info().await {
+                let info = match engine.get_new_hash_ring_info().await {
                     Ok(value) => value,
                     Err(e) => {
                         panic!("Get Hash Ring Info Failed. Error = {}", e);
                     }
                 };
                 info!("watch status: get new hash ring info");
-                for value in all_servers_address.iter() {
+                for value in info.servers.iter() {
                     if engine.address == value.0
                         || engine.hash_ring.read().as_ref().unwrap().contains(&value.0)
                     {
@@ -92,10 +138,7 @@ pub async fn watch_status(engine: Arc<DistributedEngine<FileEngine>>) {
                         panic!("watch status: add connection failed, error = {}", e);
                     }
                 }
-                engine
-                    .new_hash_ring
-                    .write()
-                    .replace(HashRing::new(all_servers_address));
+                engine.new_hash_ring.write().replace(HashRing::from(info));
                 info!("watch status: sync new hash ring finished");
                 match engine.update_server_status(ServerStatus::PreTransfer).await {
                     Ok(_) => {}
@@ -224,13 +267,8 @@ pub async fn watch_status(engine: Arc<DistributedEngine<FileEngine>>) {
                 {
                     sleep(Duration::from_secs(1)).await;
                 }
-                assert!(
-                    <i32 as TryInto<ClusterStatus>>::try_into(
-                        engine.cluster_status.load(Ordering::Relaxed)
-                    )
-                    .unwrap()
-                        == ClusterStatus::Idle
-                );
+                // the cluster may be idle, or already starting the next
+                // queued membership change.
 
                 info!("watch status: transferring data finished");
             }
@@ -250,15 +288,143 @@ pub async fn watch_status(engine: Arc<DistributedEngine<FileEngine>>) {
     }
 }
 
-pub async fn run(
-    database_path: String,
-    storage_path: String,
-    server_address: String,
-    manager_address: String,
-    #[cfg(feature = "disk-db")] cache_capacity: usize,
-    #[cfg(feature = "disk-db")] write_buffer_size: usize,
+// connections opened at once when a server starts.
+const CONNECT_CONCURRENCY: usize = 16;
+// a server of a larger ring connects to the others on the first request to them.
+const LAZY_DIAL_SERVERS: usize = 128;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// connect_servers connects to the other servers of the ring in parallel. The
+// servers unreachable are connected to on the first request to them, and
+// retried in the background until then.
+async fn connect_servers<S: StorageEngine + Send + Sync + 'static>(
+    engine: &Arc<DistributedEngine<S>>,
+    servers: Vec<String>,
+) {
+    for server in &servers {
+        engine.client.add_lazy_connection(server);
+    }
+    if servers.len() > LAZY_DIAL_SERVERS {
+        info!("Init: {} servers, connect on first use", servers.len());
+        return;
+    }
+    let semaphore = Arc::new(Semaphore::new(CONNECT_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for server in servers {
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let engine = engine.clone();
+        tasks.spawn(async move {
+            let result = engine.client.dial_lazy_connection(&server).await;
+            drop(permit);
+            (server, result)
+        });
+    }
+    while let Some(task) = tasks.join_next().await {
+        if let Ok((server, Err(e))) = task {
+            warn!(
+                "Init: Add Connection Failed, retry in background. Error = {}",
+                e
+            );
+            tokio::spawn(retry_connection(engine.clone(), server));
+        }
+    }
+}
+
+async fn retry_connection<S: StorageEngine + Send + Sync + 'static>(
+    engine: Arc<DistributedEngine<S>>,
+    server: String,
+) {
+    while !engine.closed.load(Ordering::Relaxed) {
+        sleep(CONNECT_RETRY_INTERVAL).await;
+        if engine.client.dial_lazy_connection(&server).await.is_ok() {
+            info!("connected to {} in background", server);
+            return;
+        }
+    }
+}
+
+// report_buffer_pool_stats logs the usage of the rpc buffer pool periodically.
+pub async fn report_buffer_pool_stats() {
+    loop {
+        sleep(Duration::from_secs(60)).await;
+        info!("rpc buffer pool: {}", BUFFER_POOL.stats());
+        info!("rpc compression: {}", *SERVER_COMPRESSION_STATS);
+        info!(
+            "rpc corrupt frames: {}",
+            SERVER_CORRUPT_FRAMES.load(Ordering::Relaxed)
+        );
+    }
+}
+
+// ServerOptions are the options of a server but its storage engine.
+pub struct ServerOptions {
+    pub database_path: String,
+    pub server_address: String,
+    pub manager_address: String,
+    #[cfg(feature = "disk-db")]
+    pub cache_capacity: usize,
+    #[cfg(feature = "disk-db")]
+    pub write_buffer_size: usize,
+    pub auto_migrate: bool,
+    pub id_map: IdMap,
+    pub compress_rpc: bool,
+    pub checksum_rpc: bool,
+    // metadata requests per second and at once of a connection, see
+    // rate_limit.rs, 0 for no limit.
+    pub metadata_rate: u32,
+    pub metadata_burst: u32,
+    // the candidate server a mirror_percent of the reads is mirrored to,
+    // see mirror.rs.
+    pub mirror_address: Option<String>,
+    pub mirror_percent: u32,
+    // files transferred at once in a rebalance, and to the same server.
+    pub transfer_concurrency: usize,
+    pub transfer_destination_concurrency: usize,
+}
+
+// run runs a server with the storage engine registered as the name of
+// storage, `<name>:<params>`, see storage_engine/registry.rs.
+pub async fn run(storage: &str, options: ServerOptions) -> anyhow::Result<()> {
+    let (name, params) = parse_storage(storage);
+    let Some(start) = STORAGE_ENGINES.get(name) else {
+        return Err(anyhow::anyhow!(
+            "unknown storage engine {}, the engines are: {}",
+            name,
+            STORAGE_ENGINES.names().join(", ")
+        ));
+    };
+    info!("Init: Storage Engine: {}, params: {}", name, params);
+    start(params.to_owned(), options).await
+}
+
+pub async fn run_with<S: StorageEngine + Send + Sync + 'static>(
+    storage_params: String,
+    options: ServerOptions,
 ) -> anyhow::Result<()> {
     debug!("run server");
+    let ServerOptions {
+        database_path,
+        server_address,
+        manager_address,
+        #[cfg(feature = "disk-db")]
+        cache_capacity,
+        #[cfg(feature = "disk-db")]
+        write_buffer_size,
+        auto_migrate,
+        id_map,
+        compress_rpc,
+        checksum_rpc,
+        metadata_rate,
+        metadata_burst,
+        mirror_address,
+        mirror_percent,
+        transfer_concurrency,
+        transfer_destination_concurrency,
+    } = options;
+    #[cfg(feature = "fault-injection")]
+    if let Err(e) = storage_engine::fault::load_from_env() {
+        panic!("load faults failed: {}", status_to_string(e));
+    }
     let meta_engine = Arc::new(MetaEngine::new(
         &database_path,
         #[cfg(feature = "disk-db")]
@@ -266,16 +432,56 @@ pub async fn run(
         #[cfg(feature = "disk-db")]
         write_buffer_size,
     ));
-    let storage_engine = Arc::new(FileEngine::new(&storage_path, Arc::clone(&meta_engine)));
-    storage_engine.init();
+    if let Err(e) = storage_engine::migration::check(&meta_engine, auto_migrate) {
+        panic!("{}", e);
+    }
+    let storage_engine = S::new(&storage_params, Arc::clone(&meta_engine))
+        .and_then(|engine| engine.init().map(|_| Arc::new(engine)))
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "init storage engine {} failed: {}",
+                storage_params,
+                status_to_string(e)
+            )
+        })?;
     info!("Init: Storage Engine Init Finished");
 
-    let engine = Arc::new(DistributedEngine::new(
-        server_address.clone(),
-        storage_engine,
-        meta_engine,
-    ));
+    let mut engine = DistributedEngine::new(server_address.clone(), storage_engine, meta_engine);
+    engine.id_map = id_map;
+    engine.metadata_limits = RateLimiter::new(metadata_rate, metadata_burst);
+    engine.transfer_concurrency = transfer_concurrency;
+    engine.transfer_destination_concurrency = transfer_destination_concurrency;
+    if let Some(mirror_address) = mirror_address {
+        match engine.client.add_connection(&mirror_address).await {
+            Ok(()) => {
+                info!(
+                    "Init: Mirror {}% Of The Reads To: {}",
+                    mirror_percent, mirror_address
+                );
+                engine.mirror = Some(Arc::new(Mirror::new(
+                    mirror_address,
+                    mirror_percent,
+                    engine.client.clone(),
+                )));
+            }
+            Err(e) => error!("connect to mirror {} failed: {}", mirror_address, e),
+        }
+    }
+    engine.client.set_compression(compress_rpc);
+    engine.client.set_checksum(checksum_rpc);
+    engine.server_id = match identity::load_server_id(&database_path) {
+        Ok(id) => id,
+        Err(e) => panic!("load server id failed: {}", e),
+    };
+    info!("Init: Server Id: {}", engine.server_id);
+    let engine = Arc::new(engine);
 
+    // a srv record stands for the manager, see common/dns.rs.
+    let manager_address = resolve_addresses(vec![manager_address.clone()])
+        .await
+        .into_iter()
+        .next()
+        .unwrap_or(manager_address);
     info!("Init: Connect To Manager: {}", manager_address);
     if let Err(e) = engine.client.add_connection(&manager_address).await {
         panic!("Connect To Manager Failed, Error = {}", e);
@@ -283,6 +489,17 @@ pub async fn run(
     *engine.manager_address.lock().await = manager_address;
 
     tokio::spawn(sync_cluster_status(Arc::clone(&engine)));
+    // a restarted server rejoins with its id before the manager removes it.
+    match engine.heartbeat().await {
+        Ok(_) => {}
+        Err(SERVER_ID_MISMATCH) => panic!(
+            "another server is a member at {}, remove it from the cluster first",
+            server_address
+        ),
+        Err(e) => error!("heartbeat failed, error = {}", status_to_string(e)),
+    }
+    tokio::spawn(send_heartbeats(Arc::clone(&engine)));
+    tokio::spawn(report_buffer_pool_stats());
 
     while <i32 as TryInto<ClusterStatus>>::try_into(engine.cluster_status.load(Ordering::Relaxed))
         .unwrap()
@@ -292,7 +509,9 @@ pub async fn run(
     }
 
     let handler = Arc::new(FileRequestHandler::new(engine.clone()));
-    let server = RpcServer::new(handler, &server_address);
+    let server = RpcServer::new(handler.clone(), &server_address);
+    // the clients of this host connect to the local socket, see rpc/local.rs.
+    tokio::spawn(serve_local(handler, server_address.clone()));
 
     let engine_clone = Arc::clone(&engine);
 
@@ -305,45 +524,76 @@ pub async fn run(
 
     info!("Init: Add connections and update Server Status");
 
-    let all_servers_address = match engine.get_hash_ring_info().await {
+    let info = match engine.get_hash_ring_info().await {
         Ok(value) => value,
         Err(_) => {
             panic!("Get Hash Ring Info Failed.");
         }
     };
-    info!("Init: Hash Ring Info: {:?}", all_servers_address);
-    for value in all_servers_address.iter() {
-        if server_address == value.0 {
-            continue;
-        }
-        if let Err(e) = engine.add_connection(value.0.clone()).await {
-            panic!("Init: Add Connection Failed. Error = {}", e);
-        }
+    info!(
+        "Init: Hash Ring Info: {:?}, Hash Algorithm: {}, Groups: {:?}, Epoch: {}",
+        info.servers, info.algorithm, info.groups, info.epoch
+    );
+    if let Err(e) = engine.meta_engine.check_hash_algorithm(info.algorithm) {
+        panic!("Init: {}", e);
     }
+    let in_ring = info.servers.iter().any(|value| value.0 == server_address);
+    let servers = info
+        .servers
+        .iter()
+        .filter(|value| value.0 != server_address)
+        .map(|value| value.0.clone())
+        .collect();
+    connect_servers(&engine, servers).await;
     info!("Init: Add Connections Success.");
-    engine
-        .hash_ring
-        .write()
-        .replace(HashRing::new(all_servers_address));
+    engine.hash_ring.write().replace(HashRing::from(info));
     info!("Init: Update Hash Ring Success.");
+    // the files handed off before a restart are still to be deleted.
+    if let Err(e) = engine.recover_handoffs().await {
+        panic!(
+            "Init: Recover Handoffs Failed, Error = {}",
+            status_to_string(e)
+        );
+    }
 
-    match <i32 as TryInto<ClusterStatus>>::try_into(engine.cluster_status.load(Ordering::Relaxed))
+    // a new server waits for the manager to start the change adding it, it
+    // may be queued after other membership changes.
+    loop {
+        match <i32 as TryInto<ClusterStatus>>::try_into(
+            engine.cluster_status.load(Ordering::Relaxed),
+        )
         .unwrap()
-    {
-        ClusterStatus::Initializing => {
-            match engine.update_server_status(ServerStatus::Finished).await {
-                Ok(_) => {
-                    info!("Update Server Status to Finish Success.");
-                }
-                Err(e) => {
-                    panic!("Update Server Status to Finish Failed. Error = {}", e);
+        {
+            ClusterStatus::Initializing => {
+                match engine.update_server_status(ServerStatus::Finished).await {
+                    Ok(_) => {
+                        info!("Update Server Status to Finish Success.");
+                    }
+                    Err(e) => {
+                        panic!("Update Server Status to Finish Failed. Error = {}", e);
+                    }
                 }
+                break;
             }
+            ClusterStatus::Idle if in_ring => break,
+            // the manager refuses it until the change adding the server runs.
+            ClusterStatus::NodesStarting
+                if !in_ring
+                    && engine
+                        .update_server_status(ServerStatus::Finished)
+                        .await
+                        .is_ok() =>
+            {
+                info!("Update Server Status to Finish Success.");
+                break;
+            }
+            e if in_ring => {
+                panic!("Cluster Status Unexpected. Status = {:?}", e as u32);
+            }
+            _ => {}
         }
-        ClusterStatus::Idle => {}
-        e => {
-            panic!("Cluster Status Unexpected. Status = {:?}", e as u32);
-        }
+        info!("Init: waiting for the server to be added.");
+        sleep(Duration::from_secs(1)).await;
     }
     info!("Init: Start Transferring Data.");
     watch_status(engine.clone()).await;
@@ -369,6 +619,26 @@ impl<S: StorageEngine> Handler for FileRequestHandler<S>
 where
     S: StorageEngine + std::marker::Send + std::marker::Sync + 'static,
 {
+    fn operation_name(&self, operation_type: u32) -> String {
+        match OperationType::try_from(operation_type) {
+            Ok(r#type) => format!("{:?}", r#type),
+            Err(_) => operation_type.to_string(),
+        }
+    }
+
+    // only the peers of the local socket are accepted with their
+    // credentials, see rpc/local.rs.
+    fn accept_peer(&self, id: u32, uid: u32, _gid: u32) -> bool {
+        self.engine.local_peers.insert(id, uid);
+        true
+    }
+
+    fn peer_closed(&self, id: u32) {
+        self.engine.local_peers.remove(&id);
+        self.engine.metadata_limits.remove(id);
+        self.engine.lock_table.connection_closed(id);
+    }
+
     // dispatch is the main function to handle the request from client
     // the return value is a tuple of (i32, u32, Vec<u8>, Vec<u8>)
     // the first i32 is the status of the function
@@ -393,6 +663,141 @@ where
         };
 
         let file_path = unsafe { std::str::from_utf8_unchecked(&path) };
+        // a hard link stands for its file in the requests on the file, see
+        // MetaEngine::link.
+        let file_path = match r#type {
+            OperationType::GetFileAttr
+            | OperationType::OpenFile
+            | OperationType::ReadFile
+            | OperationType::WriteFile
+            | OperationType::AppendFile
+            | OperationType::TruncateFile
+            | OperationType::SetAttr
+            | OperationType::Fsync
+            | OperationType::Seek
+            | OperationType::Lock
+            | OperationType::Unlock
+            | OperationType::TestLock
+            | OperationType::Prefetch
+            | OperationType::BeginUpload
+            | OperationType::UploadPart
+            | OperationType::CompleteUpload
+            | OperationType::OpenLocal
+            | OperationType::Grep
+            | OperationType::AddLink => self.engine.meta_engine.resolve(file_path),
+            _ => Cow::Borrowed(file_path),
+        };
+        let file_path: &str = &file_path;
+
+        if flags & FORWARDED_REQUEST == 0 && is_metadata(&r#type) {
+            self.engine.metadata_limits.acquire(id).await;
+        }
+
+        // a tree listing is sent to every server and answered from the local
+        // attrs only, so it is never forwarded.
+        if let OperationType::ListTree = r#type {
+            debug!("{} List Tree: {}", self.engine.address, file_path);
+            let md: ListTreeSendMetaData = bincode::deserialize(&metadata).unwrap();
+            let (data, status) = match self.engine.list_tree(file_path, &md.start_after, md.size) {
+                Ok(value) => (value, 0),
+                Err(e) => {
+                    debug!(
+                        "List Tree Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                        status_to_string(e),
+                        file_path,
+                        operation_type,
+                        flags
+                    );
+                    (Vec::new(), e)
+                }
+            };
+            return Ok((status, 0, 0, data.len(), Vec::new(), data));
+        }
+
+        // so is a tree change, applied to the local attrs only.
+        if let OperationType::SetAttrTree = r#type {
+            let md: SetAttrTreeSendMetaData = bincode::deserialize(&metadata).unwrap();
+            let (meta_data, status) = match self.engine.set_attr_tree(file_path, &md) {
+                Ok(recv_md) => (bincode::serialize(&recv_md).unwrap(), 0),
+                Err(e) => (Vec::new(), e),
+            };
+            debug!(
+                "{} Set Attr Tree: {}, status: {}",
+                self.engine.address, file_path, status
+            );
+            return Ok((status, 0, meta_data.len(), 0, meta_data, Vec::new()));
+        }
+
+        // and a dedup scan, of the local files only. It reads all of them,
+        // so it runs off the workers of the requests.
+        if let OperationType::DedupScan = r#type {
+            let md: DedupScanSendMetaData = bincode::deserialize(&metadata).unwrap();
+            let (engine, path) = (self.engine.clone(), file_path.to_owned());
+            let result = tokio::task::spawn_blocking(move || engine.dedup_scan(&path, &md))
+                .await
+                .unwrap_or(Err(libc::EIO));
+            let (meta_data, status) = match result {
+                Ok(recv_md) => (bincode::serialize(&recv_md).unwrap(), 0),
+                Err(e) => (Vec::new(), e),
+            };
+            debug!(
+                "{} Dedup Scan: {}, status: {}",
+                self.engine.address, file_path, status
+            );
+            return Ok((status, 0, meta_data.len(), 0, meta_data, Vec::new()));
+        }
+
+        if let OperationType::Health = r#type {
+            let report = self.engine.health();
+            debug!("{} Health: {:?}", self.engine.address, report);
+            let meta_data = bincode::serialize(&report).unwrap();
+            return Ok((0, 0, meta_data.len(), 0, meta_data, Vec::new()));
+        }
+
+        if let OperationType::TransferStatus = r#type {
+            let report = self.engine.transfer_status();
+            debug!("{} Transfer Status: {:?}", self.engine.address, report);
+            let meta_data = bincode::serialize(&report).unwrap();
+            return Ok((0, 0, meta_data.len(), 0, meta_data, Vec::new()));
+        }
+
+        if let OperationType::GetStatFs = r#type {
+            let (meta_data, status) = match self.engine.stat_fs() {
+                Ok(recv_md) => (bincode::serialize(&recv_md).unwrap(), 0),
+                Err(e) => (Vec::new(), e),
+            };
+            debug!("{} Get Stat Fs, status: {}", self.engine.address, status);
+            return Ok((status, 0, meta_data.len(), 0, meta_data, Vec::new()));
+        }
+
+        // a short-circuit read is opened by the server of the file, it is
+        // never forwarded.
+        if let OperationType::OpenLocal = r#type {
+            let (status, data) = match self.engine.open_local(id, file_path) {
+                Ok(local_path) => (0, local_path.into_bytes()),
+                Err(e) => (e, Vec::new()),
+            };
+            debug!(
+                "{} Open Local: {}, status: {}",
+                self.engine.address, file_path, status
+            );
+            return Ok((status, 0, 0, data.len(), Vec::new(), data));
+        }
+
+        // the paths of a batch are checked one by one, see batch_get_file_attr.
+        if let OperationType::BatchGetAttr = r#type {
+            let paths: Vec<String> = match bincode::deserialize(&data) {
+                Ok(paths) => paths,
+                Err(_) => return Ok((libc::EINVAL, 0, 0, 0, Vec::new(), Vec::new())),
+            };
+            debug!(
+                "{} Batch Get Attr: {} paths",
+                self.engine.address,
+                paths.len()
+            );
+            let data = self.engine.batch_get_file_attr(&paths).await;
+            return Ok((0, 0, 0, data.len(), Vec::new(), data));
+        }
 
         // this lock is deprecated, and always return false
         let _lock =
@@ -417,6 +822,7 @@ where
                 }
                 (None, lock) => lock,
             };
+        self.engine.volume_stats.add_op(file_path);
 
         match r#type {
             OperationType::Unkown => {
@@ -464,25 +870,25 @@ where
                     Vec::new(),
                 ))
             }
-            OperationType::CreateDir => {
-                debug!("{} Create Dir: path: {}", self.engine.address, file_path);
-                let meta_data_unwraped: CreateDirSendMetaData =
-                    bincode::deserialize(&metadata).unwrap();
+            OperationType::LinkTempFile => {
+                debug!(
+                    "{} Link Temp File: path: {}",
+                    self.engine.address, file_path
+                );
+                let md: LinkTempFileSendMetaData = bincode::deserialize(&metadata).unwrap();
                 let (return_meta_data, status) = match self
                     .engine
-                    .create_dir(
-                        metadata,
-                        file_path,
-                        &meta_data_unwraped.name,
-                        meta_data_unwraped.mode,
-                    )
+                    .link_temp_file(file_path, &md.name, &md.temp_path)
                     .await
                 {
                     Ok(value) => (value, 0),
                     Err(e) => {
                         debug!(
-                            "Create Dir Failed: {:?}, path: {}, operation_type: {}, flags: {}",
-                            e, file_path, operation_type, flags
+                            "Link Temp File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
                         );
                         (Vec::new(), e)
                     }
@@ -496,19 +902,29 @@ where
                     Vec::new(),
                 ))
             }
-            OperationType::GetFileAttr => {
-                debug!("{} Get File Attr: path: {}", self.engine.address, file_path);
-                let (return_meta_data, status) =
-                    match self.engine.get_file_attr(file_path) {
-                        Ok(value) => (value, 0),
-                        Err(e) => {
-                            debug!(
-                            "Get File Attr Failed: {:?}, path: {}, operation_type: {}, flags: {}",
-                            status_to_string(e), file_path, operation_type, flags
+            OperationType::CreateSymlink => {
+                debug!(
+                    "{} Create Symlink: path: {}",
+                    self.engine.address, file_path
+                );
+                let md: CreateSymlinkSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (return_meta_data, status) = match self
+                    .engine
+                    .create_symlink(metadata, file_path, &md.name)
+                    .await
+                {
+                    Ok(value) => (value, 0),
+                    Err(e) => {
+                        debug!(
+                            "Create Symlink Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
                         );
-                            (Vec::new(), e)
-                        }
-                    };
+                        (Vec::new(), e)
+                    }
+                };
                 Ok((
                     status,
                     0,
@@ -518,37 +934,21 @@ where
                     Vec::new(),
                 ))
             }
-            OperationType::OpenFile => {
-                debug!("{} Open File {}", self.engine.address, file_path);
-                let meta_data_unwraped: OpenFileSendMetaData =
-                    bincode::deserialize(&metadata).unwrap();
-                let status = match self.engine.open_file(
+            OperationType::CreateSymlinkNoParent => {
+                debug!(
+                    "{} Create Symlink no Parent: path: {}",
+                    self.engine.address, file_path
+                );
+                let md: CreateSymlinkSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (return_meta_data, status) = match self.engine.create_symlink_no_parent(
                     file_path,
-                    meta_data_unwraped.flags,
-                    meta_data_unwraped.mode,
+                    &md.target,
+                    (md.uid, md.gid),
                 ) {
-                    Ok(()) => 0,
-                    Err(e) => {
-                        debug!(
-                            "Open File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
-                            status_to_string(e),
-                            file_path,
-                            operation_type,
-                            flags
-                        );
-                        e
-                    }
-                };
-                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
-            }
-            OperationType::ReadDir => {
-                debug!("{} Read Dir: {}", self.engine.address, file_path);
-                let md: ReadDirSendMetaData = bincode::deserialize(&metadata).unwrap();
-                let (data, status) = match self.engine.read_dir(file_path, md.size, md.offset) {
                     Ok(value) => (value, 0),
                     Err(e) => {
                         debug!(
-                            "Read Dir Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            "Create Symlink Failed: {:?}, path: {}, operation_type: {}, flags: {}",
                             status_to_string(e),
                             file_path,
                             operation_type,
@@ -557,64 +957,421 @@ where
                         (Vec::new(), e)
                     }
                 };
-                Ok((status, 0, 0, data.len(), Vec::new(), data))
+                Ok((
+                    status,
+                    0,
+                    return_meta_data.len(),
+                    0,
+                    return_meta_data,
+                    Vec::new(),
+                ))
             }
-            OperationType::ReadFile => {
-                debug!("{} Read File: {}", self.engine.address, file_path);
-                let md: ReadFileSendMetaData = bincode::deserialize(&metadata).unwrap();
-                let (data, status) = match self.engine.read_file(file_path, md.size, md.offset) {
-                    Ok(value) => (value, 0),
-                    Err(e) => {
-                        debug!(
-                            "Read File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
-                            status_to_string(e),
-                            file_path,
-                            operation_type,
-                            flags
-                        );
-                        (Vec::new(), e)
-                    }
-                };
-                Ok((status, 0, 0, data.len(), Vec::new(), data))
+            OperationType::Link => {
+                debug!("{} Link: path: {}", self.engine.address, file_path);
+                let md: LinkSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (return_meta_data, status) =
+                    match self.engine.link(metadata, file_path, &md.name).await {
+                        Ok(value) => (value, 0),
+                        Err(e) => {
+                            debug!(
+                                "Link Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                                status_to_string(e),
+                                file_path,
+                                operation_type,
+                                flags
+                            );
+                            (Vec::new(), e)
+                        }
+                    };
+                Ok((
+                    status,
+                    0,
+                    return_meta_data.len(),
+                    0,
+                    return_meta_data,
+                    Vec::new(),
+                ))
             }
-            OperationType::WriteFile => {
-                debug!("{} Write File: {}", self.engine.address, file_path);
-                let md: WriteFileSendMetaData = bincode::deserialize(&metadata).unwrap();
-                let (status, size) =
-                    match self
-                        .engine
-                        .write_file(file_path, data.as_slice(), md.offset)
-                    {
-                        Ok(size) => (0, size as u32),
+            OperationType::LinkNoParent => {
+                debug!(
+                    "{} Link no Parent: path: {}",
+                    self.engine.address, file_path
+                );
+                let md: LinkSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (return_meta_data, status) =
+                    match self.engine.link_no_parent(file_path, &md).await {
+                        Ok(value) => (value, 0),
                         Err(e) => {
                             debug!(
-                                "Write File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                                "Link Failed: {:?}, path: {}, operation_type: {}, flags: {}",
                                 status_to_string(e),
                                 file_path,
                                 operation_type,
                                 flags
                             );
-                            (e, 0)
+                            (Vec::new(), e)
                         }
                     };
                 Ok((
                     status,
                     0,
-                    size.to_le_bytes().len(),
+                    return_meta_data.len(),
                     0,
-                    size.to_le_bytes().to_vec(),
+                    return_meta_data,
                     Vec::new(),
                 ))
             }
-            OperationType::DeleteFile => {
-                debug!("{} Delete File: {}", self.engine.address, file_path);
-                let meta_data_unwraped: DeleteFileSendMetaData =
-                    bincode::deserialize(&metadata).unwrap();
-                let status = match self
-                    .engine
-                    .delete_file(metadata, file_path, &meta_data_unwraped.name)
-                    .await
-                {
+            OperationType::AddLink => {
+                debug!("{} Add Link: path: {}", self.engine.address, file_path);
+                let md: AddLinkSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (return_meta_data, status) =
+                    match self.engine.add_link(file_path, md.delta, md.linked).await {
+                        Ok(value) => (bincode::serialize(&value).unwrap(), 0),
+                        Err(e) => {
+                            debug!(
+                                "Add Link Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                                status_to_string(e),
+                                file_path,
+                                operation_type,
+                                flags
+                            );
+                            (Vec::new(), e)
+                        }
+                    };
+                Ok((
+                    status,
+                    0,
+                    return_meta_data.len(),
+                    0,
+                    return_meta_data,
+                    Vec::new(),
+                ))
+            }
+            OperationType::ShardDir => {
+                debug!("{} Shard Dir: path: {}", self.engine.address, file_path);
+                let md: ShardDirSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status = match self.engine.shard_dir(file_path, md.shards).await {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Shard Dir Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::CreateDir => {
+                debug!("{} Create Dir: path: {}", self.engine.address, file_path);
+                let meta_data_unwraped: CreateDirSendMetaData =
+                    bincode::deserialize(&metadata).unwrap();
+                let (return_meta_data, status) = match self
+                    .engine
+                    .create_dir(
+                        metadata,
+                        file_path,
+                        &meta_data_unwraped.name,
+                        meta_data_unwraped.mode,
+                    )
+                    .await
+                {
+                    Ok(value) => (value, 0),
+                    Err(e) => {
+                        debug!(
+                            "Create Dir Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            e, file_path, operation_type, flags
+                        );
+                        (Vec::new(), e)
+                    }
+                };
+                Ok((
+                    status,
+                    0,
+                    return_meta_data.len(),
+                    0,
+                    return_meta_data,
+                    Vec::new(),
+                ))
+            }
+            OperationType::GetFileAttr => {
+                debug!("{} Get File Attr: path: {}", self.engine.address, file_path);
+                let (return_meta_data, status) =
+                    match self.engine.get_file_attr(file_path).await {
+                        Ok(value) => (value, 0),
+                        Err(e) => {
+                            debug!(
+                            "Get File Attr Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e), file_path, operation_type, flags
+                        );
+                            (Vec::new(), e)
+                        }
+                    };
+                self.engine.mirror_read(
+                    r#type,
+                    flags,
+                    file_path,
+                    &metadata,
+                    status,
+                    &return_meta_data,
+                );
+                Ok((
+                    status,
+                    0,
+                    return_meta_data.len(),
+                    0,
+                    return_meta_data,
+                    Vec::new(),
+                ))
+            }
+            OperationType::OpenFile => {
+                debug!("{} Open File {}", self.engine.address, file_path);
+                let meta_data_unwraped: OpenFileSendMetaData =
+                    bincode::deserialize(&metadata).unwrap();
+                let status = match self.engine.open_file(
+                    file_path,
+                    meta_data_unwraped.flags,
+                    meta_data_unwraped.mode,
+                ) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Open File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::ReadDir => {
+                debug!("{} Read Dir: {}", self.engine.address, file_path);
+                let md: ReadDirSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (data, status) = match self.engine.read_dir(file_path, md.size, md.offset).await
+                {
+                    Ok(value) => (value, 0),
+                    Err(e) => {
+                        debug!(
+                            "Read Dir Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        (Vec::new(), e)
+                    }
+                };
+                Ok((status, 0, 0, data.len(), Vec::new(), data))
+            }
+            OperationType::ReadFile => {
+                debug!("{} Read File: {}", self.engine.address, file_path);
+                let md: ReadFileSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let atime_policy = self.engine.atime_policy(file_path).await;
+                let (data, status) =
+                    match self
+                        .engine
+                        .read_file(file_path, md.size, md.offset, atime_policy)
+                    {
+                        Ok(value) => (value, 0),
+                        Err(e) => {
+                            debug!(
+                                "Read File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                                status_to_string(e),
+                                file_path,
+                                operation_type,
+                                flags
+                            );
+                            (Vec::new(), e)
+                        }
+                    };
+                self.engine.volume_stats.add_read(file_path, data.len());
+                self.engine
+                    .mirror_read(r#type, flags, file_path, &metadata, status, &data);
+                Ok((status, 0, 0, data.len(), Vec::new(), data))
+            }
+            OperationType::WriteFile => {
+                debug!("{} Write File: {}", self.engine.address, file_path);
+                let md: WriteFileSendMetaData = bincode::deserialize(&metadata).unwrap();
+                if self.engine.is_stale_write(file_path, md.epoch) {
+                    debug!(
+                        "Write File with stale epoch {}, path: {}",
+                        md.epoch, file_path
+                    );
+                    return Ok((STALE_EPOCH, 0, 0, 0, Vec::new(), Vec::new()));
+                }
+                let (status, size) =
+                    match self
+                        .engine
+                        .write_file(file_path, data.as_slice(), md.offset, md.version)
+                    {
+                        Ok(size) => (0, size as u64),
+                        Err(e) => {
+                            debug!(
+                                "Write File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                                status_to_string(e),
+                                file_path,
+                                operation_type,
+                                flags
+                            );
+                            (e, 0)
+                        }
+                    };
+                self.engine.volume_stats.add_write(file_path, size as usize);
+                Ok((
+                    status,
+                    0,
+                    size.to_le_bytes().len(),
+                    0,
+                    size.to_le_bytes().to_vec(),
+                    Vec::new(),
+                ))
+            }
+            OperationType::AppendFile => {
+                debug!("{} Append File: {}", self.engine.address, file_path);
+                let md: AppendFileSendMetaData = bincode::deserialize(&metadata).unwrap();
+                if self.engine.is_stale_write(file_path, md.epoch) {
+                    return Ok((STALE_EPOCH, 0, 0, 0, Vec::new(), Vec::new()));
+                }
+                let (status, offset, size) =
+                    match self.engine.append_file(file_path, data.as_slice()) {
+                        Ok((offset, size)) => (0, offset, size as u64),
+                        Err(e) => {
+                            debug!(
+                                "Append File Failed: {:?}, path: {}",
+                                status_to_string(e),
+                                file_path
+                            );
+                            (e, 0, 0)
+                        }
+                    };
+                self.engine.volume_stats.add_write(file_path, size as usize);
+                let recv_meta_data =
+                    bincode::serialize(&AppendFileRecvMetaData { offset, size }).unwrap();
+                Ok((
+                    status,
+                    0,
+                    recv_meta_data.len(),
+                    0,
+                    recv_meta_data,
+                    Vec::new(),
+                ))
+            }
+            OperationType::ListTree
+            | OperationType::BatchGetAttr
+            | OperationType::Health
+            | OperationType::TransferStatus
+            | OperationType::OpenLocal
+            | OperationType::SetAttrTree
+            | OperationType::DedupScan
+            | OperationType::GetStatFs => {
+                unreachable!()
+            }
+            OperationType::Prefetch => {
+                debug!("{} Prefetch: {}", self.engine.address, file_path);
+                let (status, size) = match self.engine.prefetch_file(file_path) {
+                    Ok(size) => (0, size),
+                    Err(e) => {
+                        debug!(
+                            "Prefetch Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        (e, 0)
+                    }
+                };
+                Ok((
+                    status,
+                    0,
+                    size.to_le_bytes().len(),
+                    0,
+                    size.to_le_bytes().to_vec(),
+                    Vec::new(),
+                ))
+            }
+            OperationType::BeginUpload => {
+                debug!("{} Begin Upload: {}", self.engine.address, file_path);
+                let (status, upload_id) = match self.engine.begin_upload(file_path) {
+                    Ok(upload_id) => (0, upload_id),
+                    Err(e) => {
+                        debug!(
+                            "Begin Upload Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        (e, 0)
+                    }
+                };
+                Ok((
+                    status,
+                    0,
+                    upload_id.to_le_bytes().len(),
+                    0,
+                    upload_id.to_le_bytes().to_vec(),
+                    Vec::new(),
+                ))
+            }
+            OperationType::UploadPart => {
+                debug!("{} Upload Part: {}", self.engine.address, file_path);
+                let md: UploadPartSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status =
+                    match self
+                        .engine
+                        .upload_part(file_path, md.upload_id, md.offset, &data)
+                    {
+                        Ok(()) => 0,
+                        Err(e) => {
+                            debug!(
+                                "Upload Part Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                                status_to_string(e),
+                                file_path,
+                                operation_type,
+                                flags
+                            );
+                            e
+                        }
+                    };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::CompleteUpload => {
+                debug!("{} Complete Upload: {}", self.engine.address, file_path);
+                let md: CompleteUploadSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status = match self
+                    .engine
+                    .complete_upload(file_path, md.upload_id, md.size)
+                {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Complete Upload Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::DeleteFile => {
+                debug!("{} Delete File: {}", self.engine.address, file_path);
+                let meta_data_unwraped: DeleteFileSendMetaData =
+                    bincode::deserialize(&metadata).unwrap();
+                let status = match self
+                    .engine
+                    .delete_file(metadata, file_path, &meta_data_unwraped.name)
+                    .await
+                {
                     Ok(()) => 0,
                     Err(e) => {
                         debug!(
@@ -684,6 +1441,13 @@ where
             OperationType::TruncateFile => {
                 debug!("{} Truncate File: {}", self.engine.address, file_path);
                 let md: TruncateFileSendMetaData = bincode::deserialize(&metadata).unwrap();
+                if self.engine.is_stale_write(file_path, md.epoch) {
+                    debug!(
+                        "Truncate File with stale epoch {}, path: {}",
+                        md.epoch, file_path
+                    );
+                    return Ok((STALE_EPOCH, 0, 0, 0, Vec::new(), Vec::new()));
+                }
                 let status =
                     match self.engine.truncate_file(file_path, md.length) {
                         Ok(()) => 0,
@@ -697,11 +1461,43 @@ where
                     };
                 Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
             }
+            OperationType::SetAttr => {
+                debug!("{} Set Attr: {}", self.engine.address, file_path);
+                let md: SetAttrSendMetaData = bincode::deserialize(&metadata).unwrap();
+                if md.size.is_some() && self.engine.is_stale_write(file_path, md.epoch) {
+                    debug!(
+                        "Set Attr with stale epoch {}, path: {}",
+                        md.epoch, file_path
+                    );
+                    return Ok((STALE_EPOCH, 0, 0, 0, Vec::new(), Vec::new()));
+                }
+                let (return_meta_data, status) = match self.engine.set_attr(file_path, &md) {
+                    Ok(value) => (value, 0),
+                    Err(e) => {
+                        debug!(
+                            "Set Attr Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        (Vec::new(), e)
+                    }
+                };
+                Ok((
+                    status,
+                    0,
+                    return_meta_data.len(),
+                    0,
+                    return_meta_data,
+                    Vec::new(),
+                ))
+            }
             OperationType::CheckFile => {
                 info!("{} Checkout File: {}", self.engine.address, file_path);
-                let file_attr = bytes_as_file_attr(&metadata);
+                let md: CheckFileSendMetaData = bincode::deserialize(&metadata).unwrap();
                 let status =
-                    match self.engine.check_file(file_path, file_attr) {
+                    match self.engine.check_file(file_path, &md) {
                         Ok(()) => 0,
                         Err(e) => {
                             info!(
@@ -736,10 +1532,11 @@ where
                 );
                 let meta_data_unwraped: CreateDirSendMetaData =
                     bincode::deserialize(&metadata).unwrap();
-                let (return_meta_data, status) = match self
-                    .engine
-                    .create_dir_no_parent(file_path, meta_data_unwraped.mode)
-                {
+                let (return_meta_data, status) = match self.engine.create_dir_no_parent(
+                    file_path,
+                    meta_data_unwraped.mode,
+                    (meta_data_unwraped.uid, meta_data_unwraped.gid),
+                ) {
                     Ok(value) => (value, 0),
                     Err(e) => {
                         debug!(
@@ -768,13 +1565,23 @@ where
                 );
                 let meta_data_unwraped: CreateFileSendMetaData =
                     bincode::deserialize(&metadata).unwrap();
+                let layout = FileLayout {
+                    append_only: meta_data_unwraped.append_only,
+                    ..self.engine.volume_layout(file_path).await
+                };
                 let (return_meta_data, status) = match self.engine.create_file_no_parent(
                     file_path,
                     meta_data_unwraped.flags,
                     meta_data_unwraped.umask,
                     meta_data_unwraped.mode,
+                    &layout,
+                    (meta_data_unwraped.uid, meta_data_unwraped.gid),
                 ) {
-                    Ok(value) => (value, 0),
+                    Ok(value) => {
+                        self.engine
+                            .preallocate_file(file_path, meta_data_unwraped.size_hint);
+                        (value, 0)
+                    }
                     Err(e) => {
                         debug!(
                             "Create File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
@@ -800,7 +1607,7 @@ where
                     "{} Delete Dir no Parent: {}",
                     self.engine.address, file_path
                 );
-                let status = match self.engine.delete_dir_no_parent(file_path) {
+                let status = match self.engine.delete_dir_no_parent(file_path).await {
                     Ok(()) => 0,
                     Err(e) => {
                         debug!(
@@ -820,7 +1627,7 @@ where
                     "{} Delete File no Parent: {}",
                     self.engine.address, file_path
                 );
-                let status = match self.engine.delete_file_no_parent(file_path) {
+                let status = match self.engine.unlink_no_parent(file_path).await {
                     Ok(()) => 0,
                     Err(e) => {
                         debug!(
@@ -835,6 +1642,135 @@ where
                 };
                 Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
             }
+            OperationType::Rename => {
+                debug!("{} Rename: {}", self.engine.address, file_path);
+                let md: RenameSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status = match self.engine.rename(metadata, file_path, &md.name).await {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Rename Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::RenameAt => {
+                debug!("{} Rename At: {}", self.engine.address, file_path);
+                let md: RenameAtSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status = match self.engine.rename_at(metadata, file_path, &md.name).await {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Rename At Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::RenameNoParent => {
+                debug!("{} Rename no Parent: {}", self.engine.address, file_path);
+                let md: RenameNoParentSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status = match self.engine.rename_no_parent(file_path, &md.new_path) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Rename no Parent Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::Fsync => {
+                debug!("{} Fsync: {}", self.engine.address, file_path);
+                let md: FsyncSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let status = match self.engine.fsync(file_path, &md) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "Fsync Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::Seek => {
+                debug!("{} Seek: {}", self.engine.address, file_path);
+                let md: SeekSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (status, offset) = match self.engine.seek(file_path, &md) {
+                    Ok(offset) => (0, offset),
+                    Err(e) => {
+                        debug!(
+                            "Seek Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        (e, 0)
+                    }
+                };
+                Ok((
+                    status,
+                    0,
+                    offset.to_le_bytes().len(),
+                    0,
+                    offset.to_le_bytes().to_vec(),
+                    Vec::new(),
+                ))
+            }
+            OperationType::Lock | OperationType::Unlock => {
+                debug!("{} {:?}: {}", self.engine.address, r#type, file_path);
+                let md: LockSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let result = match r#type {
+                    OperationType::Lock => self.engine.lock(file_path, &md, id),
+                    _ => self.engine.unlock(file_path, &md),
+                };
+                let status = match result {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        debug!(
+                            "{:?} Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            r#type,
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        e
+                    }
+                };
+                Ok((status, 0, 0, 0, Vec::new(), Vec::new()))
+            }
+            OperationType::TestLock => {
+                debug!("{} Test Lock: {}", self.engine.address, file_path);
+                let md: LockSendMetaData = bincode::deserialize(&metadata).unwrap();
+                let (meta_data, status) = match self.engine.test_lock(file_path, &md) {
+                    Ok(recv_md) => (bincode::serialize(&recv_md).unwrap(), 0),
+                    Err(e) => (Vec::new(), e),
+                };
+                Ok((status, 0, meta_data.len(), 0, meta_data, Vec::new()))
+            }
             OperationType::CreateVolume => {
                 info!("{} Create Volume", self.engine.address);
                 let meta_data_unwraped: CreateVolumeSendMetaData =
@@ -847,10 +1783,12 @@ where
                 {
                     return Ok((libc::EINVAL, 0, 0, 0, vec![], vec![]));
                 }
-                let status = match self
-                    .engine
-                    .create_volume(file_path, meta_data_unwraped.size)
-                {
+                let status = match self.engine.create_volume(
+                    file_path,
+                    meta_data_unwraped.size,
+                    meta_data_unwraped.atime_policy,
+                    meta_data_unwraped.public,
+                ) {
                     Ok(()) => 0,
                     Err(e) => {
                         info!(
@@ -872,16 +1810,22 @@ where
                 );
                 if !file_path.is_empty()
                     && self.engine.get_address(file_path) == self.engine.address
-                    && self.engine.meta_engine.init_volume(file_path).is_err()
                 {
-                    error!(
-                        "Volume not Exists: id: {}, file_path: {}, address {}, self_address {}",
-                        id,
-                        file_path,
-                        self.engine.get_address(file_path),
-                        self.engine.address
-                    );
-                    return Ok((libc::ENOENT, 0, 0, 0, vec![], vec![]));
+                    match self.engine.meta_engine.init_volume(file_path) {
+                        Ok(volume) => {
+                            return Ok((0, 0, volume.len(), 0, volume, Vec::new()));
+                        }
+                        Err(_) => {
+                            error!(
+                                "Volume not Exists: id: {}, file_path: {}, address {}, self_address {}",
+                                id,
+                                file_path,
+                                self.engine.get_address(file_path),
+                                self.engine.address
+                            );
+                            return Ok((libc::ENOENT, 0, 0, 0, vec![], vec![]));
+                        }
+                    }
                 }
                 //self.engine.volume_indexes.insert(id, file_path);
                 return Ok((0, 0, 0, 0, Vec::new(), Vec::new()));
@@ -948,6 +1892,122 @@ where
                 };
                 return Ok((status, 0, 0, 0, Vec::new(), Vec::new()));
             }
+            #[cfg(feature = "grep-pushdown")]
+            OperationType::Grep => {
+                debug!("{} Grep: {}", self.engine.address, file_path);
+                let md: GrepSendMetaData = bincode::deserialize(&metadata).unwrap();
+                match self.engine.grep_file(file_path, &md) {
+                    Ok((recv_md, matches)) => {
+                        let return_meta_data = bincode::serialize(&recv_md).unwrap();
+                        let data = bincode::serialize(&matches).unwrap();
+                        Ok((
+                            0,
+                            0,
+                            return_meta_data.len(),
+                            data.len(),
+                            return_meta_data,
+                            data,
+                        ))
+                    }
+                    Err(e) => {
+                        debug!(
+                            "Grep Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                            status_to_string(e),
+                            file_path,
+                            operation_type,
+                            flags
+                        );
+                        Ok((e, 0, 0, 0, Vec::new(), Vec::new()))
+                    }
+                }
+            }
+            // the servers are built without the experimental grep.
+            #[cfg(not(feature = "grep-pushdown"))]
+            OperationType::Grep => Ok((libc::EOPNOTSUPP, 0, 0, 0, Vec::new(), Vec::new())),
+        }
+    }
+
+    // dispatch_stream reads large files frame by frame, so neither side holds the
+    // whole data in memory. Other requests are handled by dispatch.
+    async fn dispatch_stream(
+        &self,
+        id: u32,
+        operation_type: u32,
+        flags: u32,
+        path: Vec<u8>,
+        data: Vec<u8>,
+        metadata: Vec<u8>,
+        frames: tokio::sync::mpsc::Sender<Vec<u8>>,
+    ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        let file_path = unsafe { std::str::from_utf8_unchecked(&path) };
+        // the file of a hard link of this server may be on another one.
+        let file_path = self.engine.meta_engine.resolve(file_path).into_owned();
+        let file_path: &str = &file_path;
+        if !matches!(
+            OperationType::try_from(operation_type),
+            Ok(OperationType::ReadFile)
+        ) || self.engine.get_forward_address(file_path).0.is_some()
+        {
+            return self
+                .dispatch(id, operation_type, flags, path, data, metadata)
+                .await;
+        }
+        debug!("{} Read File Stream: {}", self.engine.address, file_path);
+        self.engine.volume_stats.add_op(file_path);
+        let md: ReadFileSendMetaData = bincode::deserialize(&metadata).unwrap();
+        let atime_policy = self.engine.atime_policy(file_path).await;
+        let end = md.offset + md.size as i64;
+        let mut offset = md.offset;
+        // a sampled read is mirrored with all its frames, see mirror_read.
+        let mut mirrored = self
+            .engine
+            .mirror_sampled(&OperationType::ReadFile, flags)
+            .then(Vec::new);
+        let (status, last) = loop {
+            if offset >= end {
+                break (0, Vec::new());
+            }
+            let size = std::cmp::min(STREAM_FRAME_SIZE as i64, end - offset) as u32;
+            let frame = match self.engine.read_file(file_path, size, offset, atime_policy) {
+                Ok(value) => value,
+                Err(e) => {
+                    debug!(
+                        "Read File Failed: {:?}, path: {}, operation_type: {}, flags: {}",
+                        status_to_string(e),
+                        file_path,
+                        operation_type,
+                        flags
+                    );
+                    break (e, Vec::new());
+                }
+            };
+            let length = frame.len();
+            self.engine.volume_stats.add_read(file_path, length);
+            if let Some(data) = &mut mirrored {
+                data.extend_from_slice(&frame);
+            }
+            if length < size as usize || offset + length as i64 >= end {
+                // the last frame is sent as the response.
+                break (0, frame);
+            }
+            if frames.send(frame).await.is_err() {
+                return Ok((libc::EIO, 0, 0, 0, Vec::new(), Vec::new()));
+            }
+            offset += length as i64;
+        };
+        if let Some(data) = mirrored {
+            let data = match status {
+                0 => data,
+                _ => Vec::new(),
+            };
+            self.engine.mirror_response(
+                OperationType::ReadFile,
+                file_path,
+                &metadata,
+                status,
+                data,
+            );
         }
+        Ok((status, 0, 0, last.len(), Vec::new(), last))
     }
 }
@@ -0,0 +1,267 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * POSIX advisory locks on the files of a server, kept in memory by the
+ * server of each file. A lock covers the bytes start..=end of a file and is
+ * held by an owner, the lock owner of the kernel or the process of the
+ * intercept library, on one client. The read locks of the owners share the
+ * bytes and a write lock excludes the others. The locks of an owner on a
+ * range replace each other as with fcntl, and are released when it closes
+ * the file. The flock locks of the intercept library are kept apart from the
+ * fcntl locks, the two kinds neither conflict nor replace each other. The locks of a client are dropped when its connection to the
+ * server closes, e.g. when it crashes or unmounts, so that they do not
+ * block the other clients for good. The locks are lost when the server
+ * restarts or the file moves to another server.
+ */
+use std::collections::HashSet;
+
+use dashmap::DashMap;
+use libc::{F_RDLCK, F_WRLCK};
+
+use crate::common::serialization::LockSendMetaData;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileLock {
+    pub client: String,
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+    pub flock: bool,
+}
+
+impl From<&LockSendMetaData> for FileLock {
+    fn from(md: &LockSendMetaData) -> Self {
+        Self {
+            client: md.client.clone(),
+            owner: md.owner,
+            start: md.start,
+            end: md.end,
+            typ: md.typ,
+            pid: md.pid,
+            flock: md.flock,
+        }
+    }
+}
+
+impl FileLock {
+    fn same_owner(&self, other: &FileLock) -> bool {
+        self.owner == other.owner && self.client == other.client && self.flock == other.flock
+    }
+
+    fn overlaps(&self, other: &FileLock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &FileLock) -> bool {
+        self.flock == other.flock
+            && !self.same_owner(other)
+            && self.overlaps(other)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+#[derive(Default)]
+pub struct LockTable {
+    // path -> the locks on the file.
+    files: DashMap<String, Vec<FileLock>>,
+    // client -> the connection its last lock was taken on.
+    connections: DashMap<String, u32>,
+}
+
+impl LockTable {
+    // test returns a lock of another owner conflicting with lock.
+    pub fn test(&self, path: &str, lock: &FileLock) -> Option<FileLock> {
+        self.files
+            .get(path)
+            .and_then(|locks| locks.iter().find(|held| held.conflicts(lock)).cloned())
+    }
+
+    // lock takes lock in place of the locks of its owner on its range, or
+    // fails with EAGAIN if another owner holds a conflicting lock.
+    pub fn lock(&self, path: &str, lock: FileLock, connection: u32) -> Result<(), i32> {
+        if lock.start > lock.end || (lock.typ != F_RDLCK && lock.typ != F_WRLCK) {
+            return Err(libc::EINVAL);
+        }
+        let mut locks = self.files.entry(path.to_owned()).or_default();
+        if locks.iter().any(|held| held.conflicts(&lock)) {
+            return Err(libc::EAGAIN);
+        }
+        cut(&mut locks, &lock);
+        self.connections.insert(lock.client.clone(), connection);
+        locks.push(lock);
+        Ok(())
+    }
+
+    // unlock releases the range of lock from the locks of its owner.
+    pub fn unlock(&self, path: &str, lock: &FileLock) -> Result<(), i32> {
+        if lock.start > lock.end {
+            return Err(libc::EINVAL);
+        }
+        if let Some(mut locks) = self.files.get_mut(path) {
+            cut(&mut locks, lock);
+        }
+        self.files.remove_if(path, |_, locks| locks.is_empty());
+        Ok(())
+    }
+
+    // connection_closed drops the locks of the clients whose last lock was
+    // taken on connection.
+    pub fn connection_closed(&self, connection: u32) {
+        let clients: HashSet<String> = self
+            .connections
+            .iter()
+            .filter(|entry| *entry.value() == connection)
+            .map(|entry| entry.key().clone())
+            .collect();
+        if clients.is_empty() {
+            return;
+        }
+        for client in clients.iter() {
+            self.connections
+                .remove_if(client, |_, id| *id == connection);
+        }
+        for mut locks in self.files.iter_mut() {
+            locks.retain(|lock| !clients.contains(&lock.client));
+        }
+        self.files.retain(|_, locks| !locks.is_empty());
+    }
+}
+
+// cut removes the range of lock from the locks of its owner, splitting the
+// locks covering it.
+fn cut(locks: &mut Vec<FileLock>, lock: &FileLock) {
+    let mut rest = Vec::with_capacity(locks.len() + 1);
+    for held in locks.drain(..) {
+        if !held.same_owner(lock) || !held.overlaps(lock) {
+            rest.push(held);
+            continue;
+        }
+        if held.start < lock.start {
+            rest.push(FileLock {
+                end: lock.start - 1,
+                ..held.clone()
+            });
+        }
+        if held.end > lock.end {
+            rest.push(FileLock {
+                start: lock.end + 1,
+                ..held
+            });
+        }
+    }
+    *locks = rest;
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+
+    use super::{FileLock, LockTable};
+
+    fn lock(client: &str, owner: u64, start: u64, end: u64, typ: i32) -> FileLock {
+        FileLock {
+            client: client.to_owned(),
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+            flock: false,
+        }
+    }
+
+    #[test]
+    fn lock_table_test() {
+        let table = LockTable::default();
+        table.lock("f", lock("a", 1, 0, 99, F_RDLCK), 1).unwrap();
+        // the read locks share the bytes.
+        table.lock("f", lock("b", 1, 50, 149, F_RDLCK), 2).unwrap();
+        assert_eq!(
+            table.lock("f", lock("b", 1, 0, 9, F_WRLCK), 2),
+            Err(libc::EAGAIN)
+        );
+        assert_eq!(
+            table.test("f", &lock("b", 2, 0, u64::MAX, F_WRLCK)),
+            Some(lock("a", 1, 0, 99, F_RDLCK))
+        );
+        assert_eq!(table.test("f", &lock("c", 1, 150, 199, F_WRLCK)), None);
+
+        // an owner changes the type of a part of its lock.
+        table.lock("f", lock("a", 1, 0, 9, F_WRLCK), 1).unwrap();
+        assert_eq!(
+            table.test("f", &lock("b", 1, 0, 9, F_RDLCK)),
+            Some(lock("a", 1, 0, 9, F_WRLCK))
+        );
+        assert_eq!(table.test("f", &lock("b", 1, 10, 49, F_RDLCK)), None);
+
+        // unlocking the middle of a lock leaves its ends.
+        table.unlock("f", &lock("b", 1, 60, 139, F_UNLCK)).unwrap();
+        assert_eq!(table.test("f", &lock("c", 1, 100, 139, F_WRLCK)), None);
+        assert!(table.test("f", &lock("c", 1, 140, 140, F_WRLCK)).is_some());
+
+        for client in ["a", "b"] {
+            table
+                .unlock("f", &lock(client, 1, 0, u64::MAX, F_UNLCK))
+                .unwrap();
+        }
+        assert!(table.files.is_empty());
+        assert_eq!(
+            table.lock("f", lock("a", 1, 9, 0, F_WRLCK), 1),
+            Err(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn flock_test() {
+        let table = LockTable::default();
+        let flock = |client: &str, owner: u64, typ: i32| FileLock {
+            flock: true,
+            ..lock(client, owner, 0, u64::MAX, typ)
+        };
+        table.lock("f", lock("a", 1, 0, 99, F_WRLCK), 1).unwrap();
+        // the flock locks do not conflict with the fcntl locks.
+        table.lock("f", flock("b", 1, F_WRLCK), 2).unwrap();
+        assert_eq!(
+            table.lock("f", flock("a", 1, F_RDLCK), 1),
+            Err(libc::EAGAIN)
+        );
+        // nor are released with the fcntl locks of the same owner.
+        table.unlock("f", &flock("a", 1, F_UNLCK)).unwrap();
+        assert_eq!(
+            table.test("f", &lock("b", 1, 0, 0, F_RDLCK)),
+            Some(lock("a", 1, 0, 99, F_WRLCK))
+        );
+        table.unlock("f", &flock("b", 1, F_UNLCK)).unwrap();
+        table.lock("f", flock("a", 1, F_WRLCK), 1).unwrap();
+    }
+
+    #[test]
+    fn connection_closed_test() {
+        let table = LockTable::default();
+        table.lock("f", lock("a", 1, 0, 99, F_WRLCK), 1).unwrap();
+        table.lock("g", lock("a", 2, 0, 9, F_RDLCK), 1).unwrap();
+        table.lock("g", lock("b", 1, 0, 9, F_RDLCK), 2).unwrap();
+        // a client that connected again keeps the locks taken since.
+        table.lock("h", lock("c", 1, 0, 9, F_RDLCK), 3).unwrap();
+        table.lock("h", lock("c", 1, 10, 19, F_RDLCK), 4).unwrap();
+
+        table.connection_closed(3);
+        table.connection_closed(1);
+        assert_eq!(table.test("f", &lock("b", 1, 0, 99, F_WRLCK)), None);
+        assert_eq!(
+            table.test("g", &lock("c", 1, 0, 9, F_WRLCK)),
+            Some(lock("b", 1, 0, 9, F_RDLCK))
+        );
+        assert!(table.test("h", &lock("b", 1, 0, 9, F_WRLCK)).is_some());
+        assert!(!table.files.contains_key("f"));
+
+        table.connection_closed(2);
+        table.connection_closed(4);
+        assert!(table.files.is_empty());
+        assert!(table.connections.is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Rate limits of the metadata requests of the connections of a server, off
+ * by default. Every connection has a token bucket of `rate` requests per
+ * second holding `burst` of them. A request finding the bucket empty takes
+ * the next token to come and waits for it, so the requests of a connection
+ * are served in order at the rate, and a runaway client only slows itself
+ * down while the requests of the other connections go on.
+ * The requests forwarded by the other servers carry FORWARDED_REQUEST and
+ * were limited on the connection of their client. A cache node is one
+ * connection for all its clients.
+ */
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use log::warn;
+
+use crate::common::serialization::OperationType;
+
+// set on the flags of the requests forwarded by a server.
+pub const FORWARDED_REQUEST: u32 = 1;
+
+struct Bucket {
+    // negative when requests wait for the tokens to come.
+    tokens: f64,
+    last: Instant,
+    throttled: bool,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<u32, Bucket>,
+    // requests which had to wait for a token.
+    waits: AtomicU64,
+}
+
+impl RateLimiter {
+    // new limits every connection to rate requests per second and burst
+    // requests at once, a rate of 0 turns the limits off.
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            buckets: DashMap::new(),
+            waits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    // acquire waits for a token of the connection id.
+    pub async fn acquire(&self, id: u32) {
+        if !self.enabled() {
+            return;
+        }
+        let wait = {
+            let now = Instant::now();
+            let mut bucket = self.buckets.entry(id).or_insert_with(|| Bucket {
+                tokens: self.burst,
+                last: now,
+                throttled: false,
+            });
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+            bucket.last = now;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            if !bucket.throttled {
+                bucket.throttled = true;
+                warn!("connection {} is over the metadata rate limit", id);
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        };
+        self.waits.fetch_add(1, Ordering::Relaxed);
+        tokio::time::sleep(wait).await;
+    }
+
+    // remove forgets the bucket of a closed connection.
+    pub fn remove(&self, id: u32) {
+        self.buckets.remove(&id);
+    }
+
+    pub fn waits(&self) -> u64 {
+        self.waits.load(Ordering::Relaxed)
+    }
+}
+
+// is_metadata tells the requests of the clients counted by the limits.
+pub fn is_metadata(r#type: &OperationType) -> bool {
+    matches!(
+        r#type,
+        OperationType::Lookup
+            | OperationType::CreateFile
+            | OperationType::CreateDir
+            | OperationType::GetFileAttr
+            | OperationType::ReadDir
+            | OperationType::OpenFile
+            | OperationType::DeleteFile
+            | OperationType::DeleteDir
+            | OperationType::TruncateFile
+            | OperationType::SetAttr
+            | OperationType::LinkTempFile
+            | OperationType::CreateSymlink
+            | OperationType::Link
+            | OperationType::Rename
+            | OperationType::ShardDir
+            | OperationType::BatchGetAttr
+            | OperationType::ListTree
+            | OperationType::SetAttrTree
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::RateLimiter;
+
+    #[tokio::test]
+    async fn rate_limit_test() {
+        let limiter = RateLimiter::new(0, 0);
+        let start = Instant::now();
+        for _ in 0..1000 {
+            limiter.acquire(1).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(limiter.waits(), 0);
+
+        // 10 requests at once, then 1 every 10ms.
+        let limiter = RateLimiter::new(100, 10);
+        let start = Instant::now();
+        for _ in 0..15 {
+            limiter.acquire(1).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(45));
+        let waits = limiter.waits();
+        assert!((1..=5).contains(&waits));
+        // another connection has its own bucket.
+        let start = Instant::now();
+        limiter.acquire(2).await;
+        assert!(start.elapsed() < Duration::from_millis(5));
+        assert_eq!(limiter.waits(), waits);
+    }
+}
@@ -2,62 +2,274 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 
 use libc::ioctl;
 use nix::fcntl::{open, OFlag};
 use parking_lot::Mutex;
 
+use super::superblock::SuperBlock;
+
 //#define BLKGETSIZE _IO(0x12,96)	/* return device size /512 (long *arg) */
 const BLOCKGETSIZE: u64 = 0x1260;
 
 pub const CHUNK: u64 = 512 * 8;
 const SECTOR: u64 = 512;
 
+// magic number of the persisted bitmap, "SEALBMAP" in little endian.
+const BITMAP_MAGIC: u64 = 0x50414d424c414553;
+const BITMAP_HEADER_SIZE: usize = 16;
+
 pub(crate) trait Allocator {
-    fn new(path: &str) -> Self;
-    fn allocator_space(&self, lenth: u64) -> u64;
+    fn new(path: &str) -> Result<Self, i32>
+    where
+        Self: Sized;
+    fn allocator_space(&self, lenth: u64) -> Result<u64, i32>;
+    fn free(&self, begin: u64, chunk_num: u64);
 }
 
 /*
- * This Allocator use for memory.
+ * This Allocator keeps one bit for every chunk of the block device.
+ * The first chunks are reserved for the superblock, the bitmap itself and the file index.
+ * The chunks of the persisted bitmap changed since the last sync are dirty,
+ * only them are written again.
  */
 #[allow(unused)]
 pub(crate) struct BitmapAllocator {
-    block_space: Arc<Mutex<u64>>,
+    bitmap: Arc<Mutex<Vec<u64>>>,
     total_aspce: u64,
+    reserved: u64,
+    // the dirty chunks of the persisted bitmap, locked after the bitmap.
+    dirty: Mutex<BTreeSet<u64>>,
+    sync_lock: Mutex<()>,
 }
 
-impl Allocator for BitmapAllocator {
-    fn new(path: &str) -> Self {
-        let blockdevice = BlockDevice::new(path).unwrap();
-        Self {
-            block_space: Arc::new(Mutex::new(0)),
-            total_aspce: blockdevice.chunk_num,
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    pub total_chunks: u64,
+    pub free_chunks: u64,
+    pub free_extents: u64,
+    pub largest_free_extent: u64,
+}
+
+impl AllocatorStats {
+    // fragmentation is 0 when all free chunks are contiguous, and close to 1
+    // when free space is scattered in many small extents.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_chunks == 0 {
+            return 0.0;
         }
+        1.0 - self.largest_free_extent as f64 / self.free_chunks as f64
+    }
+}
+
+impl Allocator for BitmapAllocator {
+    fn new(path: &str) -> Result<Self, i32> {
+        let blockdevice = BlockDevice::new(path)?;
+        let superblock = SuperBlock::new(blockdevice.chunk_num);
+        Ok(Self::with_chunks(
+            superblock.chunk_num,
+            superblock.reserved_chunks(),
+        ))
     }
 
-    fn allocator_space(&self, lenth: u64) -> u64 {
-        // todo reduce allocatorc size.
-        // todo exent space manager.
+    fn allocator_space(&self, lenth: u64) -> Result<u64, i32> {
         let mut chunk_size = lenth / CHUNK;
         if lenth - chunk_size * CHUNK > 0 {
             chunk_size += 1;
         }
-        let mut mutex = self.block_space.lock();
-        let begin_allocator_pos = *mutex;
-        *mutex += chunk_size;
-        begin_allocator_pos
+        if chunk_size == 0 {
+            return Err(libc::EINVAL);
+        }
+        let mut bitmap = self.bitmap.lock();
+        // first fit
+        let mut begin = 0;
+        let mut run = 0;
+        for chunk in 0..self.total_aspce {
+            if is_set(&bitmap, chunk) {
+                run = 0;
+                continue;
+            }
+            if run == 0 {
+                begin = chunk;
+            }
+            run += 1;
+            if run == chunk_size {
+                for chunk in begin..begin + chunk_size {
+                    set(&mut bitmap, chunk);
+                }
+                self.mark_dirty(begin, begin + chunk_size);
+                return Ok(begin);
+            }
+        }
+        Err(libc::ENOSPC)
+    }
+
+    fn free(&self, begin: u64, chunk_num: u64) {
+        let mut bitmap = self.bitmap.lock();
+        let end = (begin + chunk_num).min(self.total_aspce);
+        for chunk in begin..end {
+            if chunk < self.reserved {
+                continue;
+            }
+            clear(&mut bitmap, chunk);
+        }
+        self.mark_dirty(begin.max(self.reserved), end);
     }
 }
 
+impl BitmapAllocator {
+    pub(crate) fn with_chunks(chunk_num: u64, reserved: u64) -> Self {
+        let reserved = reserved.min(chunk_num);
+        let mut bitmap = vec![0; chunk_num.div_ceil(64) as usize];
+        for chunk in 0..reserved {
+            set(&mut bitmap, chunk);
+        }
+        Self {
+            bitmap: Arc::new(Mutex::new(bitmap)),
+            total_aspce: chunk_num,
+            reserved,
+            dirty: Mutex::new(BTreeSet::new()),
+            sync_lock: Mutex::new(()),
+        }
+    }
+
+    // size in bytes of the persisted bitmap, including the header.
+    pub(crate) fn persist_size(&self) -> u64 {
+        bitmap_size(self.total_aspce)
+    }
+
+    pub(crate) fn dump(&self) -> Vec<u8> {
+        let bitmap = self.bitmap.lock();
+        self.persisted(&bitmap, 0, self.persist_size())
+    }
+
+    // sync writes the dirty chunks of the persisted bitmap with
+    // write(offset in the bitmap, data). The chunks not written stay dirty.
+    pub(crate) fn sync(&self, write: impl Fn(u64, &[u8]) -> Result<(), i32>) -> Result<(), i32> {
+        let _sync = self.sync_lock.lock();
+        let chunks: Vec<(u64, Vec<u8>)> = {
+            let bitmap = self.bitmap.lock();
+            let dirty = std::mem::take(&mut *self.dirty.lock());
+            dirty
+                .into_iter()
+                .map(|chunk| {
+                    let begin = chunk * CHUNK;
+                    let end = (begin + CHUNK).min(self.persist_size());
+                    (chunk, self.persisted(&bitmap, begin, end))
+                })
+                .collect()
+        };
+        for (i, (chunk, data)) in chunks.iter().enumerate() {
+            if let Err(e) = write(chunk * CHUNK, data) {
+                self.dirty
+                    .lock()
+                    .extend(chunks[i..].iter().map(|(chunk, _)| *chunk));
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // persisted returns the bytes begin..end of the persisted bitmap, a
+    // header followed by the words of the bitmap. begin is a multiple of 8.
+    fn persisted(&self, bitmap: &[u64], begin: u64, end: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity((end - begin) as usize + 8);
+        for offset in (begin..end).step_by(8) {
+            let word = match offset {
+                0 => BITMAP_MAGIC,
+                8 => self.total_aspce,
+                _ => bitmap[((offset - BITMAP_HEADER_SIZE as u64) / 8) as usize],
+            };
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.truncate((end - begin) as usize);
+        data
+    }
+
+    // mark_dirty marks the chunks of the persisted bitmap holding the bits of
+    // the chunks begin..end, with the bitmap locked.
+    fn mark_dirty(&self, begin: u64, end: u64) {
+        if begin >= end {
+            return;
+        }
+        let chunk_of = |chunk: u64| (BITMAP_HEADER_SIZE as u64 + chunk / 64 * 8) / CHUNK;
+        self.dirty
+            .lock()
+            .extend(chunk_of(begin)..=chunk_of(end - 1));
+    }
+
+    pub(crate) fn load(&self, data: &[u8]) -> Result<(), i32> {
+        if data.len() < self.persist_size() as usize {
+            return Err(libc::EINVAL);
+        }
+        let magic = u64::from_le_bytes(data[0..8].try_into().unwrap());
+        let chunk_num = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        if magic != BITMAP_MAGIC || chunk_num != self.total_aspce {
+            return Err(libc::EINVAL);
+        }
+        let mut bitmap = self.bitmap.lock();
+        for (i, word) in bitmap.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            let begin = BITMAP_HEADER_SIZE + i * 8;
+            let end = (begin + 8).min(self.persist_size() as usize);
+            bytes[..end - begin].copy_from_slice(&data[begin..end]);
+            *word = u64::from_le_bytes(bytes);
+        }
+        self.dirty.lock().clear();
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> AllocatorStats {
+        let bitmap = self.bitmap.lock();
+        let mut stats = AllocatorStats {
+            total_chunks: self.total_aspce,
+            ..Default::default()
+        };
+        let mut run = 0;
+        for chunk in 0..self.total_aspce {
+            if is_set(&bitmap, chunk) {
+                run = 0;
+                continue;
+            }
+            if run == 0 {
+                stats.free_extents += 1;
+            }
+            run += 1;
+            stats.free_chunks += 1;
+            stats.largest_free_extent = stats.largest_free_extent.max(run);
+        }
+        stats
+    }
+}
+
+// size in bytes of the persisted bitmap of a device with chunk_num chunks.
+pub(crate) fn bitmap_size(chunk_num: u64) -> u64 {
+    BITMAP_HEADER_SIZE as u64 + chunk_num.div_ceil(8)
+}
+
+#[inline]
+fn is_set(bitmap: &[u64], chunk: u64) -> bool {
+    bitmap[(chunk / 64) as usize] & (1 << (chunk % 64)) != 0
+}
+
+#[inline]
+fn set(bitmap: &mut [u64], chunk: u64) {
+    bitmap[(chunk / 64) as usize] |= 1 << (chunk % 64);
+}
+
+#[inline]
+fn clear(bitmap: &mut [u64], chunk: u64) {
+    bitmap[(chunk / 64) as usize] &= !(1 << (chunk % 64));
+}
+
 // Block device info.
-struct BlockDevice {
-    chunk_num: u64,
+pub(crate) struct BlockDevice {
+    pub(crate) chunk_num: u64,
 }
 
 impl BlockDevice {
-    fn new(path: &str) -> Result<BlockDevice, i32> {
+    pub(crate) fn new(path: &str) -> Result<BlockDevice, i32> {
         let block_num = Self::get_block_info(path)?;
         let chunk_num = block_num / (CHUNK / SECTOR);
         Ok(BlockDevice { chunk_num })
@@ -87,7 +299,7 @@ impl BlockDevice {
 mod tests {
     use std::process::Command;
 
-    use super::{Allocator, BitmapAllocator, BlockDevice};
+    use super::{Allocator, BitmapAllocator, BlockDevice, SuperBlock};
 
     #[test]
     fn block_info_test() {
@@ -127,9 +339,11 @@ mod tests {
             .arg("losetup /dev/loop8 node1")
             .output()
             .unwrap();
-        let allocator = BitmapAllocator::new("/dev/loop8");
-        let length = allocator.allocator_space(512 * 8 * 8);
-        assert_eq!(length + 8, 8);
+        let allocator = BitmapAllocator::new("/dev/loop8").unwrap();
+        let chunk_num = BlockDevice::new("/dev/loop8").unwrap().chunk_num;
+        let reserved = SuperBlock::new(chunk_num).reserved_chunks();
+        let begin = allocator.allocator_space(512 * 8 * 8).unwrap();
+        assert_eq!(begin, reserved);
         Command::new("bash")
             .arg("-c")
             .arg("losetup -d /dev/loop8")
@@ -142,3 +356,79 @@ mod tests {
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod bitmap_tests {
+    use parking_lot::Mutex;
+
+    use super::{Allocator, BitmapAllocator, CHUNK};
+
+    #[test]
+    fn allocate_and_free_test() {
+        let allocator = BitmapAllocator::with_chunks(64, 2);
+        let reserved = 2;
+        let first = allocator.allocator_space(CHUNK * 4).unwrap();
+        assert_eq!(first, reserved);
+        let second = allocator.allocator_space(CHUNK + 1).unwrap();
+        assert_eq!(second, reserved + 4);
+
+        allocator.free(first, 4);
+        let stats = allocator.stats();
+        assert_eq!(stats.free_chunks, 64 - reserved - 2);
+        assert_eq!(stats.free_extents, 2);
+        assert_eq!(stats.largest_free_extent, 64 - reserved - 6);
+
+        // the freed space is reused
+        assert_eq!(allocator.allocator_space(CHUNK).unwrap(), first);
+        assert_eq!(allocator.allocator_space(CHUNK * 64), Err(libc::ENOSPC));
+    }
+
+    #[test]
+    fn dump_and_load_test() {
+        let allocator = BitmapAllocator::with_chunks(100, 2);
+        let begin = allocator.allocator_space(CHUNK * 10).unwrap();
+        allocator.free(begin + 2, 3);
+        let data = allocator.dump();
+        assert_eq!(data.len() as u64, allocator.persist_size());
+
+        let reloaded = BitmapAllocator::with_chunks(100, 2);
+        reloaded.load(&data).unwrap();
+        assert_eq!(reloaded.stats(), allocator.stats());
+
+        let other = BitmapAllocator::with_chunks(200, 2);
+        assert_eq!(other.load(&data), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn sync_test() {
+        // a bitmap of 3 chunks and a bit.
+        let allocator = BitmapAllocator::with_chunks(CHUNK * 8 * 3 + 64, 2);
+        let device = Mutex::new(allocator.dump());
+        let writes = Mutex::new(Vec::new());
+        let write = |offset: u64, data: &[u8]| {
+            device.lock()[offset as usize..][..data.len()].copy_from_slice(data);
+            writes.lock().push(offset);
+            Ok(())
+        };
+
+        let begin = allocator.allocator_space(CHUNK * 10).unwrap();
+        allocator.free(begin, 10);
+        allocator.sync(write).unwrap();
+        assert_eq!(*writes.lock(), vec![0]);
+        allocator.sync(write).unwrap();
+        assert_eq!(writes.lock().len(), 1);
+
+        // chunks with their bits in two chunks of the bitmap, and the last ones.
+        let begin = allocator.allocator_space(CHUNK * CHUNK * 8).unwrap();
+        allocator.free(begin + CHUNK * 8 * 3, 64);
+        allocator.sync(write).unwrap();
+        assert_eq!(*writes.lock(), vec![0, 0, CHUNK, CHUNK * 3]);
+        assert_eq!(*device.lock(), allocator.dump());
+
+        // the chunks not written are written with the next sync.
+        allocator.free(begin, 1);
+        assert_eq!(allocator.sync(|_, _| Err(libc::EIO)), Err(libc::EIO));
+        allocator.sync(write).unwrap();
+        assert_eq!(*device.lock(), allocator.dump());
+    }
+}
@@ -2,16 +2,41 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+/*
+ * The file index is persisted as a log of records in the index area of the
+ * device, each a u32 length and the bincode of the record, ended by a zero
+ * length. A sync appends the changes since the last one, and rewrites the log
+ * from a snapshot of the index when the area is full.
+ */
 use dashmap::DashMap;
+use log::{error, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+const LOG_END: [u8; 4] = [0; 4];
+
+#[derive(Serialize, Deserialize)]
+enum IndexRecord {
+    Set(String, Vec<u64>),
+    Remove(String),
+}
 
 pub(crate) struct FileIndex {
     index: DashMap<String, Vec<u64>>,
+    // the records of the changes not synced, pushed with the entry locked.
+    pending: Mutex<Vec<u8>>,
+    // the end of the log in the index area, none when it must be rewritten.
+    tail: Mutex<Option<u64>>,
 }
 
 impl FileIndex {
     pub(crate) fn new() -> Self {
         let index = DashMap::new();
-        Self { index }
+        Self {
+            index,
+            pending: Mutex::new(Vec::new()),
+            tail: Mutex::new(Some(0)),
+        }
     }
 
     pub(crate) fn search(&self, file_name: &str) -> Vec<u64> {
@@ -23,12 +48,128 @@ impl FileIndex {
     }
 
     pub(crate) fn update_index(&self, path: &str, mut vec: Vec<u64>) {
-        let mut index_value_vec = self.search(path);
-        index_value_vec.append(vec.as_mut());
-        self.index.insert(path.to_string(), index_value_vec);
+        let mut entry = self.index.entry(path.to_string()).or_default();
+        entry.append(vec.as_mut());
+        self.push(&IndexRecord::Set(path.to_string(), entry.clone()));
+    }
+
+    pub(crate) fn remove(&self, path: &str) -> Vec<u64> {
+        match self.index.remove_if(path, |_, _| {
+            self.push(&IndexRecord::Remove(path.to_string()));
+            true
+        }) {
+            Some((_, vec)) => vec,
+            None => Vec::new(),
+        }
+    }
+
+    // dump returns the log of a snapshot of the index.
+    pub(crate) fn dump(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for kv in self.index.iter() {
+            append_record(
+                &mut data,
+                &IndexRecord::Set(kv.key().clone(), kv.value().clone()),
+            );
+        }
+        data.extend_from_slice(&LOG_END);
+        data
+    }
+
+    // load replays the log, a record cut by a crash ends it.
+    pub(crate) fn load(&self, data: &[u8]) -> Result<(), i32> {
+        self.index.clear();
+        let mut offset = 0;
+        loop {
+            let length = match data.get(offset..offset + 4) {
+                Some(length) => u32::from_le_bytes(length.try_into().unwrap()) as usize,
+                None => return Err(libc::EINVAL),
+            };
+            if length == 0 {
+                break;
+            }
+            let record = data
+                .get(offset + 4..offset + 4 + length)
+                .and_then(|record| bincode::deserialize(record).ok());
+            match record {
+                Some(IndexRecord::Set(path, chunks)) => {
+                    self.index.insert(path, chunks);
+                }
+                Some(IndexRecord::Remove(path)) => {
+                    self.index.remove(&path);
+                }
+                None => {
+                    warn!("file index log ends with a bad record at {}", offset);
+                    break;
+                }
+            }
+            offset += 4 + length;
+        }
+        self.pending.lock().clear();
+        *self.tail.lock() = Some(offset as u64);
+        Ok(())
+    }
+
+    // sync writes the changes since the last sync to the index area of
+    // area_size bytes with write(offset in the area, data).
+    pub(crate) fn sync(
+        &self,
+        area_size: u64,
+        write: impl Fn(u64, &[u8]) -> Result<(), i32>,
+    ) -> Result<(), i32> {
+        let mut tail = self.tail.lock();
+        let mut data = std::mem::take(&mut *self.pending.lock());
+        match *tail {
+            Some(_) if data.is_empty() => return Ok(()),
+            Some(offset) if offset + (data.len() + LOG_END.len()) as u64 <= area_size => {
+                let length = data.len() as u64;
+                data.extend_from_slice(&LOG_END);
+                // the records are lost on error, the log is rewritten by the next sync.
+                *tail = None;
+                write(offset, &data)?;
+                *tail = Some(offset + length);
+                return Ok(());
+            }
+            _ => {}
+        }
+        // the changes of the records are in the snapshot.
+        *tail = None;
+        let data = self.dump();
+        if data.len() as u64 > area_size {
+            error!("file index is larger than the index area of the device");
+            return Err(libc::ENOSPC);
+        }
+        write(0, &data)?;
+        *tail = Some((data.len() - LOG_END.len()) as u64);
+        Ok(())
+    }
+
+    // keep the first chunk_num chunks of the file and return the others.
+    pub(crate) fn truncate(&self, path: &str, chunk_num: usize) -> Vec<u64> {
+        match self.index.get_mut(path) {
+            Some(mut entry) => {
+                if entry.len() <= chunk_num {
+                    return Vec::new();
+                }
+                let chunks = entry.split_off(chunk_num);
+                self.push(&IndexRecord::Set(path.to_string(), entry.clone()));
+                chunks
+            }
+            None => Vec::new(),
+        }
+    }
+
+    fn push(&self, record: &IndexRecord) {
+        append_record(&mut self.pending.lock(), record);
     }
 }
 
+fn append_record(data: &mut Vec<u8>, record: &IndexRecord) {
+    let record = bincode::serialize(record).unwrap();
+    data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+    data.extend_from_slice(&record);
+}
+
 #[derive(Clone, Copy)]
 #[allow(unused)]
 pub(crate) struct IndexEntry {
@@ -39,6 +180,8 @@ pub(crate) struct IndexEntry {
 
 #[cfg(test)]
 mod tests {
+    use parking_lot::Mutex;
+
     use super::FileIndex;
 
     #[test]
@@ -51,5 +194,64 @@ mod tests {
         index.update_index("test", vec);
         let mut vec = index.search("test");
         assert_eq!(vec.pop(), Some(1));
+
+        let data = index.dump();
+        let reloaded = FileIndex::new();
+        reloaded.load(&data).unwrap();
+        assert_eq!(reloaded.search("test"), vec![1]);
+    }
+
+    #[test]
+    fn sync_test() {
+        let area_size = 256;
+        let index = FileIndex::new();
+        let device = Mutex::new(index.dump());
+        device.lock().resize(area_size, 0xff);
+        let writes = Mutex::new(Vec::new());
+        let write = |offset: u64, data: &[u8]| {
+            device.lock()[offset as usize..][..data.len()].copy_from_slice(data);
+            writes.lock().push(offset);
+            Ok(())
+        };
+        let reload = || {
+            let reloaded = FileIndex::new();
+            reloaded.load(&device.lock()).unwrap();
+            reloaded
+        };
+
+        // the changes are appended to the log.
+        index.update_index("a", vec![1, 2]);
+        index.update_index("b", vec![3]);
+        index.sync(area_size as u64, write).unwrap();
+        index.update_index("a", vec![4]);
+        assert_eq!(index.truncate("a", 1), vec![2, 4]);
+        assert_eq!(index.remove("b"), vec![3]);
+        index.sync(area_size as u64, write).unwrap();
+        index.sync(area_size as u64, write).unwrap();
+        assert_eq!(writes.lock().len(), 2);
+        assert!(writes.lock()[1] > 0);
+        let reloaded = reload();
+        assert_eq!(reloaded.search("a"), vec![1]);
+        assert!(reloaded.search("b").is_empty());
+
+        // the log is rewritten when the area is full.
+        for i in 0..10 {
+            index.update_index("c", vec![i]);
+            index.sync(area_size as u64, write).unwrap();
+        }
+        assert_eq!(*writes.lock().last().unwrap(), 0);
+        assert_eq!(reload().search("c"), (0..10).collect::<Vec<_>>());
+
+        // the changes not written are written with the next sync.
+        index.update_index("d", vec![5]);
+        assert_eq!(
+            index.sync(area_size as u64, |_, _| Err(libc::EIO)),
+            Err(libc::EIO)
+        );
+        index.sync(area_size as u64, write).unwrap();
+        assert_eq!(reload().search("d"), vec![5]);
+
+        index.update_index("e", vec![0; 64]);
+        assert_eq!(index.sync(area_size as u64, write), Err(libc::ENOSPC));
     }
 }
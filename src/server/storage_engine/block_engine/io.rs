@@ -2,6 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use log::error;
 use nix::{
     fcntl::{self, OFlag},
     sys::{
@@ -10,12 +11,15 @@ use nix::{
     },
 };
 
+//#define BLKDISCARD _IO(0x12,119)
+const BLOCKDISCARD: u64 = 0x1277;
+
 pub(crate) struct Storage {
     _fd: i32,
 }
 
 impl Storage {
-    pub(crate) fn new(path: &str) -> Storage {
+    pub(crate) fn new(path: &str) -> Result<Storage, i32> {
         let oflags = OFlag::O_RDWR;
         let mode = Mode::S_IRUSR
             | Mode::S_IWUSR
@@ -25,23 +29,53 @@ impl Storage {
             | Mode::S_IWOTH;
         let fd = fcntl::open(path, oflags, mode);
         match fd {
-            Ok(fd) => Self { _fd: fd },
-            Err(_) => panic!("No Raw blockdevice"),
+            Ok(fd) => Ok(Self { _fd: fd }),
+            Err(e) => {
+                error!("open block device {} error: {}", path, e);
+                Err(e as i32)
+            }
         }
     }
 
-    pub(crate) fn _write(&self, data: &[u8], offset: i64) -> Result<usize, i32> {
+    pub(crate) fn write(&self, data: &[u8], offset: i64) -> Result<usize, i32> {
         match pwrite(self._fd, data, offset) {
             Ok(size) => Ok(size),
             Err(_) => Err(libc::EIO),
         }
     }
 
-    pub(crate) fn _read(&self, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
+    pub(crate) fn read(&self, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
         let mut data = vec![0; size as usize];
         let length = pread(self._fd, data.as_mut_slice(), offset).map_err(|_| libc::EIO)?;
         Ok(data[..length].to_vec())
     }
+
+    // discard tells the device that the range is no longer used, so SSDs and
+    // thin-provisioned devices can reclaim it.
+    pub(crate) fn discard(&self, offset: u64, length: u64) -> Result<(), i32> {
+        let range: [u64; 2] = [offset, length];
+        let result = unsafe { libc::ioctl(self._fd, BLOCKDISCARD, &range) };
+        if result < 0 {
+            return Err(nix::errno::errno());
+        }
+        Ok(())
+    }
+
+    // prefetch asks the kernel to read the range into the page cache.
+    pub(crate) fn prefetch(&self, offset: u64, length: u64) -> Result<(), i32> {
+        let result = unsafe {
+            libc::posix_fadvise(
+                self._fd,
+                offset as i64,
+                length as i64,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if result != 0 {
+            return Err(result);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(feature = "block_test")]
@@ -49,7 +83,7 @@ impl Storage {
 mod tests {
     use std::process::Command;
 
-    use crate::server::storage_engine::block_device::io::Storage;
+    use crate::server::storage_engine::block_engine::io::Storage;
     #[test]
     fn write_and_read_test() {
         Command::new("bash")
@@ -62,7 +96,7 @@ mod tests {
             .arg("losetup /dev/loop8 node1")
             .output()
             .unwrap();
-        let storage = Storage::new("/dev/loop8");
+        let storage = Storage::new("/dev/loop8").unwrap();
         let writre_result = storage.write(&b"some bytes"[..], 0).unwrap();
         assert_eq!(writre_result, 10);
         let read_result = storage.read(10, 0).unwrap();
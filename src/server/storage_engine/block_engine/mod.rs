@@ -8,68 +8,98 @@ pub mod allocator;
 */
 pub mod index;
 pub mod io;
+pub mod superblock;
 
 use std::sync::Arc;
 
+use log::{error, info};
+
+use crate::common::errors::status_to_string;
 use crate::server::storage_engine::StorageEngine;
 
-use allocator::{Allocator, BitmapAllocator, CHUNK};
+use allocator::{Allocator, AllocatorStats, BitmapAllocator, BlockDevice, CHUNK};
 use index::FileIndex;
 use io::Storage;
+use superblock::SuperBlock;
 
 use super::meta_engine::MetaEngine;
 
 #[allow(unused)]
 pub struct BlockEngine {
+    superblock: SuperBlock,
     allocator: BitmapAllocator,
     index: FileIndex,
     storage: Storage,
 }
 
 impl StorageEngine for BlockEngine {
-    fn new(root: &str, _meta: Arc<MetaEngine>) -> Self {
+    fn new(root: &str, _meta: Arc<MetaEngine>) -> Result<Self, i32> {
         let index = FileIndex::new();
-        let storage = Storage::new(root);
-        let allocator = BitmapAllocator::new(root);
-        Self {
+        let storage = Storage::new(root)?;
+        let device = BlockDevice::new(root)?;
+        let superblock = storage
+            .read(to_u32(CHUNK)?, 0)
+            .and_then(|data| SuperBlock::from_bytes(&data))
+            .map_err(|e| {
+                error!("read superblock of {} error: {}", root, status_to_string(e));
+                e
+            })?;
+        if let Err(e) = superblock.validate(device.chunk_num) {
+            error!(
+                "{} is not a valid sealfs block device: {}, run `mkfs {}` first",
+                root,
+                status_to_string(e),
+                root
+            );
+            return Err(e);
+        }
+        let allocator =
+            BitmapAllocator::with_chunks(superblock.chunk_num, superblock.reserved_chunks());
+        Ok(Self {
+            superblock,
             allocator,
             index,
             storage,
-        }
+        })
     }
 
-    fn init(&self) {}
+    // reload the allocator and the index written by mkfs or the last run.
+    fn init(&self) -> Result<(), i32> {
+        let data = self.storage.read(
+            to_u32(self.allocator.persist_size())?,
+            self.allocator_offset(),
+        )?;
+        self.allocator.load(&data).map_err(|e| {
+            error!("load allocator bitmap error: {}", status_to_string(e));
+            e
+        })?;
+        let data = self
+            .storage
+            .read(to_u32(self.index_size())?, self.index_offset())?;
+        self.index.load(&data).map_err(|e| {
+            error!("load file index error: {}", status_to_string(e));
+            e
+        })?;
+        info!(
+            "block device reloaded, version: {}, chunks: {}",
+            self.superblock.version, self.superblock.chunk_num
+        );
+        Ok(())
+    }
 
-    fn read_file(&self, path: &str, _size: u32, offset: i64) -> Result<Vec<u8>, i32> {
-        let index_vec = self.index.search(path);
-        let real_offset_index = offset as u64 / CHUNK;
-        let real_offset = index_vec.get(real_offset_index as usize);
-        match real_offset {
-            Some(_real_offset) => todo!(), // self.storage.read(size, *real_offset as i64),
-            None => todo!(),               // Err(libc::EIO),
-        }
+    // the data path of the block engine is not written yet, the engine is
+    // not registered for the servers until it is.
+    fn read_file(&self, _path: &str, _size: u32, _offset: i64) -> Result<Vec<u8>, i32> {
+        Err(libc::EOPNOTSUPP)
     }
 
     fn open_file(&self, _path: &str, _flag: i32, _mode: u32) -> Result<(), i32> {
-        todo!()
+        Err(libc::EOPNOTSUPP)
     }
 
-    fn write_file(&self, path: &str, data: &[u8], _offset: i64) -> Result<usize, i32> {
-        let pos = self.allocator.allocator_space(data.len() as u64);
-        let index_value_vec = self.index.search(path);
-        let mut vec = Vec::new();
-        let mut length = (data.len() as u64) / CHUNK;
-        if data.len() as u64 - length * CHUNK > 0 {
-            length += 1;
-        }
-        for n in 0..length {
-            vec.push(pos + n * CHUNK);
-        }
-        self.index.update_index(path, vec);
-        match index_value_vec.last() {
-            Some(_last) => todo!(), // self.storage.write(data, (last + pos) as i64),
-            None => todo!(),        // self.storage.write(data, pos as i64),
-        }
+    // nothing is allocated for a write the engine cannot do.
+    fn write_file(&self, _path: &str, _data: &[u8], _offset: i64) -> Result<usize, i32> {
+        Err(libc::EOPNOTSUPP)
     }
 
     fn create_file(
@@ -79,27 +109,192 @@ impl StorageEngine for BlockEngine {
         _umask: u32,
         _mode: u32,
     ) -> Result<Vec<u8>, i32> {
-        todo!()
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn delete_file(&self, path: &str) -> Result<(), i32> {
+        let chunks = self.index.remove(path);
+        self.free_chunks(&chunks)?;
+        self.sync_index()
+    }
+
+    fn truncate_file(&self, path: &str, length: i64) -> Result<(), i32> {
+        if length < 0 {
+            return Err(libc::EINVAL);
+        }
+        let chunk_num = (length as u64).div_ceil(CHUNK);
+        let chunks = self.index.truncate(path, chunk_num as usize);
+        self.free_chunks(&chunks)?;
+        self.sync_index()
+    }
+
+    fn prefetch_file(&self, path: &str) -> Result<u64, i32> {
+        let chunks = self.index.search(path);
+        for (begin, chunk_num) in extents(&chunks) {
+            self.storage.prefetch(begin * CHUNK, chunk_num * CHUNK)?;
+        }
+        Ok(chunks.len() as u64 * CHUNK)
+    }
+
+    fn begin_upload(&self, _path: &str) -> Result<u64, i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn upload_part(
+        &self,
+        _path: &str,
+        _upload_id: u64,
+        _offset: u64,
+        _data: &[u8],
+    ) -> Result<(), i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn complete_upload(&self, _path: &str, _upload_id: u64, _size: u64) -> Result<(), i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn disk_space(&self) -> Option<Result<(u64, u64), i32>> {
+        let stats = self.allocator.stats();
+        Some(Ok((stats.free_chunks * CHUNK, stats.total_chunks * CHUNK)))
+    }
+
+    fn allocator_stats(&self) -> Option<AllocatorStats> {
+        Some(self.allocator.stats())
+    }
+
+    // the chunks of a new file are reserved in one extent.
+    fn preallocate(&self, path: &str, size: u64) -> Result<(), i32> {
+        if size == 0 || !self.index.search(path).is_empty() {
+            return Ok(());
+        }
+        let pos = self.allocator.allocator_space(size)?;
+        self.index
+            .update_index(path, (pos..pos + size.div_ceil(CHUNK)).collect());
+        self.sync_allocator()?;
+        self.sync_index()
+    }
+}
+
+impl BlockEngine {
+    // mkfs formats the block device: it writes a new superblock, an empty
+    // allocator bitmap and an empty file index.
+    pub fn mkfs(path: &str) -> Result<SuperBlock, i32> {
+        let device = BlockDevice::new(path)?;
+        let superblock = SuperBlock::new(device.chunk_num);
+        superblock.validate(device.chunk_num)?;
+        let storage = Storage::new(path)?;
+        let allocator = BitmapAllocator::new(path)?;
+        storage.write(
+            &allocator.dump(),
+            (superblock.allocator_offset * CHUNK) as i64,
+        )?;
+        storage.write(
+            &FileIndex::new().dump(),
+            (superblock.index_offset * CHUNK) as i64,
+        )?;
+        // the superblock is written last, so a device is only valid after mkfs succeeds.
+        storage.write(&superblock.to_bytes(), 0)?;
+        Ok(superblock)
+    }
+
+    fn free_chunks(&self, chunks: &[u64]) -> Result<(), i32> {
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        for chunk in chunks {
+            self.allocator.free(*chunk, 1);
+        }
+        self.sync_allocator()?;
+        for (begin, chunk_num) in extents(chunks) {
+            if let Err(e) = self.storage.discard(begin * CHUNK, chunk_num * CHUNK) {
+                // discard is only a hint, devices without support are still usable.
+                if e != libc::EOPNOTSUPP {
+                    error!("discard chunks error: {}", status_to_string(e));
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn allocator_offset(&self) -> i64 {
+        (self.superblock.allocator_offset * CHUNK) as i64
+    }
+
+    fn index_offset(&self) -> i64 {
+        (self.superblock.index_offset * CHUNK) as i64
+    }
+
+    fn index_size(&self) -> u64 {
+        self.superblock.index_chunks * CHUNK
     }
 
-    fn delete_file(&self, _path: &str) -> Result<(), i32> {
-        todo!()
+    // sync_allocator writes the chunks of the bitmap changed since the last sync.
+    fn sync_allocator(&self) -> Result<(), i32> {
+        self.allocator.sync(|offset, data| {
+            self.storage
+                .write(data, self.allocator_offset() + offset as i64)
+                .map(|_| ())
+        })
     }
 
-    fn truncate_file(&self, _path: &str, _length: i64) -> Result<(), i32> {
-        todo!()
+    // sync_index appends the changes of the index to its log.
+    fn sync_index(&self) -> Result<(), i32> {
+        self.index.sync(self.index_size(), |offset, data| {
+            self.storage
+                .write(data, self.index_offset() + offset as i64)
+                .map(|_| ())
+        })
+    }
+}
+
+// to_u32 checks the size of a read of the device.
+fn to_u32(size: u64) -> Result<u32, i32> {
+    u32::try_from(size).map_err(|_| {
+        error!("read of {} bytes is too large", size);
+        libc::EFBIG
+    })
+}
+
+// extents merges chunk numbers into (begin, chunk_num) ranges of contiguous chunks.
+fn extents(chunks: &[u64]) -> Vec<(u64, u64)> {
+    let mut chunks = chunks.to_vec();
+    chunks.sort_unstable();
+    chunks.dedup();
+    let mut extents: Vec<(u64, u64)> = Vec::new();
+    for chunk in chunks {
+        match extents.last_mut() {
+            Some((begin, chunk_num)) if *begin + *chunk_num == chunk => *chunk_num += 1,
+            _ => extents.push((chunk, 1)),
+        }
+    }
+    extents
+}
+
+#[cfg(test)]
+mod extent_tests {
+    use super::extents;
+
+    #[test]
+    fn extents_test() {
+        assert_eq!(extents(&[]), vec![]);
+        assert_eq!(extents(&[7, 3, 4, 5, 9, 8, 4]), vec![(3, 3), (7, 3)]);
     }
 }
 
 #[cfg(feature = "block_test")]
 #[cfg(test)]
 mod tests {
-    use crate::server::storage_engine::StorageEngine;
+    use std::{process::Command, sync::Arc};
 
     use super::BlockEngine;
-    use std::process::Command;
+    use crate::server::storage_engine::{
+        meta_engine::MetaEngine, meta_store::MemStore, StorageEngine,
+    };
+
     #[test]
-    fn write_and_read_test() {
+    fn mkfs_and_reload_test() {
         Command::new("bash")
             .arg("-c")
             .arg("dd if=/dev/zero of=node1 bs=4M count=1")
@@ -110,13 +305,23 @@ mod tests {
             .arg("losetup /dev/loop8 node1")
             .output()
             .unwrap();
-        let engine = BlockEngine::new("", "/dev/loop8");
-        let write_size = engine
-            .write_file("test".to_string(), &b"some bytes"[..], 0)
-            .unwrap();
-        assert_eq!(write_size, 10);
-        let read = engine.read_file("test".to_string(), 10, 0).unwrap();
-        assert_eq!(read, &b"some bytes"[..]);
+        let meta_engine = Arc::new(MetaEngine::with_store(Box::new(MemStore::new())));
+        // a device is only valid after mkfs.
+        assert!(BlockEngine::new("/dev/loop8", meta_engine.clone()).is_err());
+        BlockEngine::mkfs("/dev/loop8").unwrap();
+        let engine = BlockEngine::new("/dev/loop8", meta_engine.clone()).unwrap();
+        engine.init().unwrap();
+        engine.preallocate("test", 10).unwrap();
+        let free_chunks = engine.allocator_stats().unwrap().free_chunks;
+        assert_eq!(
+            engine.write_file("test", &b"some bytes"[..], 0),
+            Err(libc::EOPNOTSUPP)
+        );
+
+        // the allocator and the index of the last run are reloaded.
+        let engine = BlockEngine::new("/dev/loop8", meta_engine).unwrap();
+        engine.init().unwrap();
+        assert_eq!(engine.allocator_stats().unwrap().free_chunks, free_chunks);
         Command::new("bash")
             .arg("-c")
             .arg("losetup -d /dev/loop8")
@@ -0,0 +1,114 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * On-disk layout of a block device formatted by `sealfs mkfs`:
+ *
+ * | superblock (1 chunk) | allocator bitmap | file index | data chunks ... |
+ *
+ * All offsets in the superblock are in chunks.
+ */
+use serde::{Deserialize, Serialize};
+
+use super::allocator::{bitmap_size, CHUNK};
+
+// "SEALBLKD" in little endian.
+pub const SUPERBLOCK_MAGIC: u64 = 0x444b4c424c414553;
+pub const FORMAT_VERSION: u32 = 1;
+
+// one chunk of index area for every INDEX_RATIO data chunks, at least one chunk.
+const INDEX_RATIO: u64 = 256;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SuperBlock {
+    pub magic: u64,
+    pub version: u32,
+    pub chunk_size: u64,
+    pub chunk_num: u64,
+    pub allocator_offset: u64,
+    pub allocator_chunks: u64,
+    pub index_offset: u64,
+    pub index_chunks: u64,
+}
+
+impl SuperBlock {
+    pub fn new(chunk_num: u64) -> Self {
+        let allocator_offset = 1;
+        let allocator_chunks = bitmap_size(chunk_num).div_ceil(CHUNK);
+        let index_offset = allocator_offset + allocator_chunks;
+        let index_chunks = (chunk_num / INDEX_RATIO).max(1);
+        Self {
+            magic: SUPERBLOCK_MAGIC,
+            version: FORMAT_VERSION,
+            chunk_size: CHUNK,
+            chunk_num,
+            allocator_offset,
+            allocator_chunks,
+            index_offset,
+            index_chunks,
+        }
+    }
+
+    // chunks used by the superblock, the allocator and the index.
+    pub fn reserved_chunks(&self) -> u64 {
+        self.index_offset + self.index_chunks
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = bincode::serialize(self).unwrap();
+        data.resize(CHUNK as usize, 0);
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, i32> {
+        bincode::deserialize(data).map_err(|_| libc::EINVAL)
+    }
+
+    // validate checks that the superblock was written by mkfs and matches the device.
+    pub fn validate(&self, chunk_num: u64) -> Result<(), i32> {
+        if self.magic != SUPERBLOCK_MAGIC {
+            return Err(libc::EINVAL);
+        }
+        if self.version != FORMAT_VERSION {
+            return Err(libc::EPROTO);
+        }
+        if self.chunk_size != CHUNK || self.chunk_num > chunk_num {
+            return Err(libc::EINVAL);
+        }
+        if self.reserved_chunks() > self.chunk_num {
+            return Err(libc::ENOSPC);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SuperBlock, FORMAT_VERSION};
+
+    #[test]
+    fn superblock_test() {
+        let superblock = SuperBlock::new(8192);
+        assert_eq!(superblock.allocator_offset, 1);
+        assert_eq!(superblock.allocator_chunks, 1);
+        assert_eq!(superblock.index_offset, 2);
+        assert_eq!(superblock.index_chunks, 32);
+        assert_eq!(superblock.reserved_chunks(), 34);
+
+        let data = superblock.to_bytes();
+        let reloaded = SuperBlock::from_bytes(&data).unwrap();
+        assert_eq!(reloaded, superblock);
+        assert_eq!(reloaded.validate(8192), Ok(()));
+        assert_eq!(reloaded.validate(4096), Err(libc::EINVAL));
+
+        let mut old = superblock;
+        old.version = FORMAT_VERSION + 1;
+        assert_eq!(old.validate(8192), Err(libc::EPROTO));
+
+        assert!(SuperBlock::from_bytes(&[0; 4096])
+            .unwrap()
+            .validate(8192)
+            .is_err());
+    }
+}
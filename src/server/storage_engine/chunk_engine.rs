@@ -0,0 +1,696 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Content-addressed chunk storage, selected with `--storage chunk:<dir>`.
+ * The data of a file is cut in chunks of CHUNK_SIZE bytes, each kept once in
+ * `<dir>/chunks` under the hash of its content, so the files sharing data,
+ * e.g. the checkpoints of a model differing slightly between epochs or the
+ * copies of a file, share its chunks. The hash is not cryptographic: a chunk
+ * is only shared after comparing its bytes, and the chunks of different
+ * content with the same hash get distinct names.
+ * The manifest of a file, in `<dir>/files`, lists its chunks and its size and
+ * is replaced atomically on every write. The chunks count their references
+ * from the manifests, rebuilt on init, and the chunks nobody references are
+ * removed by a gc pass on init and once GC_THRESHOLD chunks are released, the
+ * chunks left by a crash too.
+ */
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::unix::fs::FileExt,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use dashmap::DashMap;
+use log::{debug, error, info};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::file_engine::generate_local_file_name;
+use super::meta_engine::MetaEngine;
+use super::StorageEngine;
+use crate::common::{
+    errors::status_to_string, health::disk_space, serialization::FILE_FLAG_APPEND_ONLY,
+    util::empty_file,
+};
+
+pub const CHUNK_SIZE: u64 = 1 << 20;
+// a gc pass runs once this many chunks lost their last reference.
+const GC_THRESHOLD: u64 = 1024;
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+struct Manifest {
+    path: String,
+    size: u64,
+    // the name of the chunk of every CHUNK_SIZE bytes, None for a hole.
+    chunks: Vec<Option<String>>,
+}
+
+pub struct ChunkEngine {
+    meta_engine: Arc<MetaEngine>,
+    root: String,
+    // path -> its manifest, locked during a write.
+    files: DashMap<String, Arc<Mutex<Manifest>>>,
+    // chunk name -> the references of the manifests.
+    refs: DashMap<String, u64>,
+    // the chunks released since the last gc pass.
+    released: AtomicU64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ChunkStats {
+    pub chunks: u64,
+    // the references to the chunks, more than the chunks when they are shared.
+    pub references: u64,
+}
+
+impl StorageEngine for ChunkEngine {
+    fn new(root: &str, meta_engine: Arc<MetaEngine>) -> Result<Self, i32> {
+        for dir in ["chunks", "files"] {
+            let dir = format!("{}/{}", root, dir);
+            if !Path::new(&dir).exists() {
+                info!("chunk path {} does not exist, creating it", dir);
+                std::fs::create_dir_all(&dir).map_err(|e| {
+                    error!("create chunk path {} error: {}", dir, e);
+                    e.raw_os_error().unwrap_or(libc::EIO)
+                })?;
+            }
+        }
+        Ok(Self {
+            meta_engine,
+            root: root.to_string(),
+            files: DashMap::new(),
+            refs: DashMap::new(),
+            released: AtomicU64::new(0),
+        })
+    }
+
+    fn init(&self) -> Result<(), i32> {
+        self.fsck()?;
+        self.meta_engine.init();
+        match self.gc() {
+            Ok((chunks, bytes)) => info!(
+                "chunk engine reloaded {} files, {} chunks, removed {} chunks of {} bytes",
+                self.files.len(),
+                self.refs.len(),
+                chunks,
+                bytes
+            ),
+            Err(e) => error!("gc chunks error: {}", status_to_string(e)),
+        }
+        Ok(())
+    }
+
+    fn read_file(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
+        fault_point!("chunk_engine.read_file", path);
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        let file = self.manifest(path)?;
+        let manifest = file.lock();
+        let (offset, end) = (
+            offset as u64,
+            manifest.size.min(offset as u64 + size as u64),
+        );
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let (index, begin) = ((pos / CHUNK_SIZE) as usize, pos % CHUNK_SIZE);
+            let length = (CHUNK_SIZE - begin).min(end - pos) as usize;
+            let filled = data.len();
+            if let Some(Some(name)) = manifest.chunks.get(index) {
+                data.extend(self.read_chunk(name, begin, length)?);
+            }
+            // the holes and the end of a short chunk read as zeros.
+            data.resize(filled + length, 0);
+            pos += length as u64;
+        }
+        debug!(
+            "read_file path: {}, size: {}, offset: {}, data_length: {}",
+            path,
+            size,
+            offset,
+            data.len()
+        );
+        Ok(data)
+    }
+
+    fn open_file(&self, path: &str, _flag: i32, _mode: u32) -> Result<(), i32> {
+        fault_point!("chunk_engine.open_file", path);
+        self.manifest(path).map(|_| ())
+    }
+
+    fn write_file(&self, path: &str, data: &[u8], offset: i64) -> Result<usize, i32> {
+        fault_point!("chunk_engine.write_file", path);
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        // the server writes at the end of an append-only file.
+        if self.meta_engine.get_file_attr(path)?.flags & FILE_FLAG_APPEND_ONLY != 0 {
+            return self.append_file(path, data).map(|(_, size)| size);
+        }
+        let file = self.manifest(path)?;
+        let mut manifest = file.lock();
+        self.write_chunks(&mut manifest, path, data, offset as u64)?;
+        drop(manifest);
+        self.maybe_gc();
+        Ok(data.len())
+    }
+
+    fn append_file(&self, path: &str, data: &[u8]) -> Result<(u64, usize), i32> {
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        // the lock of the manifest keeps the appends apart.
+        let file = self.manifest(path)?;
+        let mut manifest = file.lock();
+        let offset = self.meta_engine.get_file_attr(path)?.size;
+        self.write_chunks(&mut manifest, path, data, offset)?;
+        drop(manifest);
+        self.maybe_gc();
+        Ok((offset, data.len()))
+    }
+
+    fn create_file(
+        &self,
+        path: &str,
+        _oflag: i32,
+        _umask: u32,
+        _mode: u32,
+    ) -> Result<Vec<u8>, i32> {
+        fault_point!("chunk_engine.create_file", path);
+        let manifest_file_name = generate_local_file_name(&self.manifest_dir(), path);
+        let file = self
+            .files
+            .entry(path.to_owned())
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Manifest {
+                    path: path.to_owned(),
+                    ..Default::default()
+                }))
+            })
+            .clone();
+        self.save_manifest(&file.lock())?;
+        self.meta_engine
+            .create_file(empty_file(), &manifest_file_name, path)
+    }
+
+    fn delete_file(&self, path: &str) -> Result<(), i32> {
+        fault_point!("chunk_engine.delete_file", path);
+        let manifest_file_name = generate_local_file_name(&self.manifest_dir(), path);
+        if let Some((_, file)) = self.files.remove(path) {
+            let mut manifest = file.lock();
+            for chunk in std::mem::take(&mut manifest.chunks).into_iter().flatten() {
+                self.release(&chunk);
+            }
+        }
+        if let Err(e) = std::fs::remove_file(&manifest_file_name) {
+            error!("delete file error: {:?}", e);
+            return Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        self.meta_engine.delete_file(&manifest_file_name, path)?;
+        self.maybe_gc();
+        Ok(())
+    }
+
+    fn truncate_file(&self, path: &str, length: i64) -> Result<(), i32> {
+        fault_point!("chunk_engine.truncate_file", path);
+        if length < 0 {
+            return Err(libc::EINVAL);
+        }
+        let length = length as u64;
+        let file = self.manifest(path)?;
+        let mut manifest = file.lock();
+        let chunk_num = length.div_ceil(CHUNK_SIZE) as usize;
+        if manifest.chunks.len() > chunk_num {
+            for chunk in manifest.chunks.split_off(chunk_num).into_iter().flatten() {
+                self.release(&chunk);
+            }
+        }
+        // the end of the last chunk must read as zeros if the file grows again.
+        let tail = (length % CHUNK_SIZE) as usize;
+        if let (true, Some(Some(name))) = (tail > 0, manifest.chunks.get(chunk_num - 1)) {
+            let chunk = self.read_chunk(name, 0, tail)?;
+            self.set_chunk(&mut manifest, chunk_num - 1, &chunk)?;
+        }
+        manifest.size = length;
+        self.save_manifest(&manifest)?;
+        drop(manifest);
+        self.maybe_gc();
+        self.meta_engine.truncate(path, length)
+    }
+
+    // the chunks are read on demand, only the size is known ahead.
+    fn prefetch_file(&self, path: &str) -> Result<u64, i32> {
+        fault_point!("chunk_engine.prefetch_file", path);
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        let size = self.manifest(path)?.lock().size;
+        Ok(size)
+    }
+
+    fn begin_upload(&self, _path: &str) -> Result<u64, i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn upload_part(
+        &self,
+        _path: &str,
+        _upload_id: u64,
+        _offset: u64,
+        _data: &[u8],
+    ) -> Result<(), i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn complete_upload(&self, _path: &str, _upload_id: u64, _size: u64) -> Result<(), i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    fn disk_space(&self) -> Option<Result<(u64, u64), i32>> {
+        Some(disk_space(&self.root))
+    }
+
+    // the holes are the chunks never written, a chunk is data as a whole.
+    fn seek_data(&self, path: &str, offset: u64, size: u64, hole: bool) -> Result<u64, i32> {
+        if offset >= size {
+            return Err(libc::ENXIO);
+        }
+        let file = self.manifest(path)?;
+        let manifest = file.lock();
+        let first = (offset / CHUNK_SIZE) as usize;
+        let found = (first..size.div_ceil(CHUNK_SIZE) as usize)
+            .find(|&index| matches!(manifest.chunks.get(index), Some(Some(_))) != hole);
+        match found {
+            Some(index) => Ok((index as u64 * CHUNK_SIZE).max(offset).min(size)),
+            None if hole => Ok(size),
+            None => Err(libc::ENXIO),
+        }
+    }
+
+    fn sync_file(&self, path: &str, datasync: bool) -> Result<(), i32> {
+        let file = self.manifest(path)?;
+        let manifest = file.lock();
+        let mut file_names: Vec<String> = manifest
+            .chunks
+            .iter()
+            .flatten()
+            .map(|name| self.chunk_file_name(name))
+            .collect();
+        file_names.push(generate_local_file_name(&self.manifest_dir(), path));
+        for file_name in file_names {
+            let result = File::open(&file_name).and_then(|f| match datasync {
+                true => f.sync_data(),
+                false => f.sync_all(),
+            });
+            if let Err(e) = result {
+                error!("sync {} error: {:?}", file_name, e);
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_file(&self, path: &str, new_path: &str) -> Result<(), i32> {
+        fault_point!("chunk_engine.rename_file", path);
+        let manifest_file_name = generate_local_file_name(&self.manifest_dir(), path);
+        let new_manifest_file_name = generate_local_file_name(&self.manifest_dir(), new_path);
+        let file = self.manifest(path)?;
+        let mut manifest = file.lock();
+        manifest.path = new_path.to_owned();
+        if let Err(e) = self.save_manifest(&manifest) {
+            manifest.path = path.to_owned();
+            return Err(e);
+        }
+        if let Err(e) = self.meta_engine.rename_file(
+            &manifest_file_name,
+            &new_manifest_file_name,
+            path,
+            new_path,
+        ) {
+            manifest.path = path.to_owned();
+            let _ = std::fs::remove_file(&new_manifest_file_name);
+            return Err(e);
+        }
+        let _ = std::fs::remove_file(&manifest_file_name);
+        drop(manifest);
+        self.files.remove(path);
+        self.files.insert(new_path.to_owned(), file);
+        Ok(())
+    }
+}
+
+impl ChunkEngine {
+    // gc removes the chunks without references, it returns the number of
+    // chunks removed and their bytes.
+    pub fn gc(&self) -> Result<(u64, u64), i32> {
+        self.released.store(0, Ordering::Relaxed);
+        let (mut chunks, mut bytes, mut status) = (0, 0, 0);
+        // a shard of the references stays locked while its chunks are
+        // removed, so a chunk cannot be shared again meanwhile.
+        self.refs.retain(|name, refs| {
+            if *refs > 0 {
+                return true;
+            }
+            let chunk_file_name = self.chunk_file_name(name);
+            let size = std::fs::metadata(&chunk_file_name).map_or(0, |m| m.len());
+            match std::fs::remove_file(&chunk_file_name) {
+                Ok(_) => {
+                    chunks += 1;
+                    bytes += size;
+                    false
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+                Err(e) => {
+                    error!("remove chunk {} error: {:?}", name, e);
+                    status = e.raw_os_error().unwrap_or(libc::EIO);
+                    true
+                }
+            }
+        });
+        debug!("gc removed {} chunks of {} bytes", chunks, bytes);
+        match status {
+            0 => Ok((chunks, bytes)),
+            e => Err(e),
+        }
+    }
+
+    pub fn stats(&self) -> ChunkStats {
+        self.refs
+            .iter()
+            .fold(ChunkStats::default(), |mut stats, refs| {
+                stats.chunks += 1;
+                stats.references += *refs;
+                stats
+            })
+    }
+
+    // fsck drops the manifests of the files without attrs, reloads the
+    // others and counts the references to the chunks on disk.
+    fn fsck(&self) -> Result<(), i32> {
+        let chunks = std::fs::read_dir(format!("{}/chunks", self.root)).map_err(|e| {
+            error!("read dir error: {:?}", e);
+            libc::EIO
+        })?;
+        for entry in chunks {
+            let entry = entry.map_err(|_| libc::EIO)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            self.refs.insert(name, 0);
+        }
+
+        let manifest_dir = self.manifest_dir();
+        let manifests = std::fs::read_dir(&manifest_dir).map_err(|e| {
+            error!("read dir error: {:?}", e);
+            libc::EIO
+        })?;
+        for entry in manifests {
+            let entry = entry.map_err(|_| libc::EIO)?;
+            let file_name = format!("{}/{}", manifest_dir, entry.file_name().to_string_lossy());
+            if !self.meta_engine.check_file(&file_name) {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+            let manifest: Manifest = match std::fs::read(&file_name)
+                .ok()
+                .and_then(|data| bincode::deserialize(&data).ok())
+            {
+                Some(manifest) => manifest,
+                None => {
+                    error!("manifest {} is corrupted", file_name);
+                    return Err(libc::EIO);
+                }
+            };
+            let mut counts = HashMap::new();
+            for chunk in manifest.chunks.iter().flatten() {
+                *counts.entry(chunk.clone()).or_insert(0) += 1;
+            }
+            for (chunk, count) in counts {
+                match self.refs.get_mut(&chunk) {
+                    Some(mut refs) => *refs += count,
+                    None => {
+                        error!("chunk {} of {} is missing", chunk, manifest.path);
+                        return Err(libc::EIO);
+                    }
+                }
+            }
+            self.files
+                .insert(manifest.path.clone(), Arc::new(Mutex::new(manifest)));
+        }
+
+        self.meta_engine.check_dir();
+        Ok(())
+    }
+
+    fn manifest(&self, path: &str) -> Result<Arc<Mutex<Manifest>>, i32> {
+        match self.files.get(path) {
+            Some(file) => Ok(file.clone()),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    fn manifest_dir(&self) -> String {
+        format!("{}/files", self.root)
+    }
+
+    // the manifest is written aside and renamed over the old one, a crash
+    // leaves either of them.
+    fn save_manifest(&self, manifest: &Manifest) -> Result<(), i32> {
+        let manifest_file_name = generate_local_file_name(&self.manifest_dir(), &manifest.path);
+        let data = bincode::serialize(manifest).map_err(|_| libc::EIO)?;
+        write_and_rename(&manifest_file_name, &data)
+    }
+
+    fn chunk_file_name(&self, name: &str) -> String {
+        format!("{}/chunks/{}", self.root, name)
+    }
+
+    fn read_chunk(&self, name: &str, offset: u64, length: usize) -> Result<Vec<u8>, i32> {
+        let file = File::open(self.chunk_file_name(name)).map_err(|e| {
+            error!("read chunk {} error: {:?}", name, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        let mut data = vec![0; length];
+        let mut read = 0;
+        while read < length {
+            match file.read_at(&mut data[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) => return Err(e.raw_os_error().unwrap_or(libc::EIO)),
+            }
+        }
+        data.truncate(read);
+        Ok(data)
+    }
+
+    // write_chunks writes data at offset into the chunks of the locked
+    // manifest of path.
+    fn write_chunks(
+        &self,
+        manifest: &mut Manifest,
+        path: &str,
+        data: &[u8],
+        offset: u64,
+    ) -> Result<(), i32> {
+        let mut written = 0;
+        while written < data.len() {
+            let pos = offset + written as u64;
+            let (index, begin) = ((pos / CHUNK_SIZE) as usize, (pos % CHUNK_SIZE) as usize);
+            let length = (CHUNK_SIZE as usize - begin).min(data.len() - written);
+            let mut chunk = match manifest.chunks.get(index) {
+                Some(Some(name)) => self.read_chunk(name, 0, CHUNK_SIZE as usize)?,
+                _ => vec![],
+            };
+            if chunk.len() < begin + length {
+                chunk.resize(begin + length, 0);
+            }
+            chunk[begin..begin + length].copy_from_slice(&data[written..written + length]);
+            self.set_chunk(manifest, index, &chunk)?;
+            written += length;
+        }
+        manifest.size = manifest.size.max(offset + data.len() as u64);
+        self.save_manifest(manifest)?;
+        self.meta_engine
+            .update_size(path, offset + data.len() as u64)?;
+        debug!(
+            "write_file path: {}, offset: {}, data_len: {}",
+            path,
+            offset,
+            data.len()
+        );
+        Ok(())
+    }
+
+    // set_chunk points the chunk index of the manifest at the chunk of the
+    // data, and releases the chunk it pointed at.
+    fn set_chunk(&self, manifest: &mut Manifest, index: usize, data: &[u8]) -> Result<(), i32> {
+        let name = Some(self.put_chunk(data)?);
+        if manifest.chunks.len() <= index {
+            manifest.chunks.resize(index + 1, None);
+        }
+        if let Some(old) = std::mem::replace(&mut manifest.chunks[index], name) {
+            self.release(&old);
+        }
+        Ok(())
+    }
+
+    // put_chunk references the chunk with the content of data, and stores it
+    // unless it is already there.
+    fn put_chunk(&self, data: &[u8]) -> Result<String, i32> {
+        let hash = wyhash::wyhash(data, 0);
+        for collision in 0.. {
+            let name = format!("{:016x}-{}-{}", hash, data.len(), collision);
+            let mut refs = self.refs.entry(name.clone()).or_insert(0);
+            let chunk_file_name = self.chunk_file_name(&name);
+            match std::fs::read(&chunk_file_name) {
+                Ok(chunk) if chunk == data => {}
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    write_and_rename(&chunk_file_name, data)?;
+                }
+                Err(e) => {
+                    error!("read chunk {} error: {:?}", name, e);
+                    return Err(e.raw_os_error().unwrap_or(libc::EIO));
+                }
+            }
+            *refs += 1;
+            return Ok(name);
+        }
+        unreachable!()
+    }
+
+    fn release(&self, name: &str) {
+        if let Some(mut refs) = self.refs.get_mut(name) {
+            *refs = refs.saturating_sub(1);
+            if *refs == 0 {
+                self.released.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn maybe_gc(&self) {
+        if self.released.load(Ordering::Relaxed) < GC_THRESHOLD {
+            return;
+        }
+        if let Err(e) = self.gc() {
+            error!("gc chunks error: {}", status_to_string(e));
+        }
+    }
+}
+
+fn write_and_rename(file_name: &str, data: &[u8]) -> Result<(), i32> {
+    let tmp_file_name = format!("{}.tmp", file_name);
+    std::fs::write(&tmp_file_name, data)
+        .and_then(|_| std::fs::rename(&tmp_file_name, file_name))
+        .map_err(|e| {
+            error!("write {} error: {:?}", file_name, e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use libc::{O_CREAT, O_RDWR};
+
+    use super::{ChunkEngine, ChunkStats, CHUNK_SIZE};
+    use crate::server::storage_engine::{
+        meta_engine::MetaEngine, meta_store::MemStore, StorageEngine,
+    };
+
+    #[test]
+    fn chunk_engine_test() {
+        let root = "/tmp/test_chunk_engine";
+        let _ = std::fs::remove_dir_all(root);
+        let meta_engine = Arc::new(MetaEngine::with_store(Box::new(MemStore::new())));
+        let engine = ChunkEngine::new(root, meta_engine.clone()).unwrap();
+        engine.init().unwrap();
+
+        // two checkpoints differing in one byte of their second chunk.
+        let mut data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        engine
+            .create_file("v/epoch1", O_CREAT | O_RDWR, 0, 0o644)
+            .unwrap();
+        engine.write_file("v/epoch1", &data, 0).unwrap();
+        data[CHUNK_SIZE as usize + 7] ^= 1;
+        engine
+            .create_file("v/epoch2", O_CREAT | O_RDWR, 0, 0o644)
+            .unwrap();
+        engine.write_file("v/epoch2", &data, 0).unwrap();
+        assert_eq!(
+            engine.stats(),
+            ChunkStats {
+                chunks: 4,
+                references: 6
+            }
+        );
+        let read = engine
+            .read_file("v/epoch2", 16, CHUNK_SIZE as i64 + 1)
+            .unwrap();
+        assert_eq!(
+            read,
+            &data[CHUNK_SIZE as usize + 1..CHUNK_SIZE as usize + 17]
+        );
+        assert_eq!(
+            meta_engine.get_file_attr("v/epoch2").unwrap().size,
+            3 * CHUNK_SIZE
+        );
+
+        // the chunks only held by a deleted file are collected.
+        engine.delete_file("v/epoch1").unwrap();
+        assert_eq!(engine.gc(), Ok((1, CHUNK_SIZE)));
+        assert_eq!(engine.stats().chunks, 3);
+
+        // a truncated file reads zeros where it grows again.
+        engine.truncate_file("v/epoch2", 10).unwrap();
+        engine.truncate_file("v/epoch2", 20).unwrap();
+        let read = engine.read_file("v/epoch2", 64, 0).unwrap();
+        assert_eq!(&read[..10], &data[..10]);
+        assert_eq!(&read[10..], &[0; 10]);
+
+        // the references are counted again from the manifests on restart.
+        let engine = ChunkEngine::new(root, meta_engine.clone()).unwrap();
+        engine.init().unwrap();
+        assert_eq!(
+            engine.stats(),
+            ChunkStats {
+                chunks: 1,
+                references: 1
+            }
+        );
+        assert_eq!(engine.read_file("v/epoch2", 64, 0).unwrap(), read);
+
+        // the chunks past 4GiB, the holes before them are not stored.
+        engine.write_file("v/epoch2", b"tail", 5 << 30).unwrap();
+        assert_eq!(
+            meta_engine.get_file_attr("v/epoch2").unwrap().size,
+            (5 << 30) + 4
+        );
+        assert_eq!(engine.read_file("v/epoch2", 8, 5 << 30).unwrap(), b"tail");
+        assert_eq!(engine.read_file("v/epoch2", 2, 1 << 32).unwrap(), [0; 2]);
+        assert_eq!(engine.stats().chunks, 2);
+
+        // the holes are the chunks never written.
+        let size = (5 << 30) + 4;
+        assert_eq!(engine.seek_data("v/epoch2", 0, size, false), Ok(0));
+        assert_eq!(engine.seek_data("v/epoch2", 5, size, true), Ok(CHUNK_SIZE));
+        assert_eq!(engine.seek_data("v/epoch2", 5, size, false), Ok(5));
+        assert_eq!(
+            engine.seek_data("v/epoch2", CHUNK_SIZE, size, false),
+            Ok(5 << 30)
+        );
+        assert_eq!(engine.seek_data("v/epoch2", 5 << 30, size, true), Ok(size));
+        assert_eq!(
+            engine.seek_data("v/epoch2", size, size, true),
+            Err(libc::ENXIO)
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
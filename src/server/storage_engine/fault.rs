@@ -0,0 +1,272 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Fault injection for the storage engines, only built with the
+ * fault-injection feature. A fault makes an operation fail or sleep when
+ * its path matches a pattern, which is used to reproduce partial failures
+ * such as a directory entry added without its file. A short fault makes a
+ * read or a write complete fewer bytes than asked, as a disk may.
+ *
+ * Faults can also be loaded from the SEALFS_FAULTS environment variable:
+ *     <operation>:<pattern>:fail=<errno>|delay=<ms>|short=<bytes>[:<times>];...
+ * e.g. SEALFS_FAULTS="meta_engine.create_file:/test*:fail=5:1"
+ */
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::{error, warn};
+use parking_lot::Mutex;
+
+pub const FAULTS_ENV: &str = "SEALFS_FAULTS";
+// the operation of the faults injected by crash_after, it matches every operation.
+pub const CRASH_OPERATION: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    Fail(i32),
+    Delay(Duration),
+    // the operation completes at most this many bytes.
+    Short(usize),
+}
+
+struct Fault {
+    operation: String,
+    pattern: String,
+    action: FaultAction,
+    // None means the fault never expires.
+    remaining: Option<u64>,
+    // matching checks that pass before the fault fires.
+    skip: u64,
+}
+
+lazy_static! {
+    static ref FAULTS: Mutex<Vec<Fault>> = Mutex::new(Vec::new());
+}
+
+// inject adds a fault for operation on the paths matching pattern,
+// '*' in the operation and the pattern matches any characters.
+pub fn inject(operation: &str, pattern: &str, action: FaultAction, times: Option<u64>) {
+    warn!(
+        "inject fault {:?} into {} on {}, times: {:?}",
+        action, operation, pattern, times
+    );
+    FAULTS.lock().push(Fault {
+        operation: operation.to_string(),
+        pattern: pattern.to_string(),
+        action,
+        remaining: times,
+        skip: 0,
+    });
+}
+
+// crash_after lets the first points operations on the paths matching pattern
+// run and fails all the later ones, as if the server was killed right there.
+// It is removed with remove(CRASH_OPERATION, pattern).
+pub fn crash_after(pattern: &str, points: u64) {
+    FAULTS.lock().push(Fault {
+        operation: CRASH_OPERATION.to_string(),
+        pattern: pattern.to_string(),
+        action: FaultAction::Fail(libc::EIO),
+        remaining: None,
+        skip: points,
+    });
+}
+
+// remove removes the faults of operation on pattern.
+pub fn remove(operation: &str, pattern: &str) {
+    FAULTS
+        .lock()
+        .retain(|f| f.operation != operation || f.pattern != pattern);
+}
+
+pub fn clear() {
+    FAULTS.lock().clear();
+}
+
+// take returns the action of the first fault of operation on path, short
+// ones or the others, and counts it.
+fn take(operation: &str, path: &str, short: bool) -> Option<FaultAction> {
+    let mut faults = FAULTS.lock();
+    let index = faults.iter().position(|f| {
+        matches!(f.action, FaultAction::Short(_)) == short
+            && glob_match(&f.operation, operation)
+            && glob_match(&f.pattern, path)
+    })?;
+    let fault = &mut faults[index];
+    if fault.skip > 0 {
+        fault.skip -= 1;
+        return None;
+    }
+    let action = fault.action;
+    if let Some(remaining) = fault.remaining.as_mut() {
+        *remaining -= 1;
+        if *remaining == 0 {
+            faults.remove(index);
+        }
+    }
+    Some(action)
+}
+
+// limit is called by the engines with the length of the data operation on
+// path reads or writes, and returns the length it completes.
+pub fn limit(operation: &str, path: &str, length: usize) -> usize {
+    match take(operation, path, true) {
+        Some(FaultAction::Short(bytes)) if bytes < length => {
+            warn!(
+                "injected fault: {} on {} short of {} bytes",
+                operation,
+                path,
+                length - bytes
+            );
+            bytes
+        }
+        _ => length,
+    }
+}
+
+// check is called by the engines before running operation on path.
+pub fn check(operation: &str, path: &str) -> Result<(), i32> {
+    let Some(action) = take(operation, path, false) else {
+        return Ok(());
+    };
+    match action {
+        FaultAction::Fail(errno) => {
+            warn!("injected fault: {} on {} failed", operation, path);
+            Err(errno)
+        }
+        FaultAction::Delay(delay) => {
+            warn!("injected fault: {} on {} delayed", operation, path);
+            std::thread::sleep(delay);
+            Ok(())
+        }
+        FaultAction::Short(_) => Ok(()),
+    }
+}
+
+// load_from_env injects the faults described by SEALFS_FAULTS.
+pub fn load_from_env() -> Result<(), i32> {
+    match std::env::var(FAULTS_ENV) {
+        Ok(spec) => load(&spec),
+        Err(_) => Ok(()),
+    }
+}
+
+pub fn load(spec: &str) -> Result<(), i32> {
+    for item in spec.split(';').filter(|s| !s.trim().is_empty()) {
+        let fields: Vec<&str> = item.trim().split(':').collect();
+        if fields.len() != 3 && fields.len() != 4 {
+            error!("invalid fault: {}", item);
+            return Err(libc::EINVAL);
+        }
+        let action = match fields[2].split_once('=') {
+            Some(("fail", errno)) => errno.parse().map(FaultAction::Fail),
+            Some(("delay", ms)) => ms
+                .parse()
+                .map(|ms| FaultAction::Delay(Duration::from_millis(ms))),
+            Some(("short", bytes)) => bytes.parse().map(FaultAction::Short),
+            _ => {
+                error!("invalid fault action: {}", fields[2]);
+                return Err(libc::EINVAL);
+            }
+        }
+        .map_err(|_| libc::EINVAL)?;
+        let times = match fields.get(3) {
+            Some(times) => Some(times.parse().map_err(|_| libc::EINVAL)?),
+            None => None,
+        };
+        inject(fields[0], fields[1], action, times);
+    }
+    Ok(())
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // position of the last '*' in pattern and the text position it matched from.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        check, crash_after, glob_match, inject, limit, load, remove, FaultAction, CRASH_OPERATION,
+    };
+
+    #[test]
+    fn glob_match_test() {
+        assert!(glob_match("*", "/a/b"));
+        assert!(glob_match("/a/*", "/a/b"));
+        assert!(glob_match("/a/*/c", "/a/b/c"));
+        assert!(glob_match("/a*c", "/abbc"));
+        assert!(!glob_match("/a/*", "/b/a"));
+        assert!(!glob_match("/a", "/a/b"));
+    }
+
+    #[test]
+    fn inject_test() {
+        inject(
+            "fault_test.fail",
+            "/fault/*",
+            FaultAction::Fail(libc::EIO),
+            Some(2),
+        );
+        assert_eq!(check("fault_test.fail", "/other"), Ok(()));
+        assert_eq!(check("fault_test.fail", "/fault/a"), Err(libc::EIO));
+        assert_eq!(check("fault_test.fail", "/fault/b"), Err(libc::EIO));
+        assert_eq!(check("fault_test.fail", "/fault/a"), Ok(()));
+
+        load("fault_test.load:/x:fail=28;fault_test.load:/y:delay=1").unwrap();
+        assert_eq!(check("fault_test.load", "/x"), Err(libc::ENOSPC));
+        assert_eq!(check("fault_test.load", "/y"), Ok(()));
+        remove("fault_test.load", "/x");
+        assert_eq!(check("fault_test.load", "/x"), Ok(()));
+        assert!(load("fault_test.load:/x:crash").is_err());
+
+        inject(
+            "fault_test.delay",
+            "*",
+            FaultAction::Delay(Duration::from_millis(1)),
+            Some(1),
+        );
+        assert_eq!(check("fault_test.delay", "/a"), Ok(()));
+
+        // a short fault leaves the checks alone.
+        load("fault_test.short:/s:short=3:2").unwrap();
+        assert_eq!(check("fault_test.short", "/s"), Ok(()));
+        assert_eq!(limit("fault_test.short", "/s", 10), 3);
+        assert_eq!(limit("fault_test.short", "/s", 2), 2);
+        assert_eq!(limit("fault_test.short", "/s", 10), 10);
+    }
+
+    #[test]
+    fn crash_after_test() {
+        crash_after("/crash_test/*", 2);
+        assert_eq!(check("fault_test.a", "/crash_test/a"), Ok(()));
+        assert_eq!(check("fault_test.b", "/other"), Ok(()));
+        assert_eq!(check("fault_test.b", "/crash_test/b"), Ok(()));
+        assert_eq!(check("fault_test.a", "/crash_test/a"), Err(libc::EIO));
+        assert_eq!(check("fault_test.c", "/crash_test/c"), Err(libc::EIO));
+        remove(CRASH_OPERATION, "/crash_test/*");
+        assert_eq!(check("fault_test.a", "/crash_test/a"), Ok(()));
+    }
+}
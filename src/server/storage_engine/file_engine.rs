@@ -2,22 +2,28 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::common::serialization::FILE_FLAG_APPEND_ONLY;
 use crate::common::util::empty_file;
-use crate::common::{cache::LRUCache, errors::status_to_string};
+use crate::common::{cache::LRUCache, errors::status_to_string, health::disk_space};
 
+use super::group_commit::GroupCommit;
 use super::meta_engine::MetaEngine;
+use super::upload::Uploads;
 use super::StorageEngine;
+use dashmap::DashMap;
 use log::{debug, error, info};
 use nix::errno::errno;
 use nix::{
-    fcntl::OFlag,
+    fcntl::{fallocate, FallocateFlags, OFlag},
     sys::stat::Mode,
     unistd::{self, mkdir},
 };
+use parking_lot::Mutex;
 use std::ffi::CString;
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    os::fd::AsRawFd,
     path::Path,
     sync::Arc,
 };
@@ -26,6 +32,11 @@ pub struct FileEngine {
     pub meta_engine: Arc<MetaEngine>,
     pub root: String,
     pub cache: LRUCache<FileDescriptor>,
+    // syncs the appends to append-only files.
+    group_commit: GroupCommit,
+    // serializes the appends to each file.
+    appends: DashMap<String, Arc<Mutex<()>>>,
+    uploads: Uploads,
 }
 
 #[derive(Debug, Clone)]
@@ -46,27 +57,36 @@ impl Drop for FileDescriptor {
 }
 
 impl StorageEngine for FileEngine {
-    fn new(root: &str, meta_engine: Arc<MetaEngine>) -> Self {
+    fn new(root: &str, meta_engine: Arc<MetaEngine>) -> Result<Self, i32> {
         if !Path::new(root).exists() {
             info!("root path {} does not exist, creating it", root);
             let mode =
                 Mode::S_IRWXU | Mode::S_IRGRP | Mode::S_IWGRP | Mode::S_IROTH | Mode::S_IWOTH;
-            mkdir(root, mode).unwrap();
+            mkdir(root, mode).map_err(|e| {
+                error!("create root path {} error: {}", root, e);
+                e as i32
+            })?;
         }
 
-        Self {
+        Ok(Self {
             meta_engine,
             root: root.to_string(),
             cache: LRUCache::new(512),
-        }
+            group_commit: GroupCommit::new(),
+            appends: DashMap::new(),
+            uploads: Uploads::new(),
+        })
     }
 
-    fn init(&self) {
-        self.fsck().unwrap();
+    fn init(&self) -> Result<(), i32> {
+        self.fsck()?;
         self.meta_engine.init();
+        Ok(())
     }
 
     fn read_file(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
+        fault_point!("file_engine.read_file", path);
+        let size = fault_limit!("file_engine.read_file", path, size as usize) as u32;
         if self.meta_engine.is_dir(path)? {
             return Err(libc::EISDIR);
         }
@@ -130,65 +150,22 @@ impl StorageEngine for FileEngine {
     }
 
     fn write_file(&self, path: &str, data: &[u8], offset: i64) -> Result<usize, i32> {
+        fault_point!("file_engine.write_file", path);
+        let data = &data[..fault_limit!("file_engine.write_file", path, data.len())];
         if self.meta_engine.is_dir(path)? {
             return Err(libc::EISDIR);
         }
-
-        let local_file_name = generate_local_file_name(&self.root, path);
-        let oflag = OFlag::O_RDWR;
-        let mode = Mode::S_IRUSR
-            | Mode::S_IWUSR
-            | Mode::S_IRGRP
-            | Mode::S_IWGRP
-            | Mode::S_IROTH
-            | Mode::S_IWOTH;
-        let fd = match self.cache.get(local_file_name.as_bytes()) {
-            Some(value) => value.fd,
-            None => {
-                let fd = unsafe {
-                    libc::open(
-                        CString::new(local_file_name.clone())
-                            .unwrap()
-                            .as_c_str()
-                            .as_ptr() as *const i8,
-                        oflag.bits(),
-                        mode.bits(),
-                    )
-                };
-                if fd < 0 {
-                    let f_errno = errno();
-                    error!("write file error: {:?}", status_to_string(f_errno));
-                    return Err(f_errno);
-                }
-                self.cache
-                    .insert(local_file_name.as_bytes(), FileDescriptor::new(fd));
-                fd
-            }
-        };
-        let write_size =
-            unsafe { libc::pwrite(fd, data.as_ptr() as *const libc::c_void, data.len(), offset) };
-        if write_size < 0 {
-            let f_errno = errno();
-            error!("write file error: {:?}", status_to_string(f_errno));
-            return Err(f_errno);
+        // the server writes at the end of an append-only file.
+        if self.meta_engine.get_file_attr(path)?.flags & FILE_FLAG_APPEND_ONLY != 0 {
+            return self.append_file(path, data).map(|(_, size)| size);
         }
-
-        debug!(
-            "write_file path: {}, write_size: {}, data_len: {}",
-            path,
-            write_size,
-            data.len()
-        );
-
-        self.meta_engine
-            .update_size(path, offset as u64 + write_size as u64)?;
-
-        Ok(write_size as usize)
+        self.write_at(path, data, offset)
     }
 
     fn create_file(&self, path: &str, _oflag: i32, _umask: u32, mode: u32) -> Result<Vec<u8>, i32> {
+        fault_point!("file_engine.create_file", path);
         let local_file_name = generate_local_file_name(&self.root, path);
-        let oflag = OFlag::O_CREAT | OFlag::O_RDWR;
+        let local_oflag = OFlag::O_CREAT | OFlag::O_RDWR;
         match self.cache.get(local_file_name.as_bytes()) {
             Some(_) => {}
             None => {
@@ -198,7 +175,7 @@ impl StorageEngine for FileEngine {
                             .unwrap()
                             .as_c_str()
                             .as_ptr() as *const i8,
-                        oflag.bits(),
+                        local_oflag.bits(),
                         mode,
                     )
                 };
@@ -216,8 +193,10 @@ impl StorageEngine for FileEngine {
     }
 
     fn delete_file(&self, path: &str) -> Result<(), i32> {
+        fault_point!("file_engine.delete_file", path);
         let local_file_name = generate_local_file_name(&self.root, path);
         self.cache.remove(local_file_name.as_bytes());
+        self.appends.remove(path);
         let status = unsafe {
             libc::unlink(
                 CString::new(local_file_name.clone())
@@ -236,6 +215,7 @@ impl StorageEngine for FileEngine {
     }
 
     fn truncate_file(&self, path: &str, length: i64) -> Result<(), i32> {
+        fault_point!("file_engine.truncate_file", path);
         let local_file_name = generate_local_file_name(&self.root, path);
         let status = unsafe {
             libc::truncate(
@@ -248,11 +228,11 @@ impl StorageEngine for FileEngine {
             error!("truncate file error: {:?}", status_to_string(f_errno));
             return Err(f_errno);
         };
-        // TODO: update file attr
-        Ok(())
+        self.meta_engine.truncate(path, length as u64)
     }
 
     fn open_file(&self, path: &str, _flags: i32, mode: u32) -> Result<(), i32> {
+        fault_point!("file_engine.open_file", path);
         let local_file_name = generate_local_file_name(&self.root, path);
 
         let oflag = OFlag::O_RDWR;
@@ -275,9 +255,192 @@ impl StorageEngine for FileEngine {
             .insert(local_file_name.as_bytes(), FileDescriptor::new(fd));
         Ok(())
     }
+
+    fn prefetch_file(&self, path: &str) -> Result<u64, i32> {
+        fault_point!("file_engine.prefetch_file", path);
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        let local_file_name = generate_local_file_name(&self.root, path);
+        let file = std::fs::File::open(&local_file_name).map_err(|e| {
+            error!("prefetch file error: {:?}", e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        let size = file
+            .metadata()
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?
+            .len();
+        // the readahead runs in the background, the file can be closed right away.
+        let status =
+            unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED) };
+        if status != 0 {
+            error!("prefetch file error: {:?}", status_to_string(status));
+            return Err(status);
+        }
+        debug!("prefetch_file path: {}, size: {}", path, size);
+        Ok(size)
+    }
+
+    fn begin_upload(&self, path: &str) -> Result<u64, i32> {
+        fault_point!("file_engine.begin_upload", path);
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        let local_file_name = generate_local_file_name(&self.root, path);
+        self.uploads.begin(path, &local_file_name)
+    }
+
+    fn upload_part(&self, path: &str, upload_id: u64, offset: u64, data: &[u8]) -> Result<(), i32> {
+        fault_point!("file_engine.upload_part", path);
+        self.uploads.write_part(path, upload_id, offset, data)
+    }
+
+    fn complete_upload(&self, path: &str, upload_id: u64, size: u64) -> Result<(), i32> {
+        fault_point!("file_engine.complete_upload", path);
+        let local_file_name = generate_local_file_name(&self.root, path);
+        self.uploads
+            .complete(path, upload_id, size, &local_file_name)?;
+        // the cached descriptor still refers to the replaced data.
+        self.cache.remove(local_file_name.as_bytes());
+        self.meta_engine.truncate(path, size)
+    }
+
+    fn disk_space(&self) -> Option<Result<(u64, u64), i32>> {
+        Some(disk_space(&self.root))
+    }
+
+    fn local_path(&self, path: &str) -> Option<Result<String, i32>> {
+        let local_file_name = generate_local_file_name(&self.root, path);
+        Some(match self.meta_engine.is_dir(path) {
+            Ok(true) => Err(libc::EISDIR),
+            Ok(false) if Path::new(&local_file_name).is_file() => Ok(local_file_name),
+            Ok(false) => Err(libc::ENOENT),
+            Err(e) => Err(e),
+        })
+    }
+
+    fn rename_file(&self, path: &str, new_path: &str) -> Result<(), i32> {
+        fault_point!("file_engine.rename_file", path);
+        let local_file_name = generate_local_file_name(&self.root, path);
+        let new_local_file_name = generate_local_file_name(&self.root, new_path);
+        self.cache.remove(local_file_name.as_bytes());
+        self.appends.remove(path);
+        std::fs::rename(&local_file_name, &new_local_file_name).map_err(|e| {
+            error!("rename file error: {:?}", e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        let result =
+            self.meta_engine
+                .rename_file(&local_file_name, &new_local_file_name, path, new_path);
+        if result.is_err() {
+            let _ = std::fs::rename(&new_local_file_name, &local_file_name);
+        }
+        result
+    }
+
+    // sync_file syncs the root too, which holds the entry of a new local file.
+    fn sync_file(&self, path: &str, datasync: bool) -> Result<(), i32> {
+        let local_file_name = generate_local_file_name(&self.root, path);
+        let sync = |file: std::fs::File| match datasync {
+            true => file.sync_data(),
+            false => file.sync_all(),
+        };
+        std::fs::File::open(&local_file_name)
+            .and_then(sync)
+            .and_then(|_| std::fs::File::open(&self.root).and_then(|root| root.sync_all()))
+            .map_err(|e| {
+                error!("sync file {} error: {:?}", path, e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })
+    }
+
+    fn preallocate(&self, path: &str, size: u64) -> Result<(), i32> {
+        let local_file_name = generate_local_file_name(&self.root, path);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(local_file_name)
+            .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+        fallocate(
+            file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            0,
+            size as i64,
+        )
+        .map_err(|e| e as i32)
+    }
+
+    fn append_file(&self, path: &str, data: &[u8]) -> Result<(u64, usize), i32> {
+        if self.meta_engine.is_dir(path)? {
+            return Err(libc::EISDIR);
+        }
+        // one append at a time, so that the size only grows by the bytes written.
+        let lock = self.appends.entry(path.to_owned()).or_default().clone();
+        let guard = lock.lock();
+        let attr = self.meta_engine.get_file_attr(path)?;
+        let offset = attr.size;
+        let write_size = self.write_at(path, data, offset as i64)?;
+        drop(guard);
+        if attr.flags & FILE_FLAG_APPEND_ONLY != 0 {
+            self.group_commit
+                .commit(&generate_local_file_name(&self.root, path))?;
+        }
+        Ok((offset, write_size))
+    }
 }
 
 impl FileEngine {
+    fn write_at(&self, path: &str, data: &[u8], offset: i64) -> Result<usize, i32> {
+        let local_file_name = generate_local_file_name(&self.root, path);
+        let oflag = OFlag::O_RDWR;
+        let mode = Mode::S_IRUSR
+            | Mode::S_IWUSR
+            | Mode::S_IRGRP
+            | Mode::S_IWGRP
+            | Mode::S_IROTH
+            | Mode::S_IWOTH;
+        let fd = match self.cache.get(local_file_name.as_bytes()) {
+            Some(value) => value.fd,
+            None => {
+                let fd = unsafe {
+                    libc::open(
+                        CString::new(local_file_name.clone())
+                            .unwrap()
+                            .as_c_str()
+                            .as_ptr() as *const i8,
+                        oflag.bits(),
+                        mode.bits(),
+                    )
+                };
+                if fd < 0 {
+                    let f_errno = errno();
+                    error!("write file error: {:?}", status_to_string(f_errno));
+                    return Err(f_errno);
+                }
+                self.cache
+                    .insert(local_file_name.as_bytes(), FileDescriptor::new(fd));
+                fd
+            }
+        };
+        let write_size =
+            unsafe { libc::pwrite(fd, data.as_ptr() as *const libc::c_void, data.len(), offset) };
+        if write_size < 0 {
+            let f_errno = errno();
+            error!("write file error: {:?}", status_to_string(f_errno));
+            return Err(f_errno);
+        }
+
+        debug!(
+            "write_file path: {}, write_size: {}, data_len: {}",
+            path,
+            write_size,
+            data.len()
+        );
+
+        self.meta_engine
+            .update_size(path, offset as u64 + write_size as u64)?;
+        Ok(write_size as usize)
+    }
+
     fn fsck(&self) -> Result<(), i32> {
         let entries = match std::fs::read_dir(&self.root) {
             Ok(entries) => entries,
@@ -305,7 +468,7 @@ impl FileEngine {
 }
 
 #[inline]
-fn generate_local_file_name(root: &str, path: &str) -> String {
+pub(crate) fn generate_local_file_name(root: &str, path: &str) -> String {
     let mut hasher = DefaultHasher::new();
     path.hash(&mut hasher);
     format!("{}/{}", root, hasher.finish())
@@ -313,9 +476,10 @@ fn generate_local_file_name(root: &str, path: &str) -> String {
 
 #[cfg(test)]
 mod tests {
-    use std::{path::Path, sync::Arc};
+    use std::{os::unix::fs::MetadataExt, path::Path, sync::Arc};
 
-    use crate::server::storage_engine::meta_engine::MetaEngine;
+    use crate::common::serialization::FileLayout;
+    use crate::server::storage_engine::{meta_engine::MetaEngine, meta_store::MemStore};
     use fuser::FileType;
     use libc::mode_t;
     use nix::{
@@ -333,8 +497,8 @@ mod tests {
         let db_path = "/tmp/test_db";
         {
             let meta_engine = Arc::new(MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024));
-            let engine = FileEngine::new(root, meta_engine);
-            engine.init();
+            let engine = FileEngine::new(root, meta_engine).unwrap();
+            engine.init().unwrap();
 
             let oflag = OFlag::O_CREAT | OFlag::O_EXCL;
             let mode = Mode::S_IRUSR
@@ -349,8 +513,8 @@ mod tests {
 
         {
             let meta_engine = Arc::new(MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024));
-            let engine = FileEngine::new(root, meta_engine);
-            engine.init();
+            let engine = FileEngine::new(root, meta_engine).unwrap();
+            engine.init().unwrap();
             assert_eq!(
                 Path::new(format!("{}/test", root).as_str()).is_file(),
                 false
@@ -371,8 +535,8 @@ mod tests {
         let db_path = "/tmp/test_file_db";
         {
             let meta_engine = Arc::new(MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024));
-            let engine = FileEngine::new(root, meta_engine.clone());
-            engine.init();
+            let engine = FileEngine::new(root, meta_engine.clone()).unwrap();
+            engine.init().unwrap();
             meta_engine.create_directory("test1", 0o777).unwrap();
             let mode: mode_t = 0o777;
             let oflag: i32 = OFlag::O_CREAT.bits() | OFlag::O_RDWR.bits();
@@ -388,8 +552,8 @@ mod tests {
 
         {
             let meta_engine = Arc::new(MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024));
-            let engine = FileEngine::new(root, meta_engine.clone());
-            engine.init();
+            let engine = FileEngine::new(root, meta_engine.clone()).unwrap();
+            engine.init().unwrap();
             meta_engine.create_directory("test1", 0o777).unwrap();
             let mode: mode_t = 0o777;
             let oflag: i32 = OFlag::O_CREAT.bits() | OFlag::O_RDWR.bits();
@@ -423,8 +587,8 @@ mod tests {
         let db_path = "/tmp/test_rw_db";
         {
             let meta_engine = Arc::new(MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024));
-            let engine = FileEngine::new(root, meta_engine.clone());
-            engine.init();
+            let engine = FileEngine::new(root, meta_engine.clone()).unwrap();
+            engine.init().unwrap();
             let mode: mode_t = 0o777;
             let oflag: i32 = OFlag::O_CREAT.bits() | OFlag::O_RDWR.bits();
             engine.create_file("test1/b.txt", oflag, 0, mode).unwrap();
@@ -435,6 +599,36 @@ mod tests {
             assert_eq!("hello world", String::from_utf8(value).unwrap());
             let file_attr = meta_engine.get_file_attr("test1/b.txt").unwrap();
             assert_eq!(file_attr.size, 11);
+            engine.sync_file("test1/b.txt", true).unwrap();
+            engine.sync_file("test1/b.txt", false).unwrap();
+            meta_engine.sync().unwrap();
+            assert_eq!(engine.sync_file("test1/c.txt", false), Err(libc::ENOENT));
+
+            // the offsets of the writes to an append-only file are ignored,
+            // O_APPEND alone does not make one.
+            engine
+                .create_file("test1/log", oflag | OFlag::O_APPEND.bits(), 0, mode)
+                .unwrap();
+            engine.write_file("test1/log", b"x", 4).unwrap();
+            assert_eq!(meta_engine.get_file_attr("test1/log").unwrap().size, 5);
+            engine.truncate_file("test1/log", 0).unwrap();
+            let layout = FileLayout {
+                append_only: true,
+                ..Default::default()
+            };
+            meta_engine.set_layout("test1/log", &layout).unwrap();
+            engine.write_file("test1/log", b"first ", 100).unwrap();
+            engine.write_file("test1/log", b"second", 0).unwrap();
+            let value = engine.read_file("test1/log", 64, 0).unwrap();
+            assert_eq!(value, b"first second");
+            assert_eq!(meta_engine.get_file_attr("test1/log").unwrap().size, 12);
+
+            // the space reserved on create does not change the size.
+            engine.create_file("test1/big", oflag, 0, mode).unwrap();
+            engine.preallocate("test1/big", 1 << 20).unwrap();
+            let metadata = std::fs::metadata(generate_local_file_name(root, "test1/big")).unwrap();
+            assert_eq!(metadata.len(), 0);
+            assert!(metadata.blocks() * 512 >= 1 << 20);
         }
         rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
         rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
@@ -444,4 +638,49 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn test_large_file() {
+        let root = "/tmp/test_large_file";
+        let _ = std::fs::remove_dir_all(root);
+        let meta_engine = Arc::new(MetaEngine::with_store(Box::new(MemStore::new())));
+        let engine = FileEngine::new(root, meta_engine.clone()).unwrap();
+        engine.init().unwrap();
+        let oflag = OFlag::O_CREAT.bits() | OFlag::O_RDWR.bits();
+        engine.create_file("v/large", oflag, 0, 0o644).unwrap();
+
+        // a write across 4GiB and one past it, the file stays sparse.
+        let offset = (1i64 << 32) - 2;
+        engine.write_file("v/large", b"abcd", offset).unwrap();
+        engine.write_file("v/large", b"tail", 5 << 30).unwrap();
+        assert_eq!(
+            meta_engine.get_file_attr("v/large").unwrap().size,
+            (5 << 30) + 4
+        );
+        assert_eq!(engine.read_file("v/large", 4, offset).unwrap(), b"abcd");
+        assert_eq!(engine.read_file("v/large", 8, 5 << 30).unwrap(), b"tail");
+        assert_eq!(
+            engine.read_file("v/large", 2, (1 << 32) + 4096).unwrap(),
+            [0; 2]
+        );
+
+        // the holes are those of the local file, by blocks.
+        let size = (5 << 30) + 4;
+        assert_eq!(engine.seek_data("v/large", 0, size, true), Ok(0));
+        let data = engine.seek_data("v/large", 0, size, false).unwrap();
+        assert!(data > 0 && data <= (1 << 32) - 2);
+        assert_eq!(engine.seek_data("v/large", 5 << 30, size, true), Ok(size));
+        assert_eq!(
+            engine.seek_data("v/large", size, size, false),
+            Err(libc::ENXIO)
+        );
+
+        engine.truncate_file("v/large", (1 << 32) + 1).unwrap();
+        assert_eq!(
+            meta_engine.get_file_attr("v/large").unwrap().size,
+            (1 << 32) + 1
+        );
+        assert_eq!(engine.read_file("v/large", 8, offset).unwrap(), b"abc");
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }
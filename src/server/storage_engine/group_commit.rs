@@ -0,0 +1,118 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Group commit of the appends to append-only files.
+ * Every append must be durable before it is acknowledged, but a fsync per
+ * append would bound the ingest rate by the latency of the disk. Instead the
+ * writers queue their files, the first one becomes the leader and syncs all
+ * the queued files at once, while the others wait for the group that covers
+ * their write.
+ */
+use std::collections::HashSet;
+
+use log::error;
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+#[derive(Default)]
+struct State {
+    // files written since the current sync started.
+    pending: HashSet<String>,
+    syncing: bool,
+    // number of finished syncs.
+    generation: u64,
+    // the last failed sync and its error.
+    failure: Option<(u64, i32)>,
+}
+
+#[derive(Default)]
+pub struct GroupCommit {
+    state: Mutex<State>,
+    synced: Condvar,
+}
+
+impl GroupCommit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // commit returns once the data written to local_file_name before the call is durable.
+    pub fn commit(&self, local_file_name: &str) -> Result<(), i32> {
+        let mut state = self.state.lock();
+        state.pending.insert(local_file_name.to_owned());
+        // a running sync may have missed our write, so wait for the next one.
+        let target = match state.syncing {
+            true => state.generation + 2,
+            false => state.generation + 1,
+        };
+        while state.generation < target {
+            if state.syncing {
+                self.synced.wait(&mut state);
+                continue;
+            }
+            state.syncing = true;
+            let files = std::mem::take(&mut state.pending);
+            let result = MutexGuard::unlocked(&mut state, || sync_files(&files));
+            state.syncing = false;
+            state.generation += 1;
+            if let Err(e) = result {
+                state.failure = Some((state.generation, e));
+            }
+            self.synced.notify_all();
+        }
+        // a failure of a later sync is reported too, the write may not be durable.
+        match state.failure {
+            Some((generation, e)) if generation >= target => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn sync_files(files: &HashSet<String>) -> Result<(), i32> {
+    let mut result = Ok(());
+    for file in files {
+        if let Err(e) = std::fs::File::open(file).and_then(|f| f.sync_data()) {
+            error!("sync {} error: {}", file, e);
+            result = Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use super::GroupCommit;
+
+    #[test]
+    fn group_commit_test() {
+        let path = "/tmp/test_group_commit";
+        let group_commit = Arc::new(GroupCommit::new());
+        std::fs::File::create(path).unwrap();
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let group_commit = group_commit.clone();
+                std::thread::spawn(move || {
+                    let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+                    for _ in 0..16 {
+                        file.write_all(&[i as u8]).unwrap();
+                        group_commit.commit(path).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 8 * 16);
+        assert!(group_commit.state.lock().pending.is_empty());
+
+        assert_eq!(
+            group_commit.commit("/tmp/test_group_commit_missing"),
+            Err(libc::ENOENT)
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
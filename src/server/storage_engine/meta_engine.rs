@@ -1,48 +1,85 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime},
+};
 
 use bytes::BufMut;
 use dashmap::DashMap;
 use fuser::{FileAttr, FileType};
 use libc::{DT_DIR, DT_LNK, DT_REG};
 use log::{debug, error, info};
-#[cfg(feature = "mem-db")]
-use pegasusdb::DB;
-use rocksdb::{BlockBasedOptions, WriteBatch};
-#[cfg(feature = "disk-db")]
-use rocksdb::{Cache, IteratorMode, Options, DB};
 
+#[cfg(feature = "disk-db")]
+use super::meta_store::RocksStore;
+use super::meta_store::{MetaStore, Table};
 use crate::common::{
-    errors::{DATABASE_ERROR, SERIALIZATION_ERROR},
-    serialization::{bytes_as_file_attr, file_attr_as_bytes, FileTypeSimple, Volume},
-    util::{empty_dir, path_split},
+    errors::{status_to_string, SERIALIZATION_ERROR, VERSION_MISMATCH},
+    hash_ring::HashAlgorithm,
+    serialization::{
+        bytes_as_file_attr, file_attr_as_bytes, file_version, put_dir_entry, AtimePolicy,
+        FileLayout, FileTypeSimple, Volume, DIR_ENTRY_HEADER_SIZE, FILE_FLAG_LINKED,
+    },
+    util::{dir_cookie, empty_dir, is_shard, path_split, shard_index},
 };
 
 const INIT_SUB_FILES_NUM: u32 = 2;
 
-#[cfg(feature = "disk-db")]
-pub struct Database {
-    pub db: DB,
-    pub db_opts: Options,
-    pub path: String,
+// kept in file_db next to the format version, see migration.rs.
+const HASH_ALGORITHM_KEY: &str = "$hash_algorithm";
+
+// the hard links of this server, `$link/<path>` -> the path of the file they
+// link to on any server, are kept in file_db too.
+const LINK_KEY_PREFIX: &str = "$link/";
+
+// the volumes, `$volume/<name>` -> the bincode of the Volume, are kept in
+// file_db too.
+const VOLUME_KEY_PREFIX: &str = "$volume/";
+
+// with relatime, atime is refreshed at least once per day even if the file is not modified.
+const RELATIME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// next_version counts a change of the file, see file_version.
+fn next_version(attr: &mut FileAttr) {
+    attr.ino += 1;
+}
+
+fn link_key(path: &str) -> String {
+    format!("{}{}", LINK_KEY_PREFIX, path)
 }
 
-#[cfg(feature = "mem-db")]
-pub struct Database {
-    pub db: DB,
+fn volume_key(name: &str) -> String {
+    format!("{}{}", VOLUME_KEY_PREFIX, name)
 }
 
 pub struct FileIndex {
     pub file_attr: FileAttr,
     pub status: u32,
     pub sub_files_num: AtomicU32,
+    // the subdirectories among the entries of a directory.
+    pub sub_dirs_num: AtomicU32,
+}
+
+impl FileIndex {
+    // attr returns the file attr, with the entry counts of a directory kept
+    // in memory: nlink is 2 + its subdirectories as on local file systems,
+    // and size is its number of entries.
+    pub fn attr(&self) -> FileAttr {
+        let mut attr = self.file_attr;
+        if attr.kind == FileType::Directory {
+            attr.nlink = INIT_SUB_FILES_NUM + self.sub_dirs_num.load(Ordering::Relaxed);
+            attr.size = (self.sub_files_num.load(Ordering::Relaxed) - INIT_SUB_FILES_NUM) as u64;
+        }
+        attr
+    }
 }
 
 pub struct MetaEngine {
-    pub file_db: Database,
-    pub dir_db: Database,
-    pub file_attr_db: Database,
+    pub store: Box<dyn MetaStore>,
     pub file_indexs: DashMap<String, FileIndex>,
     pub volumes: DashMap<String, Volume>,
+    // hard link -> the path of the file it links to, on this server too.
+    pub links: DashMap<String, String>,
 }
 
 impl MetaEngine {
@@ -52,93 +89,40 @@ impl MetaEngine {
         #[cfg(feature = "disk-db")] write_buffer_size: usize,
     ) -> Self {
         #[cfg(feature = "disk-db")]
-        let (file_db, dir_db, file_attr_db) = {
-            let file_db = {
-                let mut db_opts = Options::default();
-                let mut block_opts = BlockBasedOptions::default();
-                let cache = Cache::new_lru_cache(cache_capacity).unwrap();
-                block_opts.set_block_cache(&cache);
-                db_opts.set_block_based_table_factory(&block_opts);
-                db_opts.set_write_buffer_size(write_buffer_size);
-                db_opts.create_if_missing(true);
-                let path = format!("{}_file", db_path);
-                let db = match DB::open(&db_opts, path.as_str()) {
-                    Ok(db) => db,
-                    Err(e) => panic!("{}", e),
-                };
-                Database { db, db_opts, path }
-            };
-
-            let dir_db = {
-                let mut db_opts = Options::default();
-                let mut block_opts = BlockBasedOptions::default();
-                let cache = Cache::new_lru_cache(cache_capacity).unwrap();
-                block_opts.set_block_cache(&cache);
-                db_opts.set_block_based_table_factory(&block_opts);
-                db_opts.set_write_buffer_size(write_buffer_size);
-                db_opts.create_if_missing(true);
-                let path = format!("{}_dir", db_path);
-                let db = match DB::open(&db_opts, path.as_str()) {
-                    Ok(db) => db,
-                    Err(e) => panic!("{}", e),
-                };
-                Database { db, db_opts, path }
-            };
-
-            let file_attr_db = {
-                let mut db_opts = Options::default();
-                let mut block_opts = BlockBasedOptions::default();
-                let cache = Cache::new_lru_cache(cache_capacity).unwrap();
-                block_opts.set_block_cache(&cache);
-                db_opts.set_block_based_table_factory(&block_opts);
-                db_opts.set_write_buffer_size(write_buffer_size);
-                db_opts.create_if_missing(true);
-                let path = format!("{}_file_attr", db_path);
-                let db = match DB::open(&db_opts, path.as_str()) {
-                    Ok(db) => db,
-                    Err(e) => panic!("{}", e),
-                };
-                Database { db, db_opts, path }
-            };
-            (file_db, dir_db, file_attr_db)
-        };
-
-        #[cfg(feature = "mem-db")]
-        let (file_db, dir_db, file_attr_db) = {
-            let file_db = DB::open(format!("{db_path}_file"));
-            let dir_db = DB::open(format!("{db_path}_dir"));
-            let file_attr_db = DB::open(format!("{db_path}_file_attr"));
-            (
-                Database { db: file_db },
-                Database { db: dir_db },
-                Database { db: file_attr_db },
-            )
+        let store = RocksStore::open(db_path, cache_capacity, write_buffer_size);
+        #[cfg(not(feature = "disk-db"))]
+        let store = {
+            info!("meta data of {} is kept in memory only", db_path);
+            super::meta_store::MemStore::new()
         };
+        Self::with_store(Box::new(store))
+    }
 
+    pub fn with_store(store: Box<dyn MetaStore>) -> Self {
         Self {
-            file_db,
-            dir_db,
-            file_attr_db,
+            store,
             file_indexs: DashMap::new(),
             volumes: DashMap::new(),
+            links: DashMap::new(),
         }
     }
 
     pub fn init(&self) {
-        for file_name in self.file_attr_db.db.iterator(IteratorMode::Start) {
+        for file_name in self.store.iter(Table::FileAttr, b"") {
             let (k, v) = file_name.unwrap();
-            let k = String::from_utf8(k.to_vec()).unwrap();
+            let k = String::from_utf8(k).unwrap();
             let attr = bytes_as_file_attr(&v);
             let file_type = attr.kind;
             match file_type {
-                FileType::RegularFile => {
-                    // RegularFile
+                FileType::RegularFile | FileType::Symlink => {
+                    // RegularFile, a symbolic link keeps its target as data
                     self.file_indexs.insert(
                         k,
                         FileIndex {
                             file_attr: *attr,
                             status: 0,
                             sub_files_num: AtomicU32::new(0),
+                            sub_dirs_num: AtomicU32::new(0),
                         },
                     );
                 }
@@ -150,45 +134,69 @@ impl MetaEngine {
                             file_attr: *attr,
                             status: 0,
                             sub_files_num: AtomicU32::new(INIT_SUB_FILES_NUM),
+                            sub_dirs_num: AtomicU32::new(0),
                         },
                     );
-                    if !k.contains('/') {
+                    if !k.contains('/') && !is_shard(&k) {
                         info!("found volume: {}", k);
-                        self.volumes.insert(
-                            k.clone(),
-                            Volume {
-                                name: k,
-                                size: 10000000,
-                                used_size: 0,
-                            },
-                        );
+                        // the volumes created before they were kept get the defaults.
+                        let volume = match self.store.get(Table::File, volume_key(&k).as_bytes()) {
+                            Ok(Some(value)) => bincode::deserialize(&value).ok(),
+                            _ => None,
+                        };
+                        let volume = volume.unwrap_or_else(|| Volume {
+                            name: k.clone(),
+                            size: 10000000,
+                            used_size: 0,
+                            atime_policy: AtimePolicy::default(),
+                            public: false,
+                        });
+                        self.volumes.insert(k, volume);
                     }
                 }
                 _ => {}
             }
         }
 
-        for dir_name in self.dir_db.db.iterator(IteratorMode::Start) {
-            let sub_dir_info = String::from_utf8(dir_name.unwrap().0.to_vec()).unwrap();
+        for dir_name in self.store.iter(Table::Dir, b"") {
+            let sub_dir_info = String::from_utf8(dir_name.unwrap().0).unwrap();
             let list = sub_dir_info.split('$').collect::<Vec<&str>>();
             let file_index = self
                 .file_indexs
                 .get(list.first().unwrap().to_owned())
                 .unwrap();
             file_index.sub_files_num.fetch_add(1, Ordering::Relaxed);
+            if sub_dir_info.as_bytes().last() == Some(&(FileTypeSimple::Directory as u8)) {
+                file_index.sub_dirs_num.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        for item in self.store.iter(Table::File, LINK_KEY_PREFIX.as_bytes()) {
+            let (key, value) = item.unwrap();
+            let Some(link) = key.strip_prefix(LINK_KEY_PREFIX.as_bytes()) else {
+                break;
+            };
+            let (link, path) = (
+                String::from_utf8(link.to_vec()).unwrap(),
+                String::from_utf8(value).unwrap(),
+            );
+            // the file was not moved away from the path of the link, see
+            // DistributedEngine::add_link.
+            if self.file_indexs.contains_key(&link) {
+                error!("drop the hard link {} to {}", link, path);
+                let _ = self.store.delete(Table::File, &key);
+                continue;
+            }
+            self.links.insert(link, path);
         }
     }
 
     pub fn get_file_map(&self) -> Result<Vec<String>, i32> {
         let mut file_map = Vec::new();
-        self.file_attr_db
-            .db
-            .iterator(IteratorMode::Start)
-            .for_each(|result| {
-                let (k, _) = result.unwrap();
-                let k = String::from_utf8(k.to_vec()).unwrap();
-                file_map.push(k);
-            });
+        for result in self.store.iter(Table::FileAttr, b"") {
+            let (k, _) = result?;
+            file_map.push(String::from_utf8(k).unwrap());
+        }
         Ok(file_map)
     }
 
@@ -198,6 +206,12 @@ impl MetaEngine {
         loacl_file_name: &str,
         path: &str,
     ) -> Result<Vec<u8>, i32> {
+        fault_point!("meta_engine.create_file", path);
+        if self.links.contains_key(path) {
+            return Err(libc::EEXIST);
+        }
+        let mut file_attr = file_attr;
+        next_version(&mut file_attr);
         let value = self.put_file_attr(path, &file_attr)?;
         match self.file_indexs.insert(
             path.to_string(),
@@ -205,62 +219,155 @@ impl MetaEngine {
                 file_attr,
                 status: 0,
                 sub_files_num: AtomicU32::new(INIT_SUB_FILES_NUM),
+                sub_dirs_num: AtomicU32::new(0),
             },
         ) {
             Some(_) => Err(libc::EEXIST),
-            None => match self.file_db.db.put(loacl_file_name, path) {
+            None => match self
+                .store
+                .put(Table::File, loacl_file_name.as_bytes(), path.as_bytes())
+            {
                 Ok(_) => Ok(value),
                 Err(e) => {
-                    error!("put file error: {}", e);
-                    Err(DATABASE_ERROR)
+                    error!("put file error: {}", status_to_string(e));
+                    Err(e)
                 }
             },
         }
     }
 
     pub fn delete_file(&self, local_file_name: &str, path: &str) -> Result<(), i32> {
+        fault_point!("meta_engine.delete_file", path);
         match self.file_indexs.remove(path) {
-            Some(_) => match self.file_db.db.delete(local_file_name) {
+            Some(_) => match self.store.delete(Table::File, local_file_name.as_bytes()) {
                 Ok(_) => {
                     self.delete_file_attr(path)?;
                     Ok(())
                 }
                 Err(e) => {
-                    error!("delete file error: {}", e);
-                    Err(DATABASE_ERROR)
+                    error!("delete file error: {}", status_to_string(e));
+                    Err(e)
                 }
             },
             None => Err(libc::ENOENT),
         }
     }
 
-    pub fn is_exist(&self, path: &str) -> Result<bool, i32> {
+    // rename_file moves the attr of path and the path of its local file to
+    // new_path and its local file, the storage engine moving the data.
+    pub fn rename_file(
+        &self,
+        local_file_name: &str,
+        new_local_file_name: &str,
+        path: &str,
+        new_path: &str,
+    ) -> Result<(), i32> {
+        if self.file_indexs.contains_key(new_path) {
+            return Err(libc::EEXIST);
+        }
+        let attr = self.get_file_attr(path)?;
+        self.put_file_attr(new_path, &attr)?;
+        self.store.put(
+            Table::File,
+            new_local_file_name.as_bytes(),
+            new_path.as_bytes(),
+        )?;
+        self.store.delete(Table::File, local_file_name.as_bytes())?;
+        self.delete_file_attr(path)?;
+        if let Some((_, index)) = self.file_indexs.remove(path) {
+            self.file_indexs.insert(new_path.to_owned(), index);
+        }
+        Ok(())
+    }
+
+    // resolve returns the path of the file the hard link path links to, or
+    // path itself.
+    pub fn resolve<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.links.is_empty() {
+            return Cow::Borrowed(path);
+        }
+        match self.links.get(path) {
+            Some(target) => Cow::Owned(target.clone()),
+            None => Cow::Borrowed(path),
+        }
+    }
+
+    // put_link makes path a hard link to the file target, which may be on
+    // another server.
+    pub fn put_link(&self, path: &str, target: &str) -> Result<(), i32> {
+        if self.file_indexs.contains_key(path) {
+            return Err(libc::EEXIST);
+        }
+        self.store
+            .put(Table::File, link_key(path).as_bytes(), target.as_bytes())?;
+        self.links.insert(path.to_owned(), target.to_owned());
+        Ok(())
+    }
+
+    // remove_link removes the hard link path, and returns the file it linked
+    // to, None if path is not a hard link.
+    pub fn remove_link(&self, path: &str) -> Result<Option<String>, i32> {
+        let Some((_, target)) = self.links.remove(path) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.store.delete(Table::File, link_key(path).as_bytes()) {
+            self.links.insert(path.to_owned(), target);
+            return Err(e);
+        }
+        Ok(Some(target))
+    }
+
+    // link_target returns the file the hard link path links to.
+    pub fn link_target(&self, path: &str) -> Option<String> {
+        self.links.get(path).map(|target| target.clone())
+    }
+
+    // add_link adds delta to the links of the regular file path, and marks it
+    // as known by its links only.
+    pub fn add_link(&self, path: &str, delta: i32) -> Result<Vec<u8>, i32> {
         match self.file_indexs.get(path) {
-            Some(_) => Ok(true),
-            None => Ok(false),
+            Some(index) if index.file_attr.kind == FileType::RegularFile => {}
+            Some(_) => return Err(libc::EPERM),
+            None => return Err(libc::ENOENT),
         }
+        self.update_attr(path, |attr| {
+            // a file counts its path until it is linked.
+            if attr.flags & FILE_FLAG_LINKED == 0 {
+                attr.nlink = attr.nlink.max(1);
+            }
+            attr.flags |= FILE_FLAG_LINKED;
+            attr.nlink = (attr.nlink as i64 + delta as i64).max(0) as u32;
+            attr.ctime = SystemTime::now();
+        })
+    }
+
+    // is_exist tells whether path is a file or a hard link of this server.
+    pub fn is_exist(&self, path: &str) -> Result<bool, i32> {
+        Ok(self.file_indexs.contains_key(path) || self.links.contains_key(path))
     }
 
     // this function does not need to be thread safe
     pub fn create_directory(&self, path: &str, _mode: u32) -> Result<Vec<u8>, i32> {
+        fault_point!("meta_engine.create_directory", path);
+        let mut attr = empty_dir();
+        next_version(&mut attr);
         match self.file_indexs.insert(
             path.to_owned(),
             FileIndex {
-                file_attr: empty_dir(),
+                file_attr: attr,
                 status: 0,
                 sub_files_num: AtomicU32::new(INIT_SUB_FILES_NUM),
+                sub_dirs_num: AtomicU32::new(0),
             },
         ) {
             Some(_) => Err(libc::EEXIST),
-            None => {
-                let attr = empty_dir();
-                self.put_file_attr(path, &attr)
-            }
+            None => self.put_file_attr(path, &attr),
         }
     }
 
     // this function does not need to be thread safe
     pub fn delete_directory(&self, path: &str) -> Result<(), i32> {
+        fault_point!("meta_engine.delete_directory", path);
         match self.file_indexs.get(path) {
             Some(value) => {
                 if value.sub_files_num.load(Ordering::Relaxed) > INIT_SUB_FILES_NUM {
@@ -282,55 +389,40 @@ impl MetaEngine {
 
         // delete sub file index in dir_db with prefix "path_"
         let (start_key, end_key) = (path.to_owned() + "$", path.to_owned() + "$~");
-        let mut batch = WriteBatch::default();
-        batch.delete_range(start_key, end_key);
-        match self.dir_db.db.write(batch) {
-            Ok(_) => {}
-            Err(e) => {
-                error!("delete directory force error: {}", e);
-                return Err(DATABASE_ERROR);
-            }
+        if let Err(e) =
+            self.store
+                .delete_range(Table::Dir, start_key.as_bytes(), end_key.as_bytes())
+        {
+            error!("delete directory force error: {}", status_to_string(e));
+            return Err(e);
         }
 
         self.delete_file_attr(path)
     }
 
+    // read_directory returns the entries of a directory whose cursors are
+    // after offset, in the order of the cursors, as many as fit in size bytes.
     pub fn read_directory(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32> {
-        match self.file_indexs.get(path) {
+        let index_num = match self.file_indexs.get(path) {
             Some(value) => {
                 if value.file_attr.kind != FileType::Directory {
                     return Err(libc::ENOTDIR);
                 }
+                value.sub_files_num.load(Ordering::Relaxed) //maybe better hold a lock
             }
             None => return Err(libc::ENOENT),
-        }
-
-        let mut offset = offset;
-
-        // TODO: optimize the situation while offset is not 0
-
-        let mut index_num = match self.file_indexs.get(path) {
-            Some(value) => value.sub_files_num.load(Ordering::Relaxed), //maybe better hold a lock
-            None => {
-                return Err(libc::ENOENT);
-            }
         };
 
-        let mut result = Vec::with_capacity(size as usize);
-        let mut total = 0;
-        for item in self.dir_db.db.iterator(IteratorMode::From(
-            format!("{}$", path).as_bytes(),
-            rocksdb::Direction::Forward,
-        )) {
-            if index_num == INIT_SUB_FILES_NUM {
-                break;
-            }
-            if offset > 0 {
-                offset -= 1;
-                index_num -= 1;
-                continue;
-            }
-            let (key, value) = item.unwrap();
+        // the cursors are not in the order of the keys, so the whole
+        // directory is read on each page.
+        let shard = shard_index(path);
+        let mut entries = Vec::new();
+        for item in self
+            .store
+            .iter(Table::Dir, format!("{}$", path).as_bytes())
+            .take(index_num.saturating_sub(INIT_SUB_FILES_NUM) as usize)
+        {
+            let (key, value) = item?;
             let ty = {
                 match (*key.last().unwrap()).try_into() {
                     Ok(FileTypeSimple::RegularFile) => DT_REG,
@@ -342,47 +434,145 @@ impl MetaEngine {
                             "read directory error: {}, path: {}, key as string: {}",
                             e,
                             path,
-                            String::from_utf8(key.to_vec()).unwrap()
+                            String::from_utf8_lossy(&key)
                         );
                         return Err(SERIALIZATION_ERROR);
                     }
                 }
             };
-            let rec_len = value.len() + 3;
-            total += rec_len;
-            if total > size as usize {
+            let cookie = dir_cookie(shard, &value);
+            if cookie > offset {
+                entries.push((cookie, ty, value));
+            }
+        }
+        entries.sort_unstable();
+
+        let mut result = Vec::with_capacity(size as usize);
+        for (cookie, ty, name) in entries {
+            if result.len() + DIR_ENTRY_HEADER_SIZE + name.len() > size as usize {
                 break;
             }
-            result.put_u8(ty);
-            result.put((value.len() as u16).to_le_bytes().as_ref());
-            result.put(value.as_ref());
-            index_num -= 1;
+            put_dir_entry(&mut result, ty, cookie, &name);
         }
         Ok(result)
     }
 
+    // list_tree returns the files and directories under path whose attrs are
+    // stored on this server, in key order after start_after, as many as fit in
+    // size bytes. Every record is a u16 length, the path and the raw FileAttr.
+    pub fn list_tree(&self, path: &str, start_after: &str, size: u32) -> Result<Vec<u8>, i32> {
+        let prefix = format!("{}/", path);
+        let start = match start_after.is_empty() {
+            true => prefix.as_str(),
+            false => start_after,
+        };
+        let mut result = Vec::new();
+        for item in self.store.iter(Table::FileAttr, start.as_bytes()) {
+            let (key, value) = item.map_err(|e| {
+                error!("list tree error: {}, path: {}", status_to_string(e), path);
+                e
+            })?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            // the shards of the directories are not listed.
+            if key == start_after.as_bytes() || key.contains(&0) {
+                continue;
+            }
+            if result.len() + 2 + key.len() + value.len() > size as usize {
+                if result.is_empty() {
+                    return Err(libc::EOVERFLOW);
+                }
+                break;
+            }
+            result.put((key.len() as u16).to_le_bytes().as_ref());
+            result.put(key.as_slice());
+            result.put(value.as_slice());
+        }
+        Ok(result)
+    }
+
+    // set_attr_tree changes with f the attrs of path and of the files and
+    // directories under it stored on this server, count of them at most after
+    // start_after. It returns the number of paths changed, and the last one
+    // if there may be more.
+    pub fn set_attr_tree(
+        &self,
+        path: &str,
+        start_after: &str,
+        count: u32,
+        f: impl Fn(&mut FileAttr),
+    ) -> Result<(u32, String), i32> {
+        let prefix = format!("{}/", path);
+        let mut paths = Vec::new();
+        if start_after.is_empty() && self.file_indexs.contains_key(path) {
+            paths.push(path.to_owned());
+        }
+        let start = match start_after.starts_with(&prefix) {
+            true => start_after,
+            false => prefix.as_str(),
+        };
+        let mut more = false;
+        for item in self.store.iter(Table::FileAttr, start.as_bytes()) {
+            let (key, _) = item.map_err(|e| {
+                error!(
+                    "set attr tree error: {}, path: {}",
+                    status_to_string(e),
+                    path
+                );
+                e
+            })?;
+            if !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            // the shards of the directories keep their attrs.
+            if key == start_after.as_bytes() || key.contains(&0) {
+                continue;
+            }
+            if paths.len() >= count.max(1) as usize {
+                more = true;
+                break;
+            }
+            paths.push(String::from_utf8_lossy(&key).into_owned());
+        }
+        for path in &paths {
+            match self.update_attr(path, &f) {
+                // deleted since it was listed.
+                Ok(_) | Err(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let last = match (more, paths.last()) {
+            (true, Some(last)) => last.clone(),
+            _ => String::new(),
+        };
+        Ok((paths.len() as u32, last))
+    }
+
     pub fn directory_add_entry(
         &self,
         parent_dir: &str,
         file_name: &str,
         file_type: u8,
     ) -> Result<(), i32> {
+        fault_point!("meta_engine.directory_add_entry", parent_dir);
         match self.file_indexs.get(parent_dir) {
             Some(value) => {
                 if value.file_attr.kind != FileType::Directory {
                     return Err(libc::ENOTDIR);
                 }
-                match self.dir_db.db.put(
-                    format!("{}${}${}", parent_dir, file_name, file_type as char),
-                    file_name,
-                ) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("directory add entry error: {}", e);
-                        return Err(DATABASE_ERROR);
-                    }
+                let key = format!("{}${}${}", parent_dir, file_name, file_type as char);
+                if let Err(e) = self
+                    .store
+                    .put(Table::Dir, key.as_bytes(), file_name.as_bytes())
+                {
+                    error!("directory add entry error: {}", status_to_string(e));
+                    return Err(e);
                 }
                 value.sub_files_num.fetch_add(1, Ordering::Relaxed);
+                if file_type == FileTypeSimple::Directory as u8 {
+                    value.sub_dirs_num.fetch_add(1, Ordering::Relaxed);
+                }
                 Ok(())
             }
             None => {
@@ -398,23 +588,22 @@ impl MetaEngine {
         file_name: &str,
         file_type: u8,
     ) -> Result<(), i32> {
+        fault_point!("meta_engine.directory_delete_entry", parent_dir);
         match self.file_indexs.get(parent_dir) {
             Some(value) => {
                 if value.file_attr.kind != FileType::Directory {
                     return Err(libc::ENOTDIR);
                 }
-                match self.dir_db.db.delete(format!(
-                    "{}${}${}",
-                    parent_dir, file_name, file_type as char
-                )) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("directory delete entry error: {}", e);
-                        return Err(DATABASE_ERROR);
-                    }
+                let key = format!("{}${}${}", parent_dir, file_name, file_type as char);
+                if let Err(e) = self.store.delete(Table::Dir, key.as_bytes()) {
+                    error!("directory delete entry error: {}", status_to_string(e));
+                    return Err(e);
                 }
                 //assert!(value.sub_files_num > INIT_SUB_FILES_NUM);
                 value.sub_files_num.fetch_sub(1, Ordering::Relaxed);
+                if file_type == FileTypeSimple::Directory as u8 {
+                    value.sub_dirs_num.fetch_sub(1, Ordering::Relaxed);
+                }
                 Ok(())
             }
             None => {
@@ -425,18 +614,19 @@ impl MetaEngine {
     }
 
     pub fn delete_from_parent(&self, path: &str, file_type: u8) -> Result<(), i32> {
+        fault_point!("meta_engine.delete_from_parent", path);
         let (parent, name) = path_split(path).unwrap();
         match self.file_indexs.get(&parent) {
             Some(value) => {
-                if let Err(e) = self
-                    .dir_db
-                    .db
-                    .delete(format!("{}${}${}", parent, name, file_type as char))
-                {
-                    error!("delete from parent error: {}", e);
-                    return Err(DATABASE_ERROR);
+                let key = format!("{}${}${}", parent, name, file_type as char);
+                if let Err(e) = self.store.delete(Table::Dir, key.as_bytes()) {
+                    error!("delete from parent error: {}", status_to_string(e));
+                    return Err(e);
                 }
                 value.sub_files_num.fetch_sub(1, Ordering::Relaxed);
+                if file_type == FileTypeSimple::Directory as u8 {
+                    value.sub_dirs_num.fetch_sub(1, Ordering::Relaxed);
+                }
                 Ok(())
             }
             None => Err(libc::ENOENT),
@@ -444,23 +634,189 @@ impl MetaEngine {
     }
 
     pub fn put_file_attr(&self, path: &str, attr: &FileAttr) -> Result<Vec<u8>, i32> {
+        fault_point!("meta_engine.put_file_attr", path);
         let value = file_attr_as_bytes(attr).to_vec();
-        match self.file_attr_db.db.put(path, &value) {
+        match self.store.put(Table::FileAttr, path.as_bytes(), &value) {
             Ok(_) => Ok(value),
             Err(e) => {
-                error!("put_file_attr error: {}", e);
-                Err(DATABASE_ERROR)
+                error!("put_file_attr error: {}", status_to_string(e));
+                Err(e)
             }
         }
     }
 
+    // update_size is called after a successful write, so it also updates mtime and ctime.
     pub fn update_size(&self, path: &str, size: u64) -> Result<(), i32> {
+        fault_point!("meta_engine.update_size", path);
+        match self.file_indexs.get_mut(path) {
+            Some(mut value) => {
+                if value.file_attr.size < size {
+                    value.file_attr.size = size;
+                }
+                let now = SystemTime::now();
+                value.file_attr.mtime = now;
+                value.file_attr.ctime = now;
+                next_version(&mut value.file_attr);
+                match self.put_file_attr(path, &value.file_attr) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    pub fn truncate(&self, path: &str, length: u64) -> Result<(), i32> {
+        fault_point!("meta_engine.truncate", path);
+        match self.file_indexs.get_mut(path) {
+            Some(mut value) => {
+                let now = SystemTime::now();
+                value.file_attr.size = length;
+                value.file_attr.mtime = now;
+                value.file_attr.ctime = now;
+                next_version(&mut value.file_attr);
+                match self.put_file_attr(path, &value.file_attr) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    // set_layout records the layout of a file in its attr, see FileLayout.
+    pub fn set_layout(&self, path: &str, layout: &FileLayout) -> Result<Vec<u8>, i32> {
+        self.update_attr(path, |attr| layout.apply(attr))
+    }
+
+    // dir_shards returns the number of shards of a directory, kept as its
+    // stripe count, 1 for the other files.
+    pub fn dir_shards(&self, path: &str) -> u32 {
+        match self.file_indexs.get(path) {
+            Some(value) if value.file_attr.kind == FileType::Directory => {
+                FileLayout::of(&value.file_attr).stripe_count
+            }
+            _ => 1,
+        }
+    }
+
+    // set_dir_shards records the number of shards of an empty directory.
+    pub fn set_dir_shards(&self, path: &str, shards: u32) -> Result<Vec<u8>, i32> {
+        match self.file_indexs.get(path) {
+            Some(value) if value.file_attr.kind != FileType::Directory => {
+                return Err(libc::ENOTDIR)
+            }
+            Some(value) if value.sub_files_num.load(Ordering::Relaxed) > INIT_SUB_FILES_NUM => {
+                return Err(libc::ENOTEMPTY)
+            }
+            _ => {}
+        }
+        self.update_attr(path, |attr| {
+            FileLayout {
+                stripe_count: shards,
+                ..FileLayout::of(attr)
+            }
+            .apply(attr)
+        })
+    }
+
+    // set_owner records the owner and the permissions of a new file.
+    pub fn set_owner(&self, path: &str, uid: u32, gid: u32, mode: u32) -> Result<Vec<u8>, i32> {
+        self.update_attr(path, |attr| {
+            attr.uid = uid;
+            attr.gid = gid;
+            attr.perm = (mode & 0o7777) as u16;
+        })
+    }
+
+    // set_attr changes the attr of path with f, and its ctime.
+    pub fn set_attr(&self, path: &str, f: impl FnOnce(&mut FileAttr)) -> Result<Vec<u8>, i32> {
+        self.update_attr(path, |attr| {
+            f(attr);
+            attr.ctime = SystemTime::now();
+        })
+    }
+
+    pub fn set_kind(&self, path: &str, kind: FileType) -> Result<Vec<u8>, i32> {
+        self.update_attr(path, |attr| attr.kind = kind)
+    }
+
+    // find_entry returns the type of the entry name of the directory parent,
+    // None if it has no such entry.
+    pub fn find_entry(&self, parent_dir: &str, file_name: &str) -> Result<Option<u8>, i32> {
+        for file_type in [
+            FileTypeSimple::RegularFile,
+            FileTypeSimple::Directory,
+            FileTypeSimple::Symlink,
+        ] {
+            let file_type: u8 = file_type.into();
+            let key = format!("{}${}${}", parent_dir, file_name, file_type as char);
+            if self.store.get(Table::Dir, key.as_bytes())?.is_some() {
+                return Ok(Some(file_type));
+            }
+        }
+        Ok(None)
+    }
+
+    // entry_type returns the type of the entry name of the directory parent
+    // which is not a directory, a regular file or a symbolic link.
+    pub fn entry_type(&self, parent_dir: &str, file_name: &str) -> Result<u8, i32> {
+        let symlink: u8 = FileTypeSimple::Symlink.into();
+        let key = format!("{}${}${}", parent_dir, file_name, symlink as char);
+        match self.store.get(Table::Dir, key.as_bytes())? {
+            Some(_) => Ok(symlink),
+            None => Ok(FileTypeSimple::RegularFile.into()),
+        }
+    }
+
+    fn update_attr(&self, path: &str, f: impl FnOnce(&mut FileAttr)) -> Result<Vec<u8>, i32> {
         match self.file_indexs.get_mut(path) {
             Some(mut value) => {
-                if value.file_attr.size >= size {
+                f(&mut value.file_attr);
+                next_version(&mut value.file_attr);
+                self.put_file_attr(path, &value.file_attr)
+            }
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    // check_version refuses a conditional write unless the file is at
+    // version, and counts the write at once so only one of the concurrent
+    // writes expecting it goes on.
+    pub fn check_version(&self, path: &str, version: u64) -> Result<(), i32> {
+        if version == 0 {
+            return Ok(());
+        }
+        match self.file_indexs.get_mut(path) {
+            Some(mut value) if file_version(&value.file_attr) == version => {
+                next_version(&mut value.file_attr);
+                self.put_file_attr(path, &value.file_attr).map(|_| ())
+            }
+            Some(_) => Err(VERSION_MISMATCH),
+            None => Err(libc::ENOENT),
+        }
+    }
+
+    pub fn update_atime(&self, path: &str, policy: AtimePolicy) -> Result<(), i32> {
+        match self.file_indexs.get_mut(path) {
+            Some(mut value) => {
+                let now = SystemTime::now();
+                let attr = &mut value.file_attr;
+                let update = match policy {
+                    AtimePolicy::Noatime => false,
+                    AtimePolicy::Strictatime => true,
+                    AtimePolicy::Relatime => {
+                        attr.atime <= attr.mtime
+                            || attr.atime <= attr.ctime
+                            || now
+                                .duration_since(attr.atime)
+                                .is_ok_and(|d| d >= RELATIME_INTERVAL)
+                    }
+                };
+                if !update {
                     return Ok(());
                 }
-                value.file_attr.size = size;
+                attr.atime = now;
                 match self.put_file_attr(path, &value.file_attr) {
                     Ok(_) => Ok(()),
                     Err(e) => Err(e),
@@ -472,51 +828,61 @@ impl MetaEngine {
 
     pub fn get_file_attr(&self, path: &str) -> Result<FileAttr, i32> {
         match self.file_indexs.get(path) {
-            Some(value) => Ok(value.file_attr),
+            Some(value) => Ok(value.attr()),
             None => Err(libc::ENOENT),
         }
     }
 
     pub fn get_file_attr_raw(&self, path: &str) -> Result<Vec<u8>, i32> {
         match self.file_indexs.get(path) {
-            Some(value) => Ok(file_attr_as_bytes(&value.file_attr).to_vec()),
+            Some(value) => Ok(file_attr_as_bytes(&value.attr()).to_vec()),
             None => Err(libc::ENOENT),
         }
     }
 
     pub fn complete_transfer_file(&self, path: &str, file_attr: &FileAttr) -> Result<(), i32> {
         let value = file_attr_as_bytes(file_attr);
-        match self.file_attr_db.db.put(path, value) {
+        match self.store.put(Table::FileAttr, path.as_bytes(), value) {
             Ok(_) => Ok(()),
             Err(e) => {
-                error!("complete_transfer_file error: {}", e);
-                Err(DATABASE_ERROR)
+                error!("complete_transfer_file error: {}", status_to_string(e));
+                Err(e)
             }
         }
     }
 
     pub fn delete_file_attr(&self, path: &str) -> Result<(), i32> {
-        match self.file_attr_db.db.delete(path.as_bytes()) {
+        match self.store.delete(Table::FileAttr, path.as_bytes()) {
             Ok(_) => Ok(()),
             Err(e) => {
-                error!("delete_file_attr error: {}", e);
-                Err(DATABASE_ERROR)
+                error!("delete_file_attr error: {}", status_to_string(e));
+                Err(e)
             }
         }
     }
 
-    pub fn create_volume(&self, name: &str) -> Result<(), i32> {
+    pub fn create_volume(
+        &self,
+        name: &str,
+        atime_policy: AtimePolicy,
+        public: bool,
+    ) -> Result<(), i32> {
         if self.volumes.contains_key(name) {
             return Err(libc::EEXIST);
         }
-        self.volumes.insert(
-            name.to_owned(),
-            Volume {
-                name: name.to_owned(),
-                size: 100000000,
-                used_size: 0,
-            },
-        );
+        let volume = Volume {
+            name: name.to_owned(),
+            size: 100000000,
+            used_size: 0,
+            atime_policy,
+            public,
+        };
+        self.store.put(
+            Table::File,
+            volume_key(name).as_bytes(),
+            &bincode::serialize(&volume).unwrap(),
+        )?;
+        self.volumes.insert(name.to_owned(), volume);
         match self.create_directory(name, 0o755) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
@@ -531,11 +897,11 @@ impl MetaEngine {
         Ok(bincode::serialize(&volumes).unwrap())
     }
 
-    pub fn init_volume(&self, name: &str) -> Result<(), i32> {
-        if !self.volumes.contains_key(name) {
-            return Err(libc::ENOENT);
+    pub fn init_volume(&self, name: &str) -> Result<Vec<u8>, i32> {
+        match self.volumes.get(name) {
+            Some(volume) => Ok(bincode::serialize(volume.value()).unwrap()),
+            None => Err(libc::ENOENT),
         }
-        Ok(())
     }
 
     // make sure the volume is empty
@@ -544,6 +910,8 @@ impl MetaEngine {
             return Err(libc::ENOENT);
         }
         self.volumes.remove(name);
+        self.store
+            .delete(Table::File, volume_key(name).as_bytes())?;
         match self.delete_directory_force(name) {
             Ok(_) => Ok(()),
             Err(e) => Err(e),
@@ -566,34 +934,65 @@ impl MetaEngine {
         }
     }
 
-    pub fn check_dir(&self) {
-        #[cfg(feature = "disk-db")]
-        for item in self.dir_db.db.iterator(IteratorMode::End) {
-            let (key, _value) = item.unwrap();
-            let key = String::from_utf8(key.to_vec()).unwrap();
-            if !self
-                .file_attr_db
-                .db
-                .key_may_exist(key.split('$').next().unwrap())
-            {
-                let _ = self.dir_db.db.delete(&key);
+    // sync makes the metadata written so far durable.
+    pub fn sync(&self) -> Result<(), i32> {
+        self.store.sync()
+    }
+
+    // check_hash_algorithm records the hash the files of this server are placed
+    // with, and refuses a manager that places them with another one.
+    pub fn check_hash_algorithm(&self, algorithm: HashAlgorithm) -> Result<(), String> {
+        match self.store.get(Table::File, HASH_ALGORITHM_KEY.as_bytes()) {
+            Ok(Some(value)) => {
+                let recorded = String::from_utf8_lossy(&value).to_string();
+                if recorded != algorithm.to_string() {
+                    return Err(format!(
+                        "the files are placed with the {} hash but the manager uses {}, mixed hash configs are refused",
+                        recorded, algorithm
+                    ));
+                }
+                Ok(())
             }
+            Ok(None) => self
+                .store
+                .put(
+                    Table::File,
+                    HASH_ALGORITHM_KEY.as_bytes(),
+                    algorithm.to_string().as_bytes(),
+                )
+                .map_err(|e| format!("record hash algorithm failed: {}", status_to_string(e))),
+            Err(e) => Err(format!(
+                "read hash algorithm failed: {}",
+                status_to_string(e)
+            )),
         }
     }
 
-    pub fn check_file(&self, file_name: &str) -> bool {
-        let mut file_str = String::new();
-        if self.file_db.db.key_may_exist(file_name) {
-            let file_vec = self.file_db.db.get(file_name).unwrap().unwrap();
-            file_str = String::from_utf8(file_vec).unwrap();
-            if self.file_attr_db.db.key_may_exist(&file_str) {
-                return true;
+    // check_dir deletes the directory entries whose directory has no attr.
+    pub fn check_dir(&self) {
+        for item in self.store.iter(Table::Dir, b"") {
+            let Ok((key, _value)) = item else {
+                continue;
+            };
+            let key = String::from_utf8(key).unwrap();
+            let dir = key.split('$').next().unwrap();
+            if let Ok(None) = self.store.get(Table::FileAttr, dir.as_bytes()) {
+                let _ = self.store.delete(Table::Dir, key.as_bytes());
             }
-            let _ = self.file_db.db.delete(file_name);
         }
-        if self.file_attr_db.db.key_may_exist(&file_str) {
-            let _ = self.file_attr_db.db.delete(file_str);
+    }
+
+    // check_file returns if the local file has a path with an attr, and
+    // deletes its path otherwise.
+    pub fn check_file(&self, file_name: &str) -> bool {
+        let path = match self.store.get(Table::File, file_name.as_bytes()) {
+            Ok(Some(path)) => path,
+            _ => return false,
+        };
+        if let Ok(Some(_)) = self.store.get(Table::FileAttr, &path) {
+            return true;
         }
+        let _ = self.store.delete(Table::File, file_name.as_bytes());
         false
     }
 }
@@ -603,9 +1002,25 @@ mod tests {
 
     use std::sync::atomic::Ordering;
 
+    use fuser::{FileAttr, FileType};
     use libc::mode_t;
 
-    use crate::server::storage_engine::meta_engine::{MetaEngine, INIT_SUB_FILES_NUM};
+    use crate::{
+        common::{
+            errors::VERSION_MISMATCH,
+            hash_ring::HashAlgorithm,
+            serialization::{
+                bytes_as_dir_entries, bytes_as_file_attr, bytes_as_tree_entries, file_version,
+                AtimePolicy, Compression, FileLayout, FileTypeSimple, FILE_FLAG_APPEND_ONLY,
+                FILE_FLAG_LINKED,
+            },
+            util::{dir_cookie, empty_file},
+        },
+        server::storage_engine::{
+            meta_engine::{volume_key, MetaEngine, INIT_SUB_FILES_NUM},
+            meta_store::{MemStore, Table},
+        },
+    };
 
     #[test]
     fn test_create_delete_dir() {
@@ -703,4 +1118,411 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn test_dir_entry_counts() {
+        let db_path = "/tmp/test_dir_entry_counts_db";
+        {
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.init();
+            let dir = FileTypeSimple::Directory as u8;
+            let file = FileTypeSimple::RegularFile as u8;
+            engine.create_directory("test1", 0o777).unwrap();
+            engine.directory_add_entry("test1", "a", dir).unwrap();
+            engine.directory_add_entry("test1", "b", dir).unwrap();
+            engine.directory_add_entry("test1", "c", file).unwrap();
+            let attr = engine.get_file_attr("test1").unwrap();
+            assert_eq!((attr.nlink, attr.size), (4, 3));
+
+            engine.delete_from_parent("test1/a", dir).unwrap();
+            engine.directory_delete_entry("test1", "c", file).unwrap();
+            let attr = engine.get_file_attr("test1").unwrap();
+            assert_eq!((attr.nlink, attr.size), (3, 1));
+        }
+        {
+            // the counts are rebuilt from the entries on restart.
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.init();
+            let attr = engine.get_file_attr("test1").unwrap();
+            assert_eq!((attr.nlink, attr.size), (3, 1));
+        }
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
+        rocksdb::DB::destroy(
+            &rocksdb::Options::default(),
+            format!("{}_file_attr", db_path),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_update_file_times() {
+        let db_path = "/tmp/test_times_db";
+        {
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.init();
+            engine.create_file(empty_file(), "local_a", "a").unwrap();
+            let attr = engine.get_file_attr("a").unwrap();
+
+            engine.update_size("a", 100).unwrap();
+            let new_attr = engine.get_file_attr("a").unwrap();
+            assert_eq!(new_attr.size, 100);
+            assert!(new_attr.mtime >= attr.mtime);
+            assert!(new_attr.ctime >= attr.ctime);
+
+            engine.truncate("a", 10).unwrap();
+            assert_eq!(engine.get_file_attr("a").unwrap().size, 10);
+
+            // atime is older than mtime, so relatime updates it
+            let attr = engine.get_file_attr("a").unwrap();
+            engine.update_atime("a", AtimePolicy::Relatime).unwrap();
+            let new_attr = engine.get_file_attr("a").unwrap();
+            assert!(new_attr.atime > attr.atime);
+
+            // atime is newer than mtime now, so relatime does not update it again
+            engine.update_atime("a", AtimePolicy::Relatime).unwrap();
+            assert_eq!(engine.get_file_attr("a").unwrap().atime, new_attr.atime);
+
+            engine.update_atime("a", AtimePolicy::Noatime).unwrap();
+            assert_eq!(engine.get_file_attr("a").unwrap().atime, new_attr.atime);
+
+            engine.update_atime("a", AtimePolicy::Strictatime).unwrap();
+            assert!(engine.get_file_attr("a").unwrap().atime > new_attr.atime);
+
+            engine.delete_file("local_a", "a").unwrap();
+        }
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
+        rocksdb::DB::destroy(
+            &rocksdb::Options::default(),
+            format!("{}_file_attr", db_path),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_list_tree() {
+        let db_path = "/tmp/test_tree_db";
+        {
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.init();
+            engine.create_directory("t", 0o755).unwrap();
+            engine.create_directory("t/d", 0o755).unwrap();
+            engine.create_file(empty_file(), "local_a", "t/a").unwrap();
+            engine
+                .create_file(empty_file(), "local_b", "t/d/b")
+                .unwrap();
+            engine.create_file(empty_file(), "local_c", "t2/c").unwrap();
+
+            let paths = |data: Vec<u8>| -> Vec<String> {
+                bytes_as_tree_entries(&data)
+                    .unwrap()
+                    .into_iter()
+                    .map(|(path, _)| path)
+                    .collect()
+            };
+            let all = paths(engine.list_tree("t", "", 4096).unwrap());
+            assert_eq!(all, vec!["t/a", "t/d", "t/d/b"]);
+            assert_eq!(
+                paths(engine.list_tree("t/d", "", 4096).unwrap()),
+                vec!["t/d/b"]
+            );
+
+            // page by page, one record fits in a page.
+            let record_size = engine.list_tree("t", "", 4096).unwrap().len() / 3 + 2;
+            let mut start_after = String::new();
+            let mut paged = Vec::new();
+            loop {
+                let page = paths(
+                    engine
+                        .list_tree("t", &start_after, record_size as u32)
+                        .unwrap(),
+                );
+                if page.is_empty() {
+                    break;
+                }
+                assert_eq!(page.len(), 1);
+                start_after = page.last().unwrap().clone();
+                paged.extend(page);
+            }
+            assert_eq!(paged, all);
+            assert_eq!(engine.list_tree("t", "", 8), Err(libc::EOVERFLOW));
+
+            // t and the 3 paths under it in batches of 3, t2 is kept.
+            let chmod = |attr: &mut FileAttr| attr.perm = 0o700;
+            let (changed, last) = engine.set_attr_tree("t", "", 3, chmod).unwrap();
+            assert_eq!((changed, last.as_str()), (3, "t/d"));
+            assert_eq!(
+                engine.set_attr_tree("t", &last, 3, chmod),
+                Ok((1, String::new()))
+            );
+            for path in ["t", "t/a", "t/d", "t/d/b"] {
+                assert_eq!(engine.get_file_attr(path).unwrap().perm, 0o700);
+            }
+            assert_ne!(engine.get_file_attr("t2/c").unwrap().perm, 0o700);
+
+            engine.delete_file("local_a", "t/a").unwrap();
+            engine.delete_file("local_b", "t/d/b").unwrap();
+            engine.delete_file("local_c", "t2/c").unwrap();
+            engine.delete_directory("t/d").unwrap();
+            engine.delete_directory("t").unwrap();
+        }
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
+        rocksdb::DB::destroy(
+            &rocksdb::Options::default(),
+            format!("{}_file_attr", db_path),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_check_hash_algorithm() {
+        let db_path = "/tmp/test_hash_algorithm_db";
+        {
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.check_hash_algorithm(HashAlgorithm::Wyhash).unwrap();
+            engine.check_hash_algorithm(HashAlgorithm::Wyhash).unwrap();
+            assert!(engine.check_hash_algorithm(HashAlgorithm::Conhash).is_err());
+        }
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
+        rocksdb::DB::destroy(
+            &rocksdb::Options::default(),
+            format!("{}_file_attr", db_path),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_file_layout() {
+        let db_path = "/tmp/test_file_layout_db";
+        {
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.init();
+            engine.create_file(empty_file(), "local_a", "a").unwrap();
+            assert_eq!(
+                FileLayout::of(&engine.get_file_attr("a").unwrap()),
+                FileLayout::default()
+            );
+            let layout = FileLayout {
+                stripe_count: 4,
+                replication: 3,
+                compression: Compression::Zstd,
+                chunk_size: 1 << 20,
+                append_only: true,
+            };
+            engine.set_layout("a", &layout).unwrap();
+            let attr = engine.get_file_attr("a").unwrap();
+            assert_eq!(FileLayout::of(&attr), layout);
+            assert_eq!(attr.blksize, 1 << 20);
+            assert_ne!(attr.flags & FILE_FLAG_APPEND_ONLY, 0);
+            assert_eq!(engine.set_layout("b", &layout), Err(libc::ENOENT));
+
+            engine.set_owner("a", 1000, 100, 0o100640).unwrap();
+            let attr = engine.get_file_attr("a").unwrap();
+            assert_eq!((attr.uid, attr.gid, attr.perm), (1000, 100, 0o640));
+            assert_eq!(FileLayout::of(&attr), layout);
+
+            // a directory keeps its number of shards as its stripe count.
+            engine.create_directory("d", 0o755).unwrap();
+            assert_eq!(engine.dir_shards("d"), 1);
+            assert_eq!(engine.set_dir_shards("a", 4), Err(libc::ENOTDIR));
+            engine.set_dir_shards("d", 4).unwrap();
+            assert_eq!(engine.dir_shards("d"), 4);
+            engine
+                .directory_add_entry("d", "x", FileTypeSimple::RegularFile as u8)
+                .unwrap();
+            assert_eq!(engine.set_dir_shards("d", 8), Err(libc::ENOTEMPTY));
+        }
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
+        rocksdb::DB::destroy(
+            &rocksdb::Options::default(),
+            format!("{}_file_attr", db_path),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_file_version() {
+        let db_path = "/tmp/test_file_version_db";
+        {
+            let engine = MetaEngine::new(db_path, 128 << 20, 128 * 1024 * 1024);
+            engine.init();
+            engine.create_file(empty_file(), "local_a", "a").unwrap();
+            let version = |path| file_version(&engine.get_file_attr(path).unwrap());
+            assert_eq!(version("a"), 1);
+            engine.update_size("a", 10).unwrap();
+            engine.truncate("a", 5).unwrap();
+            assert_eq!(version("a"), 3);
+            // reads do not change the file.
+            engine.update_atime("a", AtimePolicy::Strictatime).unwrap();
+            assert_eq!(version("a"), 3);
+
+            engine.check_version("a", 0).unwrap();
+            assert_eq!(engine.check_version("a", 2), Err(VERSION_MISMATCH));
+            engine.check_version("a", 3).unwrap();
+            // the concurrent writes expecting the same version.
+            assert_eq!(engine.check_version("a", 3), Err(VERSION_MISMATCH));
+            assert_eq!(engine.check_version("b", 1), Err(libc::ENOENT));
+        }
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_dir", db_path)).unwrap();
+        rocksdb::DB::destroy(&rocksdb::Options::default(), format!("{}_file", db_path)).unwrap();
+        rocksdb::DB::destroy(
+            &rocksdb::Options::default(),
+            format!("{}_file_attr", db_path),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_symlink_entry() {
+        let engine = MetaEngine::with_store(Box::new(MemStore::new()));
+        let (file, symlink) = (
+            FileTypeSimple::RegularFile as u8,
+            FileTypeSimple::Symlink as u8,
+        );
+        engine.create_directory("d", 0o777).unwrap();
+        engine.directory_add_entry("d", "f", file).unwrap();
+        engine.directory_add_entry("d", "l", symlink).unwrap();
+        assert_eq!(engine.entry_type("d", "f"), Ok(file));
+        assert_eq!(engine.entry_type("d", "l"), Ok(symlink));
+        assert_eq!(engine.find_entry("d", "l"), Ok(Some(symlink)));
+        assert_eq!(engine.find_entry("d", "m"), Ok(None));
+
+        engine.create_file(empty_file(), "local_l", "d/l").unwrap();
+        engine.set_kind("d/l", FileType::Symlink).unwrap();
+        assert_eq!(engine.get_file_attr("d/l").unwrap().kind, FileType::Symlink);
+        let entries = engine.read_directory("d", 1024, 0).unwrap();
+        let mut expected = [
+            (dir_cookie(0, b"f"), libc::DT_REG, b"f".as_slice()),
+            (dir_cookie(0, b"l"), libc::DT_LNK, b"l".as_slice()),
+        ];
+        expected.sort();
+        assert_eq!(
+            bytes_as_dir_entries(&entries).unwrap(),
+            expected
+                .iter()
+                .map(|(cookie, ty, name)| (*ty, *cookie, *name))
+                .collect::<Vec<_>>()
+        );
+        // a listing goes on from a cursor, whatever is added before it.
+        let first = bytes_as_dir_entries(&entries).unwrap()[0].1;
+        engine.directory_add_entry("d", "a", file).unwrap();
+        let mut rest: Vec<_> = [b"a".as_slice(), b"f", b"l"]
+            .into_iter()
+            .map(|name| (dir_cookie(0, name), name))
+            .filter(|(cookie, _)| *cookie > first)
+            .collect();
+        rest.sort();
+        let entries = engine.read_directory("d", 1024, first).unwrap();
+        assert_eq!(
+            bytes_as_dir_entries(&entries)
+                .unwrap()
+                .into_iter()
+                .map(|(_, cookie, name)| (cookie, name))
+                .collect::<Vec<_>>(),
+            rest
+        );
+        engine.directory_delete_entry("d", "a", file).unwrap();
+        // a page ends at the last entry that fits.
+        let entries = engine.read_directory("d", 12, 0).unwrap();
+        assert_eq!(bytes_as_dir_entries(&entries).unwrap().len(), 1);
+
+        // the links are reloaded with the files after a restart.
+        engine.file_indexs.clear();
+        engine.init();
+        assert_eq!(engine.get_file_attr("d/l").unwrap().kind, FileType::Symlink);
+
+        engine.directory_delete_entry("d", "l", symlink).unwrap();
+        assert_eq!(engine.entry_type("d", "l"), Ok(file));
+        assert_eq!(engine.find_entry("d", "l"), Ok(None));
+        assert_eq!(engine.get_file_attr("d").unwrap().size, 1);
+    }
+
+    #[test]
+    fn test_set_attr() {
+        let engine = MetaEngine::with_store(Box::new(MemStore::new()));
+        engine.create_file(empty_file(), "local_a", "d/a").unwrap();
+        let old = engine.get_file_attr("d/a").unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        engine
+            .set_attr("d/a", |attr| {
+                attr.perm = 0o600;
+                attr.mtime = mtime;
+            })
+            .unwrap();
+        let attr = engine.get_file_attr("d/a").unwrap();
+        assert_eq!((attr.perm, attr.mtime, attr.uid), (0o600, mtime, old.uid));
+        assert!(attr.ctime >= old.ctime);
+        assert_ne!(file_version(&attr), file_version(&old));
+        assert_eq!(engine.set_attr("d/b", |_| {}).err(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn test_hard_link() {
+        let engine = MetaEngine::with_store(Box::new(MemStore::new()));
+        engine.create_file(empty_file(), "local_a", "d/a").unwrap();
+        engine.create_file(empty_file(), "local_s", "d/s").unwrap();
+        engine.set_kind("d/s", FileType::Symlink).unwrap();
+        assert_eq!(engine.add_link("d/s", 1).err(), Some(libc::EPERM));
+        engine.add_link("d/a", 1).unwrap();
+        let attr = engine.get_file_attr("d/a").unwrap();
+        assert_eq!(
+            (attr.nlink, attr.flags & FILE_FLAG_LINKED),
+            (2, FILE_FLAG_LINKED)
+        );
+
+        // the links may point to the files of other servers.
+        engine.put_link("d/b", "d/a").unwrap();
+        engine.put_link("d/c", "d/x").unwrap();
+        assert_eq!(engine.put_link("d/a", "d/x"), Err(libc::EEXIST));
+        assert_eq!(
+            engine.create_file(empty_file(), "local_c", "d/c").err(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(engine.resolve("d/c"), "d/x");
+        assert_eq!(engine.is_exist("d/c"), Ok(true));
+
+        // the links are reloaded with the files after a restart.
+        engine.file_indexs.clear();
+        engine.links.clear();
+        engine.init();
+        assert_eq!(engine.resolve("d/b"), "d/a");
+        assert_eq!(engine.link_target("d/c").as_deref(), Some("d/x"));
+        assert_eq!(engine.remove_link("d/b"), Ok(Some("d/a".to_owned())));
+        assert_eq!(engine.remove_link("d/a"), Ok(None));
+        let attr = engine.add_link("d/a", -1).unwrap();
+        assert_eq!(bytes_as_file_attr(&attr).nlink, 1);
+
+        // a link left at the path of its file is dropped.
+        engine.store.put(Table::File, b"$link/d/a", b"d/x").unwrap();
+        engine.links.clear();
+        engine.init();
+        assert_eq!(engine.link_target("d/a"), None);
+        assert_eq!(engine.link_target("d/c").as_deref(), Some("d/x"));
+    }
+
+    #[test]
+    fn test_volume_reload() {
+        let engine = MetaEngine::with_store(Box::new(MemStore::new()));
+        engine
+            .create_volume("v", AtimePolicy::Noatime, true)
+            .unwrap();
+
+        // the options of the volume are reloaded after a restart.
+        engine.volumes.clear();
+        engine.file_indexs.clear();
+        engine.init();
+        let volume = engine.volumes.get("v").unwrap().clone();
+        assert!(volume.atime_policy == AtimePolicy::Noatime);
+        assert!(volume.public);
+
+        engine.delete_volume("v").unwrap();
+        assert_eq!(
+            engine.store.get(Table::File, volume_key("v").as_bytes()),
+            Ok(None)
+        );
+    }
 }
@@ -0,0 +1,236 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * MetaStore keeps the tables of MetaEngine: the paths of the local files,
+ * the directory entries and the file attrs, the volumes being the
+ * directories at the root. MetaEngine keeps its indexes in memory and reads
+ * the tables at startup, so a store only needs point reads and writes and
+ * iteration in key order.
+ * RocksStore is the store of the servers, built with the disk-db feature.
+ * MemStore keeps the tables in memory, for the mem-db feature and the tests.
+ */
+use std::collections::BTreeMap;
+
+use parking_lot::RwLock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Table {
+    // local file name -> path.
+    File,
+    // `<dir>$<name>$<type>` -> name.
+    Dir,
+    // path -> raw FileAttr.
+    FileAttr,
+}
+
+pub type MetaIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), i32>> + 'a>;
+
+pub trait MetaStore: Send + Sync {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, i32>;
+
+    fn put(&self, table: Table, key: &[u8], value: &[u8]) -> Result<(), i32>;
+
+    fn delete(&self, table: Table, key: &[u8]) -> Result<(), i32>;
+
+    // delete_range deletes the keys from start to end, end excluded.
+    fn delete_range(&self, table: Table, start: &[u8], end: &[u8]) -> Result<(), i32>;
+
+    // iter returns the entries from start in key order.
+    fn iter(&self, table: Table, start: &[u8]) -> MetaIter<'_>;
+
+    // sync makes the writes so far durable.
+    fn sync(&self) -> Result<(), i32> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct MemStore {
+    tables: [RwLock<BTreeMap<Vec<u8>, Vec<u8>>>; 3],
+}
+
+impl MemStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn table(&self, table: Table) -> &RwLock<BTreeMap<Vec<u8>, Vec<u8>>> {
+        &self.tables[table as usize]
+    }
+}
+
+impl MetaStore for MemStore {
+    fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, i32> {
+        Ok(self.table(table).read().get(key).cloned())
+    }
+
+    fn put(&self, table: Table, key: &[u8], value: &[u8]) -> Result<(), i32> {
+        self.table(table)
+            .write()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, table: Table, key: &[u8]) -> Result<(), i32> {
+        self.table(table).write().remove(key);
+        Ok(())
+    }
+
+    fn delete_range(&self, table: Table, start: &[u8], end: &[u8]) -> Result<(), i32> {
+        let mut table = self.table(table).write();
+        let mut tail = table.split_off(start);
+        let mut rest = tail.split_off(end);
+        table.append(&mut rest);
+        Ok(())
+    }
+
+    // iter iterates over a copy of the entries, the table is not locked
+    // while the caller writes to it.
+    fn iter(&self, table: Table, start: &[u8]) -> MetaIter<'_> {
+        let entries: Vec<_> = self
+            .table(table)
+            .read()
+            .range(start.to_vec()..)
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+}
+
+#[cfg(feature = "disk-db")]
+pub use rocks::RocksStore;
+
+#[cfg(feature = "disk-db")]
+mod rocks {
+    use log::error;
+    use rocksdb::{BlockBasedOptions, Cache, Direction, IteratorMode, Options, WriteBatch, DB};
+
+    use super::{MetaIter, MetaStore, Table};
+    use crate::common::errors::DATABASE_ERROR;
+
+    pub struct RocksStore {
+        file_db: DB,
+        dir_db: DB,
+        file_attr_db: DB,
+    }
+
+    impl RocksStore {
+        pub fn open(db_path: &str, cache_capacity: usize, write_buffer_size: usize) -> Self {
+            let open = |suffix: &str| {
+                let mut db_opts = Options::default();
+                let mut block_opts = BlockBasedOptions::default();
+                let cache = Cache::new_lru_cache(cache_capacity).unwrap();
+                block_opts.set_block_cache(&cache);
+                db_opts.set_block_based_table_factory(&block_opts);
+                db_opts.set_write_buffer_size(write_buffer_size);
+                db_opts.create_if_missing(true);
+                match DB::open(&db_opts, format!("{}_{}", db_path, suffix)) {
+                    Ok(db) => db,
+                    Err(e) => panic!("{}", e),
+                }
+            };
+            Self {
+                file_db: open("file"),
+                dir_db: open("dir"),
+                file_attr_db: open("file_attr"),
+            }
+        }
+
+        fn db(&self, table: Table) -> &DB {
+            match table {
+                Table::File => &self.file_db,
+                Table::Dir => &self.dir_db,
+                Table::FileAttr => &self.file_attr_db,
+            }
+        }
+    }
+
+    fn database_error(table: Table, e: rocksdb::Error) -> i32 {
+        error!("{:?} table error: {}", table, e);
+        DATABASE_ERROR
+    }
+
+    impl MetaStore for RocksStore {
+        fn get(&self, table: Table, key: &[u8]) -> Result<Option<Vec<u8>>, i32> {
+            self.db(table)
+                .get(key)
+                .map_err(|e| database_error(table, e))
+        }
+
+        fn put(&self, table: Table, key: &[u8], value: &[u8]) -> Result<(), i32> {
+            self.db(table)
+                .put(key, value)
+                .map_err(|e| database_error(table, e))
+        }
+
+        fn delete(&self, table: Table, key: &[u8]) -> Result<(), i32> {
+            self.db(table)
+                .delete(key)
+                .map_err(|e| database_error(table, e))
+        }
+
+        fn delete_range(&self, table: Table, start: &[u8], end: &[u8]) -> Result<(), i32> {
+            let mut batch = WriteBatch::default();
+            batch.delete_range(start, end);
+            self.db(table)
+                .write(batch)
+                .map_err(|e| database_error(table, e))
+        }
+
+        fn iter(&self, table: Table, start: &[u8]) -> MetaIter<'_> {
+            Box::new(
+                self.db(table)
+                    .iterator(IteratorMode::From(start, Direction::Forward))
+                    .map(move |item| match item {
+                        Ok((key, value)) => Ok((key.into_vec(), value.into_vec())),
+                        Err(e) => Err(database_error(table, e)),
+                    }),
+            )
+        }
+
+        // the writes are durable once their WAL is synced.
+        fn sync(&self) -> Result<(), i32> {
+            for table in [Table::File, Table::Dir, Table::FileAttr] {
+                self.db(table)
+                    .flush_wal(true)
+                    .map_err(|e| database_error(table, e))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemStore, MetaStore, Table};
+
+    #[test]
+    fn mem_store_test() {
+        let store = MemStore::new();
+        for key in ["a", "a$1", "a$2", "b"] {
+            store.put(Table::Dir, key.as_bytes(), b"v").unwrap();
+        }
+        store.put(Table::File, b"a$3", b"v").unwrap();
+        assert_eq!(store.get(Table::Dir, b"a$1").unwrap(), Some(b"v".to_vec()));
+        assert_eq!(store.get(Table::File, b"a$1").unwrap(), None);
+
+        let keys = |store: &MemStore, start: &[u8]| -> Vec<Vec<u8>> {
+            store
+                .iter(Table::Dir, start)
+                .map(|item| item.unwrap().0)
+                .collect()
+        };
+        assert_eq!(
+            keys(&store, b"a$"),
+            vec![b"a$1".to_vec(), b"a$2".to_vec(), b"b".to_vec()]
+        );
+
+        store.delete_range(Table::Dir, b"a$", b"a$~").unwrap();
+        assert_eq!(keys(&store, b""), vec![b"a".to_vec(), b"b".to_vec()]);
+        store.delete(Table::Dir, b"a").unwrap();
+        assert_eq!(keys(&store, b""), vec![b"b".to_vec()]);
+        assert!(store.get(Table::File, b"a$3").unwrap().is_some());
+    }
+}
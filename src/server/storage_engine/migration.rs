@@ -0,0 +1,177 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * On-disk format version of the metadata databases and its migrations.
+ * The version is kept in file_db, which is only looked up by local file name
+ * and never iterated, so the key cannot be mistaken for a file.
+ * A server refuses to start on a database of another version unless it is
+ * allowed to migrate it, see `client migrate`. A database written by a newer
+ * server is always refused, there is no way back.
+ */
+use log::info;
+
+use super::{meta_engine::MetaEngine, meta_store::Table};
+use crate::common::errors::{status_to_string, DATABASE_ERROR};
+
+pub const FORMAT_VERSION: u32 = 1;
+
+// local file names always start with the storage root, so they never start with '$'.
+const FORMAT_VERSION_KEY: &str = "$format_version";
+
+pub struct Migration {
+    // the version the migration upgrades from, to from + 1.
+    pub from: u32,
+    pub description: &'static str,
+    pub run: fn(&MetaEngine) -> Result<(), i32>,
+}
+
+// MIGRATIONS must hold one migration for every version below FORMAT_VERSION,
+// in order. Each migration must be safe to run again if it was interrupted,
+// the version is only written after it succeeds.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 0,
+    description: "record the format version of databases created before it existed",
+    run: |_| Ok(()),
+}];
+
+// format_version returns the version of the databases, a database without
+// any file is written by this server.
+pub fn format_version(meta_engine: &MetaEngine) -> Result<u32, i32> {
+    match meta_engine
+        .store
+        .get(Table::File, FORMAT_VERSION_KEY.as_bytes())
+    {
+        Ok(Some(value)) => match <[u8; 4]>::try_from(value.as_slice()) {
+            Ok(value) => Ok(u32::from_le_bytes(value)),
+            Err(_) => Err(DATABASE_ERROR),
+        },
+        Ok(None) => match meta_engine.store.iter(Table::FileAttr, b"").next() {
+            Some(_) => Ok(0),
+            None => Ok(FORMAT_VERSION),
+        },
+        Err(_) => Err(DATABASE_ERROR),
+    }
+}
+
+fn set_format_version(meta_engine: &MetaEngine, version: u32) -> Result<(), i32> {
+    meta_engine.store.put(
+        Table::File,
+        FORMAT_VERSION_KEY.as_bytes(),
+        &version.to_le_bytes(),
+    )
+}
+
+// pending_migrations returns the migrations to run from version.
+pub fn pending_migrations(version: u32) -> Result<&'static [Migration], String> {
+    if version > FORMAT_VERSION {
+        return Err(format!(
+            "database format version {} is newer than the supported version {}, upgrade the server",
+            version, FORMAT_VERSION
+        ));
+    }
+    match MIGRATIONS.iter().position(|m| m.from == version) {
+        Some(index) => Ok(&MIGRATIONS[index..]),
+        None => Ok(&[]),
+    }
+}
+
+// check runs at startup: it stamps a new database, and migrates an old one
+// if auto_migrate is set or refuses to start otherwise.
+pub fn check(meta_engine: &MetaEngine, auto_migrate: bool) -> Result<(), String> {
+    let version = format_version(meta_engine).map_err(|e| {
+        format!(
+            "read database format version failed: {}",
+            status_to_string(e)
+        )
+    })?;
+    let migrations = pending_migrations(version)?;
+    if migrations.is_empty() {
+        return set_format_version(meta_engine, FORMAT_VERSION).map_err(|e| {
+            format!(
+                "write database format version failed: {}",
+                status_to_string(e)
+            )
+        });
+    }
+    if !auto_migrate {
+        return Err(format!(
+            "database format version {} is older than {}, run `client migrate` or start the server with --auto-migrate",
+            version, FORMAT_VERSION
+        ));
+    }
+    migrate(meta_engine).map(|_| ())
+}
+
+// migrate upgrades the databases to FORMAT_VERSION and returns the migrations it ran.
+pub fn migrate(meta_engine: &MetaEngine) -> Result<Vec<&'static str>, String> {
+    let version = format_version(meta_engine).map_err(|e| {
+        format!(
+            "read database format version failed: {}",
+            status_to_string(e)
+        )
+    })?;
+    let mut applied = Vec::new();
+    for migration in pending_migrations(version)? {
+        info!(
+            "migrate database from version {}: {}",
+            migration.from, migration.description
+        );
+        (migration.run)(meta_engine)
+            .and_then(|_| set_format_version(meta_engine, migration.from + 1))
+            .map_err(|e| {
+                format!(
+                    "migrate database from version {} failed: {}",
+                    migration.from,
+                    status_to_string(e)
+                )
+            })?;
+        applied.push(migration.description);
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, format_version, migrate, set_format_version, FORMAT_VERSION, MIGRATIONS};
+    use crate::{
+        common::util::empty_file,
+        server::storage_engine::{
+            meta_engine::MetaEngine,
+            meta_store::{MemStore, Table},
+        },
+    };
+
+    #[test]
+    fn migrations_test() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from, i as u32);
+        }
+        assert_eq!(MIGRATIONS.len() as u32, FORMAT_VERSION);
+    }
+
+    #[test]
+    fn migrate_test() {
+        let engine = MetaEngine::with_store(Box::new(MemStore::new()));
+        assert_eq!(format_version(&engine), Ok(FORMAT_VERSION));
+        check(&engine, false).unwrap();
+
+        // a database written before the version was recorded.
+        engine.put_file_attr("a", &empty_file()).unwrap();
+        engine
+            .store
+            .delete(Table::File, super::FORMAT_VERSION_KEY.as_bytes())
+            .unwrap();
+        assert_eq!(format_version(&engine), Ok(0));
+        assert!(check(&engine, false).is_err());
+        assert_eq!(format_version(&engine), Ok(0));
+        assert_eq!(migrate(&engine).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(format_version(&engine), Ok(FORMAT_VERSION));
+        assert!(migrate(&engine).unwrap().is_empty());
+
+        set_format_version(&engine, FORMAT_VERSION + 1).unwrap();
+        assert!(check(&engine, true).is_err());
+        assert!(migrate(&engine).is_err());
+    }
+}
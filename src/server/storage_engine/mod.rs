@@ -2,18 +2,51 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
-use std::sync::Arc;
+use std::{os::fd::AsRawFd, sync::Arc};
 
-use self::meta_engine::MetaEngine;
+use self::{block_engine::allocator::AllocatorStats, meta_engine::MetaEngine};
+
+// fault_point fails or delays the operation on path when a fault is injected.
+// It expands to nothing unless the fault-injection feature is enabled.
+macro_rules! fault_point {
+    ($operation:expr, $path:expr) => {
+        #[cfg(feature = "fault-injection")]
+        $crate::server::storage_engine::fault::check($operation, $path)?;
+    };
+}
+
+// fault_limit returns the bytes of length the operation on path reads or
+// writes, fewer when a short fault is injected.
+macro_rules! fault_limit {
+    ($operation:expr, $path:expr, $length:expr) => {{
+        #[cfg(feature = "fault-injection")]
+        let length = $crate::server::storage_engine::fault::limit($operation, $path, $length);
+        #[cfg(not(feature = "fault-injection"))]
+        let length = $length;
+        length
+    }};
+}
 
 pub mod block_engine;
+pub mod chunk_engine;
+#[cfg(all(test, feature = "fault-injection", feature = "disk-db"))]
+mod crash_test;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
 pub mod file_engine;
+pub mod group_commit;
 pub mod meta_engine;
+pub mod meta_store;
+pub mod migration;
+pub mod registry;
+pub mod upload;
 
 pub trait StorageEngine {
-    fn new(root: &str, meta_engine: Arc<MetaEngine>) -> Self;
+    fn new(root: &str, meta_engine: Arc<MetaEngine>) -> Result<Self, i32>
+    where
+        Self: Sized;
 
-    fn init(&self);
+    fn init(&self) -> Result<(), i32>;
 
     fn read_file(&self, path: &str, size: u32, offset: i64) -> Result<Vec<u8>, i32>;
 
@@ -26,4 +59,98 @@ pub trait StorageEngine {
     fn delete_file(&self, path: &str) -> Result<(), i32>;
 
     fn truncate_file(&self, path: &str, length: i64) -> Result<(), i32>;
+
+    // prefetch_file starts reading the whole file into memory ahead of the
+    // reads, and returns the size of the file.
+    fn prefetch_file(&self, path: &str) -> Result<u64, i32>;
+
+    // begin_upload starts a multipart upload replacing the data of an existing
+    // file, and returns the id of the upload.
+    fn begin_upload(&self, path: &str) -> Result<u64, i32>;
+
+    fn upload_part(&self, path: &str, upload_id: u64, offset: u64, data: &[u8]) -> Result<(), i32>;
+
+    // complete_upload publishes the uploaded parts atomically as the new data of the file.
+    fn complete_upload(&self, path: &str, upload_id: u64, size: u64) -> Result<(), i32>;
+
+    // disk_space returns the free and total bytes of the disk of the data,
+    // if the engine knows it.
+    fn disk_space(&self) -> Option<Result<(u64, u64), i32>> {
+        None
+    }
+
+    // allocator_stats returns the free chunks of the device and how they are
+    // fragmented, if the engine allocates the chunks itself.
+    fn allocator_stats(&self) -> Option<AllocatorStats> {
+        None
+    }
+
+    // local_path returns the local file holding the data of path, for the
+    // short-circuit reads, if the engine keeps a file per path.
+    fn local_path(&self, _path: &str) -> Option<Result<String, i32>> {
+        None
+    }
+
+    // sync_file makes the data of path written so far durable, by syncing
+    // the local file of path if the engine keeps one, its data only with
+    // datasync.
+    fn sync_file(&self, path: &str, datasync: bool) -> Result<(), i32> {
+        match self.local_path(path) {
+            Some(local_path) => std::fs::File::open(local_path?)
+                .and_then(|file| match datasync {
+                    true => file.sync_data(),
+                    false => file.sync_all(),
+                })
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO)),
+            None => Ok(()),
+        }
+    }
+
+    // seek_data returns where the data, or the hole with hole, starts at or
+    // after offset in the first size bytes of path, ENXIO past the last
+    // data. The holes are those of the local file of path if the engine
+    // keeps one, the whole file is data otherwise.
+    fn seek_data(&self, path: &str, offset: u64, size: u64, hole: bool) -> Result<u64, i32> {
+        if offset >= size {
+            return Err(libc::ENXIO);
+        }
+        let file = match self.local_path(path) {
+            Some(local_path) => std::fs::File::open(local_path?)
+                .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?,
+            None if hole => return Ok(size),
+            None => return Ok(offset),
+        };
+        let whence = match hole {
+            true => libc::SEEK_HOLE,
+            false => libc::SEEK_DATA,
+        };
+        let result = unsafe { libc::lseek(file.as_raw_fd(), offset as i64, whence) };
+        match (result < 0, hole) {
+            // the local file ends before size, its end is a hole.
+            (true, true) if nix::errno::errno() == libc::ENXIO => Ok(offset),
+            (true, _) => Err(nix::errno::errno()),
+            (false, true) => Ok((result as u64).min(size)),
+            (false, false) if result as u64 >= size => Err(libc::ENXIO),
+            (false, false) => Ok(result as u64),
+        }
+    }
+
+    // rename_file moves the data and the attr of path to new_path, which is
+    // not a file, if the engine can.
+    fn rename_file(&self, _path: &str, _new_path: &str) -> Result<(), i32> {
+        Err(libc::EOPNOTSUPP)
+    }
+
+    // preallocate reserves the space of size bytes for the data of path,
+    // without changing its size, if the engine can.
+    fn preallocate(&self, _path: &str, _size: u64) -> Result<(), i32> {
+        Ok(())
+    }
+
+    // append_file writes data at the end of path, one append at a time so
+    // that concurrent appends do not overlap, and returns the offset written
+    // at and the size written, if the engine can.
+    fn append_file(&self, _path: &str, _data: &[u8]) -> Result<(u64, usize), i32> {
+        Err(libc::EOPNOTSUPP)
+    }
 }
@@ -0,0 +1,101 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The storage engines a server binary is built with, chosen at runtime with
+ * `--storage <name>:<params>`. The params are the root given to
+ * StorageEngine::new, e.g. the directory of the file engine or the device of
+ * the block engine.
+ * The engines of sealfs are registered by default, but the block engine
+ * whose data path is not written yet. A binary packaging another engine
+ * registers it in STORAGE_ENGINES before calling server::run.
+ */
+use std::{collections::BTreeMap, future::Future, pin::Pin};
+
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+
+use super::{chunk_engine::ChunkEngine, file_engine::FileEngine, StorageEngine};
+use crate::server::{run_with, ServerOptions};
+
+pub type StartServer =
+    fn(String, ServerOptions) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>>;
+
+lazy_static! {
+    pub static ref STORAGE_ENGINES: StorageEngineRegistry = {
+        let registry = StorageEngineRegistry::new();
+        registry.register::<FileEngine>("file").unwrap();
+        registry.register::<ChunkEngine>("chunk").unwrap();
+        registry
+    };
+}
+
+#[derive(Default)]
+pub struct StorageEngineRegistry {
+    engines: RwLock<BTreeMap<String, StartServer>>,
+}
+
+impl StorageEngineRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<S: StorageEngine + Send + Sync + 'static>(
+        &self,
+        name: &str,
+    ) -> Result<(), i32> {
+        if name.is_empty() || name.contains(':') {
+            return Err(libc::EINVAL);
+        }
+        let mut engines = self.engines.write();
+        if engines.contains_key(name) {
+            return Err(libc::EEXIST);
+        }
+        engines.insert(name.to_owned(), start::<S>);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<StartServer> {
+        self.engines.read().get(name).copied()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.engines.read().keys().cloned().collect()
+    }
+}
+
+fn start<S: StorageEngine + Send + Sync + 'static>(
+    params: String,
+    options: ServerOptions,
+) -> Pin<Box<dyn Future<Output = anyhow::Result<()>>>> {
+    Box::pin(run_with::<S>(params, options))
+}
+
+// parse_storage splits `<name>:<params>`, a name alone has empty params.
+pub fn parse_storage(storage: &str) -> (&str, &str) {
+    storage.split_once(':').unwrap_or((storage, ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_storage, StorageEngineRegistry, STORAGE_ENGINES};
+    use crate::server::storage_engine::file_engine::FileEngine;
+
+    #[test]
+    fn registry_test() {
+        assert_eq!(STORAGE_ENGINES.names(), vec!["chunk", "file"]);
+        assert_eq!(
+            parse_storage("file:/data/storage:1"),
+            ("file", "/data/storage:1")
+        );
+        assert_eq!(parse_storage("file"), ("file", ""));
+
+        let registry = StorageEngineRegistry::new();
+        assert!(registry.get("s3").is_none());
+        registry.register::<FileEngine>("s3").unwrap();
+        assert!(registry.get("s3").is_some());
+        assert_eq!(registry.register::<FileEngine>("s3"), Err(libc::EEXIST));
+        assert_eq!(registry.register::<FileEngine>("a:b"), Err(libc::EINVAL));
+    }
+}
@@ -0,0 +1,215 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Multipart uploads of large files.
+ * An upload writes its parts, in any order, to a staging file next to the
+ * local file. Completing the upload checks that the parts cover the whole
+ * file, syncs the staging file and renames it over the local file, so readers
+ * see either the old or the new content. Staging files left by a crash are
+ * removed by fsck since they do not belong to any file. The space reserved
+ * for a new file on create, see CreateFileSendMetaData, moves to its staging
+ * file.
+ */
+use std::{
+    fs::{File, OpenOptions},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileExt, MetadataExt},
+    },
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dashmap::DashMap;
+use log::{debug, error, info};
+use nix::fcntl::{fallocate, FallocateFlags};
+use parking_lot::Mutex;
+
+struct Upload {
+    id: u64,
+    staging_file_name: String,
+    file: File,
+    // (offset, length) of the received parts.
+    parts: Mutex<Vec<(u64, u64)>>,
+}
+
+pub struct Uploads {
+    next_id: AtomicU64,
+    // path -> its upload in progress.
+    uploads: DashMap<String, Upload>,
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uploads {
+    pub fn new() -> Self {
+        // ids of a previous run must not be accepted after a restart.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            next_id: AtomicU64::new(now.as_nanos() as u64),
+            uploads: DashMap::new(),
+        }
+    }
+
+    // begin starts an upload of path, replacing the one in progress if any.
+    pub fn begin(&self, path: &str, local_file_name: &str) -> Result<u64, i32> {
+        let staging_file_name = format!("{}.upload", local_file_name);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&staging_file_name)
+            .map_err(|e| {
+                error!("begin upload error: {:?}", e);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })?;
+        move_reservation(local_file_name, &file);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        info!("begin upload {} of {}", id, path);
+        self.uploads.insert(
+            path.to_owned(),
+            Upload {
+                id,
+                staging_file_name,
+                file,
+                parts: Mutex::new(Vec::new()),
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn write_part(&self, path: &str, id: u64, offset: u64, data: &[u8]) -> Result<(), i32> {
+        let upload = match self.uploads.get(path) {
+            Some(upload) if upload.id == id => upload,
+            _ => return Err(libc::ESTALE),
+        };
+        upload.file.write_all_at(data, offset).map_err(|e| {
+            error!("write upload part error: {:?}", e);
+            e.raw_os_error().unwrap_or(libc::EIO)
+        })?;
+        upload.parts.lock().push((offset, data.len() as u64));
+        Ok(())
+    }
+
+    // complete syncs the parts of an upload covering [0, size) and renames
+    // them over local_file_name.
+    pub fn complete(
+        &self,
+        path: &str,
+        id: u64,
+        size: u64,
+        local_file_name: &str,
+    ) -> Result<(), i32> {
+        match self.uploads.get(path) {
+            Some(upload) if upload.id == id => {
+                if !covers(&upload.parts.lock(), size) {
+                    return Err(libc::EINVAL);
+                }
+            }
+            _ => return Err(libc::ESTALE),
+        }
+        let (_, upload) = match self.uploads.remove_if(path, |_, upload| upload.id == id) {
+            Some(upload) => upload,
+            None => return Err(libc::ESTALE),
+        };
+        upload
+            .file
+            .set_len(size)
+            .and_then(|_| upload.file.sync_all())
+            .and_then(|_| std::fs::rename(&upload.staging_file_name, local_file_name))
+            .map_err(|e| {
+                error!("complete upload error: {:?}", e);
+                let _ = std::fs::remove_file(&upload.staging_file_name);
+                e.raw_os_error().unwrap_or(libc::EIO)
+            })?;
+        info!("complete upload {} of {}, size: {}", id, path, size);
+        Ok(())
+    }
+}
+
+// move_reservation reserves the space reserved by an empty local file for
+// its staging file, and frees it in the local file.
+fn move_reservation(local_file_name: &str, staging_file: &File) {
+    let reserved = match std::fs::metadata(local_file_name) {
+        Ok(metadata) if metadata.len() == 0 => metadata.blocks() * 512,
+        _ => return,
+    };
+    if reserved == 0 {
+        return;
+    }
+    let keep_size = FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    let result =
+        fallocate(staging_file.as_raw_fd(), keep_size, 0, reserved as i64).and_then(|_| {
+            let local_file = OpenOptions::new()
+                .write(true)
+                .open(local_file_name)
+                .map_err(|e| nix::Error::from_i32(e.raw_os_error().unwrap_or(libc::EIO)))?;
+            let punch_hole = keep_size | FallocateFlags::FALLOC_FL_PUNCH_HOLE;
+            fallocate(local_file.as_raw_fd(), punch_hole, 0, reserved as i64)
+        });
+    if let Err(e) = result {
+        debug!("move the reservation of {} failed: {}", local_file_name, e);
+    }
+}
+
+// covers checks that the parts cover [0, size) without going beyond it.
+fn covers(parts: &[(u64, u64)], size: u64) -> bool {
+    let mut parts = parts.to_vec();
+    parts.sort_unstable();
+    let mut end = 0;
+    for (offset, length) in parts {
+        if offset > end || offset + length > size {
+            return false;
+        }
+        end = end.max(offset + length);
+    }
+    end == size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{covers, Uploads};
+
+    #[test]
+    fn covers_test() {
+        assert!(covers(&[], 0));
+        assert!(covers(&[(4, 4), (0, 4)], 8));
+        assert!(covers(&[(0, 6), (4, 4)], 8));
+        assert!(!covers(&[(0, 4), (5, 3)], 8));
+        assert!(!covers(&[(0, 4)], 8));
+        assert!(!covers(&[(0, 8), (8, 1)], 8));
+    }
+
+    #[test]
+    fn upload_test() {
+        let local_file_name = "/tmp/test_upload";
+        std::fs::write(local_file_name, b"old").unwrap();
+        let uploads = Uploads::new();
+        let stale = uploads.begin("v/f", local_file_name).unwrap();
+        let id = uploads.begin("v/f", local_file_name).unwrap();
+        assert_eq!(uploads.write_part("v/f", stale, 0, b"x"), Err(libc::ESTALE));
+        uploads.write_part("v/f", id, 5, b"world").unwrap();
+        assert_eq!(
+            uploads.complete("v/f", id, 10, local_file_name),
+            Err(libc::EINVAL)
+        );
+        // readers still see the old content.
+        assert_eq!(std::fs::read(local_file_name).unwrap(), b"old");
+        uploads.write_part("v/f", id, 0, b"hello").unwrap();
+        uploads.complete("v/f", id, 10, local_file_name).unwrap();
+        assert_eq!(std::fs::read(local_file_name).unwrap(), b"helloworld");
+        assert_eq!(
+            uploads.complete("v/f", id, 10, local_file_name),
+            Err(libc::ESTALE)
+        );
+        std::fs::remove_file(local_file_name).unwrap();
+    }
+}
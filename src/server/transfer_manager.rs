@@ -7,13 +7,15 @@ use std::collections::HashMap;
 use dashmap::DashMap;
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use crate::common::serialization::{FileTransfer, TransferState, TransferStatusRecvMetaData};
+
 pub struct LockPool {
     locks: HashMap<String, RwLock<()>>,
 }
 
 pub struct TransferManager {
     transferring_locks: *const LockPool,
-    transferring_status: DashMap<String, bool>,
+    transferring_status: DashMap<String, FileTransfer>,
 }
 
 unsafe impl std::marker::Sync for TransferManager {}
@@ -47,7 +49,8 @@ impl TransferManager {
             transferring_locks
                 .locks
                 .insert(path.clone(), RwLock::new(()));
-            self.transferring_status.insert(path.clone(), false);
+            self.transferring_status
+                .insert(path.clone(), FileTransfer::default());
         }
     }
 
@@ -61,11 +64,97 @@ impl TransferManager {
         lock.write().await
     }
 
+    // status tells if path was transferred, None if it is not transferred.
     pub fn status(&self, path: &str) -> Option<bool> {
-        self.transferring_status.get(path).map(|status| *status)
+        self.transferring_status
+            .get(path)
+            .map(|transfer| transfer.state == TransferState::Done)
+    }
+
+    // set_state starts an attempt of the transfer of path with InFlight, or
+    // ends it with Done.
+    pub fn set_state(&self, path: &str, state: TransferState) {
+        let mut transfer = self.transferring_status.entry(path.to_owned()).or_default();
+        if state == TransferState::InFlight {
+            transfer.bytes = 0;
+        }
+        transfer.state = state;
+    }
+
+    pub fn add_bytes(&self, path: &str, bytes: u64) {
+        if let Some(mut transfer) = self.transferring_status.get_mut(path) {
+            transfer.bytes += bytes;
+        }
+    }
+
+    pub fn fail(&self, path: &str, error: i32) {
+        let mut transfer = self.transferring_status.entry(path.to_owned()).or_default();
+        transfer.state = TransferState::Failed;
+        transfer.failures += 1;
+        transfer.error = error;
+    }
+
+    // report counts the transfers by state and lists those in flight or
+    // failed, by path.
+    pub fn report(&self) -> TransferStatusRecvMetaData {
+        let mut report = TransferStatusRecvMetaData::default();
+        for item in self.transferring_status.iter() {
+            let (path, transfer) = item.pair();
+            report.bytes += transfer.bytes;
+            match transfer.state {
+                TransferState::Pending => {
+                    report.pending += 1;
+                    continue;
+                }
+                TransferState::InFlight => report.in_flight += 1,
+                TransferState::Done => {
+                    report.done += 1;
+                    continue;
+                }
+                TransferState::Failed => report.failed += 1,
+            }
+            report.files.push((path.clone(), *transfer));
+        }
+        report.files.sort_by(|a, b| a.0.cmp(&b.0));
+        report
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransferManager;
+    use crate::common::serialization::TransferState;
+
+    #[test]
+    fn transfer_report_test() {
+        let manager = TransferManager::new();
+        let paths = ["v/c", "v/a", "v/b", "v/d"].map(str::to_owned).to_vec();
+        manager.make_up_files(&paths);
+        manager.set_state("v/a", TransferState::InFlight);
+        manager.add_bytes("v/a", 10);
+        manager.set_state("v/a", TransferState::Done);
+        manager.set_state("v/b", TransferState::InFlight);
+        manager.add_bytes("v/b", 5);
+        manager.fail("v/c", libc::ETIMEDOUT);
+        assert_eq!(manager.status("v/a"), Some(true));
+        assert_eq!(manager.status("v/b"), Some(false));
+        assert_eq!(manager.status("v/e"), None);
+
+        let report = manager.report();
+        assert_eq!(
+            (report.pending, report.in_flight, report.done, report.failed),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(report.bytes, 15);
+        let paths: Vec<_> = report.files.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["v/b", "v/c"]);
+        assert_eq!(
+            (report.files[1].1.failures, report.files[1].1.error),
+            (1, libc::ETIMEDOUT)
+        );
 
-    pub fn set_status(&self, path: &str, status: bool) {
-        self.transferring_status.insert(path.to_string(), status);
+        // a retry sends the data again.
+        manager.set_state("v/b", TransferState::InFlight);
+        assert_eq!(manager.report().bytes, 10);
     }
 }
@@ -0,0 +1,153 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * The usage of the volumes served by a server: the requests, the bytes read
+ * and written and the files created and deleted. The counters are sent with
+ * each heartbeat and reset, the manager adds them up per day, see
+ * manager/history.rs.
+ */
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+
+use crate::common::serialization::VolumeUsage;
+
+#[derive(Default)]
+struct Counters {
+    ops: AtomicU64,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    files_created: AtomicU64,
+    files_deleted: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct VolumeStats {
+    volumes: DashMap<String, Counters>,
+}
+
+// the volume of a path is its first component.
+fn volume_of(path: &str) -> &str {
+    path.split('/').next().unwrap_or(path)
+}
+
+impl VolumeStats {
+    fn add(&self, path: &str, f: impl FnOnce(&Counters)) {
+        let volume = volume_of(path);
+        if volume.is_empty() {
+            return;
+        }
+        match self.volumes.get(volume) {
+            Some(counters) => f(&counters),
+            None => f(&self.volumes.entry(volume.to_owned()).or_default()),
+        }
+    }
+
+    pub fn add_op(&self, path: &str) {
+        self.add(path, |c| {
+            c.ops.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    pub fn add_read(&self, path: &str, size: usize) {
+        self.add(path, |c| {
+            c.read_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        });
+    }
+
+    pub fn add_write(&self, path: &str, size: usize) {
+        self.add(path, |c| {
+            c.write_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        });
+    }
+
+    pub fn add_file(&self, path: &str, created: bool) {
+        self.add(path, |c| {
+            match created {
+                true => c.files_created.fetch_add(1, Ordering::Relaxed),
+                false => c.files_deleted.fetch_add(1, Ordering::Relaxed),
+            };
+        });
+    }
+
+    // take returns the usage since the last take and resets the counters.
+    pub fn take(&self) -> Vec<VolumeUsage> {
+        self.volumes
+            .iter()
+            .map(|kv| VolumeUsage {
+                volume: kv.key().clone(),
+                ops: kv.ops.swap(0, Ordering::Relaxed),
+                read_bytes: kv.read_bytes.swap(0, Ordering::Relaxed),
+                write_bytes: kv.write_bytes.swap(0, Ordering::Relaxed),
+                files_created: kv.files_created.swap(0, Ordering::Relaxed),
+                files_deleted: kv.files_deleted.swap(0, Ordering::Relaxed),
+            })
+            .filter(|usage| {
+                usage.ops
+                    + usage.read_bytes
+                    + usage.write_bytes
+                    + usage.files_created
+                    + usage.files_deleted
+                    > 0
+            })
+            .collect()
+    }
+
+    // restore adds back the usage of a heartbeat that failed.
+    pub fn restore(&self, usage: Vec<VolumeUsage>) {
+        for usage in usage {
+            self.add(&usage.volume, |c| {
+                c.ops.fetch_add(usage.ops, Ordering::Relaxed);
+                c.read_bytes.fetch_add(usage.read_bytes, Ordering::Relaxed);
+                c.write_bytes
+                    .fetch_add(usage.write_bytes, Ordering::Relaxed);
+                c.files_created
+                    .fetch_add(usage.files_created, Ordering::Relaxed);
+                c.files_deleted
+                    .fetch_add(usage.files_deleted, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VolumeStats;
+    use crate::common::serialization::VolumeUsage;
+
+    #[test]
+    fn volume_stats_test() {
+        let stats = VolumeStats::default();
+        stats.add_op("v1/a");
+        stats.add_op("v1");
+        stats.add_write("v1/a", 4096);
+        stats.add_file("v1/a", true);
+        stats.add_read("v2/d/b", 10);
+        let mut usage = stats.take();
+        usage.sort_by(|a, b| a.volume.cmp(&b.volume));
+        assert_eq!(
+            usage,
+            vec![
+                VolumeUsage {
+                    volume: "v1".to_owned(),
+                    ops: 2,
+                    write_bytes: 4096,
+                    files_created: 1,
+                    ..Default::default()
+                },
+                VolumeUsage {
+                    volume: "v2".to_owned(),
+                    read_bytes: 10,
+                    ..Default::default()
+                },
+            ]
+        );
+        assert!(stats.take().is_empty());
+        stats.restore(usage.clone());
+        let mut restored = stats.take();
+        restored.sort_by(|a, b| a.volume.cmp(&b.volume));
+        assert_eq!(restored, usage);
+    }
+}
@@ -0,0 +1,629 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Intercept layer tests.
+ * The test starts a manager and a single server, then runs real tools
+ * (coreutils, tar, git) twice: under LD_PRELOAD on a sealfs volume, and on a
+ * local directory. Their output, exit code and the files they leave must be
+ * the same byte for byte. When a scenario differs, the trace of the calls
+ * served by sealfs is replayed against a fresh local directory to report the
+ * first call whose result differs from the kernel.
+ * The intercept library must be built first, run it with
+ *     make intercept_test
+ * SEALFS_INTERCEPT_LIB overrides the path of the library.
+ */
+#![cfg(feature = "e2e-test")]
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread::sleep,
+    time::Duration,
+};
+
+const MANAGER_ADDRESS: &str = "127.0.0.1:18181";
+const SERVER_ADDRESS: &str = "127.0.0.1:18185";
+const VOLUME_NAME: &str = "intercept";
+// the server completes the first write and the first read of the files of
+// the short_io case partly, when it is built with the fault-injection feature.
+const SERVER_FAULTS: &str = "file_engine.write_file:*/short_io/*:short=3:1;\
+                             file_engine.read_file:*/short_io/*:short=3:1";
+// the mount point seen by the intercepted tools, nothing is mounted there.
+const MOUNT_POINT: &str = "/mnt/sealfs-intercept";
+
+// scenarios that fail because the intercept library does not support them yet.
+const EXPECTED_FAILURES: &[&str] = &[];
+
+struct Cluster {
+    processes: Vec<Child>,
+    root: PathBuf,
+}
+
+impl Cluster {
+    fn start() -> Self {
+        let root = std::env::temp_dir().join(format!("sealfs-intercept-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut cluster = Self {
+            processes: Vec::new(),
+            root,
+        };
+
+        cluster.spawn(
+            Command::new(env!("CARGO_BIN_EXE_manager"))
+                .env(
+                    "SEALFS_CONFIG_PATH",
+                    concat!(env!("CARGO_MANIFEST_DIR"), "/examples"),
+                )
+                .args(["--address", MANAGER_ADDRESS])
+                .args(["--all-servers-address", SERVER_ADDRESS])
+                .args(["--log-level", "warn"]),
+        );
+        cluster.spawn(
+            Command::new(env!("CARGO_BIN_EXE_server"))
+                .args(["--manager-address", MANAGER_ADDRESS])
+                .args(["--server-address", SERVER_ADDRESS])
+                .arg("--database-path")
+                .arg(cluster.root.join("database/"))
+                .arg("--storage-path")
+                .arg(cluster.root.join("storage/"))
+                .args(["--log-level", "warn"])
+                .env("SEALFS_FAULTS", SERVER_FAULTS),
+        );
+        sleep(Duration::from_secs(3));
+
+        let status = Command::new(env!("CARGO_BIN_EXE_client"))
+            .args(["--log-level", "warn"])
+            .args(["create-volume", VOLUME_NAME, "100000"])
+            .args(["--manager-address", MANAGER_ADDRESS])
+            .status()
+            .unwrap();
+        assert!(status.success(), "create volume failed");
+        cluster
+    }
+
+    fn spawn(&mut self, command: &mut Command) {
+        self.processes.push(command.spawn().unwrap());
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for process in self.processes.iter_mut().rev() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+struct Scenario {
+    name: &'static str,
+    // shell script run with $D set to the directory of the scenario. The
+    // working directory is not on sealfs, so every path starts with $D but
+    // after a cd.
+    script: &'static str,
+}
+
+fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "cp_cat",
+            script: r#"
+                printf 'hello world\n' > $D/a
+                cp $D/a $D/b
+                cat $D/b
+                head -c 5 $D/b; echo
+                tail -c 6 $D/b
+                wc -c < $D/b
+            "#,
+        },
+        Scenario {
+            name: "short_io",
+            script: r#"
+                printf 'hello world\n' > $D/f
+                cat $D/f
+                wc -c < $D/f
+            "#,
+        },
+        Scenario {
+            name: "large_file",
+            script: r#"
+                truncate -s 5G $D/f
+                stat -c %s $D/f
+                truncate -s 4294967297 $D/f
+                stat -c %s $D/f
+            "#,
+        },
+        Scenario {
+            name: "large_copy",
+            script: r#"
+                seq 1 200000 > $D/seq
+                cp $D/seq $D/copy
+                cmp $D/seq $D/copy && echo same
+                tail -n 3 $D/copy
+            "#,
+        },
+        Scenario {
+            name: "dd_offsets",
+            script: r#"
+                seq 1 1000 > $D/f
+                dd if=$D/f of=$D/g bs=100 skip=3 count=5 status=none
+                dd if=$D/f of=$D/g bs=100 seek=10 count=2 conv=notrunc status=none
+                cat $D/g | head -c 300
+                stat -c %s $D/g
+            "#,
+        },
+        Scenario {
+            // cp skips the holes with SEEK_DATA and SEEK_HOLE.
+            name: "sparse_copy",
+            script: r#"
+                truncate -s 8M $D/s
+                printf x | dd of=$D/s bs=1 seek=5000000 conv=notrunc status=none
+                cp --sparse=always $D/s $D/t
+                cmp $D/s $D/t && echo same
+                stat -c %s $D/t
+            "#,
+        },
+        Scenario {
+            name: "mkdir_tree",
+            script: r#"
+                mkdir -p $D/x/y/z
+                echo one > $D/x/one
+                echo two > $D/x/y/two
+                rm $D/x/one
+                rmdir $D/x/y/z
+                ls $D/x $D/x/y
+            "#,
+        },
+        Scenario {
+            name: "list_stat",
+            script: r#"
+                mkdir $D/d
+                for i in 1 2 3 4 5; do seq 1 $i > $D/d/f$i; done
+                ls -l $D/d | awk '{print $5, $9}'
+                echo more >> $D/d/f1
+                rm $D/d/f2
+                ls -l $D/d | awk '{print $5, $9}'
+                stat -c '%n %s' $D/d/*
+            "#,
+        },
+        Scenario {
+            // ls reads the directory in several getdents.
+            name: "long_listing",
+            script: r#"
+                mkdir $D/d
+                cd $D/d && touch $(seq -f entry-%04g-of-a-listing-longer-than-a-page 1 1000)
+                ls $D/d | wc -l
+                ls $D/d | sed -n '1p;500p;1000p'
+            "#,
+        },
+        Scenario {
+            name: "open_flags",
+            script: r#"
+                printf 'long content\n' > $D/f
+                printf 'x\n' > $D/f
+                cat $D/f
+                printf 'more\n' >> $D/f
+                cat $D/f
+                : > $D/f
+                stat -c %s $D/f
+                (set -C; echo y > $D/f) 2>/dev/null || echo exists
+                (set -C; echo z > $D/g) && cat $D/g
+                mkdir $D/d
+                (echo w > $D/d) 2>/dev/null || echo directory
+            "#,
+        },
+        Scenario {
+            name: "chdir_relative",
+            script: r#"
+                mkdir $D/w
+                cd $D/w
+                echo one > f
+                mkdir sub
+                cd sub
+                echo two > ../g
+                cd ..
+                read a < f; read b < g; echo $a $b
+                ls
+                pwd -P
+            "#,
+        },
+        Scenario {
+            name: "tar_roundtrip",
+            script: r#"
+                mkdir -p $D/src/sub
+                seq 1 5000 > $D/src/numbers
+                echo leaf > $D/src/sub/leaf
+                tar -cf $D/archive.tar -C $D src
+                mkdir $D/out
+                tar -xf $D/archive.tar -C $D/out
+                tar -tf $D/archive.tar | sort
+                cmp $D/src/numbers $D/out/src/numbers && echo same
+            "#,
+        },
+        Scenario {
+            name: "git_commit",
+            script: r#"
+                git init -q $D/repo
+                echo content > $D/repo/file
+                git -C $D/repo add file
+                git -C $D/repo -c user.name=test -c user.email=test@sealfs \
+                    commit -q -m init
+                git -C $D/repo log --format=%s
+                git -C $D/repo cat-file -p HEAD:file
+            "#,
+        },
+        Scenario {
+            // the appends of concurrent processes do not overwrite each other.
+            name: "append",
+            script: r#"
+                echo a > $D/log
+                echo b >> $D/log
+                cat $D/log
+                for i in 1 2 3 4; do
+                    (for j in $(seq 50); do echo $i.$j >> $D/many; done) &
+                done
+                wait
+                wc -l < $D/many
+                sort -u $D/many | wc -l
+            "#,
+        },
+        Scenario {
+            // a flock excludes the other processes until it is released.
+            name: "flock",
+            script: r#"
+                touch $D/f
+                flock $D/f -c "flock -n $D/f -c 'echo inner' || echo busy"
+                flock -n $D/f -c 'echo free'
+            "#,
+        },
+    ]
+}
+
+fn intercept_lib() -> PathBuf {
+    match std::env::var("SEALFS_INTERCEPT_LIB") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => {
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("intercept/target/debug/libintercept.so")
+        }
+    }
+}
+
+fn intercepted(command: &mut Command) -> &mut Command {
+    command
+        .env("LD_PRELOAD", intercept_lib())
+        .env("SEALFS_MOUNT_POINT", MOUNT_POINT)
+        .env("SEALFS_VOLUME_NAME", VOLUME_NAME)
+        .env("SEALFS_MANAGER_ADDRESS", MANAGER_ADDRESS)
+        .env("SEALFS_LOG_LEVEL", "warn")
+}
+
+struct Outcome {
+    code: Option<i32>,
+    stdout: String,
+    tree: String,
+}
+
+// run runs script in dir, under LD_PRELOAD if trace is set, and returns its
+// output with dir replaced by $D.
+fn run(script: &str, dir: &str, trace: Option<&Path>) -> Outcome {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script).env("D", dir);
+    command
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("HOME", "/nonexistent");
+    if let Some(trace) = trace {
+        intercepted(&mut command).env("SEALFS_TRACE_FILE", trace);
+    }
+    let output = command.output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).replace(dir, "$D");
+
+    // the tree is listed under LD_PRELOAD as well, to see the files on sealfs.
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("cd / && find $D -type f | sort | xargs -r sha256sum; find $D -type d | sort");
+    command.env("D", dir);
+    if trace.is_some() {
+        intercepted(&mut command);
+    }
+    let tree = command.output().unwrap();
+    Outcome {
+        code: output.status.code(),
+        stdout,
+        tree: String::from_utf8_lossy(&tree.stdout).replace(dir, "$D"),
+    }
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    let s = s.trim_start_matches('x');
+    (0..s.len() / 2)
+        .map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap())
+        .collect()
+}
+
+fn errno() -> isize {
+    -(std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO) as isize)
+}
+
+fn c_string(path: &str) -> CString {
+    CString::new(path).unwrap()
+}
+
+fn stat_size(path: &CString) -> (isize, i64) {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    match unsafe { libc::stat(path.as_ptr(), &mut stat) } {
+        0 => (0, stat.st_size),
+        _ => (errno(), -1),
+    }
+}
+
+// Replay runs the calls of a trace against a local directory.
+struct Replay {
+    mount_point: String,
+    local: String,
+    // (pid, traced fd) to local fd.
+    fds: HashMap<(u32, i64), i32>,
+}
+
+impl Replay {
+    fn path(&self, path: &str) -> CString {
+        match path.strip_prefix(&self.mount_point) {
+            Some(rest) => c_string(&(self.local.clone() + rest)),
+            None => c_string(path),
+        }
+    }
+
+    fn fd(&self, pid: u32, fd: &str) -> i32 {
+        let fd: i64 = fd.parse().unwrap();
+        self.fds.get(&(pid, fd)).copied().unwrap_or(-1)
+    }
+
+    // call replays a line, returning a description of the difference if the
+    // kernel does not give the same result.
+    fn call(&mut self, line: &str) -> Option<String> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (pid, name, traced) = (
+            fields[0].parse::<u32>().unwrap(),
+            fields[1],
+            fields[2].parse::<isize>().unwrap(),
+        );
+        let args = &fields[3..];
+        let mut data = None;
+        let mut size = None;
+        let result = unsafe {
+            match name {
+                "open" | "creat" => {
+                    let path = self.path(args[0]);
+                    let (flags, mode): (i32, u32) = match name {
+                        "open" => (args[1].parse().unwrap(), args[2].parse().unwrap()),
+                        _ => (
+                            libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC,
+                            args[1].parse().unwrap(),
+                        ),
+                    };
+                    let fd = libc::open(path.as_ptr(), flags, mode);
+                    if fd >= 0 && traced >= 0 {
+                        self.fds.insert((pid, traced as i64), fd);
+                    }
+                    // the fds differ, only their success matters.
+                    match (fd >= 0, traced >= 0) {
+                        (true, true) => traced,
+                        _ if fd < 0 => errno(),
+                        _ => fd as isize,
+                    }
+                }
+                "close" => {
+                    let key = (pid, args[0].parse().unwrap());
+                    match self.fds.remove(&key) {
+                        Some(fd) => libc::close(fd) as isize,
+                        None => -libc::EBADF as isize,
+                    }
+                }
+                "read" | "pread" => {
+                    let fd = self.fd(pid, args[0]);
+                    let expected = unhex(args[args.len() - 1]);
+                    let mut buf = vec![0u8; expected.len().max(1)];
+                    let result = match name {
+                        "read" => libc::read(fd, buf.as_mut_ptr() as _, expected.len()),
+                        _ => libc::pread(
+                            fd,
+                            buf.as_mut_ptr() as _,
+                            expected.len(),
+                            args[1].parse().unwrap(),
+                        ),
+                    };
+                    if result >= 0 {
+                        buf.truncate(result as usize);
+                        data = Some((buf, expected));
+                    }
+                    if result < 0 {
+                        errno()
+                    } else {
+                        result
+                    }
+                }
+                "write" | "pwrite" => {
+                    let fd = self.fd(pid, args[0]);
+                    let buf = unhex(args[args.len() - 1]);
+                    let result = match name {
+                        "write" => libc::write(fd, buf.as_ptr() as _, buf.len()),
+                        _ => {
+                            libc::pwrite(fd, buf.as_ptr() as _, buf.len(), args[1].parse().unwrap())
+                        }
+                    };
+                    if result < 0 {
+                        errno()
+                    } else {
+                        result
+                    }
+                }
+                "lseek" => {
+                    let fd = self.fd(pid, args[0]);
+                    let result =
+                        libc::lseek(fd, args[1].parse().unwrap(), args[2].parse().unwrap());
+                    if result < 0 {
+                        errno()
+                    } else {
+                        result as isize
+                    }
+                }
+                "truncate" => {
+                    let path = self.path(args[0]);
+                    match libc::truncate(path.as_ptr(), args[1].parse().unwrap()) {
+                        0 => 0,
+                        _ => errno(),
+                    }
+                }
+                "ftruncate" => {
+                    match libc::ftruncate(self.fd(pid, args[0]), args[1].parse().unwrap()) {
+                        0 => 0,
+                        _ => errno(),
+                    }
+                }
+                "mkdir" => {
+                    let path = self.path(args[0]);
+                    match libc::mkdir(path.as_ptr(), args[1].parse().unwrap()) {
+                        0 => 0,
+                        _ => errno(),
+                    }
+                }
+                "rmdir" | "unlink" => {
+                    let path = self.path(args[0]);
+                    let result = match name {
+                        "rmdir" => libc::rmdir(path.as_ptr()),
+                        _ => libc::unlink(path.as_ptr()),
+                    };
+                    match result {
+                        0 => 0,
+                        _ => errno(),
+                    }
+                }
+                "rename" => {
+                    let (from, to) = (self.path(args[0]), self.path(args[1]));
+                    match libc::rename(from.as_ptr(), to.as_ptr()) {
+                        0 => 0,
+                        _ => errno(),
+                    }
+                }
+                "stat" => {
+                    let (result, local_size) = stat_size(&self.path(args[0]));
+                    size = Some((local_size, args[1].parse::<i64>().unwrap()));
+                    result
+                }
+                "fstat" => {
+                    let mut stat: libc::stat = std::mem::zeroed();
+                    match libc::fstat(self.fd(pid, args[0]), &mut stat) {
+                        0 => {
+                            size = Some((stat.st_size, args[1].parse::<i64>().unwrap()));
+                            0
+                        }
+                        _ => errno(),
+                    }
+                }
+                _ => return None,
+            }
+        };
+        if result != traced {
+            return Some(format!("result {} on the local directory", result));
+        }
+        if let Some((local, traced)) = data {
+            if local != traced {
+                return Some(format!(
+                    "read {:?} on the local directory",
+                    String::from_utf8_lossy(&local)
+                ));
+            }
+        }
+        if let Some((local, traced)) = size {
+            if local != traced {
+                return Some(format!("size {} on the local directory", local));
+            }
+        }
+        None
+    }
+}
+
+// replay prints the first call of trace whose result differs from the kernel.
+fn replay(trace: &Path, mount_dir: &str, local: &Path) {
+    fs::create_dir_all(local).unwrap();
+    let mut replay = Replay {
+        mount_point: mount_dir.to_owned(),
+        local: local.to_str().unwrap().to_owned(),
+        fds: HashMap::new(),
+    };
+    let trace = fs::read_to_string(trace).unwrap_or_default();
+    for (i, line) in trace.lines().enumerate() {
+        if let Some(difference) = replay.call(line) {
+            let mut shown = line.to_owned();
+            shown.truncate(200);
+            println!("    call {}: {}", i, shown);
+            println!("    {}", difference);
+            return;
+        }
+    }
+    println!("    the replay of the trace does not differ");
+}
+
+#[test]
+fn intercept_replay_test() {
+    let cluster = Cluster::start();
+    assert!(
+        intercept_lib().exists(),
+        "{:?} not found, build the intercept library first",
+        intercept_lib()
+    );
+    let mut failures = Vec::new();
+    for scenario in scenarios() {
+        println!("scenario {}", scenario.name);
+        let mount_dir = format!("{}/{}", MOUNT_POINT, scenario.name);
+        let local_dir = cluster.root.join("local").join(scenario.name);
+        let trace = cluster.root.join(format!("{}.trace", scenario.name));
+
+        let script = format!("mkdir -p $D\n{}", scenario.script);
+        let sealfs = run(&script, &mount_dir, Some(&trace));
+        let local = run(&script, local_dir.to_str().unwrap(), None);
+        if sealfs.code == local.code && sealfs.stdout == local.stdout && sealfs.tree == local.tree {
+            continue;
+        }
+        if sealfs.code != local.code {
+            println!("    exit code {:?}, expected {:?}", sealfs.code, local.code);
+        }
+        if sealfs.stdout != local.stdout {
+            println!(
+                "    output {:?}\n    expected {:?}",
+                sealfs.stdout, local.stdout
+            );
+        }
+        if sealfs.tree != local.tree {
+            println!("    files {:?}\n    expected {:?}", sealfs.tree, local.tree);
+        }
+        replay(
+            &trace,
+            &mount_dir,
+            &cluster.root.join("replay").join(scenario.name),
+        );
+        failures.push(scenario.name);
+    }
+    let unexpected: Vec<_> = failures
+        .iter()
+        .filter(|name| !EXPECTED_FAILURES.contains(name))
+        .collect();
+    let fixed: Vec<_> = EXPECTED_FAILURES
+        .iter()
+        .filter(|name| !failures.contains(name))
+        .collect();
+    assert!(unexpected.is_empty(), "failed scenarios: {:?}", unexpected);
+    assert!(
+        fixed.is_empty(),
+        "scenarios pass now, remove them from EXPECTED_FAILURES: {:?}",
+        fixed
+    );
+}
@@ -0,0 +1,107 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * RPC over IPv6: a server listening on the loopback `[::1]` and a dual-stack
+ * server listening on `[::]`, called by clients with bracketed addresses.
+ * It needs an IPv6 loopback and free local ports.
+ */
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use sealfs::{
+    common::util::{join_address, split_address},
+    rpc::{
+        client::{RpcClient, TcpStreamCreator},
+        server::{Handler, RpcServer},
+    },
+};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+struct EchoHandler;
+
+#[async_trait]
+impl Handler for EchoHandler {
+    async fn dispatch(
+        &self,
+        _id: u32,
+        _operation_type: u32,
+        _flags: u32,
+        _path: Vec<u8>,
+        data: Vec<u8>,
+        _metadata: Vec<u8>,
+    ) -> anyhow::Result<(i32, u32, usize, usize, Vec<u8>, Vec<u8>)> {
+        Ok((0, 0, 0, data.len(), vec![], data))
+    }
+}
+
+fn serve(bind_address: &'static str) {
+    tokio::spawn(async move {
+        RpcServer::new(Arc::new(EchoHandler), bind_address)
+            .run()
+            .await
+            .unwrap();
+    });
+}
+
+async fn echo(server_address: &str, data: &[u8]) -> Vec<u8> {
+    let client: RpcClient<OwnedReadHalf, OwnedWriteHalf, TcpStreamCreator> = RpcClient::new();
+    client.add_connection(server_address).await.unwrap();
+    let mut status = 0;
+    let mut rsp_flags = 0;
+    let mut recv_meta_data_length = 0;
+    let mut recv_data_length = 0;
+    let mut recv_data = vec![0u8; data.len()];
+    client
+        .call_remote(
+            server_address,
+            0,
+            0,
+            "",
+            &[],
+            data,
+            &mut status,
+            &mut rsp_flags,
+            &mut recv_meta_data_length,
+            &mut recv_data_length,
+            &mut [],
+            &mut recv_data,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+    assert_eq!(status, 0);
+    recv_data.truncate(recv_data_length);
+    recv_data
+}
+
+#[tokio::test]
+async fn loopback_test() {
+    serve("[::1]:18281");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(echo("[::1]:18281", b"hello").await, b"hello");
+}
+
+#[tokio::test]
+async fn dual_stack_test() {
+    serve("[::]:18282");
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(echo("[::1]:18282", b"v6").await, b"v6");
+    assert_eq!(echo("127.0.0.1:18282", b"v4").await, b"v4");
+}
+
+#[test]
+fn address_test() {
+    assert_eq!(split_address("[::1]:8085"), Some(("::1", 8085)));
+    assert_eq!(split_address("127.0.0.1:8085"), Some(("127.0.0.1", 8085)));
+    assert_eq!(
+        split_address("server-0.fs:8085"),
+        Some(("server-0.fs", 8085))
+    );
+    assert_eq!(split_address("::1:8085"), None);
+    assert_eq!(split_address("[::1]"), None);
+    assert_eq!(split_address("[host]:8085"), None);
+    assert_eq!(join_address("fd00::2", 8085), "[fd00::2]:8085");
+    assert_eq!(join_address("server-0.fs", 8085), "server-0.fs:8085");
+}
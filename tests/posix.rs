@@ -0,0 +1,637 @@
+// Copyright 2022 labring. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * End-to-end POSIX semantics tests.
+ * The test starts a manager, a single server and a client daemon, mounts a
+ * volume with FUSE and runs a table of cases against the mount point.
+ * It needs /dev/fuse and free local ports, run it with
+ *     cargo test --features e2e-test,fault-injection --test posix
+ */
+#![cfg(feature = "e2e-test")]
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write as _},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, MetadataExt},
+        },
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread::sleep,
+    time::Duration,
+};
+
+const MANAGER_ADDRESS: &str = "127.0.0.1:18081";
+const SERVER_ADDRESS: &str = "127.0.0.1:18085";
+const VOLUME_NAME: &str = "e2e";
+// the server completes the first write and the first read of the files of
+// the short_io case partly, when it is built with the fault-injection feature.
+const SERVER_FAULTS: &str = "file_engine.write_file:*/short_io/*:short=3:1;\
+                             file_engine.read_file:*/short_io/*:short=3:1";
+
+// cases that fail because the operation is not supported by the FUSE client yet.
+const EXPECTED_FAILURES: &[&str] = &[];
+
+struct Cluster {
+    processes: Vec<Child>,
+    root: PathBuf,
+    mount_point: PathBuf,
+}
+
+impl Cluster {
+    fn start() -> Self {
+        let root = std::env::temp_dir().join(format!("sealfs-e2e-{}", std::process::id()));
+        let mount_point = root.join("mnt");
+        fs::create_dir_all(&mount_point).unwrap();
+        let mut cluster = Self {
+            processes: Vec::new(),
+            root,
+            mount_point,
+        };
+
+        cluster.spawn(
+            Command::new(env!("CARGO_BIN_EXE_manager"))
+                .env(
+                    "SEALFS_CONFIG_PATH",
+                    concat!(env!("CARGO_MANIFEST_DIR"), "/examples"),
+                )
+                .args(["--address", MANAGER_ADDRESS])
+                .args(["--all-servers-address", SERVER_ADDRESS])
+                .args(["--log-level", "warn"]),
+        );
+        cluster.spawn(
+            Command::new(env!("CARGO_BIN_EXE_server"))
+                .args(["--manager-address", MANAGER_ADDRESS])
+                .args(["--server-address", SERVER_ADDRESS])
+                .arg("--database-path")
+                .arg(cluster.root.join("database/"))
+                .arg("--storage-path")
+                .arg(cluster.root.join("storage/"))
+                .args(["--log-level", "warn"])
+                .env("SEALFS_FAULTS", SERVER_FAULTS),
+        );
+        sleep(Duration::from_secs(3));
+
+        let status = cluster
+            .client()
+            .args(["create-volume", VOLUME_NAME, "100000"])
+            .args(["--manager-address", MANAGER_ADDRESS])
+            .status()
+            .unwrap();
+        assert!(status.success(), "create volume failed");
+
+        let mut daemon = cluster.client();
+        daemon
+            .arg("daemon")
+            .args(["--manager-address", MANAGER_ADDRESS])
+            .arg("--index-file")
+            .arg(cluster.root.join("sealfs.index"))
+            // the small writes of the cases are coalesced.
+            .args(["--write-batch-size", "65536"]);
+        cluster.with_socket(&mut daemon);
+        cluster.spawn(&mut daemon);
+        sleep(Duration::from_secs(3));
+
+        let mut mount = cluster.client();
+        mount
+            .arg("mount")
+            .arg(&cluster.mount_point)
+            .arg(VOLUME_NAME);
+        cluster.with_socket(&mut mount);
+        assert!(mount.status().unwrap().success(), "mount failed");
+        sleep(Duration::from_secs(2));
+        cluster
+    }
+
+    fn client(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_client"));
+        command.args(["--log-level", "warn"]);
+        command
+    }
+
+    fn with_socket(&self, command: &mut Command) {
+        command
+            .arg("--socket-path")
+            .arg(self.root.join("sealfs.sock"));
+    }
+
+    fn spawn(&mut self, command: &mut Command) {
+        self.processes.push(command.spawn().unwrap());
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.mount_point.join(path.trim_start_matches('/'))
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        let mut umount = self.client();
+        umount.arg("umount").arg(&self.mount_point);
+        self.with_socket(&mut umount);
+        let _ = umount.status();
+        for process in self.processes.iter_mut().rev() {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+enum Op {
+    Mkdir(&'static str),
+    Rmdir(&'static str),
+    Create(&'static str),
+    CreateExcl(&'static str),
+    Unlink(&'static str),
+    Write(&'static str, u64, &'static [u8]),
+    // a write to a file opened with O_APPEND.
+    Append(&'static str, &'static [u8]),
+    Read(&'static str, u64, &'static [u8]),
+    Truncate(&'static str, u64),
+    Size(&'static str, u64),
+    Rename(&'static str, &'static str),
+    Exists(&'static str, bool),
+    // a link and its target.
+    Symlink(&'static str, &'static str),
+    ReadLink(&'static str, &'static str),
+    // a hard link and its file.
+    Link(&'static str, &'static str),
+    // hard links <file>.0, <file>.1, ... to a file, on any server, counted
+    // by the file.
+    Links(&'static str, u32),
+    // fsync of a file or a directory, fdatasync with true.
+    Sync(&'static str, bool),
+    // lseek with a whence from an offset, and the offset it returns.
+    Seek(&'static str, i32, u64, u64),
+    // a directory listed again by new streams from each position telldir
+    // returned, with seekdir.
+    SeekDir(&'static str),
+    // a lock of a type on the first bytes of a file, held while a child
+    // process takes a read lock on them, which fails with the result.
+    Lock(&'static str, i32),
+}
+
+struct Case {
+    name: &'static str,
+    steps: Vec<(Op, Result<(), i32>)>,
+}
+
+fn errno(e: std::io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+fn check<T: PartialEq + std::fmt::Debug>(value: T, expected: T) -> Result<(), i32> {
+    match value == expected {
+        true => Ok(()),
+        false => {
+            println!("    got {:?}, expected {:?}", value, expected);
+            Err(-1)
+        }
+    }
+}
+
+fn run(dir: &Path, op: &Op) -> Result<(), i32> {
+    let path = |p: &str| dir.join(p);
+    match op {
+        Op::Mkdir(p) => fs::create_dir(path(p)).map_err(errno),
+        Op::Rmdir(p) => fs::remove_dir(path(p)).map_err(errno),
+        Op::Create(p) => OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path(p))
+            .map(|_| ())
+            .map_err(errno),
+        Op::CreateExcl(p) => OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path(p))
+            .map(|_| ())
+            .map_err(errno),
+        Op::Unlink(p) => fs::remove_file(path(p)).map_err(errno),
+        Op::Write(p, offset, data) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(path(p))
+                .map_err(errno)?;
+            file.write_all_at(data, *offset).map_err(errno)
+        }
+        Op::Append(p, data) => OpenOptions::new()
+            .append(true)
+            .open(path(p))
+            .and_then(|mut file| file.write_all(data))
+            .map_err(errno),
+        Op::Read(p, offset, expected) => {
+            let file = OpenOptions::new().read(true).open(path(p)).map_err(errno)?;
+            let mut data = vec![0u8; expected.len()];
+            file.read_exact_at(&mut data, *offset).map_err(errno)?;
+            check(data.as_slice(), *expected)
+        }
+        Op::Truncate(p, length) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .open(path(p))
+                .map_err(errno)?;
+            file.set_len(*length).map_err(errno)
+        }
+        Op::Size(p, size) => check(fs::metadata(path(p)).map_err(errno)?.len(), *size),
+        Op::Rename(from, to) => fs::rename(path(from), path(to)).map_err(errno),
+        Op::Exists(p, exists) => match fs::metadata(path(p)) {
+            Ok(_) => check(true, *exists),
+            Err(e) if e.kind() == ErrorKind::NotFound => check(false, *exists),
+            Err(e) => Err(errno(e)),
+        },
+        Op::Symlink(p, target) => std::os::unix::fs::symlink(target, path(p)).map_err(errno),
+        Op::ReadLink(p, target) => check(
+            fs::read_link(path(p)).map_err(errno)?,
+            PathBuf::from(target),
+        ),
+        Op::Link(p, target) => fs::hard_link(path(target), path(p)).map_err(errno),
+        Op::Links(p, count) => {
+            for i in 0..*count {
+                let link = path(&format!("{}.{}", p, i));
+                fs::hard_link(path(p), &link).map_err(errno)?;
+            }
+            check(
+                fs::metadata(path(p)).map_err(errno)?.nlink(),
+                1 + *count as u64,
+            )
+        }
+        Op::Sync(p, datasync) => {
+            let file = fs::File::open(path(p)).map_err(errno)?;
+            match datasync {
+                true => file.sync_data().map_err(errno),
+                false => file.sync_all().map_err(errno),
+            }
+        }
+        Op::Seek(p, whence, offset, expected) => {
+            let file = fs::File::open(path(p)).map_err(errno)?;
+            let result = unsafe { libc::lseek(file.as_raw_fd(), *offset as i64, *whence) };
+            if result < 0 {
+                return Err(errno(std::io::Error::last_os_error()));
+            }
+            check(result as u64, *expected)
+        }
+        Op::SeekDir(p) => {
+            let listing = |pos: Option<libc::c_long>| {
+                let name = std::ffi::CString::new(path(p).as_os_str().as_bytes()).unwrap();
+                let dir = unsafe { libc::opendir(name.as_ptr()) };
+                if dir.is_null() {
+                    return Err(errno(std::io::Error::last_os_error()));
+                }
+                if let Some(pos) = pos {
+                    unsafe { libc::seekdir(dir, pos) };
+                }
+                // (name, the position after it)
+                let mut entries = Vec::new();
+                loop {
+                    let entry = unsafe { libc::readdir(dir) };
+                    if entry.is_null() {
+                        break;
+                    }
+                    let name = unsafe { std::ffi::CStr::from_ptr((*entry).d_name.as_ptr()) };
+                    entries.push((name.to_owned(), unsafe { libc::telldir(dir) }));
+                }
+                unsafe { libc::closedir(dir) };
+                Ok(entries)
+            };
+            let entries = listing(None)?;
+            for (i, (_, pos)) in entries.iter().enumerate() {
+                check(listing(Some(*pos))?.as_slice(), &entries[i + 1..])?;
+            }
+            Ok(())
+        }
+        Op::Lock(p, typ) => {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path(p))
+                .map_err(errno)?;
+            let fcntl = |typ: i32| {
+                let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+                lock.l_type = typ as libc::c_short;
+                lock.l_whence = libc::SEEK_SET as libc::c_short;
+                lock.l_len = 10;
+                match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } {
+                    0 => 0,
+                    _ => errno(std::io::Error::last_os_error()),
+                }
+            };
+            match fcntl(*typ) {
+                0 => {}
+                e => return Err(e),
+            }
+            // the locks of a process are not inherited by its children.
+            match unsafe { libc::fork() } {
+                -1 => Err(errno(std::io::Error::last_os_error())),
+                0 => unsafe { libc::_exit(fcntl(libc::F_RDLCK)) },
+                child => {
+                    let mut status = 0;
+                    unsafe { libc::waitpid(child, &mut status, 0) };
+                    match libc::WEXITSTATUS(status) {
+                        0 => Ok(()),
+                        e => Err(e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn cases() -> Vec<Case> {
+    use Op::*;
+    vec![
+        Case {
+            name: "mkdir_eexist",
+            steps: vec![(Mkdir("d"), Ok(())), (Mkdir("d"), Err(libc::EEXIST))],
+        },
+        Case {
+            name: "mkdir_enoent",
+            steps: vec![(Mkdir("missing/d"), Err(libc::ENOENT))],
+        },
+        Case {
+            name: "rmdir_enoent",
+            steps: vec![(Rmdir("d"), Err(libc::ENOENT))],
+        },
+        Case {
+            name: "rmdir_enotempty",
+            steps: vec![
+                (Mkdir("d"), Ok(())),
+                (Create("d/f"), Ok(())),
+                (Rmdir("d"), Err(libc::ENOTEMPTY)),
+                (Unlink("d/f"), Ok(())),
+                (Rmdir("d"), Ok(())),
+                (Exists("d", false), Ok(())),
+            ],
+        },
+        Case {
+            name: "create_excl",
+            steps: vec![
+                (CreateExcl("f"), Ok(())),
+                (CreateExcl("f"), Err(libc::EEXIST)),
+                (Create("f"), Ok(())),
+            ],
+        },
+        Case {
+            name: "create_enoent",
+            steps: vec![(Create("missing/f"), Err(libc::ENOENT))],
+        },
+        Case {
+            name: "unlink_enoent",
+            steps: vec![
+                (Unlink("f"), Err(libc::ENOENT)),
+                (Create("f"), Ok(())),
+                (Unlink("f"), Ok(())),
+                (Exists("f", false), Ok(())),
+            ],
+        },
+        Case {
+            name: "write_offsets",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"abc"), Ok(())),
+                (Write("f", 10, b"xyz"), Ok(())),
+                (Size("f", 13), Ok(())),
+                (Read("f", 0, b"abc"), Ok(())),
+                (Read("f", 3, &[0; 7]), Ok(())),
+                (Read("f", 10, b"xyz"), Ok(())),
+                (Write("f", 1, b"BC"), Ok(())),
+                (Read("f", 0, b"aBC"), Ok(())),
+                (Size("f", 13), Ok(())),
+            ],
+        },
+        Case {
+            name: "truncate_shrink",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"hello world"), Ok(())),
+                (Truncate("f", 5), Ok(())),
+                (Size("f", 5), Ok(())),
+                (Read("f", 0, b"hello"), Ok(())),
+                (Truncate("f", 8), Ok(())),
+                (Read("f", 0, b"hello\0\0\0"), Ok(())),
+            ],
+        },
+        Case {
+            name: "rename_file",
+            steps: vec![
+                (Create("a"), Ok(())),
+                (Write("a", 0, b"data"), Ok(())),
+                (Rename("a", "b"), Ok(())),
+                (Exists("a", false), Ok(())),
+                (Read("b", 0, b"data"), Ok(())),
+                (Rename("a", "c"), Err(libc::ENOENT)),
+            ],
+        },
+        Case {
+            name: "rename_replace",
+            steps: vec![
+                (Create("a"), Ok(())),
+                (Write("a", 0, b"new"), Ok(())),
+                (Create("b"), Ok(())),
+                (Write("b", 0, b"old data"), Ok(())),
+                (Rename("a", "b"), Ok(())),
+                (Exists("a", false), Ok(())),
+                (Size("b", 3), Ok(())),
+                (Read("b", 0, b"new"), Ok(())),
+                (Mkdir("d"), Ok(())),
+                (Rename("d", "e"), Ok(())),
+                (Exists("d", false), Ok(())),
+                (Create("e/f"), Ok(())),
+                (Rename("b", "e/g"), Ok(())),
+                (Read("e/g", 0, b"new"), Ok(())),
+            ],
+        },
+        Case {
+            name: "short_io",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"hello world"), Ok(())),
+                (Size("f", 11), Ok(())),
+                (Read("f", 0, b"hello world"), Ok(())),
+                (Write("f", 6, b"there"), Ok(())),
+                (Read("f", 0, b"hello there"), Ok(())),
+            ],
+        },
+        Case {
+            name: "fsync",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"durable"), Ok(())),
+                (Sync("f", false), Ok(())),
+                (Sync("f", true), Ok(())),
+                (Mkdir("d"), Ok(())),
+                (Sync("d", false), Ok(())),
+                (Read("f", 0, b"durable"), Ok(())),
+            ],
+        },
+        Case {
+            name: "symlink",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"hi"), Ok(())),
+                (Symlink("l", "f"), Ok(())),
+                (ReadLink("l", "f"), Ok(())),
+                (Read("l", 0, b"hi"), Ok(())),
+                (Symlink("l", "f"), Err(libc::EEXIST)),
+                (Unlink("l"), Ok(())),
+                (Exists("l", false), Ok(())),
+                (Read("f", 0, b"hi"), Ok(())),
+            ],
+        },
+        Case {
+            name: "hard_link",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"hi"), Ok(())),
+                (Link("g", "f"), Ok(())),
+                (Link("g", "f"), Err(libc::EEXIST)),
+                (Write("g", 2, b"!"), Ok(())),
+                (Read("f", 0, b"hi!"), Ok(())),
+                (Unlink("f"), Ok(())),
+                (Exists("f", false), Ok(())),
+                (Read("g", 0, b"hi!"), Ok(())),
+                (Unlink("g"), Ok(())),
+                (Exists("g", false), Ok(())),
+            ],
+        },
+        Case {
+            // most of the links hash to other servers than their file on a
+            // cluster of several, and outlive the first path of the file.
+            name: "hard_links",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"hi"), Ok(())),
+                (Links("f", 16), Ok(())),
+                (Unlink("f"), Ok(())),
+                (Read("f.7", 0, b"hi"), Ok(())),
+                (Rename("f.7", "g"), Ok(())),
+                (Read("g", 0, b"hi"), Ok(())),
+                (Unlink("g"), Ok(())),
+                (Read("f.9", 0, b"hi"), Ok(())),
+            ],
+        },
+        Case {
+            // sparse, the offsets past 4GiB do not fit 32 bits.
+            name: "large_file",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", (1 << 32) - 2, b"abcd"), Ok(())),
+                (Write("f", 5 << 30, b"tail"), Ok(())),
+                (Size("f", (5 << 30) + 4), Ok(())),
+                (Read("f", (1 << 32) - 2, b"abcd"), Ok(())),
+                (Read("f", 5 << 30, b"tail"), Ok(())),
+                (Read("f", (1 << 32) + 4096, &[0; 2]), Ok(())),
+                (Truncate("f", (1 << 32) + 1), Ok(())),
+                (Size("f", (1 << 32) + 1), Ok(())),
+                (Read("f", (1 << 32) - 2, b"abc"), Ok(())),
+            ],
+        },
+        Case {
+            name: "write_batch",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"ab"), Ok(())),
+                (Write("f", 2, b"cd"), Ok(())),
+                (Write("f", 4, b"ef"), Ok(())),
+                (Size("f", 6), Ok(())),
+                (Read("f", 0, b"abcdef"), Ok(())),
+                (Write("f", 1, b"X"), Ok(())),
+                (Sync("f", false), Ok(())),
+                (Read("f", 0, b"aXcdef"), Ok(())),
+            ],
+        },
+        Case {
+            name: "seekdir",
+            steps: vec![
+                (Mkdir("d"), Ok(())),
+                (Create("d/a"), Ok(())),
+                (Create("d/b"), Ok(())),
+                (Mkdir("d/c"), Ok(())),
+                (SeekDir("d"), Ok(())),
+                (Unlink("d/a"), Ok(())),
+                (SeekDir("d"), Ok(())),
+            ],
+        },
+        Case {
+            // the data of a sparse file starts where it was written.
+            name: "seek_data",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 3 << 20, b"x"), Ok(())),
+                (Seek("f", libc::SEEK_END, 0, (3 << 20) + 1), Ok(())),
+                (Seek("f", libc::SEEK_HOLE, 0, 0), Ok(())),
+                (Seek("f", libc::SEEK_DATA, 0, 3 << 20), Ok(())),
+                (Seek("f", libc::SEEK_HOLE, 3 << 20, (3 << 20) + 1), Ok(())),
+                (Seek("f", libc::SEEK_DATA, 4 << 20, 0), Err(libc::ENXIO)),
+            ],
+        },
+        Case {
+            name: "append",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Write("f", 0, b"abc"), Ok(())),
+                (Append("f", b"de"), Ok(())),
+                (Write("f", 8, b"x"), Ok(())),
+                (Append("f", b"fg"), Ok(())),
+                (Size("f", 11), Ok(())),
+                (Read("f", 0, b"abcde\0\0\0xfg"), Ok(())),
+            ],
+        },
+        Case {
+            // the read locks share the bytes, and closing a file releases
+            // the locks of the process on it.
+            name: "fcntl_lock",
+            steps: vec![
+                (Create("f"), Ok(())),
+                (Lock("f", libc::F_RDLCK), Ok(())),
+                (Lock("f", libc::F_WRLCK), Err(libc::EAGAIN)),
+                (Lock("f", libc::F_RDLCK), Ok(())),
+            ],
+        },
+    ]
+}
+
+#[test]
+fn posix_semantics_test() {
+    let cluster = Cluster::start();
+    let mut failures = Vec::new();
+    for case in cases() {
+        // every case runs in its own directory.
+        let dir = cluster.path(case.name);
+        fs::create_dir(&dir).unwrap();
+        println!("case {}", case.name);
+        for (i, (op, expected)) in case.steps.iter().enumerate() {
+            let result = run(&dir, op);
+            if result != *expected {
+                println!(
+                    "    step {} failed: got {:?}, expected {:?}",
+                    i, result, expected
+                );
+                failures.push(case.name);
+                break;
+            }
+        }
+    }
+    let unexpected: Vec<_> = failures
+        .iter()
+        .filter(|name| !EXPECTED_FAILURES.contains(name))
+        .collect();
+    let fixed: Vec<_> = EXPECTED_FAILURES
+        .iter()
+        .filter(|name| !failures.contains(name))
+        .collect();
+    assert!(unexpected.is_empty(), "failed cases: {:?}", unexpected);
+    assert!(
+        fixed.is_empty(),
+        "cases pass now, remove them from EXPECTED_FAILURES: {:?}",
+        fixed
+    );
+}